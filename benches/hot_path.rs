@@ -0,0 +1,85 @@
+//! 推送到发布的热路径：帧解析与一帧完整测量数据的主题/载荷构造。
+//!
+//! ```sh
+//! cargo bench --bench hot_path
+//! ```
+
+use std::future::Future;
+use std::hint::black_box;
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rumqttc::{ClientError, QoS};
+use ups120_daemon::conversion::{to_measurements, ConversionContext};
+use ups120_daemon::data_models::TimestampedMeasurements;
+use ups120_daemon::framing::parse_frame;
+use ups120_daemon::mqtt_handlers::publish_sample;
+use ups120_daemon::publisher::Publisher;
+use ups120_daemon::test_support::PayloadBuilder;
+use ups120_daemon::topics::TopicMap;
+use ups120_daemon::usb_types::{ProtocolVersion, UsbData, SEQUENCE_PROTOCOL_VERSION};
+
+/// 只构造主题与载荷、不发送的发布者
+struct NullPublisher;
+
+impl Publisher for NullPublisher {
+    fn publish<S, V>(&self, topic: S, _qos: QoS, _retain: bool, payload: V) -> impl Future<Output = Result<(), ClientError>> + Send
+    where
+        S: Into<String> + Send,
+        V: Into<Vec<u8>> + Send,
+    {
+        black_box((topic.into(), payload.into()));
+        std::future::ready(Ok(()))
+    }
+}
+
+fn parse(c: &mut Criterion) {
+    let frame = PayloadBuilder::new().sequence(1).frame();
+    let protocol = ProtocolVersion { protocol: SEQUENCE_PROTOCOL_VERSION, firmware: None };
+    let ctx = ConversionContext::default();
+    c.bench_function("parse_frame", |b| b.iter(|| parse_frame(black_box(&frame), &protocol)));
+    c.bench_function("parse_and_convert", |b| {
+        b.iter(|| match parse_frame(black_box(&frame), &protocol) {
+            Ok((UsbData::StatusPush(payload), _)) => Some(to_measurements::<5>(&payload, &ctx)),
+            _ => None,
+        })
+    });
+}
+
+// 主循环把一帧样本交给 HTTP 状态、两份历史、两条总线与发布限速器
+const CONSUMERS: usize = 6;
+
+fn sample() -> TimestampedMeasurements {
+    let data = PayloadBuilder::new().measurements();
+    TimestampedMeasurements {
+        frame_id: 1,
+        ts_unix_ms: 1_700_000_000_000,
+        derived: data.bq76920.derived(),
+        data,
+        battery: Default::default(),
+        raw: None,
+    }
+}
+
+fn fan_out(c: &mut Criterion) {
+    let sample = sample();
+    c.bench_function("fan_out_arc", |b| {
+        b.iter(|| {
+            let shared = Arc::new(black_box(&sample).clone());
+            black_box([(); CONSUMERS].map(|_| Arc::clone(&shared)))
+        })
+    });
+    c.bench_function("fan_out_clone", |b| b.iter(|| black_box([(); CONSUMERS].map(|_| black_box(&sample).clone()))));
+}
+
+fn publish(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let topics = TopicMap::default();
+    let sample = sample();
+    c.bench_function("publish_sample", |b| {
+        b.iter(|| runtime.block_on(publish_sample(&NullPublisher, "ups120", &topics, black_box(&sample))).unwrap())
+    });
+}
+
+criterion_group!(benches, parse, fan_out, publish);
+criterion_main!(benches);
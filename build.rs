@@ -0,0 +1,10 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    // 构建时间戳（Unix 秒），随 daemon/info 出生消息发布
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    println!("cargo:rustc-env=UPS120_BUILD_TIMESTAMP={}", ts);
+}
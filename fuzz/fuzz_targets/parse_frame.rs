@@ -0,0 +1,34 @@
+//! 任意字节经过分帧判断、解析、异常检查与转换都不能 panic。
+//!
+//! ```sh
+//! cargo +nightly fuzz run parse_frame
+//! ```
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ups120_daemon::conversion::{to_measurements, ConversionContext};
+use ups120_daemon::diagnostics::check_payload;
+use ups120_daemon::framing::{self, parse_frame};
+use ups120_daemon::usb_types::{ProtocolVersion, UsbData, SEQUENCE_PROTOCOL_VERSION};
+
+// 带与不带帧序号的两种协议版本都要覆盖
+const PROTOCOLS: [ProtocolVersion; 2] = [
+    ProtocolVersion::LEGACY,
+    ProtocolVersion { protocol: SEQUENCE_PROTOCOL_VERSION, firmware: None },
+];
+
+fuzz_target!(|frame: &[u8]| {
+    let _ = framing::frame_status(frame);
+    let _ = framing::hex_head(frame);
+    for protocol in &PROTOCOLS {
+        if let Ok((UsbData::StatusPush(payload) | UsbData::StatusResponse(payload), _)) = parse_frame(frame, protocol) {
+            let ctx = ConversionContext::default();
+            let _ = check_payload(&payload, ctx.cell_count);
+            let measurements = to_measurements::<5>(&payload, &ctx);
+            let _ = measurements.efficiency_percent(0.0);
+            let _ = measurements.bq76920.derived();
+            let _ = serde_json::to_string(&measurements);
+        }
+    }
+});
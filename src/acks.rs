@@ -0,0 +1,346 @@
+//! QoS 1 发布的 PubAck 跟踪。
+//!
+//! rumqttc 的 `publish` 只把请求放入队列，事件循环随后按队列顺序写出并分配 pkid，
+//! 事件中只有 pkid 没有主题。`track` 在客户端与事件循环之间插入一个转发任务：所有请求都经它
+//! 按顺序交给 rumqttc，转发 QoS 1 发布时分配递增的序号。事件循环按同样的顺序写出，写出事件
+//! 按先进先出对应到序号，之后再按 pkid 对应到 PubAck。经 `TrackedClient` 发布的消息带回执，
+//! 只认自己的序号，其他发布者穿插进来的消息不影响判断。
+//!
+//! 断线后 rumqttc 以原 pkid 重发未确认的发布，所以在途 pkid 再次写出视为重发，序号不变。
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, ClientError, Publish, QoS, Request};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tracing::debug;
+
+// `TrackedClient` 到转发任务的队列长度，可容纳几个样本的全部消息
+const TRACKED_QUEUE_SIZE: usize = 256;
+
+#[derive(Debug, Default)]
+struct AckState {
+    /// 已分配的最大序号
+    queued: u64,
+    /// 已交给 rumqttc、尚未写出的序号，按队列顺序
+    unwritten: VecDeque<u64>,
+    /// pkid -> (序号, 首次写出时间)
+    inflight: HashMap<u16, (u64, Instant)>,
+}
+
+impl AckState {
+    // 序号 0 表示不需要确认 (QoS 0)
+    fn is_acked(&self, seq: u64) -> bool {
+        seq <= self.queued && !self.unwritten.contains(&seq) && !self.inflight.values().any(|(s, _)| *s == seq)
+    }
+}
+
+/// 转发任务、事件循环与等待确认的发布者之间共享的 PubAck 状态
+#[derive(Debug, Default)]
+pub struct AckTracker {
+    state: Mutex<AckState>,
+    changed: watch::Sender<()>,
+}
+
+impl AckTracker {
+    /// 未确认的发布数
+    pub fn inflight(&self) -> usize {
+        self.state.lock().unwrap().inflight.len()
+    }
+
+    /// 订阅状态变化 (分配序号、写出、确认)
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    // 转发任务把一个发布交给 rumqttc 之前调用：QoS 1 分配序号，QoS 0 记为 0；回执在通知前写入
+    fn on_queued(&self, qos: QoS, receipt: Option<&Receipt>) {
+        let mut state = self.state.lock().unwrap();
+        let seq = if qos == QoS::AtMostOnce {
+            0
+        } else {
+            state.queued += 1;
+            let seq = state.queued;
+            state.unwritten.push_back(seq);
+            seq
+        };
+        if let Some(receipt) = receipt {
+            let _ = receipt.0.set(seq);
+        }
+        drop(state);
+        self.changed.send_replace(());
+    }
+
+    /// 事件循环写出一个 QoS 1 发布；pkid 仍在途时是重连后的重发
+    pub fn on_outgoing(&self, pkid: u16) {
+        let mut state = self.state.lock().unwrap();
+        if state.inflight.contains_key(&pkid) {
+            return;
+        }
+        match state.unwritten.pop_front() {
+            Some(seq) => {
+                state.inflight.insert(pkid, (seq, Instant::now()));
+            }
+            None => debug!(pkid, "写出的发布没有经过转发任务，不跟踪"),
+        }
+        drop(state);
+        self.changed.send_replace(());
+    }
+
+    /// 收到 PubAck；返回该发布自首次写出的往返时间
+    pub fn on_puback(&self, pkid: u16) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let (_, sent_at) = state.inflight.remove(&pkid)?;
+        drop(state);
+        self.changed.send_replace(());
+        Some(sent_at.elapsed())
+    }
+
+    /// 回执对应的发布已收到 PubAck；尚未交给 rumqttc 的返回 false
+    pub fn is_acked(&self, receipt: &Receipt) -> bool {
+        self.all_acked(std::slice::from_ref(receipt))
+    }
+
+    /// 一组回执对应的发布都已收到 PubAck
+    pub fn all_acked(&self, receipts: &[Receipt]) -> bool {
+        let state = self.state.lock().unwrap();
+        receipts.iter().all(|receipt| receipt.seq().is_some_and(|seq| state.is_acked(seq)))
+    }
+
+    /// 等待一组回执全部确认，超过 `deadline` 返回 false；期间断线的由 rumqttc 重连后重发
+    pub async fn wait_acked(&self, receipts: &[Receipt], deadline: Instant) -> bool {
+        let mut changed = self.subscribe();
+        loop {
+            changed.borrow_and_update();
+            if self.all_acked(receipts) {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, changed.changed()).await.is_err() {
+                return false;
+            }
+        }
+    }
+}
+
+/// 经 `TrackedClient` 发布的一条消息；转发任务把它交给 rumqttc 时写入序号
+#[derive(Debug, Clone, Default)]
+pub struct Receipt(Arc<OnceLock<u64>>);
+
+impl Receipt {
+    fn seq(&self) -> Option<u64> {
+        self.0.get().copied()
+    }
+}
+
+/// 发布时返回回执的客户端，回执交给 `AckTracker` 查询是否已确认
+#[derive(Debug, Clone)]
+pub struct TrackedClient {
+    tx: mpsc::Sender<(Publish, Receipt)>,
+    acks: Arc<AckTracker>,
+}
+
+impl TrackedClient {
+    pub fn acks(&self) -> &AckTracker {
+        &self.acks
+    }
+
+    /// 转发任务已退出 (事件循环已停止)
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// 不等待地发布；主题无效返回 `ClientError::Request`，队列已满或已关闭返回 `ClientError::TryRequest`
+    pub fn try_publish<S, V>(&self, topic: S, qos: QoS, retain: bool, payload: V) -> Result<Receipt, ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let publish = new_publish(topic, qos, retain, payload)?;
+        let receipt = Receipt::default();
+        self.tx.try_send((publish, receipt.clone())).map_err(|e| {
+            let (publish, _) = e.into_inner();
+            ClientError::TryRequest(Request::Publish(publish))
+        })?;
+        Ok(receipt)
+    }
+
+    /// 发布，队列已满时等待；语义与 `AsyncClient::publish` 一致
+    pub async fn publish<S, V>(&self, topic: S, qos: QoS, retain: bool, payload: V) -> Result<Receipt, ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let publish = new_publish(topic, qos, retain, payload)?;
+        let receipt = Receipt::default();
+        self.tx.send((publish, receipt.clone())).await.map_err(|e| {
+            let (publish, _) = e.0;
+            ClientError::Request(Request::Publish(publish))
+        })?;
+        Ok(receipt)
+    }
+}
+
+fn new_publish<S, V>(topic: S, qos: QoS, retain: bool, payload: V) -> Result<Publish, ClientError>
+where
+    S: Into<String>,
+    V: Into<Vec<u8>>,
+{
+    let mut publish = Publish::new(topic, qos, payload);
+    publish.retain = retain;
+    if !rumqttc::valid_topic(&publish.topic) {
+        return Err(ClientError::Request(Request::Publish(publish)));
+    }
+    Ok(publish)
+}
+
+/// 在 `inner` 与其事件循环之间插入转发任务，返回此后应使用的客户端与带回执的客户端。
+///
+/// 所有请求都必须经返回的两个客户端发出，`inner` 不应再直接使用；事件循环需要把写出与
+/// PubAck 事件交给 `acks`。`cap` 为返回的 `AsyncClient` 的请求队列容量。
+pub fn track(inner: AsyncClient, acks: Arc<AckTracker>, cap: usize) -> (AsyncClient, TrackedClient) {
+    let (request_tx, request_rx) = flume::bounded(cap);
+    let (tracked_tx, tracked_rx) = mpsc::channel(TRACKED_QUEUE_SIZE);
+    tokio::spawn(forward_requests(inner, request_rx, tracked_rx, acks.clone()));
+    (AsyncClient::from_senders(request_tx), TrackedClient { tx: tracked_tx, acks })
+}
+
+async fn forward_requests(
+    inner: AsyncClient,
+    requests: flume::Receiver<Request>,
+    mut tracked: mpsc::Receiver<(Publish, Receipt)>,
+    acks: Arc<AckTracker>,
+) {
+    loop {
+        let (request, receipt) = tokio::select! {
+            Ok(request) = requests.recv_async() => (request, None),
+            Some((publish, receipt)) = tracked.recv() => (Request::Publish(publish), Some(receipt)),
+            else => break,
+        };
+        let result = match request {
+            Request::Publish(publish) => {
+                // 序号必须在请求进入 rumqttc 队列之前分配，事件循环随时可能写出它
+                acks.on_queued(publish.qos, receipt.as_ref());
+                inner.publish_bytes(publish.topic, publish.qos, publish.retain, publish.payload).await
+            }
+            Request::Subscribe(subscribe) => inner.subscribe_many(subscribe.filters).await,
+            Request::Unsubscribe(unsubscribe) => {
+                let mut result = Ok(());
+                for topic in unsubscribe.topics {
+                    result = inner.unsubscribe(topic).await;
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                result
+            }
+            Request::Disconnect(_) => inner.disconnect().await,
+            other => {
+                debug!("转发任务忽略请求: {:?}", other);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            debug!("MQTT 事件循环已停止，转发任务退出: {:?}", e);
+            break;
+        }
+    }
+}
+
+/// 以 QoS 1 依次发布一组关键消息，并等待它们全部收到 PubAck，最长等到 `deadline`。
+///
+/// 只等待这一组消息自己的确认，期间其他任务的发布不影响结果。返回是否在期限内全部确认。
+pub async fn publish_and_confirm(
+    client: &TrackedClient,
+    messages: Vec<(String, bool, String)>,
+    deadline: Instant,
+) -> Result<bool, ClientError> {
+    let mut receipts = Vec::with_capacity(messages.len());
+    for (topic, retain, payload) in messages {
+        match tokio::time::timeout_at(deadline, client.publish(topic, QoS::AtLeastOnce, retain, payload)).await {
+            Ok(receipt) => receipts.push(receipt?),
+            Err(_) => return Ok(false),
+        }
+    }
+    Ok(client.acks().wait_acked(&receipts, deadline).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_broker::TestBroker;
+
+    #[test]
+    fn a_rewritten_inflight_pkid_is_a_retransmit() {
+        let acks = AckTracker::default();
+        let receipts = [Receipt::default(), Receipt::default(), Receipt::default()];
+        acks.on_queued(QoS::AtLeastOnce, Some(&receipts[0]));
+        acks.on_outgoing(1);
+        acks.on_queued(QoS::AtLeastOnce, Some(&receipts[1]));
+        acks.on_outgoing(2);
+        // 重连后以原 pkid 重发，不能占用后面发布的序号
+        acks.on_outgoing(1);
+        acks.on_queued(QoS::AtLeastOnce, Some(&receipts[2]));
+        assert_eq!(acks.inflight(), 2);
+        acks.on_puback(1);
+        assert!(acks.is_acked(&receipts[0]));
+        assert!(!acks.is_acked(&receipts[1]));
+        assert!(!acks.is_acked(&receipts[2]));
+        assert_eq!(acks.state.lock().unwrap().unwritten, VecDeque::from([3]));
+    }
+
+    #[tokio::test]
+    async fn confirmation_waits_for_its_own_messages_only() {
+        let broker = TestBroker::start().await;
+        let (client, tracked) = broker.connect_tracked("acks-own");
+        broker.withhold_acks("ups/offline");
+        // 其他任务持续发布并被确认；按写出计数关联时会把它们的确认当成自己的
+        let flood = tokio::spawn(async move {
+            for i in 0..50 {
+                client.publish("ups/other", QoS::AtLeastOnce, false, i.to_string()).await.unwrap();
+            }
+        });
+        let deadline = Instant::now() + Duration::from_millis(500);
+        let confirmed = publish_and_confirm(&tracked, vec![("ups/offline".into(), true, "offline".into())], deadline).await;
+        assert!(!confirmed.unwrap());
+        flood.await.unwrap();
+        assert!(broker.publishes().iter().any(|p| p.topic == "ups/other"));
+
+        broker.resume_acks();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let confirmed = publish_and_confirm(&tracked, vec![("ups/status".into(), false, "bye".into())], deadline).await;
+        assert!(confirmed.unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_message_acked_after_reconnect_is_confirmed() {
+        let broker = TestBroker::start().await;
+        let (_client, tracked) = broker.connect_tracked("acks-retransmit");
+        broker.withhold_acks("");
+        let receipt = tracked.publish("ups/offline", QoS::AtLeastOnce, true, "offline").await.unwrap();
+        assert!(broker.wait_for(Duration::from_secs(5), |p| p.len() == 1).await);
+        assert!(!tracked.acks().is_acked(&receipt));
+
+        broker.resume_acks();
+        broker.drop_connections();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert!(tracked.acks().wait_acked(std::slice::from_ref(&receipt), deadline).await);
+        let publishes = broker.publishes();
+        assert!(publishes.len() >= 2);
+        // 重发沿用原 pkid
+        assert_eq!(publishes[1].topic, "ups/offline");
+        assert_eq!(publishes[0].pkid, publishes[1].pkid);
+    }
+
+    #[tokio::test]
+    async fn qos0_and_invalid_topics_do_not_take_sequence_numbers() {
+        let broker = TestBroker::start().await;
+        let (_client, tracked) = broker.connect_tracked("acks-qos0");
+        let receipt = tracked.publish("ups/debug", QoS::AtMostOnce, false, "x").await.unwrap();
+        assert!(matches!(tracked.try_publish("ups/#", QoS::AtLeastOnce, false, "x"), Err(ClientError::Request(_))));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert!(tracked.acks().wait_acked(&[receipt], deadline).await);
+        assert_eq!(tracked.acks().state.lock().unwrap().queued, 0);
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use tracing::{error, info, warn};
+use rumqttc::{AsyncClient, Event, EventLoop, Outgoing, Packet, QoS, SubscribeReasonCode};
+use serde::Serialize;
+
+use crate::config::DaemonConfig;
+use crate::mqtt_handlers::mqtt_options;
+use crate::stats::DaemonStats;
+use crate::utils::unix_ms_now;
+
+/// 按 ACL 规则划分的主题类别，每类选一个有代表性的探测主题
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TopicClass {
+    Measurement,
+    Status,
+    Alert,
+    Availability,
+    Discovery,
+}
+
+impl TopicClass {
+    pub const ALL: [TopicClass; 5] = [
+        TopicClass::Measurement,
+        TopicClass::Status,
+        TopicClass::Alert,
+        TopicClass::Availability,
+        TopicClass::Discovery,
+    ];
+
+    /// 探测用的主题；统一放在 `{prefix}/_probe/` 之下，订阅真实主题的消费者不会收到探测消息
+    pub fn probe_topic(self, topic_prefix: &str, discovery_prefix: &str, client_id: &str) -> String {
+        match self {
+            TopicClass::Measurement => format!("{}/_probe/measurements_all", topic_prefix),
+            TopicClass::Status => format!("{}/_probe/daemon/stats", topic_prefix),
+            TopicClass::Alert => format!("{}/_probe/alerts", topic_prefix),
+            TopicClass::Availability => format!("{}/_probe/daemon/availability", topic_prefix),
+            TopicClass::Discovery => format!("{}/_probe/{}", discovery_prefix, client_id),
+        }
+    }
+}
+
+/// 单个主题类别的探测结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeOutcome {
+    /// 回显收到，发布确实被 broker 接受
+    Allowed,
+    /// 未收到 PubAck，或订阅成功却收不到自己的回显（broker 静默丢弃）
+    Denied,
+    /// 收到 PubAck 但无法订阅回显，不能确认
+    Unverified,
+}
+
+impl ProbeOutcome {
+    /// 由观察到的 PubAck / SubAck / 回显推断结果
+    pub fn classify(puback: bool, subscribed: Option<bool>, echoed: bool) -> Self {
+        if echoed {
+            ProbeOutcome::Allowed
+        } else if !puback || subscribed == Some(true) {
+            ProbeOutcome::Denied
+        } else {
+            ProbeOutcome::Unverified
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AclProbeReport {
+    pub ts_unix_ms: u64,
+    pub classes: BTreeMap<TopicClass, ProbeOutcome>,
+}
+
+impl AclProbeReport {
+    pub fn denied(&self) -> Vec<TopicClass> {
+        self.classes
+            .iter()
+            .filter(|(_, outcome)| **outcome == ProbeOutcome::Denied)
+            .map(|(class, _)| *class)
+            .collect()
+    }
+}
+
+pub fn acl_probe_topic(topic_prefix: &str) -> String {
+    format!("{}/daemon/acl_probe", topic_prefix)
+}
+
+/// 使用独立的临时连接（同一用户名，client id 加 `-acl-probe` 后缀）逐类探测发布权限
+pub async fn run_acl_probe(config: &DaemonConfig) -> Result<AclProbeReport, Box<dyn std::error::Error>> {
+    let client_id = format!("{}-acl-probe", config.mqtt_client_id);
+    let (client, mut eventloop) = AsyncClient::new(mqtt_options(config, &client_id)?, 10);
+    let timeout = config.acl_probe_timeout;
+
+    tokio::time::timeout(timeout, async {
+        loop {
+            if let Event::Incoming(Packet::ConnAck(_)) = eventloop.poll().await? {
+                return Ok::<(), rumqttc::ConnectionError>(());
+            }
+        }
+    })
+    .await
+    .map_err(|_| "ACL probe: no ConnAck")??;
+
+    let mut classes = BTreeMap::new();
+    for class in TopicClass::ALL {
+        let topic = class.probe_topic(&config.mqtt_topic_prefix, &config.discovery_prefix, &config.mqtt_client_id);
+        let outcome = probe_topic(&client, &mut eventloop, &topic, timeout).await;
+        info!("ACL 探测 {:?} ({}): {:?}", class, topic, outcome);
+        classes.insert(class, outcome);
+    }
+
+    if client.disconnect().await.is_ok() {
+        let _ = tokio::time::timeout(Duration::from_secs(1), eventloop.poll()).await;
+    }
+    Ok(AclProbeReport {
+        ts_unix_ms: unix_ms_now(),
+        classes,
+    })
+}
+
+/// 探测单个主题：订阅、发布非 retained 的探测消息并等待回显，结束后退订并清除探测主题
+async fn probe_topic(client: &AsyncClient, eventloop: &mut EventLoop, topic: &str, timeout: Duration) -> ProbeOutcome {
+    let canary = format!("acl_probe {}", unix_ms_now());
+    if client.subscribe(topic, QoS::AtLeastOnce).await.is_err()
+        || client.publish(topic, QoS::AtLeastOnce, false, canary.clone()).await.is_err()
+    {
+        return ProbeOutcome::Denied;
+    }
+
+    let (mut subscribed, mut puback, mut echoed) = (None, false, false);
+    let mut publish_pkid = None;
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    // 收到 PubAck 且回显已到或已确认无法订阅回显时即可提前结束
+    while !(puback && (echoed || subscribed == Some(false))) {
+        tokio::select! {
+            _ = &mut deadline => break,
+            event = eventloop.poll() => match event {
+                Ok(Event::Incoming(Packet::SubAck(ack))) => {
+                    subscribed = Some(ack.return_codes.iter().all(|c| !matches!(c, SubscribeReasonCode::Failure)));
+                }
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) => publish_pkid = Some(pkid),
+                Ok(Event::Incoming(Packet::PubAck(ack))) if Some(ack.pkid) == publish_pkid => puback = true,
+                Ok(Event::Incoming(Packet::Publish(p))) if p.topic == topic && p.payload == canary.as_bytes() => {
+                    echoed = true;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // 部分 broker 对越权发布直接断开连接；下一次 poll 会自动重连
+                    warn!("ACL 探测 {} 时连接断开: {:?}", topic, e);
+                    return ProbeOutcome::Denied;
+                }
+            }
+        }
+    }
+    let _ = client.unsubscribe(topic).await;
+    if puback || echoed {
+        clear_probe_topic(client, eventloop, topic, timeout).await;
+    }
+    ProbeOutcome::classify(puback, subscribed, echoed)
+}
+
+/// 向探测主题发布空的 retained 消息，清掉 broker 或桥接可能留下的探测消息
+async fn clear_probe_topic(client: &AsyncClient, eventloop: &mut EventLoop, topic: &str, timeout: Duration) {
+    if client.publish(topic, QoS::AtLeastOnce, true, Vec::new()).await.is_err() {
+        return;
+    }
+    let mut publish_pkid = None;
+    let cleared = tokio::time::timeout(timeout, async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) => publish_pkid = Some(pkid),
+                Ok(Event::Incoming(Packet::PubAck(ack))) if Some(ack.pkid) == publish_pkid => return true,
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+    })
+    .await;
+    if cleared != Ok(true) {
+        warn!("清除 ACL 探测主题 {} 未得到确认", topic);
+    }
+}
+
+/// 执行探测并发布结果到 `{prefix}/daemon/acl_probe` (retained)，被拒绝的类别数记入统计
+pub async fn acl_probe_task(config: DaemonConfig, client: AsyncClient, stats: Arc<DaemonStats>) {
+    let report = match run_acl_probe(&config).await {
+        Ok(report) => report,
+        Err(e) => {
+            error!("ACL 探测失败: {:?}", e);
+            return;
+        }
+    };
+    let denied = report.denied();
+    stats.acl_denied_classes.store(denied.len() as u64, Ordering::Relaxed);
+    if denied.is_empty() {
+        info!("ACL 探测完成，所有主题类别均可发布。");
+    } else {
+        warn!("ACL 探测：以下主题类别的发布被 broker 拒绝: {:?}", denied);
+    }
+    match serde_json::to_string(&report) {
+        Ok(payload) => {
+            if let Err(e) = client
+                .publish(acl_probe_topic(&config.mqtt_topic_prefix), QoS::AtLeastOnce, true, payload)
+                .await
+            {
+                error!("发布 ACL 探测结果失败: {:?}", e);
+            }
+        }
+        Err(e) => error!("序列化 ACL 探测结果失败: {:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_broker::TestBroker;
+
+    async fn connect(broker: &TestBroker) -> (AsyncClient, EventLoop) {
+        let (client, mut eventloop) = AsyncClient::new(broker.options("probe"), 10);
+        while !matches!(eventloop.poll().await.unwrap(), Event::Incoming(Packet::ConnAck(_))) {}
+        (client, eventloop)
+    }
+
+    #[test]
+    fn probe_topics_live_under_the_probe_subtree() {
+        for class in TopicClass::ALL {
+            let topic = class.probe_topic("ups", "homeassistant", "ups120");
+            assert!(topic.starts_with("ups/_probe/") || topic == "homeassistant/_probe/ups120", "{}", topic);
+        }
+        assert_eq!(TopicClass::Measurement.probe_topic("ups", "ha", "id"), "ups/_probe/measurements_all");
+    }
+
+    #[tokio::test]
+    async fn canaries_are_not_retained_and_are_cleared_afterwards() {
+        let broker = TestBroker::start().await;
+        let (client, mut eventloop) = connect(&broker).await;
+        let topic = TopicClass::Status.probe_topic("ups", "homeassistant", "ups120");
+        let outcome = probe_topic(&client, &mut eventloop, &topic, Duration::from_secs(2)).await;
+        assert_eq!(outcome, ProbeOutcome::Allowed);
+
+        let publishes = broker.publishes();
+        assert_eq!(publishes.len(), 2);
+        assert!(publishes.iter().all(|p| p.topic == topic));
+        assert!(publishes[0].payload.starts_with(b"acl_probe "));
+        assert!(!publishes[0].retain);
+        // 空的 retained 消息清除探测主题
+        assert!(publishes[1].payload.is_empty());
+        assert!(publishes[1].retain);
+    }
+
+    #[tokio::test]
+    async fn silently_dropped_canaries_are_denied_and_not_cleared() {
+        let broker = TestBroker::start().await;
+        broker.deny("ups/_probe/alerts");
+        let (client, mut eventloop) = connect(&broker).await;
+        let topic = TopicClass::Alert.probe_topic("ups", "homeassistant", "ups120");
+        let outcome = probe_topic(&client, &mut eventloop, &topic, Duration::from_millis(300)).await;
+        assert_eq!(outcome, ProbeOutcome::Denied);
+        assert_eq!(broker.publishes().len(), 1);
+    }
+
+    #[test]
+    fn outcomes_follow_puback_suback_and_echo() {
+        assert_eq!(ProbeOutcome::classify(true, Some(true), true), ProbeOutcome::Allowed);
+        // 回显说明发布已被转发，即使 PubAck 丢失
+        assert_eq!(ProbeOutcome::classify(false, None, true), ProbeOutcome::Allowed);
+        assert_eq!(ProbeOutcome::classify(false, Some(false), false), ProbeOutcome::Denied);
+        assert_eq!(ProbeOutcome::classify(true, Some(true), false), ProbeOutcome::Denied);
+        assert_eq!(ProbeOutcome::classify(true, Some(false), false), ProbeOutcome::Unverified);
+        assert_eq!(ProbeOutcome::classify(true, None, false), ProbeOutcome::Unverified);
+    }
+
+    #[tokio::test]
+    async fn only_the_rejected_classes_are_reported_denied() {
+        let broker = TestBroker::start().await;
+        broker.deny("ups/_probe/alerts");
+        broker.deny("homeassistant/_probe/");
+        let (client, mut eventloop) = connect(&broker).await;
+        let mut classes = BTreeMap::new();
+        for class in TopicClass::ALL {
+            let topic = class.probe_topic("ups", "homeassistant", "ups120");
+            classes.insert(class, probe_topic(&client, &mut eventloop, &topic, Duration::from_millis(500)).await);
+        }
+        let report = AclProbeReport { ts_unix_ms: 0, classes };
+        assert_eq!(report.denied(), [TopicClass::Alert, TopicClass::Discovery]);
+        assert_eq!(report.classes[&TopicClass::Measurement], ProbeOutcome::Allowed);
+
+        let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["classes"]["alert"], "denied");
+        assert_eq!(json["classes"]["availability"], "allowed");
+    }
+}
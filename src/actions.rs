@@ -0,0 +1,411 @@
+//! 电源事件动作：配置文件 `[actions]` 把电源事件映射为 shell 命令，用于在电池耗尽前关机等。
+//!
+//! - `on_battery`：`ChargerStatusFlags::STAT_AC` 清除（适配器断开，由电池供电）
+//! - `on_mains_restored`：`STAT_AC` 重新置位
+//! - `low_battery` / `critical_battery`：电池供电时 SOC 或 VBAT 降到对应阈值以下
+//!
+//! 每个状态需持续 `debounce_secs`（按样本时间戳计）才确认，适配器接触不良反复插拔时不会重复执行。
+//! 每次状态变化只执行一次命令；电量回到阈值以上或恢复市电后重新计数。
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::process::Command;
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
+
+use crate::config::ConfigError;
+use crate::data_models::{ChargerStatusFlags, TimestampedMeasurements};
+use crate::pipeline::Subscription;
+use crate::usb_types::DeviceId;
+
+/// 配置文件中的 `[actions]`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ActionsConfig {
+    pub on_battery: Option<String>,
+    pub on_mains_restored: Option<String>,
+    pub low_battery: Option<String>,
+    pub critical_battery: Option<String>,
+    /// 低电量的 SOC 阈值 (%)
+    #[serde(default = "default_low_soc_pct")]
+    pub low_soc_pct: f32,
+    /// 严重低电量的 SOC 阈值 (%)
+    #[serde(default = "default_critical_soc_pct")]
+    pub critical_soc_pct: f32,
+    /// 低电量的 VBAT 阈值 (V)；与 SOC 阈值任一满足即视为低电量
+    pub low_vbat: Option<f32>,
+    pub critical_vbat: Option<f32>,
+    /// 状态需持续该时间才执行命令
+    #[serde(default = "default_debounce_secs")]
+    pub debounce_secs: u64,
+    /// 命令的最长执行时间，超时后终止
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_low_soc_pct() -> f32 {
+    20.0
+}
+
+fn default_critical_soc_pct() -> f32 {
+    10.0
+}
+
+fn default_debounce_secs() -> u64 {
+    5
+}
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+impl ActionsConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &'static str, value: String, reason: &str| ConfigError::Invalid {
+            key,
+            value,
+            reason: reason.to_string(),
+        };
+        if !(0.0..=100.0).contains(&self.critical_soc_pct) || !(0.0..=100.0).contains(&self.low_soc_pct) {
+            return Err(invalid(
+                "actions.low_soc_pct",
+                format!("{}/{}", self.low_soc_pct, self.critical_soc_pct),
+                "SOC thresholds must be within 0..=100",
+            ));
+        }
+        if self.critical_soc_pct > self.low_soc_pct {
+            return Err(invalid(
+                "actions.critical_soc_pct",
+                self.critical_soc_pct.to_string(),
+                "must not exceed low_soc_pct",
+            ));
+        }
+        if let (Some(low), Some(critical)) = (self.low_vbat, self.critical_vbat)
+            && critical > low
+        {
+            return Err(invalid("actions.critical_vbat", critical.to_string(), "must not exceed low_vbat"));
+        }
+        if self.timeout_secs == 0 {
+            return Err(invalid("actions.timeout_secs", "0".to_string(), "must be positive"));
+        }
+        Ok(())
+    }
+
+    /// 是否配置了任一命令
+    pub fn is_empty(&self) -> bool {
+        self.command(PowerEvent::OnBattery).is_none()
+            && self.command(PowerEvent::MainsRestored).is_none()
+            && self.command(PowerEvent::LowBattery).is_none()
+            && self.command(PowerEvent::CriticalBattery).is_none()
+    }
+
+    pub fn command(&self, event: PowerEvent) -> Option<&str> {
+        let command = match event {
+            PowerEvent::OnBattery => &self.on_battery,
+            PowerEvent::MainsRestored => &self.on_mains_restored,
+            PowerEvent::LowBattery => &self.low_battery,
+            PowerEvent::CriticalBattery => &self.critical_battery,
+        };
+        command.as_deref().filter(|c| !c.trim().is_empty())
+    }
+
+    fn level(&self, soc_percent: Option<f32>, vbat: f32) -> BatteryLevel {
+        let below = |soc_pct: f32, vbat_limit: Option<f32>| {
+            soc_percent.is_some_and(|soc| soc <= soc_pct) || vbat_limit.is_some_and(|limit| vbat <= limit)
+        };
+        if below(self.critical_soc_pct, self.critical_vbat) {
+            BatteryLevel::Critical
+        } else if below(self.low_soc_pct, self.low_vbat) {
+            BatteryLevel::Low
+        } else {
+            BatteryLevel::Normal
+        }
+    }
+}
+
+/// 触发命令的电源事件
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerEvent {
+    OnBattery,
+    MainsRestored,
+    LowBattery,
+    CriticalBattery,
+}
+
+impl PowerEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PowerEvent::OnBattery => "on_battery",
+            PowerEvent::MainsRestored => "on_mains_restored",
+            PowerEvent::LowBattery => "low_battery",
+            PowerEvent::CriticalBattery => "critical_battery",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum BatteryLevel {
+    Normal,
+    Low,
+    Critical,
+}
+
+/// 去抖后的状态：新值需持续 `debounce` 才替换当前值
+#[derive(Debug)]
+struct Debounced<T> {
+    state: T,
+    candidate: Option<(T, u64)>,
+}
+
+impl<T: Copy + PartialEq> Debounced<T> {
+    fn new(initial: T) -> Self {
+        Debounced { state: initial, candidate: None }
+    }
+
+    /// 确认切换时返回 `(旧值, 新值)`
+    fn update(&mut self, value: T, ts_unix_ms: u64, debounce: Duration) -> Option<(T, T)> {
+        if value == self.state {
+            self.candidate = None;
+            return None;
+        }
+        let since = match self.candidate {
+            Some((candidate, since)) if candidate == value => since,
+            _ => {
+                self.candidate = Some((value, ts_unix_ms));
+                ts_unix_ms
+            }
+        };
+        if ts_unix_ms.saturating_sub(since) < debounce.as_millis() as u64 {
+            return None;
+        }
+        self.candidate = None;
+        let previous = std::mem::replace(&mut self.state, value);
+        Some((previous, value))
+    }
+}
+
+/// 由样本序列得出电源事件的状态机，与总线解耦。初始状态视为市电供电、电量正常，
+/// 启动时已由电池供电的情况同样会触发 `on_battery`。
+#[derive(Debug)]
+pub struct PowerEventDetector {
+    config: ActionsConfig,
+    on_battery: Debounced<bool>,
+    level: Debounced<BatteryLevel>,
+}
+
+impl PowerEventDetector {
+    pub fn new(config: ActionsConfig) -> Self {
+        PowerEventDetector {
+            config,
+            on_battery: Debounced::new(false),
+            level: Debounced::new(BatteryLevel::Normal),
+        }
+    }
+
+    pub fn update(&mut self, sample: &TimestampedMeasurements) -> Vec<PowerEvent> {
+        let debounce = Duration::from_secs(self.config.debounce_secs);
+        let ts = sample.ts_unix_ms;
+        let on_battery = !sample.data.bq25730_alerts.charger_status_flags.contains(ChargerStatusFlags::STAT_AC);
+        let mut events = Vec::new();
+        match self.on_battery.update(on_battery, ts, debounce) {
+            Some((_, true)) => events.push(PowerEvent::OnBattery),
+            Some((_, false)) => events.push(PowerEvent::MainsRestored),
+            None => {}
+        }
+        // 市电供电时电量低是在充电，不触发低电量动作
+        let level = if self.on_battery.state {
+            self.config.level(sample.battery.soc_percent, sample.data.bq25730.vbat)
+        } else {
+            BatteryLevel::Normal
+        };
+        if let Some((previous, level)) = self.level.update(level, ts, debounce)
+            && level > previous
+        {
+            events.push(match level {
+                BatteryLevel::Critical => PowerEvent::CriticalBattery,
+                _ => PowerEvent::LowBattery,
+            });
+        }
+        events
+    }
+}
+
+/// 执行一个动作命令并记录退出状态；超时后终止子进程
+pub async fn run_action(command: &str, event: PowerEvent, device: &DeviceId, sample: &TimestampedMeasurements, timeout: Duration) {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+    cmd.env("UPS120_EVENT", event.as_str())
+        .env("UPS120_DEVICE", device.to_string())
+        .env("UPS120_VBAT", sample.data.bq25730.vbat.to_string())
+        .env(
+            "UPS120_SOC",
+            sample.battery.soc_percent.map(|soc| soc.to_string()).unwrap_or_default(),
+        )
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            error!(event = event.as_str(), command, error = %e, "动作命令无法启动");
+            return;
+        }
+    };
+    match tokio::time::timeout(timeout, child.wait()).await {
+        Ok(Ok(status)) if status.success() => info!(event = event.as_str(), command, "动作命令执行成功"),
+        Ok(Ok(status)) => warn!(event = event.as_str(), command, status = %status, "动作命令返回失败"),
+        Ok(Err(e)) => error!(event = event.as_str(), command, error = %e, "等待动作命令失败"),
+        Err(_) => {
+            warn!(event = event.as_str(), command, timeout_secs = timeout.as_secs(), "动作命令超时，终止");
+            if let Err(e) = child.kill().await {
+                error!(event = event.as_str(), error = %e, "终止动作命令失败");
+            }
+        }
+    }
+}
+
+/// 按电源事件执行 `[actions]` 中的命令；命令在独立任务中执行，不阻塞后续样本的处理
+pub async fn actions_task(mut samples: Subscription, device: DeviceId, config: ActionsConfig) {
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let mut detector = PowerEventDetector::new(config.clone());
+    loop {
+        let sample = match samples.recv().await {
+            Ok(sample) => sample,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("电源事件处理过慢，跳过 {} 帧", n);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        for event in detector.update(&sample) {
+            info!(device = %device, event = event.as_str(), soc = ?sample.battery.soc_percent, vbat = sample.data.bq25730.vbat, "电源事件");
+            let Some(command) = config.command(event) else {
+                continue;
+            };
+            let command = command.to_string();
+            let device = device.clone();
+            let sample = sample.clone();
+            tokio::spawn(async move {
+                run_action(&command, event, &device, &sample, timeout).await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::pipeline::Pipeline;
+    use crate::test_support::PayloadBuilder;
+
+    fn config(toml: &str) -> ActionsConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn sample(ts_unix_ms: u64, mains: bool, soc: f32) -> TimestampedMeasurements {
+        let flags = if mains { ChargerStatusFlags::STAT_AC } else { ChargerStatusFlags::empty() };
+        let mut sample = PayloadBuilder::new().charger_flags(flags).sample(ts_unix_ms, ts_unix_ms);
+        sample.battery.soc_percent = Some(soc);
+        sample
+    }
+
+    /// 每秒一帧，依次喂入 `(持续秒数, 市电, SOC)`，返回全部事件
+    fn feed(detector: &mut PowerEventDetector, phases: &[(u64, bool, f32)]) -> Vec<PowerEvent> {
+        let mut ts = 0;
+        let mut events = Vec::new();
+        for &(secs, mains, soc) in phases {
+            for _ in 0..secs {
+                events.extend(detector.update(&sample(ts, mains, soc)));
+                ts += 1000;
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn a_flickering_adapter_does_not_trigger() {
+        let mut detector = PowerEventDetector::new(config("debounce_secs = 5"));
+        let flicker: Vec<_> = (0..20).map(|i| (3, i % 2 == 0, 80.0)).collect();
+        assert!(feed(&mut detector, &flicker).is_empty());
+    }
+
+    #[test]
+    fn each_transition_fires_once_after_the_debounce() {
+        let mut detector = PowerEventDetector::new(config("debounce_secs = 5"));
+        let events = feed(
+            &mut detector,
+            &[(10, true, 80.0), (30, false, 80.0), (30, false, 15.0), (30, false, 8.0), (30, true, 8.0)],
+        );
+        assert_eq!(
+            events,
+            [PowerEvent::OnBattery, PowerEvent::LowBattery, PowerEvent::CriticalBattery, PowerEvent::MainsRestored]
+        );
+    }
+
+    #[test]
+    fn low_battery_is_ignored_on_mains_and_vbat_counts_too() {
+        let mut detector = PowerEventDetector::new(config("debounce_secs = 0\nlow_vbat = 19.0"));
+        assert!(feed(&mut detector, &[(10, true, 5.0)]).is_empty());
+        // 默认样本 VBAT 为 18.5 V，低于 low_vbat；SOC 正常
+        assert_eq!(feed(&mut detector, &[(3, false, 90.0)]), [PowerEvent::OnBattery, PowerEvent::LowBattery]);
+    }
+
+    #[test]
+    fn thresholds_are_validated() {
+        assert!(config("").validate().is_ok());
+        assert!(config("low_soc_pct = 10.0\ncritical_soc_pct = 20.0").validate().is_err());
+        assert!(config("low_soc_pct = 120.0").validate().is_err());
+        assert!(config("low_vbat = 15.0\ncritical_vbat = 16.0").validate().is_err());
+        assert!(config("timeout_secs = 0").validate().is_err());
+        assert!(config("on_battery = \"  \"").is_empty());
+        assert!(toml::from_str::<ActionsConfig>("on_batery = \"x\"").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn the_command_runs_exactly_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("on_battery");
+        let config = config(&format!(
+            "on_battery = \"echo $UPS120_EVENT >> '{}'\"\ndebounce_secs = 5",
+            marker.display()
+        ));
+        let pipeline = Pipeline::new(256);
+        let task = tokio::spawn(actions_task(pipeline.subscribe(), DeviceId::Serial("UPS01".to_string()), config));
+        let mut ts = 0;
+        // 适配器抖动，随后真正断电 20 秒
+        for mains in (0..10).map(|i| i % 2 == 0).chain(std::iter::repeat_n(false, 20)) {
+            pipeline.publish(Arc::new(sample(ts, mains, 80.0)));
+            ts += 1000;
+        }
+        drop(pipeline);
+        task.await.unwrap();
+        for _ in 0..100 {
+            if marker.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(std::fs::read_to_string(&marker).unwrap(), "on_battery\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn slow_commands_are_killed_at_the_timeout() {
+        let started = std::time::Instant::now();
+        let sample = sample(0, false, 80.0);
+        run_action("sleep 5", PowerEvent::OnBattery, &DeviceId::Serial("UPS01".to_string()), &sample, Duration::from_millis(100)).await;
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
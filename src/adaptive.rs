@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+/// 自适应发布间隔控制器 (AIMD)。
+///
+/// Broker 变慢（PubAck 往返时间超过目标或未确认消息积压）时间隔成倍放大，
+/// 恢复后按固定步长逐步收窄，始终限制在 `[min, max]` 之内。
+#[derive(Debug, Clone)]
+pub struct AimdController {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+    target_latency: Duration,
+    max_backlog: u64,
+    step: Duration,
+}
+
+impl AimdController {
+    pub fn new(min: Duration, max: Duration, target_latency: Duration, max_backlog: u64, step: Duration) -> Self {
+        let max = max.max(min);
+        AimdController {
+            min,
+            max,
+            current: min,
+            target_latency,
+            max_backlog,
+            step,
+        }
+    }
+
+    pub fn current(&self) -> Duration {
+        self.current
+    }
+
+    /// 输入一次观测值，返回调整后的间隔（未变化时返回 None）
+    pub fn observe(&mut self, latency: Duration, backlog: u64) -> Option<Duration> {
+        let congested = latency > self.target_latency || backlog > self.max_backlog;
+        let next = if congested {
+            // 乘性放大；从 0 起步时至少放大一个步长
+            (self.current * 2).max(self.current + self.step).min(self.max)
+        } else {
+            self.current.saturating_sub(self.step).max(self.min)
+        };
+        if next == self.current {
+            return None;
+        }
+        self.current = next;
+        Some(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn controller() -> AimdController {
+        AimdController::new(MS * 100, MS * 5000, MS * 200, 20, MS * 100)
+    }
+
+    // 逐个输入 (往返时间 ms, 积压) 观测值，返回每一步之后的间隔 (ms)
+    fn run(controller: &mut AimdController, series: &[(u64, u64)]) -> Vec<u64> {
+        series
+            .iter()
+            .map(|&(latency_ms, backlog)| {
+                controller.observe(MS * latency_ms as u32, backlog);
+                controller.current().as_millis() as u64
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_slow_broker_widens_the_interval_multiplicatively_up_to_max() {
+        let mut c = controller();
+        assert_eq!(run(&mut c, &[(500, 0); 7]), [200, 400, 800, 1600, 3200, 5000, 5000]);
+    }
+
+    #[test]
+    fn recovery_narrows_the_interval_additively_down_to_min() {
+        let mut c = controller();
+        run(&mut c, &[(500, 0); 3]);
+        assert_eq!(c.current(), MS * 800);
+        assert_eq!(run(&mut c, &[(50, 0); 9]), [700, 600, 500, 400, 300, 200, 100, 100, 100]);
+    }
+
+    #[test]
+    fn backlog_alone_counts_as_congestion() {
+        let mut c = controller();
+        assert_eq!(c.observe(MS * 10, 21), Some(MS * 200));
+        assert_eq!(c.observe(MS * 10, 20), Some(MS * 100));
+        assert_eq!(c.observe(MS * 10, 0), None);
+    }
+
+    #[test]
+    fn a_latency_spike_is_undone_gradually() {
+        let mut c = controller();
+        let series = [(50, 0), (900, 40), (50, 0), (50, 0), (900, 0), (50, 0)];
+        assert_eq!(run(&mut c, &series), [100, 200, 100, 100, 200, 100]);
+    }
+
+    #[test]
+    fn a_zero_minimum_still_widens() {
+        let mut c = AimdController::new(Duration::ZERO, MS * 1000, MS * 200, 20, MS * 100);
+        assert_eq!(c.observe(MS * 500, 0), Some(MS * 100));
+        // 上限小于下限时按下限处理
+        let c = AimdController::new(MS * 300, MS * 100, MS * 200, 20, MS * 100);
+        assert_eq!(c.current(), MS * 300);
+    }
+}
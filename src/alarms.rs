@@ -0,0 +1,964 @@
+//! 阈值告警。
+//!
+//! 每条规则按扁平键（与 `fields::flatten` 相同，如 `derived.cell_min`、`battery.soc_percent`）
+//! 取一个数值与阈值比较。越过阈值即激活，回到阈值另一侧超过滞回量才清除，
+//! 数值在阈值附近抖动时不会反复触发。
+//!
+//! 设置了 `window_secs` 的规则是变化率规则：比较的是窗口内的变化量（最新值减去窗口起点的值），
+//! 用于捕捉短路时单体电压骤降这类绝对阈值来不及反映的事件。两帧间隔远大于平常（USB 重连、
+//! 设备停推后恢复）时清空各规则的窗口，断档前后的数值不会被当作一次跳变。
+//!
+//! 向 `{prefix}/alarms/<name>/set` 发布 `ack` 可确认一条激活中的告警：告警主题重新发布并带上
+//! `acknowledged: true` 与确认人、时间，之后清除时不再通知 webhook。告警清除或重新激活时确认自动失效。
+
+use std::collections::VecDeque;
+#[cfg(feature = "mqtt")]
+use std::path::PathBuf;
+#[cfg(feature = "mqtt")]
+use std::sync::Arc;
+
+#[cfg(feature = "mqtt")]
+use tracing::{error, info, warn};
+#[cfg(feature = "mqtt")]
+use rumqttc::{AsyncClient, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+#[cfg(feature = "mqtt")]
+use tokio::sync::{broadcast, mpsc, watch};
+
+use crate::config::ConfigError;
+use crate::data_models::{Severity, TimestampedMeasurements};
+#[cfg(feature = "mqtt")]
+use crate::http_api::ActiveAlarms;
+#[cfg(feature = "mqtt")]
+use crate::pipeline::Subscription;
+#[cfg(feature = "mqtt")]
+use crate::ring::History;
+use crate::topics;
+#[cfg(feature = "mqtt")]
+use crate::topics::{TopicMap, Topics};
+#[cfg(feature = "mqtt")]
+use crate::usb_types::DeviceId;
+#[cfg(feature = "mqtt")]
+use crate::utils;
+#[cfg(feature = "mqtt")]
+use crate::webhook::{WebhookEvent, WebhookHandle};
+
+// 两帧间隔超过平常间隔的这么多倍时视为断档
+const GAP_FACTOR: u64 = 3;
+// 平常间隔的下限，避免突发的密集帧把断档判定收得过紧
+const MIN_INTERVAL_MS: u64 = 200;
+
+pub fn alarm_topic(topic_prefix: &str, name: &str) -> String {
+    format!("{}/{}/{}", topic_prefix, topics::ALARMS, name)
+}
+
+pub fn active_alarms_topic(topic_prefix: &str) -> String {
+    format!("{}/{}", topic_prefix, topics::ACTIVE_ALARMS)
+}
+
+pub fn alarm_context_topic(topic_prefix: &str, name: &str) -> String {
+    format!("{}/{}/{}/context", topic_prefix, topics::ALARMS, name)
+}
+
+/// 确认命令的订阅：`{prefix}/alarms/<name>/set`，以及按设备发布时的 `{prefix}/<设备>/alarms/<name>/set`
+pub fn alarm_set_topic_filters(topic_prefix: &str) -> [String; 2] {
+    [format!("{}/alarms/+/set", topic_prefix), format!("{}/+/alarms/+/set", topic_prefix)]
+}
+
+/// 从确认命令的主题中取出告警所在的主题前缀与告警名
+pub fn parse_alarm_set_topic(topic_prefix: &str, topic: &str) -> Option<(String, String)> {
+    let (alarm_prefix, name) = topic.strip_suffix("/set")?.rsplit_once("/alarms/")?;
+    let device_level = match alarm_prefix.strip_prefix(topic_prefix)? {
+        "" => true,
+        rest => rest.strip_prefix('/').is_some_and(|device| !device.is_empty() && !device.contains('/')),
+    };
+    (device_level && !name.is_empty() && !name.contains('/')).then(|| (alarm_prefix.to_string(), name.to_string()))
+}
+
+/// 一次告警确认
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlarmAck {
+    /// 确认人；载荷未提供时省略
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub by: Option<String>,
+    /// 确认时间；载荷未提供时为收到命令的时间
+    pub ts_unix_ms: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AckPayload {
+    ack: bool,
+    by: Option<String>,
+    ts_unix_ms: Option<u64>,
+}
+
+impl AlarmAck {
+    /// 解析 `{prefix}/alarms/<name>/set` 的载荷：`ack`、`ack <确认人>`，或 JSON
+    /// `{"ack": true, "by": "...", "ts_unix_ms": ...}`；不是确认命令时返回 None
+    pub fn parse(payload: &[u8], now_unix_ms: u64) -> Option<Self> {
+        let text = String::from_utf8_lossy(payload);
+        let text = text.trim();
+        if text.starts_with('{') {
+            let payload: AckPayload = serde_json::from_str(text).ok()?;
+            let by = payload.by.filter(|by| !by.trim().is_empty());
+            return payload.ack.then(|| AlarmAck { by, ts_unix_ms: payload.ts_unix_ms.unwrap_or(now_unix_ms) });
+        }
+        let (command, by) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let by = by.trim();
+        command.eq_ignore_ascii_case("ack").then(|| AlarmAck {
+            by: (!by.is_empty()).then(|| by.to_string()),
+            ts_unix_ms: now_unix_ms,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    /// 大于阈值时激活
+    Above,
+    /// 小于阈值时激活
+    Below,
+    /// 绝对值大于阈值时激活（双向电流）
+    AbsAbove,
+}
+
+/// 一条告警规则，来自配置文件 `[[alarms]]`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlarmRule {
+    /// 主题名 `{prefix}/alarms/<name>`
+    pub name: String,
+    /// 扁平键，相对于 `measurements_all` 的 JSON
+    pub field: String,
+    pub comparison: Comparison,
+    /// 阈值；变化率规则中为窗口内变化量的限值，也可写作 `delta`
+    #[serde(alias = "delta")]
+    pub value: f64,
+    #[serde(default)]
+    pub hysteresis: f64,
+    #[serde(default = "default_severity")]
+    pub severity: Severity,
+    /// 设置时为变化率规则，比较窗口内的变化量
+    #[serde(default)]
+    pub window_secs: Option<f64>,
+}
+
+fn default_severity() -> Severity {
+    Severity::Warning
+}
+
+impl AlarmRule {
+    fn new(name: &str, field: &str, comparison: Comparison, value: f64, hysteresis: f64, severity: Severity) -> Self {
+        AlarmRule {
+            name: name.to_string(),
+            field: field.to_string(),
+            comparison,
+            value,
+            hysteresis,
+            severity,
+            window_secs: None,
+        }
+    }
+
+    /// 在当前状态下，该值应处于激活还是清除
+    fn evaluate(&self, active: bool, value: f64) -> bool {
+        let (threshold, hysteresis) = (self.value, self.hysteresis);
+        let (trip, release) = match self.comparison {
+            Comparison::Above => (value > threshold, value < threshold - hysteresis),
+            Comparison::Below => (value < threshold, value > threshold + hysteresis),
+            Comparison::AbsAbove => (value.abs() > threshold, value.abs() < threshold - hysteresis),
+        };
+        if active { !release } else { trip }
+    }
+}
+
+/// 未配置 `[[alarms]]` 时使用的默认规则
+pub fn default_rules() -> Vec<AlarmRule> {
+    vec![
+        AlarmRule::new("low_cell_voltage", "derived.cell_min", Comparison::Below, 3.0, 0.1, Severity::Warning),
+        AlarmRule::new("high_cell_voltage", "derived.cell_max", Comparison::Above, 4.25, 0.05, Severity::Critical),
+        AlarmRule::new(
+            "over_temperature",
+            "data.bq76920.temperatures.ts1",
+            Comparison::Above,
+            60.0,
+            5.0,
+            Severity::Critical,
+        ),
+        AlarmRule::new("over_current", "data.bq76920.coulomb_counter", Comparison::AbsAbove, 10.0, 1.0, Severity::Warning),
+        AlarmRule::new("low_soc", "battery.soc_percent", Comparison::Below, 10.0, 5.0, Severity::Warning),
+    ]
+}
+
+/// 校验规则名与滞回量：名称用作主题的一级，不能为空、含通配符或与 `active` 冲突
+pub fn validate_rules(rules: &[AlarmRule]) -> Result<(), ConfigError> {
+    let mut names = std::collections::HashSet::new();
+    for rule in rules {
+        let reason = if rule.name.is_empty() || rule.name.contains(['/', '+', '#']) {
+            "name must be non-empty and contain no '/', '+' or '#'"
+        } else if rule.name == "active" {
+            "name 'active' is reserved for the summary topic"
+        } else if !names.insert(rule.name.as_str()) {
+            "duplicate alarm name"
+        } else if !(rule.hysteresis.is_finite() && rule.hysteresis >= 0.0 && rule.value.is_finite()) {
+            "value and hysteresis must be finite, hysteresis non-negative"
+        } else if rule.window_secs.is_some_and(|secs| !(secs.is_finite() && secs > 0.0)) {
+            "window_secs must be positive"
+        } else {
+            continue;
+        };
+        return Err(ConfigError::Invalid {
+            key: "alarms",
+            value: rule.name.clone(),
+            reason: reason.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// 告警状态变化，以 retained 方式发布到 `{prefix}/alarms/<name>`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AlarmEvent {
+    pub name: String,
+    /// 规则的扁平键
+    pub field: String,
+    /// `active` 或 `clear`
+    pub state: &'static str,
+    /// 变化前的状态；首次取到数值时为 None
+    pub previous: Option<&'static str>,
+    pub severity: Severity,
+    /// 变化率规则中为窗口内的变化量
+    pub value: f64,
+    pub threshold: f64,
+    /// 变化率规则的每秒变化量
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_per_sec: Option<f64>,
+    pub ts_unix_ms: u64,
+    /// 激活中的告警已被确认
+    pub acknowledged: bool,
+    /// 确认人与确认时间
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ack: Option<AlarmAck>,
+    /// 变化前的激活状态已被确认；这样的清除不再通知 webhook
+    #[serde(skip)]
+    pub was_acknowledged: bool,
+}
+
+/// `{prefix}/alarms/active` 中的一项
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ActiveAlarm {
+    pub name: String,
+    pub severity: Severity,
+    pub since_unix_ms: u64,
+    pub acknowledged: bool,
+}
+
+#[derive(Debug)]
+struct AlarmSlot {
+    rule: AlarmRule,
+    /// None 表示尚未取到过数值
+    active: Option<bool>,
+    since_unix_ms: u64,
+    /// 变化率规则窗口内的 (时间戳, 数值)，从旧到新
+    recent: VecDeque<(u64, f64)>,
+    /// 最近一次发布的状态，确认时带上确认信息重新发布
+    last_event: Option<AlarmEvent>,
+    /// 当前激活的确认；清除或重新激活时失效
+    ack: Option<AlarmAck>,
+}
+
+impl AlarmSlot {
+    /// 记录一个数值，返回窗口内的 (变化量, 每秒变化量)；窗口内只有当前一帧时为 None
+    fn change(&mut self, ts_unix_ms: u64, value: f64, window_secs: f64) -> Option<(f64, f64)> {
+        let window_ms = (window_secs * 1000.0) as u64;
+        self.recent.push_back((ts_unix_ms, value));
+        while let Some(&(ts, _)) = self.recent.front()
+            && ts_unix_ms.saturating_sub(ts) > window_ms
+        {
+            self.recent.pop_front();
+        }
+        let &(start_ts, start_value) = self.recent.front()?;
+        let elapsed_ms = ts_unix_ms.checked_sub(start_ts).filter(|ms| *ms > 0)?;
+        let change = value - start_value;
+        Some((change, change * 1000.0 / elapsed_ms as f64))
+    }
+}
+
+/// 一组告警的状态机，与总线解耦，可直接喂样本
+#[derive(Debug)]
+pub struct AlarmSet {
+    slots: Vec<AlarmSlot>,
+    last_ts_unix_ms: Option<u64>,
+    /// 两帧间隔的指数平均，用于判断断档
+    interval_ms: Option<u64>,
+}
+
+impl AlarmSet {
+    pub fn new(rules: Vec<AlarmRule>) -> Self {
+        AlarmSet {
+            slots: rules
+                .into_iter()
+                .map(|rule| AlarmSlot {
+                    rule,
+                    active: None,
+                    since_unix_ms: 0,
+                    recent: VecDeque::new(),
+                    last_event: None,
+                    ack: None,
+                })
+                .collect(),
+            last_ts_unix_ms: None,
+            interval_ms: None,
+        }
+    }
+
+    /// 记录帧时间戳；与上一帧的间隔远大于平常间隔或时间回退时返回 true
+    fn is_discontinuity(&mut self, ts_unix_ms: u64) -> bool {
+        let Some(last) = self.last_ts_unix_ms.replace(ts_unix_ms) else {
+            return false;
+        };
+        let Some(interval) = ts_unix_ms.checked_sub(last) else {
+            return true;
+        };
+        let gap = self.interval_ms.is_some_and(|typical| interval > typical.max(MIN_INTERVAL_MS) * GAP_FACTOR);
+        // 断档也计入平均：推送周期真的变长时，若干帧后不再被当作断档
+        self.interval_ms = Some(match self.interval_ms {
+            Some(typical) => (typical * 7 + interval) / 8,
+            None => interval,
+        });
+        gap
+    }
+
+    /// 喂入一帧，返回状态发生变化（含首次取到数值）的告警；取不到数值的规则保持原状态
+    pub fn evaluate(&mut self, sample: &TimestampedMeasurements) -> Vec<AlarmEvent> {
+        let Ok(json) = serde_json::to_value(sample) else {
+            return Vec::new();
+        };
+        if self.is_discontinuity(sample.ts_unix_ms) {
+            for slot in &mut self.slots {
+                slot.recent.clear();
+            }
+        }
+        let mut events = Vec::new();
+        for slot in &mut self.slots {
+            let pointer = format!("/{}", slot.rule.field.replace('.', "/"));
+            let Some(reading) = json.pointer(&pointer).and_then(Value::as_f64) else {
+                continue;
+            };
+            let (value, rate_per_sec) = match slot.rule.window_secs {
+                Some(window_secs) => match slot.change(sample.ts_unix_ms, reading, window_secs) {
+                    Some((change, rate)) => (change, Some(rate)),
+                    None => continue,
+                },
+                None => (reading, None),
+            };
+            let active = slot.rule.evaluate(slot.active.unwrap_or(false), value);
+            if slot.active == Some(active) {
+                continue;
+            }
+            let previous = slot.active.replace(active);
+            slot.since_unix_ms = sample.ts_unix_ms;
+            let event = AlarmEvent {
+                name: slot.rule.name.clone(),
+                field: slot.rule.field.clone(),
+                state: state_name(active),
+                previous: previous.map(state_name),
+                severity: slot.rule.severity,
+                value,
+                threshold: slot.rule.value,
+                rate_per_sec,
+                ts_unix_ms: sample.ts_unix_ms,
+                acknowledged: false,
+                ack: None,
+                was_acknowledged: slot.ack.take().is_some(),
+            };
+            slot.last_event = Some(event.clone());
+            events.push(event);
+        }
+        events
+    }
+
+    /// 确认一条激活中的告警，返回带确认信息、需要重新发布的告警状态
+    pub fn acknowledge(&mut self, name: &str, ack: AlarmAck) -> Result<AlarmEvent, &'static str> {
+        let slot = self.slots.iter_mut().find(|slot| slot.rule.name == name).ok_or("unknown alarm")?;
+        if slot.active != Some(true) {
+            return Err("alarm is not active");
+        }
+        let mut event = slot.last_event.clone().ok_or("alarm is not active")?;
+        event.acknowledged = true;
+        event.ack = Some(ack.clone());
+        slot.ack = Some(ack);
+        slot.last_event = Some(event.clone());
+        Ok(event)
+    }
+
+    pub fn active(&self) -> Vec<ActiveAlarm> {
+        self.slots
+            .iter()
+            .filter(|slot| slot.active == Some(true))
+            .map(|slot| ActiveAlarm {
+                name: slot.rule.name.clone(),
+                severity: slot.rule.severity,
+                since_unix_ms: slot.since_unix_ms,
+                acknowledged: slot.ack.is_some(),
+            })
+            .collect()
+    }
+}
+
+fn state_name(active: bool) -> &'static str {
+    if active { "active" } else { "clear" }
+}
+
+/// 告警激活时保存的上下文：激活前（含激活帧）的最近样本
+#[cfg(feature = "mqtt")]
+#[derive(Debug, Clone)]
+pub struct AlarmContext {
+    pub history: History,
+    /// 设置时写入 `{dir}/<name>-<ts_unix_ms>.json`，否则发布到 `{prefix}/alarms/<name>/context`
+    pub dir: Option<PathBuf>,
+}
+
+/// 订阅测量数据评估告警规则；告警激活或清除时发布对应主题，并更新 `{prefix}/alarms/active`
+/// 与 `active`（HTTP 接口读取，按主题前缀分组）；除首次的 `clear` 外，状态变化同时通知 webhook。
+/// 告警激活时另外保存 `context` 中的最近样本。`acks` 传入 `{prefix}/alarms/<name>/set` 的确认 (告警名, 确认)，
+/// 已确认的告警清除时不通知 webhook
+#[cfg(feature = "mqtt")]
+#[allow(clippy::too_many_arguments)]
+pub async fn alarm_task(
+    mut samples: Subscription,
+    client: AsyncClient,
+    topic_prefix: String,
+    topic_map: TopicMap,
+    rules: Vec<AlarmRule>,
+    active: watch::Sender<ActiveAlarms>,
+    device: DeviceId,
+    webhooks: Option<WebhookHandle>,
+    context: AlarmContext,
+    mut acks: mpsc::Receiver<(String, AlarmAck)>,
+) {
+    if rules.is_empty() {
+        return;
+    }
+    let mut alarms = AlarmSet::new(rules);
+    loop {
+        let sample: Arc<TimestampedMeasurements> = tokio::select! {
+            received = samples.recv() => match received {
+                Ok(sample) => sample,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("告警评估处理过慢，跳过 {} 帧", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            Some((name, ack)) = acks.recv() => {
+                let prefix = topic_prefix.clone();
+                let topics = topic_map.for_prefix(&prefix);
+                match alarms.acknowledge(&name, ack) {
+                    Ok(event) => {
+                        info!("告警 {} 已确认 (确认人 {})", name, event.ack.as_ref().and_then(|ack| ack.by.as_deref()).unwrap_or("-"));
+                        publish_json(&client, topics.alarm(&name), &event).await;
+                    }
+                    Err(reason) => {
+                        warn!("忽略告警 {} 的确认: {}", name, reason);
+                        continue;
+                    }
+                }
+                let current = alarms.active();
+                publish_json(&client, topics.active_alarms.clone(), &current).await;
+                active.send_modify(|all| {
+                    all.insert(prefix, current);
+                });
+                continue;
+            }
+        };
+        let events = alarms.evaluate(&sample);
+        if events.is_empty() {
+            continue;
+        }
+        let prefix = topic_prefix.clone();
+        let topics = topic_map.for_prefix(&prefix);
+        for event in &events {
+            if event.state == "active" {
+                warn!("告警 {} 激活: 值 {} 越过阈值 {}", event.name, event.value, event.threshold);
+                save_context(&client, &topics, event, &context).await;
+            } else {
+                info!("告警 {} 状态: {} (值 {})", event.name, event.state, event.value);
+            }
+            publish_json(&client, topics.alarm(&event.name), event).await;
+            // 首次取到数值时的清除与已确认告警的清除不通知
+            if let Some(webhooks) = &webhooks
+                && (event.state == "active" || (event.previous.is_some() && !event.was_acknowledged))
+            {
+                webhooks.notify(WebhookEvent::from_alarm(&device, event));
+            }
+        }
+        let current = alarms.active();
+        publish_json(&client, topics.active_alarms.clone(), &current).await;
+        active.send_modify(|all| {
+            all.insert(prefix, current);
+        });
+    }
+}
+
+/// 主循环先写入历史再发布到总线，激活帧已在快照中
+#[cfg(feature = "mqtt")]
+async fn save_context(client: &AsyncClient, topics: &Topics, event: &AlarmEvent, context: &AlarmContext) {
+    let samples = context.history.snapshot();
+    if samples.is_empty() {
+        return;
+    }
+    let frames: Vec<&TimestampedMeasurements> = samples.iter().map(Arc::as_ref).collect();
+    let payload = match serde_json::to_vec(&frames) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("序列化告警 {} 的上下文失败: {:?}", event.name, e);
+            return;
+        }
+    };
+    match &context.dir {
+        Some(dir) => {
+            let path = dir.join(format!("{}-{}.json", event.name, event.ts_unix_ms));
+            match utils::write_atomic(&path, &payload) {
+                Ok(()) => info!("告警 {} 的上下文已保存 ({} 帧): {}", event.name, frames.len(), path.display()),
+                Err(e) => error!("保存告警 {} 的上下文失败: {:?}", event.name, e),
+            }
+        }
+        None => {
+            let topic = topics.alarm_context(&event.name);
+            if let Err(e) = client.publish(topic, QoS::AtLeastOnce, false, payload).await {
+                error!("发布告警 {} 的上下文失败: {:?}", event.name, e);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "mqtt")]
+async fn publish_json<T: Serialize>(client: &AsyncClient, topic: String, value: &T) {
+    match serde_json::to_string(value) {
+        Ok(payload) => {
+            if let Err(e) = client.publish(topic, QoS::AtLeastOnce, true, payload).await {
+                error!("发布告警失败: {:?}", e);
+            }
+        }
+        Err(e) => error!("序列化告警失败: {:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::PayloadBuilder;
+
+    fn sample(ts_unix_ms: u64, cell_min: f32) -> TimestampedMeasurements {
+        let mut sample = PayloadBuilder::new().sample(ts_unix_ms, ts_unix_ms);
+        sample.derived.cell_min = cell_min;
+        sample
+    }
+
+    fn low_cell() -> AlarmSet {
+        AlarmSet::new(vec![AlarmRule::new("low_cell_voltage", "derived.cell_min", Comparison::Below, 3.0, 0.1, Severity::Warning)])
+    }
+
+    fn states(events: &[AlarmEvent]) -> Vec<(&str, Option<&str>)> {
+        events.iter().map(|e| (e.state, e.previous)).collect()
+    }
+
+    #[test]
+    fn first_value_reports_the_initial_state() {
+        let mut alarms = low_cell();
+        assert_eq!(states(&alarms.evaluate(&sample(1000, 3.6))), [("clear", None)]);
+        assert!(alarms.evaluate(&sample(2000, 3.5)).is_empty());
+        assert!(alarms.active().is_empty());
+    }
+
+    #[test]
+    fn crossing_the_threshold_activates_and_clears() {
+        let mut alarms = low_cell();
+        alarms.evaluate(&sample(1000, 3.6));
+        let events = alarms.evaluate(&sample(2000, 2.9));
+        assert_eq!(states(&events), [("active", Some("clear"))]);
+        let event = &events[0];
+        assert_eq!(event.name, "low_cell_voltage");
+        assert_eq!(event.field, "derived.cell_min");
+        assert!((event.value - 2.9).abs() < 1e-6);
+        assert_eq!(event.threshold, 3.0);
+        assert_eq!(event.ts_unix_ms, 2000);
+        assert_eq!(
+            alarms.active(),
+            [ActiveAlarm { name: "low_cell_voltage".to_string(), severity: Severity::Warning, since_unix_ms: 2000, acknowledged: false }]
+        );
+
+        assert_eq!(states(&alarms.evaluate(&sample(3000, 3.2))), [("clear", Some("active"))]);
+        assert!(alarms.active().is_empty());
+    }
+
+    #[test]
+    fn hysteresis_suppresses_flapping_at_the_threshold() {
+        let mut alarms = low_cell();
+        alarms.evaluate(&sample(1000, 3.6));
+        assert_eq!(states(&alarms.evaluate(&sample(2000, 2.99))), [("active", Some("clear"))]);
+        // 在阈值与阈值 + 滞回之间来回抖动，保持激活
+        for (i, cell_min) in [3.01, 2.98, 3.05, 2.99, 3.09].into_iter().enumerate() {
+            assert!(alarms.evaluate(&sample(3000 + i as u64 * 1000, cell_min)).is_empty(), "{}", cell_min);
+        }
+        assert_eq!(states(&alarms.evaluate(&sample(9000, 3.11))), [("clear", Some("active"))]);
+        // 清除后要重新越过阈值本身才激活
+        assert!(alarms.evaluate(&sample(10_000, 3.0)).is_empty());
+        assert_eq!(states(&alarms.evaluate(&sample(11_000, 2.95))), [("active", Some("clear"))]);
+    }
+
+    #[test]
+    fn each_comparison_trips_and_releases_on_its_side() {
+        let above = AlarmRule::new("high", "x", Comparison::Above, 4.25, 0.05, Severity::Critical);
+        assert!(!above.evaluate(false, 4.25));
+        assert!(above.evaluate(false, 4.26));
+        assert!(above.evaluate(true, 4.21));
+        assert!(!above.evaluate(true, 4.19));
+
+        let below = AlarmRule::new("low", "x", Comparison::Below, 10.0, 5.0, Severity::Warning);
+        assert!(below.evaluate(false, 9.0));
+        assert!(below.evaluate(true, 14.0));
+        assert!(!below.evaluate(true, 15.5));
+
+        let current = AlarmRule::new("current", "x", Comparison::AbsAbove, 10.0, 1.0, Severity::Warning);
+        assert!(current.evaluate(false, 10.5));
+        assert!(current.evaluate(false, -10.5));
+        assert!(!current.evaluate(false, -9.5));
+        assert!(current.evaluate(true, -9.5));
+        assert!(!current.evaluate(true, 8.5));
+    }
+
+    #[test]
+    fn missing_values_keep_the_previous_state() {
+        let mut alarms = AlarmSet::new(vec![AlarmRule::new(
+            "over_temperature",
+            "data.bq76920.temperatures.ts1",
+            Comparison::Above,
+            60.0,
+            5.0,
+            Severity::Critical,
+        )]);
+        let hot = PayloadBuilder::new().ts1_celsius(70.0);
+        assert_eq!(states(&alarms.evaluate(&hot.sample(1, 1000))), [("active", None)]);
+        // 传感器故障时 ts1 为 null，告警既不激活也不清除
+        let mut faulted = hot.sample(2, 2000);
+        faulted.data.bq76920.temperatures.ts1 = None;
+        assert!(alarms.evaluate(&faulted).is_empty());
+        assert_eq!(alarms.active().len(), 1);
+    }
+
+    #[test]
+    fn the_event_json_carries_state_value_threshold_and_timestamp() {
+        let mut alarms = low_cell();
+        let event = alarms.evaluate(&sample(1000, 2.5)).remove(0);
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["state"], "active");
+        assert_eq!(json["previous"], Value::Null);
+        assert_eq!(json["severity"], "warning");
+        assert_eq!(json["value"], 2.5);
+        assert_eq!(json["threshold"], 3.0);
+        assert_eq!(json["ts_unix_ms"], 1000);
+        assert!(json.get("rate_per_sec").is_none());
+        assert!(json.get("was_acknowledged").is_none());
+    }
+
+    #[test]
+    fn default_rules_are_valid_and_names_are_checked() {
+        assert!(validate_rules(&default_rules()).is_ok());
+        for name in ["", "a/b", "a+", "#", "active"] {
+            let rule = AlarmRule::new(name, "x", Comparison::Above, 1.0, 0.0, Severity::Info);
+            assert!(matches!(validate_rules(&[rule]), Err(ConfigError::Invalid { key: "alarms", .. })), "{:?}", name);
+        }
+        let rule = AlarmRule::new("dup", "x", Comparison::Above, 1.0, 0.0, Severity::Info);
+        assert!(validate_rules(&[rule.clone(), rule.clone()]).is_err());
+        assert!(validate_rules(&[AlarmRule { hysteresis: -0.1, ..rule }]).is_err());
+    }
+
+    // 电芯最低电压在 2 s 内下降超过 200 mV 时激活
+    fn collapse() -> AlarmSet {
+        let rule = AlarmRule::new("cell_collapse", "derived.cell_min", Comparison::Below, -0.2, 0.05, Severity::Critical);
+        AlarmSet::new(vec![AlarmRule { window_secs: Some(2.0), ..rule }])
+    }
+
+    // 从 `start_ms` 起每 `step_ms` 一帧依次喂入，返回产生的事件
+    fn feed(alarms: &mut AlarmSet, start_ms: u64, step_ms: u64, values: &[f32]) -> Vec<AlarmEvent> {
+        values.iter().enumerate().flat_map(|(i, v)| alarms.evaluate(&sample(start_ms + i as u64 * step_ms, *v))).collect()
+    }
+
+    #[test]
+    fn a_step_change_within_the_window_activates() {
+        let mut alarms = collapse();
+        let steady = feed(&mut alarms, 0, 500, &[3.60; 6]);
+        assert_eq!(states(&steady), [("clear", None)]);
+        let events = feed(&mut alarms, 3000, 500, &[3.45, 3.30]);
+        assert_eq!(states(&events), [("active", Some("clear"))]);
+        let event = &events[0];
+        assert!((event.value + 0.3).abs() < 1e-3, "{}", event.value);
+        // 2 s 窗口内的起点是 3000 - 2000 = 1000 ms 处的 3.60 V
+        let rate = event.rate_per_sec.unwrap();
+        assert!((rate + 0.15).abs() < 1e-3, "{}", rate);
+        assert_eq!(serde_json::to_value(event).unwrap()["rate_per_sec"], rate);
+
+        // 电压稳定后窗口内的变化量回到零附近，告警清除
+        let events = feed(&mut alarms, 4000, 500, &[3.30; 6]);
+        assert_eq!(states(&events), [("clear", Some("active"))]);
+    }
+
+    #[test]
+    fn a_slow_ramp_of_the_same_size_does_not_activate() {
+        let mut alarms = collapse();
+        // 60 s 内下降 300 mV，每 2 s 只有 10 mV
+        let ramp: Vec<f32> = (0..=120).map(|i| 3.6 - 0.0025 * i as f32).collect();
+        assert_eq!(states(&feed(&mut alarms, 0, 500, &ramp)), [("clear", None)]);
+        assert!(alarms.active().is_empty());
+    }
+
+    #[test]
+    fn reconnect_gaps_do_not_count_as_changes() {
+        let mut alarms = collapse();
+        feed(&mut alarms, 0, 500, &[3.60; 10]);
+        // 断开 30 s 后以很低的电压恢复：断档前的数值不参与比较
+        assert!(feed(&mut alarms, 34_500, 500, &[3.20, 3.20, 3.21]).is_empty());
+        // 时间回退（设备时钟或重放）同样清空窗口
+        assert!(feed(&mut alarms, 10_000, 500, &[3.60, 3.60]).is_empty());
+        // 断档后的窗口照常工作
+        let events = feed(&mut alarms, 11_000, 500, &[3.30]);
+        assert_eq!(states(&events), [("active", Some("clear"))]);
+    }
+
+    #[test]
+    fn a_slower_push_interval_stops_counting_as_a_gap() {
+        let mut alarms = collapse();
+        feed(&mut alarms, 0, 100, &[3.60; 10]);
+        // 推送周期改为 1 s：平均间隔追上之后不再清空窗口
+        let events = feed(&mut alarms, 1900, 1000, &[3.60; 20]);
+        assert!(events.is_empty());
+        assert!(!alarms.is_discontinuity(21_900));
+    }
+
+    #[test]
+    fn rate_rules_are_read_from_the_config() {
+        let toml = "name = \"pack_drop\"\nfield = \"bq25730.vbat\"\ncomparison = \"below\"\ndelta = -1.0\nwindow_secs = 5";
+        let rule: AlarmRule = toml::from_str(toml).unwrap();
+        assert_eq!((rule.value, rule.window_secs), (-1.0, Some(5.0)));
+        assert!(validate_rules(std::slice::from_ref(&rule)).is_ok());
+        assert!(validate_rules(&[AlarmRule { window_secs: Some(0.0), ..rule.clone() }]).is_err());
+        assert!(validate_rules(&[AlarmRule { window_secs: Some(f64::NAN), ..rule }]).is_err());
+    }
+
+    fn ack(by: &str, ts_unix_ms: u64) -> AlarmAck {
+        AlarmAck { by: Some(by.to_string()), ts_unix_ms }
+    }
+
+    #[test]
+    fn only_active_alarms_can_be_acknowledged() {
+        let mut alarms = low_cell();
+        assert_eq!(alarms.acknowledge("low_cell_voltage", ack("ops", 500)), Err("alarm is not active"));
+        alarms.evaluate(&sample(1000, 3.6));
+        assert_eq!(alarms.acknowledge("low_cell_voltage", ack("ops", 1500)), Err("alarm is not active"));
+        assert_eq!(alarms.acknowledge("over_temperature", ack("ops", 1500)), Err("unknown alarm"));
+    }
+
+    #[test]
+    fn the_ack_lasts_until_the_alarm_clears() {
+        let mut alarms = low_cell();
+        alarms.evaluate(&sample(1000, 3.6));
+        alarms.evaluate(&sample(2000, 2.9));
+
+        let event = alarms.acknowledge("low_cell_voltage", ack("ops", 2500)).unwrap();
+        assert_eq!((event.state, event.ts_unix_ms), ("active", 2000));
+        assert!(event.acknowledged);
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["acknowledged"], true);
+        assert_eq!(json["ack"], serde_json::json!({"by": "ops", "ts_unix_ms": 2500}));
+        assert!(alarms.active()[0].acknowledged);
+        // 仍处于激活时的后续帧不产生事件，确认保持
+        assert!(alarms.evaluate(&sample(3000, 2.8)).is_empty());
+        assert!(alarms.active()[0].acknowledged);
+
+        // 清除事件标明之前已确认 (不通知 webhook)，本身不带确认
+        let cleared = alarms.evaluate(&sample(4000, 3.2)).remove(0);
+        assert_eq!(cleared.state, "clear");
+        assert!(cleared.was_acknowledged);
+        assert!(!cleared.acknowledged);
+        assert!(serde_json::to_value(&cleared).unwrap().get("ack").is_none());
+
+        // 重新激活后需要重新确认
+        let retriggered = alarms.evaluate(&sample(5000, 2.9)).remove(0);
+        assert_eq!(retriggered.state, "active");
+        assert!(!retriggered.acknowledged && !retriggered.was_acknowledged);
+        assert!(!alarms.active()[0].acknowledged);
+        let cleared = alarms.evaluate(&sample(6000, 3.2)).remove(0);
+        assert!(!cleared.was_acknowledged);
+    }
+
+    #[test]
+    fn ack_payloads_are_parsed() {
+        let now = 9000;
+        assert_eq!(AlarmAck::parse(b"ack", now), Some(AlarmAck { by: None, ts_unix_ms: now }));
+        assert_eq!(AlarmAck::parse(b"  ACK  alice \n", now), Some(ack("alice", now)));
+        assert_eq!(AlarmAck::parse(br#"{"ack": true, "by": "bob", "ts_unix_ms": 1234}"#, now), Some(ack("bob", 1234)));
+        assert_eq!(AlarmAck::parse(br#"{"ack": true, "by": " "}"#, now), Some(AlarmAck { by: None, ts_unix_ms: now }));
+        for payload in [&b""[..], b"clear", b"acknowledge", br#"{"ack": false}"#, br#"{"ack": true, "who": "x"}"#, b"{"] {
+            assert_eq!(AlarmAck::parse(payload, now), None, "{}", String::from_utf8_lossy(payload));
+        }
+    }
+
+    #[test]
+    fn set_topics_name_the_prefix_and_the_alarm() {
+        let parse = |topic| parse_alarm_set_topic("ups", topic);
+        assert_eq!(parse("ups/alarms/low_cell_voltage/set"), Some(("ups".to_string(), "low_cell_voltage".to_string())));
+        assert_eq!(parse("ups/UPS01/alarms/low_soc/set"), Some(("ups/UPS01".to_string(), "low_soc".to_string())));
+        for topic in [
+            "ups/alarms/low_soc",
+            "ups/alarms//set",
+            "ups/a/b/alarms/low_soc/set",
+            "upsx/alarms/low_soc/set",
+            "other/alarms/low_soc/set",
+            "ups/cmd/alarms/low_soc/set/x",
+        ] {
+            assert_eq!(parse(topic), None, "{}", topic);
+        }
+        assert_eq!(alarm_set_topic_filters("ups"), ["ups/alarms/+/set", "ups/+/alarms/+/set"]);
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[tokio::test]
+    async fn acknowledging_republishes_the_alarm_and_the_active_list() {
+        use std::time::Duration;
+
+        use crate::pipeline::Pipeline;
+        use crate::test_broker::{ReceivedPublish, TestBroker};
+        use crate::topics::TopicMap;
+        use crate::usb_types::DeviceId;
+
+        let broker = TestBroker::start().await;
+        let (client, mut eventloop) = AsyncClient::new(broker.options("alarm-acks"), 10);
+        tokio::spawn(async move { while eventloop.poll().await.is_ok() {} });
+        let pipeline = Pipeline::new(8);
+        let (active, mut active_rx) = watch::channel(ActiveAlarms::new());
+        let (acks_tx, acks) = mpsc::channel(4);
+        let rule = AlarmRule::new("low_cell_voltage", "derived.cell_min", Comparison::Below, 3.0, 0.1, Severity::Warning);
+        let context = AlarmContext { history: History::new(4), dir: None };
+        tokio::spawn(alarm_task(
+            pipeline.subscribe(),
+            client,
+            "ups".to_string(),
+            TopicMap::default(),
+            vec![rule],
+            active,
+            DeviceId::Serial("UPS01".to_string()),
+            None,
+            context,
+            acks,
+        ));
+        let alarm_states = |publishes: &[ReceivedPublish]| -> Vec<Value> {
+            publishes
+                .iter()
+                .filter(|p| p.topic == "ups/alarms/low_cell_voltage")
+                .map(|p| serde_json::from_slice(&p.payload).unwrap())
+                .collect()
+        };
+
+        // 未知告警的确认被忽略
+        acks_tx.send(("over_temperature".to_string(), ack("ops", 500))).await.unwrap();
+        pipeline.publish(Arc::new(sample(1000, 3.6)));
+        assert!(broker.wait_for(Duration::from_secs(5), |p| alarm_states(p).len() == 1).await);
+        pipeline.publish(Arc::new(sample(2000, 2.9)));
+        assert!(broker.wait_for(Duration::from_secs(5), |p| alarm_states(p).len() == 2).await);
+        assert_eq!(alarm_states(&broker.publishes())[1]["acknowledged"], false);
+
+        acks_tx.send(("low_cell_voltage".to_string(), ack("ops", 2500))).await.unwrap();
+        let active_lists = |publishes: &[ReceivedPublish]| publishes.iter().filter(|p| p.topic == "ups/alarms/active").count();
+        assert!(broker.wait_for(Duration::from_secs(5), |p| alarm_states(p).len() == 3 && active_lists(p) == 3).await);
+        let publishes = broker.publishes();
+        let acked = publishes.iter().rfind(|p| p.topic == "ups/alarms/low_cell_voltage").unwrap();
+        assert!(acked.retain);
+        let acked: Value = serde_json::from_slice(&acked.payload).unwrap();
+        assert_eq!((&acked["state"], &acked["acknowledged"]), (&Value::from("active"), &Value::from(true)));
+        assert_eq!(acked["ack"], serde_json::json!({"by": "ops", "ts_unix_ms": 2500}));
+        let list = publishes.iter().rfind(|p| p.topic == "ups/alarms/active").unwrap();
+        let list: Value = serde_json::from_slice(&list.payload).unwrap();
+        assert_eq!(list[0]["acknowledged"], true);
+        let acknowledged = active_rx.wait_for(|all| all.get("ups").is_some_and(|list| list.iter().all(|a| a.acknowledged)));
+        assert!(tokio::time::timeout(Duration::from_secs(5), acknowledged).await.is_ok());
+
+        // 清除后确认失效
+        pipeline.publish(Arc::new(sample(3000, 3.2)));
+        assert!(broker.wait_for(Duration::from_secs(5), |p| alarm_states(p).len() == 4).await);
+        let cleared = &alarm_states(&broker.publishes())[3];
+        assert_eq!((&cleared["state"], &cleared["acknowledged"]), (&Value::from("clear"), &Value::from(false)));
+    }
+
+    // 依次送入 3.6 V、3.5 V、2.5 V 三帧，第三帧激活告警；返回激活事件发布前 broker 收到的全部消息
+    #[cfg(feature = "mqtt")]
+    async fn activate_with_context(context: AlarmContext) -> Vec<crate::test_broker::ReceivedPublish> {
+        use std::time::Duration;
+
+        use crate::pipeline::Pipeline;
+        use crate::test_broker::TestBroker;
+        use crate::topics::TopicMap;
+        use crate::usb_types::DeviceId;
+
+        let broker = TestBroker::start().await;
+        let (client, mut eventloop) = AsyncClient::new(broker.options("alarms"), 10);
+        tokio::spawn(async move { while eventloop.poll().await.is_ok() {} });
+        let pipeline = Pipeline::new(8);
+        let (active, _active_rx) = watch::channel(ActiveAlarms::new());
+        let (_acks_tx, acks) = mpsc::channel(1);
+        let rule = AlarmRule::new("low_cell_voltage", "derived.cell_min", Comparison::Below, 3.0, 0.1, Severity::Warning);
+        let history = context.history.clone();
+        tokio::spawn(alarm_task(
+            pipeline.subscribe(),
+            client,
+            "ups".to_string(),
+            TopicMap::default(),
+            vec![rule],
+            active,
+            DeviceId::Serial("UPS01".to_string()),
+            None,
+            context,
+            acks,
+        ));
+        for (ts, cell_min) in [(1000, 3.6), (2000, 3.5), (3000, 2.5)] {
+            let sample = Arc::new(sample(ts, cell_min));
+            history.push(sample.clone());
+            pipeline.publish(sample);
+        }
+        let activated = |p: &[crate::test_broker::ReceivedPublish]| {
+            p.iter().any(|p| p.topic == "ups/alarms/low_cell_voltage" && p.payload.windows(8).any(|w| w == b"\"active\""))
+        };
+        assert!(broker.wait_for(Duration::from_secs(5), activated).await, "告警未激活");
+        broker.publishes()
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[tokio::test]
+    async fn activation_publishes_the_preceding_samples() {
+        let history = History::new(600);
+        let publishes = activate_with_context(AlarmContext { history, dir: None }).await;
+        let context = publishes.iter().find(|p| p.topic == "ups/alarms/low_cell_voltage/context").expect("上下文未发布");
+        assert!(!context.retain);
+        let frames: Vec<Value> = serde_json::from_slice(&context.payload).unwrap();
+        let frame_ids: Vec<u64> = frames.iter().map(|frame| frame["frame_id"].as_u64().unwrap()).collect();
+        assert_eq!(frame_ids, [1000, 2000, 3000]);
+        assert_eq!(frames[2]["derived"]["cell_min"], 2.5);
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[tokio::test]
+    async fn the_context_goes_to_a_file_when_a_directory_is_set() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::new(2);
+        let publishes = activate_with_context(AlarmContext { history, dir: Some(dir.path().to_path_buf()) }).await;
+        assert!(publishes.iter().all(|p| !p.topic.ends_with("/context")));
+        let path = dir.path().join("low_cell_voltage-3000.json");
+        let frames: Vec<Value> = serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+        // 只保留历史容量内的最近两帧
+        assert_eq!(frames.iter().map(|frame| frame["frame_id"].as_u64().unwrap()).collect::<Vec<_>>(), [2000, 3000]);
+    }
+}
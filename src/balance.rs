@@ -0,0 +1,308 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[cfg(feature = "mqtt")]
+use tracing::{error, info, warn};
+#[cfg(feature = "mqtt")]
+use rumqttc::{AsyncClient, QoS};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "mqtt")]
+use tokio::sync::broadcast;
+
+#[cfg(feature = "mqtt")]
+use crate::data_models::TimestampedMeasurements;
+#[cfg(feature = "mqtt")]
+use crate::pipeline::Subscription;
+use crate::rules::PowerState;
+use crate::utils;
+
+/// 分桶边界最多个数；限制它才能保证最坏情况下的载荷大小
+pub const MAX_BUCKET_EDGES: usize = 15;
+
+/// 发布载荷上限，低于 rumqttc 默认的 10 KiB 最大报文长度
+pub const MAX_PAYLOAD_BYTES: usize = 8 * 1024;
+
+// 两次写状态文件之间的最短间隔；窗口滚动时总会写入
+#[cfg(feature = "mqtt")]
+const PERSIST_INTERVAL: Duration = Duration::from_secs(300);
+
+pub fn balance_histograms_topic(topic_prefix: &str) -> String {
+    format!("{}/stats/balance_histograms", topic_prefix)
+}
+
+/// 各电芯相对电池包平均电压的偏差分布，按充放电状态分别统计。
+///
+/// `edges_mv` 为升序的分桶边界，每个直方图有 `edges_mv.len() + 1` 个桶：
+/// 第 i 个桶统计 `edges_mv[i-1] <= Δ < edges_mv[i]` 的样本。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BalanceHistograms {
+    pub window_start_unix_ms: u64,
+    pub edges_mv: Vec<i32>,
+    /// `[电芯][桶]`
+    pub charging: Vec<Vec<u32>>,
+    pub discharging: Vec<Vec<u32>>,
+    pub idle: Vec<Vec<u32>>,
+}
+
+/// 窗口结束时发布的载荷
+#[derive(Debug, Serialize)]
+pub struct BalanceReport<'a> {
+    pub window_end_unix_ms: u64,
+    #[serde(flatten)]
+    pub histograms: &'a BalanceHistograms,
+}
+
+impl BalanceHistograms {
+    pub fn new(window_start_unix_ms: u64, edges_mv: &[i32], cell_count: usize) -> Self {
+        let empty = vec![vec![0; edges_mv.len() + 1]; cell_count];
+        BalanceHistograms {
+            window_start_unix_ms,
+            edges_mv: edges_mv.to_vec(),
+            charging: empty.clone(),
+            discharging: empty.clone(),
+            idle: empty,
+        }
+    }
+
+    pub fn cell_count(&self) -> usize {
+        self.idle.len()
+    }
+
+    /// 偏差所在的桶
+    pub fn bucket(&self, delta_mv: i32) -> usize {
+        self.edges_mv.partition_point(|&edge| edge <= delta_mv)
+    }
+
+    fn counts_mut(&mut self, state: PowerState) -> &mut Vec<Vec<u32>> {
+        match state {
+            PowerState::Charging => &mut self.charging,
+            PowerState::Discharging => &mut self.discharging,
+            PowerState::Idle => &mut self.idle,
+        }
+    }
+
+    /// 记录一次采样；`cell_voltages` 只取前 `cell_count` 节
+    pub fn record(&mut self, state: PowerState, cell_voltages: &[f32]) {
+        let cells = &cell_voltages[..self.cell_count().min(cell_voltages.len())];
+        if cells.is_empty() {
+            return;
+        }
+        let average = cells.iter().sum::<f32>() / cells.len() as f32;
+        let buckets: Vec<usize> = cells
+            .iter()
+            .map(|v| self.bucket(((v - average) * 1000.0).round() as i32))
+            .collect();
+        let counts = self.counts_mut(state);
+        for (cell, bucket) in buckets.into_iter().enumerate() {
+            counts[cell][bucket] = counts[cell][bucket].saturating_add(1);
+        }
+    }
+
+    pub fn is_due(&self, now_unix_ms: u64, window: Duration) -> bool {
+        now_unix_ms >= self.window_start_unix_ms + window.as_millis() as u64
+    }
+
+    /// 结束当前窗口，返回其发布载荷并从 `now_unix_ms` 开始新窗口
+    pub fn roll_over(&mut self, now_unix_ms: u64) -> Result<String, serde_json::Error> {
+        let payload = serde_json::to_string(&BalanceReport {
+            window_end_unix_ms: now_unix_ms,
+            histograms: self,
+        })?;
+        *self = BalanceHistograms::new(now_unix_ms, &self.edges_mv, self.cell_count());
+        Ok(payload)
+    }
+
+    /// 与当前配置兼容时恢复上次保存的窗口
+    pub fn load(path: &Path, edges_mv: &[i32], cell_count: usize) -> io::Result<Option<Self>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let saved: BalanceHistograms =
+            serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let buckets = edges_mv.len() + 1;
+        let compatible = saved.edges_mv == edges_mv
+            && saved.cell_count() == cell_count
+            && [&saved.charging, &saved.discharging, &saved.idle]
+                .iter()
+                .all(|counts| counts.len() == cell_count && counts.iter().all(|c| c.len() == buckets));
+        Ok(compatible.then_some(saved))
+    }
+
+    pub fn persist(&self, path: &Path) -> io::Result<()> {
+        let text = serde_json::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        utils::write_atomic(path, text.as_bytes())
+    }
+}
+
+/// 直方图任务的配置
+#[derive(Debug, Clone)]
+pub struct BalanceSettings {
+    pub window: Duration,
+    pub edges_mv: Vec<i32>,
+    pub cell_count: usize,
+    /// 与充放电状态检测相同的电流阈值 (A)
+    pub current_threshold: f32,
+    pub state_path: PathBuf,
+}
+
+/// 订阅测量数据累积电芯偏差直方图，窗口结束时以 retained 方式发布到
+/// `{prefix}/stats/balance_histograms`，并定期保存以便跨重启继续累积
+#[cfg(feature = "mqtt")]
+pub async fn balance_histogram_task(
+    mut samples: Subscription,
+    client: AsyncClient,
+    topic_prefix: String,
+    settings: BalanceSettings,
+) {
+    let mut histograms = match BalanceHistograms::load(&settings.state_path, &settings.edges_mv, settings.cell_count) {
+        Ok(Some(saved)) => {
+            info!("恢复电芯均衡直方图，窗口起点 {}", saved.window_start_unix_ms);
+            saved
+        }
+        Ok(None) => BalanceHistograms::new(utils::unix_ms_now(), &settings.edges_mv, settings.cell_count),
+        Err(e) => {
+            warn!("读取电芯均衡直方图 {} 失败: {:?}，重新开始统计", settings.state_path.display(), e);
+            BalanceHistograms::new(utils::unix_ms_now(), &settings.edges_mv, settings.cell_count)
+        }
+    };
+
+    let mut last_persist = tokio::time::Instant::now();
+    loop {
+        let sample: std::sync::Arc<TimestampedMeasurements> = match samples.recv().await {
+            Ok(sample) => sample,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("电芯均衡直方图处理过慢，跳过 {} 帧", n);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        let rolled_over = histograms.is_due(sample.ts_unix_ms, settings.window);
+        if rolled_over {
+            match histograms.roll_over(sample.ts_unix_ms) {
+                Ok(payload) if payload.len() > MAX_PAYLOAD_BYTES => {
+                    error!("电芯均衡直方图载荷过大 ({} 字节)，未发布", payload.len());
+                }
+                Ok(payload) => {
+                    let topic = balance_histograms_topic(&topic_prefix);
+                    if let Err(e) = client.publish(topic, QoS::AtLeastOnce, true, payload).await {
+                        error!("发布电芯均衡直方图失败: {:?}", e);
+                    }
+                }
+                Err(e) => error!("序列化电芯均衡直方图失败: {:?}", e),
+            }
+        }
+
+        let state = PowerState::from_current(sample.data.bq76920.coulomb_counter, settings.current_threshold);
+        histograms.record(state, &sample.data.bq76920.cell_voltages);
+
+        if rolled_over || last_persist.elapsed() >= PERSIST_INTERVAL {
+            last_persist = tokio::time::Instant::now();
+            persist_in_background(&histograms, &settings.state_path).await;
+        }
+    }
+    persist_in_background(&histograms, &settings.state_path).await;
+}
+
+// 写状态文件 (含 fsync) 可能阻塞较久，放到阻塞线程池，不占用运行时线程
+#[cfg(feature = "mqtt")]
+async fn persist_in_background(histograms: &BalanceHistograms, path: &Path) {
+    let (histograms, path) = (histograms.clone(), path.to_path_buf());
+    let result = tokio::task::spawn_blocking(move || histograms.persist(&path))
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)));
+    if let Err(e) = result {
+        error!("保存电芯均衡直方图失败: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EDGES: [i32; 4] = [-20, -5, 5, 20];
+
+    #[test]
+    fn deviations_land_in_half_open_buckets() {
+        let h = BalanceHistograms::new(0, &EDGES, 4);
+        assert_eq!(h.bucket(-100), 0);
+        assert_eq!(h.bucket(-20), 1);
+        assert_eq!(h.bucket(-6), 1);
+        assert_eq!(h.bucket(-5), 2);
+        assert_eq!(h.bucket(4), 2);
+        assert_eq!(h.bucket(5), 3);
+        assert_eq!(h.bucket(20), 4);
+    }
+
+    #[test]
+    fn samples_are_partitioned_by_power_state_relative_to_the_pack_average() {
+        let mut h = BalanceHistograms::new(0, &EDGES, 4);
+        // 平均 3.700 V：电芯偏差 -30 / 0 / +10 / +20 mV；第 5 节超出 cell_count，不计入
+        let cells = [3.670, 3.700, 3.710, 3.720, 0.0];
+        h.record(PowerState::Charging, &cells);
+        h.record(PowerState::Charging, &cells);
+        h.record(PowerState::Idle, &cells);
+
+        assert_eq!(h.charging, [[2, 0, 0, 0, 0], [0, 0, 2, 0, 0], [0, 0, 0, 2, 0], [0, 0, 0, 0, 2]]);
+        assert_eq!(h.idle, [[1, 0, 0, 0, 0], [0, 0, 1, 0, 0], [0, 0, 0, 1, 0], [0, 0, 0, 0, 1]]);
+        assert!(h.discharging.iter().flatten().all(|&c| c == 0));
+        h.record(PowerState::Discharging, &[]);
+        assert!(h.discharging.iter().flatten().all(|&c| c == 0));
+    }
+
+    #[test]
+    fn rollover_publishes_the_window_and_starts_empty() {
+        let window = Duration::from_secs(3600);
+        let mut h = BalanceHistograms::new(1_000, &EDGES, 2);
+        h.record(PowerState::Discharging, &[3.6, 3.7]);
+        assert!(!h.is_due(1_000 + 3_599_999, window));
+        assert!(h.is_due(1_000 + 3_600_000, window));
+
+        let payload: serde_json::Value = serde_json::from_str(&h.roll_over(3_601_000).unwrap()).unwrap();
+        assert_eq!(payload["window_start_unix_ms"], 1_000);
+        assert_eq!(payload["window_end_unix_ms"], 3_601_000);
+        assert_eq!(payload["edges_mv"], serde_json::json!(EDGES));
+        assert_eq!(payload["discharging"], serde_json::json!([[1, 0, 0, 0, 0], [0, 0, 0, 0, 1]]));
+        assert_eq!(h, BalanceHistograms::new(3_601_000, &EDGES, 2));
+    }
+
+    #[test]
+    fn persisted_windows_resume_only_with_a_compatible_configuration() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("balance.json");
+        assert_eq!(BalanceHistograms::load(&path, &EDGES, 4).unwrap(), None);
+
+        let mut h = BalanceHistograms::new(42, &EDGES, 4);
+        h.record(PowerState::Idle, &[3.7, 3.7, 3.7, 3.75]);
+        h.persist(&path).unwrap();
+        assert_eq!(BalanceHistograms::load(&path, &EDGES, 4).unwrap(), Some(h));
+        // 边界或电芯数改变后旧数据不再适用
+        assert_eq!(BalanceHistograms::load(&path, &[-10, 10], 4).unwrap(), None);
+        assert_eq!(BalanceHistograms::load(&path, &EDGES, 3).unwrap(), None);
+
+        fs::write(&path, "{not json").unwrap();
+        assert_eq!(BalanceHistograms::load(&path, &EDGES, 4).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn the_worst_case_payload_fits_under_the_broker_limit() {
+        // 边界数与数值位数都取最大，5 节电芯、三种状态的每个桶都是 u32::MAX
+        let edges: Vec<i32> = (0..MAX_BUCKET_EDGES as i32).map(|i| i32::MIN + i).collect();
+        let mut h = BalanceHistograms::new(u64::MAX / 2, &edges, 5);
+        for counts in [&mut h.charging, &mut h.discharging, &mut h.idle] {
+            counts.iter_mut().flatten().for_each(|c| *c = u32::MAX);
+        }
+        let payload = h.roll_over(u64::MAX).unwrap();
+        assert!(payload.len() <= MAX_PAYLOAD_BYTES, "{} 字节", payload.len());
+
+        // 计数饱和而不是回绕
+        let mut h = BalanceHistograms::new(0, &EDGES, 1);
+        h.idle[0][2] = u32::MAX;
+        h.record(PowerState::Idle, &[3.7]);
+        assert_eq!(h.idle[0][2], u32::MAX);
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::VecDeque;
+
+/// 有界发布缓冲区：broker 不可达时暂存样本，恢复后按顺序补发。
+/// 缓冲区满时丢弃最旧的样本并计数。
+#[derive(Debug)]
+pub struct PublishBuffer<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    dropped: u64,
+}
+
+impl<T> PublishBuffer<T> {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        PublishBuffer {
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, item: T) {
+        if self.queue.len() >= self.capacity {
+            self.queue.pop_front();
+            self.dropped += 1;
+        }
+        self.queue.push_back(item);
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.queue.front()
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        self.queue.pop_front()
+    }
+
+    /// 按从旧到新的顺序遍历
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.queue.iter()
+    }
+
+    /// 按从旧到新的顺序遍历，可修改元素
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.queue.iter_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// 因溢出而被丢弃的样本总数
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tracing::{error, info, warn};
+use rumqttc::{AsyncClient, QoS};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::capture::{self, CaptureRecord, Direction};
+use crate::data_models::{FlagBit, TimestampedMeasurements};
+use crate::pipeline::Subscription;
+use crate::utils;
+
+// 连续触发不断延长时一次抓取的帧数上限
+const MAX_BURST_FRAMES: usize = 1024;
+const FILE_PREFIX: &str = "burst-";
+const CAPTURE_SUFFIX: &str = ".cap";
+const META_SUFFIX: &str = ".json";
+
+pub fn burst_capture_topic(topic_prefix: &str) -> String {
+    format!("{}/events/burst_capture", topic_prefix)
+}
+
+/// 一次抓取的结果：触发前的 K 帧、触发帧，以及最后一次触发后的 K 帧
+#[derive(Debug, Clone, PartialEq)]
+pub struct Burst<T> {
+    /// 本次抓取中所有触发过的标志，按首次触发顺序
+    pub triggers: Vec<&'static str>,
+    /// 触发帧在 `frames` 中的下标
+    pub trigger_index: usize,
+    pub frames: Vec<T>,
+}
+
+/// 触发式抓取状态机：与时钟和 I/O 无关，逐帧喂入帧及其置位的触发标志。
+///
+/// 标志从未置位变为置位（上升沿）时开始抓取；抓取期间的新上升沿只延长
+/// 抓取（重新计算触发后的 K 帧），不会产生第二份重叠的记录。
+#[derive(Debug)]
+pub struct BurstCapture<T> {
+    frames_around: usize,
+    ring: VecDeque<T>,
+    asserted: Vec<&'static str>,
+    active: Option<(Burst<T>, usize)>,
+}
+
+impl<T: Clone> BurstCapture<T> {
+    /// `frames_around` 为触发前保留与触发后抓取的帧数 K
+    pub fn new(frames_around: usize) -> Self {
+        BurstCapture {
+            frames_around,
+            ring: VecDeque::with_capacity(frames_around),
+            asserted: Vec::new(),
+            active: None,
+        }
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// 喂入一帧及其当前置位的触发标志；抓取完成时返回记录
+    pub fn push(&mut self, frame: T, asserted: &[&'static str]) -> Option<Burst<T>> {
+        let rising: Vec<&'static str> = asserted
+            .iter()
+            .filter(|flag| !self.asserted.contains(flag))
+            .copied()
+            .collect();
+        self.asserted = asserted.to_vec();
+
+        let completed = match self.active.take() {
+            Some((mut burst, remaining)) => {
+                burst.frames.push(frame.clone());
+                let remaining = if rising.is_empty() {
+                    remaining.saturating_sub(1)
+                } else {
+                    for flag in rising {
+                        if !burst.triggers.contains(&flag) {
+                            burst.triggers.push(flag);
+                        }
+                    }
+                    self.frames_around
+                };
+                if remaining == 0 || burst.frames.len() >= MAX_BURST_FRAMES {
+                    Some(burst)
+                } else {
+                    self.active = Some((burst, remaining));
+                    None
+                }
+            }
+            None if !rising.is_empty() => {
+                let mut frames: Vec<T> = self.ring.iter().cloned().collect();
+                let trigger_index = frames.len();
+                frames.push(frame.clone());
+                let burst = Burst {
+                    triggers: rising,
+                    trigger_index,
+                    frames,
+                };
+                if self.frames_around == 0 {
+                    Some(burst)
+                } else {
+                    self.active = Some((burst, self.frames_around));
+                    None
+                }
+            }
+            None => None,
+        };
+
+        if self.frames_around > 0 {
+            if self.ring.len() == self.frames_around {
+                self.ring.pop_front();
+            }
+            self.ring.push_back(frame);
+        }
+        completed
+    }
+
+    /// 结束进行中的抓取（设备断开或退出时），返回已抓取的部分
+    pub fn finish(&mut self) -> Option<Burst<T>> {
+        self.active.take().map(|(burst, _)| burst)
+    }
+}
+
+/// 抓取任务的配置
+#[derive(Debug, Clone)]
+pub struct BurstSettings {
+    pub frames_around: usize,
+    /// 触发标志及其名称
+    pub triggers: Vec<(FlagBit, &'static str)>,
+    pub dir: PathBuf,
+    /// 保留的抓取数，更早的抓取被删除
+    pub max_files: usize,
+}
+
+/// 与抓取文件同名的 `.json`：帧号与触发标志；`frames` 与抓取文件中的记录一一对应
+#[derive(Debug, Serialize)]
+struct BurstRecord<'a> {
+    id: &'a str,
+    triggers: &'a [&'static str],
+    trigger_frame_id: u64,
+    frames: Vec<BurstFrame>,
+    capture: String,
+}
+
+#[derive(Debug, Serialize)]
+struct BurstFrame {
+    frame_id: u64,
+    ts_unix_ms: u64,
+}
+
+/// `{prefix}/events/burst_capture` 的通知载荷
+#[derive(Debug, Serialize)]
+struct BurstAnnouncement<'a> {
+    id: &'a str,
+    triggers: &'a [&'static str],
+    trigger_frame_id: u64,
+    frames: usize,
+    path: String,
+}
+
+/// 订阅测量数据，触发标志置位时把前后各 K 帧的原始字节写入 `{dir}/{id}.cap`，
+/// 帧号与触发标志写入 `{dir}/{id}.json`，并发布到 `{prefix}/events/burst_capture`
+pub async fn burst_capture_task(
+    mut samples: Subscription,
+    client: AsyncClient,
+    topic_prefix: String,
+    settings: BurstSettings,
+) {
+    let mut capture: BurstCapture<Arc<TimestampedMeasurements>> = BurstCapture::new(settings.frames_around);
+    loop {
+        let sample = match samples.recv().await {
+            Ok(sample) => sample,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("突发抓取处理过慢，跳过 {} 帧", n);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let asserted: Vec<&'static str> = settings
+            .triggers
+            .iter()
+            .filter(|(bit, _)| bit.is_set(&sample.data))
+            .map(|(_, name)| *name)
+            .collect();
+        let was_capturing = capture.is_capturing();
+        let completed = capture.push(sample, &asserted);
+        if !was_capturing && capture.is_capturing() {
+            info!("触发突发抓取: {}", asserted.join(", "));
+        }
+        if let Some(burst) = completed {
+            save_and_announce(&client, &topic_prefix, &settings, burst).await;
+        }
+    }
+    if let Some(burst) = capture.finish() {
+        save_and_announce(&client, &topic_prefix, &settings, burst).await;
+    }
+}
+
+async fn save_and_announce(
+    client: &AsyncClient,
+    topic_prefix: &str,
+    settings: &BurstSettings,
+    burst: Burst<Arc<TimestampedMeasurements>>,
+) {
+    let trigger_frame_id = burst.frames[burst.trigger_index].frame_id;
+    let id = format!("{}{}", FILE_PREFIX, trigger_frame_id);
+    let (dir, max_files) = (settings.dir.clone(), settings.max_files);
+    let save_id = id.clone();
+    let triggers = burst.triggers.clone();
+    // 抓取可能有上千帧，写文件与清理旧抓取不占用运行时线程
+    let saved = tokio::task::spawn_blocking(move || {
+        let saved = save_burst(&dir, &save_id, &burst);
+        if let Err(e) = prune_bursts(&dir, max_files) {
+            warn!("清理旧的突发抓取失败: {:?}", e);
+        }
+        saved
+    })
+    .await
+    .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+    let (path, frames) = match saved {
+        Ok(saved) => saved,
+        Err(e) => {
+            error!("保存突发抓取 {} 失败: {:?}", id, e);
+            return;
+        }
+    };
+    info!("突发抓取 {} 已保存 ({} 帧): {}", id, frames, path.display());
+
+    let announcement = BurstAnnouncement {
+        id: &id,
+        triggers: &triggers,
+        trigger_frame_id,
+        frames,
+        path: path.display().to_string(),
+    };
+    let payload = match serde_json::to_string(&announcement) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("序列化突发抓取通知失败: {:?}", e);
+            return;
+        }
+    };
+    if let Err(e) = client
+        .publish(burst_capture_topic(topic_prefix), QoS::AtLeastOnce, false, payload)
+        .await
+    {
+        error!("发布突发抓取通知失败: {:?}", e);
+    }
+}
+
+/// 写入抓取文件与其 `.json`，返回抓取文件路径与帧数；没有原始帧的样本（非设备来源）不写入
+fn save_burst(dir: &Path, id: &str, burst: &Burst<Arc<TimestampedMeasurements>>) -> io::Result<(PathBuf, usize)> {
+    std::fs::create_dir_all(dir)?;
+    let mut encoded = capture::header();
+    let mut frames = Vec::with_capacity(burst.frames.len());
+    for sample in &burst.frames {
+        let Some(raw) = &sample.raw else {
+            continue;
+        };
+        let record = CaptureRecord {
+            timestamp_us: sample.ts_unix_ms * 1000,
+            direction: Direction::Push,
+            bytes: raw.to_vec(),
+        };
+        record.encode(&mut encoded);
+        frames.push(BurstFrame { frame_id: sample.frame_id, ts_unix_ms: sample.ts_unix_ms });
+    }
+    let skipped = burst.frames.len() - frames.len();
+    if skipped > 0 {
+        warn!("突发抓取 {} 中 {} 帧没有原始字节，未写入", id, skipped);
+    }
+    let count = frames.len();
+    let capture_name = format!("{}{}", id, CAPTURE_SUFFIX);
+    let path = dir.join(&capture_name);
+    utils::write_atomic(&path, &encoded)?;
+    let record = BurstRecord {
+        id,
+        triggers: &burst.triggers,
+        trigger_frame_id: burst.frames[burst.trigger_index].frame_id,
+        frames,
+        capture: capture_name,
+    };
+    let json = serde_json::to_vec(&record).map_err(io::Error::other)?;
+    utils::write_atomic(&dir.join(format!("{}{}", id, META_SUFFIX)), &json)?;
+    Ok((path, count))
+}
+
+// `burst-{帧号}.cap` / `.json` 中的帧号；其他文件返回 None
+fn burst_frame_id(name: &str) -> Option<u64> {
+    let stem = name.strip_suffix(CAPTURE_SUFFIX).or_else(|| name.strip_suffix(META_SUFFIX))?;
+    stem.strip_prefix(FILE_PREFIX)?.parse().ok()
+}
+
+/// 只保留帧号最大的 `keep` 次抓取，返回删除的文件数；帧号跨重启递增，越大越新
+pub fn prune_bursts(dir: &Path, keep: usize) -> io::Result<usize> {
+    let mut files: Vec<(u64, PathBuf)> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let id = burst_frame_id(entry.file_name().to_str()?)?;
+            Some((id, entry.path()))
+        })
+        .collect();
+    let mut ids: Vec<u64> = files.iter().map(|(id, _)| *id).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    let Some(&oldest_kept) = ids.len().checked_sub(keep).and_then(|first| ids.get(first)) else {
+        return Ok(0);
+    };
+    files.retain(|(id, _)| *id < oldest_kept);
+    for (_, path) in &files {
+        std::fs::remove_file(path)?;
+    }
+    Ok(files.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::read_capture;
+    use crate::test_support::PayloadBuilder;
+
+    // 按脚本逐帧喂入 (帧号, 置位的标志)，返回完成的抓取
+    fn run(capture: &mut BurstCapture<u32>, script: &[(u32, &[&'static str])]) -> Vec<Burst<u32>> {
+        script.iter().filter_map(|(frame, asserted)| capture.push(*frame, asserted)).collect()
+    }
+
+    #[test]
+    fn a_rising_edge_captures_k_frames_on_each_side() {
+        let mut capture = BurstCapture::new(2);
+        let bursts = run(&mut capture, &[(1, &[]), (2, &[]), (3, &[]), (4, &["SCD"]), (5, &["SCD"]), (6, &[]), (7, &[])]);
+        assert_eq!(bursts, vec![Burst { triggers: vec!["SCD"], trigger_index: 2, frames: vec![2, 3, 4, 5, 6] }]);
+        assert!(!capture.is_capturing());
+    }
+
+    #[test]
+    fn a_held_flag_does_not_retrigger() {
+        let mut capture = BurstCapture::new(1);
+        let bursts = run(&mut capture, &[(1, &["OCD"]), (2, &["OCD"]), (3, &["OCD"]), (4, &["OCD"]), (5, &[]), (6, &["OCD"]), (7, &[])]);
+        assert_eq!(
+            bursts,
+            vec![
+                Burst { triggers: vec!["OCD"], trigger_index: 0, frames: vec![1, 2] },
+                Burst { triggers: vec!["OCD"], trigger_index: 1, frames: vec![5, 6, 7] },
+            ]
+        );
+    }
+
+    #[test]
+    fn overlapping_triggers_extend_one_capture() {
+        let mut capture = BurstCapture::new(2);
+        let script: &[(u32, &[&'static str])] =
+            &[(1, &[]), (2, &["SCD"]), (3, &[]), (4, &["OCD"]), (5, &["OCD", "SCD"]), (6, &[]), (7, &[]), (8, &[])];
+        let bursts = run(&mut capture, script);
+        assert_eq!(bursts.len(), 1);
+        assert_eq!(bursts[0].triggers, vec!["SCD", "OCD"]);
+        assert_eq!(bursts[0].trigger_index, 1);
+        // 最后一次上升沿 (5) 之后再抓 2 帧
+        assert_eq!(bursts[0].frames, vec![1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn zero_frames_around_captures_only_the_trigger() {
+        let mut capture = BurstCapture::new(0);
+        let bursts = run(&mut capture, &[(1, &[]), (2, &["SYSOVP"]), (3, &[])]);
+        assert_eq!(bursts, vec![Burst { triggers: vec!["SYSOVP"], trigger_index: 0, frames: vec![2] }]);
+    }
+
+    #[test]
+    fn finish_returns_the_partial_capture() {
+        let mut capture = BurstCapture::new(3);
+        assert!(run(&mut capture, &[(1, &[]), (2, &["SCD"]), (3, &[])]).is_empty());
+        assert_eq!(capture.finish(), Some(Burst { triggers: vec!["SCD"], trigger_index: 1, frames: vec![1, 2, 3] }));
+        assert_eq!(capture.finish(), None);
+    }
+
+    #[test]
+    fn continuous_retriggering_is_capped() {
+        let mut capture = BurstCapture::new(4);
+        let mut completed = None;
+        for frame in 0..(2 * MAX_BURST_FRAMES as u32) {
+            // 每隔一帧重新置位，抓取不断延长
+            let asserted: &[&'static str] = if frame % 2 == 0 { &["SCD"] } else { &[] };
+            if let Some(burst) = capture.push(frame, asserted) {
+                completed = Some(burst);
+                break;
+            }
+        }
+        assert_eq!(completed.unwrap().frames.len(), MAX_BURST_FRAMES);
+    }
+
+    #[test]
+    fn saved_bursts_hold_the_raw_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let builders = [PayloadBuilder::new().vbat_mv(16000), PayloadBuilder::new().vbat_mv(15000)];
+        let frames: Vec<_> = builders.iter().enumerate().map(|(i, b)| Arc::new(b.sample(10 + i as u64, 1_000 + i as u64))).collect();
+        let burst = Burst { triggers: vec!["SCD"], trigger_index: 1, frames };
+        let (path, count) = save_burst(dir.path(), "burst-11", &burst).unwrap();
+        assert_eq!(count, 2);
+        let records = read_capture(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        for (record, builder) in records.iter().zip(&builders) {
+            assert_eq!(record.bytes, builder.frame());
+            assert_eq!(record.direction, Direction::Push);
+        }
+        assert_eq!(records[1].timestamp_us, 1_001_000);
+        let meta: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.path().join("burst-11.json")).unwrap()).unwrap();
+        assert_eq!(meta["trigger_frame_id"], 11);
+        assert_eq!(meta["capture"], "burst-11.cap");
+        assert_eq!(meta["frames"][0]["frame_id"], 10);
+    }
+
+    #[test]
+    fn pruning_keeps_the_newest_bursts_by_frame_id() {
+        let dir = tempfile::tempdir().unwrap();
+        for id in [9, 10, 100, 11] {
+            for suffix in [CAPTURE_SUFFIX, META_SUFFIX] {
+                std::fs::write(dir.path().join(format!("burst-{}{}", id, suffix)), b"").unwrap();
+            }
+        }
+        std::fs::write(dir.path().join("notes.txt"), b"").unwrap();
+        assert_eq!(prune_bursts(dir.path(), 2).unwrap(), 4);
+        let mut left: Vec<String> =
+            std::fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        left.sort();
+        assert_eq!(left, ["burst-100.cap", "burst-100.json", "burst-11.cap", "burst-11.json", "notes.txt"]);
+        assert_eq!(prune_bursts(dir.path(), 10).unwrap(), 0);
+    }
+}
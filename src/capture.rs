@@ -0,0 +1,296 @@
+//! 原始 USB 帧抓取 (`--capture <path>`)。
+//!
+//! 推送端点与响应端点读到的每一段数据、写入命令端点的每条命令都按收发顺序记录到一个二进制文件，
+//! 用于离线复现解析问题。文件格式（整数均为小端）：
+//!
+//! - 文件头：魔数 `UPS120CP` (8 字节) + 版本 `u16`
+//! - 记录：`timestamp_us: u64` (Unix 微秒) + `direction: u8` + `len: u16` + `len` 字节数据
+//!
+//! USB 线程只把记录放进有界队列，写文件由独立任务完成，磁盘 I/O 不会阻塞读取；
+//! 队列满时丢弃记录并计数。
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+pub const MAGIC: [u8; 8] = *b"UPS120CP";
+pub const VERSION: u16 = 1;
+
+// USB 线程到写文件任务的队列长度
+const CAPTURE_QUEUE_SIZE: usize = 1024;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 数据的方向与端点
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// 推送端点 (IN)
+    Push,
+    /// 响应端点 (IN)
+    Response,
+    /// 命令端点 (OUT)
+    Command,
+}
+
+impl Direction {
+    pub fn as_u8(self) -> u8 {
+        match self {
+            Direction::Push => 0,
+            Direction::Response => 1,
+            Direction::Command => 2,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Direction::Push),
+            1 => Some(Direction::Response),
+            2 => Some(Direction::Command),
+            _ => None,
+        }
+    }
+}
+
+/// 一条抓取记录
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    pub timestamp_us: u64,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+impl CaptureRecord {
+    /// 追加编码后的记录；超过 `u16::MAX` 的部分被截断（实际帧远小于该长度）
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let len = self.bytes.len().min(u16::MAX as usize);
+        out.extend_from_slice(&self.timestamp_us.to_le_bytes());
+        out.push(self.direction.as_u8());
+        out.extend_from_slice(&(len as u16).to_le_bytes());
+        out.extend_from_slice(&self.bytes[..len]);
+    }
+}
+
+/// 文件头
+pub fn header() -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out
+}
+
+/// 读取整个抓取文件；文件头不符或记录被截断时返回 `InvalidData`
+pub fn read_capture(path: &Path) -> io::Result<Vec<CaptureRecord>> {
+    let data = std::fs::read(path)?;
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+    let header = header();
+    if data.len() < header.len() || data[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not a capture file"));
+    }
+    let version = u16::from_le_bytes([data[8], data[9]]);
+    if version != VERSION {
+        return Err(invalid(&format!("unsupported capture version {}", version)));
+    }
+    let mut records = Vec::new();
+    let mut rest = &data[header.len()..];
+    while !rest.is_empty() {
+        if rest.len() < 11 {
+            return Err(invalid("truncated record header"));
+        }
+        let timestamp_us = u64::from_le_bytes(rest[..8].try_into().unwrap_or_default());
+        let direction = Direction::from_u8(rest[8]).ok_or_else(|| invalid("unknown record direction"))?;
+        let len = u16::from_le_bytes([rest[9], rest[10]]) as usize;
+        let Some(bytes) = rest.get(11..11 + len) else {
+            return Err(invalid("truncated record"));
+        };
+        records.push(CaptureRecord { timestamp_us, direction, bytes: bytes.to_vec() });
+        rest = &rest[11 + len..];
+    }
+    Ok(records)
+}
+
+/// USB 线程持有的记录入口；记录从不阻塞
+#[derive(Debug, Clone)]
+pub struct CaptureSink {
+    tx: mpsc::Sender<CaptureRecord>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl CaptureSink {
+    pub fn record(&self, direction: Direction, bytes: &[u8]) {
+        let record = CaptureRecord {
+            timestamp_us: unix_us_now(),
+            direction,
+            bytes: bytes.to_vec(),
+        };
+        if self.tx.try_send(record).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn unix_us_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// 创建抓取文件并写入文件头，启动写文件任务；文件无法创建时返回错误
+pub fn spawn_capture(path: &Path, shutdown: CancellationToken) -> io::Result<CaptureSink> {
+    let mut file = File::create(path)?;
+    file.write_all(&header())?;
+    info!("USB 帧抓取写入 {}", path.display());
+    let (tx, rx) = mpsc::channel(CAPTURE_QUEUE_SIZE);
+    let dropped = Arc::new(AtomicU64::new(0));
+    tokio::spawn(capture_task(
+        tokio::fs::File::from_std(file),
+        rx,
+        dropped.clone(),
+        shutdown,
+    ));
+    Ok(CaptureSink { tx, dropped })
+}
+
+/// 写入记录直到所有 `CaptureSink` 都被释放；收到退出信号后立即落盘一次，继续接收
+/// 释放设备时的取消订阅命令
+async fn capture_task(
+    file: tokio::fs::File,
+    mut rx: mpsc::Receiver<CaptureRecord>,
+    dropped: Arc<AtomicU64>,
+    shutdown: CancellationToken,
+) {
+    let mut writer = BufWriter::new(file);
+    let mut flush_timer = tokio::time::interval(FLUSH_INTERVAL);
+    let mut reported_dropped = 0;
+    let mut encoded = Vec::new();
+    let mut records = 0u64;
+    loop {
+        tokio::select! {
+            record = rx.recv() => {
+                let Some(record) = record else { break };
+                encoded.clear();
+                record.encode(&mut encoded);
+                if let Err(e) = writer.write_all(&encoded).await {
+                    error!("写入抓取文件失败: {:?}，停止抓取", e);
+                    return;
+                }
+                records += 1;
+                continue;
+            }
+            _ = flush_timer.tick() => {}
+            _ = shutdown.cancelled(), if !shutdown.is_cancelled() => {}
+        }
+        if let Err(e) = writer.flush().await {
+            error!("抓取文件落盘失败: {:?}", e);
+        }
+        let total_dropped = dropped.load(Ordering::Relaxed);
+        if total_dropped > reported_dropped {
+            warn!("抓取队列已满，累计丢弃 {} 条记录", total_dropped);
+            reported_dropped = total_dropped;
+        }
+    }
+    if let Err(e) = writer.flush().await {
+        error!("抓取文件落盘失败: {:?}", e);
+    }
+    info!("USB 帧抓取结束，共 {} 条记录", records);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp_us: u64, direction: Direction, bytes: &[u8]) -> CaptureRecord {
+        CaptureRecord { timestamp_us, direction, bytes: bytes.to_vec() }
+    }
+
+    fn encode_file(records: &[CaptureRecord]) -> Vec<u8> {
+        let mut data = header();
+        for record in records {
+            record.encode(&mut data);
+        }
+        data
+    }
+
+    #[test]
+    fn records_round_trip_through_the_file_format() {
+        let records = [
+            record(1_700_000_000_000_000, Direction::Command, &[0x01]),
+            record(1_700_000_000_000_500, Direction::Response, &[0xAA; 70]),
+            record(1_700_000_000_001_000, Direction::Push, &[]),
+        ];
+        let data = encode_file(&records);
+        // 文件头 10 字节，每条记录 11 字节的头加数据
+        assert_eq!(data.len(), 10 + 3 * 11 + 1 + 70);
+        assert_eq!(&data[..10], b"UPS120CP\x01\x00");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.cap");
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(read_capture(&path).unwrap(), records);
+    }
+
+    #[test]
+    fn oversized_records_are_truncated_to_the_length_field() {
+        let mut out = Vec::new();
+        record(0, Direction::Push, &vec![0x55; u16::MAX as usize + 10]).encode(&mut out);
+        assert_eq!(out.len(), 11 + u16::MAX as usize);
+        assert_eq!(&out[9..11], &u16::MAX.to_le_bytes());
+    }
+
+    #[test]
+    fn damaged_files_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.cap");
+        let valid = encode_file(&[record(1, Direction::Push, &[1, 2, 3])]);
+
+        let mut wrong_version = valid.clone();
+        wrong_version[8] = 2;
+        let mut unknown_direction = valid.clone();
+        unknown_direction[10 + 8] = 3;
+        for (data, reason) in [
+            (b"UPS120C".to_vec(), "not a capture file"),
+            (b"NOTACAPTURE".to_vec(), "not a capture file"),
+            (wrong_version, "unsupported capture version 2"),
+            (unknown_direction, "unknown record direction"),
+            (valid[..valid.len() - 5].to_vec(), "truncated record header"),
+            (valid[..valid.len() - 1].to_vec(), "truncated record"),
+        ] {
+            std::fs::write(&path, &data).unwrap();
+            let error = read_capture(&path).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(error.to_string(), reason);
+        }
+    }
+
+    #[tokio::test]
+    async fn the_sink_writes_records_in_order_once_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.cap");
+        let sink = spawn_capture(&path, CancellationToken::new()).unwrap();
+        sink.record(Direction::Command, &[0x10]);
+        sink.record(Direction::Response, &[0x20, 0x21]);
+        sink.record(Direction::Push, &[0x30, 0x31, 0x32]);
+        drop(sink);
+
+        // 所有入口释放后写文件任务落盘并结束
+        let records = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match read_capture(&path) {
+                    Ok(records) if records.len() == 3 => break records,
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let directions: Vec<_> = records.iter().map(|r| (r.direction, r.bytes.len())).collect();
+        assert_eq!(directions, [(Direction::Command, 1), (Direction::Response, 2), (Direction::Push, 3)]);
+        assert!(records.windows(2).all(|pair| pair[0].timestamp_us <= pair[1].timestamp_us));
+    }
+}
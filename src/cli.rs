@@ -0,0 +1,78 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+
+use crate::replay::parse_speed;
+use crate::wizard::InitArgs;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "UPS120 上位机守护进程")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// 把收发的原始 USB 帧记录到该文件（二进制格式，见 capture 模块）
+    #[arg(long, global = true, value_name = "PATH")]
+    pub capture: Option<PathBuf>,
+    /// 不打开 USB 设备，回放 `--capture` 记录的文件，读完后退出
+    #[arg(long, global = true, value_name = "PATH", conflicts_with = "capture")]
+    pub replay: Option<PathBuf>,
+    /// 回放速度，如 1x、10x、0.5x
+    #[arg(long, global = true, value_parser = parse_speed, default_value = "1x", requires = "replay")]
+    pub speed: f64,
+    /// 不打开 USB 设备，生成模拟的测量数据（用于没有硬件时调试仪表盘与自动化）
+    #[arg(long, global = true, conflicts_with_all = ["replay", "capture"])]
+    pub simulate: bool,
+    /// 模拟数据的生成间隔，例如 1s、200ms
+    #[arg(long, global = true, value_parser = parse_sim_interval, default_value = "1s", requires = "simulate")]
+    pub sim_interval: Duration,
+    /// 模拟数据的随机种子，相同种子与场景产生相同的数值序列
+    #[arg(long, global = true, default_value_t = 0, requires = "simulate")]
+    pub sim_seed: u64,
+    /// 模拟场景文件（TOML 阶段列表，见 simulate 模块）；默认充电与放电交替
+    #[arg(long, global = true, value_name = "PATH", requires = "simulate")]
+    pub sim_scenario: Option<PathBuf>,
+    /// 照常连接设备并解析，但不连接 MQTT broker：每条要发布的消息只以 info 级别输出到日志
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+}
+
+// 间隔为 0 时 tokio::time::interval 会 panic
+fn parse_sim_interval(text: &str) -> Result<Duration, String> {
+    match humantime::parse_duration(text) {
+        Ok(interval) if !interval.is_zero() => Ok(interval),
+        Ok(_) => Err("interval must be greater than 0".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// 以严格模式运行指定时长后退出，并在 stdout 输出检测摘要（出厂检测用）
+    Validate {
+        /// 检测时长，例如 60s、2m
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "60s")]
+        duration: Duration,
+    },
+    /// 交互式生成配置文件 (.env)：broker、认证、主题前缀与 USB 设备
+    Init(InitArgs),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sim_interval_must_be_positive() {
+        let cli = Cli::try_parse_from(["ups120-daemon", "--simulate", "--sim-interval", "200ms"]).unwrap();
+        assert_eq!(cli.sim_interval, Duration::from_millis(200));
+        let cli = Cli::try_parse_from(["ups120-daemon", "--simulate"]).unwrap();
+        assert_eq!(cli.sim_interval, Duration::from_secs(1));
+
+        for zero in ["0s", "0ms"] {
+            let error = Cli::try_parse_from(["ups120-daemon", "--simulate", "--sim-interval", zero]).unwrap_err();
+            assert_eq!(error.kind(), clap::error::ErrorKind::ValueValidation, "{}", zero);
+        }
+        assert!(Cli::try_parse_from(["ups120-daemon", "--simulate", "--sim-interval", "soon"]).is_err());
+    }
+}
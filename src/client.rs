@@ -0,0 +1,451 @@
+//! 供其他程序嵌入的设备读取接口。
+//!
+//! `Ups120Client` 封装设备发现、订阅命令与事件通道，`measurements()` 把设备事件收敛为
+//! `AllMeasurements<5>` 的异步流；不依赖 MQTT，关闭 `mqtt` feature 后仍可使用。
+//! 守护进程本身也通过它驱动设备注册表、回放与模拟任务。
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::stream::{self, Stream};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+
+use crate::conversion::ConversionContext;
+use crate::data_models::AllMeasurements;
+use crate::debug_dump::DebugDump;
+use crate::device_registry::{device_registry_task, DeviceRegistry, DiscoverySettings};
+use crate::frame_id::FrameIdAllocator;
+use crate::sequence::SequenceTracker;
+use crate::timing::UsbTimeouts;
+use crate::transport::UsbTransport;
+use crate::usb_handlers::{connect_and_subscribe_usb, handle_push_frame, read_frame, PushContext, UsbManagerSettings};
+use crate::usb_types::{DeviceCommand, DeviceEvent, DeviceId, UsbCommand, UsbEvent, UsbId, UsbLinkStats};
+use crate::utils::unix_ms_now;
+
+/// 命令与事件通道的容量
+const CHANNEL_CAPACITY: usize = 32;
+
+/// 直接在给定传输上读取时使用的设备标识
+pub const TRANSPORT_DEVICE: &str = "transport";
+
+/// 设备读取的句柄：向设备发送命令，接收带设备标识的事件。
+/// 句柄（或由它得到的测量流）被丢弃后命令通道关闭，后台任务随之退出
+#[derive(Debug)]
+pub struct Ups120Client {
+    commands: mpsc::Sender<DeviceCommand>,
+    events: mpsc::Receiver<DeviceEvent>,
+    task: JoinHandle<()>,
+}
+
+/// `Ups120Client::with_transport` 的参数
+#[derive(Debug, Clone)]
+pub struct TransportSettings {
+    pub frame_ids: Arc<FrameIdAllocator>,
+    pub conversion_ctx: Arc<ConversionContext>,
+    pub timeouts: UsbTimeouts,
+    /// 取消后中断推送读取，任务发出 `Detached` 后退出
+    pub shutdown: CancellationToken,
+    pub debug_dump: DebugDump,
+    /// 握手后设置的推送间隔 (ms)
+    pub report_interval: Option<u16>,
+}
+
+impl Default for TransportSettings {
+    fn default() -> Self {
+        TransportSettings {
+            frame_ids: Arc::new(FrameIdAllocator::in_memory()),
+            conversion_ctx: Arc::new(ConversionContext::default()),
+            timeouts: UsbTimeouts::default(),
+            shutdown: CancellationToken::new(),
+            debug_dump: DebugDump::default(),
+            report_interval: None,
+        }
+    }
+}
+
+impl Ups120Client {
+    /// 启动设备注册表：按 `discovery` 周期扫描 USB 总线，为每块匹配的设备启动管理任务
+    pub fn spawn(settings: UsbManagerSettings, discovery: DiscoverySettings) -> Self {
+        Self::from_task(|cmd_rx, event_tx| {
+            device_registry_task(DeviceRegistry::new(settings, event_tx), discovery, cmd_rx)
+        })
+    }
+
+    /// 在一个已打开的传输上握手并读取推送数据，不做设备发现与重连。
+    /// 传输返回 `NoDevice` 或 `settings.shutdown` 取消后发出 `Detached` 并停止
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use futures::StreamExt;
+    /// use ups120_daemon::client::{TransportSettings, Ups120Client};
+    /// use ups120_daemon::simulate::{Scenario, Simulator};
+    /// use ups120_daemon::transport::MockTransport;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let builder = Simulator::new(Scenario::default(), Duration::from_secs(1), 5, 0).sample_builder();
+    ///
+    /// // 旧固件不回复 GetVersion；订阅后先推送两帧无法解析的数据（丢弃后继续读取），再推送一帧，随后设备断开
+    /// let transport = MockTransport::new()
+    ///     .respond(Err(rusb::Error::Timeout))
+    ///     .respond(Ok(builder.response_frame()))
+    ///     .push(Ok(vec![0x7F, 0xDE, 0xAD, 0xBE, 0xEF]))
+    ///     .push(Ok(vec![0xFF; 64]))
+    ///     .push(Ok(builder.frame()))
+    ///     .push(Err(rusb::Error::NoDevice));
+    ///
+    /// let client = Ups120Client::with_transport(transport, TransportSettings::default());
+    /// let mut measurements = Box::pin(client.measurements());
+    /// let first = measurements.next().await.expect("一帧测量数据");
+    /// assert_eq!(first.bq76920.cells().len(), 5);
+    /// assert_eq!(first, builder.measurements());
+    /// // 设备断开后流结束
+    /// assert!(measurements.next().await.is_none());
+    /// # }
+    /// ```
+    pub fn with_transport<T>(transport: T, settings: TransportSettings) -> Self
+    where
+        T: UsbTransport + Send + Sync + 'static,
+    {
+        Self::from_task(|cmd_rx, event_tx| transport_task(transport, settings, cmd_rx, event_tx))
+    }
+
+    /// 由任意任务产生事件（回放、模拟等）：任务接收命令、发送事件，语义与设备注册表一致
+    pub fn from_task<F, Fut>(task: F) -> Self
+    where
+        F: FnOnce(mpsc::Receiver<DeviceCommand>, mpsc::Sender<DeviceEvent>) -> Fut,
+        Fut: Future + Send + 'static,
+    {
+        let (commands, cmd_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let (event_tx, events) = mpsc::channel(CHANNEL_CAPACITY);
+        let task = task(cmd_rx, event_tx);
+        let task = tokio::spawn(async move {
+            task.await;
+        });
+        Ups120Client { commands, events, task }
+    }
+
+    /// 发送一条命令；`target` 为 None 时发给所有设备
+    pub async fn send(&self, target: Option<DeviceId>, command: UsbCommand) -> Result<(), SendError<DeviceCommand>> {
+        self.commands.send(DeviceCommand { target, command }).await
+    }
+
+    /// 重新订阅推送
+    pub async fn subscribe(&self, target: Option<DeviceId>) -> Result<(), SendError<DeviceCommand>> {
+        self.send(target, UsbCommand::Subscribe).await
+    }
+
+    /// 通知设备停止推送并释放接口，完成后收到 `UsbEvent::Unsubscribed`
+    pub async fn unsubscribe(&self, target: Option<DeviceId>) -> Result<(), SendError<DeviceCommand>> {
+        self.send(target, UsbCommand::Unsubscribe).await
+    }
+
+    /// 关闭并重新打开设备
+    pub async fn reconnect(&self, target: Option<DeviceId>) -> Result<(), SendError<DeviceCommand>> {
+        self.send(target, UsbCommand::Reconnect).await
+    }
+
+    /// 设置推送间隔 (ms)；设备重连后沿用该间隔
+    pub async fn set_report_interval(&self, target: Option<DeviceId>, interval_ms: u16) -> Result<(), SendError<DeviceCommand>> {
+        self.send(target, UsbCommand::SetInterval(interval_ms)).await
+    }
+
+    /// 等待下一个设备事件；所有后台任务退出后返回 None
+    pub async fn next_event(&mut self) -> Option<DeviceEvent> {
+        self.events.recv().await
+    }
+
+    /// 只保留测量数据的流；其余事件被丢弃，所有设备的数据合并在同一个流中
+    pub fn measurements(self) -> impl Stream<Item = AllMeasurements<5>> + Send + 'static {
+        // 命令端随流一起保留，避免后台任务因命令通道关闭而退出
+        stream::unfold(self, |mut client| async move {
+            loop {
+                let DeviceEvent { event, .. } = client.events.recv().await?;
+                if let UsbEvent::Measurements(sample) = event {
+                    return Some((sample.data, client));
+                }
+            }
+        })
+    }
+
+    /// 拆分为命令发送端、事件接收端与后台任务。命令发送端全部丢弃后后台任务等待各设备释放接口再结束，
+    /// 退出时可等待返回的任务句柄确认设备已释放
+    pub fn into_parts(self) -> (mpsc::Sender<DeviceCommand>, mpsc::Receiver<DeviceEvent>, JoinHandle<()>) {
+        (self.commands, self.events, self.task)
+    }
+}
+
+/// `with_transport` 的后台任务：握手、逐帧读取，收到取消订阅命令时停止
+async fn transport_task<T>(
+    transport: T,
+    settings: TransportSettings,
+    mut cmd_rx: mpsc::Receiver<DeviceCommand>,
+    event_tx: mpsc::Sender<DeviceEvent>,
+) where
+    T: UsbTransport + Send + Sync + 'static,
+{
+    let device = DeviceId::Serial(TRANSPORT_DEVICE.to_string());
+    let (raw_tx, mut raw_rx) = mpsc::channel::<UsbEvent>(CHANNEL_CAPACITY);
+    let forward_device = device.clone();
+    tokio::spawn(async move {
+        while let Some(event) = raw_rx.recv().await {
+            if event_tx.send(DeviceEvent { device: forward_device.clone(), event }).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let transport = Arc::new(transport);
+    let protocol = match connect_and_subscribe_usb(transport.as_ref(), &settings.timeouts, settings.report_interval).await {
+        Ok(protocol) => {
+            let connected = UsbEvent::Connected { device_id: device.clone(), usb_id: UsbId { vid: 0, pid: 0 }, protocol };
+            let _ = raw_tx.send(connected).await;
+            protocol
+        }
+        Err(e) => {
+            error!(error = %e, "传输握手失败");
+            let _ = raw_tx.send(UsbEvent::Error(e)).await;
+            let _ = raw_tx.send(UsbEvent::Detached).await;
+            return;
+        }
+    };
+
+    let mut sequence = SequenceTracker::default();
+    let mut link_stats = UsbLinkStats::default();
+    loop {
+        if let Ok(DeviceCommand { command: UsbCommand::Unsubscribe, .. }) = cmd_rx.try_recv() {
+            let _ = raw_tx.send(UsbEvent::Unsubscribed).await;
+            break;
+        }
+        let reader = Arc::clone(&transport);
+        let shutdown = settings.shutdown.clone();
+        let read = tokio::task::spawn_blocking(move || {
+            let mut buf = vec![0u8; 256];
+            read_frame(reader.as_ref(), &mut buf, Duration::from_secs(1), &shutdown).map(|n| {
+                buf.truncate(n);
+                buf
+            })
+        })
+        .await
+        .unwrap_or(Err(rusb::Error::Other));
+        match read {
+            Ok(frame) if frame.is_empty() => {}
+            Ok(frame) => {
+                let mut push = PushContext {
+                    device: &device,
+                    frame_ids: &settings.frame_ids,
+                    conversion_ctx: &settings.conversion_ctx,
+                    protocol,
+                    event_tx: &raw_tx,
+                    sequence: &mut sequence,
+                    link_stats: &mut link_stats,
+                    debug_dump: &settings.debug_dump,
+                };
+                handle_push_frame(&frame, unix_ms_now(), &mut push).await;
+            }
+            Err(rusb::Error::Timeout) => {}
+            Err(rusb::Error::NoDevice) => {
+                let _ = raw_tx.send(UsbEvent::Disconnected(rusb::Error::NoDevice.into())).await;
+                break;
+            }
+            Err(rusb::Error::Interrupted) if settings.shutdown.is_cancelled() => break,
+            Err(e) => {
+                error!(error = ?e, "传输读取失败");
+                let _ = raw_tx.send(UsbEvent::Disconnected(e.into())).await;
+                break;
+            }
+        }
+    }
+    let _ = raw_tx.send(UsbEvent::Detached).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::test_support::PayloadBuilder;
+    use crate::transport::MockTransport;
+    use crate::usb_types::UsbError;
+
+    fn device(serial: &str) -> DeviceId {
+        DeviceId::Serial(serial.to_string())
+    }
+
+    async fn next(client: &mut Ups120Client) -> UsbEvent {
+        tokio::time::timeout(Duration::from_secs(5), client.next_event()).await.expect("事件未到达").expect("通道已关闭").event
+    }
+
+    #[tokio::test]
+    async fn the_stream_keeps_only_measurements_from_every_device() {
+        let first = PayloadBuilder::new().cell_mv(0, 3600);
+        let second = PayloadBuilder::new().cell_mv(0, 3800);
+        let samples = [first.sample(1, 1000), second.sample(2, 1001)];
+        let client = Ups120Client::from_task(move |_cmd_rx, event_tx| async move {
+            let send = |serial: &str, event| event_tx.send(DeviceEvent { device: device(serial), event });
+            send("A", UsbEvent::Stale).await.unwrap();
+            send("A", UsbEvent::Measurements(Arc::new(samples[0].clone()))).await.unwrap();
+            send("B", UsbEvent::Stats(UsbLinkStats::default())).await.unwrap();
+            send("B", UsbEvent::Measurements(Arc::new(samples[1].clone()))).await.unwrap();
+            send("B", UsbEvent::Detached).await.unwrap();
+        });
+        let measurements: Vec<_> = client.measurements().collect().await;
+        assert_eq!(measurements, [first.measurements(), second.measurements()]);
+    }
+
+    #[tokio::test]
+    async fn commands_reach_the_task() {
+        let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+        let client = Ups120Client::from_task(move |mut cmd_rx, _event_tx| async move {
+            while let Some(command) = cmd_rx.recv().await {
+                seen_tx.send((command.target, format!("{:?}", command.command))).unwrap();
+            }
+        });
+        client.subscribe(None).await.unwrap();
+        client.set_report_interval(Some(device("A")), 250).await.unwrap();
+        client.reconnect(Some(device("B"))).await.unwrap();
+        client.unsubscribe(None).await.unwrap();
+        // 命令发送端全部丢弃后任务结束
+        let (commands, _events, task) = client.into_parts();
+        drop(commands);
+        task.await.unwrap();
+        let mut seen = Vec::new();
+        while let Ok(command) = seen_rx.try_recv() {
+            seen.push(command);
+        }
+        assert_eq!(
+            seen,
+            [
+                (None, "Subscribe".to_string()),
+                (Some(device("A")), "SetInterval(250)".to_string()),
+                (Some(device("B")), "Reconnect".to_string()),
+                (None, "Unsubscribe".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_failed_handshake_reports_the_error_and_detaches() {
+        let transport = MockTransport::new().fail_writes(rusb::Error::Pipe);
+        let mut client = Ups120Client::with_transport(transport, TransportSettings::default());
+        let event = next(&mut client).await;
+        assert!(matches!(event, UsbEvent::Error(UsbError::RusbError(rusb::Error::Pipe))), "{:?}", event);
+        assert!(matches!(next(&mut client).await, UsbEvent::Detached));
+        assert!(client.next_event().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn unsubscribing_stops_the_transport() {
+        let builder = PayloadBuilder::new();
+        let transport = MockTransport::new()
+            .respond(Err(rusb::Error::Timeout))
+            .respond(Ok(builder.response_frame()))
+            .push(Ok(builder.frame()));
+        let mut client = Ups120Client::with_transport(transport, TransportSettings::default());
+        let event = tokio::time::timeout(Duration::from_secs(5), client.next_event()).await.unwrap().unwrap();
+        assert_eq!(event.device, device(TRANSPORT_DEVICE));
+        assert!(matches!(event.event, UsbEvent::Connected { .. }));
+        let UsbEvent::Measurements(sample) = next(&mut client).await else {
+            panic!("应为测量数据");
+        };
+        assert_eq!(sample.data, builder.measurements());
+        client.unsubscribe(None).await.unwrap();
+        assert!(matches!(next(&mut client).await, UsbEvent::Unsubscribed));
+        assert!(matches!(next(&mut client).await, UsbEvent::Detached));
+    }
+
+    #[tokio::test]
+    async fn unplug_and_replug_are_reported_in_order() {
+        let builder = PayloadBuilder::new();
+        let handshake = || MockTransport::new().respond(Err(rusb::Error::Timeout)).respond(Ok(builder.response_frame()));
+
+        // 拔出：已建立的连接先报告断开，再报告设备移除
+        let transport = handshake().push(Ok(builder.frame())).push(Err(rusb::Error::NoDevice));
+        let mut client = Ups120Client::with_transport(transport, TransportSettings::default());
+        assert!(matches!(next(&mut client).await, UsbEvent::Connected { .. }));
+        assert!(matches!(next(&mut client).await, UsbEvent::Measurements(_)));
+        let event = next(&mut client).await;
+        assert!(matches!(event, UsbEvent::Disconnected(UsbError::RusbError(rusb::Error::NoDevice))), "{:?}", event);
+        assert!(matches!(next(&mut client).await, UsbEvent::Detached));
+        assert!(client.next_event().await.is_none());
+
+        // 重新插入：新连接重新上线并继续推送
+        let replugged = builder.clone().cell_mv(0, 3700);
+        let transport = handshake().push(Ok(replugged.frame()));
+        let mut client = Ups120Client::with_transport(transport, TransportSettings::default());
+        assert!(matches!(next(&mut client).await, UsbEvent::Connected { .. }));
+        let UsbEvent::Measurements(sample) = next(&mut client).await else {
+            panic!("应为测量数据");
+        };
+        assert_eq!(sample.data, replugged.measurements());
+    }
+
+    #[tokio::test]
+    async fn a_read_failure_disconnects_before_detaching() {
+        let builder = PayloadBuilder::new();
+        let transport = MockTransport::new()
+            .respond(Err(rusb::Error::Timeout))
+            .respond(Ok(builder.response_frame()))
+            .push(Err(rusb::Error::Pipe));
+        let mut client = Ups120Client::with_transport(transport, TransportSettings::default());
+        assert!(matches!(next(&mut client).await, UsbEvent::Connected { .. }));
+        let event = next(&mut client).await;
+        assert!(matches!(event, UsbEvent::Disconnected(UsbError::RusbError(rusb::Error::Pipe))), "{:?}", event);
+        assert!(matches!(next(&mut client).await, UsbEvent::Detached));
+    }
+
+    #[tokio::test]
+    async fn garbage_frames_do_not_end_the_read_loop() {
+        let builder = PayloadBuilder::new();
+        let mut truncated = builder.frame();
+        truncated.truncate(30);
+        // 不完整的帧以零长度包结束，否则读取端会把下一帧当作它的后续分片
+        let transport = MockTransport::new()
+            .respond(Err(rusb::Error::Timeout))
+            .respond(Ok(builder.response_frame()))
+            .push(Ok(Vec::new()))
+            .push(Ok(vec![0x7F, 0xDE, 0xAD, 0xBE, 0xEF]))
+            .push(Ok(vec![0xFF; 64]))
+            .push(Ok(vec![0x82, 0x00]))
+            .push(Ok(Vec::new()))
+            .push(Ok(truncated))
+            .push(Ok(Vec::new()))
+            .push(Ok(vec![0x84; 3]))
+            .push(Ok(builder.frame()))
+            .push(Err(rusb::Error::NoDevice));
+        let mut client = Ups120Client::with_transport(transport, TransportSettings::default());
+        assert!(matches!(next(&mut client).await, UsbEvent::Connected { .. }));
+        let mut errors = 0;
+        let sample = loop {
+            match next(&mut client).await {
+                UsbEvent::Error(_) => errors += 1,
+                UsbEvent::Measurements(sample) => break sample,
+                other => panic!("垃圾帧之后不应出现 {:?}", other),
+            }
+        };
+        // 每个垃圾帧一个错误，读取循环继续并收到之后的有效帧
+        assert_eq!(errors, 5);
+        assert_eq!(sample.data, builder.measurements());
+        assert!(matches!(next(&mut client).await, UsbEvent::Disconnected(_)));
+    }
+
+    #[tokio::test]
+    async fn shutdown_interrupts_the_read() {
+        let builder = PayloadBuilder::new();
+        let transport = MockTransport::new().respond(Err(rusb::Error::Timeout)).respond(Ok(builder.response_frame()));
+        let settings = TransportSettings::default();
+        let shutdown = settings.shutdown.clone();
+        let mut client = Ups120Client::with_transport(transport, settings);
+        assert!(matches!(next(&mut client).await, UsbEvent::Connected { .. }));
+        shutdown.cancel();
+        assert!(matches!(next(&mut client).await, UsbEvent::Detached));
+        let (_commands, _events, task) = client.into_parts();
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+// 每分钟上限的统计周期
+const RATE_PERIOD: Duration = Duration::from_secs(60);
+
+/// 合并器的输出
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Emission {
+    /// 新类别（或窗口过期后再次出现）的首次错误，立即转发原始错误
+    First,
+    /// 窗口内被合并的重复错误数（不含首次）
+    Repeated { category: &'static str, count: u64 },
+    /// 超过每分钟上限而被丢弃的错误数
+    Suppressed { count: u64 },
+}
+
+#[derive(Debug)]
+struct Run {
+    category: &'static str,
+    window_end: Instant,
+    merged: u64,
+}
+
+/// 错误事件合并器：同一类别的连续错误在窗口内只转发第一次，窗口结束时汇总重复次数；
+/// 每分钟转发的首次错误超过上限后只在周期结束时给出丢弃数。
+///
+/// 时间由调用方传入，不读取时钟。
+#[derive(Debug)]
+pub struct ErrorCoalescer {
+    window: Duration,
+    max_per_minute: u32,
+    current: Option<Run>,
+    period_start: Instant,
+    emitted: u32,
+    suppressed: u64,
+}
+
+impl ErrorCoalescer {
+    pub fn new(window: Duration, max_per_minute: u32, now: Instant) -> Self {
+        ErrorCoalescer {
+            window,
+            max_per_minute: max_per_minute.max(1),
+            current: None,
+            period_start: now,
+            emitted: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// 记录一次错误，返回应立即发出的事件（可能包含之前窗口的汇总）
+    pub fn record(&mut self, category: &'static str, now: Instant) -> Vec<Emission> {
+        let mut out = self.flush(now);
+        if let Some(run) = &mut self.current
+            && run.category == category
+        {
+            run.merged += 1;
+            return out;
+        }
+        // 类别变化：提前结束上一段
+        if let Some(run) = self.current.take()
+            && run.merged > 0
+        {
+            out.push(Emission::Repeated { category: run.category, count: run.merged });
+        }
+        if self.emitted >= self.max_per_minute {
+            self.suppressed += 1;
+            return out;
+        }
+        self.emitted += 1;
+        self.current = Some(Run {
+            category,
+            window_end: now + self.window,
+            merged: 0,
+        });
+        out.push(Emission::First);
+        out
+    }
+
+    /// 输出已到期的汇总：合并窗口结束、每分钟周期结束
+    pub fn flush(&mut self, now: Instant) -> Vec<Emission> {
+        let mut out = Vec::new();
+        if let Some(run) = &self.current
+            && now >= run.window_end
+        {
+            if run.merged > 0 {
+                out.push(Emission::Repeated { category: run.category, count: run.merged });
+            }
+            self.current = None;
+        }
+        if now >= self.period_start + RATE_PERIOD {
+            if self.suppressed > 0 {
+                out.push(Emission::Suppressed { count: self.suppressed });
+            }
+            self.period_start = now;
+            self.emitted = 0;
+            self.suppressed = 0;
+        }
+        out
+    }
+
+    /// 输出所有尚未发出的汇总（退出时调用）
+    pub fn finish(&mut self) -> Vec<Emission> {
+        let mut out = Vec::new();
+        if let Some(run) = self.current.take()
+            && run.merged > 0
+        {
+            out.push(Emission::Repeated { category: run.category, count: run.merged });
+        }
+        if self.suppressed > 0 {
+            out.push(Emission::Suppressed { count: self.suppressed });
+            self.suppressed = 0;
+        }
+        out
+    }
+
+    /// 下一次需要调用 `flush` 的时间；没有待汇总的内容时为 None
+    pub fn deadline(&self) -> Option<Instant> {
+        let run_end = self.current.as_ref().filter(|run| run.merged > 0).map(|run| run.window_end);
+        let period_end = (self.suppressed > 0).then(|| self.period_start + RATE_PERIOD);
+        match (run_end, period_end) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(5);
+
+    fn secs(start: Instant, s: u64) -> Instant {
+        start + Duration::from_secs(s)
+    }
+
+    #[test]
+    fn the_first_occurrence_is_immediate_and_repeats_are_summarised_at_window_end() {
+        let t0 = Instant::now();
+        let mut c = ErrorCoalescer::new(WINDOW, 100, t0);
+        assert_eq!(c.deadline(), None);
+        assert_eq!(c.record("timeout", t0), vec![Emission::First]);
+        assert_eq!(c.deadline(), None);
+        for s in 1..=3 {
+            assert!(c.record("timeout", secs(t0, s)).is_empty());
+        }
+        assert_eq!(c.deadline(), Some(secs(t0, 5)));
+        assert!(c.flush(secs(t0, 4)).is_empty());
+        assert_eq!(c.flush(secs(t0, 5)), vec![Emission::Repeated { category: "timeout", count: 3 }]);
+        assert_eq!(c.deadline(), None);
+        // 窗口结束后再次出现视为首次
+        assert_eq!(c.record("timeout", secs(t0, 6)), vec![Emission::First]);
+    }
+
+    #[test]
+    fn a_category_change_closes_the_previous_run_early() {
+        let t0 = Instant::now();
+        let mut c = ErrorCoalescer::new(WINDOW, 100, t0);
+        c.record("timeout", t0);
+        c.record("timeout", secs(t0, 1));
+        assert_eq!(
+            c.record("pipe", secs(t0, 2)),
+            vec![Emission::Repeated { category: "timeout", count: 1 }, Emission::First]
+        );
+        // 没有重复的段不产生汇总
+        assert_eq!(c.record("timeout", secs(t0, 3)), vec![Emission::First]);
+    }
+
+    #[test]
+    fn errors_beyond_the_rate_cap_are_reported_once_per_minute() {
+        let t0 = Instant::now();
+        let mut c = ErrorCoalescer::new(WINDOW, 2, t0);
+        let categories = ["a", "b", "c", "d", "e"];
+        let firsts = categories
+            .iter()
+            .flat_map(|category| c.record(category, secs(t0, 1)))
+            .filter(|e| *e == Emission::First)
+            .count();
+        assert_eq!(firsts, 2);
+        assert_eq!(c.deadline(), Some(secs(t0, 60)));
+        assert!(c.flush(secs(t0, 59)).is_empty());
+        assert_eq!(c.flush(secs(t0, 60)), vec![Emission::Suppressed { count: 3 }]);
+        // 新周期重新计数
+        assert_eq!(c.record("f", secs(t0, 61)), vec![Emission::First]);
+    }
+
+    #[test]
+    fn finish_reports_everything_still_pending() {
+        let t0 = Instant::now();
+        let mut c = ErrorCoalescer::new(WINDOW, 1, t0);
+        c.record("timeout", t0);
+        c.record("timeout", t0);
+        assert_eq!(c.finish(), vec![Emission::Repeated { category: "timeout", count: 1 }]);
+
+        let mut c = ErrorCoalescer::new(WINDOW, 1, t0);
+        c.record("timeout", t0);
+        c.record("pipe", t0);
+        c.record("pipe", t0);
+        assert_eq!(c.finish(), vec![Emission::Suppressed { count: 2 }]);
+        assert!(c.finish().is_empty());
+    }
+}
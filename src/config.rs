@@ -0,0 +1,1288 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::actions::ActionsConfig;
+use crate::alarms::{self, AlarmRule};
+use crate::balance::MAX_BUCKET_EDGES;
+#[cfg(unix)]
+use crate::control_socket::ControlSocketSettings;
+use crate::conversion::CurrentSign;
+use crate::data_models::FlagBit;
+use crate::datalog::FsyncPolicy;
+use crate::debug_dump::DumpTarget;
+use crate::filter::FilterAlphas;
+use crate::flag_events::FlagDebounce;
+use crate::health::HealthRules;
+use crate::influx::InfluxSettings;
+use crate::logging::LogFormat;
+use crate::nut_server::{self, NutSettings};
+use crate::platform;
+use crate::plausibility::Limits;
+use crate::soc::SocTable;
+use crate::timing::{Policy, UsbTimeouts};
+use crate::tls::{TlsError, TlsSettings};
+use crate::topics::TopicMap;
+use crate::units::{OutputUnits, Scale};
+use crate::utils::{CurrentSenseConfig, ThermistorConfig};
+use crate::webhook::{self, WebhookConfig};
+use crate::usb_types::{DeviceSelector, UsbId, UsbLayout, UsbMode};
+
+// 守护进程配置，统一从环境变量 (.env) 读取；结构化的配置项来自可选的 TOML 文件 (CONFIG_FILE)
+#[derive(Debug, Clone)]
+pub struct DaemonConfig {
+    pub mqtt_broker_host: String,
+    pub mqtt_broker_port: u16,
+    pub mqtt_username: Option<String>,
+    pub mqtt_password: Option<String>,
+    pub mqtt_client_id: String,
+    pub mqtt_topic_prefix: String,
+    /// 主 broker 的 TLS / 双向 TLS 设置
+    pub mqtt_tls: TlsSettings,
+    /// 各字段主题后缀，可由配置文件 `[topics]` 覆盖
+    pub topics: TopicMap,
+    /// 额外镜像测量数据的 broker，来自配置文件 `[[mirrors]]`
+    pub mirrors: Vec<BrokerConfig>,
+    /// 测量数据主题加上设备序列号一级 (`{prefix}/{serial}`)，用于同一主机连接多块板子
+    pub topic_per_device: bool,
+    /// Home Assistant 等自动发现主题的前缀（ACL 探测用）
+    pub discovery_prefix: String,
+    /// 启动时探测 broker ACL 是否允许各类主题的发布
+    pub acl_probe: bool,
+    /// ACL 探测中每类主题等待 PubAck 与回显的时间
+    pub acl_probe_timeout: Duration,
+    /// 候选的 VID/PID，按顺序尝试
+    pub usb_ids: Vec<UsbId>,
+    /// 多块板子 VID/PID 相同时按序列号或总线地址选择
+    pub usb_device: DeviceSelector,
+    /// 同时管理所有匹配的设备，测量数据按设备发布到 `{prefix}/{device}`
+    pub usb_multi_device: bool,
+    /// 扫描设备插拔的周期
+    pub usb_scan_interval: Duration,
+    /// 启动后这么久仍未连接到任何设备时以退出码 4 退出；未设置时一直等待
+    pub usb_startup_deadline: Option<Duration>,
+    /// 接口号与端点地址的覆盖，未设置时自动发现
+    pub usb_layout: UsbLayout,
+    /// 持续推送或按间隔轮询
+    pub usb_mode: UsbMode,
+    /// `{prefix}/daemon/usb_stats` 的发布周期
+    pub usb_stats_interval: Duration,
+    /// 订阅后发送给固件的推送间隔 (ms)；未设置时不发送，使用固件默认值
+    pub usb_report_interval_ms: Option<u16>,
+    /// 启动时是否打开原始帧转储；运行中可由 SIGUSR2 或 `debug_dump` 命令切换
+    pub debug_dump: bool,
+    /// 原始帧转储写入日志还是 `{prefix}/debug/raw`
+    pub debug_dump_target: DumpTarget,
+    /// BQ25730 的检流电阻 (mΩ)，影响 ICHG / IDCHG / IIN 与 PSYS 的换算
+    pub current_sense: CurrentSenseConfig,
+    /// BQ25730 ChargeOption1.PSYS_RATIO（true 为 1 µA/W）
+    pub psys_ratio: bool,
+    /// BQ25730 ADCOption.ADC_FULLSCALE（true 为 3.06 V 满量程）
+    pub adc_fullscale: bool,
+    /// 样本合理性检查的范围
+    pub plausibility_limits: Limits,
+    /// 丢弃超出合理范围的样本而不是照常发布
+    pub drop_implausible: bool,
+    /// 测量值指数滑动平均的系数，默认全部为 1（不平滑）
+    pub filter_alphas: FilterAlphas,
+    /// 阈值告警规则
+    pub alarms: Vec<AlarmRule>,
+    /// 电源事件动作；配置文件没有 `[actions]` 或未配置任何命令时为 None
+    pub actions: Option<ActionsConfig>,
+    /// 故障与告警事件的 webhook，来自配置文件 `[[webhooks]]`
+    pub webhooks: Vec<WebhookConfig>,
+    /// 本地 HTTP 接口的监听地址；未设置时不启动
+    pub http_listen: Option<SocketAddr>,
+    /// 每个 WebSocket 客户端的发送队列长度
+    pub http_stream_queue: usize,
+    /// `/healthz` 与 `{prefix}/daemon/health` 的判定规则
+    pub health_rules: HealthRules,
+    /// `{prefix}/daemon/health` 的发布周期
+    pub health_interval: Duration,
+    /// 本地控制套接字；`CONTROL_SOCKET` 为空时不启用
+    #[cfg(unix)]
+    pub control_socket: Option<ControlSocketSettings>,
+    /// NUT 兼容服务；未设置 `NUT_LISTEN` 时不启动
+    pub nut: Option<NutSettings>,
+    /// InfluxDB 输出；未设置 `INFLUX_URL` 时不启用
+    pub influx: Option<InfluxSettings>,
+    /// TS1..TS3 外接热敏电阻的参数
+    pub thermistors: [ThermistorConfig; 3],
+    /// 电池包电流 (coulomb_counter) 的符号约定
+    pub current_sign: CurrentSign,
+    /// 实际串联的电芯数 (1..=5)
+    pub cell_count: usize,
+    /// 同类 USB 错误在该窗口内合并为一个事件
+    pub usb_error_coalesce_window: Duration,
+    /// 每分钟最多转发的 USB 错误事件数，超出部分只汇总计数
+    pub usb_error_max_per_minute: u32,
+    /// 超时与重试间隔
+    pub timing: Policy,
+    /// 两次 MQTT 发布之间的最小间隔，0 表示不限速
+    pub publish_min_interval: Duration,
+    /// broker 不可达时最多缓冲的样本数
+    pub publish_buffer_size: usize,
+    /// 根据 broker 往返时间自动调整发布间隔（下限为 publish_min_interval）
+    pub publish_adaptive: bool,
+    pub publish_adaptive_max_interval: Duration,
+    /// PubAck 往返时间超过该值即视为 broker 拥塞
+    pub publish_adaptive_target_rtt: Duration,
+    /// 未确认消息数超过该值即视为 broker 拥塞
+    pub publish_adaptive_max_backlog: u64,
+    /// 恢复时每次收窄的步长
+    pub publish_adaptive_step: Duration,
+    /// `{prefix}/daemon/stats` 的发布周期
+    pub stats_interval: Duration,
+    /// `{prefix}/daemon/heartbeat` 的发布周期
+    pub heartbeat_interval: Duration,
+    /// 电池包电流超过该值 (A) 视为充电，低于其相反数视为放电
+    pub power_state_current_threshold: f32,
+    /// 单体电压 (V) -> SOC (%) 表
+    pub soc_table: SocTable,
+    /// 静置时 SOC 变化超过该值 (%) 才更新
+    pub soc_hysteresis_pct: f32,
+    /// 电池包容量 (mAh)；未设置时不估算剩余时间，也不启用库仑计
+    pub battery_capacity_mah: Option<f32>,
+    /// 估算剩余时间时电流平滑的时间常数
+    pub runtime_ema_window: Duration,
+    /// 充放电状态需持续该时间才确认切换
+    pub power_state_debounce: Duration,
+    /// 故障标志需持续该时间才发布告警
+    pub fault_alert_debounce: Duration,
+    /// `{prefix}/events` 与 webhook 的标志事件防抖时间，按标志可覆盖
+    pub flag_event_debounce: FlagDebounce,
+    /// 电芯均衡直方图的统计窗口
+    pub balance_histogram_window: Duration,
+    /// 电芯均衡直方图的分桶边界 (mV，相对电池包平均电压，升序)
+    pub balance_histogram_edges_mv: Vec<i32>,
+    /// JSONL 数据记录目录；未设置时不记录
+    pub data_log_dir: Option<PathBuf>,
+    /// 单个数据记录文件的最大字节数
+    pub data_log_max_file_bytes: u64,
+    /// 保留的数据记录文件数
+    pub data_log_max_files: usize,
+    pub data_log_fsync: FsyncPolicy,
+    pub data_log_fsync_interval: Duration,
+    /// CSV 记录目录；未设置时不记录
+    pub csv_log_dir: Option<PathBuf>,
+    /// 单个 CSV 文件的最大字节数
+    pub csv_log_max_file_bytes: u64,
+    /// 保留的 CSV 文件数
+    pub csv_log_max_files: usize,
+    /// UTC 日期变化时切换 CSV 文件
+    pub csv_log_rotate_daily: bool,
+    pub csv_log_flush_interval: Duration,
+    /// 突发抓取：触发前保留与触发后抓取的帧数，0 表示关闭
+    pub burst_capture_frames: usize,
+    /// 触发突发抓取的标志
+    pub burst_trigger_flags: Vec<(FlagBit, &'static str)>,
+    /// 保留的突发抓取数
+    pub burst_capture_max_files: usize,
+    /// 充电器输入功率超过该值 (W) 时才计算转换效率
+    pub efficiency_min_input_w: f32,
+    /// 内存中保留的最近样本数，0 表示关闭
+    pub history_samples: usize,
+    /// 告警上下文写入的目录；未设置时发布到 `{prefix}/alarms/<name>/context`
+    pub alarm_context_dir: Option<PathBuf>,
+    /// 能量计数的状态文件；未设置时为 `{state_dir}/energy.json`
+    pub energy_state_file: Option<PathBuf>,
+    /// 能量计数与库仑计两次写状态文件之间的最短间隔
+    pub energy_persist_interval: Duration,
+    /// `{prefix}/energy/*` 两次发布之间的最短间隔
+    pub energy_publish_interval: Duration,
+    /// 能量与库仑计积分时两帧间隔的上限，超出部分不计入
+    pub energy_max_gap: Duration,
+    /// 状态文件目录，默认值取决于平台
+    pub state_dir: PathBuf,
+    /// 启动时以 retained 方式发布上次保存的测量数据 (带 `stale: true`)
+    pub restore_last_state: bool,
+    /// 两次保存最近测量数据之间的最短间隔
+    pub last_state_persist_interval: Duration,
+    /// 每分配多少个帧号写一次状态文件；异常退出后最多跳过这么多个号
+    pub frame_id_persist_batch: u64,
+    /// 日志格式：人读文本或单行 JSON
+    pub log_format: LogFormat,
+    /// 严格模式：把生产模式下仅记录日志的异常视为致命错误（出厂检测用）
+    pub strict_mode: bool,
+    /// 严格模式下允许的最长无数据帧时间
+    pub strict_frame_deadline: Duration,
+    /// 严格模式下启动时等待 MQTT 连接成功的时间
+    pub strict_mqtt_connect_timeout: Duration,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Missing(&'static str),
+    Invalid { key: &'static str, value: String, reason: String },
+    File { path: PathBuf, reason: String },
+    Tls { broker: String, source: TlsError },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Missing(key) => write!(f, "{} not set", key),
+            ConfigError::Invalid { key, value, reason } => {
+                write!(f, "Invalid {} '{}': {}", key, value, reason)
+            }
+            ConfigError::File { path, reason } => write!(f, "Invalid config file {}: {}", path.display(), reason),
+            ConfigError::Tls { broker, source } => write!(f, "Invalid TLS settings for broker '{}': {}", broker, source),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// 配置文件 (TOML) 中的结构化配置
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// 字段主题覆盖，如 `vbat = "battery/voltage"`
+    #[serde(default)]
+    pub topics: BTreeMap<String, String>,
+    /// 镜像 broker 列表
+    #[serde(default)]
+    pub mirrors: Vec<BrokerConfig>,
+    /// 各字段的平滑系数，如 `cell0 = 0.2`
+    #[serde(default)]
+    pub filter: BTreeMap<String, f32>,
+    /// 阈值告警规则；未指定时使用默认规则，`alarms = []` 关闭告警
+    pub alarms: Option<Vec<AlarmRule>>,
+    /// 各 TS 通道外接热敏电阻的参数，键为 `ts1`..`ts3`
+    #[serde(default)]
+    pub thermistors: BTreeMap<String, ThermistorParams>,
+    /// 电源事件（切换到电池、恢复市电、低电量）触发的命令
+    pub actions: Option<ActionsConfig>,
+    /// 故障标志与告警事件的 webhook 列表
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    /// 各标志事件的防抖时间 (ms)，如 `charger_stat_ac = 10000`
+    #[serde(default)]
+    pub flag_debounce: BTreeMap<String, u64>,
+}
+
+/// 单个热敏电阻的参数，未指定的项使用 `THERMISTOR_BETA` / `THERMISTOR_R25_OHMS`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThermistorParams {
+    pub beta: Option<f32>,
+    pub r25_ohms: Option<f32>,
+}
+
+/// 镜像 broker：独立连接、独立缓冲，只接收测量数据
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BrokerConfig {
+    /// 日志与统计中使用的名称，不能为 `primary`
+    pub name: String,
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// 默认为 `{MQTT_CLIENT_ID}-{name}`
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub tls: bool,
+    /// 自定义 CA 证书 (PEM)；未指定时使用系统证书
+    pub ca_file: Option<PathBuf>,
+    /// 双向 TLS 的客户端证书与私钥 (PEM)
+    pub client_cert_file: Option<PathBuf>,
+    pub client_key_file: Option<PathBuf>,
+}
+
+impl BrokerConfig {
+    pub fn tls_settings(&self) -> TlsSettings {
+        TlsSettings {
+            enabled: self.tls,
+            ca_file: self.ca_file.clone(),
+            client_cert_file: self.client_cert_file.clone(),
+            client_key_file: self.client_key_file.clone(),
+        }
+    }
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+/// 主 broker 在统计中的名称
+pub const PRIMARY_BROKER: &str = "primary";
+
+fn validate_tls(broker: &str, settings: &TlsSettings) -> Result<(), ConfigError> {
+    settings.validate().map_err(|source| ConfigError::Tls {
+        broker: broker.to_string(),
+        source,
+    })
+}
+
+fn validate_mirrors(mirrors: &[BrokerConfig]) -> Result<(), ConfigError> {
+    let mut names = std::collections::HashSet::new();
+    for broker in mirrors {
+        let reason = if broker.name.trim().is_empty() {
+            "name must not be empty"
+        } else if broker.name == PRIMARY_BROKER {
+            "name 'primary' is reserved for MQTT_BROKER_HOST"
+        } else if !names.insert(broker.name.as_str()) {
+            "duplicate broker name"
+        } else {
+            continue;
+        };
+        return Err(ConfigError::Invalid {
+            key: "mirrors.name",
+            value: broker.name.clone(),
+            reason: reason.to_string(),
+        });
+    }
+    Ok(())
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::File {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        })?;
+        toml::from_str(&text).map_err(|e| ConfigError::File {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        })
+    }
+}
+
+impl DaemonConfig {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let file = match var_os("CONFIG_FILE") {
+            Some(path) => ConfigFile::load(Path::new(&path))?,
+            None => ConfigFile::default(),
+        };
+        validate_mirrors(&file.mirrors)?;
+        if let Some(rules) = &file.alarms {
+            alarms::validate_rules(rules)?;
+        }
+        if let Some(actions) = &file.actions {
+            actions.validate()?;
+        }
+        webhook::validate_webhooks(&file.webhooks)?;
+        let mqtt_tls = TlsSettings {
+            enabled: parse_bool_or("MQTT_TLS", false)?,
+            ca_file: var_os("MQTT_CA_FILE").map(PathBuf::from),
+            client_cert_file: var_os("MQTT_CLIENT_CERT_FILE").map(PathBuf::from),
+            client_key_file: var_os("MQTT_CLIENT_KEY_FILE").map(PathBuf::from),
+        };
+        // 证书与私钥在启动时就加载校验，出错时指出具体文件
+        validate_tls(PRIMARY_BROKER, &mqtt_tls)?;
+        for broker in &file.mirrors {
+            validate_tls(&broker.name, &broker.tls_settings())?;
+        }
+        Ok(DaemonConfig {
+            mqtt_broker_host: required("MQTT_BROKER_HOST")?,
+            mqtt_broker_port: parse_required("MQTT_BROKER_PORT")?,
+            mqtt_username: var("MQTT_USERNAME").ok(),
+            mqtt_password: var("MQTT_PASSWORD").ok(),
+            mqtt_client_id: var("MQTT_CLIENT_ID")
+                .unwrap_or_else(|_| "ups120_cli_client".to_string()),
+            mqtt_topic_prefix: topic_prefix()?,
+            mqtt_tls,
+            topics: TopicMap::new(&file.topics)?
+                .with_mos_status_raw(parse_bool_or("MOS_STATUS_RAW", false)?)
+                .with_flags_as_names(parse_bool_or("FLAGS_AS_NAMES", false)?)
+                .with_units(parse_output_units()?),
+            mirrors: file.mirrors,
+            topic_per_device: parse_bool_or("TOPIC_PER_DEVICE", false)?,
+            discovery_prefix: var("DISCOVERY_PREFIX").unwrap_or_else(|_| "homeassistant".to_string()),
+            acl_probe: parse_bool_or("ACL_PROBE", true)?,
+            acl_probe_timeout: Duration::from_millis(parse_or("ACL_PROBE_TIMEOUT_MS", 3_000u64)?),
+            usb_ids: parse_usb_ids()?,
+            usb_device: parse_device_selector()?,
+            usb_multi_device: parse_bool_or("USB_MULTI_DEVICE", false)?,
+            usb_layout: parse_usb_layout()?,
+            usb_mode: parse_usb_mode()?,
+            usb_stats_interval: Duration::from_secs(parse_or("USB_STATS_INTERVAL_SECS", 30u64)?.max(1)),
+            usb_report_interval_ms: parse_report_interval()?,
+            debug_dump: parse_bool_or("DEBUG_DUMP", false)?,
+            debug_dump_target: parse_debug_dump_target()?,
+            usb_scan_interval: Duration::from_secs(parse_or("USB_SCAN_INTERVAL_SECS", 5u64)?.max(1)),
+            usb_startup_deadline: parse_optional::<u64>("USB_STARTUP_DEADLINE_SECS")?
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            current_sense: CurrentSenseConfig {
+                rsns_bat_mohm: parse_positive_or("RSNS_BAT_MOHM", 10.0)?,
+                rsns_ac_mohm: parse_positive_or("RSNS_AC_MOHM", 10.0)?,
+            },
+            psys_ratio: parse_bool_or("PSYS_RATIO", false)?,
+            adc_fullscale: parse_bool_or("ADC_FULLSCALE", true)?,
+            plausibility_limits: parse_plausibility_limits()?,
+            drop_implausible: parse_bool_or("DROP_IMPLAUSIBLE", false)?,
+            http_listen: parse_optional("HTTP_LISTEN")?,
+            http_stream_queue: parse_or("HTTP_STREAM_QUEUE", 32usize)?.max(1),
+            health_rules: parse_health_rules()?,
+            health_interval: Duration::from_secs(parse_or("HEALTH_INTERVAL_SECS", 30u64)?.max(1)),
+            #[cfg(unix)]
+            control_socket: parse_control_socket()?,
+            nut: parse_nut()?,
+            influx: parse_influx()?,
+            filter_alphas: FilterAlphas::new(parse_or("FILTER_ALPHA", 1.0f32)?, &file.filter)?,
+            alarms: file.alarms.unwrap_or_else(alarms::default_rules),
+            actions: file.actions.filter(|actions| !actions.is_empty()),
+            webhooks: file.webhooks,
+            thermistors: parse_thermistors(&file.thermistors)?,
+            current_sign: parse_current_sign()?,
+            cell_count: parse_cell_count()?,
+            usb_error_coalesce_window: Duration::from_secs(parse_or("USB_ERROR_COALESCE_WINDOW_SECS", 30u64)?),
+            usb_error_max_per_minute: parse_or("USB_ERROR_MAX_PER_MINUTE", 10u32)?,
+            timing: parse_timing_policy()?,
+            publish_min_interval: Duration::from_millis(parse_or("PUBLISH_MIN_INTERVAL_MS", 0u64)?),
+            publish_buffer_size: parse_or("PUBLISH_BUFFER_SIZE", 300usize)?,
+            publish_adaptive: parse_bool_or("MQTT_PUBLISH_ADAPTIVE", false)?,
+            publish_adaptive_max_interval: Duration::from_millis(parse_or("PUBLISH_ADAPTIVE_MAX_MS", 10_000u64)?),
+            publish_adaptive_target_rtt: Duration::from_millis(parse_or("PUBLISH_ADAPTIVE_TARGET_RTT_MS", 500u64)?),
+            publish_adaptive_max_backlog: parse_or("PUBLISH_ADAPTIVE_MAX_BACKLOG", 20u64)?,
+            publish_adaptive_step: Duration::from_millis(parse_or("PUBLISH_ADAPTIVE_STEP_MS", 250u64)?),
+            stats_interval: Duration::from_secs(parse_or("STATS_INTERVAL_SECS", 30u64)?.max(1)),
+            heartbeat_interval: Duration::from_secs(parse_or("HEARTBEAT_INTERVAL_SECS", 15u64)?.max(1)),
+            power_state_current_threshold: parse_or("POWER_STATE_CURRENT_THRESHOLD_A", 0.05f32)?,
+            soc_table: parse_soc_table()?,
+            soc_hysteresis_pct: parse_or("SOC_HYSTERESIS_PCT", 2.0f32)?,
+            battery_capacity_mah: parse_optional_positive("BATTERY_CAPACITY_MAH")?,
+            runtime_ema_window: Duration::from_secs(parse_or("RUNTIME_EMA_WINDOW_SECS", 60u64)?),
+            power_state_debounce: Duration::from_millis(parse_or("POWER_STATE_DEBOUNCE_MS", 2_000u64)?),
+            fault_alert_debounce: Duration::from_millis(parse_or("FAULT_ALERT_DEBOUNCE_MS", 0u64)?),
+            flag_event_debounce: FlagDebounce::new(
+                Duration::from_millis(parse_or("FLAG_EVENT_DEBOUNCE_MS", 5_000u64)?),
+                &file.flag_debounce,
+            )?,
+            balance_histogram_window: Duration::from_secs(parse_or("BALANCE_HISTOGRAM_WINDOW_SECS", 86_400u64)?),
+            balance_histogram_edges_mv: parse_bucket_edges("BALANCE_HISTOGRAM_EDGES_MV", "-50,-20,-10,-5,5,10,20,50")?,
+            data_log_dir: var_os("DATA_LOG_DIR").map(PathBuf::from),
+            data_log_max_file_bytes: parse_or("DATA_LOG_MAX_FILE_MB", 16u64)? * 1024 * 1024,
+            data_log_max_files: parse_or("DATA_LOG_MAX_FILES", 8usize)?.max(1),
+            data_log_fsync: parse_fsync_policy()?,
+            data_log_fsync_interval: Duration::from_secs(parse_or("DATA_LOG_FSYNC_INTERVAL_SECS", 10u64)?.max(1)),
+            csv_log_dir: var_os("CSV_LOG_DIR").map(PathBuf::from),
+            csv_log_max_file_bytes: parse_or("CSV_LOG_MAX_FILE_MB", 16u64)? * 1024 * 1024,
+            csv_log_max_files: parse_or("CSV_LOG_MAX_FILES", 30usize)?.max(1),
+            csv_log_rotate_daily: parse_bool_or("CSV_LOG_ROTATE_DAILY", true)?,
+            csv_log_flush_interval: Duration::from_secs(parse_or("CSV_LOG_FLUSH_INTERVAL_SECS", 10u64)?.max(1)),
+            burst_capture_frames: parse_or("BURST_CAPTURE_FRAMES", 10usize)?,
+            burst_trigger_flags: parse_flag_list("BURST_TRIGGER_FLAGS", "SCD,OCD,SYSOVP")?,
+            burst_capture_max_files: parse_or("BURST_CAPTURE_MAX_FILES", 50usize)?.max(1),
+            history_samples: parse_or("HISTORY_SAMPLES", 600usize)?,
+            efficiency_min_input_w: parse_positive_or("EFFICIENCY_MIN_INPUT_W", 2.0)?,
+            alarm_context_dir: var_os("ALARM_CONTEXT_DIR").map(PathBuf::from),
+            energy_state_file: var_os("ENERGY_STATE_FILE").map(PathBuf::from),
+            energy_persist_interval: Duration::from_secs(parse_or("ENERGY_PERSIST_INTERVAL_SECS", 60u64)?),
+            energy_publish_interval: Duration::from_secs(parse_or("ENERGY_PUBLISH_INTERVAL_SECS", 10u64)?),
+            energy_max_gap: Duration::from_secs(parse_or("ENERGY_MAX_GAP_SECS", 120u64)?.max(1)),
+            state_dir: var_os("STATE_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| platform::current().default_state_dir()),
+            restore_last_state: parse_bool_or("RESTORE_LAST_STATE", true)?,
+            last_state_persist_interval: Duration::from_secs(parse_or("LAST_STATE_PERSIST_INTERVAL_SECS", 30u64)?),
+            frame_id_persist_batch: parse_or("FRAME_ID_PERSIST_BATCH", 1_000u64)?,
+            log_format: parse_or("LOG_FORMAT", LogFormat::Text)?,
+            strict_mode: parse_bool_or("STRICT_MODE", false)?,
+            strict_frame_deadline: Duration::from_secs(parse_or("STRICT_FRAME_DEADLINE_SECS", 30u64)?),
+            strict_mqtt_connect_timeout: Duration::from_secs(parse_or("STRICT_MQTT_CONNECT_TIMEOUT_SECS", 10u64)?),
+        })
+    }
+
+    /// 只用给定的变量构造配置，不读取也不修改进程环境
+    #[cfg(test)]
+    pub(crate) fn from_vars(vars: &[(&str, &str)]) -> Result<Self, ConfigError> {
+        test_vars::with_vars(vars, Self::from_env)
+    }
+}
+
+fn topic_prefix() -> Result<String, ConfigError> {
+    let prefix = var("MQTT_TOPIC_PREFIX").unwrap_or_else(|_| "ups120".to_string());
+    validate_topic_prefix(&prefix).map_err(|reason| ConfigError::Invalid {
+        key: "MQTT_TOPIC_PREFIX",
+        value: prefix.clone(),
+        reason,
+    })?;
+    Ok(prefix)
+}
+
+// 配置变量统一从这里读取；测试通过 `with_vars` 在当前线程上提供变量，不改动进程环境
+fn var(key: &str) -> Result<String, env::VarError> {
+    #[cfg(test)]
+    if let Some(value) = test_vars::get(key) {
+        return value.ok_or(env::VarError::NotPresent);
+    }
+    env::var(key)
+}
+
+fn var_os(key: &str) -> Option<OsString> {
+    #[cfg(test)]
+    if let Some(value) = test_vars::get(key) {
+        return value.map(OsString::from);
+    }
+    env::var_os(key)
+}
+
+#[cfg(test)]
+mod test_vars {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    thread_local! {
+        static VARS: RefCell<Option<HashMap<String, String>>> = const { RefCell::new(None) };
+    }
+
+    /// `with_vars` 期间返回 `Some`：给定的变量或 `Some(None)`，其余进程环境变量一律视为未设置
+    pub(super) fn get(key: &str) -> Option<Option<String>> {
+        VARS.with(|vars| vars.borrow().as_ref().map(|vars| vars.get(key).cloned()))
+    }
+
+    pub(super) fn with_vars<R>(vars: &[(&str, &str)], f: impl FnOnce() -> R) -> R {
+        let map = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let previous = VARS.with(|cell| cell.replace(Some(map)));
+        let result = f();
+        VARS.with(|cell| *cell.borrow_mut() = previous);
+        result
+    }
+}
+
+fn required(key: &'static str) -> Result<String, ConfigError> {
+    var(key).map_err(|_| ConfigError::Missing(key))
+}
+
+fn parse_timing_policy() -> Result<Policy, ConfigError> {
+    let default = Policy::default();
+    // 0 会让 tokio::time::interval panic，libusb 也会把 0 当作不超时
+    let positive = |key: &'static str, default: u64| -> Result<u64, ConfigError> {
+        match parse_or(key, default)? {
+            0 => Err(ConfigError::Invalid {
+                key,
+                value: "0".to_string(),
+                reason: "must be a positive number".to_string(),
+            }),
+            value => Ok(value),
+        }
+    };
+    let millis = |key, default: Duration| -> Result<Duration, ConfigError> {
+        Ok(Duration::from_millis(positive(key, default.as_millis() as u64)?))
+    };
+    let secs = |key, default: Duration| -> Result<Duration, ConfigError> {
+        Ok(Duration::from_secs(positive(key, default.as_secs())?))
+    };
+    Ok(Policy {
+        usb: UsbTimeouts {
+            command: millis("USB_COMMAND_TIMEOUT_MS", default.usb.command)?,
+            response: millis("USB_RESPONSE_TIMEOUT_MS", default.usb.response)?,
+            push_read: millis("USB_PUSH_READ_TIMEOUT_MS", default.usb.push_read)?,
+            stale_timeout: secs("USB_STALE_TIMEOUT_SECS", default.usb.stale_timeout)?,
+        },
+        usb_context_retry: secs("USB_CONTEXT_RETRY_SECS", default.usb_context_retry)?,
+        usb_open_retry: secs("USB_OPEN_RETRY_SECS", default.usb_open_retry)?,
+        usb_setup_retry: secs("USB_SETUP_RETRY_SECS", default.usb_setup_retry)?,
+        mqtt_connect_retry: secs("MQTT_CONNECT_RETRY_SECS", default.mqtt_connect_retry)?,
+        mqtt_keep_alive: secs("MQTT_KEEP_ALIVE_SECS", default.mqtt_keep_alive)?,
+        mqtt_reconnect_initial: millis("MQTT_RECONNECT_BACKOFF_INITIAL_MS", default.mqtt_reconnect_initial)?,
+        mqtt_reconnect_max: secs("MQTT_RECONNECT_BACKOFF_MAX_SECS", default.mqtt_reconnect_max)?,
+        shutdown_confirm: millis("SHUTDOWN_CONFIRM_TIMEOUT_MS", default.shutdown_confirm)?,
+        shutdown_unsubscribe: millis("SHUTDOWN_UNSUBSCRIBE_TIMEOUT_MS", default.shutdown_unsubscribe)?,
+        shutdown_tasks: millis("SHUTDOWN_TASKS_TIMEOUT_MS", default.shutdown_tasks)?,
+    })
+}
+
+fn parse_required<T>(key: &'static str) -> Result<T, ConfigError>
+where
+    T: std::str::FromStr,
+    T::Err: fmt::Display,
+{
+    let value = required(key)?;
+    value.trim().parse().map_err(|e: T::Err| ConfigError::Invalid {
+        key,
+        value: value.clone(),
+        reason: e.to_string(),
+    })
+}
+
+fn parse_or<T>(key: &'static str, default: T) -> Result<T, ConfigError>
+where
+    T: std::str::FromStr,
+    T::Err: fmt::Display,
+{
+    match var(key) {
+        Ok(_) => parse_required(key),
+        Err(_) => Ok(default),
+    }
+}
+
+fn parse_bool_or(key: &'static str, default: bool) -> Result<bool, ConfigError> {
+    let Ok(value) = var(key) else {
+        return Ok(default);
+    };
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" | "" => Ok(false),
+        _ => Err(ConfigError::Invalid {
+            key,
+            value,
+            reason: "expected true/false".to_string(),
+        }),
+    }
+}
+
+fn parse_positive_or(key: &'static str, default: f32) -> Result<f32, ConfigError> {
+    let value = parse_or(key, default)?;
+    if value.is_finite() && value > 0.0 {
+        Ok(value)
+    } else {
+        Err(ConfigError::Invalid {
+            key,
+            value: value.to_string(),
+            reason: "must be a positive number".to_string(),
+        })
+    }
+}
+
+fn parse_plausibility_limits() -> Result<Limits, ConfigError> {
+    let defaults = Limits::default();
+    let current_max = parse_positive_or("PLAUSIBLE_CURRENT_A_MAX", defaults.current.1)?;
+    Ok(Limits {
+        cell_voltage: (
+            parse_or("PLAUSIBLE_CELL_V_MIN", defaults.cell_voltage.0)?,
+            parse_or("PLAUSIBLE_CELL_V_MAX", defaults.cell_voltage.1)?,
+        ),
+        current: (-current_max, current_max),
+        temperature: (
+            parse_or("PLAUSIBLE_TEMP_C_MIN", defaults.temperature.0)?,
+            parse_or("PLAUSIBLE_TEMP_C_MAX", defaults.temperature.1)?,
+        ),
+    })
+}
+
+fn parse_thermistors(overrides: &BTreeMap<String, ThermistorParams>) -> Result<[ThermistorConfig; 3], ConfigError> {
+    let defaults = ThermistorConfig {
+        beta: parse_positive_or("THERMISTOR_BETA", 3435.0)?,
+        r25_ohms: parse_positive_or("THERMISTOR_R25_OHMS", 10_000.0)?,
+    };
+    let mut thermistors = [defaults; 3];
+    for (key, params) in overrides {
+        let Some(index) = ["ts1", "ts2", "ts3"].iter().position(|k| k == key) else {
+            return Err(ConfigError::Invalid {
+                key: "thermistors",
+                value: key.clone(),
+                reason: "expected ts1, ts2 or ts3".to_string(),
+            });
+        };
+        for value in [params.beta, params.r25_ohms].into_iter().flatten() {
+            if !(value.is_finite() && value > 0.0) {
+                return Err(ConfigError::Invalid {
+                    key: "thermistors",
+                    value: format!("{}: {}", key, value),
+                    reason: "must be a positive number".to_string(),
+                });
+            }
+        }
+        thermistors[index] = ThermistorConfig {
+            beta: params.beta.unwrap_or(defaults.beta),
+            r25_ohms: params.r25_ohms.unwrap_or(defaults.r25_ohms),
+        };
+    }
+    Ok(thermistors)
+}
+
+fn parse_optional<T>(key: &'static str) -> Result<Option<T>, ConfigError>
+where
+    T: std::str::FromStr,
+    T::Err: fmt::Display,
+{
+    match var(key) {
+        Ok(_) => parse_required(key).map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+fn parse_optional_positive(key: &'static str) -> Result<Option<f32>, ConfigError> {
+    if var(key).is_err() {
+        return Ok(None);
+    }
+    parse_positive_or(key, 1.0).map(Some)
+}
+
+fn parse_report_interval() -> Result<Option<u16>, ConfigError> {
+    let interval = parse_optional::<u16>("USB_REPORT_INTERVAL_MS")?;
+    if interval == Some(0) {
+        return Err(ConfigError::Invalid {
+            key: "USB_REPORT_INTERVAL_MS",
+            value: "0".to_string(),
+            reason: "must be a positive number".to_string(),
+        });
+    }
+    Ok(interval)
+}
+
+fn parse_current_sign() -> Result<CurrentSign, ConfigError> {
+    let Ok(value) = var("CURRENT_SIGN") else {
+        return Ok(CurrentSign::ChargePositive);
+    };
+    match value.trim().to_ascii_lowercase().as_str() {
+        "charge_positive" => Ok(CurrentSign::ChargePositive),
+        "discharge_positive" => Ok(CurrentSign::DischargePositive),
+        _ => Err(ConfigError::Invalid {
+            key: "CURRENT_SIGN",
+            value,
+            reason: "expected charge_positive/discharge_positive".to_string(),
+        }),
+    }
+}
+
+fn parse_health_rules() -> Result<HealthRules, ConfigError> {
+    let defaults = HealthRules::default();
+    let secs = |key, default: Duration| -> Result<Duration, ConfigError> {
+        Ok(Duration::from_secs(parse_or(key, default.as_secs())?))
+    };
+    let rules = HealthRules {
+        push_degraded_after: secs("HEALTH_PUSH_DEGRADED_SECS", defaults.push_degraded_after)?,
+        push_failing_after: secs("HEALTH_PUSH_FAILING_SECS", defaults.push_failing_after)?,
+        mqtt_failing_after: secs("HEALTH_MQTT_FAILING_SECS", defaults.mqtt_failing_after)?,
+        queue_degraded_depth: parse_or("HEALTH_QUEUE_DEGRADED_DEPTH", defaults.queue_degraded_depth)?.max(1),
+    };
+    if rules.push_degraded_after > rules.push_failing_after {
+        return Err(ConfigError::Invalid {
+            key: "HEALTH_PUSH_DEGRADED_SECS",
+            value: rules.push_degraded_after.as_secs().to_string(),
+            reason: "must not exceed HEALTH_PUSH_FAILING_SECS".to_string(),
+        });
+    }
+    Ok(rules)
+}
+
+#[cfg(unix)]
+fn parse_control_socket() -> Result<Option<ControlSocketSettings>, ConfigError> {
+    let path = match var_os("CONTROL_SOCKET") {
+        Some(path) if path.is_empty() => return Ok(None),
+        Some(path) => PathBuf::from(path),
+        None => match platform::current().default_control_socket() {
+            Some(path) => path,
+            None => return Ok(None),
+        },
+    };
+    let value = var("CONTROL_SOCKET_MODE").unwrap_or_else(|_| "0660".to_string());
+    let mode = u32::from_str_radix(value.trim().trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| ConfigError::Invalid {
+            key: "CONTROL_SOCKET_MODE",
+            value: value.clone(),
+            reason: "expected octal permission bits such as 0660".to_string(),
+        })?;
+    Ok(Some(ControlSocketSettings { path, mode }))
+}
+
+fn parse_nut() -> Result<Option<NutSettings>, ConfigError> {
+    let Ok(value) = var("NUT_LISTEN") else {
+        return Ok(None);
+    };
+    let listen = nut_server::parse_listen(&value).map_err(|reason| ConfigError::Invalid {
+        key: "NUT_LISTEN",
+        value: value.clone(),
+        reason,
+    })?;
+    let ups_name = var("NUT_UPS_NAME").unwrap_or_else(|_| "ups120".to_string());
+    if ups_name.is_empty() || ups_name.contains(|c: char| c.is_whitespace() || c == '"') {
+        return Err(ConfigError::Invalid {
+            key: "NUT_UPS_NAME",
+            value: ups_name,
+            reason: "must be a non-empty name without spaces or quotes".to_string(),
+        });
+    }
+    Ok(Some(NutSettings {
+        listen,
+        ups_name,
+        username: var("NUT_USERNAME").ok(),
+        password: var("NUT_PASSWORD").ok(),
+        low_battery_pct: parse_or("NUT_LOW_BATTERY_PCT", 20.0f32)?,
+        charging_threshold: parse_or("POWER_STATE_CURRENT_THRESHOLD_A", 0.05f32)?,
+    }))
+}
+
+fn parse_influx() -> Result<Option<InfluxSettings>, ConfigError> {
+    let Ok(url) = var("INFLUX_URL") else {
+        return Ok(None);
+    };
+    let settings = InfluxSettings {
+        url,
+        org: required("INFLUX_ORG")?,
+        bucket: required("INFLUX_BUCKET")?,
+        token: required("INFLUX_TOKEN")?,
+        measurement: var("INFLUX_MEASUREMENT").unwrap_or_else(|_| "ups120".to_string()),
+        batch_size: parse_or("INFLUX_BATCH_SIZE", 50usize)?.max(1),
+        flush_interval: Duration::from_secs(parse_or("INFLUX_FLUSH_INTERVAL_SECS", 10u64)?.max(1)),
+        buffer_lines: parse_or("INFLUX_BUFFER_LINES", 10_000usize)?.max(1),
+    };
+    if let Err(reason) = settings.write_url() {
+        return Err(ConfigError::Invalid {
+            key: "INFLUX_URL",
+            value: settings.url,
+            reason,
+        });
+    }
+    Ok(Some(settings))
+}
+
+fn parse_fsync_policy() -> Result<FsyncPolicy, ConfigError> {
+    let Ok(value) = var("DATA_LOG_FSYNC") else {
+        return Ok(FsyncPolicy::Interval);
+    };
+    match value.trim().to_ascii_lowercase().as_str() {
+        "never" => Ok(FsyncPolicy::Never),
+        "interval" => Ok(FsyncPolicy::Interval),
+        "every_write" => Ok(FsyncPolicy::EveryWrite),
+        _ => Err(ConfigError::Invalid {
+            key: "DATA_LOG_FSYNC",
+            value,
+            reason: "expected never/interval/every_write".to_string(),
+        }),
+    }
+}
+
+fn parse_cell_count() -> Result<usize, ConfigError> {
+    let count = parse_or("CELL_COUNT", 5usize)?;
+    if (1..=5).contains(&count) {
+        Ok(count)
+    } else {
+        Err(ConfigError::Invalid {
+            key: "CELL_COUNT",
+            value: count.to_string(),
+            reason: "BQ76920 supports 1 to 5 cells".to_string(),
+        })
+    }
+}
+
+fn parse_soc_table() -> Result<SocTable, ConfigError> {
+    let Ok(value) = var("SOC_TABLE") else {
+        return Ok(SocTable::default());
+    };
+    let invalid = |reason: String| ConfigError::Invalid {
+        key: "SOC_TABLE",
+        value: value.clone(),
+        reason,
+    };
+    let points = value
+        .split(',')
+        .map(|point| {
+            let (voltage, soc) = point
+                .split_once(':')
+                .ok_or_else(|| format!("expected voltage:percent, got '{}'", point.trim()))?;
+            let voltage = voltage.trim().parse::<f32>().map_err(|e| e.to_string())?;
+            let soc = soc.trim().parse::<f32>().map_err(|e| e.to_string())?;
+            Ok((voltage, soc))
+        })
+        .collect::<Result<Vec<_>, String>>()
+        .map_err(invalid)?;
+    SocTable::new(points).map_err(invalid)
+}
+
+fn parse_bucket_edges(key: &'static str, default: &str) -> Result<Vec<i32>, ConfigError> {
+    let value = var(key).unwrap_or_else(|_| default.to_string());
+    let invalid = |reason: String| ConfigError::Invalid {
+        key,
+        value: value.clone(),
+        reason,
+    };
+    let edges = value
+        .split(',')
+        .map(|edge| edge.trim().parse::<i32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(e.to_string()))?;
+    if edges.len() > MAX_BUCKET_EDGES {
+        return Err(invalid(format!("at most {} edges", MAX_BUCKET_EDGES)));
+    }
+    if edges.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(invalid("edges must be strictly ascending".to_string()));
+    }
+    Ok(edges)
+}
+
+fn parse_flag_list(key: &'static str, default: &str) -> Result<Vec<(FlagBit, &'static str)>, ConfigError> {
+    let value = var(key).unwrap_or_else(|_| default.to_string());
+    let mut flags: Vec<(FlagBit, &'static str)> = Vec::new();
+    for name in value.split(',').filter(|name| !name.trim().is_empty()) {
+        let flag = FlagBit::from_name(name).ok_or_else(|| ConfigError::Invalid {
+            key,
+            value: value.clone(),
+            reason: format!("unknown flag '{}'", name.trim()),
+        })?;
+        if !flags.contains(&flag) {
+            flags.push(flag);
+        }
+    }
+    Ok(flags)
+}
+
+fn parse_device_selector() -> Result<DeviceSelector, ConfigError> {
+    let serial = var("USB_SERIAL").ok().filter(|s| !s.trim().is_empty());
+    let bus_addr = var("USB_BUS_ADDR").ok().filter(|s| !s.trim().is_empty());
+    match (serial, bus_addr) {
+        (Some(serial), None) => Ok(DeviceSelector::Serial(serial.trim().to_string())),
+        (None, Some(value)) => parse_bus_addr(&value).map_err(|reason| ConfigError::Invalid {
+            key: "USB_BUS_ADDR",
+            value: value.clone(),
+            reason,
+        }),
+        (None, None) => Ok(DeviceSelector::Any),
+        (Some(_), Some(value)) => Err(ConfigError::Invalid {
+            key: "USB_BUS_ADDR",
+            value,
+            reason: "USB_SERIAL and USB_BUS_ADDR are mutually exclusive".to_string(),
+        }),
+    }
+}
+
+fn parse_usb_mode() -> Result<UsbMode, ConfigError> {
+    let Ok(value) = var("USB_MODE") else {
+        return Ok(UsbMode::Push);
+    };
+    match value.trim().to_ascii_lowercase().as_str() {
+        "push" => Ok(UsbMode::Push),
+        "poll" => Ok(UsbMode::Poll {
+            interval: Duration::from_secs(parse_or("USB_POLL_INTERVAL_SECS", 60u64)?.max(1)),
+        }),
+        _ => Err(ConfigError::Invalid {
+            key: "USB_MODE",
+            value,
+            reason: "expected push/poll".to_string(),
+        }),
+    }
+}
+
+fn parse_output_units() -> Result<OutputUnits, ConfigError> {
+    let scale = |key: &'static str, base: &str| -> Result<Scale, ConfigError> {
+        match var(key) {
+            Ok(value) => Scale::parse(key, &value, base),
+            Err(_) => Ok(Scale::Base),
+        }
+    };
+    Ok(OutputUnits {
+        voltage: scale("VOLTAGE_UNIT", "V")?,
+        current: scale("CURRENT_UNIT", "A")?,
+        power: scale("POWER_UNIT", "W")?,
+        precision: parse_or("OUTPUT_PRECISION", 3usize)?.min(9),
+    })
+}
+
+fn parse_debug_dump_target() -> Result<DumpTarget, ConfigError> {
+    let Ok(value) = var("DEBUG_DUMP_TARGET") else {
+        return Ok(DumpTarget::Log);
+    };
+    match value.trim().to_ascii_lowercase().as_str() {
+        "log" => Ok(DumpTarget::Log),
+        "mqtt" => Ok(DumpTarget::Mqtt),
+        _ => Err(ConfigError::Invalid {
+            key: "DEBUG_DUMP_TARGET",
+            value,
+            reason: "expected log/mqtt".to_string(),
+        }),
+    }
+}
+
+fn parse_usb_layout() -> Result<UsbLayout, ConfigError> {
+    let endpoint = |key: &'static str, direction: rusb::Direction| -> Result<Option<u8>, ConfigError> {
+        let Some(value) = var(key).ok().filter(|v| !v.trim().is_empty()) else {
+            return Ok(None);
+        };
+        parse_endpoint(&value, direction)
+            .map(Some)
+            .map_err(|reason| ConfigError::Invalid { key, value, reason })
+    };
+    Ok(UsbLayout {
+        interface: match var("USB_INTERFACE") {
+            Ok(_) => Some(parse_required("USB_INTERFACE")?),
+            Err(_) => None,
+        },
+        ep_cmd: endpoint("USB_EP_CMD", rusb::Direction::Out)?,
+        ep_response: endpoint("USB_EP_RESPONSE", rusb::Direction::In)?,
+        ep_push: endpoint("USB_EP_PUSH", rusb::Direction::In)?,
+    })
+}
+
+/// 解析十六进制端点地址（如 `0x81`），方向位 (0x80) 必须与端点的用途一致
+pub fn parse_endpoint(value: &str, direction: rusb::Direction) -> Result<u8, String> {
+    let address = u8::try_from(parse_hex(value)?).map_err(|e| e.to_string())?;
+    if address & 0x0f == 0 {
+        return Err("endpoint 0 is the control endpoint".to_string());
+    }
+    if address & 0x70 != 0 {
+        return Err("reserved bits 4-6 must be zero".to_string());
+    }
+    match (direction, address & 0x80 != 0) {
+        (rusb::Direction::In, false) => Err("IN endpoint must have the direction bit 0x80 set".to_string()),
+        (rusb::Direction::Out, true) => Err("OUT endpoint must have the direction bit 0x80 clear".to_string()),
+        _ => Ok(address),
+    }
+}
+
+/// 解析 `bus:address` 形式的设备位置，如 `1:7`
+pub fn parse_bus_addr(value: &str) -> Result<DeviceSelector, String> {
+    let (bus, address) = value.trim().split_once(':').ok_or("expected bus:address")?;
+    Ok(DeviceSelector::BusAddr {
+        bus: bus.trim().parse().map_err(|e| format!("bus: {}", e))?,
+        address: address.trim().parse().map_err(|e| format!("address: {}", e))?,
+    })
+}
+
+// USB_IDS 优先；未设置时使用单个 USB_VID / USB_PID
+fn parse_usb_ids() -> Result<Vec<UsbId>, ConfigError> {
+    let Ok(value) = var("USB_IDS") else {
+        return Ok(vec![UsbId {
+            vid: parse_hex_u16("USB_VID", "0x1209")?,
+            pid: parse_hex_u16("USB_PID", "0x0002")?,
+        }]);
+    };
+    parse_usb_id_list(&value).map_err(|reason| ConfigError::Invalid {
+        key: "USB_IDS",
+        value: value.clone(),
+        reason,
+    })
+}
+
+/// 解析 `1209:0002,1209:0003` 形式的 VID/PID 列表（十六进制）
+pub fn parse_usb_id_list(value: &str) -> Result<Vec<UsbId>, String> {
+    let mut ids = Vec::new();
+    for (index, entry) in value.split(',').map(str::trim).enumerate() {
+        let (vid, pid) = entry
+            .split_once(':')
+            .ok_or_else(|| format!("entry {} '{}': expected vid:pid", index + 1, entry))?;
+        let id = UsbId {
+            vid: parse_hex(vid).map_err(|e| format!("entry {} '{}': vid: {}", index + 1, entry, e))?,
+            pid: parse_hex(pid).map_err(|e| format!("entry {} '{}': pid: {}", index + 1, entry, e))?,
+        };
+        if ids.contains(&id) {
+            return Err(format!("entry {} '{}': duplicate", index + 1, entry));
+        }
+        ids.push(id);
+    }
+    Ok(ids)
+}
+
+fn parse_hex_u16(key: &'static str, default: &str) -> Result<u16, ConfigError> {
+    let value = var(key).unwrap_or_else(|_| default.to_string());
+    parse_hex(&value).map_err(|reason| ConfigError::Invalid {
+        key,
+        value: value.clone(),
+        reason,
+    })
+}
+
+/// 解析 `0x1209` 或 `1209` 形式的十六进制 VID/PID
+pub fn parse_hex(value: &str) -> Result<u16, String> {
+    u16::from_str_radix(value.trim().trim_start_matches("0x"), 16).map_err(|e| e.to_string())
+}
+
+/// 主题前缀不能为空，不能含通配符，也不能以 `/` 开头或结尾
+pub fn validate_topic_prefix(prefix: &str) -> Result<(), String> {
+    if prefix.is_empty() {
+        Err("must not be empty".to_string())
+    } else if prefix.contains(['+', '#']) {
+        Err("must not contain MQTT wildcards".to_string())
+    } else if prefix.starts_with('/') || prefix.ends_with('/') {
+        Err("must not start or end with '/'".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMING_OVERRIDES: [(&str, &str); 14] = [
+        ("USB_COMMAND_TIMEOUT_MS", "1100"),
+        ("USB_RESPONSE_TIMEOUT_MS", "1200"),
+        ("USB_PUSH_READ_TIMEOUT_MS", "1300"),
+        ("USB_STALE_TIMEOUT_SECS", "14"),
+        ("USB_CONTEXT_RETRY_SECS", "15"),
+        ("USB_OPEN_RETRY_SECS", "16"),
+        ("USB_SETUP_RETRY_SECS", "17"),
+        ("MQTT_CONNECT_RETRY_SECS", "18"),
+        ("MQTT_KEEP_ALIVE_SECS", "19"),
+        ("MQTT_RECONNECT_BACKOFF_INITIAL_MS", "2000"),
+        ("MQTT_RECONNECT_BACKOFF_MAX_SECS", "21"),
+        ("SHUTDOWN_CONFIRM_TIMEOUT_MS", "2200"),
+        ("SHUTDOWN_UNSUBSCRIBE_TIMEOUT_MS", "2300"),
+        ("SHUTDOWN_TASKS_TIMEOUT_MS", "2400"),
+    ];
+
+    #[test]
+    fn every_timing_field_can_be_overridden() {
+        assert_eq!(test_vars::with_vars(&[], parse_timing_policy).unwrap(), Policy::default());
+
+        let policy = test_vars::with_vars(&TIMING_OVERRIDES, parse_timing_policy);
+        let expected = Policy {
+            usb: UsbTimeouts {
+                command: Duration::from_millis(1100),
+                response: Duration::from_millis(1200),
+                push_read: Duration::from_millis(1300),
+                stale_timeout: Duration::from_secs(14),
+            },
+            usb_context_retry: Duration::from_secs(15),
+            usb_open_retry: Duration::from_secs(16),
+            usb_setup_retry: Duration::from_secs(17),
+            mqtt_connect_retry: Duration::from_secs(18),
+            mqtt_keep_alive: Duration::from_secs(19),
+            mqtt_reconnect_initial: Duration::from_millis(2000),
+            mqtt_reconnect_max: Duration::from_secs(21),
+            shutdown_confirm: Duration::from_millis(2200),
+            shutdown_unsubscribe: Duration::from_millis(2300),
+            shutdown_tasks: Duration::from_millis(2400),
+        };
+        assert_eq!(policy.unwrap(), expected);
+
+        let error = test_vars::with_vars(&[("USB_OPEN_RETRY_SECS", "soon")], parse_timing_policy).unwrap_err();
+        assert!(matches!(error, ConfigError::Invalid { key: "USB_OPEN_RETRY_SECS", .. }));
+    }
+
+    #[test]
+    fn zero_timing_values_are_rejected() {
+        for (key, _) in TIMING_OVERRIDES {
+            let error = test_vars::with_vars(&[(key, "0")], parse_timing_policy).unwrap_err();
+            match error {
+                ConfigError::Invalid { key: rejected, value, .. } => assert_eq!((rejected, value.as_str()), (key, "0")),
+                other => panic!("{}: {:?}", key, other),
+            }
+        }
+    }
+
+    #[test]
+    fn a_zero_stats_interval_is_raised_to_one_second() {
+        let config = DaemonConfig::from_vars(&[
+            ("MQTT_BROKER_HOST", "localhost"),
+            ("MQTT_BROKER_PORT", "1883"),
+            ("STATS_INTERVAL_SECS", "0"),
+        ])
+        .unwrap();
+        assert_eq!(config.stats_interval, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn from_vars_ignores_the_process_environment() {
+        let vars = [("MQTT_BROKER_HOST", "broker.local"), ("MQTT_BROKER_PORT", "1884")];
+        let config = DaemonConfig::from_vars(&vars).unwrap();
+        assert_eq!((config.mqtt_broker_host.as_str(), config.mqtt_broker_port), ("broker.local", 1884));
+        assert!(matches!(DaemonConfig::from_vars(&[]), Err(ConfigError::Missing("MQTT_BROKER_HOST"))));
+        assert_eq!(test_vars::get("MQTT_BROKER_HOST"), None);
+    }
+
+    #[test]
+    fn usb_id_lists_keep_their_order() {
+        let ids = parse_usb_id_list("1209:0002, 0x1209:0x0003,ABCD:ef01").unwrap();
+        assert_eq!(
+            ids,
+            [
+                UsbId { vid: 0x1209, pid: 0x0002 },
+                UsbId { vid: 0x1209, pid: 0x0003 },
+                UsbId { vid: 0xabcd, pid: 0xef01 },
+            ]
+        );
+        assert_eq!(ids[2].to_string(), "abcd:ef01");
+    }
+
+    #[test]
+    fn malformed_usb_id_entries_name_the_entry() {
+        for (value, reason) in [
+            ("1209", "entry 1 '1209': expected vid:pid"),
+            ("1209:0002,", "entry 2 '': expected vid:pid"),
+            ("1209:0002,xyz:0003", "entry 2 'xyz:0003': vid: "),
+            ("1209:10000", "entry 1 '1209:10000': pid: "),
+            ("1209:0002,0x1209:2", "entry 2 '0x1209:2': duplicate"),
+        ] {
+            let error = parse_usb_id_list(value).unwrap_err();
+            assert!(error.starts_with(reason), "{}: {}", value, error);
+        }
+    }
+
+    #[test]
+    fn usb_ids_fall_back_to_usb_vid_and_usb_pid() {
+        let default = test_vars::with_vars(&[], parse_usb_ids).unwrap();
+        assert_eq!(default, [UsbId { vid: 0x1209, pid: 0x0002 }]);
+        let single = test_vars::with_vars(&[("USB_VID", "0x1234"), ("USB_PID", "5678")], parse_usb_ids).unwrap();
+        assert_eq!(single, [UsbId { vid: 0x1234, pid: 0x5678 }]);
+
+        // USB_IDS 优先于 USB_VID / USB_PID
+        let vars = [("USB_IDS", "1209:0003,1209:0002"), ("USB_VID", "0x1234")];
+        let list = test_vars::with_vars(&vars, parse_usb_ids).unwrap();
+        assert_eq!(list, [UsbId { vid: 0x1209, pid: 0x0003 }, UsbId { vid: 0x1209, pid: 0x0002 }]);
+
+        let error = test_vars::with_vars(&[("USB_IDS", "1209-0002")], parse_usb_ids).unwrap_err();
+        assert!(matches!(error, ConfigError::Invalid { key: "USB_IDS", .. }), "{:?}", error);
+        let error = test_vars::with_vars(&[("USB_PID", "zz")], parse_usb_ids).unwrap_err();
+        assert!(matches!(error, ConfigError::Invalid { key: "USB_PID", .. }), "{:?}", error);
+    }
+
+    #[test]
+    fn endpoint_addresses_must_match_their_role() {
+        assert_eq!(parse_endpoint("0x81", rusb::Direction::In), Ok(0x81));
+        assert_eq!(parse_endpoint(" 02 ", rusb::Direction::Out), Ok(0x02));
+        assert_eq!(parse_endpoint("0x8f", rusb::Direction::In), Ok(0x8f));
+        for (value, direction, reason) in [
+            ("0x01", rusb::Direction::In, "direction bit 0x80 set"),
+            ("0x82", rusb::Direction::Out, "direction bit 0x80 clear"),
+            ("0x80", rusb::Direction::In, "control endpoint"),
+            ("0x00", rusb::Direction::Out, "control endpoint"),
+            ("0x91", rusb::Direction::In, "reserved bits"),
+            ("0x181", rusb::Direction::In, "out of range"),
+            ("ep1", rusb::Direction::Out, "invalid digit"),
+        ] {
+            let error = parse_endpoint(value, direction).unwrap_err();
+            assert!(error.contains(reason), "{}: {}", value, error);
+        }
+    }
+
+    #[test]
+    fn usb_layout_overrides_are_optional() {
+        let layout = test_vars::with_vars(&[], parse_usb_layout).unwrap();
+        assert_eq!(layout, UsbLayout::default());
+        assert_eq!(layout.interface(), UsbLayout::DEFAULT_INTERFACE);
+
+        let vars = [("USB_INTERFACE", "2"), ("USB_EP_CMD", "0x03"), ("USB_EP_RESPONSE", "0x83"), ("USB_EP_PUSH", " ")];
+        let layout = test_vars::with_vars(&vars, parse_usb_layout).unwrap();
+        assert_eq!(
+            layout,
+            UsbLayout { interface: Some(2), ep_cmd: Some(0x03), ep_response: Some(0x83), ep_push: None }
+        );
+
+        // 方向位与用途不符时指出是哪个变量
+        for (key, value) in [("USB_EP_CMD", "0x81"), ("USB_EP_RESPONSE", "0x01"), ("USB_EP_PUSH", "0x02"), ("USB_INTERFACE", "one")] {
+            let error = test_vars::with_vars(&[(key, value)], parse_usb_layout).unwrap_err();
+            match error {
+                ConfigError::Invalid { key: rejected, .. } => assert_eq!(rejected, key),
+                other => panic!("{}: {:?}", key, other),
+            }
+        }
+    }
+
+    #[test]
+    fn output_units_default_to_base_units_and_cap_the_precision() {
+        assert_eq!(test_vars::with_vars(&[], parse_output_units).unwrap(), OutputUnits::default());
+        let vars = [("VOLTAGE_UNIT", "mV"), ("POWER_UNIT", "mw"), ("OUTPUT_PRECISION", "20")];
+        let units = test_vars::with_vars(&vars, parse_output_units).unwrap();
+        assert_eq!(
+            units,
+            OutputUnits { voltage: Scale::Milli, current: Scale::Base, power: Scale::Milli, precision: 9 }
+        );
+        let error = test_vars::with_vars(&[("CURRENT_UNIT", "uA")], parse_output_units).unwrap_err();
+        assert!(matches!(error, ConfigError::Invalid { key: "CURRENT_UNIT", .. }), "{:?}", error);
+    }
+
+    #[test]
+    fn the_report_interval_is_optional_but_not_zero() {
+        assert_eq!(test_vars::with_vars(&[], parse_report_interval).unwrap(), None);
+        assert_eq!(test_vars::with_vars(&[("USB_REPORT_INTERVAL_MS", "250")], parse_report_interval).unwrap(), Some(250));
+        for value in ["0", "65536", "fast"] {
+            let error = test_vars::with_vars(&[("USB_REPORT_INTERVAL_MS", value)], parse_report_interval).unwrap_err();
+            assert!(matches!(error, ConfigError::Invalid { key: "USB_REPORT_INTERVAL_MS", .. }), "{}: {:?}", value, error);
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! 本地控制套接字 (`CONTROL_SOCKET`)：Unix 域套接字上的换行分隔 JSON 请求/响应，供本机脚本使用，不必经过 MQTT。
+//!
+//! 每行一个请求，每个请求回复一行：
+//!
+//! - `{"cmd":"status"}`：最近一帧测量数据；尚未收到数据时 `result` 为 `null`
+//! - `{"cmd":"resubscribe"}`：让 USB 管理任务重新订阅
+//! - `{"cmd":"stats"}`：守护进程运行统计，同 `{prefix}/daemon/stats`
+//! - `{"cmd":"history","n":100}`：最近 `n` 帧测量数据（从旧到新），省略 `n` 时返回全部
+//!
+//! 成功时回复 `{"ok":true,"result":...}`，失败时回复 `{"ok":false,"error":"..."}`。
+//! 启动时如果套接字文件已存在且没有进程在监听（上次异常退出遗留），先删除再监听。
+//! 套接字先在同目录下权限为 0700 的临时目录中创建并设置权限，再移动到目标路径，
+//! 其他用户不会在权限设置之前连上。
+
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::data_models::TimestampedMeasurements;
+use crate::ring::History;
+use crate::stats::DaemonStats;
+use crate::usb_types::{DeviceCommand, UsbCommand};
+
+/// 单行请求的最大长度，超出时断开连接
+const MAX_LINE_BYTES: usize = 4096;
+
+/// 控制套接字的配置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlSocketSettings {
+    pub path: PathBuf,
+    /// 套接字文件的权限位，如 0o660
+    pub mode: u32,
+}
+
+/// 处理请求所需的状态
+#[derive(Debug, Clone)]
+pub struct ControlState {
+    pub latest: watch::Receiver<Option<Arc<TimestampedMeasurements>>>,
+    pub stats: Arc<DaemonStats>,
+    pub usb_commands: mpsc::Sender<DeviceCommand>,
+    pub history: History,
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    cmd: String,
+    #[serde(default)]
+    n: Option<usize>,
+}
+
+/// 处理一行请求，返回不含换行的响应
+pub async fn handle_request(line: &str, state: &ControlState) -> String {
+    let request: Request = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return failure(&format!("invalid request: {}", e)),
+    };
+    let result: Result<Value, String> = match request.cmd.as_str() {
+        "status" => {
+            let latest = state.latest.borrow().clone();
+            serde_json::to_value(latest.as_deref()).map_err(|e| e.to_string())
+        }
+        "stats" => serde_json::to_value(state.stats.snapshot()).map_err(|e| e.to_string()),
+        "history" => {
+            let samples = match request.n {
+                Some(n) => state.history.last(n),
+                None => state.history.snapshot(),
+            };
+            let frames: Vec<&TimestampedMeasurements> = samples.iter().map(Arc::as_ref).collect();
+            serde_json::to_value(frames).map_err(|e| e.to_string())
+        }
+        "resubscribe" => state
+            .usb_commands
+            .send(DeviceCommand { target: None, command: UsbCommand::Subscribe })
+            .await
+            .map(|()| json!("resubscribe requested"))
+            .map_err(|_| "USB manager is not running".to_string()),
+        other => Err(format!("unknown command '{}'", other)),
+    };
+    match result {
+        Ok(result) => json!({ "ok": true, "result": result }).to_string(),
+        Err(reason) => failure(&reason),
+    }
+}
+
+fn failure(reason: &str) -> String {
+    json!({ "ok": false, "error": reason }).to_string()
+}
+
+/// 准备监听路径：创建父目录；已存在的套接字文件若无进程监听则删除，有进程监听时报错
+pub async fn prepare_path(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "path exists and is not a socket"));
+    }
+    match UnixStream::connect(path).await {
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, "another instance is listening")),
+        Err(_) => {
+            info!("删除遗留的控制套接字 {}", path.display());
+            std::fs::remove_file(path)
+        }
+    }
+}
+
+/// 在 `path` 监听并把套接字文件的权限设为 `mode`。套接字在只有本用户可进入的临时目录中
+/// 创建并设置权限后才移动到 `path`；任何一步失败都不监听
+pub fn bind_with_mode(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "socket path has no file name"))?;
+    let staging = parent.join(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("s");
+    let result = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    if let Err(e) = std::fs::remove_dir_all(&staging) {
+        warn!("删除控制套接字临时目录 {} 失败: {:?}", staging.display(), e);
+    }
+    result
+}
+
+/// 监听控制套接字直到 `shutdown` 取消，退出时删除套接字文件。监听或设置权限失败只记录错误，不启用控制套接字
+pub async fn control_socket_task(settings: ControlSocketSettings, state: ControlState, shutdown: CancellationToken) {
+    let path = &settings.path;
+    let listener = match prepare_path(path).await.and_then(|()| bind_with_mode(path, settings.mode)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(path = %path.display(), mode = %format!("{:o}", settings.mode), error = ?e, "控制套接字无法监听");
+            return;
+        }
+    };
+    info!("控制套接字已监听 {}", path.display());
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let state = state.clone();
+                    let shutdown = shutdown.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, &state, &shutdown).await {
+                            debug!("控制套接字连接处理失败: {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("控制套接字接受连接失败: {:?}", e),
+            },
+        }
+    }
+    if let Err(e) = std::fs::remove_file(path) {
+        warn!("删除控制套接字 {} 失败: {:?}", path.display(), e);
+    }
+    info!("控制套接字已停止");
+}
+
+async fn handle_connection(stream: UnixStream, state: &ControlState, shutdown: &CancellationToken) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let mut limited = (&mut reader).take(MAX_LINE_BYTES as u64);
+        let read = tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            read = limited.read_line(&mut line) => read?,
+        };
+        if read == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && line.len() >= MAX_LINE_BYTES {
+            writer.write_all(failure("request too large").as_bytes()).await?;
+            return writer.write_all(b"\n").await;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut response = handle_request(line.trim(), state).await;
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_support::PayloadBuilder;
+
+    struct Instance {
+        path: PathBuf,
+        latest: watch::Sender<Option<Arc<TimestampedMeasurements>>>,
+        history: History,
+        usb: mpsc::Receiver<DeviceCommand>,
+        shutdown: CancellationToken,
+        task: tokio::task::JoinHandle<()>,
+        _dir: tempfile::TempDir,
+    }
+
+    async fn start(mode: u32) -> Instance {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run").join("control.sock");
+        let (latest, latest_rx) = watch::channel(None);
+        // 模拟的 USB 管理任务：只收命令
+        let (usb_tx, usb) = mpsc::channel(4);
+        let history = History::new(8);
+        let state = ControlState {
+            latest: latest_rx,
+            stats: Arc::new(DaemonStats::default()),
+            usb_commands: usb_tx,
+            history: history.clone(),
+        };
+        let shutdown = CancellationToken::new();
+        let settings = ControlSocketSettings { path: path.clone(), mode };
+        let task = tokio::spawn(control_socket_task(settings, state, shutdown.clone()));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while UnixStream::connect(&path).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("控制套接字未监听");
+        Instance { path, latest, history, usb, shutdown, task, _dir: dir }
+    }
+
+    async fn request(stream: &mut BufReader<UnixStream>, line: &str) -> Value {
+        stream.get_mut().write_all(format!("{}\n", line).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_line(&mut response).await.unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_are_answered_line_by_line() {
+        let mut instance = start(0o600).await;
+        let mut stream = BufReader::new(UnixStream::connect(&instance.path).await.unwrap());
+
+        assert_eq!(request(&mut stream, r#"{"cmd":"status"}"#).await, json!({ "ok": true, "result": null }));
+        instance.latest.send_replace(Some(Arc::new(PayloadBuilder::new().sample(7, 1_000))));
+        let status = request(&mut stream, r#"{"cmd":"status"}"#).await;
+        assert_eq!(status["result"]["frame_id"], 7);
+
+        let stats = request(&mut stream, r#"{"cmd":"stats"}"#).await;
+        assert_eq!(stats["ok"], true);
+        assert!(stats["result"].is_object());
+
+        let resubscribe = request(&mut stream, r#"{"cmd":"resubscribe"}"#).await;
+        assert_eq!(resubscribe["ok"], true);
+        let command = instance.usb.try_recv().unwrap();
+        assert!(matches!(command.command, UsbCommand::Subscribe));
+
+        let unknown = request(&mut stream, r#"{"cmd":"reboot"}"#).await;
+        assert_eq!(unknown["ok"], false);
+
+        instance.shutdown.cancel();
+        instance.task.await.unwrap();
+        assert!(!instance.path.exists());
+    }
+
+    #[tokio::test]
+    async fn history_returns_the_last_samples_oldest_first() {
+        let instance = start(0o600).await;
+        let mut stream = BufReader::new(UnixStream::connect(&instance.path).await.unwrap());
+        assert_eq!(request(&mut stream, r#"{"cmd":"history"}"#).await, json!({ "ok": true, "result": [] }));
+        for frame_id in 1..=10 {
+            instance.history.push(Arc::new(PayloadBuilder::new().sample(frame_id, 1_000 * frame_id)));
+        }
+        let frame_ids = |response: Value| -> Vec<u64> {
+            response["result"].as_array().unwrap().iter().map(|frame| frame["frame_id"].as_u64().unwrap()).collect()
+        };
+        assert_eq!(frame_ids(request(&mut stream, r#"{"cmd":"history","n":3}"#).await), [8, 9, 10]);
+        // 容量为 8，省略 n 时返回全部
+        assert_eq!(frame_ids(request(&mut stream, r#"{"cmd":"history"}"#).await), (3..=10).collect::<Vec<_>>());
+        instance.shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn the_socket_gets_the_configured_mode_and_no_staging_directory_is_left() {
+        let instance = start(0o600).await;
+        let metadata = std::fs::metadata(&instance.path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        let entries: Vec<_> = std::fs::read_dir(instance.path.parent().unwrap()).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        instance.shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn a_stale_socket_from_a_crash_is_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        // 监听后直接丢弃，留下没有进程监听的套接字文件
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        prepare_path(&path).await.unwrap();
+        assert!(!path.exists());
+        let _listener = bind_with_mode(&path, 0o660).unwrap();
+        assert!(UnixStream::connect(&path).await.is_ok());
+        assert_eq!(prepare_path(&path).await.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+    }
+}
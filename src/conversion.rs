@@ -0,0 +1,405 @@
+use super::data_models::{
+    AllMeasurements, Bq25730Measurements, Bq76920Measurements, Ina226Measurements, Temperatures,
+    SystemStatus, MosStatus, ChargerStatusFlags, ChargerFaultFlags, ProchotLsbFlags, ProchotMsbFlags,
+    Bq25730Alerts, Bq76920Alerts, HostSideUsbPayload,
+};
+use crate::config::DaemonConfig;
+use crate::diagnostics::classify_ts_raw;
+use crate::utils::{self, CurrentSenseConfig, DieTempConfig, PsysConfig, ThermistorConfig};
+
+// 载荷 <-> AllMeasurements 转换层。转换所需的全部常量都来自 ConversionContext，
+// 本模块不读取环境变量。
+
+/// 电池包电流的符号约定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CurrentSign {
+    /// 充电为正（固件原始约定）
+    ChargePositive,
+    /// 放电为正
+    DischargePositive,
+}
+
+impl CurrentSign {
+    fn factor(self) -> f32 {
+        match self {
+            CurrentSign::ChargePositive => 1.0,
+            CurrentSign::DischargePositive => -1.0,
+        }
+    }
+
+    /// 把按本约定发布的电流换回充电为正
+    pub fn to_charge_positive(self, amps: f32) -> f32 {
+        amps * self.factor()
+    }
+}
+
+/// 转换上下文：启动时由配置构建一次，之后只读，按引用传入所有转换路径
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionContext {
+    /// 检流电阻，决定 ICHG / IDCHG / IIN 的 LSB
+    pub current_sense: CurrentSenseConfig,
+    /// PSYS 换算参数（输入检流电阻、PSYS_RATIO、ADC 满量程）
+    pub psys: PsysConfig,
+    pub current_sign: CurrentSign,
+    /// 实际串联的电芯数，其余通道输出 0
+    pub cell_count: usize,
+    /// TS 通道按芯片温度换算的常数
+    pub die_temp: DieTempConfig,
+    /// TS1..TS3 外接热敏电阻的参数，载荷标记为热敏电阻模式时使用
+    pub thermistors: [ThermistorConfig; 3],
+}
+
+impl Default for ConversionContext {
+    fn default() -> Self {
+        ConversionContext {
+            current_sense: CurrentSenseConfig::default(),
+            psys: PsysConfig::default(),
+            current_sign: CurrentSign::ChargePositive,
+            cell_count: 5,
+            die_temp: DieTempConfig::default(),
+            thermistors: [ThermistorConfig::default(); 3],
+        }
+    }
+}
+
+impl ConversionContext {
+    pub fn from_config(config: &DaemonConfig) -> Self {
+        ConversionContext {
+            current_sense: config.current_sense,
+            psys: PsysConfig {
+                rsns_ac_mohm: config.current_sense.rsns_ac_mohm,
+                psys_ratio: config.psys_ratio,
+                adc_fullscale: config.adc_fullscale,
+            },
+            current_sign: config.current_sign,
+            cell_count: config.cell_count,
+            thermistors: config.thermistors,
+            ..ConversionContext::default()
+        }
+    }
+
+    /// TS 原始 ADC 值 -> 温度 (°C)；`sensor` 为 0..3 对应 TS1..TS3
+    fn ts_to_celsius(&self, sensor: usize, raw_adc: u16, is_thermistor: bool) -> f32 {
+        if is_thermistor {
+            utils::thermistor_raw_to_celsius(raw_adc, &self.thermistors[sensor])
+        } else {
+            utils::ts_raw_to_celsius(raw_adc, &self.die_temp)
+        }
+    }
+
+    fn celsius_to_ts(&self, sensor: usize, celsius: f32, is_thermistor: bool) -> u16 {
+        if is_thermistor {
+            utils::celsius_to_thermistor_raw(celsius, &self.thermistors[sensor])
+        } else {
+            utils::celsius_to_ts_raw(celsius, &self.die_temp)
+        }
+    }
+}
+
+// 可选温度通道的有效原始值；不存在或读数无效时为 None
+// PROCHOT_STATUS (REG0x22/0x23) 按数据手册的布局：低字节为 LSB 标志；高字节 bit6 为 EN_PROCHOT_EXT，
+// bit5:4 为 PROCHOT_WIDTH，bit3 为 PROCHOT_CLEAR，bit1:0 为 VAP 状态，bit7 与 bit2 保留
+const PROCHOT_WIDTH_SHIFT: u16 = 12;
+const PROCHOT_WIDTH_MASK: u16 = 0b11 << PROCHOT_WIDTH_SHIFT;
+
+/// 拆分 PROCHOT_STATUS 原始值为 (LSB 标志, MSB 标志, 宽度)。保留位丢弃
+pub fn unpack_prochot(raw: u16) -> (ProchotLsbFlags, ProchotMsbFlags, u8) {
+    let lsb = ProchotLsbFlags::from_bits_truncate((raw & 0xFF) as u8);
+    let msb = ProchotMsbFlags::from_bits_truncate((raw >> 8) as u8);
+    let width = ((raw & PROCHOT_WIDTH_MASK) >> PROCHOT_WIDTH_SHIFT) as u8;
+    (lsb, msb, width)
+}
+
+/// `unpack_prochot` 的逆运算；宽度只取低两位
+pub fn pack_prochot(lsb: ProchotLsbFlags, msb: ProchotMsbFlags, width: u8) -> u16 {
+    ((msb.bits() as u16) << 8) | (((width as u16) << PROCHOT_WIDTH_SHIFT) & PROCHOT_WIDTH_MASK) | lsb.bits() as u16
+}
+
+fn optional_temp(present: u8, raw: u16) -> Option<u16> {
+    (present != 0 && classify_ts_raw(raw).is_none()).then_some(raw)
+}
+
+/// 固件载荷 -> 带物理单位的测量数据
+pub fn to_measurements<const N: usize>(payload: &HostSideUsbPayload, ctx: &ConversionContext) -> AllMeasurements<N> {
+        tracing::debug!("[CONVERT] Constructing AllMeasurements struct from HostSideUsbPayload");
+
+        AllMeasurements {
+            bq25730: Bq25730Measurements {
+                // 固件发送 PSYS 的 8 位 ADC 原始计数，W/LSB 由检流电阻与 PSYS_RATIO / ADC_FULLSCALE 决定
+                psys: utils::psys_raw_to_watts(payload.bq25730_adc_psys_raw, &ctx.psys),
+                vbus: payload.bq25730_adc_vbus_raw as f32 / 1000.0, // Correct if vbus_raw is mV
+                // 电流为 ADC 原始计数，LSB 取决于检流电阻
+                idchg: utils::current_raw_to_amps(payload.bq25730_adc_idchg_raw, ctx.current_sense.idchg_lsb_ma()),
+                ichg: utils::current_raw_to_amps(payload.bq25730_adc_ichg_raw, ctx.current_sense.ichg_lsb_ma()),
+                cmpin: payload.bq25730_adc_cmpin_raw as f32 / 1000.0, // Correct if cmpin_raw is mV (was (val as u8 * 12.0)/1000)
+                iin: utils::current_raw_to_amps(payload.bq25730_adc_iin_raw, ctx.current_sense.iin_lsb_ma()),
+                vbat: payload.bq25730_adc_vbat_raw as f32 / 1000.0, // Correct if vbat_raw is mV
+                vsys: payload.bq25730_adc_vsys_raw as f32 / 1000.0, // Correct if vsys_raw is mV
+            },
+            bq76920: Bq76920Measurements {
+                cell_voltages: {
+                    let mut voltages_v = [0.0f32; N];
+                    if N >= 1 { voltages_v[0] = payload.bq76920_cell1_mv as f32 / 1000.0; }
+                    if N >= 2 { voltages_v[1] = payload.bq76920_cell2_mv as f32 / 1000.0; }
+                    if N >= 3 { voltages_v[2] = payload.bq76920_cell3_mv as f32 / 1000.0; }
+                    if N >= 4 { voltages_v[3] = payload.bq76920_cell4_mv as f32 / 1000.0; }
+                    if N >= 5 { voltages_v[4] = payload.bq76920_cell5_mv as f32 / 1000.0; }
+                    for v in voltages_v.iter_mut().skip(ctx.cell_count) {
+                        *v = 0.0;
+                    }
+                    voltages_v
+                },
+                cell_count: ctx.cell_count.min(N),
+                temperatures: {
+                    // 热敏电阻模式按外接 NTC 的 β 方程换算，否则按芯片温度公式
+                    let is_thermistor = payload.bq76920_is_thermistor != 0;
+                    Temperatures {
+                        // TS1 总是存在；任何非零 present 都算存在。读数无效（开路/短路/超量程）时按传感器故障处理，不输出温度
+                        ts1: optional_temp(1, payload.bq76920_ts1_raw_adc).map(|raw| ctx.ts_to_celsius(0, raw, is_thermistor)),
+                        ts2: optional_temp(payload.bq76920_ts2_present, payload.bq76920_ts2_raw_adc).map(|raw| ctx.ts_to_celsius(1, raw, is_thermistor)),
+                        ts3: optional_temp(payload.bq76920_ts3_present, payload.bq76920_ts3_raw_adc).map(|raw| ctx.ts_to_celsius(2, raw, is_thermistor)),
+                        is_thermistor,
+                    }
+                },
+                coulomb_counter: payload.bq76920_current_ma as f32 / 1000.0 * ctx.current_sign.factor(),
+                system_status: SystemStatus::from_bits_truncate(payload.bq76920_system_status_bits),
+                mos_status: MosStatus::from_bits(payload.bq76920_mos_status_bits),
+            },
+            ina226: payload.ina226_present.then_some(Ina226Measurements {
+                voltage: payload.ina226_voltage_f32,
+                current: payload.ina226_current_f32,
+                power: payload.ina226_power_f32,
+            }),
+            bq25730_alerts: {
+                let charger_status_flags = ChargerStatusFlags::from_bits_truncate((payload.bq25730_charger_status_raw_u16 >> 8) as u8);
+                let charger_fault_flags = ChargerFaultFlags::from_bits_truncate((payload.bq25730_charger_status_raw_u16 & 0xFF) as u8);
+                let (prochot_lsb_flags, prochot_msb_flags, prochot_width) =
+                    unpack_prochot(payload.bq25730_prochot_status_raw_u16);
+                Bq25730Alerts {
+                    charger_status_flags,
+                    charger_fault_flags,
+                    prochot_lsb_flags,
+                    prochot_msb_flags,
+                    prochot_width,
+                }
+            },
+            bq76920_alerts: Bq76920Alerts {
+                system_status: SystemStatus::from_bits_truncate(payload.bq76920_alerts_system_status_bits),
+            },
+        }
+}
+
+/// 测量数据 -> 固件载荷，`to_measurements` 的逆变换（用于构造测试/回放数据）
+pub fn to_payload<const N: usize>(measurements: &AllMeasurements<N>, ctx: &ConversionContext) -> HostSideUsbPayload {
+        tracing::debug!("[CONVERT] Preparing HostSideUsbPayload from AllMeasurements: {:?}", measurements);
+
+        let is_thermistor = measurements.bq76920.temperatures.is_thermistor;
+        let cell_mv = |i: usize| if N > i && i < measurements.bq76920.cell_count.min(ctx.cell_count) { (measurements.bq76920.cell_voltages[i] * 1000.0).round() as i32 } else { 0 };
+        HostSideUsbPayload {
+            // BQ25730: Convert back to raw u16 values (voltages in mV; currents and psys are raw ADC counts)
+            bq25730_adc_vbat_raw: (measurements.bq25730.vbat * 1000.0).round() as u16,
+            bq25730_adc_vsys_raw: (measurements.bq25730.vsys * 1000.0).round() as u16,
+            bq25730_adc_ichg_raw: utils::amps_to_current_raw(measurements.bq25730.ichg, ctx.current_sense.ichg_lsb_ma()),
+            bq25730_adc_idchg_raw: utils::amps_to_current_raw(measurements.bq25730.idchg, ctx.current_sense.idchg_lsb_ma()),
+            bq25730_adc_iin_raw: utils::amps_to_current_raw(measurements.bq25730.iin, ctx.current_sense.iin_lsb_ma()),
+            bq25730_adc_psys_raw: utils::watts_to_psys_raw(measurements.bq25730.psys, &ctx.psys), // W to raw ADC count
+            bq25730_adc_vbus_raw: (measurements.bq25730.vbus * 1000.0).round() as u16,
+            bq25730_adc_cmpin_raw: (measurements.bq25730.cmpin * 1000.0).round() as u16, // V to mV
+
+            // BQ76920
+            bq76920_cell1_mv: cell_mv(0),
+            bq76920_cell2_mv: cell_mv(1),
+            bq76920_cell3_mv: cell_mv(2),
+            bq76920_cell4_mv: cell_mv(3),
+            bq76920_cell5_mv: cell_mv(4),
+            
+            // 温度按正向换算的逆运算还原为原始 ADC 值（芯片温度模式误差在 1 LSB 内）
+            bq76920_ts1_raw_adc: measurements.bq76920.temperatures.ts1.map_or(0, |t| ctx.celsius_to_ts(0, t, is_thermistor)),
+            bq76920_ts2_present: measurements.bq76920.temperatures.ts2.is_some() as u8,
+            bq76920_ts2_raw_adc: measurements.bq76920.temperatures.ts2.map_or(0, |t| ctx.celsius_to_ts(1, t, is_thermistor)),
+            bq76920_ts3_present: measurements.bq76920.temperatures.ts3.is_some() as u8,
+            bq76920_ts3_raw_adc: measurements.bq76920.temperatures.ts3.map_or(0, |t| ctx.celsius_to_ts(2, t, is_thermistor)),
+            bq76920_is_thermistor: measurements.bq76920.temperatures.is_thermistor as u8,
+            bq76920_current_ma: (measurements.bq76920.coulomb_counter * ctx.current_sign.factor() * 1000.0).round() as i32,
+            bq76920_system_status_bits: measurements.bq76920.system_status.bits(),
+            bq76920_mos_status_bits: measurements.bq76920.mos_status.as_bits(),
+
+            // INA226（没有读数时填 0，写入始终使用 v2 布局）
+            ina226_voltage_f32: measurements.ina226.as_ref().map_or(0.0, |ina226| ina226.voltage),
+            ina226_current_f32: measurements.ina226.as_ref().map_or(0.0, |ina226| ina226.current),
+            ina226_power_f32: measurements.ina226.as_ref().map_or(0.0, |ina226| ina226.power),
+
+            // BQ25730 Alerts
+            bq25730_charger_status_raw_u16: 
+                ((measurements.bq25730_alerts.charger_status_flags.bits() as u16) << 8) |
+                (measurements.bq25730_alerts.charger_fault_flags.bits() as u16),
+            bq25730_prochot_status_raw_u16: pack_prochot(
+                measurements.bq25730_alerts.prochot_lsb_flags,
+                measurements.bq25730_alerts.prochot_msb_flags,
+                measurements.bq25730_alerts.prochot_width,
+            ),
+
+            // BQ76920 Alerts
+            bq76920_alerts_system_status_bits: measurements.bq76920_alerts.system_status.bits(),
+            sequence: None,
+            ina226_present: measurements.ina226.is_some(),
+        }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::diagnostics::{check_payload, FrameAnomaly, SensorFault, TS_ADC_MAX};
+    use crate::test_support::PayloadBuilder;
+
+    // 固件实际发出过的温度通道组合：(ts1_raw, ts2_present, ts2_raw)
+    fn fixture(ts1_raw: u16, ts2_present: u8, ts2_raw: u16) -> HostSideUsbPayload {
+        HostSideUsbPayload {
+            bq76920_ts1_raw_adc: ts1_raw,
+            bq76920_ts2_present: ts2_present,
+            bq76920_ts2_raw_adc: ts2_raw,
+            ..PayloadBuilder::new().payload()
+        }
+    }
+
+    fn temperatures(payload: &HostSideUsbPayload) -> Temperatures {
+        to_measurements::<5>(payload, &ConversionContext::default()).bq76920.temperatures
+    }
+
+    #[test]
+    fn faulted_ts1_is_absent_and_reported() {
+        for (raw, fault) in [(0, SensorFault::Short), (TS_ADC_MAX, SensorFault::Open), (0xFFFF, SensorFault::OutOfRange)] {
+            let payload = fixture(raw, 0, 0);
+            assert_eq!(temperatures(&payload).ts1, None, "raw {:#06x}", raw);
+            assert!(check_payload(&payload, 5).contains(&FrameAnomaly::SensorFault { sensor: "ts1", fault, raw }));
+        }
+    }
+
+    #[test]
+    fn faulted_ts1_is_published_as_null() {
+        let measurements = to_measurements::<5>(&fixture(TS_ADC_MAX, 0, 0), &ConversionContext::default());
+        let json = serde_json::to_value(measurements).unwrap();
+        assert_eq!(json["bq76920"]["temperatures"]["ts1"], serde_json::Value::Null);
+        let back: AllMeasurements<5> = serde_json::from_value(json).unwrap();
+        assert_eq!(back.bq76920.temperatures.ts1, None);
+    }
+
+    #[test]
+    fn any_nonzero_present_flag_counts_as_present() {
+        let valid = PayloadBuilder::new().payload().bq76920_ts1_raw_adc;
+        let ts = temperatures(&fixture(valid, 0xFF, valid));
+        assert!(ts.ts1.is_some());
+        assert_eq!(ts.ts2, ts.ts1);
+    }
+
+    #[test]
+    fn present_sensor_with_garbage_raw_is_a_fault() {
+        let payload = fixture(PayloadBuilder::new().payload().bq76920_ts1_raw_adc, 1, 0x8001);
+        assert_eq!(temperatures(&payload).ts2, None);
+        assert!(check_payload(&payload, 5).contains(&FrameAnomaly::SensorFault {
+            sensor: "ts2",
+            fault: SensorFault::OutOfRange,
+            raw: 0x8001,
+        }));
+    }
+
+    #[test]
+    fn absent_sensor_with_raw_value_is_a_contract_violation() {
+        let payload = fixture(PayloadBuilder::new().payload().bq76920_ts1_raw_adc, 0, 0x1234);
+        assert_eq!(temperatures(&payload).ts2, None);
+        assert!(check_payload(&payload, 5)
+            .iter()
+            .any(|anomaly| matches!(anomaly, FrameAnomaly::ContractViolation { field: "bq76920_ts2_present", .. })));
+    }
+
+    #[test]
+    fn temperatures_and_present_flags_survive_a_round_trip() {
+        let ctx = ConversionContext::default();
+        let mut measurements = PayloadBuilder::new().measurements();
+        measurements.bq76920.temperatures = Temperatures { ts1: Some(31.5), ts2: Some(-12.25), ts3: None, is_thermistor: false };
+        let payload = to_payload(&measurements, &ctx);
+        assert_eq!((payload.bq76920_ts2_present, payload.bq76920_ts3_present), (1, 0));
+        assert_ne!(payload.bq76920_ts1_raw_adc, 0);
+        let back = to_measurements::<5>(&payload, &ctx).bq76920.temperatures;
+        // 芯片温度模式 1 LSB 约 0.09°C
+        let lsb_celsius = 382.0 / 42.0 / 100.0;
+        assert!((back.ts1.unwrap() - 31.5).abs() <= lsb_celsius);
+        assert!((back.ts2.unwrap() + 12.25).abs() <= lsb_celsius);
+        assert_eq!(back.ts3, None);
+    }
+
+    #[test]
+    fn thermistor_mode_uses_each_sensors_parameters() {
+        let mut ctx = ConversionContext::default();
+        ctx.thermistors[1] = ThermistorConfig { beta: 3950.0, r25_ohms: 100_000.0 };
+        let raw = utils::celsius_to_thermistor_raw(25.0, &ThermistorConfig::default());
+        let payload = HostSideUsbPayload {
+            bq76920_is_thermistor: 1,
+            ..fixture(raw, 1, raw)
+        };
+        let temperatures = to_measurements::<5>(&payload, &ctx).bq76920.temperatures;
+        assert!(temperatures.is_thermistor);
+        assert!((temperatures.ts1.unwrap() - 25.0).abs() <= 0.5);
+        // 同样的引脚电压对 100 kΩ 的 NTC 意味着高得多的温度
+        assert!(temperatures.ts2.unwrap() > 60.0, "{:?}", temperatures.ts2);
+    }
+
+    #[test]
+    fn unused_cell_channels_are_masked_in_both_directions() {
+        let mut payload = PayloadBuilder::new().payload();
+        // 未接电芯的通道上有噪声
+        payload.bq76920_cell1_mv = 3601;
+        payload.bq76920_cell2_mv = 3602;
+        payload.bq76920_cell3_mv = 3603;
+        payload.bq76920_cell4_mv = 137;
+        payload.bq76920_cell5_mv = 42;
+        for cell_count in 3..=5 {
+            let ctx = ConversionContext { cell_count, ..ConversionContext::default() };
+            let measurements: AllMeasurements<5> = to_measurements(&payload, &ctx);
+            assert_eq!(measurements.bq76920.cells().len(), cell_count);
+            assert!(measurements.bq76920.cell_voltages[cell_count..].iter().all(|&v| v == 0.0));
+            let json = serde_json::to_value(measurements.bq76920).unwrap();
+            assert_eq!(json["cell_voltages"].as_array().unwrap().len(), cell_count);
+
+            let back = to_payload(&measurements, &ctx);
+            let cells = [back.bq76920_cell1_mv, back.bq76920_cell2_mv, back.bq76920_cell3_mv, back.bq76920_cell4_mv, back.bq76920_cell5_mv];
+            let expected = [3601, 3602, 3603, 137, 42];
+            for i in 0..5 {
+                assert_eq!(cells[i], if i < cell_count { expected[i] } else { 0 }, "{}S cell{}", cell_count, i + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn prochot_pack_and_unpack_are_inverse() {
+        for lsb in 0..=u8::MAX {
+            let lsb = ProchotLsbFlags::from_bits(lsb).unwrap();
+            for msb in 0..=u8::MAX {
+                let Some(msb) = ProchotMsbFlags::from_bits(msb) else {
+                    continue;
+                };
+                for width in 0..=3 {
+                    assert_eq!(unpack_prochot(pack_prochot(lsb, msb, width)), (lsb, msb, width));
+                }
+            }
+        }
+        // 原始值中只有保留位 (MSB 的 bit7 与 bit2) 在往返中丢失
+        const RESERVED: u16 = 0x8400;
+        for raw in 0..=u16::MAX {
+            let (lsb, msb, width) = unpack_prochot(raw);
+            assert_eq!(pack_prochot(lsb, msb, width), raw & !RESERVED, "{:#06x}", raw);
+        }
+    }
+
+    #[test]
+    fn prochot_bits_match_the_register_layout() {
+        assert_eq!(pack_prochot(ProchotLsbFlags::empty(), ProchotMsbFlags::EN_PROCHOT_EXT, 0), 1 << 14);
+        assert_eq!(pack_prochot(ProchotLsbFlags::empty(), ProchotMsbFlags::PROCHOT_CLEAR, 0), 1 << 11);
+        assert_eq!(pack_prochot(ProchotLsbFlags::STAT_VINDPM, ProchotMsbFlags::empty(), 0), 1 << 7);
+        assert_eq!(pack_prochot(ProchotLsbFlags::empty(), ProchotMsbFlags::empty(), 0b10), 0b10 << 12);
+        // 宽度只取低两位
+        assert_eq!(pack_prochot(ProchotLsbFlags::empty(), ProchotMsbFlags::empty(), 0b110), 0b10 << 12);
+        let (_, msb, width) = unpack_prochot(0x7000);
+        assert_eq!((msb, width), (ProchotMsbFlags::EN_PROCHOT_EXT, 0b11));
+    }
+}
@@ -0,0 +1,314 @@
+//! 库仑计电量 (SOC)。
+//!
+//! 对电池包电流积分跟踪剩余电量，不受负载下电压跌落的影响。积分误差会随时间累积，
+//! 因此在充电器报告充满时校准到 100%，在欠压保护动作时校准到 0%。
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{error, info, warn};
+use rumqttc::{AsyncClient, QoS};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::conversion::CurrentSign;
+use crate::data_models::{ChargerStatusFlags, SystemStatus, TimestampedMeasurements};
+use crate::pipeline::Subscription;
+use crate::utils;
+
+/// 未经校准的累计净电荷 (Ah，充电为正)
+pub fn coulomb_ah_topic(topic_prefix: &str) -> String {
+    format!("{}/battery/coulomb_ah", topic_prefix)
+}
+
+/// 库仑计电量百分比
+pub fn coulomb_soc_topic(topic_prefix: &str) -> String {
+    format!("{}/battery/coulomb_soc", topic_prefix)
+}
+
+/// 校准原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Calibration {
+    /// 充电器由充电状态退出且仍接着适配器
+    ChargeDone,
+    /// 欠压保护置位
+    Undervoltage,
+}
+
+/// 库仑计状态，可序列化保存以便跨重启继续计数
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CoulombCounter {
+    /// 累计净电荷 (Ah)，只积分、从不校准，与 `remaining_ah` 的差异反映积分漂移
+    pub integrated_ah: f64,
+    /// 剩余电量 (Ah)；None 表示尚未初始化
+    pub remaining_ah: Option<f64>,
+    /// 上一帧的接收时间与电流 (A，充电为正)
+    #[serde(skip)]
+    last: Option<(u64, f32)>,
+    /// 上一帧充电器是否处于充电状态
+    #[serde(skip)]
+    was_charging: bool,
+}
+
+impl CoulombCounter {
+    /// 计入一段电流，梯形积分。时钟回拨的区间不计入，两帧间隔超过 `max_gap` 时只按 `max_gap` 计入
+    pub fn record(&mut self, ts_unix_ms: u64, current_a: f32, capacity_ah: f64, max_gap: Duration) {
+        if let Some((last_ts, last_current)) = self.last
+            && ts_unix_ms > last_ts
+        {
+            let dt_ms = (ts_unix_ms - last_ts).min(max_gap.as_millis() as u64);
+            let ah = (last_current as f64 + current_a as f64) / 2.0 * dt_ms as f64 / 3_600_000.0;
+            self.integrated_ah += ah;
+            if let Some(remaining) = self.remaining_ah.as_mut() {
+                *remaining = (*remaining + ah).clamp(0.0, capacity_ah);
+            }
+        }
+        self.last = Some((ts_unix_ms, current_a));
+    }
+
+    /// 按充电器与保护芯片的状态校准，返回本帧是否发生了校准
+    pub fn calibrate(
+        &mut self,
+        charger: ChargerStatusFlags,
+        system_status: SystemStatus,
+        capacity_ah: f64,
+    ) -> Option<Calibration> {
+        let charging = charger.intersects(ChargerStatusFlags::IN_FCHRG | ChargerStatusFlags::IN_PCHRG);
+        let charge_done = self.was_charging && !charging && charger.contains(ChargerStatusFlags::STAT_AC);
+        self.was_charging = charging;
+        if system_status.contains(SystemStatus::UV) {
+            self.remaining_ah = Some(0.0);
+            Some(Calibration::Undervoltage)
+        } else if charge_done {
+            self.remaining_ah = Some(capacity_ah);
+            Some(Calibration::ChargeDone)
+        } else {
+            None
+        }
+    }
+
+    /// 尚未初始化时以 `soc_percent`（通常为电压查表的估算值）作为起点
+    pub fn seed(&mut self, soc_percent: Option<f32>, capacity_ah: f64) {
+        if self.remaining_ah.is_none()
+            && let Some(soc) = soc_percent
+        {
+            self.remaining_ah = Some(capacity_ah * soc as f64 / 100.0);
+        }
+    }
+
+    /// 剩余电量百分比，保留一位小数
+    pub fn soc_percent(&self, capacity_ah: f64) -> Option<f32> {
+        self.remaining_ah
+            .map(|remaining| ((remaining / capacity_ah * 1000.0).round() / 10.0) as f32)
+    }
+
+    /// 读取上次保存的状态；文件不存在时返回 None
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let saved: CoulombCounter =
+            serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Some(saved))
+    }
+
+    pub fn persist(&self, path: &Path) -> io::Result<()> {
+        let text = serde_json::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        utils::write_atomic(path, text.as_bytes())
+    }
+}
+
+/// 库仑计任务的配置
+#[derive(Debug, Clone)]
+pub struct CoulombSettings {
+    pub state_path: PathBuf,
+    pub capacity_ah: f64,
+    /// 两次写状态文件之间的最短间隔
+    pub persist_interval: Duration,
+    /// 两次发布之间的最短间隔
+    pub publish_interval: Duration,
+    /// 积分时两帧间隔的上限
+    pub max_gap: Duration,
+    pub current_sign: CurrentSign,
+}
+
+/// 订阅测量数据，以电池包电流积分跟踪剩余电量，以 retained 方式发布到
+/// `{prefix}/battery/coulomb_ah` 与 `{prefix}/battery/coulomb_soc`，并定期保存以便跨重启继续计数
+pub async fn coulomb_task(
+    mut samples: Subscription,
+    client: AsyncClient,
+    topic_prefix: String,
+    settings: CoulombSettings,
+) {
+    let capacity_ah = settings.capacity_ah;
+    let mut counter = match CoulombCounter::load(&settings.state_path) {
+        Ok(Some(mut saved)) => {
+            // 容量配置变小后剩余电量不能超过新容量
+            saved.remaining_ah = saved.remaining_ah.map(|remaining| remaining.clamp(0.0, capacity_ah));
+            info!(
+                "恢复库仑计: 累计 {:.3} Ah，电量 {:?}%",
+                saved.integrated_ah,
+                saved.soc_percent(capacity_ah)
+            );
+            saved
+        }
+        Ok(None) => CoulombCounter::default(),
+        Err(e) => {
+            warn!("读取库仑计状态 {} 失败: {:?}，重新开始", settings.state_path.display(), e);
+            CoulombCounter::default()
+        }
+    };
+
+    let mut last_persist = tokio::time::Instant::now();
+    let mut last_publish: Option<tokio::time::Instant> = None;
+    loop {
+        let sample: Arc<TimestampedMeasurements> = match samples.recv().await {
+            Ok(sample) => sample,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("库仑计处理过慢，跳过 {} 帧", n);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        let data = &sample.data;
+        let current = settings.current_sign.to_charge_positive(data.bq76920.coulomb_counter);
+        counter.record(sample.ts_unix_ms, current, capacity_ah, settings.max_gap);
+        counter.seed(sample.battery.soc_percent, capacity_ah);
+        let system_status = data.bq76920.system_status | data.bq76920_alerts.system_status;
+        if let Some(calibration) =
+            counter.calibrate(data.bq25730_alerts.charger_status_flags, system_status, capacity_ah)
+        {
+            info!("库仑计校准 ({:?})，累计 {:.3} Ah", calibration, counter.integrated_ah);
+        }
+
+        if last_publish.is_none_or(|at| at.elapsed() >= settings.publish_interval) {
+            last_publish = Some(tokio::time::Instant::now());
+            let mut publishes = vec![(coulomb_ah_topic(&topic_prefix), format!("{:.4}", counter.integrated_ah))];
+            if let Some(soc) = counter.soc_percent(capacity_ah) {
+                publishes.push((coulomb_soc_topic(&topic_prefix), soc.to_string()));
+            }
+            for (topic, payload) in publishes {
+                if let Err(e) = client.publish(topic, QoS::AtLeastOnce, true, payload).await {
+                    error!("发布库仑计失败: {:?}", e);
+                }
+            }
+        }
+
+        if last_persist.elapsed() >= settings.persist_interval {
+            last_persist = tokio::time::Instant::now();
+            if let Err(e) = counter.persist(&settings.state_path) {
+                error!("保存库仑计状态失败: {:?}", e);
+            }
+        }
+    }
+    if let Err(e) = counter.persist(&settings.state_path) {
+        error!("保存库仑计状态失败: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAPACITY_AH: f64 = 2.0;
+    const MAX_GAP: Duration = Duration::from_secs(10);
+    const MINUTE_MS: u64 = 60_000;
+
+    /// 每秒一帧，按 `current_a` 积分 `minutes` 分钟，从 `ts` 开始，返回结束时间
+    fn run(counter: &mut CoulombCounter, ts: u64, minutes: u64, current_a: f32) -> u64 {
+        let end = ts + minutes * MINUTE_MS;
+        for t in (ts..=end).step_by(1000) {
+            counter.record(t, current_a, CAPACITY_AH, MAX_GAP);
+        }
+        end
+    }
+
+    #[test]
+    fn a_cycle_with_drift_is_pulled_back_by_calibration() {
+        let mut counter = CoulombCounter::default();
+        counter.seed(Some(50.0), CAPACITY_AH);
+        counter.seed(Some(80.0), CAPACITY_AH);
+        assert_eq!(counter.soc_percent(CAPACITY_AH), Some(50.0));
+
+        // 检流偏置使读数比实际放电少 0.1 A：1 小时 1 A 放电只记到 0.9 Ah
+        let drift = 0.1;
+        let ts = run(&mut counter, 0, 30, -1.0 + drift);
+        assert!((counter.integrated_ah + 0.45).abs() < 1e-3, "{}", counter.integrated_ah);
+        assert_eq!(counter.soc_percent(CAPACITY_AH), Some(27.5));
+
+        // 充电器在积分值到顶之前报告充满
+        let charging = ChargerStatusFlags::STAT_AC | ChargerStatusFlags::IN_FCHRG;
+        assert_eq!(counter.calibrate(charging, SystemStatus::empty(), CAPACITY_AH), None);
+        let ts = run(&mut counter, ts + 1000, 30, 1.5 + drift);
+        assert_eq!(counter.soc_percent(CAPACITY_AH), Some(67.5));
+        assert_eq!(counter.calibrate(charging, SystemStatus::empty(), CAPACITY_AH), None);
+        // 充电器退出充电状态且仍接着适配器：校准到 100%
+        assert_eq!(counter.calibrate(ChargerStatusFlags::STAT_AC, SystemStatus::empty(), CAPACITY_AH), Some(Calibration::ChargeDone));
+        assert_eq!(counter.soc_percent(CAPACITY_AH), Some(100.0));
+        let integrated = counter.integrated_ah;
+
+        // 放电到欠压保护：不论积分值多少都校准到 0%
+        let ts = run(&mut counter, ts + 1000, 60, -1.5 + drift);
+        assert!(counter.remaining_ah.unwrap() > 0.5);
+        assert_eq!(counter.calibrate(ChargerStatusFlags::empty(), SystemStatus::UV, CAPACITY_AH), Some(Calibration::Undervoltage));
+        assert_eq!(counter.soc_percent(CAPACITY_AH), Some(0.0));
+        // 校准只修正剩余电量，原始积分不变
+        assert!(counter.integrated_ah < integrated);
+        run(&mut counter, ts + 1000, 1, -1.0);
+        assert_eq!(counter.remaining_ah, Some(0.0));
+    }
+
+    #[test]
+    fn unplugging_while_charging_is_not_charge_done() {
+        let mut counter = CoulombCounter { remaining_ah: Some(1.0), ..CoulombCounter::default() };
+        counter.calibrate(ChargerStatusFlags::STAT_AC | ChargerStatusFlags::IN_PCHRG, SystemStatus::empty(), CAPACITY_AH);
+        assert_eq!(counter.calibrate(ChargerStatusFlags::empty(), SystemStatus::empty(), CAPACITY_AH), None);
+        assert_eq!(counter.remaining_ah, Some(1.0));
+        // 未经充电状态直接报告 STAT_AC 也不算充满
+        assert_eq!(counter.calibrate(ChargerStatusFlags::STAT_AC, SystemStatus::empty(), CAPACITY_AH), None);
+    }
+
+    #[test]
+    fn gaps_are_capped_and_clock_jumps_skipped() {
+        let mut counter = CoulombCounter { remaining_ah: Some(1.0), ..CoulombCounter::default() };
+        counter.record(0, -3.6, CAPACITY_AH, MAX_GAP);
+        // 一小时没有样本，只按 max_gap 计入
+        counter.record(3_600_000, -3.6, CAPACITY_AH, MAX_GAP);
+        assert!((counter.integrated_ah + 0.01).abs() < 1e-9, "{}", counter.integrated_ah);
+        // 时钟回拨的区间不计入，之后从新的时间继续
+        counter.record(1_000_000, -3.6, CAPACITY_AH, MAX_GAP);
+        counter.record(1_001_000, -3.6, CAPACITY_AH, MAX_GAP);
+        assert!((counter.integrated_ah + 0.011).abs() < 1e-9, "{}", counter.integrated_ah);
+        assert!((counter.remaining_ah.unwrap() - 0.989).abs() < 1e-9);
+    }
+
+    #[test]
+    fn state_survives_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("coulomb.json");
+        assert_eq!(CoulombCounter::load(&path).unwrap(), None);
+
+        let mut counter = CoulombCounter::default();
+        counter.seed(Some(60.0), CAPACITY_AH);
+        run(&mut counter, 0, 6, -2.0);
+        counter.persist(&path).unwrap();
+        let loaded = CoulombCounter::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.integrated_ah, counter.integrated_ah);
+        assert_eq!(loaded.remaining_ah, counter.remaining_ah);
+        assert_eq!(loaded.soc_percent(CAPACITY_AH), Some(50.0));
+
+        // 重启后的第一帧只作为积分起点，不会把停机时间计入
+        let mut resumed = loaded.clone();
+        resumed.record(10 * 3_600_000, -2.0, CAPACITY_AH, MAX_GAP);
+        assert_eq!(resumed.remaining_ah, loaded.remaining_ah);
+
+        fs::write(&path, "not json").unwrap();
+        assert_eq!(CoulombCounter::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
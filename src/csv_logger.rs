@@ -0,0 +1,455 @@
+//! CSV 数据记录：每帧一行，按天（UTC）或按大小滚动文件。
+//!
+//! 列名为 `fields::flatten_sample` 的扁平键，`ts_unix_ms`、`frame_id` 固定在最前，其余按展开顺序排列。
+//! 列集合在新建文件时由第一帧确定并写入表头，同一文件内不会变化；之后才出现的字段要到
+//! 下一个文件才会成为新列。文件在运行中被删除时，下一次落盘检查会重新创建文件并写入表头。
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tracing::{error, info, warn};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::data_models::TimestampedMeasurements;
+use crate::fields::{self, FieldValue};
+use crate::pipeline::Pipeline;
+use crate::utils::unix_ms_now;
+
+const FILE_PREFIX: &str = "ups120-";
+const FILE_SUFFIX: &str = ".csv";
+const MS_PER_DAY: u64 = 86_400_000;
+
+/// 固定在最前的列
+const LEADING_COLUMNS: [&str; 2] = ["ts_unix_ms", "frame_id"];
+
+/// CSV 记录的配置
+#[derive(Debug, Clone)]
+pub struct CsvLogSettings {
+    pub dir: PathBuf,
+    /// 单个文件的最大字节数，超过后滚动
+    pub max_file_bytes: u64,
+    /// 保留的文件数（含当前文件），更早的文件被删除
+    pub max_files: usize,
+    /// UTC 日期变化时滚动
+    pub rotate_daily: bool,
+    /// 两次 flush 之间的间隔
+    pub flush_interval: Duration,
+}
+
+struct ActiveFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    columns: Vec<String>,
+    /// 已写入的字节数（含表头与尚在缓冲区中的）
+    bytes: u64,
+    /// 文件中第一行数据的 UTC 日序号
+    day: u64,
+}
+
+/// CSV 记录器，由 `csv_log_task` 驱动
+pub struct CsvLogger {
+    settings: CsvLogSettings,
+    active: Option<ActiveFile>,
+    row: String,
+    /// 最近创建的文件的 (毫秒, 序号)；新文件必须排在它之后
+    last_file: Option<(u64, u64)>,
+}
+
+impl CsvLogger {
+    pub fn new(settings: CsvLogSettings) -> io::Result<Self> {
+        fs::create_dir_all(&settings.dir)?;
+        Ok(CsvLogger {
+            settings,
+            active: None,
+            row: String::with_capacity(1024),
+            last_file: None,
+        })
+    }
+
+    /// 写入一行。写入失败时丢弃当前文件句柄，下一行写入新文件
+    pub fn write(&mut self, sample: &TimestampedMeasurements) -> io::Result<()> {
+        let result = self.append(sample);
+        if result.is_err() {
+            self.active = None;
+        }
+        result
+    }
+
+    fn append(&mut self, sample: &TimestampedMeasurements) -> io::Result<()> {
+        let values = fields::flatten_sample(sample);
+        let day = sample.ts_unix_ms / MS_PER_DAY;
+        let needs_rotation = match &self.active {
+            Some(active) => {
+                active.bytes >= self.settings.max_file_bytes || (self.settings.rotate_daily && day != active.day)
+            }
+            None => true,
+        };
+        if needs_rotation {
+            self.rotate(&values, day)?;
+        }
+        let active = self.active.as_mut().expect("active file opened by rotate");
+
+        let by_key: HashMap<&str, &FieldValue> = values.iter().map(|(key, value)| (key.as_str(), value)).collect();
+        self.row.clear();
+        for (i, column) in active.columns.iter().enumerate() {
+            if i > 0 {
+                self.row.push(',');
+            }
+            if let Some(value) = by_key.get(column.as_str()) {
+                push_cell(&mut self.row, value);
+            }
+        }
+        self.row.push('\n');
+        active.writer.write_all(self.row.as_bytes())?;
+        active.bytes += self.row.len() as u64;
+        Ok(())
+    }
+
+    /// flush 缓冲区；当前文件已被删除时关闭它，下一行写入重新创建的文件
+    pub fn flush(&mut self) -> io::Result<()> {
+        let Some(active) = &mut self.active else {
+            return Ok(());
+        };
+        if !active.path.exists() {
+            warn!("CSV 文件 {} 已被删除，重新创建", active.path.display());
+            self.active = None;
+            return Ok(());
+        }
+        active.writer.flush()
+    }
+
+    pub fn close(&mut self) -> io::Result<()> {
+        match self.active.take() {
+            Some(mut active) => active.writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn rotate(&mut self, values: &[(String, FieldValue)], day: u64) -> io::Result<()> {
+        if let Some(active) = &self.active {
+            info!("CSV 文件 {} 切换新文件 ({} 字节)", active.path.display(), active.bytes);
+        }
+        self.close()?;
+
+        let (ms, mut suffix) = next_file(self.last_file, unix_ms_now());
+        let mut path = file_path(&self.settings.dir, ms, suffix);
+        while path.exists() {
+            suffix += 1;
+            path = file_path(&self.settings.dir, ms, suffix);
+        }
+        let file = OpenOptions::new().create_new(true).append(true).open(&path)?;
+        self.last_file = Some((ms, suffix));
+        let mut writer = BufWriter::new(file);
+
+        let columns = columns(values);
+        let mut header = String::new();
+        for (i, column) in columns.iter().enumerate() {
+            if i > 0 {
+                header.push(',');
+            }
+            push_text(&mut header, column);
+        }
+        header.push('\n');
+        writer.write_all(header.as_bytes())?;
+        self.active = Some(ActiveFile {
+            path,
+            writer,
+            columns,
+            bytes: header.len() as u64,
+            day,
+        });
+
+        if let Err(e) = prune(&self.settings.dir, self.settings.max_files) {
+            warn!("清理旧 CSV 文件失败: {:?}", e);
+        }
+        Ok(())
+    }
+}
+
+/// 列顺序：`LEADING_COLUMNS`，然后是其余扁平键的展开顺序
+pub fn columns(values: &[(String, FieldValue)]) -> Vec<String> {
+    let mut columns: Vec<String> = LEADING_COLUMNS.iter().map(|column| column.to_string()).collect();
+    columns.extend(
+        values
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| !LEADING_COLUMNS.contains(&key.as_str()))
+            .cloned(),
+    );
+    columns
+}
+
+// 数值按 `fields::format_number` 输出，非有限值为空；布尔值为 true / false
+fn push_cell(row: &mut String, value: &FieldValue) {
+    match value {
+        FieldValue::Number(n) if n.is_finite() => row.push_str(&fields::format_number(*n)),
+        FieldValue::Number(_) => {}
+        FieldValue::Bool(b) => row.push_str(if *b { "true" } else { "false" }),
+        FieldValue::Text(s) => push_text(row, s),
+    }
+}
+
+// 含逗号、引号或换行的文本加引号，引号加倍 (RFC 4180)
+fn push_text(row: &mut String, text: &str) {
+    if text.contains([',', '"', '\n', '\r']) {
+        row.push('"');
+        row.push_str(&text.replace('"', "\"\""));
+        row.push('"');
+    } else {
+        row.push_str(text);
+    }
+}
+
+// 文件名 `ups120-<毫秒>[-<序号>].csv` 中的 (毫秒, 序号)；不是记录文件时为 None
+// 新文件的 (毫秒, 序号)，必须排在上一个文件之后，否则会被 prune 当作最旧的文件删掉。
+// 同一毫秒内连续滚动或时钟回拨时沿用上一个文件的毫秒数并递增序号
+fn next_file(last: Option<(u64, u64)>, now: u64) -> (u64, u64) {
+    match last {
+        Some((last_ms, last_suffix)) if now <= last_ms => (last_ms, last_suffix + 1),
+        _ => (now, 0),
+    }
+}
+
+fn file_path(dir: &Path, ms: u64, suffix: u64) -> PathBuf {
+    match suffix {
+        0 => dir.join(format!("{}{}{}", FILE_PREFIX, ms, FILE_SUFFIX)),
+        _ => dir.join(format!("{}{}-{}{}", FILE_PREFIX, ms, suffix, FILE_SUFFIX)),
+    }
+}
+
+fn file_order(path: &Path) -> Option<(u64, u64)> {
+    let name = path.file_name()?.to_str()?;
+    let stem = name.strip_prefix(FILE_PREFIX)?.strip_suffix(FILE_SUFFIX)?;
+    let (ms, suffix) = stem.split_once('-').unwrap_or((stem, "0"));
+    Some((ms.parse().ok()?, suffix.parse().ok()?))
+}
+
+// 按文件名中的时间戳与序号排序，只保留最新的 `keep` 个文件。不能直接比较文件名：
+// 同一毫秒内的 `-1` 后缀会排在不带后缀的文件之前
+fn prune(dir: &Path, keep: usize) -> io::Result<()> {
+    let mut files: Vec<((u64, u64), PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| Some((file_order(&entry.path())?, entry.path())))
+        .collect();
+    if files.len() <= keep {
+        return Ok(());
+    }
+    files.sort();
+    for (_, path) in &files[..files.len() - keep] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// 订阅测量数据并写入 CSV 文件，收到退出信号后关闭文件
+pub async fn csv_log_task(pipeline: Pipeline, mut logger: CsvLogger, shutdown: CancellationToken) {
+    let mut samples = pipeline.subscribe();
+    let mut flush_timer = tokio::time::interval(logger.settings.flush_interval);
+    flush_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            sample = samples.recv() => match sample {
+                Ok(sample) => {
+                    if let Err(e) = logger.write(&sample) {
+                        error!("写入 CSV 记录失败: {:?}", e);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("CSV 记录写入过慢，跳过 {} 帧", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = flush_timer.tick() => {
+                if let Err(e) = logger.flush() {
+                    error!("CSV 记录落盘失败: {:?}", e);
+                }
+            }
+        }
+    }
+    if let Err(e) = logger.close() {
+        error!("关闭 CSV 文件失败: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::PayloadBuilder;
+
+    fn settings(dir: &Path) -> CsvLogSettings {
+        CsvLogSettings {
+            dir: dir.to_path_buf(),
+            max_file_bytes: 1 << 20,
+            max_files: 3,
+            rotate_daily: true,
+            flush_interval: Duration::from_secs(1),
+        }
+    }
+
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<_> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        files.sort_by_key(|path| file_order(path));
+        files
+    }
+
+    fn sample(frame_id: u64, ts_unix_ms: u64) -> TimestampedMeasurements {
+        PayloadBuilder::new().sample(frame_id, ts_unix_ms)
+    }
+
+    // 列顺序变化会破坏下游的表格，修改前请确认是有意为之
+    #[test]
+    fn column_order_snapshot() {
+        let columns = columns(&fields::flatten_sample(&sample(1, 1_700_000_000_000)));
+        assert_eq!(
+            columns,
+            [
+                "ts_unix_ms",
+                "frame_id",
+                "bq25730.cmpin",
+                "bq25730.ichg",
+                "bq25730.idchg",
+                "bq25730.iin",
+                "bq25730.psys",
+                "bq25730.vbat",
+                "bq25730.vbus",
+                "bq25730.vsys",
+                "bq25730_alerts.charger_fault_flags",
+                "bq25730_alerts.charger_status_flags",
+                "bq25730_alerts.prochot_lsb_flags",
+                "bq25730_alerts.prochot_msb_flags",
+                "bq25730_alerts.prochot_width",
+                "bq76920.cell_count",
+                "bq76920.cell_voltages.0",
+                "bq76920.cell_voltages.1",
+                "bq76920.cell_voltages.2",
+                "bq76920.cell_voltages.3",
+                "bq76920.cell_voltages.4",
+                "bq76920.coulomb_counter",
+                "bq76920.mos_status",
+                "bq76920.system_status",
+                "bq76920.temperatures.is_thermistor",
+                "bq76920.temperatures.ts1",
+                "bq76920_alerts.system_status",
+                "ina226.current",
+                "ina226.power",
+                "ina226.voltage",
+                "derived.cell_delta",
+                "derived.cell_max",
+                "derived.cell_min",
+                "derived.pack_voltage",
+            ]
+        );
+    }
+
+    #[test]
+    fn rows_follow_the_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut logger = CsvLogger::new(settings(dir.path())).unwrap();
+        for frame_id in 1..=3 {
+            logger.write(&sample(frame_id, 1_700_000_000_000 + frame_id)).unwrap();
+        }
+        logger.close().unwrap();
+        let [path] = &files(dir.path())[..] else {
+            panic!("应只有一个文件");
+        };
+        let text = fs::read_to_string(path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("ts_unix_ms,frame_id,bq25730.cmpin,"));
+        let header: Vec<&str> = lines[0].split(',').collect();
+        let row: Vec<&str> = lines[2].split(',').collect();
+        assert_eq!(row.len(), header.len());
+        let cell = |name: &str| row[header.iter().position(|column| *column == name).unwrap()];
+        assert_eq!((cell("ts_unix_ms"), cell("frame_id")), ("1700000000002", "2"));
+        assert_eq!(cell("bq25730.vbat"), "18.5");
+        assert_eq!(cell("bq76920.cell_voltages.0"), "3.7");
+        assert_eq!(cell("bq76920.mos_status"), "BothOn");
+        assert_eq!(cell("bq76920.temperatures.is_thermistor"), "true");
+    }
+
+    #[test]
+    fn files_rotate_by_size_and_day_and_old_ones_are_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut logger = CsvLogger::new(CsvLogSettings { max_file_bytes: 1, rotate_daily: false, ..settings(dir.path()) }).unwrap();
+        for frame_id in 1..=5 {
+            logger.write(&sample(frame_id, 1_700_000_000_000)).unwrap();
+        }
+        logger.close().unwrap();
+        let kept = files(dir.path());
+        assert_eq!(kept.len(), 3);
+        // 保留的是最新的文件，每个文件只有表头与一行
+        let last = fs::read_to_string(kept.last().unwrap()).unwrap();
+        assert_eq!(last.lines().count(), 2);
+        assert!(last.lines().nth(1).unwrap().starts_with("1700000000000,5,"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut logger = CsvLogger::new(settings(dir.path())).unwrap();
+        logger.write(&sample(1, 3 * MS_PER_DAY - 1)).unwrap();
+        logger.write(&sample(2, 3 * MS_PER_DAY - 1)).unwrap();
+        logger.write(&sample(3, 3 * MS_PER_DAY)).unwrap();
+        logger.close().unwrap();
+        let lines: Vec<usize> = files(dir.path()).iter().map(|p| fs::read_to_string(p).unwrap().lines().count()).collect();
+        assert_eq!(lines, [3, 2]);
+    }
+
+    #[test]
+    fn a_deleted_file_is_recreated_with_a_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut logger = CsvLogger::new(settings(dir.path())).unwrap();
+        logger.write(&sample(1, 1_700_000_000_000)).unwrap();
+        logger.flush().unwrap();
+        let [path] = &files(dir.path())[..] else {
+            panic!("应只有一个文件");
+        };
+        fs::remove_file(path).unwrap();
+        logger.flush().unwrap();
+        logger.write(&sample(2, 1_700_000_000_001)).unwrap();
+        logger.close().unwrap();
+        let [path] = &files(dir.path())[..] else {
+            panic!("应重新创建一个文件");
+        };
+        let text = fs::read_to_string(path).unwrap();
+        assert!(text.starts_with("ts_unix_ms,frame_id,"));
+        assert_eq!(text.lines().count(), 2);
+    }
+
+    #[test]
+    fn files_in_the_same_millisecond_sort_by_sequence() {
+        let order = |name: &str| file_order(Path::new(name));
+        assert!(order("ups120-1700000000000.csv") < order("ups120-1700000000000-1.csv"));
+        assert!(order("ups120-1700000000000-9.csv") < order("ups120-1700000000000-10.csv"));
+        assert!(order("ups120-1700000000000-10.csv") < order("ups120-1700000000001.csv"));
+        assert_eq!(order("notes.csv"), None);
+        assert_eq!(order("ups120-latest.csv"), None);
+    }
+
+    #[test]
+    fn new_files_sort_after_the_previous_one() {
+        assert_eq!(next_file(None, 1000), (1000, 0));
+        assert_eq!(next_file(Some((1000, 0)), 1000), (1000, 1));
+        // 时钟回拨
+        assert_eq!(next_file(Some((1000, 3)), 999), (1000, 4));
+        assert_eq!(next_file(Some((1000, 3)), 1001), (1001, 0));
+        let dir = Path::new("/tmp");
+        assert_eq!(file_order(&file_path(dir, 1000, 0)), Some((1000, 0)));
+        assert_eq!(file_order(&file_path(dir, 1000, 4)), Some((1000, 4)));
+    }
+
+    #[test]
+    fn text_is_quoted_only_when_needed() {
+        let mut row = String::new();
+        push_text(&mut row, "BothOn");
+        row.push(',');
+        push_text(&mut row, r#"a,"b""#);
+        row.push(',');
+        push_cell(&mut row, &FieldValue::Number(f64::NAN));
+        row.push(',');
+        push_cell(&mut row, &FieldValue::Number(12.3f32 as f64));
+        assert_eq!(row, r#"BothOn,"a,""b""",,12.3"#);
+    }
+}
@@ -1,5 +1,7 @@
+use std::sync::Arc;
+
 use serde::{Serialize, Deserialize};
-use serde::ser::{SerializeSeq, SerializeStruct};
+use serde::ser::SerializeStruct;
 use bitflags::bitflags;
 
 // BQ25730 测量数据 (简化，只包含需要序列化的字段)
@@ -18,7 +20,7 @@ pub struct Bq25730Measurements {
 bitflags! {
     /// BQ76920 系统状态寄存器 (SysStat, 0x00)
     /// 固件中读取并解析此寄存器，然后通过 USB 发送给上位机。
-    #[derive(Serialize, Deserialize)] // Default will be implemented manually
+    #[derive(Serialize)] // Default will be implemented manually
     #[serde(transparent)]
     pub struct SystemStatus: u8 {
         const OCD = 0b0000_0001;     // 过流放电 (对应固件 Bit 0)
@@ -39,7 +41,7 @@ impl Default for SystemStatus {
 
 /// MOS 管状态 (SysCtrl2, 0x05)
 /// 固件中读取并解析此寄存器，然后通过 USB 发送给上位机。
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum MosStatus {
     ChargeOn,
     DischargeOn,
@@ -48,40 +50,204 @@ pub enum MosStatus {
     Unknown, // 用于处理意外情况
 }
 
+impl MosStatus {
+    /// 线上格式与 `mos_status_raw` 主题使用的数值：bit0 为充电管，bit1 为放电管；`Unknown` 为 0xFF
+    pub fn as_bits(&self) -> u8 {
+        match self {
+            MosStatus::BothOff => 0b00,
+            MosStatus::ChargeOn => 0b01,
+            MosStatus::DischargeOn => 0b10,
+            MosStatus::BothOn => 0b11,
+            MosStatus::Unknown => 0xFF,
+        }
+    }
+
+    /// 0..=3 以外的数值均为 `Unknown`
+    pub fn from_bits(bits: u8) -> Self {
+        match bits {
+            0b00 => MosStatus::BothOff,
+            0b01 => MosStatus::ChargeOn,
+            0b10 => MosStatus::DischargeOn,
+            0b11 => MosStatus::BothOn,
+            _ => MosStatus::Unknown,
+        }
+    }
+}
+
 // BQ76920 测量数据 (简化，只包含需要序列化的字段)
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+// 序列化时 cell_voltages 只包含前 cell_count 节，见下方手写的 Serialize
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
 pub struct Bq76920Measurements<const N: usize> {
-    #[serde(serialize_with = "serialize_voltages", deserialize_with = "deserialize_voltages")]
+    #[serde(deserialize_with = "deserialize_voltages")]
     pub cell_voltages: [f32; N], // 修正为原始类型
-    #[serde(serialize_with = "serialize_temperatures", deserialize_with = "deserialize_temperatures")]
+    /// 实际串联的电芯数，其余通道为 0 且不发布
+    #[serde(default = "default_cell_count")]
+    pub cell_count: usize,
+    #[serde(deserialize_with = "deserialize_temperatures")]
     pub temperatures: Temperatures,
     pub coulomb_counter: f32, // 修改为 f32
     pub system_status: SystemStatus, // 新增字段
     pub mos_status: MosStatus,       // 新增字段
 }
 
+fn default_cell_count() -> usize {
+    5
+}
+
+impl<const N: usize> Bq76920Measurements<N> {
+    /// 实际串联电芯的电压
+    pub fn cells(&self) -> &[f32] {
+        &self.cell_voltages[..self.cell_count.min(N)]
+    }
+}
+
+impl<const N: usize> Serialize for Bq76920Measurements<N> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        struct Temps<'a>(&'a Temperatures);
+        impl Serialize for Temps<'_> {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serialize_temperatures(self.0, serializer)
+            }
+        }
+
+        let mut state = serializer.serialize_struct("Bq76920Measurements", 6)?;
+        state.serialize_field("cell_voltages", self.cells())?;
+        state.serialize_field("cell_count", &self.cell_count)?;
+        state.serialize_field("temperatures", &Temps(&self.temperatures))?;
+        state.serialize_field("coulomb_counter", &self.coulomb_counter)?;
+        state.serialize_field("system_status", &self.system_status)?;
+        state.serialize_field("mos_status", &self.mos_status)?;
+        state.end()
+    }
+}
+
+/// 派生指标：由单体电压得出的电池包总压与单体极值 (V)，以及直流通路的转换效率
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DerivedMetrics {
+    pub pack_voltage: f32,
+    pub cell_min: f32,
+    pub cell_max: f32,
+    /// 最高与最低单体电压之差
+    pub cell_delta: f32,
+    /// INA226 输出功率相对充电器输入功率的百分比，见 `AllMeasurements::efficiency_percent`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub efficiency_percent: Option<f32>,
+}
+
+impl<const N: usize> Bq76920Measurements<N> {
+    /// 计算前 `cell_count` 节电芯的总压与单体极值；没有电芯时各项均为 0
+    pub fn derived(&self) -> DerivedMetrics {
+        let mut cells = self.cells().iter().copied().peekable();
+        if cells.peek().is_none() {
+            return DerivedMetrics::default();
+        }
+        let (pack_voltage, cell_min, cell_max) = cells.fold((0.0f32, f32::MAX, f32::MIN), |(sum, min, max), v| {
+            (sum + v, min.min(v), max.max(v))
+        });
+        DerivedMetrics {
+            pack_voltage,
+            cell_min,
+            cell_max,
+            cell_delta: cell_max - cell_min,
+            efficiency_percent: None,
+        }
+    }
+}
+
 // Temperatures 结构体 (简化)
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Temperatures {
-    #[serde(serialize_with = "serialize_thermodynamic_temperature")]
-    pub ts1: f32,
+    /// TS1 读数无效（短路、开路或超出 ADC 量程）时为 None，发布为 null
+    pub ts1: Option<f32>,
     pub ts2: Option<f32>,
     pub ts3: Option<f32>,
     pub is_thermistor: bool,
 }
 
 // AllMeasurements 聚合所有设备的测量数据
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct AllMeasurements<const N: usize> {
     pub bq25730: Bq25730Measurements,
     pub bq76920: Bq76920Measurements<N>,
-    pub ina226: Ina226Measurements,
+    /// v1 固件的载荷不带 INA226 读数，此时为 None，不发布
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ina226: Option<Ina226Measurements>,
     pub bq25730_alerts: Bq25730Alerts,
     pub bq76920_alerts: Bq76920Alerts,
 }
 
-// INA226测量结构体 (already exists)
+// 效率的上限：两侧测量误差可能使效率略高于 100%，超出该值的读数视为无效并截断
+const MAX_EFFICIENCY_PERCENT: f32 = 110.0;
+
+impl<const N: usize> AllMeasurements<N> {
+    /// INA226 输出功率相对 BQ25730 输入功率 (vbus × iin) 的百分比，限制在 0–110%。
+    /// 输入功率不超过 `min_input_w`（接近 0 时比值无意义）、读数无效或没有 INA226 读数时返回 None
+    pub fn efficiency_percent(&self, min_input_w: f32) -> Option<f32> {
+        let input_w = self.bq25730.vbus * self.bq25730.iin;
+        if !input_w.is_finite() || input_w <= min_input_w.max(f32::EPSILON) {
+            return None;
+        }
+        let efficiency = self.ina226.as_ref()?.power / input_w * 100.0;
+        efficiency.is_finite().then(|| efficiency.clamp(0.0, MAX_EFFICIENCY_PERCENT))
+    }
+}
+
+/// 带接收时间戳的测量数据，作为 JSON 载荷发布。不派生 Copy：样本在各任务间以 `Arc` 共享
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimestampedMeasurements {
+    /// 单调递增的帧号，跨重启不重复，用于关联日志、MQTT 消息与异常记录
+    pub frame_id: u64,
+    pub ts_unix_ms: u64,
+    pub data: AllMeasurements<5>,
+    /// 由单体电压派生的电池包指标
+    #[serde(default)]
+    pub derived: DerivedMetrics,
+    /// 由守护进程根据测量值估算的电池指标
+    #[serde(default)]
+    pub battery: BatteryMetrics,
+    /// 收到的原始帧 (含 magic)，供突发抓取保存；不发布也不写入记录
+    #[serde(skip)]
+    pub raw: Option<Arc<[u8]>>,
+}
+
+/// 守护进程估算的电池指标，不来自设备载荷
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct BatteryMetrics {
+    /// 电量百分比 (0-100)，尚无有效单体电压时为 None
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub soc_percent: Option<f32>,
+    /// 放电时的剩余时间 (分钟)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime_min: Option<f32>,
+    /// 充电时的充满时间 (分钟)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_to_full_min: Option<f32>,
+}
+
+/// 设备标识与固件版本，设备连接时以 retained 发布到 `{prefix}/device/info`。
+/// 字符串描述符读取失败或为空时对应字段为 None
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    /// 守护进程使用的设备标识（序列号或 `bus-address`）
+    pub device_id: String,
+    /// 实际匹配到的 VID/PID，如 `1209:0002`
+    pub usb_id: String,
+    pub bus: u8,
+    pub address: u8,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    /// 握手协商得到的载荷协议版本
+    pub protocol_version: u16,
+    /// 固件版本 `a.b.c.d`；旧固件不支持版本查询时为 None
+    pub firmware_version: Option<String>,
+}
+
+// INA226测量结构体 (already exists)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Ina226Measurements {
     pub voltage: f32,
     pub current: f32,
@@ -89,7 +255,7 @@ pub struct Ina226Measurements {
 }
 
 bitflags! {
-    #[derive(Serialize, Deserialize, Default)]
+    #[derive(Serialize, Default)]
     #[serde(transparent)]
     pub struct ChargerStatusFlags: u8 {
         const STAT_AC         = 0b10000000; // Input source status: 1 = AC adapter
@@ -104,7 +270,7 @@ bitflags! {
 }
 
 bitflags! {
-    #[derive(Serialize, Deserialize, Default)]
+    #[derive(Serialize, Default)]
     #[serde(transparent)]
     pub struct ChargerFaultFlags: u8 {
         const FAULT_ACOV      = 0b10000000; // ACOV fault
@@ -119,7 +285,7 @@ bitflags! {
 }
 
 bitflags! {
-    #[derive(Serialize, Deserialize, Default)]
+    #[derive(Serialize, Default)]
     #[serde(transparent)]
     pub struct ProchotLsbFlags: u8 { // Corresponds to PROCHOT_STATUS_LSB (0x22 in firmware)
             const STAT_VINDPM       = 1 << 7;
@@ -134,11 +300,11 @@ bitflags! {
 }
 
 bitflags! {
-    #[derive(Serialize, Deserialize, Default)]
+    #[derive(Serialize, Default)]
     #[serde(transparent)]
     pub struct ProchotMsbFlags: u8 { // Corresponds to PROCHOT_STATUS_MSB (0x23 in firmware)
             const EN_PROCHOT_EXT  = 1 << 6;
-            // PROCHOT_WIDTH (bits 5:4 of original MSB) is handled as a separate field 'prochot_width'
+            // PROCHOT_WIDTH (bits 5:4 of original MSB) is handled as a separate field 'prochot_width', see conversion::unpack_prochot
             const PROCHOT_CLEAR   = 1 << 3;
             // Bit 2 is reserved in firmware
             const STAT_VAP_FAIL   = 1 << 1;
@@ -152,7 +318,7 @@ pub struct Bq25730Alerts {
     pub charger_fault_flags: ChargerFaultFlags,
     pub prochot_lsb_flags: ProchotLsbFlags,
     pub prochot_msb_flags: ProchotMsbFlags,
-    pub prochot_width: u8, // Extracted from PROCHOT_STATUS_MSB bits 6:5
+    pub prochot_width: u8, // Extracted from PROCHOT_STATUS_MSB bits 5:4
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, Default)]
@@ -160,6 +326,165 @@ pub struct Bq76920Alerts {
     pub system_status: SystemStatus, // Uses the existing SystemStatus bitflag
 }
 
+/// 建议与告警的严重程度
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+/// 保护/故障标志对应的说明与处理建议，面向非专业用户
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Advisory {
+    /// 标志名，如 `bq76920.SCD`
+    pub flag: &'static str,
+    pub severity: Severity,
+    pub explanation: &'static str,
+    pub action: &'static str,
+}
+
+/// 建议表的键：某个 bitflags 类型中的一位
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlagBit {
+    SystemStatus(SystemStatus),
+    ChargerFault(ChargerFaultFlags),
+}
+
+impl FlagBit {
+    pub fn is_set<const N: usize>(&self, data: &AllMeasurements<N>) -> bool {
+        match self {
+            FlagBit::SystemStatus(flag) => {
+                (data.bq76920.system_status | data.bq76920_alerts.system_status).contains(*flag)
+            }
+            FlagBit::ChargerFault(flag) => data.bq25730_alerts.charger_fault_flags.contains(*flag),
+        }
+    }
+
+    /// 在测量数据中置位该标志（保护标志同时置位状态寄存器与告警寄存器）
+    pub fn set<const N: usize>(&self, data: &mut AllMeasurements<N>) {
+        match self {
+            FlagBit::SystemStatus(flag) => {
+                data.bq76920.system_status |= *flag;
+                data.bq76920_alerts.system_status |= *flag;
+            }
+            FlagBit::ChargerFault(flag) => data.bq25730_alerts.charger_fault_flags |= *flag,
+        }
+    }
+
+    /// 按名称查找建议表中的标志，如 `SCD`、`bq76920.SCD`、`SYSOVP`、`FAULT_SYSOVP`
+    pub fn from_name(name: &str) -> Option<(FlagBit, &'static str)> {
+        let name = name.trim().to_ascii_uppercase();
+        ADVISORY_TABLE.iter().find_map(|(bit, advisory)| {
+            let short = advisory.flag.rsplit('.').next().unwrap_or(advisory.flag);
+            let matches = advisory.flag.eq_ignore_ascii_case(&name)
+                || short == name
+                || short.strip_prefix("FAULT_") == Some(name.as_str());
+            matches.then_some((*bit, advisory.flag))
+        })
+    }
+}
+
+/// 所有保护/故障标志的建议表。`SystemStatus::CC_READY` 是普通状态位，不在表中。
+pub static ADVISORY_TABLE: &[(FlagBit, Advisory)] = &[
+    (FlagBit::SystemStatus(SystemStatus::SCD), Advisory {
+        flag: "bq76920.SCD",
+        severity: Severity::Critical,
+        explanation: "放电回路短路，保护芯片已切断放电",
+        action: "立即断开负载并检查输出线路是否短路；排除故障后通过复位或重新接入充电器恢复",
+    }),
+    (FlagBit::SystemStatus(SystemStatus::OCD), Advisory {
+        flag: "bq76920.OCD",
+        severity: Severity::Critical,
+        explanation: "放电电流过大，保护芯片已切断放电",
+        action: "减少所接负载，确认负载功率在 UPS 额定范围内后再恢复供电",
+    }),
+    (FlagBit::SystemStatus(SystemStatus::OV), Advisory {
+        flag: "bq76920.OV",
+        severity: Severity::Critical,
+        explanation: "有电芯电压过高，充电已被切断",
+        action: "停止充电并检查充电器设置与各节电芯电压；电芯持续不均衡时需要均衡或更换",
+    }),
+    (FlagBit::SystemStatus(SystemStatus::UV), Advisory {
+        flag: "bq76920.UV",
+        severity: Severity::Warning,
+        explanation: "有电芯电压过低，放电已被切断",
+        action: "尽快接入电源给电池充电；长期欠压会损伤电芯",
+    }),
+    (FlagBit::SystemStatus(SystemStatus::OVRD_ALERT), Advisory {
+        flag: "bq76920.OVRD_ALERT",
+        severity: Severity::Warning,
+        explanation: "ALERT 引脚被外部拉高，保护芯片进入告警状态",
+        action: "检查外部保护电路或二级保护器件；无外部保护时可能是硬件故障，请联系维护人员",
+    }),
+    (FlagBit::SystemStatus(SystemStatus::DEVICE_XREADY), Advisory {
+        flag: "bq76920.DEVICE_XREADY",
+        severity: Severity::Critical,
+        explanation: "电池保护芯片内部故障，可能未正常工作",
+        action: "断电后重新上电；反复出现时停止使用并联系维护人员",
+    }),
+    (FlagBit::ChargerFault(ChargerFaultFlags::FAULT_ACOV), Advisory {
+        flag: "bq25730.FAULT_ACOV",
+        severity: Severity::Critical,
+        explanation: "输入电源电压过高",
+        action: "立即断开输入电源，确认所用电源适配器的输出电压符合要求",
+    }),
+    (FlagBit::ChargerFault(ChargerFaultFlags::FAULT_BATOC), Advisory {
+        flag: "bq25730.FAULT_BATOC",
+        severity: Severity::Critical,
+        explanation: "电池放电电流过大",
+        action: "减少所接负载，检查电池与输出线路是否有短路",
+    }),
+    (FlagBit::ChargerFault(ChargerFaultFlags::FAULT_ACOC), Advisory {
+        flag: "bq25730.FAULT_ACOC",
+        severity: Severity::Warning,
+        explanation: "输入电流过大",
+        action: "减少负载或更换功率更大的电源适配器",
+    }),
+    (FlagBit::ChargerFault(ChargerFaultFlags::FAULT_SYSOVP), Advisory {
+        flag: "bq25730.FAULT_SYSOVP",
+        severity: Severity::Critical,
+        explanation: "系统输出电压过高，充电器已停止工作",
+        action: "断开负载与输入电源后重新上电；反复出现时联系维护人员",
+    }),
+    (FlagBit::ChargerFault(ChargerFaultFlags::FAULT_VSYS_UVP), Advisory {
+        flag: "bq25730.FAULT_VSYS_UVP",
+        severity: Severity::Warning,
+        explanation: "系统输出电压过低，可能因负载过重或电池电量不足",
+        action: "减少负载并给电池充电",
+    }),
+    (FlagBit::ChargerFault(ChargerFaultFlags::FAULT_CONV_OFF), Advisory {
+        flag: "bq25730.FAULT_CONV_OFF",
+        severity: Severity::Warning,
+        explanation: "充电器转换器被强制关闭",
+        action: "检查输入电源是否稳定；持续出现时重新上电",
+    }),
+    (FlagBit::ChargerFault(ChargerFaultFlags::FAULT_OTG_OVP), Advisory {
+        flag: "bq25730.FAULT_OTG_OVP",
+        severity: Severity::Warning,
+        explanation: "反向供电 (OTG) 输出电压过高",
+        action: "断开反向供电的负载，检查 OTG 输出设置",
+    }),
+    (FlagBit::ChargerFault(ChargerFaultFlags::FAULT_OTG_UVP), Advisory {
+        flag: "bq25730.FAULT_OTG_UVP",
+        severity: Severity::Warning,
+        explanation: "反向供电 (OTG) 输出电压过低，可能负载过重",
+        action: "减少反向供电的负载",
+    }),
+];
+
+/// 当前置位的标志对应的建议，严重程度高的在前
+pub fn active_advisories<const N: usize>(data: &AllMeasurements<N>) -> Vec<Advisory> {
+    let mut active: Vec<Advisory> = ADVISORY_TABLE
+        .iter()
+        .filter(|(bit, _)| bit.is_set(data))
+        .map(|(_, advisory)| *advisory)
+        .collect();
+    active.sort_by_key(|a| std::cmp::Reverse(a.severity));
+    active
+}
+
 // 为 ElectricPotential 实现自定义序列化
 #[allow(dead_code)] // 添加此行
 fn serialize_electric_potential<S>(
@@ -172,32 +497,6 @@ where
     serializer.serialize_f32(*value)
 }
 
-// 为 ThermodynamicTemperature 实现自定义序列化
-fn serialize_thermodynamic_temperature<S>(
-    value: &f32, // 修正为原始类型
-    serializer: S,
-) -> Result<S::Ok, S::Error>
-where
-    S: serde::Serializer,
-{
-    serializer.serialize_f32(*value)
-}
-
-// 为 [f32] 实现自定义序列化
-fn serialize_voltages<S, const N: usize>(
-    voltages: &[f32; N], // 修正为原始类型
-    serializer: S,
-) -> Result<S::Ok, S::Error>
-where
-    S: serde::Serializer,
-{
-    let mut seq = serializer.serialize_seq(Some(N))?;
-    for voltage in voltages {
-        seq.serialize_element(voltage)?; // 直接序列化
-    }
-    seq.end()
-}
-
 // 为 Temperatures 实现自定义序列化
 fn serialize_temperatures<S>(temperatures: &Temperatures, serializer: S) -> Result<S::Ok, S::Error>
 where
@@ -226,16 +525,23 @@ where
         type Value = [f32; N];
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            write!(formatter, "an array of size {}", N)
+            write!(formatter, "an array of at most {} voltages", N)
         }
 
         fn visit_seq<A>(self, mut seq: A) -> Result<[f32; N], A::Error>
         where
             A: SeqAccess<'de>,
         {
-            let mut arr = [0.0; N]; // 默认值
-            for i in 0..N {
-                arr[i] = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(i, &self))?;
+            // 只序列化了实际串联的电芯，缺少的通道补 0
+            let mut arr = [0.0; N];
+            for slot in arr.iter_mut() {
+                match seq.next_element()? {
+                    Some(voltage) => *slot = voltage,
+                    None => break,
+                }
+            }
+            if seq.next_element::<f32>()?.is_some() {
+                return Err(de::Error::invalid_length(N + 1, &self));
             }
             Ok(arr)
         }
@@ -251,10 +557,6 @@ fn deserialize_temperatures<'de, D>(
 where
     D: de::Deserializer<'de>,
 {
-    #[derive(Deserialize)]
-    #[serde(field_identifier, rename_all = "lowercase")]
-    enum Field { Ts1, Ts2, Ts3, IsThermistor }
-
     struct TemperaturesVisitor;
 
     impl<'de> Visitor<'de> for TemperaturesVisitor {
@@ -321,9 +623,9 @@ where
     )
 }
 // Payload structure for USB communication, mirroring device-side AllMeasurementsUsbPayload
-// This will be used by binrw to parse the raw USB byte stream.
-#[derive(Debug, Clone, Copy, binrw::BinRead, binrw::BinWrite)] // Added BinWrite
-#[brw(big)] // Default to Big Endian to match firmware's write_be
+// 解析按载荷长度区分 v1 / v2 布局，见 binrw_impls 模块；写入始终使用 v2 布局
+#[derive(Debug, Clone, Copy, binrw::BinWrite)]
+#[bw(big)] // Default to Big Endian to match firmware's write_be
 pub struct HostSideUsbPayload {
     // Fields from Bq25730Measurements -> AdcMeasurements
     // These are raw u16 values as sent by firmware, matching names in device's AllMeasurementsUsbPayload
@@ -374,4 +676,187 @@ pub struct HostSideUsbPayload {
 
     // Fields from Bq76920Alerts
     pub bq76920_alerts_system_status_bits: u8,
-}
\ No newline at end of file
+
+    // 新固件附带的帧序号，旧固件没有该字段
+    pub sequence: Option<u16>,
+
+    /// 载荷是否带 INA226 读数（v1 固件没有，对应字段为 0），不在线上编码
+    #[bw(ignore)]
+    pub ina226_present: bool,
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::PayloadBuilder;
+
+    fn has_entry(bit: FlagBit) -> bool {
+        ADVISORY_TABLE.iter().any(|(entry, _)| *entry == bit)
+    }
+
+    #[test]
+    fn every_protection_and_fault_bit_has_advice() {
+        for shift in 0..u8::BITS {
+            if let Some(flag) = SystemStatus::from_bits(1 << shift)
+                && flag != SystemStatus::CC_READY
+            {
+                assert!(has_entry(FlagBit::SystemStatus(flag)), "SystemStatus {:?} 缺少建议", flag);
+            }
+            if let Some(flag) = ChargerFaultFlags::from_bits(1 << shift) {
+                assert!(has_entry(FlagBit::ChargerFault(flag)), "ChargerFaultFlags {:?} 缺少建议", flag);
+            }
+        }
+        assert!(!has_entry(FlagBit::SystemStatus(SystemStatus::CC_READY)));
+        assert_eq!(ADVISORY_TABLE.len(), 6 + 8);
+    }
+
+    #[test]
+    fn names_resolve_to_table_entries() {
+        assert_eq!(FlagBit::from_name("scd"), Some((FlagBit::SystemStatus(SystemStatus::SCD), "bq76920.SCD")));
+        assert_eq!(
+            FlagBit::from_name("SYSOVP"),
+            Some((FlagBit::ChargerFault(ChargerFaultFlags::FAULT_SYSOVP), "bq25730.FAULT_SYSOVP"))
+        );
+        assert_eq!(FlagBit::from_name("bq25730.fault_acoc").map(|(_, flag)| flag), Some("bq25730.FAULT_ACOC"));
+        assert_eq!(FlagBit::from_name("CC_READY"), None);
+    }
+
+    #[test]
+    fn composite_fault_advisories_render_most_severe_first() {
+        assert!(active_advisories(&PayloadBuilder::new().measurements()).is_empty());
+
+        let data = PayloadBuilder::new()
+            .system_status(SystemStatus::UV | SystemStatus::SCD | SystemStatus::CC_READY)
+            .charger_faults(ChargerFaultFlags::FAULT_ACOC)
+            .measurements();
+        let rendered = serde_json::to_string_pretty(&active_advisories(&data)).unwrap();
+        assert_eq!(
+            rendered,
+            r#"[
+  {
+    "flag": "bq76920.SCD",
+    "severity": "critical",
+    "explanation": "放电回路短路，保护芯片已切断放电",
+    "action": "立即断开负载并检查输出线路是否短路；排除故障后通过复位或重新接入充电器恢复"
+  },
+  {
+    "flag": "bq76920.UV",
+    "severity": "warning",
+    "explanation": "有电芯电压过低，放电已被切断",
+    "action": "尽快接入电源给电池充电；长期欠压会损伤电芯"
+  },
+  {
+    "flag": "bq25730.FAULT_ACOC",
+    "severity": "warning",
+    "explanation": "输入电流过大",
+    "action": "减少负载或更换功率更大的电源适配器"
+  }
+]"#
+        );
+    }
+
+    fn pack<const N: usize>(cell_voltages: [f32; N], cell_count: usize) -> Bq76920Measurements<N> {
+        let base = PayloadBuilder::new().measurements().bq76920;
+        Bq76920Measurements {
+            cell_voltages,
+            cell_count,
+            temperatures: base.temperatures,
+            coulomb_counter: 0.0,
+            system_status: base.system_status,
+            mos_status: base.mos_status,
+        }
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn derived_metrics_cover_a_five_cell_pack() {
+        let derived = pack([3.61, 3.65, 3.60, 3.70, 3.64], 5).derived();
+        assert!(close(derived.pack_voltage, 18.20));
+        assert_eq!((derived.cell_min, derived.cell_max), (3.60, 3.70));
+        assert!(close(derived.cell_delta, 0.10));
+        assert_eq!(derived.efficiency_percent, None);
+    }
+
+    #[test]
+    fn derived_metrics_use_only_the_connected_cells() {
+        // 3S 电池包接在 5 通道的芯片上，未用的通道读数为 0
+        let derived = pack([3.70, 3.72, 3.68, 0.0, 0.0], 3).derived();
+        assert!(close(derived.pack_voltage, 11.10));
+        assert_eq!((derived.cell_min, derived.cell_max), (3.68, 3.72));
+
+        // N < 5 的泛型实例
+        let derived = pack([3.50, 3.55, 3.45], 3).derived();
+        assert!(close(derived.pack_voltage, 10.50));
+        assert!(close(derived.cell_delta, 0.10));
+        let single = pack([3.90], 1).derived();
+        assert_eq!((single.pack_voltage, single.cell_min, single.cell_max, single.cell_delta), (3.90, 3.90, 3.90, 0.0));
+        // cell_count 超过 N 时按 N 计
+        assert!(close(pack([3.50, 3.50], 5).derived().pack_voltage, 7.0));
+    }
+
+    #[test]
+    fn a_pack_without_cells_derives_zeroes() {
+        assert_eq!(pack([3.7; 4], 0).derived(), DerivedMetrics::default());
+        assert_eq!(pack::<0>([], 5).derived(), DerivedMetrics::default());
+    }
+
+    #[test]
+    fn mos_status_bits_round_trip() {
+        let variants = [MosStatus::BothOff, MosStatus::ChargeOn, MosStatus::DischargeOn, MosStatus::BothOn, MosStatus::Unknown];
+        for status in variants {
+            let copy = status;
+            assert_eq!(MosStatus::from_bits(status.as_bits()), copy);
+        }
+        assert_eq!(variants.map(|s| s.as_bits()), [0, 1, 2, 3, 0xFF]);
+        for bits in [0x04, 0x80, 0xFE] {
+            assert_eq!(MosStatus::from_bits(bits), MosStatus::Unknown);
+        }
+    }
+
+    // 输入 vbus × iin，输出为 INA226 的功率
+    fn dc_path(vbus: f32, iin: f32, output_w: Option<f32>) -> AllMeasurements<5> {
+        let mut data = PayloadBuilder::new().measurements();
+        data.bq25730.vbus = vbus;
+        data.bq25730.iin = iin;
+        data.ina226 = output_w.map(|power| Ina226Measurements { voltage: 12.0, current: power / 12.0, power });
+        data
+    }
+
+    #[test]
+    fn efficiency_compares_output_to_charger_input_power() {
+        let efficiency = dc_path(20.0, 1.0, Some(18.0)).efficiency_percent(2.0).unwrap();
+        assert!(close(efficiency, 90.0), "{}", efficiency);
+        // 测量误差略超 100% 时保留，超过 110% 截断；输出功率为负时为 0
+        assert!(close(dc_path(20.0, 1.0, Some(20.4)).efficiency_percent(2.0).unwrap(), 102.0));
+        assert_eq!(dc_path(20.0, 1.0, Some(40.0)).efficiency_percent(2.0), Some(MAX_EFFICIENCY_PERCENT));
+        assert_eq!(dc_path(20.0, 1.0, Some(-1.0)).efficiency_percent(2.0), Some(0.0));
+    }
+
+    #[test]
+    fn efficiency_needs_enough_input_power_and_an_ina226() {
+        // 输入功率不超过下限
+        assert_eq!(dc_path(20.0, 0.05, Some(0.9)).efficiency_percent(2.0), None);
+        assert_eq!(dc_path(20.0, 0.1, Some(1.8)).efficiency_percent(2.0), None);
+        assert!(dc_path(20.0, 0.11, Some(1.8)).efficiency_percent(2.0).is_some());
+        // 下限为 0 时仍不除以 0，适配器断开时没有输入功率
+        assert_eq!(dc_path(0.0, 0.0, Some(10.0)).efficiency_percent(0.0), None);
+        assert_eq!(dc_path(20.0, -1.0, Some(10.0)).efficiency_percent(0.0), None);
+        // 无效读数与旧固件（没有 INA226）
+        assert_eq!(dc_path(f32::NAN, 1.0, Some(10.0)).efficiency_percent(2.0), None);
+        assert_eq!(dc_path(f32::INFINITY, 1.0, Some(10.0)).efficiency_percent(2.0), None);
+        assert_eq!(dc_path(20.0, 1.0, Some(f32::NAN)).efficiency_percent(2.0), None);
+        assert_eq!(dc_path(20.0, 1.0, None).efficiency_percent(2.0), None);
+    }
+
+    #[test]
+    fn efficiency_is_omitted_from_the_json_when_unknown() {
+        let mut derived = pack([3.7; 4], 4).derived();
+        assert!(serde_json::to_value(derived).unwrap().get("efficiency_percent").is_none());
+        derived.efficiency_percent = Some(91.5);
+        let json = serde_json::to_value(derived).unwrap();
+        assert_eq!(json["efficiency_percent"], 91.5);
+        assert_eq!(serde_json::from_value::<DerivedMetrics>(json).unwrap(), derived);
+    }
+}
@@ -0,0 +1,511 @@
+//! JSONL 数据记录：每帧测量数据一行，按大小滚动文件。
+//!
+//! 长期运行（2 Hz、数月）时的约束：
+//! - 每条记录先用 `serde_json::to_writer` 序列化进一个复用的 `Vec<u8>`，再追加到复用的待写缓冲区，
+//!   缓冲区达到峰值容量后稳态下每条记录 0 次堆分配；只有滚动文件时才会分配（路径、文件句柄）。
+//! - 待写缓冲区只含完整的记录，攒满 `WRITE_BUFFER_SIZE` 或按 `DATA_LOG_FSYNC` 落盘时一次写入文件。
+//!   写入失败时把文件截回最后一条完整记录的末尾，待写的记录留在缓冲区中下次重试，
+//!   因此文件中不会留下半条记录，记录也不会因一次写入失败而丢失。
+//! - 滚动在写入前判断：完整序列化后的记录放不下当前文件时先切换文件，
+//!   因此一条记录不会被拆到两个文件中。
+//! - 文件读写与 fsync 都是阻塞调用，`data_log_task` 把它们放到 `spawn_blocking` 中执行。
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use tracing::{error, info, warn};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::data_models::TimestampedMeasurements;
+use crate::pipeline::Pipeline;
+use crate::stats::DaemonStats;
+use crate::utils::unix_ms_now;
+
+const FILE_PREFIX: &str = "ups120-";
+const FILE_SUFFIX: &str = ".jsonl";
+
+// 待写缓冲区攒到该大小后写入文件；约为几十条记录
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+// 文件持续无法写入时待写缓冲区的上限，超过后丢弃新记录
+const MAX_PENDING_BYTES: usize = 64 * WRITE_BUFFER_SIZE;
+
+/// 数据落盘策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// 只在滚动与退出时 flush，由操作系统决定何时落盘
+    Never,
+    /// 按 `DATA_LOG_FSYNC_INTERVAL_SECS` 周期 flush + fsync
+    Interval,
+    /// 每条记录都 flush + fsync，最安全也最慢
+    EveryWrite,
+}
+
+/// 数据记录器的配置
+#[derive(Debug, Clone)]
+pub struct DataLogSettings {
+    pub dir: PathBuf,
+    /// 单个文件的最大字节数，超过后滚动
+    pub max_file_bytes: u64,
+    /// 保留的文件数（含当前文件），更早的文件被删除
+    pub max_files: usize,
+    pub fsync: FsyncPolicy,
+    pub fsync_interval: Duration,
+}
+
+struct ActiveFile {
+    path: PathBuf,
+    file: File,
+    /// 已写入文件的完整记录字节数，也是写入失败时截断的位置
+    bytes: u64,
+}
+
+/// JSONL 数据记录器，写入路径全部同步完成，由 `data_log_task` 在阻塞线程池中驱动
+pub struct DataLogger {
+    settings: DataLogSettings,
+    active: Option<ActiveFile>,
+    record: Vec<u8>,
+    /// 尚未写入文件的完整记录
+    pending: Vec<u8>,
+    /// 最近创建的文件的 (毫秒, 序号)；新文件必须排在它之后
+    last_file: Option<(u64, u32)>,
+    last_sync: Instant,
+    stats: Arc<DaemonStats>,
+}
+
+impl DataLogger {
+    pub fn new(settings: DataLogSettings, stats: Arc<DaemonStats>) -> io::Result<Self> {
+        fs::create_dir_all(&settings.dir)?;
+        Ok(DataLogger {
+            settings,
+            active: None,
+            record: Vec::with_capacity(1024),
+            pending: Vec::with_capacity(WRITE_BUFFER_SIZE),
+            last_file: None,
+            last_sync: Instant::now(),
+            stats,
+        })
+    }
+
+    /// 写入一条记录。写入失败时记录留在待写缓冲区，随下一次写入或落盘重试；
+    /// 只有缓冲区超过上限（文件长时间无法写入）时才丢弃记录。
+    pub fn write(&mut self, sample: &TimestampedMeasurements) -> io::Result<()> {
+        let started = Instant::now();
+        self.record.clear();
+        serde_json::to_writer(&mut self.record, sample).map_err(io::Error::other)?;
+        self.record.push(b'\n');
+
+        let result = self.append();
+        if result.is_err() {
+            self.stats.data_log_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.stats
+            .data_log_write_latency_us
+            .store(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        result
+    }
+
+    fn append(&mut self) -> io::Result<()> {
+        if self.pending.len() + self.record.len() > MAX_PENDING_BYTES {
+            // 先把积压的记录写入文件（没有打开的文件时先打开新文件），仍写不进时才丢弃该记录
+            let drained = match self.active {
+                Some(_) => self.flush_pending(),
+                None => self.rotate().and_then(|()| self.flush_pending()),
+            };
+            if drained.is_err() {
+                return Err(io::Error::other("数据记录文件持续无法写入，待写缓冲区已满，丢弃该记录"));
+            }
+        }
+        let len = (self.pending.len() + self.record.len()) as u64;
+        let needs_rotation = match &self.active {
+            Some(active) => active.bytes > 0 && active.bytes + len > self.settings.max_file_bytes,
+            None => true,
+        };
+        // 切换失败时记录仍进入待写缓冲区，之后写入旧文件或下一次成功打开的文件
+        let rotated = if needs_rotation { self.rotate() } else { Ok(()) };
+        self.pending.extend_from_slice(&self.record);
+        rotated?;
+
+        if self.settings.fsync == FsyncPolicy::EveryWrite {
+            self.sync()?;
+        } else if self.pending.len() >= WRITE_BUFFER_SIZE {
+            self.flush_pending()?;
+        }
+        Ok(())
+    }
+
+    // 把待写的完整记录写入当前文件；失败时截回写入前的长度，记录留待重试
+    fn flush_pending(&mut self) -> io::Result<()> {
+        let Some(active) = &mut self.active else {
+            return Ok(());
+        };
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        if let Err(e) = active.file.write_all(&self.pending) {
+            if let Err(truncate_err) = active.file.set_len(active.bytes) {
+                // 无法去掉半条记录时不再追加到该文件，下一条记录写入新文件
+                error!("截断数据记录文件 {} 失败: {:?}，改写新文件", active.path.display(), truncate_err);
+                self.active = None;
+            }
+            return Err(e);
+        }
+        let len = self.pending.len() as u64;
+        active.bytes += len;
+        self.stats.data_log_bytes_written.fetch_add(len, Ordering::Relaxed);
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// 周期落盘；仅 `Interval` 策略下到期时生效
+    pub fn sync_if_due(&mut self) -> io::Result<()> {
+        if self.settings.fsync == FsyncPolicy::Interval && self.last_sync.elapsed() >= self.settings.fsync_interval {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// 写入待写的记录并 fsync 当前文件
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.last_sync = Instant::now();
+        self.flush_pending()?;
+        if let Some(active) = &self.active {
+            active.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// 关闭当前文件（写入待写的记录，`Never` 以外的策略还会 fsync）；写入失败时保留文件与记录
+    pub fn close(&mut self) -> io::Result<()> {
+        self.flush_pending()?;
+        let Some(active) = self.active.take() else {
+            return Ok(());
+        };
+        if self.settings.fsync != FsyncPolicy::Never {
+            active.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(active) = &self.active {
+            info!("数据记录文件 {} 已满 ({} 字节)，切换新文件", active.path.display(), active.bytes);
+        }
+        self.close()?;
+
+        let (ms, mut suffix) = next_file(self.last_file, unix_ms_now());
+        let mut path = file_path(&self.settings.dir, ms, suffix);
+        while path.exists() {
+            suffix += 1;
+            path = file_path(&self.settings.dir, ms, suffix);
+        }
+        let file = OpenOptions::new().create_new(true).append(true).open(&path)?;
+        self.last_file = Some((ms, suffix));
+        self.active = Some(ActiveFile { path, file, bytes: 0 });
+        self.last_sync = Instant::now();
+
+        if let Err(e) = prune(&self.settings.dir, self.settings.max_files) {
+            warn!("清理旧数据记录文件失败: {:?}", e);
+        }
+        Ok(())
+    }
+}
+
+// 新文件的 (毫秒, 序号)，必须排在上一个文件之后，否则会被 prune 当作最旧的文件删掉。
+// 同一毫秒内连续滚动或时钟回拨时沿用上一个文件的毫秒数并递增序号
+fn next_file(last: Option<(u64, u32)>, now: u64) -> (u64, u32) {
+    match last {
+        Some((last_ms, last_suffix)) if now <= last_ms => (last_ms, last_suffix + 1),
+        _ => (now, 0),
+    }
+}
+
+fn file_path(dir: &Path, ms: u64, suffix: u32) -> PathBuf {
+    match suffix {
+        0 => dir.join(format!("{}{}{}", FILE_PREFIX, ms, FILE_SUFFIX)),
+        _ => dir.join(format!("{}{}-{}{}", FILE_PREFIX, ms, suffix, FILE_SUFFIX)),
+    }
+}
+
+// 文件名 `ups120-<unix_ms>[-<n>].jsonl` 的先后顺序；不是记录文件时为 None
+fn file_order(name: &str) -> Option<(u64, u32)> {
+    let stem = name.strip_prefix(FILE_PREFIX)?.strip_suffix(FILE_SUFFIX)?;
+    let (ms, suffix) = match stem.split_once('-') {
+        Some((ms, suffix)) => (ms, suffix.parse().ok()?),
+        None => (stem, 0),
+    };
+    Some((ms.parse().ok()?, suffix))
+}
+
+// 按文件名中的时间戳与序号排序（字符串顺序在时间戳位数变化或同一毫秒滚动时不对），只保留最新的 `keep` 个文件
+fn prune(dir: &Path, keep: usize) -> io::Result<()> {
+    let mut files: Vec<((u64, u32), PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let order = file_order(entry.file_name().to_str()?)?;
+            Some((order, entry.path()))
+        })
+        .collect();
+    if files.len() <= keep {
+        return Ok(());
+    }
+    files.sort();
+    for (_, path) in &files[..files.len() - keep] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// 订阅测量数据并写入 JSONL 文件，收到退出信号后关闭文件。写入与落盘在阻塞线程池中执行，
+/// 期间到达的样本留在广播通道中
+pub async fn data_log_task(pipeline: Pipeline, mut logger: DataLogger, shutdown: CancellationToken) {
+    let mut samples = pipeline.subscribe();
+    let mut sync_timer = tokio::time::interval(logger.settings.fsync_interval);
+    sync_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            sample = samples.recv() => match sample {
+                Ok(sample) => {
+                    let written = run_blocking(logger, move |logger| {
+                        if let Err(e) = logger.write(&sample) {
+                            error!("写入数据记录失败: {:?}", e);
+                        }
+                    });
+                    let Some(returned) = written.await else { return };
+                    logger = returned;
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("数据记录写入过慢，跳过 {} 帧", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = sync_timer.tick() => {
+                let synced = run_blocking(logger, |logger| {
+                    if let Err(e) = logger.sync_if_due() {
+                        error!("数据记录落盘失败: {:?}", e);
+                    }
+                });
+                let Some(returned) = synced.await else { return };
+                logger = returned;
+            }
+        }
+    }
+    run_blocking(logger, |logger| {
+        if let Err(e) = logger.close() {
+            error!("关闭数据记录文件失败: {:?}", e);
+        }
+    })
+    .await;
+}
+
+// 在阻塞线程池中操作记录器，完成后交还；线程 panic 时记录器随之丢失，返回 None 结束记录
+async fn run_blocking<F>(mut logger: DataLogger, f: F) -> Option<DataLogger>
+where
+    F: FnOnce(&mut DataLogger) + Send + 'static,
+{
+    match tokio::task::spawn_blocking(move || {
+        f(&mut logger);
+        logger
+    })
+    .await
+    {
+        Ok(logger) => Some(logger),
+        Err(e) => {
+            error!("数据记录线程异常结束，停止记录: {:?}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::PayloadBuilder;
+
+    fn settings(dir: &Path, max_file_bytes: u64, max_files: usize) -> DataLogSettings {
+        DataLogSettings {
+            dir: dir.to_path_buf(),
+            max_file_bytes,
+            max_files,
+            fsync: FsyncPolicy::Never,
+            fsync_interval: Duration::from_secs(10),
+        }
+    }
+
+    fn logger(settings: DataLogSettings) -> DataLogger {
+        DataLogger::new(settings, Arc::new(DaemonStats::default())).unwrap()
+    }
+
+    // 按文件先后顺序读出全部记录的帧号；每一行都必须是完整的 JSON
+    fn frame_ids(dir: &Path) -> Vec<Vec<u64>> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .filter_map(|entry| Some((file_order(entry.file_name().to_str()?)?, entry.path())))
+            .collect();
+        files.sort();
+        files
+            .iter()
+            .map(|(_, path)| {
+                let text = fs::read_to_string(path).unwrap();
+                assert!(text.is_empty() || text.ends_with('\n'), "{} 末尾有半条记录", path.display());
+                text.lines()
+                    .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["frame_id"].as_u64().unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn file_order_parses_timestamp_and_suffix() {
+        assert_eq!(file_order("ups120-1700000000000.jsonl"), Some((1_700_000_000_000, 0)));
+        assert_eq!(file_order("ups120-1700000000000-12.jsonl"), Some((1_700_000_000_000, 12)));
+        assert_eq!(file_order("ups120-latest.jsonl"), None);
+        assert_eq!(file_order("other-1.jsonl"), None);
+    }
+
+    #[test]
+    fn new_files_sort_after_the_previous_one() {
+        assert_eq!(next_file(None, 1000), (1000, 0));
+        assert_eq!(next_file(Some((1000, 0)), 1000), (1000, 1));
+        // 时钟回拨
+        assert_eq!(next_file(Some((1000, 3)), 999), (1000, 4));
+        assert_eq!(next_file(Some((1000, 3)), 1001), (1001, 0));
+    }
+
+    #[test]
+    fn rapid_rotations_keep_the_newest_files() {
+        let dir = tempfile::tempdir().unwrap();
+        // 每条记录都落盘并切换文件，大部分滚动落在同一毫秒内
+        let mut logger = logger(DataLogSettings { fsync: FsyncPolicy::EveryWrite, ..settings(dir.path(), 1, 3) });
+        let mut sample = PayloadBuilder::new().sample(0, 1_700_000_000_000);
+        for frame_id in 0..20 {
+            sample.frame_id = frame_id;
+            logger.write(&sample).unwrap();
+        }
+        logger.close().unwrap();
+        assert_eq!(frame_ids(dir.path()), [[17], [18], [19]]);
+    }
+
+    #[test]
+    fn prune_keeps_the_newest_files_by_timestamp_and_suffix() {
+        let dir = tempfile::tempdir().unwrap();
+        // 字符串顺序下 999 排在 1000 之后，-10 排在 -2 之前，`-1` 又排在无序号的文件之前
+        let names = ["ups120-999.jsonl", "ups120-1000.jsonl", "ups120-1000-2.jsonl", "ups120-1000-10.jsonl", "notes.txt"];
+        for name in names {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        prune(dir.path(), 2).unwrap();
+        let mut left: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["notes.txt", "ups120-1000-10.jsonl", "ups120-1000-2.jsonl"]);
+    }
+
+    #[test]
+    fn soak_100k_records_across_rotations_keeps_every_line_whole() {
+        const RECORDS: u64 = 100_000;
+        let dir = tempfile::tempdir().unwrap();
+        let mut logger = logger(settings(dir.path(), 256 * 1024, 4));
+        let builder = PayloadBuilder::new();
+        let mut sample = builder.sample(0, 1_700_000_000_000);
+        for frame_id in 0..RECORDS {
+            sample.frame_id = frame_id;
+            logger.write(&sample).unwrap();
+        }
+        logger.close().unwrap();
+
+        // 保留的文件首尾相接，帧号连续到最后一条，每个文件都不超过上限
+        let files = frame_ids(dir.path());
+        assert_eq!(files.len(), 4);
+        let kept: Vec<u64> = files.concat();
+        let first = kept[0];
+        assert_eq!(kept, (first..RECORDS).collect::<Vec<_>>());
+        for entry in fs::read_dir(dir.path()).unwrap() {
+            assert!(entry.unwrap().metadata().unwrap().len() <= 256 * 1024);
+        }
+        assert_eq!(logger.stats.data_log_errors.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn records_survive_a_failed_write_and_are_retried() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("log");
+        // 每条记录都切换文件：目录消失后打开新文件失败
+        let mut logger = logger(DataLogSettings { fsync: FsyncPolicy::EveryWrite, ..settings(&dir, 1, 100) });
+        let mut sample = PayloadBuilder::new().sample(0, 1_700_000_000_000);
+        logger.write(&sample).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        sample.frame_id = 1;
+        assert!(logger.write(&sample).is_err());
+
+        fs::create_dir_all(&dir).unwrap();
+        sample.frame_id = 2;
+        logger.write(&sample).unwrap();
+        logger.close().unwrap();
+        assert_eq!(frame_ids(&dir).concat(), [1, 2]);
+        assert_eq!(logger.stats.data_log_errors.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn pending_records_are_capped_while_no_file_can_be_opened() {
+        const WRITES: u64 = 10_000;
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("log");
+        let mut logger = logger(settings(&dir, u64::MAX, 2));
+        fs::remove_dir_all(&dir).unwrap();
+        let mut sample = PayloadBuilder::new().sample(0, 1_700_000_000_000);
+        for frame_id in 0..WRITES {
+            sample.frame_id = frame_id;
+            assert!(logger.write(&sample).is_err());
+        }
+
+        // 恢复后先写入缓冲区中最早的记录，超过上限的记录被丢弃
+        fs::create_dir_all(&dir).unwrap();
+        sample.frame_id = WRITES;
+        logger.write(&sample).unwrap();
+        logger.close().unwrap();
+        let mut kept = frame_ids(&dir).concat();
+        assert_eq!(kept.pop(), Some(WRITES));
+        assert!(!kept.is_empty() && (kept.len() as u64) < WRITES, "kept {} records", kept.len());
+        assert_eq!(kept, (0..kept.len() as u64).collect::<Vec<_>>());
+        let bytes: u64 = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().metadata().unwrap().len()).sum();
+        assert!(bytes <= (MAX_PENDING_BYTES + 2 * logger.record.len()) as u64);
+        assert_eq!(logger.stats.data_log_errors.load(Ordering::Relaxed), WRITES);
+    }
+
+    #[tokio::test]
+    async fn the_task_writes_published_samples_and_closes_on_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger = logger(DataLogSettings { fsync: FsyncPolicy::EveryWrite, ..settings(dir.path(), u64::MAX, 2) });
+        let pipeline = Pipeline::new(16);
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(data_log_task(pipeline.clone(), logger, shutdown.clone()));
+        // 让任务先订阅总线
+        tokio::task::yield_now().await;
+
+        let builder = PayloadBuilder::new();
+        for frame_id in 0..3 {
+            pipeline.publish(Arc::new(builder.sample(frame_id, 1_700_000_000_000 + frame_id)));
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while frame_ids(dir.path()).concat().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+        assert_eq!(frame_ids(dir.path()).concat(), [0, 1, 2]);
+    }
+}
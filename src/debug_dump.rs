@@ -0,0 +1,95 @@
+//! 运行时切换的原始帧十六进制转储。
+//!
+//! 开关是与 USB 管理任务共享的 `AtomicBool`，由 SIGUSR2 或 `{prefix}/cmd/debug_dump` 命令切换，
+//! 不需要重启。转储写入日志，或以 `UsbEvent::RawFrame` 交给主循环发布到 `{prefix}/debug/raw`。
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+use crate::usb_types::{DeviceId, UsbEvent};
+
+pub fn debug_raw_topic(topic_prefix: &str) -> String {
+    format!("{}/debug/raw", topic_prefix)
+}
+
+/// 转储的输出位置 (`DEBUG_DUMP_TARGET`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DumpTarget {
+    #[default]
+    Log,
+    Mqtt,
+}
+
+/// 转储开关；克隆后共享同一个状态
+#[derive(Debug, Clone, Default)]
+pub struct DebugDump {
+    enabled: Arc<AtomicBool>,
+    target: DumpTarget,
+}
+
+impl DebugDump {
+    pub fn new(enabled: bool, target: DumpTarget) -> Self {
+        DebugDump { enabled: Arc::new(AtomicBool::new(enabled)), target }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// 切换开关，返回切换后的状态
+    pub fn toggle(&self) -> bool {
+        !self.enabled.fetch_xor(true, Ordering::Relaxed)
+    }
+
+    /// 开关打开时转储一帧收到的数据
+    pub async fn dump(&self, device: &DeviceId, frame: &[u8], event_tx: &mpsc::Sender<UsbEvent>) {
+        if !self.is_enabled() {
+            return;
+        }
+        match self.target {
+            DumpTarget::Log => info!(device = %device, len = frame.len(), hex = %hex(frame), "[debug_dump] 收到原始帧"),
+            DumpTarget::Mqtt => {
+                if let Err(e) = event_tx.send(UsbEvent::RawFrame(frame.to_vec())).await {
+                    error!("发送原始帧事件失败: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// 以空格分隔的完整十六进制
+pub fn hex(frame: &[u8]) -> String {
+    frame.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+/// 每收到一次 SIGUSR2 切换一次开关，直到 `shutdown` 取消
+#[cfg(unix)]
+pub async fn signal_toggle_task(dump: DebugDump, shutdown: tokio_util::sync::CancellationToken) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigusr2 = match signal(SignalKind::user_defined2()) {
+        Ok(sigusr2) => sigusr2,
+        Err(e) => {
+            tracing::warn!("注册 SIGUSR2 处理失败: {:?}", e);
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            received = sigusr2.recv() => {
+                if received.is_none() {
+                    break;
+                }
+                let enabled = dump.toggle();
+                info!(enabled, "收到 SIGUSR2，切换原始帧转储");
+            }
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! 缓冲样本经 `TrackedClient` 的投递，主 broker 与各镜像 broker 共用。
+//!
+//! 每个样本先生成全部消息，再逐条用 `try_publish` 交给客户端；队列满时停下，等下次调用接着投递，
+//! 从不阻塞调用方。样本的消息全部收到 PubAck 后才从缓冲区移除，已交出但断线未确认的消息由
+//! rumqttc 重连后重发。
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use rumqttc::ClientError;
+use tracing::error;
+
+use crate::acks::{Receipt, TrackedClient};
+use crate::buffer::PublishBuffer;
+use crate::data_models::TimestampedMeasurements;
+use crate::mqtt_handlers::publish_sample;
+use crate::publisher::{CollectPublisher, OutgoingMessage};
+use crate::topics::TopicMap;
+
+/// 缓冲区中的一个样本及其投递进度
+#[derive(Debug)]
+pub struct PendingSample {
+    pub prefix: String,
+    pub sample: Arc<TimestampedMeasurements>,
+    // 尚未交给客户端的消息；None 表示还未生成
+    unsent: Option<VecDeque<OutgoingMessage>>,
+    receipts: Vec<Receipt>,
+    // 消息生成失败，不会再投递
+    failed: bool,
+}
+
+impl PendingSample {
+    pub fn new(prefix: String, sample: Arc<TimestampedMeasurements>) -> Self {
+        PendingSample { prefix, sample, unsent: None, receipts: Vec::new(), failed: false }
+    }
+
+    fn all_handed_over(&self) -> bool {
+        self.unsent.as_ref().is_some_and(VecDeque::is_empty)
+    }
+}
+
+/// 一次 `drain` 的结果
+#[derive(Debug, Default, PartialEq)]
+pub struct DrainOutcome {
+    /// 开始投递的样本数
+    pub started: u64,
+    /// 全部消息已确认、移出缓冲区的样本数
+    pub delivered: u64,
+    /// 无法生成消息而放弃的样本数
+    pub failed: u64,
+    /// 客户端队列已满，剩余的消息等下次投递
+    pub blocked: bool,
+    /// 客户端已关闭 (事件循环已停止)
+    pub closed: bool,
+}
+
+/// 移除已全部确认的样本，并在 `connected` 时按顺序把后续样本的消息交给客户端
+pub async fn drain(
+    buffer: &mut PublishBuffer<PendingSample>,
+    client: &TrackedClient,
+    topics: &TopicMap,
+    connected: bool,
+) -> DrainOutcome {
+    let mut outcome = DrainOutcome::default();
+    while let Some(pending) = buffer.front()
+        && pending.all_handed_over()
+        && client.acks().all_acked(&pending.receipts)
+    {
+        if !pending.failed {
+            outcome.delivered += 1;
+        }
+        buffer.pop_front();
+    }
+    if !connected {
+        return outcome;
+    }
+    for pending in buffer.iter_mut() {
+        if pending.unsent.is_none() {
+            let collector = CollectPublisher::default();
+            match publish_sample(&collector, &pending.prefix, topics, &pending.sample).await {
+                Ok(()) => outcome.started += 1,
+                Err(e) => {
+                    error!(topic_prefix = %pending.prefix, error = ?e, "生成样本消息失败，放弃该样本");
+                    pending.failed = true;
+                    outcome.failed += 1;
+                }
+            }
+            pending.unsent = Some(if pending.failed { VecDeque::new() } else { collector.into_messages().into() });
+        }
+        let unsent = pending.unsent.as_mut().expect("消息已生成");
+        while let Some(message) = unsent.front() {
+            match client.try_publish(message.topic.clone(), message.qos, message.retain, message.payload.clone()) {
+                Ok(receipt) => {
+                    pending.receipts.push(receipt);
+                    unsent.pop_front();
+                }
+                Err(ClientError::TryRequest(_)) => {
+                    outcome.closed = client.is_closed();
+                    outcome.blocked = !outcome.closed;
+                    return outcome;
+                }
+                Err(e) => {
+                    error!(topic = %message.topic, error = ?e, "消息无法发布，跳过");
+                    unsent.pop_front();
+                }
+            }
+        }
+    }
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rumqttc::{AsyncClient, MqttOptions};
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::acks::{track, AckTracker};
+    use crate::test_broker::TestBroker;
+    use crate::test_support::PayloadBuilder;
+
+    fn pending(frame_id: u64) -> PendingSample {
+        PendingSample::new("ups".to_string(), Arc::new(PayloadBuilder::new().sample(frame_id, 1_700_000_000_000 + frame_id)))
+    }
+
+    fn message_count() -> usize {
+        let collector = CollectPublisher::default();
+        futures::executor::block_on(publish_sample(&collector, "ups", &TopicMap::default(), &pending(0).sample)).unwrap();
+        collector.into_messages().len()
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_returns_instead_of_waiting() {
+        // 事件循环从不运行，队列很快被占满
+        let (inner, _eventloop) = AsyncClient::new(MqttOptions::new("delivery-stalled", "localhost", 1883), 10);
+        let (_client, tracked) = track(inner, Arc::new(AckTracker::default()), 10);
+        let mut buffer = PublishBuffer::new(16);
+        for frame_id in 0..10 {
+            buffer.push(pending(frame_id));
+        }
+        let outcome = tokio::time::timeout(Duration::from_secs(1), drain(&mut buffer, &tracked, &TopicMap::default(), true))
+            .await
+            .expect("队列满时不应阻塞");
+        assert!(outcome.blocked);
+        assert_eq!(outcome.delivered, 0);
+        assert_eq!(buffer.len(), 10);
+        let handed_over: usize = buffer.iter().map(|p| p.receipts.len()).sum();
+        assert!(handed_over < 10 * message_count());
+    }
+
+    #[tokio::test]
+    async fn samples_leave_the_buffer_only_once_acked() {
+        let broker = TestBroker::start().await;
+        let (_client, tracked) = broker.connect_tracked("delivery");
+        let topics = TopicMap::default();
+        let count = message_count();
+        let mut buffer = PublishBuffer::new(4);
+        buffer.push(pending(1));
+        broker.withhold_acks("");
+        let outcome = drain(&mut buffer, &tracked, &topics, true).await;
+        assert_eq!(outcome.started, 1);
+        assert!(broker.wait_for(Duration::from_secs(5), |p| p.len() == count).await);
+        let outcome = drain(&mut buffer, &tracked, &topics, true).await;
+        assert_eq!(outcome.delivered, 0);
+        assert_eq!(buffer.len(), 1);
+
+        // 重连后 rumqttc 重发未确认的消息，确认到齐后才移出
+        broker.resume_acks();
+        broker.drop_connections();
+        let mut changed = tracked.acks().subscribe();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut delivered = 0;
+        while !buffer.is_empty() {
+            assert!(tokio::time::timeout_at(deadline, changed.changed()).await.is_ok(), "样本未被确认");
+            delivered += drain(&mut buffer, &tracked, &topics, true).await.delivered;
+        }
+        assert_eq!(delivered, 1);
+        let publishes = broker.publishes();
+        assert!(publishes.len() >= 2 * count);
+        assert_eq!(publishes.iter().filter(|p| p.topic == "ups/measurements_all").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn disconnected_drains_hand_nothing_over() {
+        let broker = TestBroker::start().await;
+        let (_client, tracked) = broker.connect_tracked("delivery");
+        let mut buffer = PublishBuffer::new(4);
+        buffer.push(pending(1));
+        let outcome = drain(&mut buffer, &tracked, &TopicMap::default(), false).await;
+        assert_eq!(outcome, DrainOutcome::default());
+        assert!(buffer.front().unwrap().receipts.is_empty());
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use tracing::{debug, error, info, warn};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::usb_handlers::{usb_manager_task, UsbManagerSettings};
+use crate::usb_types::{DeviceCommand, DeviceEvent, DeviceId, DeviceSelector, UsbCommand, UsbEvent, UsbId};
+
+// 连续多少次扫描都找不到才视为拔出；设备重置后重新枚举期间会短暂消失
+const DETACH_AFTER_MISSED_SCANS: u32 = 2;
+
+/// 扫描到的一块设备
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredDevice {
+    /// 打开设备或读取序列号失败时为 None，下次扫描再试
+    pub id: Option<DeviceId>,
+    pub usb_id: UsbId,
+    pub bus: u8,
+    pub address: u8,
+}
+
+struct ManagedDevice {
+    cmd_tx: mpsc::Sender<UsbCommand>,
+    task: JoinHandle<()>,
+    missed_scans: u32,
+}
+
+/// 设备注册表：每块设备一个 `usb_manager_task`，按设备标识分发命令
+pub struct DeviceRegistry {
+    settings: UsbManagerSettings,
+    event_tx: mpsc::Sender<DeviceEvent>,
+    devices: BTreeMap<DeviceId, ManagedDevice>,
+}
+
+impl DeviceRegistry {
+    pub fn new(settings: UsbManagerSettings, event_tx: mpsc::Sender<DeviceEvent>) -> Self {
+        DeviceRegistry {
+            settings,
+            event_tx,
+            devices: BTreeMap::new(),
+        }
+    }
+
+    pub fn contains(&self, device: &DeviceId) -> bool {
+        self.devices.contains_key(device)
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// 已在管理中的设备标识
+    pub fn managed(&self) -> Vec<DeviceId> {
+        self.devices.keys().cloned().collect()
+    }
+
+    /// 为新设备启动管理任务；已在管理中的设备忽略
+    pub fn attach(&mut self, device: DeviceId) {
+        if self.devices.contains_key(&device) {
+            return;
+        }
+        info!("发现 USB 设备 {}，启动管理任务", device);
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let task = tokio::spawn(usb_manager_task(
+            device.clone(),
+            self.settings.clone(),
+            cmd_rx,
+            self.event_tx.clone(),
+        ));
+        self.devices.insert(
+            device,
+            ManagedDevice {
+                cmd_tx,
+                task,
+                missed_scans: 0,
+            },
+        );
+    }
+
+    /// 停止设备的管理任务并通知主循环
+    pub async fn detach(&mut self, device: &DeviceId) {
+        let Some(managed) = self.devices.remove(device) else {
+            return;
+        };
+        info!("USB 设备 {} 已移除，停止管理任务", device);
+        managed.task.abort();
+        let event = DeviceEvent {
+            device: device.clone(),
+            event: UsbEvent::Detached,
+        };
+        if let Err(e) = self.event_tx.send(event).await {
+            error!("发送 USB 设备移除事件失败: {:?}", e);
+        }
+    }
+
+    /// 按一次扫描结果增删设备：新设备立即启动，连续多次缺席的设备才移除
+    pub async fn update(&mut self, present: &[DeviceId]) {
+        for device in present {
+            match self.devices.get_mut(device) {
+                Some(managed) => managed.missed_scans = 0,
+                None => self.attach(device.clone()),
+            }
+        }
+        let mut gone = Vec::new();
+        for (device, managed) in self.devices.iter_mut() {
+            if present.contains(device) {
+                continue;
+            }
+            managed.missed_scans += 1;
+            if managed.missed_scans >= DETACH_AFTER_MISSED_SCANS || managed.task.is_finished() {
+                gone.push(device.clone());
+            }
+        }
+        for device in gone {
+            self.detach(&device).await;
+        }
+    }
+
+    /// 把命令发给目标设备；未指定目标时发给所有设备
+    pub async fn dispatch(&self, command: DeviceCommand) {
+        let DeviceCommand { target, command } = command;
+        match target {
+            Some(device) => match self.devices.get(&device) {
+                Some(managed) => {
+                    if let Err(e) = managed.cmd_tx.send(command).await {
+                        error!("转发命令到设备 {} 失败: {:?}", device, e);
+                    }
+                }
+                None => warn!("设备 {} 不在管理中，忽略命令 {:?}", device, command),
+            },
+            None => {
+                for (device, managed) in &self.devices {
+                    if let Err(e) = managed.cmd_tx.send(command.clone()).await {
+                        error!("转发命令到设备 {} 失败: {:?}", device, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 枚举匹配任一候选 VID/PID 与选择条件的设备，按候选的优先级排序。读取序列号需要打开设备；
+/// `known` 缓存了各位置上已识别的设备，已在管理中的设备不会被再次打开
+/// （Windows 上已打开的设备无法被第二次打开）。无法识别的设备 `id` 为 None，由 [`resolve_present`] 决定如何处理。
+pub fn scan_devices(
+    usb_ids: &[UsbId],
+    selector: &DeviceSelector,
+    known: &HashMap<(u8, u8), DeviceId>,
+) -> rusb::Result<Vec<DiscoveredDevice>> {
+    use rusb::UsbContext;
+
+    let context = rusb::Context::new()?;
+    let mut found = Vec::new();
+    for device in context.devices()?.iter() {
+        let Ok(desc) = device.device_descriptor() else {
+            continue;
+        };
+        let usb_id = UsbId {
+            vid: desc.vendor_id(),
+            pid: desc.product_id(),
+        };
+        if !usb_ids.contains(&usb_id) {
+            continue;
+        }
+        let (bus, address) = (device.bus_number(), device.address());
+        let id = match known.get(&(bus, address)) {
+            Some(id) => Some(id.clone()),
+            None => identify(&device, &desc),
+        };
+        let selected = match selector {
+            DeviceSelector::Any => true,
+            DeviceSelector::Serial(serial) => id.as_ref() == Some(&DeviceId::Serial(serial.clone())),
+            DeviceSelector::BusAddr { bus: b, address: a } => (bus, address) == (*b, *a),
+        };
+        if selected {
+            found.push(DiscoveredDevice { id, usb_id, bus, address });
+        }
+    }
+    found.sort_by_key(|d| (usb_ids.iter().position(|id| *id == d.usb_id), d.bus, d.address));
+    Ok(found)
+}
+
+// 打开设备读取标识。没有序列号的设备以总线号与地址标识；打开或读取失败时无法确定是哪块设备，返回 None
+fn identify(device: &rusb::Device<rusb::Context>, desc: &rusb::DeviceDescriptor) -> Option<DeviceId> {
+    let (bus, address) = (device.bus_number(), device.address());
+    if desc.serial_number_string_index().is_none() {
+        return Some(DeviceId::BusAddr { bus, address });
+    }
+    let handle = match device.open() {
+        Ok(handle) => handle,
+        Err(e) => {
+            debug!("无法打开 USB 设备 (Bus: {}, Addr: {}) 读取序列号: {}", bus, address, e);
+            return None;
+        }
+    };
+    match handle.read_serial_number_string_ascii(desc) {
+        Ok(serial) if !serial.trim().is_empty() => Some(DeviceId::Serial(serial.trim().to_string())),
+        Ok(_) => Some(DeviceId::BusAddr { bus, address }),
+        Err(e) => {
+            debug!("读取 USB 设备 (Bus: {}, Addr: {}) 的序列号失败: {}", bus, address, e);
+            None
+        }
+    }
+}
+
+/// 把一次扫描结果解析为在场设备的标识，保持扫描的优先级顺序。
+///
+/// 无法识别的设备可能正是某块已在管理中、刚重新枚举到新地址的设备；此时跳过它，下次扫描再识别，
+/// 避免同一块设备以 `DeviceId::BusAddr` 再启动一个管理任务。没有这样的设备时（如没有权限打开），
+/// 才以总线号与地址标识它，由管理任务报告打开失败的原因。
+pub fn resolve_present(found: &[DiscoveredDevice], managed: &[DeviceId]) -> Vec<DeviceId> {
+    let accounted = |id: &DeviceId| {
+        found.iter().any(|d| match &d.id {
+            Some(found_id) => found_id == id,
+            None => *id == DeviceId::BusAddr { bus: d.bus, address: d.address },
+        })
+    };
+    let awaiting = managed.iter().any(|id| !accounted(id));
+    found
+        .iter()
+        .filter_map(|d| match &d.id {
+            Some(id) => Some(id.clone()),
+            None => {
+                let fallback = DeviceId::BusAddr { bus: d.bus, address: d.address };
+                if managed.contains(&fallback) || !awaiting {
+                    Some(fallback)
+                } else {
+                    debug!("暂时无法识别 USB 设备 (Bus: {}, Addr: {})，可能是重新枚举中的已知设备，下次扫描再试", d.bus, d.address);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// 注册表任务的配置
+#[derive(Debug, Clone)]
+pub struct DiscoverySettings {
+    pub selector: DeviceSelector,
+    /// 为 false 时只管理一块设备（优先保留已在管理中的那块）
+    pub multi_device: bool,
+    pub scan_interval: Duration,
+}
+
+/// 周期扫描 USB 总线，随设备插拔增删管理任务，并转发主循环的命令
+pub async fn device_registry_task(
+    mut registry: DeviceRegistry,
+    discovery: DiscoverySettings,
+    mut cmd_rx: mpsc::Receiver<DeviceCommand>,
+) {
+    let usb_ids = registry.settings.usb_ids.clone();
+    let wanted: Vec<String> = usb_ids.iter().map(UsbId::to_string).collect();
+    let mut known: HashMap<(u8, u8), DeviceId> = HashMap::new();
+    let mut scan_timer = tokio::time::interval(discovery.scan_interval);
+    scan_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut reported_missing = false;
+    loop {
+        tokio::select! {
+            command = cmd_rx.recv() => match command {
+                Some(command) => registry.dispatch(command).await,
+                None => {
+                    info!("命令通道关闭，设备注册表退出。");
+                    break;
+                }
+            },
+            _ = scan_timer.tick() => {
+                let selector = discovery.selector.clone();
+                let cache = known.clone();
+                let ids = usb_ids.clone();
+                let scanned = tokio::task::spawn_blocking(move || scan_devices(&ids, &selector, &cache)).await;
+                let found = match scanned {
+                    Ok(Ok(found)) => found,
+                    Ok(Err(e)) => {
+                        error!("枚举 USB 设备失败: {:?}", e);
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("USB 扫描任务异常: {:?}", e);
+                        continue;
+                    }
+                };
+                // 只缓存确实识别出的设备，打开失败的位置下次扫描重新识别
+                known = found.iter().filter_map(|d| Some(((d.bus, d.address), d.id.clone()?))).collect();
+
+                let mut present = resolve_present(&found, &registry.managed());
+                if !discovery.multi_device && present.len() > 1 {
+                    let keep = present
+                        .iter()
+                        .position(|id| registry.contains(id))
+                        .unwrap_or(0);
+                    let keep = present.swap_remove(keep);
+                    debug!("单设备模式，忽略其余设备: {:?}", present);
+                    present = vec![keep];
+                }
+
+                if present.is_empty() && registry.is_empty() {
+                    if !reported_missing {
+                        warn!("未找到 {} 设备 ({})，等待设备接入...", wanted.join(", "), discovery.selector);
+                        reported_missing = true;
+                    }
+                } else {
+                    reported_missing = false;
+                }
+                registry.update(&present).await;
+                // 没有设备时由扫描维持 USB 心跳；有设备时由各管理任务的阻塞读取维持
+                if registry.is_empty() {
+                    registry.settings.heartbeat.beat();
+                }
+            }
+        }
+    }
+    // 关闭各管理任务的命令通道，等它们释放接口后再退出
+    for (device, ManagedDevice { task, .. }) in std::mem::take(&mut registry.devices) {
+        if let Err(e) = task.await {
+            error!("设备 {} 的管理任务异常结束: {:?}", device, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USB_ID: UsbId = UsbId { vid: 0x1209, pid: 0x0001 };
+
+    fn found(id: Option<DeviceId>, bus: u8, address: u8) -> DiscoveredDevice {
+        DiscoveredDevice { id, usb_id: USB_ID, bus, address }
+    }
+
+    fn serial(serial: &str) -> DeviceId {
+        DeviceId::Serial(serial.to_string())
+    }
+
+    #[test]
+    fn reenumerated_device_that_cannot_be_opened_is_not_managed_twice() {
+        // 设备重置后出现在新地址，打开失败；原来的管理任务仍在等待它回来
+        let present = resolve_present(&[found(None, 1, 9)], &[serial("A1")]);
+        assert!(present.is_empty());
+        // 下次扫描识别成功，仍是同一个标识
+        let present = resolve_present(&[found(Some(serial("A1")), 1, 9)], &[serial("A1")]);
+        assert_eq!(present, vec![serial("A1")]);
+    }
+
+    #[test]
+    fn unidentifiable_device_falls_back_to_bus_address_when_nothing_is_missing() {
+        let present = resolve_present(&[found(Some(serial("A1")), 1, 4), found(None, 1, 9)], &[serial("A1")]);
+        assert_eq!(present, vec![serial("A1"), DeviceId::BusAddr { bus: 1, address: 9 }]);
+    }
+
+    #[test]
+    fn device_managed_by_bus_address_stays_present_while_open() {
+        // Windows 上管理任务已打开的设备无法再次打开读取序列号
+        let managed = DeviceId::BusAddr { bus: 2, address: 3 };
+        let present = resolve_present(&[found(None, 2, 3)], std::slice::from_ref(&managed));
+        assert_eq!(present, vec![managed]);
+    }
+}
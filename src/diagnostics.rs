@@ -0,0 +1,145 @@
+use serde::Serialize;
+
+use crate::data_models::HostSideUsbPayload;
+
+// 保留位掩码（对应固件寄存器定义中的 reserved 位）
+const SYSTEM_STATUS_RESERVED_MASK: u8 = 0b0100_0000; // SysStat bit 6
+const PROCHOT_MSB_RESERVED_MASK: u8 = 0b1000_0100; // PROCHOT_STATUS_MSB bit 7, bit 2
+const MOS_STATUS_RESERVED_MASK: u8 = !0b11;
+
+/// 电池包总压 (电芯之和) 与 BQ25730 VBAT 之间允许的最大偏差 (mV)
+const PACK_VOLTAGE_TOLERANCE_MV: i64 = 500;
+
+/// BQ76920 TS 通道 ADC 为 14 位，超出该值的原始值不可能来自 ADC
+pub const TS_ADC_MAX: u16 = 0x3FFF;
+
+/// 温度传感器故障类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorFault {
+    /// 读数为 0，传感器短路
+    Short,
+    /// 读数满量程，传感器开路
+    Open,
+    /// 超出 ADC 量程，原始值为垃圾数据
+    OutOfRange,
+}
+
+impl std::fmt::Display for SensorFault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SensorFault::Short => write!(f, "short"),
+            SensorFault::Open => write!(f, "open"),
+            SensorFault::OutOfRange => write!(f, "out of ADC range"),
+        }
+    }
+}
+
+/// 判断 TS 原始读数是否为有效温度值；转换层与诊断共用此判定
+pub fn classify_ts_raw(raw: u16) -> Option<SensorFault> {
+    match raw {
+        0 => Some(SensorFault::Short),
+        TS_ADC_MAX => Some(SensorFault::Open),
+        r if r > TS_ADC_MAX => Some(SensorFault::OutOfRange),
+        _ => None,
+    }
+}
+
+/// 单帧载荷中发现的协议/数据异常。生产模式下只记录日志，严格模式下视为致命错误。
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FrameAnomaly {
+    /// 寄存器保留位被置位
+    ReservedBits { register: &'static str, bits: u8 },
+    /// 载荷中相互冗余的字段不一致
+    CrossCheck { check: &'static str, detail: String },
+    /// 标记为存在的温度传感器读数无效
+    SensorFault { sensor: &'static str, fault: SensorFault, raw: u16 },
+    /// 固件违反载荷约定，例如 present=0 却带有非零原始值
+    ContractViolation { field: &'static str, detail: String },
+}
+
+impl std::fmt::Display for FrameAnomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameAnomaly::ReservedBits { register, bits } => {
+                write!(f, "reserved bits {:#010b} set in {}", bits, register)
+            }
+            FrameAnomaly::CrossCheck { check, detail } => write!(f, "cross-check '{}' failed: {}", check, detail),
+            FrameAnomaly::SensorFault { sensor, fault, raw } => {
+                write!(f, "temperature sensor {} fault: {} (raw {:#06x})", sensor, fault, raw)
+            }
+            FrameAnomaly::ContractViolation { field, detail } => {
+                write!(f, "payload contract violation in {}: {}", field, detail)
+            }
+        }
+    }
+}
+
+/// 检查原始载荷的保留位、温度通道约定与冗余字段一致性；电池包总压只累加前 `cell_count` 节
+pub fn check_payload(payload: &HostSideUsbPayload, cell_count: usize) -> Vec<FrameAnomaly> {
+    let mut anomalies = Vec::new();
+
+    let reserved = [
+        ("bq76920_system_status", payload.bq76920_system_status_bits & SYSTEM_STATUS_RESERVED_MASK),
+        ("bq76920_alerts_system_status", payload.bq76920_alerts_system_status_bits & SYSTEM_STATUS_RESERVED_MASK),
+        ("bq25730_prochot_status_msb", (payload.bq25730_prochot_status_raw_u16 >> 8) as u8 & PROCHOT_MSB_RESERVED_MASK),
+        ("bq76920_mos_status", payload.bq76920_mos_status_bits & MOS_STATUS_RESERVED_MASK),
+    ];
+    for (register, bits) in reserved {
+        if bits != 0 {
+            anomalies.push(FrameAnomaly::ReservedBits { register, bits });
+        }
+    }
+
+    // 任何非零的 present 都视为存在（固件曾发送 0xFF）；present=0 时原始值应为 0
+    let sensors = [
+        ("ts1", "bq76920_ts1_present", 1, payload.bq76920_ts1_raw_adc),
+        ("ts2", "bq76920_ts2_present", payload.bq76920_ts2_present, payload.bq76920_ts2_raw_adc),
+        ("ts3", "bq76920_ts3_present", payload.bq76920_ts3_present, payload.bq76920_ts3_raw_adc),
+    ];
+    for (sensor, present_field, present, raw) in sensors {
+        if present != 0 {
+            if let Some(fault) = classify_ts_raw(raw) {
+                anomalies.push(FrameAnomaly::SensorFault { sensor, fault, raw });
+            }
+        } else if raw != 0 {
+            anomalies.push(FrameAnomaly::ContractViolation {
+                field: present_field,
+                detail: format!("present=0 but raw={:#06x}", raw),
+            });
+        }
+    }
+
+    // 同一个 SysStat 寄存器在载荷中出现了两次，两者应当一致
+    if payload.bq76920_system_status_bits != payload.bq76920_alerts_system_status_bits {
+        anomalies.push(FrameAnomaly::CrossCheck {
+            check: "system_status",
+            detail: format!(
+                "measurements={:#04x} alerts={:#04x}",
+                payload.bq76920_system_status_bits, payload.bq76920_alerts_system_status_bits
+            ),
+        });
+    }
+
+    let pack_mv: i64 = [
+        payload.bq76920_cell1_mv,
+        payload.bq76920_cell2_mv,
+        payload.bq76920_cell3_mv,
+        payload.bq76920_cell4_mv,
+        payload.bq76920_cell5_mv,
+    ]
+    .iter()
+    .take(cell_count)
+    .map(|&mv| mv as i64)
+    .sum();
+    let vbat_mv = payload.bq25730_adc_vbat_raw as i64;
+    if pack_mv > 0 && vbat_mv > 0 && (pack_mv - vbat_mv).abs() > PACK_VOLTAGE_TOLERANCE_MV {
+        anomalies.push(FrameAnomaly::CrossCheck {
+            check: "pack_voltage",
+            detail: format!("cells_sum={}mV vbat={}mV", pack_mv, vbat_mv),
+        });
+    }
+
+    anomalies
+}
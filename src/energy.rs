@@ -0,0 +1,495 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{error, info, warn};
+use rumqttc::{AsyncClient, QoS};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::conversion::CurrentSign;
+use crate::data_models::{ChargerStatusFlags, TimestampedMeasurements};
+use crate::pipeline::Subscription;
+use crate::utils;
+
+pub fn charged_wh_topic(topic_prefix: &str) -> String {
+    format!("{}/energy/charged_wh", topic_prefix)
+}
+
+pub fn discharged_wh_topic(topic_prefix: &str) -> String {
+    format!("{}/energy/discharged_wh", topic_prefix)
+}
+
+pub fn battery_cycles_topic(topic_prefix: &str) -> String {
+    format!("{}/battery/cycles", topic_prefix)
+}
+
+pub fn battery_health_topic(topic_prefix: &str) -> String {
+    format!("{}/battery/health", topic_prefix)
+}
+
+// 循环次数与健康指标两次发布之间的最短间隔
+const BATTERY_PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+// 相邻两帧电流变化至少这么大 (A) 才视为负载阶跃
+const LOAD_STEP_MIN_A: f32 = 0.5;
+// 负载阶跃的两帧间隔上限；间隔过长时电压变化混入了放电本身的下降
+const LOAD_STEP_MAX_DT_MS: u64 = 5_000;
+// 充满判定：充电器停止充电且电流绝对值低于该值 (A)
+const FULL_CURRENT_A: f32 = 0.2;
+
+/// 粗略的电池健康指标，随能量计数一起保存
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BatteryHealth {
+    /// 充满时观察到的最小单体压差 (mV)；压差随老化变大
+    pub min_full_cell_delta_mv: Option<f32>,
+    /// 负载阶跃时电压跌落与电流变化之比的最大值 (mΩ)，内阻的代理
+    pub max_sag_resistance_mohm: Option<f32>,
+}
+
+/// 电池累计充入与放出的能量 (Wh)，按相邻两帧的电池功率积分。
+///
+/// 时间戳取样本的接收时间：时钟回拨（时间戳不增）的区间不计入，
+/// 两帧间隔超过 `max_gap` 时只按 `max_gap` 计入，避免断线或休眠后一次性累加大量能量。
+/// 同时累计放出的电荷用于计算循环次数（累计放出一个标称容量为一次循环）。
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EnergyCounters {
+    pub charged_wh: f64,
+    pub discharged_wh: f64,
+    /// 累计放出的电荷 (Ah)
+    #[serde(default)]
+    pub discharged_ah: f64,
+    #[serde(default)]
+    pub health: BatteryHealth,
+    /// 上一帧的接收时间、电池包电压 (V) 与电流 (A，充电为正)
+    #[serde(skip)]
+    last: Option<(u64, f32, f32)>,
+}
+
+impl EnergyCounters {
+    /// 计入一帧。`current_a` 为电池电流，充电为正
+    pub fn record(&mut self, ts_unix_ms: u64, voltage_v: f32, current_a: f32, max_gap: Duration) {
+        if let Some((last_ts, last_voltage, last_current)) = self.last
+            && ts_unix_ms > last_ts
+        {
+            let elapsed_ms = ts_unix_ms - last_ts;
+            let dt_ms = elapsed_ms.min(max_gap.as_millis() as u64);
+            // 梯形积分；符号变化的区间按两端分别计入充电与放电
+            let integrate = |x: f32| x as f64 * dt_ms as f64 / 2.0 / 3_600_000.0;
+            for (v, i) in [(last_voltage, last_current), (voltage_v, current_a)] {
+                let p = v * i;
+                if p > 0.0 {
+                    self.charged_wh += integrate(p);
+                } else {
+                    self.discharged_wh += integrate(-p);
+                }
+                if i < 0.0 {
+                    self.discharged_ah += integrate(-i);
+                }
+            }
+            let step = current_a - last_current;
+            if elapsed_ms <= LOAD_STEP_MAX_DT_MS && step.abs() >= LOAD_STEP_MIN_A {
+                // 放电电流增大（充电为正时 step < 0）伴随电压下降，比值为正
+                let resistance_mohm = (voltage_v - last_voltage) / step * 1000.0;
+                if resistance_mohm > 0.0 && self.health.max_sag_resistance_mohm.is_none_or(|max| resistance_mohm > max) {
+                    self.health.max_sag_resistance_mohm = Some(resistance_mohm);
+                }
+            }
+        }
+        self.last = Some((ts_unix_ms, voltage_v, current_a));
+    }
+
+    /// 电池充满时记录单体压差 (V)
+    pub fn record_full(&mut self, cell_delta_v: f32) {
+        let delta_mv = cell_delta_v * 1000.0;
+        if self.health.min_full_cell_delta_mv.is_none_or(|min| delta_mv < min) {
+            self.health.min_full_cell_delta_mv = Some(delta_mv);
+        }
+    }
+
+    /// 循环次数：累计放出的电荷除以标称容量
+    pub fn cycles(&self, capacity_ah: f64) -> f64 {
+        self.discharged_ah / capacity_ah
+    }
+
+    /// 读取上次保存的计数；文件不存在时返回 None
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let saved: EnergyCounters =
+            serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Some(saved))
+    }
+
+    pub fn persist(&self, path: &Path) -> io::Result<()> {
+        let text = serde_json::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        utils::write_atomic(path, text.as_bytes())
+    }
+}
+
+/// 能量计数任务的配置
+#[derive(Debug, Clone)]
+pub struct EnergySettings {
+    pub state_path: PathBuf,
+    /// 两次写状态文件之间的最短间隔
+    pub persist_interval: Duration,
+    /// 两次发布之间的最短间隔
+    pub publish_interval: Duration,
+    /// 积分时两帧间隔的上限
+    pub max_gap: Duration,
+    pub current_sign: CurrentSign,
+    /// 标称容量 (Ah)；未配置时不发布循环次数
+    pub capacity_ah: Option<f64>,
+}
+
+/// 充电器接着适配器且已停止充电，电流接近 0
+fn is_full(sample: &TimestampedMeasurements, current_a: f32) -> bool {
+    let charger = sample.data.bq25730_alerts.charger_status_flags;
+    charger.contains(ChargerStatusFlags::STAT_AC)
+        && !charger.intersects(ChargerStatusFlags::IN_FCHRG | ChargerStatusFlags::IN_PCHRG)
+        && current_a.abs() < FULL_CURRENT_A
+        && sample.derived.cell_delta > 0.0
+}
+
+/// 订阅测量数据，以电池包总压与电流积分累计充放电能量，以 retained 方式发布到
+/// `{prefix}/energy/charged_wh` 与 `{prefix}/energy/discharged_wh`，并定期保存以便跨重启继续累计。
+/// 循环次数与健康指标发布到 `{prefix}/battery/cycles` 与 `{prefix}/battery/health`，至多每分钟一次
+pub async fn energy_task(
+    mut samples: Subscription,
+    client: AsyncClient,
+    topic_prefix: String,
+    settings: EnergySettings,
+) {
+    let mut counters = match EnergyCounters::load(&settings.state_path) {
+        Ok(Some(saved)) => {
+            info!(
+                "恢复能量计数: 充入 {:.3} Wh，放出 {:.3} Wh ({:.3} Ah)",
+                saved.charged_wh, saved.discharged_wh, saved.discharged_ah
+            );
+            saved
+        }
+        Ok(None) => EnergyCounters::default(),
+        Err(e) => {
+            warn!("读取能量计数 {} 失败: {:?}，从 0 开始", settings.state_path.display(), e);
+            EnergyCounters::default()
+        }
+    };
+
+    let mut last_persist = tokio::time::Instant::now();
+    let mut last_publish: Option<tokio::time::Instant> = None;
+    let mut last_battery_publish: Option<tokio::time::Instant> = None;
+    let mut published_battery: Option<(String, BatteryHealth)> = None;
+    loop {
+        let sample: Arc<TimestampedMeasurements> = match samples.recv().await {
+            Ok(sample) => sample,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("能量计数处理过慢，跳过 {} 帧", n);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        let current = settings.current_sign.to_charge_positive(sample.data.bq76920.coulomb_counter);
+        counters.record(sample.ts_unix_ms, sample.derived.pack_voltage, current, settings.max_gap);
+        if is_full(&sample, current) {
+            counters.record_full(sample.derived.cell_delta);
+        }
+
+        if last_publish.is_none_or(|at| at.elapsed() >= settings.publish_interval) {
+            last_publish = Some(tokio::time::Instant::now());
+            for (topic, value) in [
+                (charged_wh_topic(&topic_prefix), counters.charged_wh),
+                (discharged_wh_topic(&topic_prefix), counters.discharged_wh),
+            ] {
+                if let Err(e) = client.publish(topic, QoS::AtLeastOnce, true, format!("{:.3}", value)).await {
+                    error!("发布能量计数失败: {:?}", e);
+                }
+            }
+        }
+
+        if last_battery_publish.is_none_or(|at| at.elapsed() >= BATTERY_PUBLISH_INTERVAL) {
+            let cycles = settings.capacity_ah.map(|capacity| format!("{:.3}", counters.cycles(capacity))).unwrap_or_default();
+            let battery = (cycles, counters.health.clone());
+            if published_battery.as_ref() != Some(&battery) {
+                last_battery_publish = Some(tokio::time::Instant::now());
+                publish_battery(&client, &topic_prefix, &battery.0, &battery.1).await;
+                published_battery = Some(battery);
+            }
+        }
+
+        if last_persist.elapsed() >= settings.persist_interval {
+            last_persist = tokio::time::Instant::now();
+            if let Err(e) = counters.persist(&settings.state_path) {
+                error!("保存能量计数失败: {:?}", e);
+            }
+        }
+    }
+    if let Err(e) = counters.persist(&settings.state_path) {
+        error!("保存能量计数失败: {:?}", e);
+    }
+}
+
+async fn publish_battery(client: &AsyncClient, topic_prefix: &str, cycles: &str, health: &BatteryHealth) {
+    if !cycles.is_empty()
+        && let Err(e) = client.publish(battery_cycles_topic(topic_prefix), QoS::AtLeastOnce, true, cycles).await
+    {
+        error!("发布循环次数失败: {:?}", e);
+    }
+    match serde_json::to_string(health) {
+        Ok(payload) => {
+            if let Err(e) = client.publish(battery_health_topic(topic_prefix), QoS::AtLeastOnce, true, payload).await {
+                error!("发布电池健康指标失败: {:?}", e);
+            }
+        }
+        Err(e) => error!("序列化电池健康指标失败: {:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rumqttc::MqttOptions;
+
+    use crate::pipeline::Pipeline;
+    use crate::test_support::PayloadBuilder;
+
+    #[tokio::test]
+    async fn task_saves_counters_and_exits_when_the_route_is_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("energy.json");
+        // 事件循环不运行，发布只进入客户端队列
+        let (client, _event_loop) = AsyncClient::new(MqttOptions::new("energy-test", "localhost", 1883), 16);
+        let settings = EnergySettings {
+            state_path: state_path.clone(),
+            persist_interval: Duration::from_secs(3600),
+            publish_interval: Duration::from_secs(3600),
+            max_gap: Duration::from_secs(10),
+            current_sign: CurrentSign::ChargePositive,
+            capacity_ah: None,
+        };
+        let pipeline = Pipeline::new(8);
+        let task = tokio::spawn(energy_task(pipeline.subscribe(), client, "ups120".to_string(), settings));
+        let discharging = PayloadBuilder::new().pack_current_ma(-2000);
+        pipeline.publish(Arc::new(discharging.sample(1, 1_000)));
+        pipeline.publish(Arc::new(discharging.sample(2, 2_000)));
+        // 路由移除：总线的发送端全部丢弃
+        drop(pipeline);
+
+        tokio::time::timeout(Duration::from_secs(5), task).await.expect("任务应随总线关闭结束").unwrap();
+        let saved = EnergyCounters::load(&state_path).unwrap().expect("退出时应保存能量计数");
+        assert!(saved.discharged_wh > 0.0);
+        assert_eq!(saved.charged_wh, 0.0);
+    }
+
+    const GAP: Duration = Duration::from_secs(10);
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn constant_power_integrates_to_watt_hours() {
+        let mut counters = EnergyCounters::default();
+        // 20 V、-3 A 放电 60 W，按 1 秒一帧积分 1 小时
+        for second in 0..=3600u64 {
+            counters.record(second * 1000, 20.0, -3.0, GAP);
+        }
+        assert!(close(counters.discharged_wh, 60.0));
+        assert!(close(counters.discharged_ah, 3.0));
+        assert_eq!(counters.charged_wh, 0.0);
+    }
+
+    #[test]
+    fn a_sign_change_splits_the_interval_between_charge_and_discharge() {
+        let mut counters = EnergyCounters::default();
+        counters.record(0, 20.0, 1.8, GAP);
+        counters.record(3600, 20.0, -1.8, GAP);
+        // 每端各占一半的时间：36 W × 1.8 s
+        assert!(close(counters.charged_wh, 36.0 * 1.8 / 3600.0));
+        assert!(close(counters.discharged_wh, 36.0 * 1.8 / 3600.0));
+    }
+
+    #[test]
+    fn clock_jumps_backwards_are_skipped() {
+        let mut counters = EnergyCounters::default();
+        counters.record(10_000, 20.0, 2.0, GAP);
+        counters.record(5_000, 20.0, 2.0, GAP);
+        counters.record(5_000, 20.0, 2.0, GAP);
+        assert_eq!(counters.charged_wh, 0.0);
+        // 回拨后从新的时间戳继续计
+        counters.record(6_000, 20.0, 2.0, GAP);
+        assert!(close(counters.charged_wh, 40.0 / 3600.0));
+    }
+
+    #[test]
+    fn long_gaps_are_capped() {
+        let mut counters = EnergyCounters::default();
+        counters.record(0, 20.0, -1.0, GAP);
+        counters.record(3_600_000, 20.0, -1.0, GAP);
+        assert!(close(counters.discharged_wh, 20.0 * 10.0 / 3600.0));
+    }
+
+    #[test]
+    fn the_configured_current_sign_decides_charge_or_discharge() {
+        let raw = 2.0;
+        for (sign, charging) in [(CurrentSign::ChargePositive, true), (CurrentSign::DischargePositive, false)] {
+            let mut counters = EnergyCounters::default();
+            counters.record(0, 20.0, sign.to_charge_positive(raw), GAP);
+            counters.record(1000, 20.0, sign.to_charge_positive(raw), GAP);
+            assert_eq!(counters.charged_wh > 0.0, charging, "{:?}", sign);
+            assert_eq!(counters.discharged_wh > 0.0, !charging, "{:?}", sign);
+        }
+    }
+
+    #[test]
+    fn counters_survive_a_restart_without_the_last_sample() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("energy.json");
+        assert_eq!(EnergyCounters::load(&path).unwrap(), None);
+
+        let mut counters = EnergyCounters::default();
+        counters.record(0, 20.0, -1.0, GAP);
+        counters.record(1000, 20.0, -1.0, GAP);
+        counters.record_full(0.012);
+        counters.persist(&path).unwrap();
+        let restored = EnergyCounters::load(&path).unwrap().unwrap();
+        assert_eq!(restored.discharged_wh, counters.discharged_wh);
+        assert_eq!(restored.health.min_full_cell_delta_mv, Some(12.0));
+        // 上一帧不保存：重启后的第一帧只作为积分起点
+        assert_eq!(restored.last, None);
+        assert!(!path.with_extension("tmp").exists());
+
+        fs::write(&path, "not json").unwrap();
+        assert_eq!(EnergyCounters::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn the_state_file_format_is_stable() {
+        let mut counters = EnergyCounters {
+            charged_wh: 12.5,
+            discharged_wh: 10.25,
+            discharged_ah: 0.75,
+            health: BatteryHealth { min_full_cell_delta_mv: Some(8.0), max_sag_resistance_mohm: None },
+            last: None,
+        };
+        counters.record(0, 20.0, -1.0, GAP);
+        assert_eq!(
+            serde_json::to_value(&counters).unwrap(),
+            serde_json::json!({
+                "charged_wh": 12.5,
+                "discharged_wh": 10.25,
+                "discharged_ah": 0.75,
+                "health": { "min_full_cell_delta_mv": 8.0, "max_sag_resistance_mohm": null },
+            })
+        );
+        // 加入循环次数之前保存的文件只有能量字段
+        let old: EnergyCounters = serde_json::from_str(r#"{"charged_wh":1.0,"discharged_wh":2.0}"#).unwrap();
+        assert_eq!((old.discharged_ah, old.health), (0.0, BatteryHealth::default()));
+        let partial: EnergyCounters =
+            serde_json::from_str(r#"{"charged_wh":1.0,"discharged_wh":2.0,"health":{"max_sag_resistance_mohm":40.0}}"#).unwrap();
+        assert_eq!(partial.health, BatteryHealth { min_full_cell_delta_mv: None, max_sag_resistance_mohm: Some(40.0) });
+    }
+
+    #[test]
+    fn cycles_count_discharged_capacity_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("energy.json");
+        let mut counters = EnergyCounters::default();
+        // 2 Ah 电池以 1 A 放电 30 分钟：四分之一个循环；充电不计入
+        for second in 0..=1800u64 {
+            counters.record(second * 1000, 16.0, -1.0, GAP);
+        }
+        for second in 1801..=3600u64 {
+            counters.record(second * 1000, 16.8, 1.0, GAP);
+        }
+        assert!((counters.cycles(2.0) - 0.25).abs() < 1e-3, "{}", counters.cycles(2.0));
+        counters.persist(&path).unwrap();
+
+        let mut restored = EnergyCounters::load(&path).unwrap().unwrap();
+        for second in 0..=5400u64 {
+            restored.record(10_000_000 + second * 1000, 16.0, -1.0, GAP);
+        }
+        assert!((restored.cycles(2.0) - 1.0).abs() < 1e-3, "{}", restored.cycles(2.0));
+        // 累计放出 2 Ah：5 Ah 电池只算 0.4 次
+        assert!((restored.cycles(5.0) - 0.4).abs() < 1e-3);
+    }
+
+    #[test]
+    fn load_steps_track_the_largest_sag_resistance() {
+        let mut counters = EnergyCounters::default();
+        let sag = |counters: &EnergyCounters| counters.health.max_sag_resistance_mohm.map(|r| (r * 10.0).round() / 10.0);
+        // 放电电流从 0.5 A 增到 2.5 A，电压跌 100 mV：50 mΩ
+        counters.record(0, 16.5, -0.5, GAP);
+        counters.record(1000, 16.4, -2.5, GAP);
+        assert_eq!(sag(&counters), Some(50.0));
+        // 负载减小时电压回升 40 mV：40 mΩ，不超过最大值
+        counters.record(2000, 16.44, -1.5, GAP);
+        assert_eq!(sag(&counters), Some(50.0));
+        // 电流变化太小、间隔太长或电压反向变化的阶跃都不计入
+        counters.record(3000, 16.0, -1.8, GAP);
+        counters.record(9000, 15.0, -3.8, GAP);
+        counters.record(10_000, 15.2, -4.8, GAP);
+        assert_eq!(sag(&counters), Some(50.0));
+        counters.record(11_000, 15.4, -2.8, GAP);
+        assert_eq!(sag(&counters), Some(100.0));
+    }
+
+    #[test]
+    fn the_smallest_cell_delta_at_full_is_kept() {
+        let mut counters = EnergyCounters::default();
+        counters.record_full(0.015);
+        counters.record_full(0.009);
+        counters.record_full(0.020);
+        assert_eq!(counters.health.min_full_cell_delta_mv, Some(9.0));
+
+        let full = PayloadBuilder::new().cell_mv(0, 4180).charger_flags(ChargerStatusFlags::STAT_AC).sample(1, 1000);
+        assert!(is_full(&full, 0.05));
+        assert!(!is_full(&full, 0.5));
+        let charging = PayloadBuilder::new()
+            .cell_mv(0, 4180)
+            .charger_flags(ChargerStatusFlags::STAT_AC | ChargerStatusFlags::IN_FCHRG)
+            .sample(1, 1000);
+        assert!(!is_full(&charging, 0.05));
+        let on_battery = PayloadBuilder::new().cell_mv(0, 4180).charger_flags(ChargerStatusFlags::empty()).sample(1, 1000);
+        assert!(!is_full(&on_battery, 0.05));
+    }
+
+    #[tokio::test]
+    async fn cycles_and_health_are_published_retained_at_most_once_a_minute() {
+        use crate::test_broker::TestBroker;
+
+        let broker = TestBroker::start().await;
+        let (client, mut eventloop) = AsyncClient::new(broker.options("energy"), 16);
+        tokio::spawn(async move { while eventloop.poll().await.is_ok() {} });
+        let dir = tempfile::tempdir().unwrap();
+        let settings = EnergySettings {
+            state_path: dir.path().join("energy.json"),
+            persist_interval: Duration::from_secs(3600),
+            publish_interval: Duration::from_secs(3600),
+            max_gap: GAP,
+            current_sign: CurrentSign::ChargePositive,
+            capacity_ah: Some(2.0),
+        };
+        let pipeline = Pipeline::new(8);
+        tokio::spawn(energy_task(pipeline.subscribe(), client, "ups".to_string(), settings));
+        let discharging = PayloadBuilder::new().pack_current_ma(-2000);
+        for frame_id in 0..3 {
+            pipeline.publish(Arc::new(discharging.sample(frame_id, 1000 * frame_id)));
+        }
+        let published = |p: &[crate::test_broker::ReceivedPublish]| p.iter().any(|p| p.topic == "ups/battery/health");
+        assert!(broker.wait_for(Duration::from_secs(5), published).await);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let battery: Vec<_> = broker.publishes().into_iter().filter(|p| p.topic.starts_with("ups/battery/")).collect();
+        assert_eq!(battery.len(), 2, "{:?}", battery);
+        assert!(battery.iter().all(|p| p.retain));
+        assert_eq!((battery[0].topic.as_str(), battery[0].payload.as_slice()), ("ups/battery/cycles", b"0.000".as_slice()));
+        let health: BatteryHealth = serde_json::from_slice(&battery[1].payload).unwrap();
+        assert_eq!(health, BatteryHealth::default());
+    }
+}
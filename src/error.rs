@@ -0,0 +1,433 @@
+//! 守护进程的顶层错误类型与稳定错误码。
+//!
+//! 每个错误对应一个数值码与文本码（如 `31` / `usb.open_failed`），随错误发布到
+//! `{prefix}/daemon/last_error`。码值一经发布不得更改或复用，新增错误只能追加新码。
+//! 进程退出码只区分错误类别 (`ExitCategory`)；严格模式的退出码 (10..=16) 见 `strict` 模块。
+
+use std::io;
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::config::ConfigError;
+use crate::tls::TlsError;
+use crate::usb_types::UsbError;
+#[cfg(feature = "mqtt")]
+use crate::wizard::WizardError;
+
+/// 文本码 → 数值码的唯一映射表
+pub const ERROR_CODES: &[(&str, u8)] = &[
+    ("config.missing", 20),
+    ("config.invalid", 21),
+    ("config.file", 22),
+    ("config.tls", 23),
+    ("config.wizard", 24),
+    ("usb.device_not_found", 30),
+    ("usb.open_failed", 31),
+    ("usb.set_configuration_failed", 32),
+    ("usb.claim_interface_failed", 33),
+    ("usb.detach_failed", 34),
+    ("usb.endpoint_not_found", 35),
+    ("usb.command_write_failed", 36),
+    ("usb.response_read_failed", 37),
+    ("usb.response_parse_error", 38),
+    ("usb.unexpected_response", 39),
+    ("usb.subscription_failed", 40),
+    ("usb.rusb", 41),
+    ("usb.io", 42),
+    ("usb.binrw", 43),
+    ("usb.timeout", 44),
+    ("usb.incomplete_payload", 45),
+    ("usb.length_mismatch", 46),
+    ("usb.unsupported_protocol", 47),
+    ("usb.task_failed", 48),
+    ("usb.other", 49),
+    ("mqtt.client", 50),
+    ("mqtt.connection", 51),
+    ("mqtt.fatal", 52),
+    ("mqtt.tls", 53),
+    ("io", 60),
+    ("json", 61),
+];
+
+/// 错误码：数值码用于退出码，文本码便于在日志与 MQTT 中阅读
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ErrorCode {
+    pub code: u8,
+    pub name: &'static str,
+}
+
+impl ErrorCode {
+    fn from_name(name: &'static str) -> Self {
+        let code = ERROR_CODES
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, code)| *code)
+            .expect("every error has a code");
+        ErrorCode { code, name }
+    }
+}
+
+/// 守护进程的顶层错误
+#[derive(Debug, Error)]
+pub enum DaemonError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[cfg(feature = "mqtt")]
+    #[error(transparent)]
+    Wizard(#[from] WizardError),
+    #[error(transparent)]
+    Usb(#[from] UsbError),
+    #[cfg(feature = "mqtt")]
+    #[error("MQTT client error: {0}")]
+    MqttClient(#[from] rumqttc::ClientError),
+    #[cfg(feature = "mqtt")]
+    #[error("MQTT connection error: {0}")]
+    MqttConnection(#[from] rumqttc::ConnectionError),
+    /// 事件循环已停止的不可恢复错误（认证被拒、TLS 握手失败）
+    #[error("MQTT fatal error: {0}")]
+    MqttFatal(String),
+    #[error("Invalid MQTT TLS settings: {0}")]
+    Tls(#[from] TlsError),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl DaemonError {
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::from_name(match self {
+            DaemonError::Config(ConfigError::Missing(_)) => "config.missing",
+            DaemonError::Config(ConfigError::Invalid { .. }) => "config.invalid",
+            DaemonError::Config(ConfigError::File { .. }) => "config.file",
+            DaemonError::Config(ConfigError::Tls { .. }) => "config.tls",
+            #[cfg(feature = "mqtt")]
+            DaemonError::Wizard(_) => "config.wizard",
+            DaemonError::Usb(e) => return e.code(),
+            #[cfg(feature = "mqtt")]
+            DaemonError::MqttClient(_) => "mqtt.client",
+            #[cfg(feature = "mqtt")]
+            DaemonError::MqttConnection(_) => "mqtt.connection",
+            DaemonError::MqttFatal(_) => "mqtt.fatal",
+            DaemonError::Tls(_) => "mqtt.tls",
+            DaemonError::Io(_) => "io",
+            DaemonError::Json(_) => "json",
+        })
+    }
+}
+
+impl UsbError {
+    /// 错误码，文本码为 `usb.` 加错误类别
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::from_name(match self {
+            UsbError::DeviceNotFound(_) => "usb.device_not_found",
+            UsbError::OpenFailed(_) => "usb.open_failed",
+            UsbError::SetConfigurationFailed(_) => "usb.set_configuration_failed",
+            UsbError::ClaimInterfaceFailed { .. } => "usb.claim_interface_failed",
+            UsbError::DetachFailed(_) => "usb.detach_failed",
+            UsbError::EndpointNotFound(_) => "usb.endpoint_not_found",
+            UsbError::CommandWriteFailed(_) => "usb.command_write_failed",
+            UsbError::ResponseReadFailed(_) => "usb.response_read_failed",
+            UsbError::ResponseParseError(_) => "usb.response_parse_error",
+            UsbError::UnexpectedResponse => "usb.unexpected_response",
+            UsbError::SubscriptionFailed(_) => "usb.subscription_failed",
+            UsbError::RusbError(_) => "usb.rusb",
+            UsbError::IoError(_) => "usb.io",
+            UsbError::BinrwError(_) => "usb.binrw",
+            UsbError::Timeout => "usb.timeout",
+            UsbError::IncompletePayload { .. } => "usb.incomplete_payload",
+            UsbError::LengthMismatch { .. } => "usb.length_mismatch",
+            UsbError::UnsupportedProtocol { .. } => "usb.unsupported_protocol",
+            UsbError::TaskFailed(_) => "usb.task_failed",
+            UsbError::Other(_) => "usb.other",
+        })
+    }
+}
+
+/// 进程退出的类别，供 systemd 按类别决定是否重启
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCategory {
+    Clean,
+    /// 不属于以下类别的错误
+    Failure,
+    /// 环境变量、配置文件或证书文件无效
+    Config,
+    /// MQTT 认证被拒或 TLS 握手失败
+    MqttAuth,
+    /// 启动期限内未找到设备
+    UsbNotFound,
+    /// 不可恢复的 USB 错误
+    UsbFatal,
+}
+
+/// 类别 → 退出码的唯一映射表
+pub const EXIT_CODES: &[(ExitCategory, i32)] = &[
+    (ExitCategory::Clean, 0),
+    (ExitCategory::Failure, 1),
+    (ExitCategory::Config, 2),
+    (ExitCategory::MqttAuth, 3),
+    (ExitCategory::UsbNotFound, 4),
+    (ExitCategory::UsbFatal, 5),
+];
+
+impl ExitCategory {
+    pub fn exit_code(self) -> i32 {
+        EXIT_CODES
+            .iter()
+            .find(|(category, _)| *category == self)
+            .map(|(_, code)| *code)
+            .expect("every ExitCategory has an exit code")
+    }
+}
+
+impl DaemonError {
+    pub fn exit_category(&self) -> ExitCategory {
+        match self {
+            DaemonError::Config(_) | DaemonError::Tls(_) => ExitCategory::Config,
+            #[cfg(feature = "mqtt")]
+            DaemonError::Wizard(_) => ExitCategory::Config,
+            DaemonError::MqttFatal(_) => ExitCategory::MqttAuth,
+            DaemonError::Usb(UsbError::DeviceNotFound(_)) => ExitCategory::UsbNotFound,
+            DaemonError::Usb(_) => ExitCategory::UsbFatal,
+            #[cfg(feature = "mqtt")]
+            DaemonError::MqttClient(_) | DaemonError::MqttConnection(_) => ExitCategory::Failure,
+            DaemonError::Io(_) | DaemonError::Json(_) => ExitCategory::Failure,
+        }
+    }
+}
+
+impl UsbError {
+    /// 重新连接也无法恢复的错误：单设备模式下守护进程因此退出
+    pub fn is_unrecoverable(&self) -> bool {
+        matches!(self, UsbError::UnsupportedProtocol { .. })
+    }
+}
+
+/// `{prefix}/daemon/last_error` 的载荷，retained 发布
+#[derive(Debug, Serialize)]
+pub struct LastError<'a> {
+    pub code: u8,
+    pub name: &'static str,
+    pub message: String,
+    /// 来自设备的错误附带设备标识
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<&'a str>,
+    pub ts_unix_ms: u64,
+}
+
+impl<'a> LastError<'a> {
+    pub fn new(error: &DaemonError, device: Option<&'a str>, ts_unix_ms: u64) -> Self {
+        let ErrorCode { code, name } = error.code();
+        LastError {
+            code,
+            name,
+            message: error.to_string(),
+            device,
+            ts_unix_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::error::Error as _;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn io_error() -> io::Error {
+        io::Error::new(io::ErrorKind::PermissionDenied, "denied")
+    }
+
+    fn json_error() -> serde_json::Error {
+        serde_json::from_str::<u8>("x").unwrap_err()
+    }
+
+    fn binrw_error() -> binrw::Error {
+        binrw::Error::AssertFail { pos: 0, message: "bad magic".to_string() }
+    }
+
+    async fn join_error() -> tokio::task::JoinError {
+        let task = tokio::spawn(std::future::pending::<()>());
+        task.abort();
+        task.await.unwrap_err()
+    }
+
+    // 已发布的错误码：只能追加，不能修改
+    #[tokio::test]
+    async fn error_codes_are_stable() {
+        let table: Vec<(DaemonError, u8, &str)> = vec![
+            (ConfigError::Missing("MQTT_HOST").into(), 20, "config.missing"),
+            (
+                ConfigError::Invalid { key: "MQTT_PORT", value: "x".to_string(), reason: "not a number".to_string() }.into(),
+                21,
+                "config.invalid",
+            ),
+            (ConfigError::File { path: PathBuf::from("ups.toml"), reason: "missing".to_string() }.into(), 22, "config.file"),
+            (
+                ConfigError::Tls { broker: "main".to_string(), source: TlsError::Incomplete("MQTT_CLIENT_KEY") }.into(),
+                23,
+                "config.tls",
+            ),
+            (UsbError::DeviceNotFound("1209:0001".to_string()).into(), 30, "usb.device_not_found"),
+            (UsbError::OpenFailed(rusb::Error::Access).into(), 31, "usb.open_failed"),
+            (UsbError::SetConfigurationFailed(rusb::Error::Busy).into(), 32, "usb.set_configuration_failed"),
+            (
+                UsbError::ClaimInterfaceFailed { source: rusb::Error::Busy, hint: None }.into(),
+                33,
+                "usb.claim_interface_failed",
+            ),
+            (UsbError::DetachFailed(rusb::Error::NotSupported).into(), 34, "usb.detach_failed"),
+            (UsbError::EndpointNotFound("IN".to_string()).into(), 35, "usb.endpoint_not_found"),
+            (UsbError::CommandWriteFailed(rusb::Error::Pipe).into(), 36, "usb.command_write_failed"),
+            (UsbError::ResponseReadFailed(rusb::Error::Io).into(), 37, "usb.response_read_failed"),
+            (UsbError::ResponseParseError(binrw_error()).into(), 38, "usb.response_parse_error"),
+            (UsbError::UnexpectedResponse.into(), 39, "usb.unexpected_response"),
+            (UsbError::SubscriptionFailed("stalled".to_string()).into(), 40, "usb.subscription_failed"),
+            (UsbError::RusbError(rusb::Error::NoDevice).into(), 41, "usb.rusb"),
+            (UsbError::IoError(io_error()).into(), 42, "usb.io"),
+            (UsbError::BinrwError(binrw_error()).into(), 43, "usb.binrw"),
+            (UsbError::Timeout.into(), 44, "usb.timeout"),
+            (UsbError::IncompletePayload { got: 10, expected: 64 }.into(), 45, "usb.incomplete_payload"),
+            (
+                UsbError::LengthMismatch { magic: Some(0xA5), got: 10, expected: 64, head: "a5".to_string() }.into(),
+                46,
+                "usb.length_mismatch",
+            ),
+            (UsbError::UnsupportedProtocol { device: 9, supported: 2 }.into(), 47, "usb.unsupported_protocol"),
+            (UsbError::TaskFailed(join_error().await).into(), 48, "usb.task_failed"),
+            (UsbError::Other("?".to_string()).into(), 49, "usb.other"),
+            (DaemonError::MqttFatal("not authorized".to_string()), 52, "mqtt.fatal"),
+            (TlsError::NoCertificate(PathBuf::from("ca.pem")).into(), 53, "mqtt.tls"),
+            (io_error().into(), 60, "io"),
+            (json_error().into(), 61, "json"),
+        ];
+        #[cfg(feature = "mqtt")]
+        let table: Vec<(DaemonError, u8, &str)> = table.into_iter().chain([
+            (WizardError::Exists(PathBuf::from("ups.toml")).into(), 24, "config.wizard"),
+            (
+                rumqttc::ClientError::Request(rumqttc::Request::PingReq(rumqttc::PingReq)).into(),
+                50,
+                "mqtt.client",
+            ),
+            (rumqttc::ConnectionError::RequestsDone.into(), 51, "mqtt.connection"),
+        ]).collect();
+        for (error, code, name) in &table {
+            assert_eq!(error.code(), ErrorCode { code: *code, name }, "{}", error);
+        }
+        #[cfg(feature = "mqtt")]
+        assert_eq!(table.len(), ERROR_CODES.len());
+    }
+
+    #[test]
+    fn error_codes_are_unique() {
+        let codes: HashSet<u8> = ERROR_CODES.iter().map(|(_, code)| *code).collect();
+        let names: HashSet<&str> = ERROR_CODES.iter().map(|(name, _)| *name).collect();
+        assert_eq!((codes.len(), names.len()), (ERROR_CODES.len(), ERROR_CODES.len()));
+        // 文本码前缀与数值码区间一致
+        for (name, code) in ERROR_CODES {
+            let expected = match name.split('.').next().unwrap() {
+                "config" => 20..30,
+                "usb" => 30..50,
+                "mqtt" => 50..60,
+                _ => 60..70,
+            };
+            assert!(expected.contains(code), "{} = {}", name, code);
+        }
+    }
+
+    #[test]
+    fn exit_codes_are_stable() {
+        let expected = [
+            (ExitCategory::Clean, 0),
+            (ExitCategory::Failure, 1),
+            (ExitCategory::Config, 2),
+            (ExitCategory::MqttAuth, 3),
+            (ExitCategory::UsbNotFound, 4),
+            (ExitCategory::UsbFatal, 5),
+        ];
+        for (category, code) in expected {
+            assert_eq!(category.exit_code(), code);
+        }
+        assert_eq!(EXIT_CODES.len(), expected.len());
+    }
+
+    #[test]
+    fn strict_mode_exit_codes_do_not_overlap_the_categories() {
+        for (_, code) in crate::strict::EXIT_CODES {
+            assert!(EXIT_CODES.iter().all(|(_, category)| category != code), "{}", code);
+        }
+    }
+
+    #[test]
+    fn errors_map_to_exit_categories() {
+        let config_errors: Vec<DaemonError> = vec![
+            ConfigError::Missing("MQTT_BROKER_HOST").into(),
+            ConfigError::Invalid { key: "MQTT_PORT", value: "x".to_string(), reason: "not a number".to_string() }.into(),
+            ConfigError::File { path: PathBuf::from("ups.toml"), reason: "missing".to_string() }.into(),
+            ConfigError::Tls { broker: "main".to_string(), source: TlsError::Incomplete("MQTT_CLIENT_KEY") }.into(),
+            TlsError::NoCertificate(PathBuf::from("ca.pem")).into(),
+        ];
+        for error in config_errors {
+            assert_eq!(error.exit_category(), ExitCategory::Config, "{}", error);
+        }
+        #[cfg(feature = "mqtt")]
+        {
+            assert_eq!(DaemonError::from(WizardError::Exists(PathBuf::from("ups.toml"))).exit_category(), ExitCategory::Config);
+            // 普通的连接错误由事件循环重连，走到退出时只算一般失败
+            assert_eq!(DaemonError::from(rumqttc::ConnectionError::RequestsDone).exit_category(), ExitCategory::Failure);
+        }
+        assert_eq!(DaemonError::MqttFatal("not authorized".to_string()).exit_category(), ExitCategory::MqttAuth);
+
+        // 只有启动期限内未找到设备单独归类，其余 USB 错误都是不可恢复的 USB 错误
+        assert_eq!(DaemonError::from(UsbError::DeviceNotFound("no device".to_string())).exit_category(), ExitCategory::UsbNotFound);
+        let usb_errors = [
+            UsbError::OpenFailed(rusb::Error::Access),
+            UsbError::ClaimInterfaceFailed { source: rusb::Error::Busy, hint: None },
+            UsbError::Timeout,
+            UsbError::UnsupportedProtocol { device: 9, supported: 2 },
+            UsbError::Other("?".to_string()),
+        ];
+        for error in usb_errors {
+            assert_eq!(DaemonError::from(error).exit_category(), ExitCategory::UsbFatal);
+        }
+        assert_eq!(DaemonError::from(io_error()).exit_category(), ExitCategory::Failure);
+        assert_eq!(DaemonError::from(json_error()).exit_category(), ExitCategory::Failure);
+    }
+
+    #[test]
+    fn only_unsupported_protocols_are_unrecoverable() {
+        assert!(UsbError::UnsupportedProtocol { device: 9, supported: 2 }.is_unrecoverable());
+        assert!(!UsbError::Timeout.is_unrecoverable());
+        assert!(!UsbError::DeviceNotFound("no device".to_string()).is_unrecoverable());
+        assert!(!UsbError::RusbError(rusb::Error::NoDevice).is_unrecoverable());
+    }
+
+    #[test]
+    fn typed_sources_are_kept() {
+        let error = DaemonError::from(UsbError::OpenFailed(rusb::Error::Access));
+        let source = error.source().expect("透明包装保留 UsbError 的来源");
+        assert_eq!(source.downcast_ref::<rusb::Error>(), Some(&rusb::Error::Access));
+        let claim = UsbError::ClaimInterfaceFailed { source: rusb::Error::Busy, hint: Some("detach the kernel driver") };
+        assert_eq!(claim.source().unwrap().downcast_ref::<rusb::Error>(), Some(&rusb::Error::Busy));
+        assert!(claim.to_string().ends_with("(detach the kernel driver)"));
+        let io = DaemonError::from(UsbError::IoError(io_error()));
+        assert_eq!(io.source().unwrap().downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn last_error_carries_the_code_and_message() {
+        let error = DaemonError::from(UsbError::Timeout);
+        let payload = serde_json::to_value(LastError::new(&error, Some("UPS01"), 1234)).unwrap();
+        assert_eq!(
+            payload,
+            serde_json::json!({
+                "code": 44, "name": "usb.timeout", "message": "USB operation timed out", "device": "UPS01", "ts_unix_ms": 1234,
+            })
+        );
+        let payload = serde_json::to_value(LastError::new(&error, None, 1234)).unwrap();
+        assert!(payload.get("device").is_none());
+    }
+}
@@ -0,0 +1,223 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::data_models::{AllMeasurements, TimestampedMeasurements};
+
+/// 扁平化后的字段值
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Number(f64),
+    Bool(bool),
+    Text(String),
+}
+
+impl FieldValue {
+    /// 数值视图：布尔值映射为 0/1，文本无数值
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            FieldValue::Number(n) => Some(*n),
+            FieldValue::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+            FieldValue::Text(_) => None,
+        }
+    }
+}
+
+/// 数值的文本形式。数据模型中的测量值多为 f32，经 f64 输出会带出 12.300000190734863 这样的尾数，
+/// 因此能无损表示为 f32 的值按 f32 的最短形式输出
+pub fn format_number(n: f64) -> String {
+    let narrow = n as f32;
+    if narrow as f64 == n {
+        narrow.to_string()
+    } else {
+        n.to_string()
+    }
+}
+
+/// 把任意可序列化的数据模型展开为 `(点分隔键, 值)` 列表，
+/// 例如 `bq25730.vbat`、`bq76920.cell_voltages.0`。键名直接来自 serde 序列化结果，
+/// 数据模型新增字段后无需手工维护。
+pub fn flatten<T: Serialize>(value: &T) -> Vec<(String, FieldValue)> {
+    let mut out = Vec::new();
+    if let Ok(json) = serde_json::to_value(value) {
+        flatten_value(String::new(), &json, &mut out);
+    }
+    out
+}
+
+/// 展开一帧数据，测量值的键去掉 `data.` 前缀（如 `bq25730.vbat`、`derived.cell_min`、`frame_id`），
+/// 用作 CSV 列名与 InfluxDB 字段名
+pub fn flatten_sample(sample: &TimestampedMeasurements) -> Vec<(String, FieldValue)> {
+    flatten(sample)
+        .into_iter()
+        .map(|(key, value)| match key.strip_prefix("data.") {
+            Some(stripped) => (stripped.to_string(), value),
+            None => (key, value),
+        })
+        .collect()
+}
+
+fn flatten_value(key: String, value: &Value, out: &mut Vec<(String, FieldValue)>) {
+    let child = |name: &str| {
+        if key.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", key, name)
+        }
+    };
+    match value {
+        Value::Object(map) => {
+            for (name, v) in map {
+                flatten_value(child(name), v, out);
+            }
+        }
+        Value::Array(items) => {
+            for (i, v) in items.iter().enumerate() {
+                flatten_value(child(&i.to_string()), v, out);
+            }
+        }
+        Value::Number(n) => {
+            if let Some(n) = n.as_f64() {
+                out.push((key, FieldValue::Number(n)));
+            }
+        }
+        Value::Bool(b) => out.push((key, FieldValue::Bool(*b))),
+        Value::String(s) => out.push((key, FieldValue::Text(s.clone()))),
+        Value::Null => {}
+    }
+}
+
+/// 按扁平键读取单个数值字段的访问器。键在构造时解析一次，之后每个样本直接读取结构体字段，
+/// 不再经过序列化。键名与 `flatten` 的结果一致（不含 `data.` 前缀），文本字段 (`mos_status`) 没有数值，不可订阅
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldAccessor {
+    key: String,
+    field: Field,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Psys,
+    Vbus,
+    Idchg,
+    Ichg,
+    Cmpin,
+    Iin,
+    Vbat,
+    Vsys,
+    Cell(usize),
+    CellCount,
+    Ts1,
+    IsThermistor,
+    CoulombCounter,
+    SystemStatus,
+    InaVoltage,
+    InaCurrent,
+    InaPower,
+    ChargerStatusFlags,
+    ChargerFaultFlags,
+    ProchotLsbFlags,
+    ProchotMsbFlags,
+    ProchotWidth,
+    AlertSystemStatus,
+}
+
+impl FieldAccessor {
+    /// 解析扁平键，如 `bq25730.vbat`、`bq76920.cell_voltages.2`；未知键或非数值字段返回 None
+    pub fn resolve(key: &str) -> Option<Self> {
+        let field = match key {
+            "bq25730.psys" => Field::Psys,
+            "bq25730.vbus" => Field::Vbus,
+            "bq25730.idchg" => Field::Idchg,
+            "bq25730.ichg" => Field::Ichg,
+            "bq25730.cmpin" => Field::Cmpin,
+            "bq25730.iin" => Field::Iin,
+            "bq25730.vbat" => Field::Vbat,
+            "bq25730.vsys" => Field::Vsys,
+            "bq76920.cell_count" => Field::CellCount,
+            "bq76920.temperatures.ts1" => Field::Ts1,
+            "bq76920.temperatures.is_thermistor" => Field::IsThermistor,
+            "bq76920.coulomb_counter" => Field::CoulombCounter,
+            "bq76920.system_status" => Field::SystemStatus,
+            "ina226.voltage" => Field::InaVoltage,
+            "ina226.current" => Field::InaCurrent,
+            "ina226.power" => Field::InaPower,
+            "bq25730_alerts.charger_status_flags" => Field::ChargerStatusFlags,
+            "bq25730_alerts.charger_fault_flags" => Field::ChargerFaultFlags,
+            "bq25730_alerts.prochot_lsb_flags" => Field::ProchotLsbFlags,
+            "bq25730_alerts.prochot_msb_flags" => Field::ProchotMsbFlags,
+            "bq25730_alerts.prochot_width" => Field::ProchotWidth,
+            "bq76920_alerts.system_status" => Field::AlertSystemStatus,
+            _ => Field::Cell(key.strip_prefix("bq76920.cell_voltages.")?.parse().ok()?),
+        };
+        Some(FieldAccessor { key: key.to_string(), field })
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// 读取字段数值；布尔值映射为 0/1，标志位为寄存器数值。
+    /// 缺失的读数（无 INA226、TS1 无效、超出 `cell_count` 的电芯）返回 None
+    pub fn get<const N: usize>(&self, data: &AllMeasurements<N>) -> Option<f64> {
+        let bool_value = |b: bool| if b { 1.0 } else { 0.0 };
+        let value = match self.field {
+            Field::Psys => data.bq25730.psys as f64,
+            Field::Vbus => data.bq25730.vbus as f64,
+            Field::Idchg => data.bq25730.idchg as f64,
+            Field::Ichg => data.bq25730.ichg as f64,
+            Field::Cmpin => data.bq25730.cmpin as f64,
+            Field::Iin => data.bq25730.iin as f64,
+            Field::Vbat => data.bq25730.vbat as f64,
+            Field::Vsys => data.bq25730.vsys as f64,
+            Field::Cell(index) => *data.bq76920.cells().get(index)? as f64,
+            Field::CellCount => data.bq76920.cell_count as f64,
+            Field::Ts1 => data.bq76920.temperatures.ts1? as f64,
+            Field::IsThermistor => bool_value(data.bq76920.temperatures.is_thermistor),
+            Field::CoulombCounter => data.bq76920.coulomb_counter as f64,
+            Field::SystemStatus => data.bq76920.system_status.bits() as f64,
+            Field::InaVoltage => data.ina226.as_ref()?.voltage as f64,
+            Field::InaCurrent => data.ina226.as_ref()?.current as f64,
+            Field::InaPower => data.ina226.as_ref()?.power as f64,
+            Field::ChargerStatusFlags => data.bq25730_alerts.charger_status_flags.bits() as f64,
+            Field::ChargerFaultFlags => data.bq25730_alerts.charger_fault_flags.bits() as f64,
+            Field::ProchotLsbFlags => data.bq25730_alerts.prochot_lsb_flags.bits() as f64,
+            Field::ProchotMsbFlags => data.bq25730_alerts.prochot_msb_flags.bits() as f64,
+            Field::ProchotWidth => data.bq25730_alerts.prochot_width as f64,
+            Field::AlertSystemStatus => data.bq76920_alerts.system_status.bits() as f64,
+        };
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::PayloadBuilder;
+
+    #[test]
+    fn every_numeric_flattened_key_has_an_accessor_with_the_same_value() {
+        let data = PayloadBuilder::new().cell_count(4).ts1_celsius(25.0).ina226(12.0, 0.5).measurements();
+        let flattened = flatten(&data);
+        assert!(flattened.len() > 20);
+        for (key, value) in flattened {
+            let accessor = FieldAccessor::resolve(&key);
+            match value.as_f64() {
+                Some(expected) => {
+                    let accessor = accessor.unwrap_or_else(|| panic!("{} 没有访问器", key));
+                    assert_eq!(accessor.get(&data), Some(expected), "{}", key);
+                }
+                None => assert!(accessor.is_none(), "{} 不是数值字段", key),
+            }
+        }
+    }
+
+    #[test]
+    fn missing_readings_and_unknown_keys() {
+        let data = PayloadBuilder::new().cell_count(3).without_ina226().measurements();
+        assert_eq!(FieldAccessor::resolve("bq76920.cell_voltages.3").unwrap().get(&data), None);
+        assert!(FieldAccessor::resolve("bq76920.cell_voltages.2").unwrap().get(&data).is_some());
+        assert_eq!(FieldAccessor::resolve("ina226.power").unwrap().get(&data), None);
+        assert_eq!(FieldAccessor::resolve("bq25730.nonexistent"), None);
+        assert_eq!(FieldAccessor::resolve("bq76920.cell_voltages.x"), None);
+    }
+}
@@ -0,0 +1,242 @@
+//! 测量数据的指数滑动平均 (EMA) 平滑。
+//!
+//! 只作用于浮点测量值，标志位与状态字段原样通过。每个字段的系数 alpha 取值 (0, 1]，
+//! 1 表示不平滑（默认）；越小越平滑，响应也越慢。
+
+use std::collections::BTreeMap;
+
+use crate::config::ConfigError;
+use crate::data_models::AllMeasurements;
+
+/// 可平滑的字段，键名与 `[topics]` 一致，另加 `ts2`/`ts3` 与 INA226 字段
+pub const FILTER_FIELDS: &[&str] = &[
+    "psys",
+    "vbus",
+    "idchg",
+    "ichg",
+    "cmpin",
+    "iin",
+    "vbat",
+    "vsys",
+    "cell0",
+    "cell1",
+    "cell2",
+    "cell3",
+    "cell4",
+    "ts1",
+    "ts2",
+    "ts3",
+    "coulomb_counter",
+    "ina226_voltage",
+    "ina226_current",
+    "ina226_power",
+];
+
+const FIELD_COUNT: usize = FILTER_FIELDS.len();
+
+/// 各字段的 alpha，顺序与 `FILTER_FIELDS` 一致
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterAlphas([f32; FIELD_COUNT]);
+
+impl Default for FilterAlphas {
+    fn default() -> Self {
+        FilterAlphas([1.0; FIELD_COUNT])
+    }
+}
+
+impl FilterAlphas {
+    /// 所有字段使用 `default`，再按 `overrides` 逐字段覆盖
+    pub fn new(default: f32, overrides: &BTreeMap<String, f32>) -> Result<Self, ConfigError> {
+        check_alpha("FILTER_ALPHA", &default.to_string(), default)?;
+        let mut alphas = [default; FIELD_COUNT];
+        for (key, &alpha) in overrides {
+            let Some(index) = FILTER_FIELDS.iter().position(|k| k == key) else {
+                return Err(ConfigError::Invalid {
+                    key: "filter",
+                    value: key.clone(),
+                    reason: "unknown field".to_string(),
+                });
+            };
+            check_alpha("filter", key, alpha)?;
+            alphas[index] = alpha;
+        }
+        Ok(FilterAlphas(alphas))
+    }
+}
+
+fn check_alpha(key: &'static str, value: &str, alpha: f32) -> Result<(), ConfigError> {
+    if alpha > 0.0 && alpha <= 1.0 {
+        Ok(())
+    } else {
+        Err(ConfigError::Invalid {
+            key,
+            value: format!("{} = {}", value, alpha),
+            reason: "alpha must be in (0, 1]".to_string(),
+        })
+    }
+}
+
+/// 每块设备一个的 EMA 状态
+#[derive(Debug, Clone)]
+pub struct MeasurementFilter {
+    alphas: FilterAlphas,
+    /// 各字段上一次的输出；None 表示该字段尚无数据（或可选字段缺失）
+    state: [Option<f32>; FIELD_COUNT],
+}
+
+impl MeasurementFilter {
+    pub fn new(alphas: FilterAlphas) -> Self {
+        MeasurementFilter {
+            alphas,
+            state: [None; FIELD_COUNT],
+        }
+    }
+
+    /// 丢弃平滑状态，下一帧原样输出。设备重新连接后调用
+    pub fn reset(&mut self) {
+        self.state = [None; FIELD_COUNT];
+    }
+
+    /// 原地平滑一帧
+    pub fn apply(&mut self, measurements: &mut AllMeasurements<5>) {
+        for (index, field) in float_fields(measurements).into_iter().enumerate() {
+            let Some(value) = field else {
+                self.state[index] = None;
+                continue;
+            };
+            let alpha = self.alphas.0[index];
+            let smoothed = match self.state[index] {
+                Some(previous) if alpha < 1.0 => previous + alpha * (*value - previous),
+                _ => *value,
+            };
+            *value = smoothed;
+            self.state[index] = Some(smoothed);
+        }
+    }
+}
+
+// 与 FILTER_FIELDS 顺序一致；缺失的可选温度与 INA226 读数为 None
+fn float_fields(m: &mut AllMeasurements<5>) -> [Option<&mut f32>; FIELD_COUNT] {
+    let [cell0, cell1, cell2, cell3, cell4] = &mut m.bq76920.cell_voltages;
+    let temperatures = &mut m.bq76920.temperatures;
+    let (ina226_voltage, ina226_current, ina226_power) = match &mut m.ina226 {
+        Some(ina226) => (Some(&mut ina226.voltage), Some(&mut ina226.current), Some(&mut ina226.power)),
+        None => (None, None, None),
+    };
+    [
+        Some(&mut m.bq25730.psys),
+        Some(&mut m.bq25730.vbus),
+        Some(&mut m.bq25730.idchg),
+        Some(&mut m.bq25730.ichg),
+        Some(&mut m.bq25730.cmpin),
+        Some(&mut m.bq25730.iin),
+        Some(&mut m.bq25730.vbat),
+        Some(&mut m.bq25730.vsys),
+        Some(cell0),
+        Some(cell1),
+        Some(cell2),
+        Some(cell3),
+        Some(cell4),
+        temperatures.ts1.as_mut(),
+        temperatures.ts2.as_mut(),
+        temperatures.ts3.as_mut(),
+        Some(&mut m.bq76920.coulomb_counter),
+        ina226_voltage,
+        ina226_current,
+        ina226_power,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::data_models::{ChargerFaultFlags, SystemStatus};
+    use crate::test_support::PayloadBuilder;
+
+    fn smoothing(alpha: f32) -> MeasurementFilter {
+        MeasurementFilter::new(FilterAlphas::new(alpha, &BTreeMap::new()).unwrap())
+    }
+
+    fn vbat(mv: u16) -> AllMeasurements<5> {
+        PayloadBuilder::new().vbat_mv(mv).measurements()
+    }
+
+    #[test]
+    fn the_default_alpha_passes_samples_through() {
+        let mut filter = smoothing(1.0);
+        for mv in [16_000, 18_000, 17_000] {
+            let mut m = vbat(mv);
+            filter.apply(&mut m);
+            assert_eq!(m, vbat(mv));
+        }
+    }
+
+    #[test]
+    fn a_step_converges_to_the_new_value() {
+        let mut filter = smoothing(0.5);
+        let mut first = vbat(16_000);
+        filter.apply(&mut first);
+        assert_eq!(first.bq25730.vbat, vbat(16_000).bq25730.vbat);
+
+        let target = vbat(18_000).bq25730.vbat;
+        let mut previous_error = f32::MAX;
+        for _ in 0..20 {
+            let mut m = vbat(18_000);
+            filter.apply(&mut m);
+            let error = (target - m.bq25730.vbat).abs();
+            assert!(error < previous_error || error == 0.0);
+            previous_error = error;
+        }
+        assert!(previous_error < 1e-3, "{}", previous_error);
+    }
+
+    #[test]
+    fn flags_pass_through_untouched() {
+        let mut filter = smoothing(0.2);
+        filter.apply(&mut vbat(16_000));
+        let faulted = PayloadBuilder::new()
+            .vbat_mv(18_000)
+            .system_status(SystemStatus::UV | SystemStatus::CC_READY)
+            .charger_faults(ChargerFaultFlags::FAULT_ACOC)
+            .measurements();
+        let mut m = faulted;
+        filter.apply(&mut m);
+        assert_ne!(m.bq25730.vbat, faulted.bq25730.vbat);
+        assert_eq!((m.bq76920.system_status, m.bq76920.mos_status), (faulted.bq76920.system_status, faulted.bq76920.mos_status));
+        assert_eq!((m.bq25730_alerts, m.bq76920_alerts), (faulted.bq25730_alerts, faulted.bq76920_alerts));
+        assert_eq!(m.bq76920.cell_count, faulted.bq76920.cell_count);
+    }
+
+    #[test]
+    fn reset_drops_the_state_from_before_a_reconnect() {
+        let mut filter = smoothing(0.1);
+        filter.apply(&mut vbat(16_000));
+        filter.reset();
+        let mut m = vbat(18_000);
+        filter.apply(&mut m);
+        assert_eq!(m, vbat(18_000));
+    }
+
+    #[test]
+    fn a_missing_reading_restarts_that_field_only() {
+        let mut filter = smoothing(0.5);
+        filter.apply(&mut PayloadBuilder::new().ina226(12.0, 1.0).vbat_mv(16_000).measurements());
+        filter.apply(&mut PayloadBuilder::new().without_ina226().vbat_mv(16_000).measurements());
+        let mut m = PayloadBuilder::new().ina226(24.0, 1.0).vbat_mv(18_000).measurements();
+        filter.apply(&mut m);
+        assert_eq!(m.ina226.unwrap().voltage, 24.0);
+        assert!(m.bq25730.vbat < vbat(18_000).bq25730.vbat);
+    }
+
+    #[test]
+    fn alphas_are_validated() {
+        assert!(FilterAlphas::new(0.0, &BTreeMap::new()).is_err());
+        assert!(FilterAlphas::new(1.5, &BTreeMap::new()).is_err());
+        let overrides = BTreeMap::from([("vbat".to_string(), 0.3)]);
+        let alphas = FilterAlphas::new(1.0, &overrides).unwrap();
+        assert_eq!(alphas.0[FILTER_FIELDS.iter().position(|k| *k == "vbat").unwrap()], 0.3);
+        let unknown = BTreeMap::from([("vbus_typo".to_string(), 0.3)]);
+        assert!(matches!(FilterAlphas::new(1.0, &unknown), Err(ConfigError::Invalid { key: "filter", .. })));
+    }
+}
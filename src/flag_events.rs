@@ -0,0 +1,364 @@
+//! 状态/故障标志的边沿检测。
+//!
+//! 状态主题每帧都会重发一遍，无法看出故障是何时出现的。这里比较相邻两帧的告警结构，
+//! 只在某一位发生变化时生成事件，发布到 `{prefix}/events`。
+//!
+//! 适配器接触不良时 STAT_AC 等标志可能每秒跳变数次。`FlagDebouncer` 为每个标志维护一个小状态机：
+//! 新状态持续超过该标志的防抖时间才成为事件，状态主题仍逐帧更新。
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::config::ConfigError;
+use crate::data_models::{
+    Bq25730Alerts, Bq76920Alerts, ChargerFaultFlags, ChargerStatusFlags, ProchotLsbFlags, ProchotMsbFlags,
+    SystemStatus,
+};
+
+pub fn events_topic(measurement_prefix: &str) -> String {
+    format!("{}/events", measurement_prefix)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Register {
+    ChargerStatus,
+    ChargerFault,
+    ProchotLsb,
+    ProchotMsb,
+    SystemStatus,
+}
+
+impl Register {
+    fn bits(self, bq25730: &Bq25730Alerts, bq76920: &Bq76920Alerts) -> u8 {
+        match self {
+            Register::ChargerStatus => bq25730.charger_status_flags.bits(),
+            Register::ChargerFault => bq25730.charger_fault_flags.bits(),
+            Register::ProchotLsb => bq25730.prochot_lsb_flags.bits(),
+            Register::ProchotMsb => bq25730.prochot_msb_flags.bits(),
+            Register::SystemStatus => bq76920.system_status.bits(),
+        }
+    }
+}
+
+/// 事件中的标志名与对应状态主题的键相同
+const FLAGS: &[(&str, Register, u8)] = &[
+    ("charger_stat_ac", Register::ChargerStatus, ChargerStatusFlags::STAT_AC.bits()),
+    ("charger_ico_done", Register::ChargerStatus, ChargerStatusFlags::ICO_DONE.bits()),
+    ("charger_in_vap", Register::ChargerStatus, ChargerStatusFlags::IN_VAP.bits()),
+    ("charger_in_vindpm", Register::ChargerStatus, ChargerStatusFlags::IN_VINDPM.bits()),
+    ("charger_in_iin_dpm", Register::ChargerStatus, ChargerStatusFlags::IN_IIN_DPM.bits()),
+    ("charger_in_fchrg", Register::ChargerStatus, ChargerStatusFlags::IN_FCHRG.bits()),
+    ("charger_in_pchrg", Register::ChargerStatus, ChargerStatusFlags::IN_PCHRG.bits()),
+    ("charger_in_otg", Register::ChargerStatus, ChargerStatusFlags::IN_OTG.bits()),
+    ("charger_fault_acov", Register::ChargerFault, ChargerFaultFlags::FAULT_ACOV.bits()),
+    ("charger_fault_batoc", Register::ChargerFault, ChargerFaultFlags::FAULT_BATOC.bits()),
+    ("charger_fault_acoc", Register::ChargerFault, ChargerFaultFlags::FAULT_ACOC.bits()),
+    ("charger_fault_sysovp", Register::ChargerFault, ChargerFaultFlags::FAULT_SYSOVP.bits()),
+    ("charger_fault_vsys_uvp", Register::ChargerFault, ChargerFaultFlags::FAULT_VSYS_UVP.bits()),
+    ("charger_fault_conv_off", Register::ChargerFault, ChargerFaultFlags::FAULT_CONV_OFF.bits()),
+    ("charger_fault_otg_ovp", Register::ChargerFault, ChargerFaultFlags::FAULT_OTG_OVP.bits()),
+    ("charger_fault_otg_uvp", Register::ChargerFault, ChargerFaultFlags::FAULT_OTG_UVP.bits()),
+    ("prochot_lsb_stat_vindpm", Register::ProchotLsb, ProchotLsbFlags::STAT_VINDPM.bits()),
+    ("prochot_lsb_stat_comp", Register::ProchotLsb, ProchotLsbFlags::STAT_COMP.bits()),
+    ("prochot_lsb_stat_icrit", Register::ProchotLsb, ProchotLsbFlags::STAT_ICRIT.bits()),
+    ("prochot_lsb_stat_inom", Register::ProchotLsb, ProchotLsbFlags::STAT_INOM.bits()),
+    ("prochot_lsb_stat_idchg1", Register::ProchotLsb, ProchotLsbFlags::STAT_IDCHG1.bits()),
+    ("prochot_lsb_stat_vsys", Register::ProchotLsb, ProchotLsbFlags::STAT_VSYS.bits()),
+    ("prochot_lsb_stat_bat_removal", Register::ProchotLsb, ProchotLsbFlags::STAT_BAT_REMOVAL.bits()),
+    ("prochot_lsb_stat_adpt_removal", Register::ProchotLsb, ProchotLsbFlags::STAT_ADPT_REMOVAL.bits()),
+    ("prochot_msb_en_prochot_ext", Register::ProchotMsb, ProchotMsbFlags::EN_PROCHOT_EXT.bits()),
+    ("prochot_msb_prochot_clear", Register::ProchotMsb, ProchotMsbFlags::PROCHOT_CLEAR.bits()),
+    ("prochot_msb_stat_vap_fail", Register::ProchotMsb, ProchotMsbFlags::STAT_VAP_FAIL.bits()),
+    ("prochot_msb_stat_exit_vap", Register::ProchotMsb, ProchotMsbFlags::STAT_EXIT_VAP.bits()),
+    ("system_ocd", Register::SystemStatus, SystemStatus::OCD.bits()),
+    ("system_scd", Register::SystemStatus, SystemStatus::SCD.bits()),
+    ("system_ov", Register::SystemStatus, SystemStatus::OV.bits()),
+    ("system_uv", Register::SystemStatus, SystemStatus::UV.bits()),
+    ("system_ovrd_alert", Register::SystemStatus, SystemStatus::OVRD_ALERT.bits()),
+    ("system_device_xready", Register::SystemStatus, SystemStatus::DEVICE_XREADY.bits()),
+    ("system_cc_ready", Register::SystemStatus, SystemStatus::CC_READY.bits()),
+];
+
+/// 电池保护动作，跳变时以 warn 级别记录
+const PROTECTION_FLAGS: &[&str] = &["system_scd", "system_ocd", "system_ov", "system_uv"];
+
+/// 保护动作以外视为故障的 BQ76920 标志；充电器故障按 `charger_fault_` 前缀判断
+const FAULT_FLAGS: &[&str] = &["system_ovrd_alert", "system_device_xready"];
+
+/// 某一位的一次跳变
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FlagEvent {
+    pub flag: &'static str,
+    pub old: bool,
+    pub new: bool,
+    pub ts_unix_ms: u64,
+}
+
+impl FlagEvent {
+    /// SCD / OCD / OV / UV 等保护动作
+    pub fn is_protection(&self) -> bool {
+        PROTECTION_FLAGS.contains(&self.flag)
+    }
+
+    /// 保护动作、充电器故障或 BQ76920 告警
+    pub fn is_fault(&self) -> bool {
+        self.is_protection() || self.flag.starts_with("charger_fault_") || FAULT_FLAGS.contains(&self.flag)
+    }
+}
+
+/// 各标志的防抖时间，顺序与 `FLAGS` 一致
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlagDebounce(Vec<Duration>);
+
+impl FlagDebounce {
+    /// 所有标志使用 `default`，再按 `overrides` (标志名 -> 毫秒) 逐个覆盖
+    pub fn new(default: Duration, overrides: &BTreeMap<String, u64>) -> Result<Self, ConfigError> {
+        let mut durations = vec![default; FLAGS.len()];
+        for (key, &ms) in overrides {
+            let Some(index) = FLAGS.iter().position(|(flag, _, _)| flag == key) else {
+                return Err(ConfigError::Invalid {
+                    key: "flag_debounce",
+                    value: key.clone(),
+                    reason: "unknown flag".to_string(),
+                });
+            };
+            durations[index] = Duration::from_millis(ms);
+        }
+        Ok(FlagDebounce(durations))
+    }
+}
+
+impl Default for FlagDebounce {
+    /// 不防抖：每次跳变都是事件
+    fn default() -> Self {
+        FlagDebounce(vec![Duration::ZERO; FLAGS.len()])
+    }
+}
+
+/// 单个标志的防抖状态：已确认的状态，以及原始状态开始与之不同的时刻
+#[derive(Debug, Clone, Copy)]
+struct FlagTracker {
+    stable: bool,
+    /// (开始不同的时刻, 该帧的接收时间)
+    pending: Option<(Instant, u64)>,
+}
+
+impl FlagTracker {
+    /// 原始状态与已确认状态不同且持续了 `debounce` 时返回新状态与开始变化的帧时间
+    fn update(&mut self, raw: bool, now: Instant, ts_unix_ms: u64, debounce: Duration) -> Option<(bool, u64)> {
+        if raw == self.stable {
+            self.pending = None;
+            return None;
+        }
+        let (since, since_ts) = *self.pending.get_or_insert((now, ts_unix_ms));
+        if now.duration_since(since) < debounce {
+            return None;
+        }
+        self.stable = raw;
+        self.pending = None;
+        Some((raw, since_ts))
+    }
+}
+
+/// 逐帧输入告警结构，输出经过防抖的标志事件；第一帧只记录初始状态
+#[derive(Debug, Clone)]
+pub struct FlagDebouncer {
+    debounce: FlagDebounce,
+    trackers: Option<Vec<FlagTracker>>,
+}
+
+impl FlagDebouncer {
+    pub fn new(debounce: FlagDebounce) -> Self {
+        FlagDebouncer { debounce, trackers: None }
+    }
+
+    /// 事件的时间为原始状态开始变化的那一帧，而不是确认的时刻
+    pub fn update(&mut self, current: (&Bq25730Alerts, &Bq76920Alerts), ts_unix_ms: u64, now: Instant) -> Vec<FlagEvent> {
+        let raw = FLAGS.iter().map(|&(_, register, mask)| register.bits(current.0, current.1) & mask != 0);
+        let Some(trackers) = self.trackers.as_mut() else {
+            self.trackers = Some(raw.map(|stable| FlagTracker { stable, pending: None }).collect());
+            return Vec::new();
+        };
+        raw.zip(trackers.iter_mut())
+            .zip(FLAGS.iter().zip(&self.debounce.0))
+            .filter_map(|((raw, tracker), (&(flag, _, _), &debounce))| {
+                let old = tracker.stable;
+                tracker
+                    .update(raw, now, ts_unix_ms, debounce)
+                    .map(|(new, ts_unix_ms)| FlagEvent { flag, old, new, ts_unix_ms })
+            })
+            .collect()
+    }
+}
+
+/// 比较前后两帧的告警结构，返回发生变化的位；两帧相同时为空
+pub fn flag_transitions(
+    previous: (&Bq25730Alerts, &Bq76920Alerts),
+    current: (&Bq25730Alerts, &Bq76920Alerts),
+    ts_unix_ms: u64,
+) -> Vec<FlagEvent> {
+    FLAGS
+        .iter()
+        .filter_map(|&(flag, register, mask)| {
+            let old = register.bits(previous.0, previous.1) & mask != 0;
+            let new = register.bits(current.0, current.1) & mask != 0;
+            (old != new).then_some(FlagEvent { flag, old, new, ts_unix_ms })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alerts(system_status: SystemStatus, faults: ChargerFaultFlags) -> (Bq25730Alerts, Bq76920Alerts) {
+        (
+            Bq25730Alerts { charger_fault_flags: faults, ..Bq25730Alerts::default() },
+            Bq76920Alerts { system_status },
+        )
+    }
+
+    fn pair(alerts: &(Bq25730Alerts, Bq76920Alerts)) -> (&Bq25730Alerts, &Bq76920Alerts) {
+        (&alerts.0, &alerts.1)
+    }
+
+    #[test]
+    fn identical_alerts_produce_no_events() {
+        let quiet = alerts(SystemStatus::empty(), ChargerFaultFlags::empty());
+        assert!(flag_transitions(pair(&quiet), pair(&quiet), 1000).is_empty());
+        let tripped = alerts(SystemStatus::all(), ChargerFaultFlags::all());
+        assert!(flag_transitions(pair(&tripped), pair(&tripped), 1000).is_empty());
+    }
+
+    #[test]
+    fn each_changed_bit_is_one_event() {
+        let before = alerts(SystemStatus::UV, ChargerFaultFlags::empty());
+        let after = alerts(SystemStatus::SCD, ChargerFaultFlags::FAULT_ACOC);
+        let events = flag_transitions(pair(&before), pair(&after), 1234);
+        assert_eq!(
+            events,
+            [
+                FlagEvent { flag: "charger_fault_acoc", old: false, new: true, ts_unix_ms: 1234 },
+                FlagEvent { flag: "system_scd", old: false, new: true, ts_unix_ms: 1234 },
+                FlagEvent { flag: "system_uv", old: true, new: false, ts_unix_ms: 1234 },
+            ]
+        );
+        let json = serde_json::to_value(&events[1]).unwrap();
+        assert_eq!(json, serde_json::json!({"flag": "system_scd", "old": false, "new": true, "ts_unix_ms": 1234}));
+    }
+
+    #[test]
+    fn every_flag_is_a_single_distinct_bit() {
+        let names: std::collections::HashSet<_> = FLAGS.iter().map(|(flag, _, _)| flag).collect();
+        assert_eq!(names.len(), FLAGS.len());
+        assert!(FLAGS.iter().all(|&(_, _, mask)| mask.count_ones() == 1));
+        // 全部置位与全部清零之间，每个标志恰好跳变一次
+        let quiet = (Bq25730Alerts::default(), Bq76920Alerts::default());
+        let tripped = (
+            Bq25730Alerts {
+                charger_status_flags: ChargerStatusFlags::all(),
+                charger_fault_flags: ChargerFaultFlags::all(),
+                prochot_lsb_flags: ProchotLsbFlags::all(),
+                prochot_msb_flags: ProchotMsbFlags::all(),
+                prochot_width: 0,
+            },
+            Bq76920Alerts { system_status: SystemStatus::all() },
+        );
+        assert_eq!(flag_transitions(pair(&quiet), pair(&tripped), 0).len(), FLAGS.len());
+    }
+
+    fn stat_ac(on: bool) -> (Bq25730Alerts, Bq76920Alerts) {
+        let flags = if on { ChargerStatusFlags::STAT_AC } else { ChargerStatusFlags::empty() };
+        (Bq25730Alerts { charger_status_flags: flags, ..Bq25730Alerts::default() }, Bq76920Alerts::default())
+    }
+
+    /// 以暂停的 tokio 时钟逐帧输入 STAT_AC；帧时间为自开始起的毫秒数
+    struct Feed {
+        debouncer: FlagDebouncer,
+        start: Instant,
+        step: Duration,
+    }
+
+    impl Feed {
+        fn new(default_ms: u64, overrides: &[(&str, u64)]) -> Self {
+            let overrides = overrides.iter().map(|&(flag, ms)| (flag.to_string(), ms)).collect();
+            let debounce = FlagDebounce::new(Duration::from_millis(default_ms), &overrides).unwrap();
+            Feed { debouncer: FlagDebouncer::new(debounce), start: Instant::now(), step: Duration::from_millis(200) }
+        }
+
+        fn now_ms(&self) -> u64 {
+            Instant::now().duration_since(self.start).as_millis() as u64
+        }
+
+        async fn frames(&mut self, states: impl IntoIterator<Item = bool>) -> Vec<FlagEvent> {
+            let mut events = Vec::new();
+            for on in states {
+                let frame = stat_ac(on);
+                events.extend(self.debouncer.update(pair(&frame), self.now_ms(), Instant::now()));
+                tokio::time::advance(self.step).await;
+            }
+            events
+        }
+    }
+
+    /// 从 `first` 开始交替的 `frames` 帧
+    fn flapping(first: bool, frames: usize) -> impl Iterator<Item = bool> {
+        (0..frames).map(move |i| first ^ (i % 2 == 1))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rapid_flapping_produces_no_events() {
+        let mut feed = Feed::new(5_000, &[]);
+        assert!(feed.frames([false]).await.is_empty());
+        // 每 200 ms 跳变一次，持续一分钟
+        assert!(feed.frames(flapping(true, 300)).await.is_empty());
+        // 每种状态持续 4 s，仍不足防抖时间
+        let slow = (0..20).flat_map(|i| std::iter::repeat_n(i % 2 == 0, 20));
+        assert!(feed.frames(slow).await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flapping_yields_at_most_one_event_per_direction() {
+        let mut feed = Feed::new(5_000, &[]);
+        feed.frames([false]).await;
+
+        // 抖动后稳定在置位：只有一条上升事件，时间为稳定状态开始的那一帧
+        let mut events = feed.frames(flapping(true, 50)).await;
+        let settled_ms = feed.now_ms();
+        events.extend(feed.frames(std::iter::repeat_n(true, 40)).await);
+        assert_eq!(events, [FlagEvent { flag: "charger_stat_ac", old: false, new: true, ts_unix_ms: settled_ms }]);
+
+        // 再抖动后稳定在清除：只有一条下降事件
+        let mut events = feed.frames(flapping(false, 50)).await;
+        let settled_ms = feed.now_ms();
+        events.extend(feed.frames(std::iter::repeat_n(false, 40)).await);
+        assert_eq!(events, [FlagEvent { flag: "charger_stat_ac", old: true, new: false, ts_unix_ms: settled_ms }]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_event_fires_once_the_debounce_has_elapsed() {
+        let mut feed = Feed::new(1_000, &[]);
+        feed.frames([false]).await;
+        // 第 1 帧开始变化，经过 4 个间隔 (800 ms) 仍未确认，第 6 帧 (1000 ms) 确认
+        assert!(feed.frames(std::iter::repeat_n(true, 5)).await.is_empty());
+        assert_eq!(feed.frames([true]).await.len(), 1);
+        assert!(feed.frames(std::iter::repeat_n(true, 20)).await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn per_flag_overrides_apply() {
+        // STAT_AC 不防抖：每次跳变都是事件
+        let mut feed = Feed::new(5_000, &[("charger_stat_ac", 0)]);
+        feed.frames([false]).await;
+        let events = feed.frames(flapping(true, 6)).await;
+        assert_eq!(events.len(), 6);
+        assert!(events.iter().zip(flapping(true, 6)).all(|(event, new)| event.new == new && event.old != new));
+
+        let unknown = BTreeMap::from([("charger_stat_dc".to_string(), 100)]);
+        let err = FlagDebounce::new(Duration::ZERO, &unknown).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { key: "flag_debounce", ref value, .. } if value == "charger_stat_dc"), "{:?}", err);
+    }
+}
@@ -0,0 +1,187 @@
+//! 标志位的名称表示。
+//!
+//! 各 bitflags 类型默认序列化为整数。`FLAGS_AS_NAMES=true` 时 `measurements_all` 中的标志改为
+//! 置位标志名的数组，如 `["OV","CC_READY"]`。反序列化两种形式都接受，因此名称形式的载荷
+//! 仍可读回为 `TimestampedMeasurements`。
+
+use std::fmt;
+
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::data_models::{ChargerFaultFlags, ChargerStatusFlags, ProchotLsbFlags, ProchotMsbFlags, SystemStatus};
+
+/// 带名称表的 8 位标志类型
+pub trait NamedFlags: Copy + Sized + 'static {
+    /// 各位的名称，顺序为寄存器位从低到高
+    const NAMES: &'static [(&'static str, u8)];
+
+    fn bits(&self) -> u8;
+    fn from_bits_truncate(bits: u8) -> Self;
+
+    /// 置位标志的名称；名称表以外的位被忽略
+    fn names(&self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(_, bit)| self.bits() & bit != 0)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<Self, String> {
+        let mut bits = 0;
+        for name in names {
+            let Some((_, bit)) = Self::NAMES.iter().find(|(known, _)| *known == name) else {
+                return Err(format!("unknown flag name '{}'", name));
+            };
+            bits |= bit;
+        }
+        Ok(Self::from_bits_truncate(bits))
+    }
+}
+
+macro_rules! named_flags {
+    ($ty:ident, [$($name:ident),* $(,)?]) => {
+        impl NamedFlags for $ty {
+            const NAMES: &'static [(&'static str, u8)] = &[$((stringify!($name), $ty::$name.bits())),*];
+
+            fn bits(&self) -> u8 {
+                $ty::bits(self)
+            }
+
+            fn from_bits_truncate(bits: u8) -> Self {
+                $ty::from_bits_truncate(bits)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserializer.deserialize_any(FlagsVisitor::<$ty>(std::marker::PhantomData))
+            }
+        }
+    };
+}
+
+named_flags!(SystemStatus, [OCD, SCD, OV, UV, OVRD_ALERT, DEVICE_XREADY, CC_READY]);
+named_flags!(ChargerStatusFlags, [IN_OTG, IN_PCHRG, IN_FCHRG, IN_IIN_DPM, IN_VINDPM, IN_VAP, ICO_DONE, STAT_AC]);
+named_flags!(
+    ChargerFaultFlags,
+    [FAULT_OTG_UVP, FAULT_OTG_OVP, FAULT_CONV_OFF, FAULT_VSYS_UVP, FAULT_SYSOVP, FAULT_ACOC, FAULT_BATOC, FAULT_ACOV]
+);
+named_flags!(
+    ProchotLsbFlags,
+    [STAT_ADPT_REMOVAL, STAT_BAT_REMOVAL, STAT_VSYS, STAT_IDCHG1, STAT_INOM, STAT_ICRIT, STAT_COMP, STAT_VINDPM]
+);
+named_flags!(ProchotMsbFlags, [STAT_EXIT_VAP, STAT_VAP_FAIL, PROCHOT_CLEAR, EN_PROCHOT_EXT]);
+
+/// 接受整数或名称数组
+struct FlagsVisitor<F>(std::marker::PhantomData<F>);
+
+impl<'de, F: NamedFlags> Visitor<'de> for FlagsVisitor<F> {
+    type Value = F;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an 8-bit integer or an array of flag names")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<F, E> {
+        u8::try_from(value)
+            .map(F::from_bits_truncate)
+            .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(value), &self))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<F, E> {
+        u8::try_from(value)
+            .map(F::from_bits_truncate)
+            .map_err(|_| E::invalid_value(de::Unexpected::Signed(value), &self))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<F, A::Error> {
+        let mut names = Vec::new();
+        while let Some(name) = seq.next_element::<String>()? {
+            names.push(name);
+        }
+        F::from_names(names.iter().map(String::as_str)).map_err(de::Error::custom)
+    }
+}
+
+fn names_of<F: NamedFlags>(bits: u8) -> Vec<&'static str> {
+    F::from_bits_truncate(bits).names()
+}
+
+type NamesFn = fn(u8) -> Vec<&'static str>;
+
+/// `TimestampedMeasurements` JSON 中各标志字段的位置与对应类型
+const FLAG_FIELDS: &[(&str, NamesFn)] = &[
+    ("/data/bq76920/system_status", names_of::<SystemStatus>),
+    ("/data/bq76920_alerts/system_status", names_of::<SystemStatus>),
+    ("/data/bq25730_alerts/charger_status_flags", names_of::<ChargerStatusFlags>),
+    ("/data/bq25730_alerts/charger_fault_flags", names_of::<ChargerFaultFlags>),
+    ("/data/bq25730_alerts/prochot_lsb_flags", names_of::<ProchotLsbFlags>),
+    ("/data/bq25730_alerts/prochot_msb_flags", names_of::<ProchotMsbFlags>),
+];
+
+/// 把已序列化的 `TimestampedMeasurements` 中的标志整数原地替换为名称数组
+pub fn flags_to_names(json: &mut Value) {
+    for (pointer, names) in FLAG_FIELDS {
+        if let Some(field) = json.pointer_mut(pointer)
+            && let Some(bits) = field.as_u64().and_then(|bits| u8::try_from(bits).ok())
+        {
+            *field = Value::from(names(bits));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::data_models::TimestampedMeasurements;
+    use crate::test_support::PayloadBuilder;
+
+    #[test]
+    fn names_for_empty_single_and_multiple_flags() {
+        assert!(SystemStatus::empty().names().is_empty());
+        assert_eq!(SystemStatus::OV.names(), ["OV"]);
+        assert_eq!((SystemStatus::CC_READY | SystemStatus::OV).names(), ["OV", "CC_READY"]);
+        assert_eq!((ChargerStatusFlags::STAT_AC | ChargerStatusFlags::IN_FCHRG).names(), ["IN_FCHRG", "STAT_AC"]);
+        assert_eq!(ProchotMsbFlags::all().names().len(), ProchotMsbFlags::NAMES.len());
+    }
+
+    #[test]
+    fn both_forms_deserialize() {
+        for (value, expected) in [
+            (json!([]), SystemStatus::empty()),
+            (json!(0), SystemStatus::empty()),
+            (json!(["SCD"]), SystemStatus::SCD),
+            (json!(["UV", "OCD"]), SystemStatus::UV | SystemStatus::OCD),
+            (json!((SystemStatus::UV | SystemStatus::OCD).bits()), SystemStatus::UV | SystemStatus::OCD),
+        ] {
+            assert_eq!(serde_json::from_value::<SystemStatus>(value.clone()).unwrap(), expected, "{}", value);
+        }
+        for flags in [ChargerFaultFlags::empty(), ChargerFaultFlags::FAULT_ACOV, ChargerFaultFlags::all()] {
+            let names = serde_json::to_value(flags.names()).unwrap();
+            assert_eq!(serde_json::from_value::<ChargerFaultFlags>(names).unwrap(), flags);
+        }
+        assert!(serde_json::from_value::<SystemStatus>(json!(["NOT_A_FLAG"])).is_err());
+        assert!(serde_json::from_value::<SystemStatus>(json!(256)).is_err());
+    }
+
+    #[test]
+    fn a_sample_round_trips_through_the_names_form() {
+        let sample = PayloadBuilder::new()
+            .system_status(SystemStatus::OV | SystemStatus::CC_READY)
+            .charger_faults(ChargerFaultFlags::FAULT_BATOC)
+            .sample(1, 1_700_000_000_000);
+        let mut json = serde_json::to_value(&sample).unwrap();
+        flags_to_names(&mut json);
+        assert_eq!(json["data"]["bq76920"]["system_status"], json!(["OV", "CC_READY"]));
+        assert_eq!(json["data"]["bq25730_alerts"]["charger_fault_flags"], json!(["FAULT_BATOC"]));
+        assert_eq!(json["data"]["bq25730_alerts"]["prochot_msb_flags"], json!([]));
+        let back: TimestampedMeasurements = serde_json::from_value(json).unwrap();
+        assert_eq!(back.data.bq76920.system_status, sample.data.bq76920.system_status);
+        assert_eq!(back.data.bq25730_alerts, sample.data.bq25730_alerts);
+    }
+}
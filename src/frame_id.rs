@@ -0,0 +1,236 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tracing::{error, info, warn};
+
+use crate::utils;
+
+/// 帧号分配器：每个解码成功的数据帧分配一个单调递增的 `frame_id`，重启后也不重复。
+///
+/// 状态文件记录的是"已预留到"的上界而不是当前值：每分配 `batch` 个帧号才写一次文件，
+/// 每次写入都预留下一批。异常退出后从上界继续，最多跳过一批号码，但绝不重复。
+#[derive(Debug)]
+pub struct FrameIdAllocator {
+    next: AtomicU64,
+    reserved_until: AtomicU64,
+    batch: u64,
+    path: Option<PathBuf>,
+    persist_lock: Mutex<()>,
+}
+
+impl FrameIdAllocator {
+    /// 从状态文件恢复；文件不存在时从 0 开始
+    pub fn open(path: impl Into<PathBuf>, batch: u64) -> io::Result<Self> {
+        let path = path.into();
+        let start = match fs::read_to_string(&path) {
+            Ok(text) => text.trim().parse::<u64>().map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        let batch = batch.max(1);
+        let reserved = start + batch;
+        write_atomic(&path, reserved)?;
+        info!("帧号从 {} 开始 (状态文件 {})", start, path.display());
+        Ok(FrameIdAllocator {
+            next: AtomicU64::new(start),
+            reserved_until: AtomicU64::new(reserved),
+            batch,
+            path: Some(path),
+            persist_lock: Mutex::new(()),
+        })
+    }
+
+    /// 不持久化的分配器，状态目录不可用时使用
+    pub fn in_memory() -> Self {
+        FrameIdAllocator {
+            next: AtomicU64::new(0),
+            reserved_until: AtomicU64::new(u64::MAX),
+            batch: 1,
+            path: None,
+            persist_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn next_id(&self) -> u64 {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        if id >= self.reserved_until.load(Ordering::Acquire) {
+            self.reserve_past(id);
+        }
+        id
+    }
+
+    // 当前批次用完时预留下一批
+    fn reserve_past(&self, id: u64) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let _guard = self.persist_lock.lock().unwrap();
+        if id < self.reserved_until.load(Ordering::Acquire) {
+            return;
+        }
+        let reserved = id + 1 + self.batch;
+        // 写入失败时不前移预留位置，下一个帧号再次尝试；否则崩溃后会重复已分配的帧号
+        match write_atomic(path, reserved) {
+            Ok(()) => self.reserved_until.store(reserved, Ordering::Release),
+            Err(e) => error!("写入帧号状态文件 {} 失败: {:?}", path.display(), e),
+        }
+    }
+
+    /// 正常退出时写入确切的下一个帧号，避免跳号
+    pub fn persist(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let _guard = self.persist_lock.lock().unwrap();
+        let next = self.next.load(Ordering::Relaxed);
+        match write_atomic(path, next) {
+            Ok(()) => self.reserved_until.store(next, Ordering::Release),
+            Err(e) => warn!("保存帧号失败: {:?}", e),
+        }
+    }
+}
+
+fn write_atomic(path: &Path, value: u64) -> io::Result<()> {
+    utils::write_atomic(path, format!("{}\n", value).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_state(path: &Path) -> Option<u64> {
+        fs::read_to_string(path).ok().map(|text| text.trim().parse().unwrap())
+    }
+
+    #[test]
+    fn restart_continues_past_reserved_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("frame_id");
+        let ids = FrameIdAllocator::open(&path, 4).unwrap();
+        let issued: Vec<u64> = (0..6).map(|_| ids.next_id()).collect();
+        assert_eq!(issued, vec![0, 1, 2, 3, 4, 5]);
+        // 模拟崩溃：不调用 persist，重启后从预留上界继续
+        let restarted = FrameIdAllocator::open(&path, 4).unwrap();
+        assert!(restarted.next_id() > 5);
+    }
+
+    #[test]
+    fn persist_records_exact_next_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("frame_id");
+        let ids = FrameIdAllocator::open(&path, 100).unwrap();
+        ids.next_id();
+        ids.next_id();
+        ids.persist();
+        assert_eq!(read_state(&path), Some(2));
+        assert_eq!(FrameIdAllocator::open(&path, 100).unwrap().next_id(), 2);
+    }
+
+    #[test]
+    fn failed_reservation_is_retried_on_next_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().join("state");
+        let path = state_dir.join("frame_id");
+        let ids = FrameIdAllocator::open(&path, 2).unwrap();
+        assert_eq!(read_state(&path), Some(2));
+        for _ in 0..3 {
+            ids.next_id();
+        }
+        assert_eq!(read_state(&path), Some(5));
+
+        // 状态目录被同名文件占据，下一次预留写入失败
+        fs::remove_dir_all(&state_dir).unwrap();
+        fs::write(&state_dir, b"").unwrap();
+        ids.next_id();
+        ids.next_id();
+        assert_eq!(ids.next_id(), 5);
+
+        // 写入恢复后，下一个帧号重新预留，而不是沿用未写入的上界
+        fs::remove_file(&state_dir).unwrap();
+        assert_eq!(ids.next_id(), 6);
+        assert_eq!(read_state(&path), Some(9));
+    }
+
+    #[test]
+    fn repeated_crashes_never_reuse_an_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("frame_id");
+        let mut last = None;
+        for run in 0..5 {
+            let ids = FrameIdAllocator::open(&path, 8).unwrap();
+            // 每次运行分配的数量不同，有的跨过预留上界，有的没有
+            for _ in 0..(run * 5 + 1) {
+                let id = ids.next_id();
+                assert!(last.is_none_or(|last| id > last), "帧号 {} 不大于上次的 {:?}", id, last);
+                last = Some(id);
+            }
+        }
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[tokio::test]
+    async fn frame_ids_reach_the_payload_the_data_log_and_anomaly_records() {
+        use crate::datalog::{DataLogSettings, DataLogger, FsyncPolicy};
+        use crate::mqtt_handlers::publish_sample;
+        use crate::publisher::CollectPublisher;
+        use crate::test_support::{push_events, PayloadBuilder};
+        use crate::topics::TopicMap;
+        use crate::usb_types::{ProtocolVersion, UsbEvent};
+
+        let builder = PayloadBuilder::new().without_ina226();
+        let mut anomalous = builder.payload();
+        anomalous.bq76920_mos_status_bits |= 0x04;
+        let frames = [builder.frame(), builder.frame_from(anomalous), builder.vbat_mv(18_400).frame()];
+        let events = push_events(&frames, ProtocolVersion::LEGACY).await;
+
+        let anomaly_ids: Vec<u64> = events
+            .iter()
+            .filter_map(|e| match e {
+                UsbEvent::Anomaly { frame_id, .. } => Some(*frame_id),
+                _ => None,
+            })
+            .collect();
+        let samples: Vec<_> = events
+            .into_iter()
+            .filter_map(|e| match e {
+                UsbEvent::Measurements(sample) => Some(sample),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(samples.iter().map(|s| s.frame_id).collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(anomaly_ids, [1]);
+
+        // 聚合 JSON
+        let collector = CollectPublisher::default();
+        publish_sample(&collector, "ups", &TopicMap::default(), &samples[2]).await.unwrap();
+        let all = collector.into_messages().into_iter().find(|m| m.topic == "ups/measurements_all").unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&all.payload).unwrap();
+        assert_eq!(payload["frame_id"], 2);
+
+        // 数据记录
+        let dir = tempfile::tempdir().unwrap();
+        let settings = DataLogSettings {
+            dir: dir.path().to_path_buf(),
+            max_file_bytes: 1024 * 1024,
+            max_files: 2,
+            fsync: FsyncPolicy::Never,
+            fsync_interval: std::time::Duration::from_secs(60),
+        };
+        let mut logger = DataLogger::new(settings, Default::default()).unwrap();
+        for sample in &samples {
+            logger.write(sample).unwrap();
+        }
+        logger.close().unwrap();
+        let rows: Vec<u64> = fs::read_dir(dir.path())
+            .unwrap()
+            .flat_map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap().lines().map(str::to_string).collect::<Vec<_>>())
+            .map(|line| serde_json::from_str::<serde_json::Value>(&line).unwrap()["frame_id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(rows, [0, 1, 2]);
+    }
+}
@@ -0,0 +1,422 @@
+//! USB 帧的边界判断。
+//!
+//! `HostSideUsbPayload` 加上 1 字节 magic 超过中断端点 64 字节的最大包长，固件可能分多次
+//! 传输发送一帧。读取端按首字节 (magic) 得出整帧长度，不足时继续读取后续分片。
+//!
+//! v1 固件的状态载荷不带 INA226 与 PROCHOT 状态字段，整帧不超过一个包，按实际长度识别。
+//!
+//! 帧可以在已知字段之后带有多余的字节（较新的固件追加了守护进程不认识的字段）：长度校验只要求
+//! 不短于预期，`parse_frame` 解析已知部分并返回被忽略的字节数，计入 USB 链路统计。帧序号只在握手
+//! 得到的协议版本带有序号时读取（见 `SEQUENCE_PROTOCOL_VERSION`），否则同样计为被忽略的字节。
+//!
+//! 所有来自设备的字节都经 `parse_frame` 解析：先校验长度再读取，只读取帧内的字节，任意输入
+//! 都只返回错误。`fuzz/` 下的 cargo-fuzz 目标 `parse_frame` 持续验证这一点。
+
+use std::io::Cursor;
+
+use binrw::BinRead;
+use tracing::debug;
+
+use crate::data_models::HostSideUsbPayload;
+use crate::usb_types::{ProtocolVersion, UsbData, UsbError};
+
+// magic 字节，与 `UsbData` 的定义一致
+pub const MAGIC_SUBSCRIBE: u8 = 0x00;
+pub const MAGIC_UNSUBSCRIBE: u8 = 0x01;
+pub const MAGIC_GET_VERSION: u8 = 0x02;
+pub const MAGIC_GET_STATUS: u8 = 0x03;
+pub const MAGIC_SET_REPORT_INTERVAL: u8 = 0x04;
+pub const MAGIC_STATUS_RESPONSE: u8 = 0x80;
+pub const MAGIC_VERSION_RESPONSE: u8 = 0x82;
+pub const MAGIC_REPORT_INTERVAL_RESPONSE: u8 = 0x84;
+pub const MAGIC_STATUS_PUSH: u8 = 0xC0;
+
+impl HostSideUsbPayload {
+    /// 线上编码的字节数（binrw 按字段顺序紧凑编码，无填充）
+    pub const SIZE: usize = 8 * 2 // BQ25730 ADC
+        + 5 * 4 // 电芯电压
+        + 2 + 1 + 2 + 1 + 2 + 1 // TS1..TS3 与热敏电阻标志
+        + 4 + 1 + 1 // 电流、SYS_STAT、MOS 状态
+        + 3 * 4 // INA226
+        + 2 * 2 // BQ25730 告警
+        + 1; // BQ76920 告警
+
+    /// v1 固件载荷的字节数：没有 INA226 读数与 PROCHOT 状态
+    pub const V1_SIZE: usize = Self::SIZE - 3 * 4 - 2;
+
+    /// 新固件在末尾附带的帧序号的字节数
+    pub const SEQUENCE_SIZE: usize = 2;
+
+    /// 接收时线上编码的字节数（v1 载荷没有 INA226 读数）
+    pub fn encoded_len(&self) -> usize {
+        let layout = if self.ina226_present { PayloadLayout::V2 } else { PayloadLayout::V1 };
+        layout.size() + if self.sequence.is_some() { Self::SEQUENCE_SIZE } else { 0 }
+    }
+}
+
+/// 解析状态载荷的参数，由握手得到的协议版本决定（`ProtocolVersion::payload_args`）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PayloadArgs {
+    /// 已知字段之后带有 u16 帧序号
+    pub sequence: bool,
+}
+
+/// 状态载荷的布局
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadLayout {
+    /// 旧固件：其余字段顺序不变，缺少 INA226 读数与 PROCHOT 状态
+    V1,
+    V2,
+}
+
+impl PayloadLayout {
+    /// 按载荷长度（不含 magic，可带序号）选择布局；不符合任何布局时返回 None
+    pub fn from_payload_len(len: usize) -> Option<Self> {
+        [PayloadLayout::V2, PayloadLayout::V1]
+            .into_iter()
+            .find(|layout| len == layout.size() || len == layout.size() + HostSideUsbPayload::SEQUENCE_SIZE)
+    }
+
+    /// 不含序号的载荷字节数
+    pub fn size(self) -> usize {
+        match self {
+            PayloadLayout::V1 => HostSideUsbPayload::V1_SIZE,
+            PayloadLayout::V2 => HostSideUsbPayload::SIZE,
+        }
+    }
+}
+
+/// 按 magic 得出整帧的最短长度（含 magic）；未知的 magic 返回 None。
+/// 状态帧按 v2 布局计算，也可能是更短的 v1 载荷，见 `check_length`
+pub fn expected_frame_len(magic: u8) -> Option<usize> {
+    match magic {
+        MAGIC_STATUS_RESPONSE | MAGIC_STATUS_PUSH => Some(1 + HostSideUsbPayload::SIZE),
+        // 协议版本 u16 + 固件版本 4 字节
+        MAGIC_VERSION_RESPONSE => Some(1 + 2 + 4),
+        // 推送间隔 u16
+        MAGIC_SET_REPORT_INTERVAL | MAGIC_REPORT_INTERVAL_RESPONSE => Some(1 + 2),
+        MAGIC_SUBSCRIBE | MAGIC_UNSUBSCRIBE | MAGIC_GET_VERSION | MAGIC_GET_STATUS => Some(1),
+        _ => None,
+    }
+}
+
+/// 已接收字节相对整帧的状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameStatus {
+    /// 已收齐（可能多于预期，由解析阶段处理）
+    Complete,
+    /// 还差若干字节
+    Incomplete { got: usize, expected: usize },
+    /// 空数据或未知 magic，无法判断长度，直接交给解析器
+    Unknown,
+}
+
+pub fn frame_status(frame: &[u8]) -> FrameStatus {
+    let Some(expected) = frame.first().copied().and_then(expected_frame_len) else {
+        return FrameStatus::Unknown;
+    };
+    // v1 状态帧不超过一个包，长度恰好符合时不再等待后续分片
+    if frame.len() >= expected || status_layout(frame[0], frame.len()) == Some(PayloadLayout::V1) {
+        FrameStatus::Complete
+    } else {
+        FrameStatus::Incomplete { got: frame.len(), expected }
+    }
+}
+
+// 长度不符时日志与错误中附带的帧头字节数
+const DIAGNOSTIC_HEAD_BYTES: usize = 32;
+
+/// 帧头的十六进制表示（最多 32 字节），用于长度不符时的诊断
+pub fn hex_head(frame: &[u8]) -> String {
+    let head = &frame[..frame.len().min(DIAGNOSTIC_HEAD_BYTES)];
+    let mut hex = head.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+    if frame.len() > DIAGNOSTIC_HEAD_BYTES {
+        hex.push_str(" ..");
+    }
+    hex
+}
+
+// 状态帧按整帧长度 (含 magic) 得出的载荷布局
+fn status_layout(magic: u8, len: usize) -> Option<PayloadLayout> {
+    if !matches!(magic, MAGIC_STATUS_RESPONSE | MAGIC_STATUS_PUSH) {
+        return None;
+    }
+    PayloadLayout::from_payload_len(len.checked_sub(1)?)
+}
+
+/// 解析前校验帧长度：已知 magic 的帧不能短于预期长度（状态帧也可以恰好是 v1 载荷），空帧也视为不符。
+/// 多出的字节由 `parse_frame` 忽略；未知 magic 不在此处理，由解析器报告
+pub fn check_length(frame: &[u8]) -> Result<(), UsbError> {
+    let Some(&magic) = frame.first() else {
+        return Err(UsbError::LengthMismatch {
+            magic: None,
+            got: 0,
+            expected: 1,
+            head: String::new(),
+        });
+    };
+    match expected_frame_len(magic) {
+        Some(expected) if frame.len() < expected && status_layout(magic, frame.len()).is_none() => Err(UsbError::LengthMismatch {
+            magic: Some(magic),
+            got: frame.len(),
+            expected,
+            head: hex_head(frame),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// 解析一帧，返回数据与已知字段之后被忽略的字节数。`protocol` 为握手得到的协议版本，决定状态载荷
+/// 是否带帧序号。先按 magic 校验长度 (`check_length`)，解析只在 `frame` 的范围内读取；
+/// 任意输入都只会返回错误，不会 panic
+///
+/// ```
+/// use ups120_daemon::framing::parse_frame;
+/// use ups120_daemon::usb_types::{ProtocolVersion, UsbError};
+///
+/// let protocol = ProtocolVersion::LEGACY;
+/// assert!(matches!(parse_frame(&[], &protocol), Err(UsbError::LengthMismatch { .. })));
+/// assert!(matches!(parse_frame(&[0xC0, 0x01, 0x02], &protocol), Err(UsbError::LengthMismatch { .. })));
+/// assert!(matches!(parse_frame(&[0x7F; 80], &protocol), Err(UsbError::BinrwError(_))));
+/// ```
+pub fn parse_frame(frame: &[u8], protocol: &ProtocolVersion) -> Result<(UsbData, usize), UsbError> {
+    check_length(frame)?;
+    let mut cursor = Cursor::new(frame);
+    let data = UsbData::read_le_args(&mut cursor, protocol.payload_args())?;
+    let ignored = frame.len().saturating_sub(cursor.position() as usize);
+    if ignored > 0 {
+        debug!(magic = frame[0], len = frame.len(), ignored, "忽略帧末尾的多余字节，固件可能比守护进程新");
+    }
+    Ok((data, ignored))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::PayloadBuilder;
+    use crate::usb_types::SEQUENCE_PROTOCOL_VERSION;
+
+    const SEQUENCED: ProtocolVersion = ProtocolVersion { protocol: SEQUENCE_PROTOCOL_VERSION, firmware: None };
+
+    fn padded(mut frame: Vec<u8>, extra: usize) -> Vec<u8> {
+        frame.extend(std::iter::repeat_n(0xA5, extra));
+        frame
+    }
+
+    fn status(frame: &[u8], protocol: &ProtocolVersion) -> (HostSideUsbPayload, usize) {
+        match parse_frame(frame, protocol).unwrap() {
+            (UsbData::StatusPush(payload), ignored) => (payload, ignored),
+            (other, _) => panic!("expected StatusPush, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn frame_padded_with_16_bytes_parses_and_counts_them() {
+        let builder = PayloadBuilder::new().cell_mv(2, 3650);
+        let frame = padded(builder.frame(), 16);
+        assert_eq!(frame.len(), 1 + HostSideUsbPayload::SIZE + 16);
+        let (payload, ignored) = status(&frame, &ProtocolVersion::LEGACY);
+        assert_eq!(ignored, 16);
+        assert_eq!(payload.sequence, None);
+        assert!(payload.ina226_present);
+        assert_eq!(payload.bq76920_cell3_mv, 3650);
+    }
+
+    #[test]
+    fn sequence_is_read_only_when_the_protocol_has_one() {
+        let frame = padded(PayloadBuilder::new().sequence(7).frame(), 16);
+        let (payload, ignored) = status(&frame, &SEQUENCED);
+        assert_eq!((payload.sequence, ignored), (Some(7), 16));
+
+        // 旧协议的固件追加的字节不是序号
+        let (payload, ignored) = status(&frame, &ProtocolVersion::LEGACY);
+        assert_eq!((payload.sequence, ignored), (None, 18));
+    }
+
+    #[test]
+    fn sequenced_protocol_without_sequence_is_an_error() {
+        let frame = PayloadBuilder::new().frame();
+        assert!(matches!(parse_frame(&frame, &SEQUENCED), Err(UsbError::BinrwError(_))));
+    }
+
+    #[test]
+    fn v1_layout_is_recognised_by_exact_length() {
+        let builder = PayloadBuilder::new().without_ina226().cell_mv(0, 3500);
+        let frame = builder.frame();
+        assert_eq!(frame.len(), 1 + HostSideUsbPayload::V1_SIZE);
+        assert_eq!(frame_status(&frame), FrameStatus::Complete);
+        let (payload, ignored) = status(&frame, &ProtocolVersion::LEGACY);
+        assert_eq!(ignored, 0);
+        assert!(!payload.ina226_present);
+        assert_eq!(payload.bq76920_cell1_mv, 3500);
+    }
+
+    #[test]
+    fn short_status_frame_is_a_length_mismatch() {
+        let frame = PayloadBuilder::new().frame();
+        let short = &frame[..frame.len() - 3];
+        assert_eq!(frame_status(short), FrameStatus::Incomplete { got: short.len(), expected: frame.len() });
+        assert!(matches!(parse_frame(short, &ProtocolVersion::LEGACY), Err(UsbError::LengthMismatch { .. })));
+    }
+
+    #[test]
+    fn payload_size_matches_the_binrw_layout() {
+        let builder = PayloadBuilder::new().ina226(18.25, -0.5);
+        let frame = builder.frame();
+        // magic 之后恰好是 SIZE 字节，解析正好用完且字段不错位
+        assert_eq!(frame.len(), 1 + HostSideUsbPayload::SIZE);
+        assert_eq!(expected_frame_len(frame[0]), Some(frame.len()));
+        let (payload, ignored) = status(&frame, &ProtocolVersion::LEGACY);
+        assert_eq!(ignored, 0);
+        assert_eq!(payload.encoded_len(), HostSideUsbPayload::SIZE);
+        let mut rewritten = Cursor::new(Vec::new());
+        binrw::BinWrite::write_be(&payload, &mut rewritten).unwrap();
+        assert_eq!(rewritten.into_inner(), frame[1..]);
+        assert_eq!(crate::conversion::to_measurements::<5>(&payload, &Default::default()), builder.measurements());
+    }
+
+    #[test]
+    fn zero_length_frame_reports_no_magic() {
+        match parse_frame(&[], &ProtocolVersion::LEGACY) {
+            Err(UsbError::LengthMismatch { magic, got, expected, head }) => {
+                assert_eq!((magic, got, expected, head.as_str()), (None, 0, 1, ""));
+            }
+            other => panic!("expected LengthMismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncated_frames_carry_magic_lengths_and_a_hex_head() {
+        let builder = PayloadBuilder::new();
+        for frame in [builder.frame(), builder.response_frame()] {
+            let truncated = &frame[..40];
+            match parse_frame(truncated, &ProtocolVersion::LEGACY) {
+                Err(UsbError::LengthMismatch { magic, got, expected, head }) => {
+                    assert_eq!((magic, got, expected), (Some(frame[0]), 40, frame.len()));
+                    // 只转储前 32 字节
+                    assert_eq!(head, format!("{} ..", hex_head(&truncated[..DIAGNOSTIC_HEAD_BYTES])));
+                    assert!(head.starts_with(&format!("{:02x} ", frame[0])));
+                }
+                other => panic!("expected LengthMismatch, got {:?}", other),
+            }
+        }
+        // 长于 v1 但不足 v2 的帧同样是截断
+        let v1 = PayloadBuilder::new().without_ina226().frame();
+        let between = padded(v1, 3);
+        assert!(matches!(parse_frame(&between, &ProtocolVersion::LEGACY), Err(UsbError::LengthMismatch { .. })));
+    }
+
+    #[test]
+    fn oversized_frames_parse_and_count_the_trailing_bytes() {
+        let frame = padded(PayloadBuilder::new().response_frame(), 64);
+        assert!(check_length(&frame).is_ok());
+        match parse_frame(&frame, &ProtocolVersion::LEGACY).unwrap() {
+            (UsbData::StatusResponse(_), ignored) => assert_eq!(ignored, 64),
+            (other, _) => panic!("expected StatusResponse, got {:?}", other),
+        }
+    }
+
+    // fuzz/corpus/parse_frame 中的帧与解析它们所用的协议
+    fn corpus() -> Vec<(&'static str, &'static [u8], ProtocolVersion)> {
+        macro_rules! frame {
+            ($name:literal, $protocol:expr) => {
+                ($name, &include_bytes!(concat!("../fuzz/corpus/parse_frame/", $name))[..], $protocol)
+            };
+        }
+        vec![
+            frame!("report_interval_response", ProtocolVersion::LEGACY),
+            frame!("status_push_trailing", ProtocolVersion::LEGACY),
+            frame!("status_push_truncated", ProtocolVersion::LEGACY),
+            frame!("status_push_v1", ProtocolVersion::LEGACY),
+            frame!("status_push_v1_sequence", SEQUENCED),
+            frame!("status_push_v2", ProtocolVersion::LEGACY),
+            frame!("status_push_v2_sequence", SEQUENCED),
+            frame!("status_response_v2", ProtocolVersion::LEGACY),
+            frame!("unknown_magic", ProtocolVersion::LEGACY),
+            frame!("version_response", ProtocolVersion::LEGACY),
+        ]
+    }
+
+    #[test]
+    fn the_fuzz_corpus_parses_as_named() {
+        for (name, frame, protocol) in corpus() {
+            let result = parse_frame(frame, &protocol);
+            match (name, &result) {
+                ("report_interval_response", Ok((UsbData::ReportIntervalResponse { .. }, 0))) => {}
+                ("version_response", Ok((UsbData::VersionResponse { .. }, 0))) => {}
+                ("status_response_v2", Ok((UsbData::StatusResponse(payload), 0))) => assert!(payload.ina226_present),
+                ("status_push_v2", Ok((UsbData::StatusPush(payload), 0))) => assert!(payload.ina226_present),
+                ("status_push_v1", Ok((UsbData::StatusPush(payload), 0))) => assert!(!payload.ina226_present),
+                ("status_push_v2_sequence" | "status_push_v1_sequence", Ok((UsbData::StatusPush(payload), 0))) => {
+                    assert!(payload.sequence.is_some())
+                }
+                ("status_push_trailing", Ok((UsbData::StatusPush(_), ignored))) => assert!(*ignored > 0),
+                ("status_push_truncated", Err(UsbError::LengthMismatch { .. })) => {}
+                ("unknown_magic", Err(UsbError::BinrwError(_))) => {}
+                _ => panic!("{}: {:?}", name, result),
+            }
+        }
+    }
+
+    // 与 fuzz 目标相同的检查：解析成功的状态帧继续走异常检查、转换与序列化
+    fn exercise(frame: &[u8]) {
+        let _ = frame_status(frame);
+        let _ = hex_head(frame);
+        for protocol in [ProtocolVersion::LEGACY, SEQUENCED] {
+            if let Ok((data, ignored)) = parse_frame(frame, &protocol) {
+                assert!(ignored < frame.len());
+                if let UsbData::StatusPush(payload) | UsbData::StatusResponse(payload) = data {
+                    let ctx = crate::conversion::ConversionContext::default();
+                    let _ = crate::diagnostics::check_payload(&payload, ctx.cell_count);
+                    let measurements = crate::conversion::to_measurements::<5>(&payload, &ctx);
+                    let _ = measurements.efficiency_percent(0.0);
+                    let _ = measurements.bq76920.derived();
+                    serde_json::to_string(&measurements).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_and_mutated_corpus_frames_never_panic() {
+        for (_, frame, _) in corpus() {
+            for len in 0..=frame.len() {
+                exercise(&frame[..len]);
+            }
+            let mut mutated = frame.to_vec();
+            for i in 0..mutated.len() {
+                let original = mutated[i];
+                for byte in [0x00, 0x7F, 0x80, 0xFF, !original] {
+                    mutated[i] = byte;
+                    exercise(&mutated);
+                }
+                mutated[i] = original;
+            }
+        }
+    }
+
+    #[test]
+    fn random_frames_never_panic() {
+        // xorshift64：固定种子，失败可复现
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..5_000 {
+            let len = (next() % 160) as usize;
+            let mut frame: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            // 一半的帧带已知的 magic，才能走到载荷解析
+            if let Some(first) = frame.first_mut()
+                && next() % 2 == 0
+            {
+                let magics = [MAGIC_STATUS_PUSH, MAGIC_STATUS_RESPONSE, MAGIC_VERSION_RESPONSE, MAGIC_REPORT_INTERVAL_RESPONSE];
+                *first = magics[(next() % magics.len() as u64) as usize];
+            }
+            exercise(&frame);
+        }
+    }
+}
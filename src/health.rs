@@ -0,0 +1,334 @@
+//! 守护进程的健康状态：各组件的状态集中保存在 `DaemonState` 中，由主循环经 watch 通道更新，
+//! `GET /healthz` 与 `{prefix}/daemon/health` 按 `HealthRules` 从中得出 ok / degraded / failing。
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+#[cfg(feature = "mqtt")]
+use rumqttc::{AsyncClient, QoS};
+use serde::Serialize;
+#[cfg(feature = "mqtt")]
+use tokio::sync::watch;
+use tokio::time::Instant;
+#[cfg(feature = "mqtt")]
+use tokio_util::sync::CancellationToken;
+#[cfg(feature = "mqtt")]
+use tracing::error;
+
+use crate::debug_dump::DebugDump;
+use crate::usb_types::DeviceId;
+use crate::utils::unix_ms_now;
+
+pub fn health_topic(topic_prefix: &str) -> String {
+    format!("{}/daemon/health", topic_prefix)
+}
+
+/// 各组件的当前状态
+#[derive(Debug, Clone)]
+pub struct DaemonState {
+    pub started_at: Instant,
+    pub mqtt_connected: bool,
+    /// 最近一次连接断开或失败的原因
+    pub mqtt_last_error: Option<String>,
+    /// 首次连接之后的重连次数
+    pub mqtt_reconnects: u64,
+    /// 最近一次断开的时间；从未连接时为 None（按运行时间计算）
+    pub mqtt_down_since: Option<Instant>,
+    /// 当前已连接的 USB 设备
+    pub usb_devices: HashSet<DeviceId>,
+    /// 最近一帧推送数据的接收时间（所有设备）
+    pub usb_last_push: Option<Instant>,
+    /// 首次连接之后的 USB 重连次数（所有设备合计）
+    pub usb_reconnects: u64,
+    /// 等待发布到主 broker 的样本数
+    pub publish_queue_depth: usize,
+    /// 与 USB 管理任务共享的原始帧转储开关
+    pub debug_dump: DebugDump,
+    mqtt_seen: bool,
+    usb_seen: HashSet<DeviceId>,
+}
+
+impl Default for DaemonState {
+    fn default() -> Self {
+        DaemonState {
+            started_at: Instant::now(),
+            mqtt_connected: false,
+            mqtt_last_error: None,
+            mqtt_reconnects: 0,
+            mqtt_down_since: None,
+            usb_devices: HashSet::new(),
+            usb_last_push: None,
+            usb_reconnects: 0,
+            publish_queue_depth: 0,
+            debug_dump: DebugDump::default(),
+            mqtt_seen: false,
+            usb_seen: HashSet::new(),
+        }
+    }
+}
+
+impl DaemonState {
+    pub fn on_mqtt_connected(&mut self) {
+        if self.mqtt_seen {
+            self.mqtt_reconnects += 1;
+        }
+        self.mqtt_seen = true;
+        self.mqtt_connected = true;
+        self.mqtt_down_since = None;
+    }
+
+    pub fn on_mqtt_error(&mut self, reason: &str, at: Instant) {
+        if self.mqtt_connected {
+            self.mqtt_down_since = Some(at);
+        }
+        self.mqtt_connected = false;
+        self.mqtt_last_error = Some(reason.to_string());
+    }
+
+    pub fn on_usb_connected(&mut self, device: &DeviceId) {
+        if !self.usb_seen.insert(device.clone()) {
+            self.usb_reconnects += 1;
+        }
+        self.usb_devices.insert(device.clone());
+    }
+
+    pub fn on_usb_detached(&mut self, device: &DeviceId) {
+        self.usb_devices.remove(device);
+    }
+
+    pub fn on_push(&mut self, at: Instant) {
+        self.usb_last_push = Some(at);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Ok,
+    Degraded,
+    Failing,
+}
+
+/// 判定规则
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HealthRules {
+    /// 超过该时间没有推送数据视为 degraded
+    pub push_degraded_after: Duration,
+    /// 超过该时间没有推送数据视为 failing
+    pub push_failing_after: Duration,
+    /// MQTT 断开超过该时间视为 failing，之前为 degraded（样本在缓冲）
+    pub mqtt_failing_after: Duration,
+    /// 待发布样本数达到该值视为 degraded
+    pub queue_degraded_depth: usize,
+}
+
+impl Default for HealthRules {
+    fn default() -> Self {
+        HealthRules {
+            push_degraded_after: Duration::from_secs(10),
+            push_failing_after: Duration::from_secs(60),
+            mqtt_failing_after: Duration::from_secs(300),
+            queue_degraded_depth: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MqttHealth {
+    pub connected: bool,
+    pub last_error: Option<String>,
+    pub reconnects: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UsbHealth {
+    pub device_present: bool,
+    pub devices: usize,
+    /// 距最近一帧推送的时间；尚未收到时为 None
+    pub last_push_age_ms: Option<u64>,
+    pub reconnects: u64,
+}
+
+/// `/healthz` 与 `{prefix}/daemon/health` 的内容
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    /// 得出当前状态的原因，ok 时为空
+    pub reasons: Vec<String>,
+    pub uptime_secs: u64,
+    pub mqtt: MqttHealth,
+    pub usb: UsbHealth,
+    pub publish_queue_depth: usize,
+    /// 原始帧转储是否打开
+    pub debug_dump: bool,
+    pub ts_unix_ms: u64,
+}
+
+/// 按规则评估当前状态；取各项中最差的结果
+pub fn evaluate(state: &DaemonState, rules: &HealthRules, now: Instant) -> HealthReport {
+    let uptime = now.saturating_duration_since(state.started_at);
+    let push_age = state.usb_last_push.map(|at| now.saturating_duration_since(at));
+    let mut status = HealthStatus::Ok;
+    let mut reasons = Vec::new();
+    let mut flag = |level: HealthStatus, reason: String| {
+        status = status.max(level);
+        reasons.push(reason);
+    };
+
+    if state.usb_devices.is_empty() {
+        // 启动后给设备留出首次连接的时间
+        let level = if uptime >= rules.push_failing_after { HealthStatus::Failing } else { HealthStatus::Degraded };
+        flag(level, "no USB device connected".to_string());
+    }
+    // 尚未收到过推送时按运行时间计算
+    let silent_for = push_age.unwrap_or(uptime);
+    if silent_for >= rules.push_failing_after {
+        flag(HealthStatus::Failing, format!("no push for {}s", silent_for.as_secs()));
+    } else if silent_for >= rules.push_degraded_after {
+        flag(HealthStatus::Degraded, format!("no push for {}s", silent_for.as_secs()));
+    }
+    if !state.mqtt_connected {
+        let down_for = state.mqtt_down_since.map_or(uptime, |at| now.saturating_duration_since(at));
+        let level = if down_for >= rules.mqtt_failing_after { HealthStatus::Failing } else { HealthStatus::Degraded };
+        flag(level, format!("MQTT disconnected for {}s", down_for.as_secs()));
+    }
+    if state.publish_queue_depth >= rules.queue_degraded_depth {
+        flag(HealthStatus::Degraded, format!("{} samples queued", state.publish_queue_depth));
+    }
+
+    HealthReport {
+        status,
+        reasons,
+        uptime_secs: uptime.as_secs(),
+        mqtt: MqttHealth {
+            connected: state.mqtt_connected,
+            last_error: state.mqtt_last_error.clone(),
+            reconnects: state.mqtt_reconnects,
+        },
+        usb: UsbHealth {
+            device_present: !state.usb_devices.is_empty(),
+            devices: state.usb_devices.len(),
+            last_push_age_ms: push_age.map(|age| age.as_millis() as u64),
+            reconnects: state.usb_reconnects,
+        },
+        publish_queue_depth: state.publish_queue_depth,
+        debug_dump: state.debug_dump.is_enabled(),
+        ts_unix_ms: unix_ms_now(),
+    }
+}
+
+/// 按周期把健康状态发布到 `{prefix}/daemon/health`
+#[cfg(feature = "mqtt")]
+pub async fn health_publish_task(
+    client: AsyncClient,
+    topic_prefix: String,
+    state: watch::Receiver<DaemonState>,
+    rules: HealthRules,
+    interval: Duration,
+    shutdown: CancellationToken,
+) {
+    let topic = health_topic(&topic_prefix);
+    let mut timer = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = timer.tick() => {}
+        }
+        let report = evaluate(&state.borrow(), &rules, Instant::now());
+        match serde_json::to_string(&report) {
+            Ok(payload) => {
+                if let Err(e) = client.publish(&topic, QoS::AtLeastOnce, false, payload).await {
+                    error!(topic = %topic, error = ?e, "发布健康状态失败");
+                }
+            }
+            Err(e) => error!("序列化健康状态失败: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    fn device() -> DeviceId {
+        DeviceId::Serial("UPS01".to_string())
+    }
+
+    // 运行 10 分钟、MQTT 已连接、设备在线且刚刚推送过
+    fn healthy(start: Instant) -> (DaemonState, Instant) {
+        let mut state = DaemonState { started_at: start, ..DaemonState::default() };
+        let now = start + 600 * SEC;
+        state.on_mqtt_connected();
+        state.on_usb_connected(&device());
+        state.on_push(now - SEC);
+        (state, now)
+    }
+
+    #[test]
+    fn a_connected_daemon_with_fresh_pushes_is_ok() {
+        let (state, now) = healthy(Instant::now());
+        let report = evaluate(&state, &HealthRules::default(), now);
+        assert_eq!(report.status, HealthStatus::Ok);
+        assert!(report.reasons.is_empty());
+        assert_eq!(report.uptime_secs, 600);
+        assert_eq!(report.usb.last_push_age_ms, Some(1000));
+        assert!(report.usb.device_present && report.mqtt.connected);
+    }
+
+    #[test]
+    fn push_age_degrades_then_fails() {
+        let rules = HealthRules::default();
+        let (mut state, now) = healthy(Instant::now());
+        state.on_push(now - 10 * SEC);
+        assert_eq!(evaluate(&state, &rules, now).status, HealthStatus::Degraded);
+        state.on_push(now - 60 * SEC);
+        let report = evaluate(&state, &rules, now);
+        assert_eq!(report.status, HealthStatus::Failing);
+        assert_eq!(report.reasons, ["no push for 60s"]);
+    }
+
+    #[test]
+    fn mqtt_outages_degrade_until_the_failing_threshold() {
+        let rules = HealthRules::default();
+        let (mut state, now) = healthy(Instant::now());
+        state.on_mqtt_error("connection refused", now - 299 * SEC);
+        let report = evaluate(&state, &rules, now);
+        assert_eq!(report.status, HealthStatus::Degraded);
+        assert_eq!(report.mqtt.last_error.as_deref(), Some("connection refused"));
+        assert_eq!(evaluate(&state, &rules, now + SEC).status, HealthStatus::Failing);
+
+        state.on_mqtt_connected();
+        assert_eq!(state.mqtt_reconnects, 1);
+        assert_eq!(evaluate(&state, &rules, now).status, HealthStatus::Ok);
+    }
+
+    #[test]
+    fn the_worst_component_wins() {
+        let rules = HealthRules::default();
+        let (mut state, now) = healthy(Instant::now());
+        state.publish_queue_depth = rules.queue_degraded_depth;
+        assert_eq!(evaluate(&state, &rules, now).status, HealthStatus::Degraded);
+        state.on_usb_detached(&device());
+        state.on_push(now - 120 * SEC);
+        let report = evaluate(&state, &rules, now);
+        assert_eq!(report.status, HealthStatus::Failing);
+        assert_eq!(report.reasons, ["no USB device connected", "no push for 120s", "100 samples queued"]);
+
+        state.on_usb_connected(&device());
+        assert_eq!(state.usb_reconnects, 1);
+    }
+
+    #[test]
+    fn startup_is_degraded_not_failing() {
+        let start = Instant::now();
+        let state = DaemonState { started_at: start, ..DaemonState::default() };
+        let rules = HealthRules::default();
+        assert_eq!(evaluate(&state, &rules, start + 5 * SEC).status, HealthStatus::Degraded);
+        assert_eq!(evaluate(&state, &rules, start + 60 * SEC).status, HealthStatus::Failing);
+        let report = evaluate(&state, &rules, start);
+        assert_eq!(report.usb.last_push_age_ms, None);
+        assert_eq!(report.mqtt.reconnects, 0);
+    }
+}
@@ -0,0 +1,513 @@
+//! 本地 HTTP 接口 (`HTTP_LISTEN`)。
+//!
+//! 只读、无认证，供局域网内直接 `curl` 查询，不必经过 MQTT。请求量很小，这里直接在
+//! tokio 的 TCP 连接上处理 HTTP/1.1 的 GET 请求，每个连接只处理一个请求。
+//!
+//! - `GET /api/v1/measurements`：最近一帧测量数据与设备信息；尚未收到数据时返回 503
+//! - `GET /api/v1/health`：守护进程是否已收到数据；尚未收到时返回 503
+//! - `GET /healthz`：各组件的状态与总体状态 (ok / degraded / failing)；failing 时返回 503
+//! - `GET /api/v1/alarms`：当前激活的阈值告警，按主题前缀分组
+//! - `GET /api/v1/stream`：WebSocket，连接后先发送最近一帧，之后每收到一帧发送一条 JSON 文本消息
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_tungstenite::tokio::TokioAdapter;
+use async_tungstenite::tungstenite::handshake::derive_accept_key;
+use async_tungstenite::tungstenite::protocol::Role;
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use futures::{SinkExt, StreamExt};
+use tracing::{debug, error, info, warn};
+use serde::Serialize;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::alarms::ActiveAlarm;
+use crate::data_models::{DeviceInfo, TimestampedMeasurements};
+use crate::health::{self, DaemonState, HealthRules, HealthStatus};
+use crate::pipeline::Pipeline;
+use crate::utils::unix_ms_now;
+
+/// 请求头的最大字节数，超出时返回 431
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+/// 读取请求的期限，防止空闲连接一直占用任务
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 各主题前缀下当前激活的告警，由各设备的告警任务更新
+pub type ActiveAlarms = BTreeMap<String, Vec<ActiveAlarm>>;
+
+/// 处理请求所需的只读状态，由主循环与告警任务经 watch 通道更新
+#[derive(Debug, Clone)]
+pub struct ApiState {
+    pub latest: watch::Receiver<Option<Arc<TimestampedMeasurements>>>,
+    /// 最近一帧数据所属设备的信息
+    pub device: watch::Receiver<Option<DeviceInfo>>,
+    pub alarms: watch::Receiver<ActiveAlarms>,
+    /// 各组件的状态，由主循环更新
+    pub daemon: watch::Receiver<DaemonState>,
+    pub health_rules: HealthRules,
+    /// 所有设备的测量数据总线，供 WebSocket 推送
+    pub pipeline: Pipeline,
+    /// 每个 WebSocket 客户端的发送队列长度，溢出时断开该客户端
+    pub stream_queue: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    fn json<T: Serialize>(status: u16, value: &T) -> Self {
+        match serde_json::to_string(value) {
+            Ok(body) => Response { status, body },
+            Err(e) => Response::error(500, &e.to_string()),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response {
+            status,
+            body: json!({ "error": message }).to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MeasurementsResponse<'a> {
+    #[serde(flatten)]
+    sample: &'a TimestampedMeasurements,
+    device: Option<&'a DeviceInfo>,
+}
+
+/// 按方法与路径生成响应，不涉及网络
+pub fn route(method: &str, path: &str, state: &ApiState) -> Response {
+    let path = path.split('?').next().unwrap_or(path);
+    let known = matches!(
+        path,
+        "/api/v1/measurements" | "/api/v1/health" | "/api/v1/alarms" | "/api/v1/stream" | "/healthz"
+    );
+    if !known {
+        return Response::error(404, "not found");
+    }
+    if method != "GET" {
+        return Response::error(405, "method not allowed");
+    }
+    match path {
+        "/api/v1/measurements" => {
+            let latest = state.latest.borrow();
+            let Some(sample) = latest.as_deref() else {
+                return Response::error(503, "no measurement received yet");
+            };
+            let device = state.device.borrow();
+            Response::json(200, &MeasurementsResponse { sample, device: device.as_ref() })
+        }
+        "/api/v1/health" => match state.latest.borrow().as_ref() {
+            Some(sample) => Response::json(
+                200,
+                &json!({
+                    "status": "ok",
+                    "last_frame_id": sample.frame_id,
+                    "last_measurement_age_ms": unix_ms_now().saturating_sub(sample.ts_unix_ms),
+                }),
+            ),
+            None => Response::json(503, &json!({ "status": "waiting_for_data" })),
+        },
+        "/api/v1/alarms" => Response::json(200, &*state.alarms.borrow()),
+        "/healthz" => {
+            let report = health::evaluate(&state.daemon.borrow(), &state.health_rules, Instant::now());
+            let status = if report.status == HealthStatus::Failing { 503 } else { 200 };
+            Response::json(status, &report)
+        }
+        // 带 WebSocket 握手头的请求在 handle_connection 中处理，走到这里说明不是升级请求
+        _ => Response::error(426, "websocket upgrade required"),
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// 监听 `addr` 直到 `shutdown` 取消。绑定失败只记录错误，不影响守护进程的其他功能
+pub async fn http_api_task(addr: SocketAddr, state: ApiState, shutdown: CancellationToken) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("HTTP 接口无法监听 {}: {:?}", addr, e);
+            return;
+        }
+    };
+    info!("HTTP 接口已监听 {}", addr);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let state = state.clone();
+                    let shutdown = shutdown.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, &state, &shutdown).await {
+                            debug!("HTTP 连接 {} 处理失败: {:?}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("HTTP 接口接受连接失败: {:?}", e),
+            },
+        }
+    }
+    info!("HTTP 接口已停止");
+}
+
+async fn handle_connection(mut stream: TcpStream, state: &ApiState, shutdown: &CancellationToken) -> std::io::Result<()> {
+    let response = match tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream)).await {
+        Err(_) => Response::error(408, "request timeout"),
+        Ok(Err(e)) => return Err(e),
+        Ok(Ok(None)) => Response::error(431, "request too large"),
+        Ok(Ok(Some(head))) => {
+            let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some("GET"), Some("/api/v1/stream")) if header(&head, "upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket")) => {
+                    let Some(key) = header(&head, "sec-websocket-key") else {
+                        return write_response(&mut stream, Response::error(400, "missing Sec-WebSocket-Key")).await;
+                    };
+                    let accept = derive_accept_key(key.as_bytes());
+                    return serve_stream(stream, &accept, state, shutdown).await;
+                }
+                (Some(method), Some(path)) => route(method, path, state),
+                _ => Response::error(400, "bad request"),
+            }
+        }
+    };
+    write_response(&mut stream, response).await
+}
+
+async fn write_response(stream: &mut TcpStream, response: Response) -> std::io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason_phrase(response.status),
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// 请求头的值（名称不区分大小写）
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// 完成 WebSocket 握手后推送测量数据，直到客户端断开、发送队列溢出或收到退出信号
+async fn serve_stream(mut stream: TcpStream, accept: &str, state: &ApiState, shutdown: &CancellationToken) -> std::io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    );
+    stream.write_all(head.as_bytes()).await?;
+    let peer = stream.peer_addr()?;
+    let socket = WebSocketStream::from_raw_socket(TokioAdapter::new(stream), Role::Server, None).await;
+    let (mut sink, mut incoming) = socket.split();
+
+    // 写入放在单独的任务中，慢客户端只会让自己的队列堆积
+    let (queue_tx, mut queue_rx) = mpsc::channel::<Message>(state.stream_queue);
+    let writer = tokio::spawn(async move {
+        while let Some(message) = queue_rx.recv().await {
+            if sink.send(message).await.is_err() {
+                return;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    // 先订阅再取快照，两者之间到达的帧按帧号去重
+    let mut samples = state.pipeline.subscribe();
+    let snapshot = state.latest.borrow().clone();
+    let mut sent_frame_id = None;
+    if let Some(sample) = snapshot {
+        sent_frame_id = Some(sample.frame_id);
+        if let Ok(text) = serde_json::to_string(&*sample) {
+            let _ = queue_tx.try_send(Message::Text(text));
+        }
+    }
+    info!("WebSocket 客户端 {} 已连接", peer);
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            received = samples.recv() => match received {
+                Ok(sample) => {
+                    if sent_frame_id.is_some_and(|id| sample.frame_id <= id) {
+                        continue;
+                    }
+                    sent_frame_id = None;
+                    let Ok(text) = serde_json::to_string(&*sample) else {
+                        continue;
+                    };
+                    match queue_tx.try_send(Message::Text(text)) {
+                        Ok(()) => {}
+                        Err(mpsc::error::TrySendError::Full(_)) => {
+                            warn!("WebSocket 客户端 {} 发送队列已满 ({} 条)，断开", peer, state.stream_queue);
+                            writer.abort();
+                            return Ok(());
+                        }
+                        Err(mpsc::error::TrySendError::Closed(_)) => break,
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("WebSocket 客户端 {} 跳过 {} 帧，断开", peer, n);
+                    writer.abort();
+                    return Ok(());
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            message = incoming.next() => match message {
+                // 客户端消息（含 ping）由 tungstenite 处理，只关心断开
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    drop(queue_tx);
+    let _ = writer.await;
+    info!("WebSocket 客户端 {} 已断开", peer);
+    Ok(())
+}
+
+// 读到空行为止；超过上限时返回 None
+async fn read_request_head(stream: &mut TcpStream) -> std::io::Result<Option<String>> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST_BYTES {
+            return Ok(None);
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(Some(String::from_utf8_lossy(&buf).into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::alarms::ActiveAlarm;
+    use crate::data_models::Severity;
+    use crate::test_support::PayloadBuilder;
+
+    struct Harness {
+        state: ApiState,
+        latest: watch::Sender<Option<Arc<TimestampedMeasurements>>>,
+        device: watch::Sender<Option<DeviceInfo>>,
+        alarms: watch::Sender<ActiveAlarms>,
+        daemon: watch::Sender<DaemonState>,
+    }
+
+    fn harness() -> Harness {
+        let (latest, latest_rx) = watch::channel(None);
+        let (device, device_rx) = watch::channel(None);
+        let (alarms, alarms_rx) = watch::channel(ActiveAlarms::new());
+        let (daemon, daemon_rx) = watch::channel(DaemonState::default());
+        let state = ApiState {
+            latest: latest_rx,
+            device: device_rx,
+            alarms: alarms_rx,
+            daemon: daemon_rx,
+            health_rules: HealthRules::default(),
+            pipeline: Pipeline::new(16),
+            stream_queue: 4,
+        };
+        Harness { state, latest, device, alarms, daemon }
+    }
+
+    fn body(response: &Response) -> Value {
+        serde_json::from_str(&response.body).unwrap()
+    }
+
+    #[test]
+    fn measurements_are_unavailable_until_the_first_frame() {
+        let harness = harness();
+        let response = route("GET", "/api/v1/measurements", &harness.state);
+        assert_eq!(response.status, 503);
+        assert_eq!(body(&response)["error"], "no measurement received yet");
+        let response = route("GET", "/api/v1/health", &harness.state);
+        assert_eq!((response.status, body(&response)["status"].clone()), (503, Value::from("waiting_for_data")));
+
+        let sample = PayloadBuilder::new().vbat_mv(16_000).sample(42, unix_ms_now());
+        harness.latest.send_replace(Some(Arc::new(sample)));
+        harness.device.send_replace(Some(DeviceInfo {
+            device_id: "UPS01".to_string(),
+            usb_id: "1209:0002".to_string(),
+            bus: 1,
+            address: 4,
+            manufacturer: None,
+            product: None,
+            serial: Some("UPS01".to_string()),
+            protocol_version: 2,
+            firmware_version: None,
+        }));
+        let response = route("GET", "/api/v1/measurements?pretty", &harness.state);
+        assert_eq!(response.status, 200);
+        let json = body(&response);
+        assert_eq!(json["frame_id"], 42);
+        assert_eq!(json["data"]["bq25730"]["vbat"], 16.0);
+        assert_eq!(json["device"]["serial"], "UPS01");
+        let response = route("GET", "/api/v1/health", &harness.state);
+        assert_eq!((response.status, body(&response)["last_frame_id"].clone()), (200, Value::from(42)));
+    }
+
+    #[test]
+    fn alarms_are_grouped_by_prefix() {
+        let harness = harness();
+        assert_eq!(body(&route("GET", "/api/v1/alarms", &harness.state)), serde_json::json!({}));
+        let alarm = ActiveAlarm { name: "low_soc".to_string(), severity: Severity::Warning, since_unix_ms: 1000, acknowledged: false };
+        harness.alarms.send_replace(ActiveAlarms::from([("ups".to_string(), vec![alarm])]));
+        let json = body(&route("GET", "/api/v1/alarms", &harness.state));
+        assert_eq!(json["ups"][0]["name"], "low_soc");
+        assert_eq!(json["ups"][0]["severity"], "warning");
+    }
+
+    #[test]
+    fn healthz_is_unavailable_only_when_failing() {
+        let harness = harness();
+        let response = route("GET", "/healthz", &harness.state);
+        assert_eq!((response.status, body(&response)["status"].clone()), (200, Value::from("degraded")));
+        harness.daemon.send_modify(|state| state.started_at -= Duration::from_secs(120));
+        let response = route("GET", "/healthz", &harness.state);
+        assert_eq!((response.status, body(&response)["status"].clone()), (503, Value::from("failing")));
+    }
+
+    #[test]
+    fn unknown_paths_and_methods_are_rejected() {
+        let harness = harness();
+        assert_eq!(route("GET", "/api/v2/measurements", &harness.state).status, 404);
+        assert_eq!(route("POST", "/api/v1/measurements", &harness.state).status, 405);
+        assert_eq!(route("DELETE", "/nope", &harness.state).status, 404);
+    }
+
+    async fn get(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    // 先占用一个空闲端口再释放，交给 http_api_task 绑定
+    fn free_addr() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    async fn serve(state: ApiState) -> (SocketAddr, CancellationToken, tokio::task::JoinHandle<()>) {
+        let addr = free_addr();
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(http_api_task(addr, state, shutdown.clone()));
+        for _ in 0..100 {
+            if TcpStream::connect(addr).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        (addr, shutdown, task)
+    }
+
+    #[tokio::test]
+    async fn serves_http_and_stops_on_shutdown() {
+        let harness = harness();
+        let (addr, shutdown, task) = serve(harness.state.clone()).await;
+        let response = get(addr, "GET /api/v1/measurements HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        assert!(response.ends_with(r#"{"error":"no measurement received yet"}"#));
+
+        harness.latest.send_replace(Some(Arc::new(PayloadBuilder::new().sample(1, unix_ms_now()))));
+        let response = get(addr, "GET /api/v1/measurements HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(get(addr, "garbage\r\n\r\n").await.starts_with("HTTP/1.1 400 "));
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(1), task).await.expect("HTTP 接口应随退出信号停止").unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    async fn next_frame_id<S>(client: &mut S) -> u64
+    where
+        S: futures::Stream<Item = Result<Message, async_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        let message = tokio::time::timeout(Duration::from_secs(2), client.next()).await.expect("应收到一帧").unwrap().unwrap();
+        let Message::Text(text) = message else {
+            panic!("应为文本消息: {:?}", message);
+        };
+        serde_json::from_str::<Value>(&text).unwrap()["frame_id"].as_u64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn stream_sends_the_snapshot_then_each_new_frame() {
+        let harness = harness();
+        assert_eq!(route("GET", "/api/v1/stream", &harness.state).status, 426);
+        harness.latest.send_replace(Some(Arc::new(PayloadBuilder::new().sample(1, 1_700_000_000_000))));
+        let (addr, shutdown, task) = serve(harness.state.clone()).await;
+        let (mut client, _) = async_tungstenite::tokio::connect_async(format!("ws://{}/api/v1/stream", addr)).await.unwrap();
+        assert_eq!(next_frame_id(&mut client).await, 1);
+
+        for frame_id in 2..=4 {
+            harness.state.pipeline.publish(Arc::new(PayloadBuilder::new().sample(frame_id, 1_700_000_000_000 + frame_id)));
+            assert_eq!(next_frame_id(&mut client).await, frame_id);
+        }
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(1), task).await.unwrap().unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(1), client.next()).await.expect("退出时应关闭连接");
+        assert!(matches!(closed, None | Some(Ok(Message::Close(_))) | Some(Err(_))), "{:?}", closed);
+    }
+
+    #[tokio::test]
+    async fn clients_that_fall_behind_are_disconnected() {
+        let harness = harness();
+        let (addr, shutdown, _task) = serve(harness.state.clone()).await;
+        let (mut client, _) = async_tungstenite::tokio::connect_async(format!("ws://{}/api/v1/stream", addr)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // 客户端不读取，总线与发送队列很快溢出
+        const FRAMES: u64 = 5000;
+        for frame_id in 1..=FRAMES {
+            harness.state.pipeline.publish(Arc::new(PayloadBuilder::new().sample(frame_id, frame_id)));
+        }
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut received = 0;
+            while let Some(Ok(Message::Text(_))) = client.next().await {
+                received += 1;
+            }
+            received
+        })
+        .await
+        .expect("慢客户端应被断开");
+        assert!(received < FRAMES, "{}", received);
+        shutdown.cancel();
+    }
+}
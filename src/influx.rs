@@ -0,0 +1,291 @@
+//! InfluxDB v2 输出 (`INFLUX_URL`)。
+//!
+//! 每帧测量数据编码为一行 line protocol，按批 POST 到 `/api/v2/write`，不再需要 Telegraf 转换
+//! MQTT 主题。字段名来自 `fields::flatten_sample`，数据模型新增字段后无需手工维护。写入失败时按
+//! 指数退避重试，期间的数据行暂存在有界缓冲区中，溢出时丢弃最旧的行。
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{debug, error, info, warn};
+use reqwest::{StatusCode, Url};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::backoff::Backoff;
+use crate::buffer::PublishBuffer;
+use crate::data_models::TimestampedMeasurements;
+use crate::fields::{self, FieldValue};
+use crate::usb_types::DeviceId;
+
+// 主循环到写入任务的队列长度；写入任务只负责编码并转入自己的缓冲区
+const INFLUX_QUEUE_SIZE: usize = 64;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_INITIAL: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// InfluxDB 输出的配置
+#[derive(Debug, Clone)]
+pub struct InfluxSettings {
+    /// 服务地址，如 `http://localhost:8086`
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: String,
+    pub measurement: String,
+    /// 每次请求最多写入的行数
+    pub batch_size: usize,
+    /// 不足一批时最长等待这么久再写入
+    pub flush_interval: Duration,
+    /// 服务不可达时最多暂存的行数
+    pub buffer_lines: usize,
+}
+
+impl InfluxSettings {
+    /// `{url}/api/v2/write?org=..&bucket=..&precision=ns`
+    pub fn write_url(&self) -> Result<Url, String> {
+        let mut url = Url::parse(&format!("{}/api/v2/write", self.url.trim_end_matches('/'))).map_err(|e| e.to_string())?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err("expected an http:// or https:// URL".to_string());
+        }
+        url.query_pairs_mut()
+            .append_pair("org", &self.org)
+            .append_pair("bucket", &self.bucket)
+            .append_pair("precision", "ns");
+        Ok(url)
+    }
+}
+
+/// 一帧数据编码为一行 line protocol：`<measurement>,device=<设备标识> <字段> <纳秒时间戳>`。
+/// 字段键见 `fields::flatten_sample`，非有限的数值被跳过；没有任何字段时返回 None
+pub fn encode_line(measurement: &str, device: &str, sample: &TimestampedMeasurements) -> Option<String> {
+    let mut fields = String::new();
+    for (key, value) in fields::flatten_sample(sample) {
+        if key == "ts_unix_ms" {
+            continue;
+        }
+        let value = match value {
+            FieldValue::Number(n) if n.is_finite() => fields::format_number(n),
+            FieldValue::Number(_) => continue,
+            FieldValue::Bool(b) => b.to_string(),
+            FieldValue::Text(s) => format!("\"{}\"", escape(&s, &['"', '\\'])),
+        };
+        if !fields.is_empty() {
+            fields.push(',');
+        }
+        fields.push_str(&escape(&key, &[',', '=', ' ']));
+        fields.push('=');
+        fields.push_str(&value);
+    }
+    if fields.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{},device={} {} {}",
+        escape(measurement, &[',', ' ']),
+        escape(device, &[',', '=', ' ']),
+        fields,
+        sample.ts_unix_ms.saturating_mul(1_000_000)
+    ))
+}
+
+fn escape(text: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if special.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// 主循环持有的句柄；投递样本从不阻塞主循环
+#[derive(Clone)]
+pub struct InfluxHandle {
+    tx: mpsc::Sender<(String, Arc<TimestampedMeasurements>)>,
+}
+
+impl InfluxHandle {
+    pub fn offer(&self, device: &DeviceId, sample: Arc<TimestampedMeasurements>) {
+        if let Err(e) = self.tx.try_send((device.to_string(), sample)) {
+            debug!("InfluxDB 队列已满或已关闭，丢弃样本: {}", e);
+        }
+    }
+}
+
+/// 启动写入任务；地址无效或 HTTP 客户端无法创建时返回错误
+pub fn spawn_influx(settings: InfluxSettings) -> Result<InfluxHandle, String> {
+    let url = settings.write_url()?;
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())?;
+    info!("InfluxDB 输出: {} (bucket {})", settings.url, settings.bucket);
+    let (tx, rx) = mpsc::channel(INFLUX_QUEUE_SIZE);
+    tokio::spawn(influx_write_task(client, url, settings, rx));
+    Ok(InfluxHandle { tx })
+}
+
+enum WriteError {
+    /// 服务拒绝了这批数据（如格式错误、权限不足），重试也不会成功
+    Rejected(StatusCode, String),
+    /// 网络错误、超时或服务端错误，稍后重试
+    Retry(String),
+}
+
+async fn influx_write_task(
+    client: reqwest::Client,
+    url: Url,
+    settings: InfluxSettings,
+    mut rx: mpsc::Receiver<(String, Arc<TimestampedMeasurements>)>,
+) {
+    let mut buffer = PublishBuffer::new(settings.buffer_lines);
+    let mut backoff = Backoff::new(RETRY_INITIAL, RETRY_MAX);
+    let mut retry_at: Option<Instant> = None;
+    let mut reported_dropped = 0;
+    let mut flush_timer = tokio::time::interval(settings.flush_interval);
+    loop {
+        tokio::select! {
+            received = rx.recv() => match received {
+                Some((device, sample)) => {
+                    if let Some(line) = encode_line(&settings.measurement, &device, &sample) {
+                        buffer.push(line);
+                    }
+                    if buffer.len() < settings.batch_size {
+                        continue;
+                    }
+                }
+                None => break,
+            },
+            _ = flush_timer.tick() => {}
+        }
+        if retry_at.is_some_and(|at| Instant::now() < at) {
+            continue;
+        }
+        retry_at = None;
+        while !buffer.is_empty() {
+            let count = buffer.len().min(settings.batch_size);
+            let body = buffer.iter().take(count).map(String::as_str).collect::<Vec<_>>().join("\n");
+            match write_batch(&client, &url, &settings.token, body).await {
+                Ok(()) => {
+                    for _ in 0..count {
+                        buffer.pop_front();
+                    }
+                    backoff.reset();
+                }
+                Err(WriteError::Rejected(status, message)) => {
+                    error!("InfluxDB 拒绝写入 ({}): {}，丢弃 {} 行", status, message, count);
+                    for _ in 0..count {
+                        buffer.pop_front();
+                    }
+                }
+                Err(WriteError::Retry(message)) => {
+                    let delay = backoff.next_delay();
+                    warn!("InfluxDB 写入失败: {}，{:?} 后重试 (缓冲 {} 行)", message, delay, buffer.len());
+                    retry_at = Some(Instant::now() + delay);
+                    break;
+                }
+            }
+        }
+        if buffer.dropped() > reported_dropped {
+            warn!("InfluxDB 缓冲区已满，累计丢弃 {} 行", buffer.dropped());
+            reported_dropped = buffer.dropped();
+        }
+    }
+}
+
+async fn write_batch(client: &reqwest::Client, url: &Url, token: &str, body: String) -> Result<(), WriteError> {
+    let response = client
+        .post(url.clone())
+        .header("Authorization", format!("Token {}", token))
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| WriteError::Retry(e.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let message = response.text().await.unwrap_or_default();
+    if status.is_client_error() && status != StatusCode::REQUEST_TIMEOUT && status != StatusCode::TOO_MANY_REQUESTS {
+        Err(WriteError::Rejected(status, message))
+    } else {
+        Err(WriteError::Retry(format!("{}: {}", status, message)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::PayloadBuilder;
+
+    fn settings(url: &str) -> InfluxSettings {
+        InfluxSettings {
+            url: url.to_string(),
+            org: "home lab".to_string(),
+            bucket: "ups".to_string(),
+            token: "secret".to_string(),
+            measurement: "ups120".to_string(),
+            batch_size: 100,
+            flush_interval: Duration::from_secs(1),
+            buffer_lines: 1000,
+        }
+    }
+
+    // (键, 值) 按出现顺序
+    fn fields(line: &str) -> Vec<(&str, &str)> {
+        let (_, rest) = line.split_once(' ').unwrap();
+        let (fields, _) = rest.rsplit_once(' ').unwrap();
+        fields.split(',').map(|field| field.split_once('=').unwrap()).collect()
+    }
+
+    #[test]
+    fn a_sample_becomes_one_line() {
+        let sample = PayloadBuilder::new().vbat_mv(16_000).cell_mv(0, 3650).sample(7, 1_700_000_000_123);
+        let line = encode_line("ups120", "UPS01", &sample).unwrap();
+        assert!(line.starts_with("ups120,device=UPS01 "), "{}", line);
+        assert!(line.ends_with(" 1700000000123000000"), "{}", line);
+        assert!(!line.contains('\n'));
+        let fields = fields(&line);
+        assert!(fields.contains(&("frame_id", "7")));
+        assert!(fields.contains(&("bq25730.vbat", "16")));
+        assert!(fields.contains(&("bq76920.cell_voltages.0", "3.65")));
+        assert!(fields.contains(&("bq76920.mos_status", "\"BothOn\"")));
+        assert!(fields.iter().all(|(key, _)| *key != "ts_unix_ms"));
+    }
+
+    #[test]
+    fn measurement_tag_and_text_are_escaped() {
+        let sample = PayloadBuilder::new().sample(1, 2);
+        let line = encode_line("ups 120,a=b", "bus 1,addr=4", &sample).unwrap();
+        assert!(line.starts_with(r"ups\ 120\,a=b,device=bus\ 1\,addr\=4 bq25730.cmpin=0,"), "{}", line);
+        assert!(line.ends_with(" 2000000"));
+
+        assert_eq!(escape(r#"say "hi" \o/"#, &['"', '\\']), r#"say \"hi\" \\o/"#);
+        assert_eq!(escape("a,b=c d", &[',', '=', ' ']), r"a\,b\=c\ d");
+        assert_eq!(escape("plain", &[',', '=', ' ']), "plain");
+    }
+
+    #[test]
+    fn non_finite_values_are_skipped() {
+        let mut sample = PayloadBuilder::new().sample(1, 2);
+        sample.data.bq25730.vbat = f32::NAN;
+        sample.data.bq25730.vsys = f32::INFINITY;
+        let line = encode_line("ups120", "UPS01", &sample).unwrap();
+        assert!(!line.contains("bq25730.vbat="), "{}", line);
+        assert!(!line.contains("bq25730.vsys="), "{}", line);
+        assert!(line.contains("bq25730.ichg="));
+    }
+
+    #[test]
+    fn write_url_carries_org_bucket_and_precision() {
+        assert_eq!(
+            settings("http://influx:8086/").write_url().unwrap().as_str(),
+            "http://influx:8086/api/v2/write?org=home+lab&bucket=ups&precision=ns"
+        );
+        assert!(settings("ftp://influx").write_url().is_err());
+        assert!(settings("not a url").write_url().is_err());
+    }
+}
@@ -0,0 +1,265 @@
+//! 最近一次测量数据的保存与启动时恢复。
+//!
+//! 重启后到设备推送第一帧之前，订阅方（如 Home Assistant）只能看到 unknown。每台设备的最新样本
+//! 按间隔写入状态文件；启动时、连接 USB 之前，将其以 retained 方式发布到 `{prefix}/measurements_all`
+//! 并带上 `"stale": true`，数据的年龄 (秒) 发布到 `{prefix}/state_age`。收到新样本后清除这两条
+//! retained 消息，测量数据恢复正常发布。
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
+
+use crate::data_models::TimestampedMeasurements;
+use crate::error::DaemonError;
+use crate::mqtt_handlers::{measurements_all_topic, measurements_json};
+use crate::pipeline::Subscription;
+use crate::topics::{self, TopicMap};
+use crate::utils::{self, unix_ms_now};
+
+/// 状态文件格式版本；结构变化时递增，旧版本的文件直接跳过
+pub const STATE_VERSION: u32 = 1;
+
+/// 状态文件名前缀，多设备时后接设备标识
+pub const STATE_FILE_STEM: &str = "last_state";
+
+/// 恢复的数据距保存时的年龄 (秒)，retained；收到新样本后清除
+pub fn state_age_topic(topic_prefix: &str) -> String {
+    format!("{}/{}", topic_prefix, topics::STATE_AGE)
+}
+
+/// 状态文件内容：发布前缀与最近一帧样本（含接收时间）
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedState {
+    pub version: u32,
+    pub topic_prefix: String,
+    pub sample: TimestampedMeasurements,
+}
+
+impl SavedState {
+    pub fn new(topic_prefix: &str, sample: TimestampedMeasurements) -> Self {
+        SavedState { version: STATE_VERSION, topic_prefix: topic_prefix.to_string(), sample }
+    }
+
+    /// 读取保存的状态；文件不存在时返回 None，内容损坏或版本不符时返回 InvalidData
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let saved: SavedState =
+            serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if saved.version != STATE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported state version {} (expected {})", saved.version, STATE_VERSION),
+            ));
+        }
+        Ok(Some(saved))
+    }
+
+    pub fn persist(&self, path: &Path) -> io::Result<()> {
+        let text = serde_json::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        utils::write_atomic(path, text.as_bytes())
+    }
+}
+
+/// 状态目录中各设备的状态文件
+pub fn state_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let matches = path.extension().is_some_and(|ext| ext == "json")
+            && path.file_stem().is_some_and(|stem| stem.to_string_lossy().starts_with(STATE_FILE_STEM));
+        if matches {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// 启动时发布所有保存的状态；无法读取的文件记录警告后跳过
+pub async fn restore_last_states(client: &AsyncClient, dir: &Path, topics: &TopicMap) {
+    let files = match state_files(dir) {
+        Ok(files) => files,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("读取状态目录 {} 失败: {:?}，不恢复上次的测量数据", dir.display(), e);
+            return;
+        }
+    };
+    for path in files {
+        match SavedState::load(&path) {
+            Ok(Some(saved)) => match publish_restored(client, &saved, topics).await {
+                Ok(age_secs) => info!(
+                    "已恢复上次的测量数据: {} (帧 {}，{} 秒前)",
+                    saved.topic_prefix, saved.sample.frame_id, age_secs
+                ),
+                Err(e) => error!("发布恢复的测量数据失败: {:?}", e),
+            },
+            Ok(None) => {}
+            Err(e) => warn!("跳过无法读取的状态文件 {}: {:?}", path.display(), e),
+        }
+    }
+}
+
+/// 以 retained 方式发布带 `"stale": true` 的测量数据与其年龄，返回年龄 (秒)
+async fn publish_restored(client: &AsyncClient, saved: &SavedState, topics: &TopicMap) -> Result<u64, DaemonError> {
+    let mut json = measurements_json(&saved.sample, topics.flags_as_names(), topics.units())?;
+    if let Value::Object(map) = &mut json {
+        map.insert("stale".to_string(), Value::Bool(true));
+    }
+    let age_secs = unix_ms_now().saturating_sub(saved.sample.ts_unix_ms) / 1000;
+    let names = topics.for_prefix(&saved.topic_prefix);
+    client.publish(names.measurements_all.as_str(), QoS::AtLeastOnce, true, json.to_string()).await?;
+    client.publish(names.state_age.as_str(), QoS::AtLeastOnce, true, age_secs.to_string()).await?;
+    Ok(age_secs)
+}
+
+/// 状态保存任务的配置
+#[derive(Debug, Clone)]
+pub struct LastStateSettings {
+    pub state_path: PathBuf,
+    /// 两次写状态文件之间的最短间隔
+    pub persist_interval: Duration,
+}
+
+/// 订阅测量数据：收到第一帧时清除恢复时发布的 retained 消息，之后按间隔保存最新样本，总线关闭时再保存一次
+pub async fn last_state_task(
+    mut samples: Subscription,
+    client: AsyncClient,
+    topic_prefix: String,
+    settings: LastStateSettings,
+) {
+    let mut latest: Option<Arc<TimestampedMeasurements>> = None;
+    let mut last_persist: Option<tokio::time::Instant> = None;
+    loop {
+        let sample: Arc<TimestampedMeasurements> = match samples.recv().await {
+            Ok(sample) => sample,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if latest.is_none() {
+            // 空的 retained 载荷清除恢复的数据，之后的测量数据照常以非 retained 方式发布
+            for topic in [measurements_all_topic(&topic_prefix), state_age_topic(&topic_prefix)] {
+                if let Err(e) = client.publish(topic, QoS::AtLeastOnce, true, "").await {
+                    error!("清除恢复的测量数据失败: {:?}", e);
+                }
+            }
+        }
+        latest = Some(sample.clone());
+        if last_persist.is_none_or(|at| at.elapsed() >= settings.persist_interval) {
+            last_persist = Some(tokio::time::Instant::now());
+            persist(&settings.state_path, &topic_prefix, &sample);
+        }
+    }
+    if let Some(sample) = latest {
+        persist(&settings.state_path, &topic_prefix, &sample);
+    }
+}
+
+fn persist(path: &Path, topic_prefix: &str, sample: &TimestampedMeasurements) {
+    if let Err(e) = SavedState::new(topic_prefix, sample.clone()).persist(path) {
+        error!("保存最近的测量数据 {} 失败: {:?}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::PayloadBuilder;
+
+    fn saved() -> SavedState {
+        let mut sample = PayloadBuilder::new().sample(7, 1_700_000_000_000);
+        // 原始帧不写入状态文件
+        sample.raw = None;
+        SavedState::new("ups120", sample)
+    }
+
+    fn invalid_data(result: io::Result<Option<SavedState>>) -> bool {
+        matches!(result, Err(e) if e.kind() == io::ErrorKind::InvalidData)
+    }
+
+    #[test]
+    fn a_saved_state_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("last_state.json");
+        assert_eq!(SavedState::load(&path).unwrap(), None);
+
+        saved().persist(&path).unwrap();
+        assert_eq!(SavedState::load(&path).unwrap(), Some(saved()));
+    }
+
+    #[test]
+    fn truncated_or_corrupt_files_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("last_state.json");
+        let text = serde_json::to_string(&saved()).unwrap();
+
+        fs::write(&path, &text[..text.len() / 2]).unwrap();
+        assert!(invalid_data(SavedState::load(&path)));
+        fs::write(&path, "").unwrap();
+        assert!(invalid_data(SavedState::load(&path)));
+        fs::write(&path, [0xff, 0xfe, 0x00, 0x7b]).unwrap();
+        assert!(invalid_data(SavedState::load(&path)));
+        fs::write(&path, r#"{"version":1,"topic_prefix":"ups120"}"#).unwrap();
+        assert!(invalid_data(SavedState::load(&path)));
+    }
+
+    #[test]
+    fn old_or_unknown_versions_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("last_state.json");
+        for version in [0, STATE_VERSION + 1, u32::MAX] {
+            let state = SavedState { version, ..saved() };
+            fs::write(&path, serde_json::to_string(&state).unwrap()).unwrap();
+            assert!(invalid_data(SavedState::load(&path)), "version {}", version);
+        }
+        // 没有版本字段的文件同样跳过
+        let mut json = serde_json::to_value(saved()).unwrap();
+        json.as_object_mut().unwrap().remove("version");
+        fs::write(&path, json.to_string()).unwrap();
+        assert!(invalid_data(SavedState::load(&path)));
+    }
+
+    #[test]
+    fn a_failed_write_keeps_the_previous_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("last_state.json");
+        saved().persist(&path).unwrap();
+
+        // 临时文件的位置被目录占用，写入在替换之前失败
+        fs::create_dir(path.with_extension("tmp")).unwrap();
+        let mut newer = saved();
+        newer.sample.frame_id = 8;
+        assert!(newer.persist(&path).is_err());
+        assert_eq!(SavedState::load(&path).unwrap(), Some(saved()));
+    }
+
+    #[test]
+    fn leftover_temp_files_are_not_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        saved().persist(&dir.path().join("last_state.json")).unwrap();
+        saved().persist(&dir.path().join("last_state_2.json")).unwrap();
+        // 写到一半中断时留下的临时文件
+        fs::write(dir.path().join("last_state_3.tmp"), "{\"version\":").unwrap();
+        fs::write(dir.path().join("other.json"), "{}").unwrap();
+
+        let names: Vec<_> = state_files(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, ["last_state.json", "last_state_2.json"]);
+    }
+}
@@ -1,6 +1,95 @@
+//! UPS120 的设备读取库与 MQTT 守护进程。
+//!
+//! 嵌入其他程序时通常只需要 [`Ups120Client`] 与测量数据类型；MQTT 发布相关的模块在
+//! `mqtt` feature（默认开启）之后，`default-features = false` 时不依赖 rumqttc。
+
 pub mod data_models;
+mod binrw_impls;
 pub mod usb_types;
-pub mod binrw_impls;
+pub mod conversion;
 pub mod usb_handlers;
+#[cfg(feature = "mqtt")]
 pub mod mqtt_handlers;
-pub mod utils; // 声明 utils 模块
\ No newline at end of file
+#[cfg(feature = "mqtt")]
+pub mod publisher;
+pub mod utils; // 声明 utils 模块
+pub mod config;
+pub mod throttle;
+pub mod diagnostics;
+pub mod strict;
+#[cfg(feature = "mqtt")]
+pub mod cli;
+pub mod adaptive;
+pub mod stats;
+pub mod backoff;
+pub mod buffer;
+pub mod platform;
+pub mod fields;
+pub mod pipeline;
+pub mod rules;
+pub mod frame_id;
+#[cfg(feature = "mqtt")]
+pub mod acl_probe;
+pub mod topics;
+#[cfg(feature = "mqtt")]
+pub mod mirror;
+pub mod balance;
+pub mod tls;
+pub mod coalesce;
+#[cfg(feature = "mqtt")]
+pub mod wizard;
+pub mod datalog;
+pub mod device_registry;
+#[cfg(feature = "mqtt")]
+pub mod burst;
+pub mod timing;
+#[cfg(feature = "mqtt")]
+pub mod acks;
+#[cfg(feature = "mqtt")]
+pub mod delivery;
+pub mod framing;
+pub mod transport;
+pub mod soc;
+#[cfg(feature = "mqtt")]
+pub mod energy;
+pub mod runtime;
+pub mod filter;
+pub mod plausibility;
+pub mod alarms;
+pub mod flag_events;
+#[cfg(feature = "mqtt")]
+pub mod coulomb;
+pub mod flag_names;
+pub mod sequence;
+pub mod http_api;
+pub mod influx;
+pub mod csv_logger;
+pub mod systemd;
+pub mod logging;
+pub mod health;
+pub mod nut_server;
+pub mod actions;
+pub mod webhook;
+pub mod capture;
+pub mod replay;
+pub mod simulate;
+pub mod test_support;
+#[cfg(all(test, feature = "mqtt"))]
+mod test_broker;
+pub mod error;
+pub mod client;
+pub mod tasks;
+pub mod debug_dump;
+pub mod ring;
+pub mod units;
+#[cfg(feature = "mqtt")]
+pub mod last_state;
+#[cfg(unix)]
+pub mod control_socket;
+
+pub use client::{TransportSettings, Ups120Client};
+pub use data_models::{
+    AllMeasurements, Bq25730Alerts, Bq25730Measurements, Bq76920Alerts, Bq76920Measurements, ChargerFaultFlags,
+    ChargerStatusFlags, DeviceInfo, Ina226Measurements, MosStatus, SystemStatus, Temperatures, TimestampedMeasurements,
+};
+pub use usb_types::{DeviceCommand, DeviceEvent, DeviceId, UsbCommand, UsbError, UsbEvent};
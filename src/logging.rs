@@ -0,0 +1,65 @@
+//! 日志输出：`LOG_FORMAT=text`（默认）为人读的单行文本，`LOG_FORMAT=json` 时每条记录是一行 JSON 对象，
+//! 包含 `timestamp`、`level`、`target`、`message` 以及设备、主题、错误类别等结构化字段。
+//!
+//! 过滤规则沿用 `RUST_LOG`（默认 `info`）。依赖库（如 rumqttc）经 `log` 输出的记录桥接到同一个订阅者。
+//! 由 systemd 启动且输出接到 journald（设置了 `JOURNAL_STREAM`）时，文本格式省略时间戳与颜色，
+//! 由 journald 记录时间。
+
+use std::env;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use tracing_subscriber::EnvFilter;
+
+/// 日志格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected text/json".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// 初始化全局日志订阅者；`to_stderr` 为 true 时日志写到 stderr（严格模式下 stdout 留给检测摘要）
+pub fn init(format: LogFormat, to_stderr: bool) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let journald = env::var_os("JOURNAL_STREAM").is_some();
+    let writer = move || -> Box<dyn io::Write> {
+        if to_stderr { Box::new(io::stderr()) } else { Box::new(io::stdout()) }
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(writer);
+    let result = match format {
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(false)
+            .try_init(),
+        LogFormat::Text if journald => builder.without_time().with_ansi(false).try_init(),
+        LogFormat::Text => builder.try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("初始化日志失败: {}", e);
+    }
+}
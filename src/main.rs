@@ -1,120 +1,1314 @@
 use dotenv::dotenv;
-use env_logger::{Builder, Target};
-use log::{error, info};
-use std::env;
+use clap::Parser;
+use tracing::{debug, error, info, warn};
+use rumqttc::{AsyncClient, QoS};
+use std::collections::{HashMap, HashSet};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 
 // Ensure UsbEvent is imported correctly and data_models module is available
+#[cfg(unix)]
+use ups120_daemon::control_socket::{control_socket_task, ControlState};
+#[cfg(unix)]
+use ups120_daemon::debug_dump::signal_toggle_task;
 use ups120_daemon::{
+    acks::{publish_and_confirm, AckTracker, TrackedClient},
+    actions::actions_task,
+    acl_probe::acl_probe_task,
+    alarms::{alarm_task, alarm_topic, AlarmAck, AlarmContext},
+    balance::{balance_histogram_task, BalanceSettings},
+    burst::{burst_capture_task, BurstSettings},
+    capture::spawn_capture,
+    adaptive::AimdController,
+    cli::{Cli, Command},
+    config::{ConfigError, DaemonConfig},
+    conversion::ConversionContext,
+    coulomb::{coulomb_task, CoulombSettings},
+    csv_logger::{csv_log_task, CsvLogSettings, CsvLogger},
+    buffer::PublishBuffer,
+    error::{DaemonError, ExitCategory, LastError},
+    data_models::{DeviceInfo, TimestampedMeasurements},
+    debug_dump::{debug_raw_topic, hex, DebugDump},
+    delivery::{drain, PendingSample},
+    datalog::{data_log_task, DataLogSettings, DataLogger},
+    client::Ups120Client,
+    device_registry::DiscoverySettings,
+    diagnostics::FrameAnomaly,
+    energy::{energy_task, EnergySettings},
+    filter::MeasurementFilter,
+    flag_events::{events_topic, FlagDebouncer, FlagEvent},
+    frame_id::FrameIdAllocator,
+    health::{health_publish_task, DaemonState},
+    http_api::{http_api_task, ActiveAlarms, ApiState},
+    influx::{spawn_influx, InfluxHandle},
+    logging,
+    mirror::{spawn_mirror, MirrorHandle},
     mqtt_handlers::*,
+    nut_server::{nut_server_task, NutSource},
+    pipeline::Pipeline,
+    publisher::{dry_run_session, Publisher, TryPublisher},
+    platform::shutdown_signal,
+    replay::{replay_task, ReplaySettings, ReplayTransport},
+    ring::History,
+    last_state::{last_state_task, restore_last_states, LastStateSettings, STATE_FILE_STEM},
+    simulate::{simulation_task, Scenario, SimulationSettings},
+    plausibility::{validate_sample, ImplausibleStreak},
+    rules::{advisory_task, fault_alert_task, power_state_task},
+    runtime::RuntimeEstimator,
+    soc::SocEstimator,
+    stats::DaemonStats,
+    strict::{StrictCondition, StrictReport},
+    systemd::{Heartbeat, Notifier},
+    tasks::TaskSet,
+    throttle::PublishThrottle,
+    topics::TopicMap,
     usb_handlers::*,
-    usb_types::{UsbCommand, UsbEvent}, // UsbEvent is defined in usb_types
+    utils::unix_ms_now,
+    usb_types::{DeviceCommand, DeviceEvent, DeviceId, UsbCommand, UsbError, UsbEvent, UsbMode}, // UsbEvent is defined in usb_types
+    webhook::{spawn_webhooks, WebhookEvent, WebhookHandle},
+    wizard,
 };
 
+// 从 main 返回退出码而不是调用 process::exit，退出前照常析构运行时与各任务
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    Builder::from_env(env_logger::Env::default().default_filter_or("info"))
-        .target(Target::Stdout)
-        .init();
-    info!("上位机程序启动...");
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(code) => code,
+        Err(e) => {
+            // 日志可能尚未初始化（如配置错误），直接输出到 stderr
+            let code = e.code();
+            eprintln!("Error [{} {}]: {}", code.code, code.name, e);
+            exit_code(e.exit_category().exit_code())
+        }
+    }
+}
+
+async fn run() -> Result<ExitCode, DaemonError> {
     dotenv().ok(); // 加载 .env 文件
+    let cli = Cli::parse();
+    let validate_duration = match cli.command {
+        Some(Command::Init(args)) => {
+            wizard::run(&args).await?;
+            return Ok(exit_code(ExitCategory::Clean.exit_code()));
+        }
+        Some(Command::Validate { duration }) => Some(duration),
+        None => None,
+    };
+    let mut config = DaemonConfig::from_env().inspect_err(|e| {
+        if let ConfigError::Missing(_) = e {
+            eprintln!("{}。首次使用可运行 `ups120-daemon init` 生成配置。", e);
+        }
+    })?;
+    let validate_until = validate_duration.map(|duration| {
+        config.strict_mode = true;
+        Instant::now() + duration
+    });
 
-    let mqtt_broker_host = env::var("MQTT_BROKER_HOST").expect("MQTT_BROKER_HOST not set");
-    let mqtt_broker_port: u16 = env::var("MQTT_BROKER_PORT")
-        .expect("MQTT_BROKER_PORT not set")
-        .parse()
-        .expect("Invalid MQTT_BROKER_PORT");
-    info!("MQTT 地址: {}:{}", mqtt_broker_host, mqtt_broker_port);
-    let mqtt_username = env::var("MQTT_USERNAME").ok();
-    let mqtt_password = env::var("MQTT_PASSWORD").ok();
-    let mqtt_client_id =
-        env::var("MQTT_CLIENT_ID").unwrap_or_else(|_| "ups120_cli_client".to_string());
-    let mqtt_topic_prefix = env::var("MQTT_TOPIC_PREFIX").unwrap_or_else(|_| "ups120".to_string());
-
-    let usb_vid: u16 = u16::from_str_radix(
-        env::var("USB_VID")
-            .unwrap_or_else(|_| "0x1209".to_string())
-            .trim_start_matches("0x"),
-        16,
-    )
-    .expect("Invalid USB_VID");
-    let usb_pid: u16 = u16::from_str_radix(
-        env::var("USB_PID")
-            .unwrap_or_else(|_| "0x0002".to_string())
-            .trim_start_matches("0x"),
-        16,
-    )
-    .expect("Invalid USB_PID");
-
-    let mqtt_client = loop {
-        match connect_mqtt_and_publish(
-            &mqtt_broker_host,
-            mqtt_broker_port,
-            mqtt_username.clone(),
-            mqtt_password.clone(),
-            &mqtt_client_id,
-            &mqtt_topic_prefix,
-        )
-        .await
-        {
-            Ok(client) => break client,
-            Err(e) => {
-                error!("MQTT 连接失败: {:?}, 10秒后重试...", e);
-                tokio::time::sleep(Duration::from_secs(10)).await;
+    // 严格模式下 stdout 只输出机器可读的检测摘要，日志改走 stderr
+    logging::init(config.log_format, config.strict_mode);
+    info!("上位机程序启动...");
+    if config.strict_mode {
+        info!("严格模式已启用");
+    }
+    let mut strict_report = StrictReport::default();
+    // 各组件的状态，供 /healthz 与 {prefix}/daemon/health 使用
+    // 原始帧转储开关，由 USB 管理任务、SIGUSR2 与 `debug_dump` 命令共享
+    let debug_dump = DebugDump::new(config.debug_dump, config.debug_dump_target);
+    let mut initial_state = DaemonState::default();
+    initial_state.debug_dump = debug_dump.clone();
+    let (daemon_state_tx, daemon_state_rx) = watch::channel(initial_state);
+
+    info!("MQTT 地址: {}:{}", config.mqtt_broker_host, config.mqtt_broker_port);
+    let mqtt_topic_prefix = config.mqtt_topic_prefix.clone();
+
+    let stats = Arc::new(DaemonStats::default());
+    let acks = Arc::new(AckTracker::default());
+    let shutdown = CancellationToken::new();
+    let (mqtt_cmd_tx, mut mqtt_cmd_rx) = mpsc::channel::<MqttCommand>(8);
+    let (mqtt_events_tx, mut mqtt_events_rx) = mpsc::unbounded_channel::<MqttEvent>();
+    // dry-run 不连接 broker，发布直接输出到日志，视为一直在线
+    let mut mqtt_connected = cli.dry_run;
+    let connect_hooks = ConnectHooks::default();
+    // systemd (Type=notify)：未设置 NOTIFY_SOCKET 时以下通知都是空操作
+    let mut notifier = Notifier::from_env();
+    notifier.status("connecting to MQTT broker");
+    let mqtt_heartbeat = Heartbeat::default();
+    let usb_heartbeat = Heartbeat::default();
+    let (mqtt_liveness, usb_liveness) = liveness_limits(&config);
+    let mut notified_ready = false;
+    let daemon_info = DaemonInfo::from_config(&config);
+    info!("守护进程信息: {:?}", daemon_info);
+    connect_hooks.register_retained(daemon_info_topic(&mqtt_topic_prefix), serde_json::to_string(&daemon_info)?);
+    if cli.dry_run {
+        // 镜像与 ACL 探测各自连接 broker，dry-run 下不启用
+        config.mirrors.clear();
+        config.acl_probe = false;
+    }
+    let mqtt_session = if cli.dry_run {
+        info!("dry-run 模式：不连接 MQTT broker，要发布的消息只输出到日志");
+        let session = dry_run_session(acks.clone());
+        daemon_state_tx.send_modify(DaemonState::on_mqtt_connected);
+        connect_hooks.run_all(&session.client);
+        session
+    } else {
+        loop {
+            match connect_mqtt_and_publish(
+                &config,
+                mqtt_cmd_tx.clone(),
+                mqtt_events_tx.clone(),
+                stats.clone(),
+                acks.clone(),
+                connect_hooks.clone(),
+                mqtt_heartbeat.clone(),
+            )
+            .await
+            {
+                Ok(session) => break session,
+                Err(e) => {
+                    daemon_state_tx.send_modify(|state| state.on_mqtt_error(&e.to_string(), Instant::now()));
+                    error!(error = ?e, retry_in = ?config.timing.mqtt_connect_retry, "MQTT 连接失败，稍后重试");
+                    tokio::time::sleep(config.timing.mqtt_connect_retry).await;
+                }
+            }
+        }
+    };
+
+    // 长期运行的后台任务，退出时统一等待；MQTT 事件循环最后停止，保证退出消息能够送达
+    let MqttSession { client: mqtt_client, tracked: mqtt_tracked, stop: mqtt_stop, event_loop: mqtt_event_loop } = mqtt_session;
+    let mut tasks = TaskSet::new();
+    tasks.spawn("mqtt_event_loop", async move {
+        if let Err(e) = mqtt_event_loop.await {
+            error!("MQTT 事件循环异常结束: {:?}", e);
+        }
+    });
+
+    if config.strict_mode && !cli.dry_run {
+        let connected = tokio::time::timeout(config.strict_mqtt_connect_timeout, async {
+            loop {
+                match mqtt_events_rx.recv().await {
+                    Some(MqttEvent::Connected) => break Ok(()),
+                    Some(MqttEvent::FatalAuthError(reason)) => break Err(reason),
+                    Some(MqttEvent::Disconnected(_)) => {}
+                    None => break Err("MQTT event loop stopped".to_string()),
+                }
+            }
+        })
+        .await;
+        match connected {
+            Ok(Ok(())) => {
+                mqtt_connected = true;
+                daemon_state_tx.send_modify(DaemonState::on_mqtt_connected);
             }
+            Ok(Err(reason)) => {
+                strict_report.fail(StrictCondition::MqttUnreachable, reason);
+            }
+            Err(_) => {
+                strict_report.fail(
+                    StrictCondition::MqttUnreachable,
+                    format!("no ConnAck within {:?}", config.strict_mqtt_connect_timeout),
+                );
+            }
+        }
+        if strict_report.fatal.is_some() {
+            // 还没有连接设备，也没有可送达的退出消息，只需停止 MQTT 事件循环
+            mqtt_stop.cancel();
+            tasks.shutdown(config.timing.shutdown_tasks).await;
+            return Ok(strict_summary(&strict_report));
+        }
+    }
+
+    if config.acl_probe {
+        tokio::spawn(acl_probe_task(config.clone(), mqtt_client.clone(), stats.clone()));
+    }
+
+    // 设备连接之前先发布上次保存的测量数据，订阅方不必等到第一帧推送
+    if config.restore_last_state {
+        restore_last_states(&mqtt_client, &config.state_dir, &config.topics).await;
+    }
+
+    // 帧号分配器：状态目录不可用时退化为进程内计数
+    let frame_id_path = config.state_dir.join("frame_id");
+    let frame_ids = Arc::new(
+        FrameIdAllocator::open(&frame_id_path, config.frame_id_persist_batch).unwrap_or_else(|e| {
+            error!("无法打开帧号状态文件 {}: {:?}，帧号将不会跨重启保持唯一", frame_id_path.display(), e);
+            FrameIdAllocator::in_memory()
+        }),
+    );
+
+    // 转换上下文只在启动时构建一次
+    let conversion_ctx = Arc::new(ConversionContext::from_config(&config));
+
+    // 原始帧抓取：文件无法创建时直接退出，避免误以为正在记录
+    let capture = match &cli.capture {
+        Some(path) => Some(spawn_capture(path, shutdown.clone()).map_err(|e| {
+            error!("无法创建抓取文件 {}: {:?}", path.display(), e);
+            e
+        })?),
+        None => None,
+    };
+
+    // 回放模式不打开 USB 设备，回放任务代替设备注册表产生事件；读完后按正常流程退出
+    let replay_finished = CancellationToken::new();
+    let client = if let Some(path) = &cli.replay {
+        let transport = ReplayTransport::open(path, cli.speed).map_err(|e| {
+            error!("无法读取抓取文件 {}: {:?}", path.display(), e);
+            e
+        })?;
+        info!("回放 {} ({}x)", path.display(), cli.speed);
+        let settings = ReplaySettings {
+            frame_ids: frame_ids.clone(),
+            conversion_ctx,
+            timeouts: config.timing.usb,
+            shutdown: shutdown.clone(),
+            finished: replay_finished.clone(),
+            debug_dump: debug_dump.clone(),
+        };
+        Ups120Client::from_task(|cmd_rx, event_tx| replay_task(transport, settings, cmd_rx, event_tx))
+    } else if cli.simulate {
+        // 模拟模式同样代替设备注册表，生成的数据走与真实设备相同的处理流程
+        let scenario = match &cli.sim_scenario {
+            Some(path) => Scenario::load(path)?,
+            None => Scenario::default(),
+        };
+        info!("模拟模式：每 {:?} 生成一帧，种子 {}", cli.sim_interval, cli.sim_seed);
+        let settings = SimulationSettings {
+            scenario,
+            interval: cli.sim_interval,
+            seed: cli.sim_seed,
+            cell_count: config.cell_count,
+            frame_ids: frame_ids.clone(),
+            shutdown: shutdown.clone(),
+        };
+        Ups120Client::from_task(|cmd_rx, event_tx| simulation_task(settings, cmd_rx, event_tx))
+    } else {
+        // 启动设备注册表：发现设备后为每块设备启动一个 USB 管理任务
+        let settings = UsbManagerSettings {
+            usb_ids: config.usb_ids.clone(),
+            frame_ids: frame_ids.clone(),
+            conversion_ctx,
+            error_coalesce_window: config.usb_error_coalesce_window,
+            error_max_per_minute: config.usb_error_max_per_minute,
+            timing: config.timing.clone(),
+            layout: config.usb_layout,
+            mode: config.usb_mode,
+            stats_interval: config.usb_stats_interval,
+            shutdown: shutdown.clone(),
+            heartbeat: usb_heartbeat.clone(),
+            capture,
+            debug_dump: debug_dump.clone(),
+            report_interval: config.usb_report_interval_ms,
+        };
+        let discovery = DiscoverySettings {
+            selector: config.usb_device.clone(),
+            multi_device: config.usb_multi_device,
+            scan_interval: config.usb_scan_interval,
+        };
+        if config.usb_multi_device {
+            info!("多设备模式：测量数据按设备发布到 {}/<设备标识>", mqtt_topic_prefix);
         }
+        Ups120Client::spawn(settings, discovery)
     };
+    // 命令经设备注册表（或代替它的任务）分发，各设备的事件带设备标识汇入同一通道
+    let (usb_cmd_tx, mut usb_event_rx, usb_task) = client.into_parts();
+    tasks.spawn("usb", async move {
+        if let Err(e) = usb_task.await {
+            error!("USB 任务异常结束: {:?}", e);
+        }
+    });
+
+    // 镜像 broker：每个都有独立的连接、重连与缓冲，任何一个故障都不影响主 broker 与其他镜像
+    let mirrors: Vec<MirrorHandle> = config
+        .mirrors
+        .iter()
+        .filter_map(|broker| {
+            let mirror_stats = stats.register_mirror(&broker.name);
+            match spawn_mirror(broker, &config, mirror_stats) {
+                Ok(handle) => Some(handle),
+                Err(e) => {
+                    error!("无法启动镜像 broker {}: {:?}", broker.name, e);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    // InfluxDB 输出：独立的写入任务，按批写入，服务不可达时缓冲并退避重试
+    let influx: Option<InfluxHandle> = config.influx.clone().and_then(|settings| {
+        spawn_influx(settings)
+            .inspect_err(|e| error!("无法启动 InfluxDB 输出: {}", e))
+            .ok()
+    });
+
+    // webhook：每个地址独立的队列与重试，不阻塞主循环
+    let webhooks: Option<WebhookHandle> = spawn_webhooks(&config.webhooks)
+        .inspect_err(|e| error!("无法启动 webhook: {}", e))
+        .ok()
+        .flatten();
+
+    // 测量数据总线：汇总所有设备的数据；按设备运行的规则订阅各自路由上的总线
+    let pipeline = Pipeline::new(64);
+    let mut routes: HashMap<DeviceId, DeviceRoute> = HashMap::new();
+    // 连接过的设备；路由在拔出时移除，重连计数以此判断
+    let mut seen_devices: HashSet<DeviceId> = HashSet::new();
+    if let Some(dir) = &config.data_log_dir {
+        let settings = DataLogSettings {
+            dir: dir.clone(),
+            max_file_bytes: config.data_log_max_file_bytes,
+            max_files: config.data_log_max_files,
+            fsync: config.data_log_fsync,
+            fsync_interval: config.data_log_fsync_interval,
+        };
+        match DataLogger::new(settings, stats.clone()) {
+            Ok(logger) => {
+                info!("JSONL 数据记录目录: {} (落盘策略 {:?})", dir.display(), config.data_log_fsync);
+                tasks.spawn("data_log", data_log_task(pipeline.clone(), logger, shutdown.clone()));
+            }
+            Err(e) => error!("无法创建数据记录目录 {}: {:?}", dir.display(), e),
+        }
+    }
+    if let Some(dir) = &config.csv_log_dir {
+        let settings = CsvLogSettings {
+            dir: dir.clone(),
+            max_file_bytes: config.csv_log_max_file_bytes,
+            max_files: config.csv_log_max_files,
+            rotate_daily: config.csv_log_rotate_daily,
+            flush_interval: config.csv_log_flush_interval,
+        };
+        match CsvLogger::new(settings) {
+            Ok(logger) => {
+                info!("CSV 记录目录: {}", dir.display());
+                tasks.spawn("csv_log", csv_log_task(pipeline.clone(), logger, shutdown.clone()));
+            }
+            Err(e) => error!("无法创建 CSV 记录目录 {}: {:?}", dir.display(), e),
+        }
+    }
+
+    // 本地 HTTP 接口、控制套接字与 NUT 服务读取的状态；都未启用时只更新告警
+    let (latest_tx, latest_rx) = watch::channel::<Option<Arc<TimestampedMeasurements>>>(None);
+    let (device_info_tx, device_info_rx) = watch::channel::<Option<DeviceInfo>>(None);
+    let (alarms_tx, alarms_rx) = watch::channel(ActiveAlarms::new());
+    // 所有设备的最近样本，供 `history` 命令查询；告警上下文使用各设备路由自己的历史
+    let history = History::new(config.history_samples);
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut serve_latest = config.http_listen.is_some() || config.nut.is_some();
+    #[cfg(unix)]
+    {
+        serve_latest |= config.control_socket.is_some();
+    }
+    if let Some(addr) = config.http_listen {
+        let state = ApiState {
+            latest: latest_rx,
+            device: device_info_rx,
+            alarms: alarms_rx,
+            daemon: daemon_state_rx.clone(),
+            health_rules: config.health_rules,
+            pipeline: pipeline.clone(),
+            stream_queue: config.http_stream_queue,
+        };
+        tasks.spawn("http_api", http_api_task(addr, state, shutdown.clone()));
+    }
 
-    // 创建 MPSC 渠道
-    let (usb_cmd_tx, usb_cmd_rx) = mpsc::channel::<UsbCommand>(32);
-    // UsbEvent itself is not generic. Its Measurements variant carries data_models::AllMeasurements<5>.
-    let (usb_event_tx, mut usb_event_rx) = mpsc::channel::<UsbEvent>(32);
+    #[cfg(unix)]
+    if let Some(settings) = config.control_socket.clone() {
+        let state = ControlState {
+            latest: latest_tx.subscribe(),
+            stats: stats.clone(),
+            usb_commands: usb_cmd_tx.clone(),
+            history: history.clone(),
+        };
+        tasks.spawn("control_socket", control_socket_task(settings, state, shutdown.clone()));
+    }
+    if let Some(settings) = config.nut.clone() {
+        let source = NutSource { latest: latest_tx.subscribe(), device: device_info_tx.subscribe() };
+        tasks.spawn("nut_server", nut_server_task(settings, source, shutdown.clone()));
+    }
+    #[cfg(unix)]
+    tasks.spawn("debug_dump_signal", signal_toggle_task(debug_dump.clone(), shutdown.clone()));
+    tasks.spawn(
+        "health_publish",
+        health_publish_task(
+            mqtt_client.clone(),
+            mqtt_topic_prefix.clone(),
+            daemon_state_rx,
+            config.health_rules,
+            config.health_interval,
+            shutdown.clone(),
+        ),
+    );
+
+    // 发布限速：每个设备的路由各有一个限速器，间隔内只保留最新样本，到期后发布
+    let mut publish_interval = config.publish_min_interval;
+    if !config.publish_min_interval.is_zero() {
+        info!("MQTT 最小发布间隔: {:?}", config.publish_min_interval);
+    }
+    stats.publish_interval_ms.store(config.publish_min_interval.as_millis() as u64, Ordering::Relaxed);
+    let mut adaptive = config.publish_adaptive.then(|| {
+        info!(
+            "自适应发布间隔已启用: {:?} ~ {:?}",
+            config.publish_min_interval, config.publish_adaptive_max_interval
+        );
+        AimdController::new(
+            config.publish_min_interval,
+            config.publish_adaptive_max_interval,
+            config.publish_adaptive_target_rtt,
+            config.publish_adaptive_max_backlog,
+            config.publish_adaptive_step,
+        )
+    });
+    let mut stats_timer = tokio::time::interval(config.stats_interval);
+    // 心跳与测量数据无关，设备空闲或离线时照常发布
+    let mut heartbeat_timer = tokio::time::interval(config.heartbeat_interval);
+    let mut heartbeats = HeartbeatSender::default();
+    let mut publish_buffer = PublishBuffer::new(config.publish_buffer_size);
+    // 发布队列腾出空间或收到 PubAck 时继续投递缓冲区
+    let mut publish_acks = mqtt_tracked.acks().subscribe();
 
-    // 启动 USB 管理任务
-    tokio::spawn(usb_manager_task(usb_vid, usb_pid, usb_cmd_rx, usb_event_tx));
+    let mut frame_deadline = Instant::now() + config.strict_frame_deadline;
+    // 首次连接到设备前的期限，连接后不再检查
+    let mut usb_deadline = config.usb_startup_deadline.map(|deadline| Instant::now() + deadline);
+    let watchdog_interval = notifier.watchdog_interval();
+    let mut watchdog_timer = tokio::time::interval(watchdog_interval.unwrap_or(Duration::from_secs(60)));
 
     // 主循环，处理 USB 事件和 MQTT 发布
-    let main_loop_result: Result<(), Box<dyn std::error::Error>> = loop {
+    // 主循环内的发布不等待发送队列：broker 不可达、队列占满时丢弃并计数，设备事件与样本缓冲照常处理
+    let loop_publisher = TryPublisher::new(mqtt_client.clone(), stats.clone());
+    // 检测时长已到（validate 子命令）
+    let mut validated = false;
+    // 正常退出时为退出原因（信号名称等）
+    let main_loop_result: Result<&'static str, DaemonError> = loop {
+        let throttle_deadline = routes.values().filter_map(|route| route.throttle.deadline()).min();
+        let streaming = routes.values().any(|route| route.received && !route.stale);
+        notifier.status(daemon_status(mqtt_connected, &routes));
+        daemon_state_tx.send_if_modified(|state| {
+            let changed = state.publish_queue_depth != publish_buffer.len();
+            state.publish_queue_depth = publish_buffer.len();
+            changed
+        });
+        if !notified_ready && mqtt_connected && streaming {
+            info!("MQTT 已连接且已收到测量数据，通知 systemd 就绪");
+            notifier.ready();
+            notified_ready = true;
+        }
         tokio::select! {
-            _ = tokio::signal::ctrl_c() => {
-                info!("收到 Ctrl+C 信号，正在执行优雅退出...");
-                if let Err(e) = usb_cmd_tx.send(UsbCommand::Unsubscribe).await {
-                    error!("发送取消订阅命令到 USB 管理任务失败: {:?}", e);
+            signal = async {
+                tokio::select! {
+                    signal = shutdown_signal() => signal,
+                    _ = replay_finished.cancelled() => "replay finished",
+                }
+            } => {
+                info!("收到 {} 信号，正在执行优雅退出...", signal);
+                break Ok(signal);
+            }
+            _ = tokio::time::sleep_until(frame_deadline), if config.strict_mode => {
+                strict_report.fail(
+                    StrictCondition::NoFrame,
+                    format!("no frame within {:?}", config.strict_frame_deadline),
+                );
+                break Ok(STRICT_FAILED);
+            }
+            _ = tokio::time::sleep_until(validate_until.unwrap_or_else(Instant::now)), if validate_until.is_some() => {
+                info!("检测时长已到，共收到 {} 帧。", strict_report.frames);
+                validated = true;
+                break Ok("validation finished");
+            }
+            _ = tokio::time::sleep_until(usb_deadline.unwrap_or_else(Instant::now)), if usb_deadline.is_some() => {
+                let waited = config.usb_startup_deadline.unwrap_or_default();
+                error!("{:?} 内未连接到 USB 设备，程序退出", waited);
+                break Err(DaemonError::Usb(UsbError::DeviceNotFound(format!("no device connected within {:?}", waited))));
+            }
+            _ = tokio::time::sleep_until(throttle_deadline.unwrap_or_else(Instant::now)), if throttle_deadline.is_some() => {
+                let now = Instant::now();
+                for route in routes.values_mut() {
+                    if let Some(measurements) = route.throttle.take_due(now) {
+                        mirror_sample(&mirrors, &route.prefix, &measurements);
+                        publish_buffer.push(PendingSample::new(route.prefix.clone(), measurements));
+                    }
+                }
+                drain_publish_buffer(&mqtt_tracked, &config.topics, &mut publish_buffer, mqtt_connected, &stats).await;
+                adapt_publish_interval(adaptive.as_mut(), &mut routes, &mut publish_interval, &stats);
+            }
+            Ok(()) = publish_acks.changed(), if !publish_buffer.is_empty() => {
+                drain_publish_buffer(&mqtt_tracked, &config.topics, &mut publish_buffer, mqtt_connected, &stats).await;
+            }
+            Some(event) = mqtt_events_rx.recv() => match event {
+                MqttEvent::Connected => {
+                    mqtt_connected = true;
+                    daemon_state_tx.send_modify(DaemonState::on_mqtt_connected);
+                    if !publish_buffer.is_empty() {
+                        info!("MQTT 已恢复，补发 {} 条缓冲样本。", publish_buffer.len());
+                        drain_publish_buffer(&mqtt_tracked, &config.topics, &mut publish_buffer, mqtt_connected, &stats).await;
+                    }
+                }
+                MqttEvent::Disconnected(reason) => {
+                    warn!(reason = %reason, "MQTT 连接断开，样本将缓冲到重连成功");
+                    mqtt_connected = false;
+                    daemon_state_tx.send_modify(|state| state.on_mqtt_error(&reason, Instant::now()));
+                }
+                MqttEvent::FatalAuthError(reason) => {
+                    error!(reason = %reason, "MQTT 连接无法恢复，程序退出");
+                    daemon_state_tx.send_modify(|state| state.on_mqtt_error(&reason, Instant::now()));
+                    if config.strict_mode {
+                        strict_report.fail(StrictCondition::MqttUnreachable, reason.clone());
+                    }
+                    break Err(DaemonError::MqttFatal(reason));
+                }
+            },
+            _ = watchdog_timer.tick(), if watchdog_interval.is_some() => {
+                // 只有 MQTT 事件循环与 USB 管理任务都有心跳时才喂狗，任何一方卡死都交给 systemd 重启
+                let (mqtt_age, usb_age) = (mqtt_heartbeat.age(), usb_heartbeat.age());
+                if mqtt_age <= mqtt_liveness && usb_age <= usb_liveness {
+                    notifier.watchdog();
+                } else {
+                    warn!("任务心跳超时 (MQTT {:?}, USB {:?})，停止喂狗", mqtt_age, usb_age);
                 }
-                info!("程序退出。");
-                break Ok(());
             }
-            Some(usb_event) = usb_event_rx.recv() => {
+            _ = heartbeat_timer.tick() => {
+                heartbeats.beat(&loop_publisher, &mqtt_topic_prefix, &stats).await;
+            }
+            _ = stats_timer.tick() => {
+                stats.usb_event_queue_depth.store(usb_event_rx.len() as u64, Ordering::Relaxed);
+                if let Err(e) = publish_daemon_stats(&loop_publisher, &mqtt_topic_prefix, &stats.snapshot()).await {
+                    error!("发布守护进程统计失败: {:?}", e);
+                }
+            }
+            Some(command) = mqtt_cmd_rx.recv() => {
+                let result_topic = command_result_topic(&mqtt_topic_prefix);
+                let (usb_command, reply) = match command {
+                    MqttCommand::Subscribe => (Some(UsbCommand::Subscribe), "ok: subscribe".to_string()),
+                    MqttCommand::Unsubscribe => (Some(UsbCommand::Unsubscribe), "ok: unsubscribe".to_string()),
+                    MqttCommand::Reconnect => (Some(UsbCommand::Reconnect), "ok: reconnect".to_string()),
+                    MqttCommand::SetReportInterval(interval_ms) => {
+                        (Some(UsbCommand::SetInterval(interval_ms)), format!("ok: report_interval {} ms", interval_ms))
+                    }
+                    MqttCommand::AclProbe => {
+                        tokio::spawn(acl_probe_task(config.clone(), mqtt_client.clone(), stats.clone()));
+                        (None, "ok: acl_probe started".to_string())
+                    }
+                    MqttCommand::DebugDump(enabled) => {
+                        let enabled = match enabled {
+                            Some(enabled) => {
+                                debug_dump.set(enabled);
+                                enabled
+                            }
+                            None => debug_dump.toggle(),
+                        };
+                        (None, format!("ok: debug_dump {}", if enabled { "on" } else { "off" }))
+                    }
+                    MqttCommand::History(n) => {
+                        let samples = match n {
+                            Some(n) => history.last(n),
+                            None => history.snapshot(),
+                        };
+                        let frames: Vec<&TimestampedMeasurements> = samples.iter().map(Arc::as_ref).collect();
+                        match serde_json::to_string(&frames) {
+                            Ok(json) => (None, json),
+                            Err(e) => (None, format!("error: {}", e)),
+                        }
+                    }
+                    MqttCommand::AckAlarm { topic_prefix, name, ack: Some(ack) } => {
+                        match routes.values().find(|route| route.prefix == topic_prefix) {
+                            Some(route) => match route.alarm_acks.try_send((name.clone(), ack)) {
+                                Ok(()) => (None, format!("ok: ack {}", alarm_topic(&topic_prefix, &name))),
+                                Err(e) => (None, format!("error: ack {}: {}", name, e)),
+                            },
+                            None => (None, format!("error: no connected device publishes to {}", topic_prefix)),
+                        }
+                    }
+                    MqttCommand::AckAlarm { name, ack: None, .. } => {
+                        (None, format!("error: alarms/{}/set expects 'ack'", name))
+                    }
+                    MqttCommand::Unknown(text) => (None, format!("error: unknown command '{}'", text)),
+                };
+                info!("收到 MQTT 控制命令: {}", reply);
+                if let Some(usb_command) = usb_command
+                    && let Err(e) = usb_cmd_tx.send(DeviceCommand { target: None, command: usb_command }).await
+                {
+                    error!("转发命令到 USB 管理任务失败: {:?}", e);
+                }
+                if let Err(e) = loop_publisher.publish(result_topic, QoS::AtLeastOnce, false, reply).await {
+                    error!("发布命令结果失败: {:?}", e);
+                }
+            }
+            Some(DeviceEvent { device, event: usb_event }) = usb_event_rx.recv() => {
                 match usb_event {
-                    // measurements_data is already of type data_models::AllMeasurements<5>
-                    UsbEvent::Measurements(measurements_data) => {
-                        info!("[LOG POINT 3] Received Processed Measurements: {:?}", measurements_data);
-
-                        // No further conversion needed here as measurements_data is already the correct type.
-                        // The conversion from HostSideUsbPayload to data_models::AllMeasurements<5>
-                        // is assumed to happen within usb_handlers.rs before sending the UsbEvent::Measurements.
-
-                        info!("[LOG POINT 3.5] Publishing Processed Measurements to MQTT: {:?}", measurements_data);
-                        let topic = format!("{}/measurements_all", mqtt_topic_prefix);
-                        if let Err(e) =
-                            publish_measurements(&mqtt_client, &topic, measurements_data).await // Pass measurements_data directly
-                        {
-                            error!("MQTT 发布失败: {:?}, 5秒后重试...", e);
-                            tokio::time::sleep(Duration::from_secs(5)).await;
+                    UsbEvent::Connected { usb_id, protocol, .. } => {
+                        info!(device = %device, usb_id = %usb_id, protocol = %protocol, "USB 设备已连接");
+                        usb_deadline = None;
+                        if !seen_devices.insert(device.clone()) {
+                            stats.usb_reconnects.fetch_add(1, Ordering::Relaxed);
+                        }
+                        daemon_state_tx.send_modify(|state| state.on_usb_connected(&device));
+                        let route = routes
+                            .entry(device.clone())
+                            .or_insert_with(|| open_device_route(&mut tasks, &config, &mqtt_client, &device, publish_interval, &alarms_tx, webhooks.as_ref()));
+                        info!("测量数据主题前缀: {}", route.prefix);
+                        route.filter.reset();
+                        let topic = device_usb_id_topic(&route.prefix);
+                        if let Err(e) = loop_publisher.publish(topic, QoS::AtLeastOnce, true, usb_id.to_string()).await {
+                            error!("发布设备 VID/PID 失败: {:?}", e);
+                        }
+                        route.stale = false;
+                        publish_device_availability(&loop_publisher, &route.prefix, true).await;
+                    }
+                    UsbEvent::DeviceInfo(info) => {
+                        info!(
+                            "设备信息: {} {} (序列号 {}, 协议 v{}, 固件 {})",
+                            info.manufacturer.as_deref().unwrap_or("?"),
+                            info.product.as_deref().unwrap_or("?"),
+                            info.serial.as_deref().unwrap_or("-"),
+                            info.protocol_version,
+                            info.firmware_version.as_deref().unwrap_or("unknown"),
+                        );
+                        if let Some(route) = routes.get_mut(&device) {
+                            route.info = Some(info.clone());
+                            match serde_json::to_string(&info) {
+                                Ok(payload) => {
+                                    if let Err(e) = loop_publisher.publish(device_info_topic(&route.prefix), QoS::AtLeastOnce, true, payload).await {
+                                        error!("发布设备信息失败: {:?}", e);
+                                    }
+                                }
+                                Err(e) => error!("序列化设备信息失败: {:?}", e),
+                            }
+                        }
+                    }
+                    UsbEvent::Stats(link_stats) => {
+                        let prefix = routes.get(&device).map_or(&mqtt_topic_prefix, |route| &route.prefix);
+                        match serde_json::to_string(&link_stats) {
+                            Ok(payload) => {
+                                if let Err(e) = loop_publisher.publish(usb_stats_topic(prefix), QoS::AtLeastOnce, false, payload).await {
+                                    error!("发布 USB 链路统计失败: {:?}", e);
+                                }
+                            }
+                            Err(e) => error!("序列化 USB 链路统计失败: {:?}", e),
+                        }
+                    }
+                    UsbEvent::Detached => {
+                        info!(device = %device, "USB 设备已拔出");
+                        daemon_state_tx.send_modify(|state| state.on_usb_detached(&device));
+                        if let Some(route) = routes.remove(&device) {
+                            publish_device_availability(&loop_publisher, &route.prefix, false).await;
+                            if route.throttle.deadline().is_some() {
+                                debug!("设备 {} 暂存的样本未发布", device);
+                            }
+                        }
+                        // 路由的总线已关闭，其任务保存状态后结束；回收之前已结束的任务
+                        tasks.reap();
+                    }
+                    UsbEvent::Measurements(sample) => {
+                        debug!("[LOG POINT 3] Received Processed Measurements (device {}, frame {}): {:?}", device, sample.frame_id, sample.data);
+                        strict_report.record_frame();
+                        stats.measurements_received.fetch_add(1, Ordering::Relaxed);
+                        daemon_state_tx.send_modify(|state| state.on_push(Instant::now()));
+                        frame_deadline = Instant::now() + config.strict_frame_deadline;
+
+                        let route = routes
+                            .entry(device.clone())
+                            .or_insert_with(|| open_device_route(&mut tasks, &config, &mqtt_client, &device, publish_interval, &alarms_tx, webhooks.as_ref()));
+                        route.received = true;
+                        if let Err(violations) = validate_sample(&sample.data, &config.plausibility_limits) {
+                            let fields: Vec<String> = violations.iter().map(ToString::to_string).collect();
+                            warn!(device = %device, frame_id = sample.frame_id, fields = %fields.join(","), "样本超出合理范围");
+                            stats.implausible_samples.fetch_add(1, Ordering::Relaxed);
+                            if route.implausible_streak.record(false) {
+                                warn!(device = %device, "连续多帧不合理，重新订阅");
+                                let command = DeviceCommand { target: Some(device.clone()), command: UsbCommand::Subscribe };
+                                if let Err(e) = usb_cmd_tx.send(command).await {
+                                    error!("发送重新订阅命令失败: {:?}", e);
+                                }
+                            }
+                            if config.drop_implausible {
+                                continue;
+                            }
+                        } else {
+                            route.implausible_streak.record(true);
+                        }
+                        // 主循环是此时唯一的持有者，取出样本不会复制
+                        let mut sample = Arc::unwrap_or_clone(sample);
+                        route.filter.apply(&mut sample.data);
+                        sample.derived = sample.data.bq76920.derived();
+                        sample.derived.efficiency_percent = sample.data.efficiency_percent(config.efficiency_min_input_w);
+                        let bq76920 = &sample.data.bq76920;
+                        let current = config.current_sign.to_charge_positive(bq76920.coulomb_counter);
+                        sample.battery.soc_percent = route.soc.update(bq76920.cells(), Some(current));
+                        if let Some(runtime) = route.runtime.as_mut() {
+                            let estimate = runtime.update(sample.ts_unix_ms, current, sample.battery.soc_percent);
+                            sample.battery.runtime_min = estimate.runtime_min;
+                            sample.battery.time_to_full_min = estimate.time_to_full_min;
+                        }
+                        let alerts = (&sample.data.bq25730_alerts, &sample.data.bq76920_alerts);
+                        for event in &route.flags.update(alerts, sample.ts_unix_ms, tokio::time::Instant::now()) {
+                            publish_flag_event(&loop_publisher, &route.prefix, &device, event).await;
+                            if let Some(webhooks) = &webhooks {
+                                webhooks.notify(WebhookEvent::from_flag(&device, event));
+                            }
+                        }
+                        let shared = Arc::new(sample);
+                        if serve_latest {
+                            latest_tx.send_replace(Some(shared.clone()));
+                            device_info_tx.send_if_modified(|current| {
+                                let changed = *current != route.info;
+                                if changed {
+                                    current.clone_from(&route.info);
+                                }
+                                changed
+                            });
+                        }
+                        // 先写入历史再发布到总线，告警任务取上下文时已包含当前帧
+                        history.push(shared.clone());
+                        route.history.push(shared.clone());
+                        if let Some(influx) = &influx {
+                            influx.offer(&device, shared.clone());
+                        }
+                        pipeline.publish(shared.clone());
+                        route.pipeline.publish(shared.clone());
+                        if route.stale {
+                            info!("USB 设备 {} 恢复推送数据", device);
+                            route.stale = false;
+                            publish_device_availability(&loop_publisher, &route.prefix, true).await;
+                        }
+                        if let Some(measurements) = route.throttle.offer(shared, Instant::now()) {
+                            mirror_sample(&mirrors, &route.prefix, &measurements);
+                            publish_buffer.push(PendingSample::new(route.prefix.clone(), measurements));
+                            drain_publish_buffer(&mqtt_tracked, &config.topics, &mut publish_buffer, mqtt_connected, &stats).await;
+                            adapt_publish_interval(adaptive.as_mut(), &mut routes, &mut publish_interval, &stats);
+                        } else {
+                            debug!("发布间隔未到，暂存最新样本");
                         }
                     }
                     UsbEvent::Error(e) => {
-                        error!("USB 管理任务报告错误: {:?}, 尝试重新连接USB...", e);
+                        error!(device = %device, error_kind = e.category(), error = %e, "USB 设备报告错误，尝试重新连接");
+                        let prefix = routes.get(&device).map_or(&mqtt_topic_prefix, |route| &route.prefix);
+                        if let Some(error) = report_usb_error(&loop_publisher, &config, &stats, &mut strict_report, &device, prefix, e).await {
+                            break Err(error);
+                        }
+                        if strict_report.fatal.is_some() {
+                            break Ok(STRICT_FAILED);
+                        }
+                    }
+                    UsbEvent::Disconnected(e) => {
+                        error!(device = %device, error_kind = e.category(), error = %e, "USB 连接中断，标记为离线并尝试重新连接");
+                        if let Some(route) = routes.get_mut(&device)
+                            && !route.stale
+                        {
+                            route.stale = true;
+                            publish_device_availability(&loop_publisher, &route.prefix, false).await;
+                        }
+                        let prefix = routes.get(&device).map_or(&mqtt_topic_prefix, |route| &route.prefix);
+                        if let Some(error) = report_usb_error(&loop_publisher, &config, &stats, &mut strict_report, &device, prefix, e).await {
+                            break Err(error);
+                        }
+                        if strict_report.fatal.is_some() {
+                            break Ok(STRICT_FAILED);
+                        }
+                    }
+                    UsbEvent::Stale => {
+                        warn!(device = %device, "USB 设备数据已过期，标记为离线");
+                        if let Some(route) = routes.get_mut(&device)
+                            && !route.stale
+                        {
+                            route.stale = true;
+                            publish_device_availability(&loop_publisher, &route.prefix, false).await;
+                        }
+                    }
+                    UsbEvent::Unsubscribed => {
+                        info!("USB 设备 {} 已取消订阅，等待订阅或重连命令。", device);
+                    }
+                    UsbEvent::ErrorRepeated { category, count } => {
+                        warn!(device = %device, error_kind = %category, count, "同类 USB 错误在合并窗口内重复发生");
+                    }
+                    UsbEvent::RawFrame(frame) => {
+                        let payload = serde_json::json!({ "device": device.to_string(), "len": frame.len(), "hex": hex(&frame) });
+                        if let Err(e) = loop_publisher.publish(debug_raw_topic(&mqtt_topic_prefix), QoS::AtMostOnce, false, payload.to_string()).await {
+                            error!("发布原始帧转储失败: {:?}", e);
+                        }
+                    }
+                    UsbEvent::ErrorsSuppressed { count } => {
+                        warn!(device = %device, count, "USB 错误过多，过去一分钟内的部分错误事件未单独上报");
+                    }
+                    UsbEvent::Anomaly { frame_id, anomaly } => {
+                        warn!(device = %device, frame_id, anomaly = %anomaly, "数据帧异常");
+                        if matches!(anomaly, FrameAnomaly::ContractViolation { .. }) {
+                            stats.contract_violations.fetch_add(1, Ordering::Relaxed);
+                        }
+                        if config.strict_mode {
+                            strict_report.fail(StrictCondition::for_anomaly(&anomaly), format!("frame {}: {}", frame_id, anomaly));
+                            break Ok(STRICT_FAILED);
+                        }
                     }
                 }
             }
             else => {
                 info!("USB 事件流结束，主循环退出。");
-                break Ok(());
+                break Ok("usb events closed");
+            }
+        }
+    };
+
+    // 优雅退出：中断进行中的读取与各服务，设备取消订阅，发布离线状态，最后等待所有任务结束
+    notifier.stopping();
+    notifier.status("shutting down");
+    // 先中断进行中的推送读取，取消订阅命令才能立即拿到设备句柄
+    shutdown.cancel();
+    if let Err(e) = usb_cmd_tx.send(DeviceCommand { target: None, command: UsbCommand::Unsubscribe }).await {
+        error!("发送取消订阅命令到 USB 管理任务失败: {:?}", e);
+    }
+    // 等设备停止推送再退出，否则固件会继续向已退出的主机推送
+    let mut pending: HashSet<DeviceId> = routes.keys().cloned().collect();
+    let waited = tokio::time::timeout(config.timing.shutdown_unsubscribe, async {
+        while !pending.is_empty() {
+            match usb_event_rx.recv().await {
+                Some(DeviceEvent { device, event: UsbEvent::Unsubscribed | UsbEvent::Detached }) => {
+                    pending.remove(&device);
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+    })
+    .await;
+    if waited.is_err() {
+        warn!("{:?} 内未完成取消订阅，继续退出。", config.timing.shutdown_unsubscribe);
+    }
+    // 退出事件与离线状态必须在进程结束前送达 broker，等待 PubAck，但不超过期限；事件循环已停止时跳过
+    if let Ok(reason) = &main_loop_result {
+        let shutdown_event = ShutdownEvent { event: "shutdown_initiated", reason, ts_unix_ms: unix_ms_now() };
+        let messages = vec![
+            (shutdown_event_topic(&mqtt_topic_prefix), false, serde_json::to_string(&shutdown_event)?),
+            (daemon_availability_topic(&mqtt_topic_prefix), true, "offline".to_string()),
+        ];
+        if cli.dry_run {
+            // 没有 broker 确认，只输出到日志
+            for (topic, retain, payload) in messages {
+                if let Err(e) = mqtt_client.publish(topic, QoS::AtLeastOnce, retain, payload).await {
+                    error!("发布退出消息失败: {:?}", e);
+                }
+            }
+        } else {
+            let deadline = Instant::now() + config.timing.shutdown_confirm;
+            match publish_and_confirm(&mqtt_tracked, messages, deadline).await {
+                Ok(true) => info!("退出消息已被 broker 确认。"),
+                Ok(false) => warn!("{:?} 内未收到退出消息的确认，继续退出。", config.timing.shutdown_confirm),
+                Err(e) => error!("发布退出消息失败: {:?}", e),
+            }
+        }
+    }
+    frame_ids.persist();
+    // 关闭命令通道后设备注册表等各设备释放接口再结束；设备路由移除后按设备运行的规则任务随总线关闭退出
+    drop(usb_cmd_tx);
+    drop(usb_event_rx);
+    routes.clear();
+    mqtt_stop.cancel();
+    let unfinished = tasks.shutdown(config.timing.shutdown_tasks).await;
+    if !unfinished.is_empty() {
+        warn!("{:?} 内仍有任务未结束，已中止: {}", config.timing.shutdown_tasks, unfinished.join(", "));
+    }
+    info!("程序退出。");
+    if validated || strict_report.fatal.is_some() {
+        return Ok(strict_summary(&strict_report));
+    }
+    main_loop_result.map(|_| exit_code(ExitCategory::Clean.exit_code()))
+}
+
+// 单个设备的发布路由：测量数据主题前缀、发布限速器，以及按设备运行的规则所订阅的总线
+struct DeviceRoute {
+    prefix: String,
+    pipeline: Pipeline,
+    throttle: PublishThrottle<Arc<TimestampedMeasurements>>,
+    /// 设备停止推送后置位，收到新数据时清除
+    stale: bool,
+    /// 连续超出合理范围的样本数
+    implausible_streak: ImplausibleStreak,
+    /// 测量值平滑，设备重新连接时重置
+    filter: MeasurementFilter,
+    soc: SocEstimator,
+    /// 配置了电池容量时估算剩余时间
+    runtime: Option<RuntimeEstimator>,
+    /// 标志位边沿检测与防抖
+    flags: FlagDebouncer,
+    /// 设备连接时读取的设备信息
+    info: Option<DeviceInfo>,
+    /// 已收到过测量数据
+    received: bool,
+    /// 该设备的最近样本，告警激活时作为上下文
+    history: History,
+    /// 转发 `{prefix}/alarms/<name>/set` 的确认给该设备的告警任务
+    alarm_acks: mpsc::Sender<(String, AlarmAck)>,
+}
+
+// 设备首次连接时建立路由，并启动该设备的充放电状态、故障告警、操作建议、阈值告警、均衡直方图与能量计数任务；
+// 这些任务只持有总线的订阅端，路由移除时总线随之关闭，任务保存状态后退出；退出时在 `tasks` 中等待它们结束
+fn open_device_route(
+    tasks: &mut TaskSet,
+    config: &DaemonConfig,
+    client: &AsyncClient,
+    device: &DeviceId,
+    publish_interval: Duration,
+    active_alarms: &watch::Sender<ActiveAlarms>,
+    webhooks: Option<&WebhookHandle>,
+) -> DeviceRoute {
+    // 多设备时各设备的状态文件名加上设备标识
+    let (prefix, state_suffix) = if config.usb_multi_device {
+        let name: String = device
+            .to_string()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        (device_topic_prefix(&config.mqtt_topic_prefix, device), format!("-{}", name))
+    } else if config.topic_per_device {
+        (device_topic_prefix(&config.mqtt_topic_prefix, device), String::new())
+    } else {
+        (config.mqtt_topic_prefix.clone(), String::new())
+    };
+    let energy_state_path = match &config.energy_state_file {
+        Some(path) => {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let file = match path.extension() {
+                Some(ext) => format!("{}{}.{}", stem, state_suffix, ext.to_string_lossy()),
+                None => format!("{}{}", stem, state_suffix),
+            };
+            path.with_file_name(file)
+        }
+        None => config.state_dir.join(format!("energy{}.json", state_suffix)),
+    };
+
+    let pipeline = Pipeline::new(64);
+    let history = History::new(config.history_samples);
+    let (alarm_acks, alarm_acks_rx) = mpsc::channel(8);
+    tasks.spawn(
+        "power_state",
+        power_state_task(
+            pipeline.subscribe(),
+            client.clone(),
+            prefix.clone(),
+            config.power_state_current_threshold,
+            config.power_state_debounce,
+        ),
+    );
+    tasks.spawn(
+        "fault_alert",
+        fault_alert_task(
+            pipeline.subscribe(),
+            client.clone(),
+            prefix.clone(),
+            config.fault_alert_debounce,
+        ),
+    );
+    tasks.spawn("advisory", advisory_task(pipeline.subscribe(), client.clone(), prefix.clone()));
+    tasks.spawn(
+        "alarms",
+        alarm_task(
+            pipeline.subscribe(),
+            client.clone(),
+            prefix.clone(),
+            config.topics.clone(),
+            config.alarms.clone(),
+            active_alarms.clone(),
+            device.clone(),
+            webhooks.cloned(),
+            AlarmContext { history: history.clone(), dir: config.alarm_context_dir.clone() },
+            alarm_acks_rx,
+        ),
+    );
+    tasks.spawn(
+        "balance_histogram",
+        balance_histogram_task(
+            pipeline.subscribe(),
+            client.clone(),
+            prefix.clone(),
+            BalanceSettings {
+                window: config.balance_histogram_window,
+                edges_mv: config.balance_histogram_edges_mv.clone(),
+                cell_count: config.cell_count,
+                current_threshold: config.power_state_current_threshold,
+                state_path: config.state_dir.join(format!("balance_histograms{}.json", state_suffix)),
+            },
+        ),
+    );
+    tasks.spawn(
+        "energy",
+        energy_task(
+            pipeline.subscribe(),
+            client.clone(),
+            prefix.clone(),
+            EnergySettings {
+                state_path: energy_state_path,
+                persist_interval: config.energy_persist_interval,
+                publish_interval: config.energy_publish_interval,
+                max_gap: config.energy_max_gap,
+                current_sign: config.current_sign,
+                capacity_ah: config.battery_capacity_mah.map(|capacity_mah| capacity_mah as f64 / 1000.0),
+            },
+        ),
+    );
+    if let Some(capacity_mah) = config.battery_capacity_mah {
+        tasks.spawn(
+            "coulomb",
+            coulomb_task(
+                pipeline.subscribe(),
+                client.clone(),
+                prefix.clone(),
+                CoulombSettings {
+                    state_path: config.state_dir.join(format!("coulomb{}.json", state_suffix)),
+                    capacity_ah: capacity_mah as f64 / 1000.0,
+                    persist_interval: config.energy_persist_interval,
+                    publish_interval: config.energy_publish_interval,
+                    max_gap: config.energy_max_gap,
+                    current_sign: config.current_sign,
+                },
+            ),
+        );
+    }
+    if config.restore_last_state {
+        tasks.spawn(
+            "last_state",
+            last_state_task(
+                pipeline.subscribe(),
+                client.clone(),
+                prefix.clone(),
+                LastStateSettings {
+                    state_path: config.state_dir.join(format!("{}{}.json", STATE_FILE_STEM, state_suffix)),
+                    persist_interval: config.last_state_persist_interval,
+                },
+            ),
+        );
+    }
+    if let Some(actions) = &config.actions {
+        tasks.spawn("actions", actions_task(pipeline.subscribe(), device.clone(), actions.clone()));
+    }
+    if config.burst_capture_frames > 0 && !config.burst_trigger_flags.is_empty() {
+        tasks.spawn(
+            "burst_capture",
+            burst_capture_task(
+                pipeline.subscribe(),
+                client.clone(),
+                prefix.clone(),
+                BurstSettings {
+                    frames_around: config.burst_capture_frames,
+                    triggers: config.burst_trigger_flags.clone(),
+                    dir: config.state_dir.join("bursts"),
+                    max_files: config.burst_capture_max_files,
+                },
+            ),
+        );
+    }
+
+    DeviceRoute {
+        prefix,
+        pipeline,
+        throttle: PublishThrottle::new(publish_interval),
+        stale: false,
+        implausible_streak: ImplausibleStreak::default(),
+        filter: MeasurementFilter::new(config.filter_alphas.clone()),
+        soc: SocEstimator::new(
+            config.soc_table.clone(),
+            config.soc_hysteresis_pct,
+            config.power_state_current_threshold,
+        ),
+        runtime: config.battery_capacity_mah.map(|capacity| {
+            RuntimeEstimator::new(capacity, config.runtime_ema_window, config.power_state_current_threshold)
+        }),
+        flags: FlagDebouncer::new(config.flag_event_debounce.clone()),
+        info: None,
+        received: false,
+        history,
+        alarm_acks,
+    }
+}
+
+// systemd 状态文本
+fn daemon_status(mqtt_connected: bool, routes: &HashMap<DeviceId, DeviceRoute>) -> &'static str {
+    if !mqtt_connected {
+        "waiting for MQTT broker"
+    } else if !routes.values().any(|route| route.received) {
+        "waiting for device"
+    } else if routes.values().all(|route| route.stale) {
+        "device stale, resubscribing"
+    } else {
+        "streaming"
+    }
+}
+
+// 心跳超过这么久视为任务卡死：取各任务正常运行时两次心跳之间最长的等待，再留一倍余量
+fn liveness_limits(config: &DaemonConfig) -> (Duration, Duration) {
+    let timing = &config.timing;
+    let mqtt = timing.mqtt_keep_alive.max(timing.mqtt_reconnect_max) * 2;
+    let poll = match config.usb_mode {
+        UsbMode::Poll { interval } => interval,
+        UsbMode::Push => Duration::ZERO,
+    };
+    let usb = [
+        timing.usb.push_read,
+        timing.usb.command + timing.usb.response,
+        timing.usb_context_retry,
+        timing.usb_open_retry,
+        timing.usb_setup_retry,
+        config.usb_scan_interval,
+        poll,
+    ]
+    .into_iter()
+    .max()
+    .unwrap_or_default()
+        * 2;
+    (mqtt, usb)
+}
+
+async fn publish_device_availability(client: &impl Publisher, topic_prefix: &str, online: bool) {
+    let payload = if online { "online" } else { "offline" };
+    if let Err(e) = client.publish(device_availability_topic(topic_prefix), QoS::AtLeastOnce, true, payload).await {
+        error!("发布设备可用状态失败: {:?}", e);
+    }
+}
+
+async fn publish_flag_event(client: &impl Publisher, topic_prefix: &str, device: &DeviceId, event: &FlagEvent) {
+    if event.is_protection() {
+        warn!("USB 设备 {} 保护标志 {} 变化: {} -> {}", device, event.flag, event.old, event.new);
+    } else {
+        debug!("USB 设备 {} 标志 {} 变化: {} -> {}", device, event.flag, event.old, event.new);
+    }
+    match serde_json::to_string(event) {
+        Ok(payload) => {
+            if let Err(e) = client.publish(events_topic(topic_prefix), QoS::AtLeastOnce, false, payload).await {
+                error!("发布标志事件失败: {:?}", e);
             }
         }
+        Err(e) => error!("序列化标志事件失败: {:?}", e),
+    }
+}
+
+/// 发布设备报告的错误并计数，严格模式下遇到解析错误记入检测摘要（调用方随后结束主循环）；返回应结束主循环的错误
+async fn report_usb_error(
+    client: &impl Publisher,
+    config: &DaemonConfig,
+    stats: &DaemonStats,
+    strict_report: &mut StrictReport,
+    device: &DeviceId,
+    topic_prefix: &str,
+    usb_error: UsbError,
+) -> Option<DaemonError> {
+    publish_usb_error(client, topic_prefix, &usb_error).await;
+    let strict_condition = StrictCondition::for_usb_error(&usb_error);
+    let parse_error = usb_error.is_parse_error();
+    if parse_error {
+        stats.usb_parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+    let unrecoverable = usb_error.is_unrecoverable();
+    let error = DaemonError::from(usb_error);
+    publish_last_error(client, &config.mqtt_topic_prefix, &error, Some(device)).await;
+    if config.strict_mode && let Some(condition) = strict_condition {
+        strict_report.fail(condition, error.to_string());
+        return None;
+    }
+    // 多设备时不因单块设备退出
+    if unrecoverable && !config.usb_multi_device {
+        error!(device = %device, "USB 错误无法通过重连恢复，程序退出");
+        return Some(error);
+    }
+    None
+}
+
+async fn publish_usb_error(client: &impl Publisher, topic_prefix: &str, usb_error: &UsbError) {
+    let message = usb_error.to_string();
+    let event = UsbErrorEvent {
+        category: usb_error.category(),
+        message: &message,
+        ts_unix_ms: unix_ms_now(),
+    };
+    let payload = match serde_json::to_string(&event) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("序列化 USB 错误事件失败: {:?}", e);
+            return;
+        }
+    };
+    if let Err(e) = client.publish(usb_error_topic(topic_prefix), QoS::AtLeastOnce, false, payload).await {
+        error!("发布 USB 错误事件失败: {:?}", e);
+    }
+}
+
+// retained 发布最近一次错误及其错误码
+async fn publish_last_error(client: &impl Publisher, topic_prefix: &str, error: &DaemonError, device: Option<&DeviceId>) {
+    let device = device.map(|device| device.to_string());
+    let payload = match serde_json::to_string(&LastError::new(error, device.as_deref(), unix_ms_now())) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("序列化最近错误失败: {:?}", e);
+            return;
+        }
+    };
+    if let Err(e) = client.publish(daemon_last_error_topic(topic_prefix), QoS::AtLeastOnce, true, payload).await {
+        error!("发布最近错误失败: {:?}", e);
+    }
+}
+
+// 把即将发布到主 broker 的样本同时投递给各镜像 broker，不等待
+fn mirror_sample(mirrors: &[MirrorHandle], topic_prefix: &str, sample: &Arc<TimestampedMeasurements>) {
+    for mirror in mirrors {
+        mirror.offer(topic_prefix, sample.clone());
+    }
+}
+
+// 按顺序投递缓冲区中的样本（各自带有设备的主题前缀），不阻塞主循环：客户端队列满时剩余消息留到下次，
+// 全部消息收到 PubAck 后才移出缓冲区；broker 未连接时只移除已确认的样本
+async fn drain_publish_buffer(
+    client: &TrackedClient,
+    topics: &TopicMap,
+    buffer: &mut PublishBuffer<PendingSample>,
+    connected: bool,
+    stats: &DaemonStats,
+) {
+    let outcome = drain(buffer, client, topics, connected).await;
+    stats.publishes_attempted.fetch_add(outcome.started, Ordering::Relaxed);
+    stats.publishes_failed.fetch_add(outcome.failed, Ordering::Relaxed);
+    stats.primary.published.fetch_add(outcome.delivered, Ordering::Relaxed);
+    if outcome.closed {
+        error!(buffered = buffer.len(), "MQTT 事件循环已停止，样本保留在缓冲区");
+    } else if outcome.blocked {
+        debug!(buffered = buffer.len(), "MQTT 发布队列已满，剩余消息稍后投递");
+    } else if !connected {
+        debug!("MQTT 未连接，样本已缓冲 ({} 条)", buffer.len());
+    }
+    stats.buffer_depth.store(buffer.len() as u64, Ordering::Relaxed);
+    stats.buffer_dropped.store(buffer.dropped(), Ordering::Relaxed);
+    stats.primary.buffer_depth.store(buffer.len() as u64, Ordering::Relaxed);
+    stats.primary.buffer_dropped.store(buffer.dropped(), Ordering::Relaxed);
+}
+
+// 自适应模式下根据最新的 broker 往返时间与积压调整所有设备的发布间隔
+fn adapt_publish_interval(
+    controller: Option<&mut AimdController>,
+    routes: &mut HashMap<DeviceId, DeviceRoute>,
+    publish_interval: &mut Duration,
+    stats: &DaemonStats,
+) {
+    let Some(controller) = controller else {
+        return;
     };
+    let rtt = Duration::from_millis(stats.mqtt_rtt_ms.load(Ordering::Relaxed));
+    let backlog = stats.mqtt_inflight.load(Ordering::Relaxed);
+    if let Some(interval) = controller.observe(rtt, backlog) {
+        info!("发布间隔调整为 {:?} (RTT {:?}, 积压 {})", interval, rtt, backlog);
+        *publish_interval = interval;
+        for route in routes.values_mut() {
+            route.throttle.set_min_interval(interval);
+        }
+        stats.publish_interval_ms.store(interval.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+// 严格模式条件触发后走正常退出流程，作为退出事件中的原因
+const STRICT_FAILED: &str = "strict mode";
+
+// 严格模式结束：输出检测摘要，返回对应的退出码
+fn strict_summary(report: &StrictReport) -> ExitCode {
+    println!("{}", report.summary_json());
+    exit_code(report.exit_code)
+}
 
-    main_loop_result
+fn exit_code(code: i32) -> ExitCode {
+    ExitCode::from(u8::try_from(code).unwrap_or(1))
 }
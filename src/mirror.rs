@@ -0,0 +1,324 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use tracing::{debug, error, info, warn};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use tokio::sync::{mpsc, watch};
+
+use crate::acks::{self, AckTracker, TrackedClient};
+use crate::backoff::Backoff;
+use crate::buffer::PublishBuffer;
+use crate::config::{BrokerConfig, DaemonConfig};
+use crate::data_models::TimestampedMeasurements;
+use crate::delivery::{drain, PendingSample};
+use crate::mqtt_handlers::{connection_error_kind, daemon_availability_topic, fatal_connection_error};
+use crate::stats::BrokerStats;
+use crate::tls::TlsError;
+use crate::topics::TopicMap;
+
+/// 投递给镜像的样本及其测量数据主题前缀
+pub type RoutedSample = (String, Arc<TimestampedMeasurements>);
+
+// 主循环到镜像任务的队列长度；镜像任务只负责把样本转入自己的缓冲区，正常情况下不会积压
+const MIRROR_QUEUE_SIZE: usize = 64;
+
+/// 按镜像 broker 配置生成连接参数
+pub fn broker_options(broker: &BrokerConfig, client_id: &str, keep_alive: Duration) -> Result<MqttOptions, TlsError> {
+    let mut options = MqttOptions::new(client_id, &broker.host, broker.port);
+    options.set_keep_alive(keep_alive);
+    if let Some(u) = &broker.username {
+        options.set_credentials(u, broker.password.clone().unwrap_or_default());
+    }
+    options.set_transport(broker.tls_settings().transport()?);
+    Ok(options)
+}
+
+/// 主循环持有的镜像句柄；投递样本从不阻塞主循环
+#[derive(Clone)]
+pub struct MirrorHandle {
+    pub name: String,
+    tx: mpsc::Sender<RoutedSample>,
+    stats: Arc<BrokerStats>,
+}
+
+impl MirrorHandle {
+    pub fn offer(&self, topic_prefix: &str, sample: Arc<TimestampedMeasurements>) {
+        if let Err(e) = self.tx.try_send((topic_prefix.to_string(), sample)) {
+            self.stats.buffer_dropped.fetch_add(1, Ordering::Relaxed);
+            debug!("镜像 broker {} 队列已满或已关闭，丢弃样本: {}", self.name, e);
+        }
+    }
+}
+
+/// 为一个镜像 broker 启动独立的连接与发布任务，各自重连、各自缓冲
+pub fn spawn_mirror(
+    broker: &BrokerConfig,
+    config: &DaemonConfig,
+    stats: Arc<BrokerStats>,
+) -> Result<MirrorHandle, TlsError> {
+    let client_id = broker
+        .client_id
+        .clone()
+        .unwrap_or_else(|| format!("{}-{}", config.mqtt_client_id, broker.name));
+    let mut options = broker_options(broker, &client_id, config.timing.mqtt_keep_alive)?;
+    let availability_topic = daemon_availability_topic(&config.mqtt_topic_prefix);
+    options.set_last_will(LastWill::new(&availability_topic, "offline", QoS::AtLeastOnce, true));
+    let (inner, eventloop) = AsyncClient::new(options, 10);
+    let acks = Arc::new(AckTracker::default());
+    let (client, tracked) = acks::track(inner, acks.clone(), 10);
+    let (connected_tx, connected_rx) = watch::channel(false);
+
+    info!("镜像 broker {}: {}:{} (client id {})", broker.name, broker.host, broker.port, client_id);
+    let backoff = Backoff::new(config.timing.mqtt_reconnect_initial, config.timing.mqtt_reconnect_max);
+    tokio::spawn(mirror_eventloop(
+        broker.name.clone(),
+        client,
+        eventloop,
+        acks,
+        availability_topic,
+        backoff,
+        connected_tx,
+        stats.clone(),
+    ));
+
+    let (tx, rx) = mpsc::channel(MIRROR_QUEUE_SIZE);
+    tokio::spawn(mirror_publish_task(
+        broker.name.clone(),
+        tracked,
+        rx,
+        connected_rx,
+        config.topics.clone(),
+        PublishBuffer::new(config.publish_buffer_size),
+        stats.clone(),
+    ));
+
+    Ok(MirrorHandle {
+        name: broker.name.clone(),
+        tx,
+        stats,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn mirror_eventloop(
+    name: String,
+    client: AsyncClient,
+    mut eventloop: rumqttc::EventLoop,
+    acks: Arc<AckTracker>,
+    availability_topic: String,
+    mut backoff: Backoff,
+    connected_tx: watch::Sender<bool>,
+    stats: Arc<BrokerStats>,
+) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("镜像 broker {} 连接成功。", name);
+                backoff.reset();
+                connected_tx.send_replace(true);
+                stats.connected.store(true, Ordering::Relaxed);
+                let client = client.clone();
+                let topic = availability_topic.clone();
+                // 发布队列由本循环消费，不能在这里直接 await
+                tokio::spawn(async move {
+                    if let Err(e) = client.publish(topic, QoS::AtLeastOnce, true, "online").await {
+                        error!("发布在线状态失败: {:?}", e);
+                    }
+                });
+            }
+            Ok(Event::Outgoing(Outgoing::Publish(pkid))) if pkid != 0 => acks.on_outgoing(pkid),
+            Ok(Event::Incoming(Packet::PubAck(ack))) => {
+                acks.on_puback(ack.pkid);
+            }
+            Ok(event) => debug!("镜像 broker {} MQTT Event: {:?}", name, event),
+            Err(e) => {
+                connected_tx.send_replace(false);
+                stats.connected.store(false, Ordering::Relaxed);
+                // 与主 broker 相同：认证被拒、TLS 失败时重连无益，只停止这一个镜像
+                if let Some(reason) = fatal_connection_error(&e) {
+                    error!(broker = %name, reason = %reason, "镜像 broker 连接出现不可恢复的错误，停止该镜像");
+                    return;
+                }
+                let delay = backoff.next_delay();
+                warn!(broker = %name, error = %e, error_kind = connection_error_kind(&e), retry_in = ?delay, "镜像 broker 连接错误，稍后重试");
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+// 与主 broker 相同：消息不阻塞地交给客户端，样本全部确认后才移出缓冲区
+async fn mirror_publish_task(
+    name: String,
+    client: TrackedClient,
+    mut rx: mpsc::Receiver<RoutedSample>,
+    mut connected: watch::Receiver<bool>,
+    topics: TopicMap,
+    mut buffer: PublishBuffer<PendingSample>,
+    stats: Arc<BrokerStats>,
+) {
+    let mut acked = client.acks().subscribe();
+    let mut reported_overflow = 0;
+    loop {
+        tokio::select! {
+            sample = rx.recv() => match sample {
+                Some((prefix, sample)) => buffer.push(PendingSample::new(prefix, sample)),
+                None => break,
+            },
+            changed = connected.changed() => if changed.is_err() {
+                // 事件循环已因不可恢复的错误退出，之后投递的样本计入 buffer_dropped
+                warn!("镜像 broker {} 已停止，丢弃缓冲区中的 {} 条样本", name, buffer.len());
+                stats.buffer_depth.store(0, Ordering::Relaxed);
+                break;
+            },
+            Ok(()) = acked.changed(), if !buffer.is_empty() => {}
+        }
+        let online = *connected.borrow();
+        let outcome = drain(&mut buffer, &client, &topics, online).await;
+        stats.published.fetch_add(outcome.delivered, Ordering::Relaxed);
+        if outcome.closed {
+            warn!("镜像 broker {} 的事件循环已停止，样本保留在缓冲区 ({} 条)", name, buffer.len());
+        }
+        stats.buffer_depth.store(buffer.len() as u64, Ordering::Relaxed);
+        // 队列满 (offer) 与缓冲区溢出的丢弃都计入 buffer_dropped
+        stats.buffer_dropped.fetch_add(buffer.dropped() - reported_overflow, Ordering::Relaxed);
+        reported_overflow = buffer.dropped();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_broker::TestBroker;
+    use crate::test_support::PayloadBuilder;
+    use crate::timing::Policy;
+
+    async fn wait_until(done: impl Fn() -> bool) -> bool {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !done() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    #[tokio::test]
+    async fn the_mirror_keeps_buffering_while_acks_are_outstanding() {
+        let broker = TestBroker::start().await;
+        let (_client, tracked) = broker.connect_tracked("mirror");
+        let (tx, rx) = mpsc::channel(MIRROR_QUEUE_SIZE);
+        let (_connected_tx, connected_rx) = watch::channel(true);
+        let stats = Arc::new(BrokerStats::default());
+        broker.withhold_acks("");
+        tokio::spawn(mirror_publish_task(
+            "backup".to_string(),
+            tracked,
+            rx,
+            connected_rx,
+            TopicMap::default(),
+            PublishBuffer::new(4),
+            stats.clone(),
+        ));
+        // 未确认的消息占满 rumqttc 的在途窗口后事件循环停止读取请求，镜像任务仍要继续接收样本
+        for frame_id in 0..10 {
+            tx.send(("ups".to_string(), Arc::new(PayloadBuilder::new().sample(frame_id, frame_id)))).await.unwrap();
+        }
+        assert!(wait_until(|| stats.buffer_dropped.load(Ordering::Relaxed) == 6).await);
+        assert_eq!(stats.published.load(Ordering::Relaxed), 0);
+        assert_eq!(stats.buffer_depth.load(Ordering::Relaxed), 4);
+
+        broker.resume_acks();
+        broker.drop_connections();
+        assert!(wait_until(|| stats.published.load(Ordering::Relaxed) == 4).await);
+        assert!(wait_until(|| stats.buffer_depth.load(Ordering::Relaxed) == 0).await);
+    }
+
+    async fn run_eventloop(broker: &TestBroker, stats: Arc<BrokerStats>) -> watch::Receiver<bool> {
+        run_eventloop_with(broker, stats, Backoff::new(Duration::from_millis(10), Duration::from_millis(50))).await
+    }
+
+    async fn run_eventloop_with(broker: &TestBroker, stats: Arc<BrokerStats>, backoff: Backoff) -> watch::Receiver<bool> {
+        let (client, eventloop) = AsyncClient::new(broker.options("mirror"), 10);
+        let (connected_tx, connected_rx) = watch::channel(false);
+        tokio::spawn(mirror_eventloop(
+            "backup".to_string(),
+            client,
+            eventloop,
+            Arc::new(AckTracker::default()),
+            "ups/daemon/availability".to_string(),
+            backoff,
+            connected_tx,
+            stats,
+        ));
+        connected_rx
+    }
+
+    #[tokio::test]
+    async fn a_refused_login_stops_only_the_mirror() {
+        let broker = TestBroker::start().await;
+        // CONNACK 返回码 5：未授权
+        broker.refuse_connections(5);
+        let stats = Arc::new(BrokerStats::default());
+        let mut connected = run_eventloop(&broker, stats.clone()).await;
+        // 事件循环退出后 connected 的发送端被丢弃
+        let stopped = tokio::time::timeout(Duration::from_secs(5), async { while connected.changed().await.is_ok() {} }).await;
+        assert!(stopped.is_ok(), "认证被拒后镜像应停止重连");
+        assert!(!stats.connected.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn an_unavailable_broker_is_retried() {
+        let broker = TestBroker::start().await;
+        // CONNACK 返回码 3：服务不可用，属于可恢复错误
+        broker.refuse_connections(3);
+        let stats = Arc::new(BrokerStats::default());
+        let mut connected = run_eventloop(&broker, stats.clone()).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(connected.has_changed().is_ok(), "可恢复的错误不应停止镜像");
+        broker.accept_connections();
+        assert!(wait_until(|| stats.connected.load(Ordering::Relaxed)).await);
+        assert!(*connected.borrow_and_update());
+    }
+
+    #[tokio::test]
+    async fn reconnects_follow_the_policy_backoff() {
+        let timing = Policy {
+            mqtt_reconnect_initial: Duration::from_millis(100),
+            mqtt_reconnect_max: Duration::from_millis(200),
+            ..Policy::default()
+        };
+        let broker = TestBroker::start().await;
+        broker.refuse_connections(3);
+        let backoff = Backoff::new(timing.mqtt_reconnect_initial, timing.mqtt_reconnect_max);
+        let _connected = run_eventloop_with(&broker, Arc::new(BrokerStats::default()), backoff).await;
+        // 尝试时刻约为 0、100、300、500 ms；按默认的 1 秒起步只会有一次
+        tokio::time::sleep(Duration::from_millis(650)).await;
+        let attempts = broker.connection_attempts();
+        assert!((3..=5).contains(&attempts), "{} 次连接尝试", attempts);
+    }
+
+    #[tokio::test]
+    async fn the_publish_task_exits_once_the_mirror_stops() {
+        let broker = TestBroker::start().await;
+        let (_client, tracked) = broker.connect_tracked("mirror");
+        let (tx, rx) = mpsc::channel(MIRROR_QUEUE_SIZE);
+        let (connected_tx, connected_rx) = watch::channel(false);
+        let stats = Arc::new(BrokerStats::default());
+        let task = tokio::spawn(mirror_publish_task(
+            "backup".to_string(),
+            tracked,
+            rx,
+            connected_rx,
+            TopicMap::default(),
+            PublishBuffer::new(4),
+            stats.clone(),
+        ));
+        tx.send(("ups".to_string(), Arc::new(PayloadBuilder::new().sample(1, 1)))).await.unwrap();
+        drop(connected_tx);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+        assert!(tx.is_closed());
+        assert_eq!(stats.buffer_depth.load(Ordering::Relaxed), 0);
+    }
+}
@@ -1,36 +1,391 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 
-use log::{debug, error, info};
-use rumqttc::{AsyncClient, Event, MqttOptions, QoS, Transport};
+use futures::future::BoxFuture;
+use tracing::{debug, error, info, warn};
+use rumqttc::{AsyncClient, ConnectReturnCode, ConnectionError, Event, LastWill, MqttOptions, QoS};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
-use crate::data_models::{AllMeasurements, ChargerStatusFlags, ChargerFaultFlags, ProchotLsbFlags, ProchotMsbFlags, SystemStatus as Bq76920SystemStatus}; // Added specific flag types
+use crate::acks::{self, AckTracker, TrackedClient};
+use crate::alarms::{alarm_set_topic_filters, parse_alarm_set_topic, AlarmAck};
+use crate::backoff::Backoff;
+use crate::config::DaemonConfig;
+use crate::error::DaemonError;
+use crate::flag_names::{flags_to_names, NamedFlags};
+use crate::publisher::Publisher;
+use crate::stats::{DaemonStats, DaemonStatsSnapshot};
+use crate::systemd::Heartbeat;
+use crate::tls::TlsError;
+use crate::units::{OutputUnits, Quantity};
+use crate::topics::{self, TopicMap, Topics, CELL_TOPIC_KEYS};
+pub use crate::topics::device_topic_prefix;
+use crate::utils::unix_ms_now;
+use crate::data_models::{AllMeasurements, DerivedMetrics, TimestampedMeasurements, ChargerStatusFlags, ChargerFaultFlags, ProchotLsbFlags, ProchotMsbFlags, SystemStatus as Bq76920SystemStatus}; // Added specific flag types
+
+/// 通过 `{prefix}/cmd/#` 下发的控制命令
+#[derive(Debug, Clone, PartialEq)]
+pub enum MqttCommand {
+    Subscribe,
+    Unsubscribe,
+    Reconnect,
+    /// `report_interval <ms>`：设置固件的推送间隔
+    SetReportInterval(u16),
+    AclProbe,
+    /// `{prefix}/cmd/debug_dump`：载荷 `on` / `off` 设置原始帧转储，空载荷或 `toggle` 切换
+    DebugDump(Option<bool>),
+    /// `history [N]`：最近 N 帧（默认全部）测量数据，JSON 数组发布在 `{prefix}/cmd/result`
+    History(Option<usize>),
+    /// `{前缀}/alarms/<name>/set`：确认告警；载荷不是确认命令时 `ack` 为 None
+    AckAlarm { topic_prefix: String, name: String, ack: Option<AlarmAck> },
+    Unknown(String),
+}
+
+impl MqttCommand {
+    pub fn parse(payload: &[u8]) -> Self {
+        let text = String::from_utf8_lossy(payload).trim().to_ascii_lowercase();
+        match text.as_str() {
+            "subscribe" => MqttCommand::Subscribe,
+            "unsubscribe" => MqttCommand::Unsubscribe,
+            "reconnect" => MqttCommand::Reconnect,
+            "acl_probe" => MqttCommand::AclProbe,
+            "history" => MqttCommand::History(None),
+            _ => {
+                if let Some(Ok(n)) = text.strip_prefix("history ").map(|n| n.trim().parse()) {
+                    MqttCommand::History(Some(n))
+                } else if let Some(Ok(ms)) = text.strip_prefix("report_interval ").map(|ms| ms.trim().parse::<u16>())
+                    && ms > 0
+                {
+                    MqttCommand::SetReportInterval(ms)
+                } else {
+                    MqttCommand::Unknown(text)
+                }
+            }
+        }
+    }
+
+    /// 按子主题解析：`debug_dump` 子主题的载荷是开关状态，其余子主题的载荷是命令本身
+    pub fn parse_topic(subtopic: &str, payload: &[u8]) -> Self {
+        if subtopic != "debug_dump" {
+            return Self::parse(payload);
+        }
+        let text = String::from_utf8_lossy(payload).trim().to_ascii_lowercase();
+        match text.as_str() {
+            "" | "toggle" => MqttCommand::DebugDump(None),
+            "on" | "true" | "1" => MqttCommand::DebugDump(Some(true)),
+            "off" | "false" | "0" => MqttCommand::DebugDump(Some(false)),
+            _ => MqttCommand::Unknown(format!("debug_dump {}", text)),
+        }
+    }
+}
+
+/// 事件循环向主循环报告的连接状态变化
+#[derive(Debug, Clone, PartialEq)]
+pub enum MqttEvent {
+    Connected,
+    /// 连接断开，事件循环会自动重连
+    Disconnected(String),
+    /// 不可恢复的错误（认证被拒、TLS 失败），事件循环已停止
+    FatalAuthError(String),
+}
+
+/// 判断连接错误是否不可恢复；网络类错误返回 None，交给重连处理
+pub fn fatal_connection_error(error: &ConnectionError) -> Option<String> {
+    match error {
+        ConnectionError::ConnectionRefused(
+            code @ (ConnectReturnCode::BadUserNamePassword
+            | ConnectReturnCode::NotAuthorized
+            | ConnectReturnCode::BadClientId
+            | ConnectReturnCode::RefusedProtocolVersion),
+        ) => Some(format!("broker refused connection: {:?}", code)),
+        // rustls 握手失败（证书无效、被拒绝等）以 InvalidData 的 I/O 错误返回
+        ConnectionError::Tls(rumqttc::TlsError::Io(e)) if e.kind() != std::io::ErrorKind::InvalidData => None,
+        ConnectionError::Tls(e) => Some(format!("TLS failure: {}", e)),
+        _ => None,
+    }
+}
+
+/// 日志中使用的连接错误类别
+pub fn connection_error_kind(error: &ConnectionError) -> &'static str {
+    match error {
+        ConnectionError::MqttState(_) => "mqtt_state",
+        ConnectionError::NetworkTimeout => "network_timeout",
+        ConnectionError::FlushTimeout => "flush_timeout",
+        ConnectionError::Tls(_) => "tls",
+        ConnectionError::Io(_) => "io",
+        ConnectionError::ConnectionRefused(_) => "connection_refused",
+        ConnectionError::NotConnAck(_) => "not_connack",
+        ConnectionError::RequestsDone => "requests_done",
+        _ => "other",
+    }
+}
+
+pub fn command_topic_filter(topic_prefix: &str) -> String {
+    format!("{}/cmd/#", topic_prefix)
+}
+
+pub fn command_result_topic(topic_prefix: &str) -> String {
+    format!("{}/cmd/result", topic_prefix)
+}
+
+pub fn daemon_availability_topic(topic_prefix: &str) -> String {
+    format!("{}/daemon/availability", topic_prefix)
+}
+
+pub fn measurements_all_topic(topic_prefix: &str) -> String {
+    format!("{}/{}", topic_prefix, topics::MEASUREMENTS_ALL)
+}
+
+pub fn daemon_heartbeat_topic(topic_prefix: &str) -> String {
+    format!("{}/daemon/heartbeat", topic_prefix)
+}
+
+pub fn shutdown_event_topic(topic_prefix: &str) -> String {
+    format!("{}/events/shutdown", topic_prefix)
+}
+
+/// `{prefix}/events/shutdown` 的载荷，退出前发布
+#[derive(Debug, Serialize)]
+pub struct ShutdownEvent<'a> {
+    pub event: &'static str,
+    pub reason: &'a str,
+    pub ts_unix_ms: u64,
+}
+
+pub fn usb_error_topic(topic_prefix: &str) -> String {
+    format!("{}/events/usb_error", topic_prefix)
+}
+
+/// `{prefix}/events/usb_error` 的载荷；`message` 为错误的可读描述
+#[derive(Debug, Serialize)]
+pub struct UsbErrorEvent<'a> {
+    pub category: &'static str,
+    pub message: &'a str,
+    pub ts_unix_ms: u64,
+}
+
+pub fn usb_stats_topic(topic_prefix: &str) -> String {
+    format!("{}/daemon/usb_stats", topic_prefix)
+}
+
+pub fn daemon_info_topic(topic_prefix: &str) -> String {
+    format!("{}/daemon/info", topic_prefix)
+}
+
+/// 最近一次错误（含错误码），retained 发布
+pub fn daemon_last_error_topic(topic_prefix: &str) -> String {
+    format!("{}/daemon/last_error", topic_prefix)
+}
+
+/// 设备实际匹配到的 VID/PID (retained)，如 `1209:0002`
+pub fn device_info_topic(measurement_prefix: &str) -> String {
+    format!("{}/{}", measurement_prefix, topics::DEVICE_INFO)
+}
+
+pub fn device_usb_id_topic(measurement_prefix: &str) -> String {
+    format!("{}/{}", measurement_prefix, topics::DEVICE_USB_ID)
+}
+
+/// 估算的电量百分比 (retained)
+pub fn soc_topic(measurement_prefix: &str) -> String {
+    format!("{}/{}", measurement_prefix, topics::SOC)
+}
+
+/// 放电剩余时间 (分钟，retained)；不在放电时清空
+pub fn runtime_topic(measurement_prefix: &str) -> String {
+    format!("{}/{}", measurement_prefix, topics::RUNTIME)
+}
+
+/// 充满时间 (分钟，retained)；不在充电时清空
+pub fn time_to_full_topic(measurement_prefix: &str) -> String {
+    format!("{}/{}", measurement_prefix, topics::TIME_TO_FULL)
+}
+
+pub fn device_availability_topic(measurement_prefix: &str) -> String {
+    format!("{}/{}", measurement_prefix, topics::DEVICE_AVAILABILITY)
+}
+
+/// 每次连接成功时以 retained 方式发布的出生消息，便于区分多台主机上的部署
+#[derive(Debug, Clone, Serialize)]
+pub struct DaemonInfo {
+    pub version: &'static str,
+    pub build_timestamp: u64,
+    /// 按优先级排列的候选 VID/PID，如 `["1209:0002", "1209:0003"]`
+    pub usb_ids: Vec<String>,
+    pub topic_prefix: String,
+    pub publish_min_interval_ms: u64,
+    pub publish_adaptive: bool,
+}
+
+impl DaemonInfo {
+    pub fn from_config(config: &DaemonConfig) -> Self {
+        DaemonInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            build_timestamp: env!("UPS120_BUILD_TIMESTAMP").parse().unwrap_or(0),
+            usb_ids: config.usb_ids.iter().map(ToString::to_string).collect(),
+            topic_prefix: config.mqtt_topic_prefix.clone(),
+            publish_min_interval_ms: config.publish_min_interval.as_millis() as u64,
+            publish_adaptive: config.publish_adaptive,
+        }
+    }
+}
+
+type ConnectHook = Arc<dyn Fn(AsyncClient) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// 每次收到 ConnAck（包括断线重连后）时执行的回调，
+/// 用于重新订阅命令主题、重新发布 retained 的在线状态等。
+#[derive(Clone, Default)]
+pub struct ConnectHooks(Arc<Mutex<Vec<ConnectHook>>>);
+
+impl ConnectHooks {
+    pub fn register<F>(&self, hook: F)
+    where
+        F: Fn(AsyncClient) -> BoxFuture<'static, ()> + Send + Sync + 'static,
+    {
+        self.0.lock().unwrap().push(Arc::new(hook));
+    }
+
+    /// 每次连接成功时以 retained 方式发布固定载荷
+    pub fn register_retained(&self, topic: String, payload: String) {
+        self.register(move |client| {
+            let topic = topic.clone();
+            let payload = payload.clone();
+            Box::pin(async move {
+                if let Err(e) = client.publish(&topic, QoS::AtLeastOnce, true, payload).await {
+                    error!(topic = %topic, error = ?e, "发布 retained 消息失败");
+                }
+            })
+        });
+    }
+
+    // 回调在独立任务中执行：它们需要 await 发布队列，而队列只有事件循环在消费
+    pub fn run_all(&self, client: &AsyncClient) {
+        let hooks = self.0.lock().unwrap().clone();
+        for hook in hooks {
+            tokio::spawn(hook(client.clone()));
+        }
+    }
+}
+
+/// 按配置生成连接参数（broker 地址、认证、传输方式），不含遗嘱消息
+pub fn mqtt_options(config: &DaemonConfig, client_id: &str) -> Result<MqttOptions, TlsError> {
+    let mut mqtt_options = MqttOptions::new(client_id, &config.mqtt_broker_host, config.mqtt_broker_port);
+    mqtt_options.set_keep_alive(config.timing.mqtt_keep_alive);
+    if let Some(u) = &config.mqtt_username {
+        mqtt_options.set_credentials(u, config.mqtt_password.clone().unwrap_or_default());
+    }
+    mqtt_options.set_transport(config.mqtt_tls.transport()?); // 默认使用 TCP，配置了证书时使用 TLS
+    Ok(mqtt_options)
+}
+
+/// 主 broker 的连接：客户端与后台运行的事件循环
+pub struct MqttSession {
+    pub client: AsyncClient,
+    /// 发布时返回回执、可查询是否已被 broker 确认的客户端
+    pub tracked: TrackedClient,
+    /// 取消后事件循环退出；退出时应在退出消息被确认之后再取消
+    pub stop: CancellationToken,
+    pub event_loop: JoinHandle<()>,
+}
 
 // MQTT 连接和发布函数
 pub async fn connect_mqtt_and_publish(
-    host: &str,
-    port: u16,
-    username: Option<String>,
-    password: Option<String>,
-    client_id: &str,
-    _topic_prefix: &str, // 添加下划线
-) -> Result<AsyncClient, Box<dyn std::error::Error>> {
-    let mut mqtt_options = MqttOptions::new(client_id, host, port);
-    mqtt_options.set_keep_alive(Duration::from_secs(5));
-    if let Some(u) = username {
-        mqtt_options.set_credentials(u, password.unwrap_or_default());
-    }
-    mqtt_options.set_transport(Transport::Tcp); // 默认使用 TCP
-
-    let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10); // eventloop 声明为可变
-
-    tokio::spawn(async move {
+    config: &DaemonConfig,
+    command_tx: mpsc::Sender<MqttCommand>,
+    events_tx: mpsc::UnboundedSender<MqttEvent>,
+    stats: Arc<DaemonStats>,
+    acks: Arc<AckTracker>,
+    hooks: ConnectHooks,
+    heartbeat: Heartbeat,
+) -> Result<MqttSession, DaemonError> {
+    let topic_prefix = config.mqtt_topic_prefix.as_str();
+    let mut mqtt_options = mqtt_options(config, &config.mqtt_client_id)?;
+    let availability_topic = daemon_availability_topic(topic_prefix);
+    mqtt_options.set_last_will(LastWill::new(&availability_topic, "offline", QoS::AtLeastOnce, true));
+
+    let (inner, mut eventloop) = AsyncClient::new(mqtt_options, 10); // eventloop 声明为可变
+    let (client, tracked) = acks::track(inner, acks.clone(), 10);
+
+    // clean session 下订阅不会在重连后保留，因此在每次连接成功时重新订阅并声明在线
+    let mut command_filters = vec![command_topic_filter(topic_prefix)];
+    command_filters.extend(alarm_set_topic_filters(topic_prefix));
+    hooks.register(move |client| {
+        let command_filters = command_filters.clone();
+        let availability_topic = availability_topic.clone();
+        Box::pin(async move {
+            for filter in &command_filters {
+                if let Err(e) = client.subscribe(filter, QoS::AtLeastOnce).await {
+                    error!(topic = %filter, error = ?e, "订阅 MQTT 命令主题失败");
+                }
+            }
+            if let Err(e) = client.publish(&availability_topic, QoS::AtLeastOnce, true, "online").await {
+                error!(topic = %availability_topic, error = ?e, "发布在线状态失败");
+            }
+        })
+    });
+
+    let mut backoff = Backoff::new(config.timing.mqtt_reconnect_initial, config.timing.mqtt_reconnect_max);
+    let hook_client = client.clone();
+    let command_prefix = format!("{}/cmd/", topic_prefix);
+    let alarm_prefix = topic_prefix.to_string();
+    let result_topic = command_result_topic(topic_prefix);
+    let stop = CancellationToken::new();
+    let stopped = stop.clone();
+    let event_loop = tokio::spawn(async move {
+        let mut had_error = false;
         loop {
-            match eventloop.poll().await {
+            // 连接空闲时 poll 也会按 keep alive 周期返回 PingReq，每次返回都说明事件循环仍在运行
+            let event = tokio::select! {
+                _ = stopped.cancelled() => break,
+                event = eventloop.poll() => event,
+            };
+            heartbeat.beat();
+            match event {
                 Ok(Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
-                    info!("MQTT 连接成功!");
+                    if had_error {
+                        info!("MQTT 重连成功，重新订阅并发布在线状态。");
+                    } else {
+                        info!("MQTT 连接成功!");
+                    }
+                    had_error = false;
+                    backoff.reset();
+                    stats.mqtt_backoff_ms.store(0, Ordering::Relaxed);
+                    let _ = events_tx.send(MqttEvent::Connected);
+                    stats.primary.connected.store(true, Ordering::Relaxed);
+                    hooks.run_all(&hook_client);
                 }
                 Ok(Event::Incoming(rumqttc::Packet::Publish(p))) => {
                     info!("收到 MQTT 消息: {:?}", p);
+                    let command = if let Some(subtopic) = p.topic.strip_prefix(&command_prefix)
+                        && p.topic != result_topic
+                    {
+                        Some(MqttCommand::parse_topic(subtopic, &p.payload))
+                    } else {
+                        parse_alarm_set_topic(&alarm_prefix, &p.topic).map(|(topic_prefix, name)| MqttCommand::AckAlarm {
+                            topic_prefix,
+                            name,
+                            ack: AlarmAck::parse(&p.payload, unix_ms_now()),
+                        })
+                    };
+                    // 不能在事件循环中阻塞等待，否则发布队列无法被消费
+                    if let Some(command) = command
+                        && let Err(e) = command_tx.try_send(command)
+                    {
+                        warn!(topic = %p.topic, error = ?e, "转发 MQTT 命令失败");
+                    }
+                }
+                Ok(Event::Outgoing(rumqttc::Outgoing::Publish(pkid))) if pkid != 0 => {
+                    acks.on_outgoing(pkid);
+                    stats.mqtt_inflight.store(acks.inflight() as u64, Ordering::Relaxed);
+                }
+                Ok(Event::Incoming(rumqttc::Packet::PubAck(ack))) => {
+                    if let Some(rtt) = acks.on_puback(ack.pkid) {
+                        stats.mqtt_rtt_ms.store(rtt.as_millis() as u64, Ordering::Relaxed);
+                    }
+                    stats.mqtt_inflight.store(acks.inflight() as u64, Ordering::Relaxed);
                 }
                 Ok(Event::Outgoing(rumqttc::Outgoing::PingReq)) => {
                     debug!("MQTT PingReq");
@@ -42,101 +397,419 @@ pub async fn connect_mqtt_and_publish(
                     debug!("MQTT Event: {:?}", event);
                 }
                 Err(e) => {
-                    error!("MQTT EventLoop 错误: {:?}", e);
-                    tokio::time::sleep(Duration::from_secs(5)).await; // 错误后等待
+                    stats.primary.connected.store(false, Ordering::Relaxed);
+                    if let Some(reason) = fatal_connection_error(&e) {
+                        error!(reason = %reason, "MQTT 连接出现不可恢复的错误，停止重连");
+                        let _ = events_tx.send(MqttEvent::FatalAuthError(reason));
+                        return;
+                    }
+                    let delay = backoff.next_delay();
+                    stats.mqtt_backoff_ms.store(delay.as_millis() as u64, Ordering::Relaxed);
+                    error!(error = %e, error_kind = connection_error_kind(&e), retry_in = ?delay, "MQTT 事件循环错误，稍后重试");
+                    if !had_error {
+                        let _ = events_tx.send(MqttEvent::Disconnected(e.to_string()));
+                    }
+                    had_error = true;
+                    // 未确认的发布由 rumqttc 在重连后以原 pkid 重发，仍然计入在途
+                    stats.mqtt_inflight.store(acks.inflight() as u64, Ordering::Relaxed);
+                    // 错误后指数退避
+                    tokio::select! {
+                        _ = stopped.cancelled() => break,
+                        _ = tokio::time::sleep(delay) => {}
+                    }
                 }
             }
         }
+        info!("MQTT 事件循环退出。");
     });
 
-    Ok(client)
+    Ok(MqttSession { client, tracked, stop, event_loop })
 }
 
-pub async fn publish_measurements(
-    client: &AsyncClient,
+pub async fn publish_daemon_stats(
+    client: &impl Publisher,
+    topic_prefix: &str,
+    snapshot: &DaemonStatsSnapshot,
+) -> Result<(), DaemonError> {
+    let payload = serde_json::to_string(snapshot)?;
+    client.publish(format!("{}/daemon/stats", topic_prefix), QoS::AtLeastOnce, false, payload).await?;
+    Ok(())
+}
+
+/// 心跳：递增序号与发布时间，不 retained，broker 端据此判断守护进程是否存活
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct DaemonHeartbeat {
+    pub seq: u64,
+    pub ts_unix_ms: u64,
+}
+
+pub async fn publish_heartbeat(client: &impl Publisher, topic_prefix: &str, heartbeat: &DaemonHeartbeat) -> Result<(), DaemonError> {
+    let payload = serde_json::to_string(heartbeat)?;
+    client.publish(daemon_heartbeat_topic(topic_prefix), QoS::AtLeastOnce, false, payload).await?;
+    Ok(())
+}
+
+/// 主循环按 `HEARTBEAT_INTERVAL_SECS` 周期发布心跳，记录序号与连续失败次数
+#[derive(Debug, Default)]
+pub struct HeartbeatSender {
+    seq: u64,
+    failures_in_row: u32,
+}
+
+impl HeartbeatSender {
+    /// 发布下一个心跳；发布失败（包括队列已满、事件循环已停止）时累计连续失败次数，
+    /// 连续第二次及之后的失败计入 `heartbeat_failures`
+    pub async fn beat(&mut self, client: &impl Publisher, topic_prefix: &str, stats: &DaemonStats) {
+        self.seq += 1;
+        let heartbeat = DaemonHeartbeat { seq: self.seq, ts_unix_ms: unix_ms_now() };
+        match publish_heartbeat(client, topic_prefix, &heartbeat).await {
+            Ok(()) => self.failures_in_row = 0,
+            Err(e) => {
+                self.failures_in_row += 1;
+                if self.failures_in_row >= 2 {
+                    stats.heartbeat_failures.fetch_add(1, Ordering::Relaxed);
+                    error!(failures_in_row = self.failures_in_row, "连续发布心跳失败: {:?}", e);
+                } else {
+                    warn!("发布心跳失败: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// 以 RFC3339 字符串 (UTC) retained 发布最近一次样本的接收时间
+pub async fn publish_last_update(
+    client: &impl Publisher,
+    topics: &Topics,
+    ts_unix_ms: u64,
+) -> Result<(), DaemonError> {
+    let time = std::time::UNIX_EPOCH + Duration::from_millis(ts_unix_ms);
+    let payload = humantime::format_rfc3339_millis(time).to_string();
+    client
+        .publish(topics.last_update.as_str(), QoS::AtLeastOnce, true, payload)
+        .await?;
+    Ok(())
+}
+
+/// 发布一个样本：完整 JSON、`last_update` 与各字段主题。主 broker 与镜像 broker 共用
+pub async fn publish_sample(
+    client: &impl Publisher,
     topic_prefix: &str,
-    measurements: AllMeasurements<5>,
-) -> Result<(), Box<dyn std::error::Error>> {
+    map: &TopicMap,
+    measurements: &TimestampedMeasurements,
+) -> Result<(), DaemonError> {
+    debug!("[LOG POINT 3.5] Publishing Processed Measurements to MQTT (frame {}): {:?}", measurements.frame_id, measurements);
+    let topics = map.for_prefix(topic_prefix);
+    publish_measurements_json(client, &topics.measurements_all, measurements, map.flags_as_names(), map.units()).await?;
+    publish_last_update(client, &topics, measurements.ts_unix_ms).await?;
+    if let Some(soc) = measurements.battery.soc_percent {
+        client.publish(topics.soc.as_str(), QoS::AtLeastOnce, true, soc.to_string()).await?;
+    }
+    // 空的 retained 载荷清除上一次的估算值
+    for (topic, minutes) in [
+        (&topics.runtime, measurements.battery.runtime_min),
+        (&topics.time_to_full, measurements.battery.time_to_full_min),
+    ] {
+        client.publish(topic.as_str(), QoS::AtLeastOnce, true, minutes.map(|m| m.to_string()).unwrap_or_default()).await?;
+    }
+    publish_derived(client, map, &topics, &measurements.derived).await?;
+    publish_measurements(client, map, &topics, &measurements.data).await
+}
+
+/// 发布电池包总压、单体极值与转换效率；效率未计算时不发布
+pub async fn publish_derived(
+    client: &impl Publisher,
+    map: &TopicMap,
+    topics: &Topics,
+    derived: &DerivedMetrics,
+) -> Result<(), DaemonError> {
+    let units = map.units();
+    client.publish(topics.field("pack_voltage"), QoS::AtLeastOnce, false, units.format(Quantity::Voltage, derived.pack_voltage)).await?;
+    client.publish(topics.field("cell_min"), QoS::AtLeastOnce, false, units.format(Quantity::Voltage, derived.cell_min)).await?;
+    client.publish(topics.field("cell_max"), QoS::AtLeastOnce, false, units.format(Quantity::Voltage, derived.cell_max)).await?;
+    client.publish(topics.field("cell_delta"), QoS::AtLeastOnce, false, units.format(Quantity::Voltage, derived.cell_delta)).await?;
+    if let Some(efficiency) = derived.efficiency_percent {
+        client.publish(topics.field("efficiency_percent"), QoS::AtLeastOnce, false, units.format(Quantity::Other, efficiency)).await?;
+    }
+    Ok(())
+}
+
+/// 将完整测量数据（含原始接收时间戳）以 JSON 发布到 `topic`；数值按 `units` 换算与舍入，并附带 `units` 对象
+pub async fn publish_measurements_json(
+    client: &impl Publisher,
+    topic: &str,
+    measurements: &TimestampedMeasurements,
+    flags_as_names: bool,
+    units: &OutputUnits,
+) -> Result<(), DaemonError> {
+    let payload = measurements_json(measurements, flags_as_names, units)?.to_string();
+    client.publish(topic, QoS::AtLeastOnce, false, payload).await?;
+    Ok(())
+}
+
+/// `measurements_all` 的 JSON：按配置转换标志位并应用单位与精度
+pub fn measurements_json(
+    measurements: &TimestampedMeasurements,
+    flags_as_names: bool,
+    units: &OutputUnits,
+) -> Result<serde_json::Value, serde_json::Error> {
+    let mut json = serde_json::to_value(measurements)?;
+    if flags_as_names {
+        flags_to_names(&mut json);
+    }
+    units.apply_json(&mut json);
+    Ok(json)
+}
+
+// 标志位主题的载荷，不经过格式化
+fn flag_payload(set: bool) -> &'static str {
+    if set { "true" } else { "false" }
+}
+
+pub async fn publish_measurements(
+    client: &impl Publisher,
+    map: &TopicMap,
+    topics: &Topics,
+    measurements: &AllMeasurements<5>,
+) -> Result<(), DaemonError> {
+    let units = map.units();
     // 发布 BQ25730 测量数据
     let bq25730 = &measurements.bq25730;
-    client.publish(format!("{}/bq25730/psys", topic_prefix), QoS::AtLeastOnce, false, bq25730.psys.to_string()).await?;
-    client.publish(format!("{}/bq25730/vbus", topic_prefix), QoS::AtLeastOnce, false, bq25730.vbus.to_string()).await?;
-    client.publish(format!("{}/bq25730/idchg", topic_prefix), QoS::AtLeastOnce, false, bq25730.idchg.to_string()).await?;
-    client.publish(format!("{}/bq25730/ichg", topic_prefix), QoS::AtLeastOnce, false, bq25730.ichg.to_string()).await?;
-    client.publish(format!("{}/bq25730/cmpin", topic_prefix), QoS::AtLeastOnce, false, bq25730.cmpin.to_string()).await?;
-    client.publish(format!("{}/bq25730/iin", topic_prefix), QoS::AtLeastOnce, false, bq25730.iin.to_string()).await?;
-    client.publish(format!("{}/bq25730/vbat", topic_prefix), QoS::AtLeastOnce, false, bq25730.vbat.to_string()).await?;
-    client.publish(format!("{}/bq25730/vsys", topic_prefix), QoS::AtLeastOnce, false, bq25730.vsys.to_string()).await?;
+    client.publish(topics.field("psys"), QoS::AtLeastOnce, false, units.format(Quantity::Power, bq25730.psys)).await?;
+    client.publish(topics.field("vbus"), QoS::AtLeastOnce, false, units.format(Quantity::Voltage, bq25730.vbus)).await?;
+    client.publish(topics.field("idchg"), QoS::AtLeastOnce, false, units.format(Quantity::Current, bq25730.idchg)).await?;
+    client.publish(topics.field("ichg"), QoS::AtLeastOnce, false, units.format(Quantity::Current, bq25730.ichg)).await?;
+    client.publish(topics.field("cmpin"), QoS::AtLeastOnce, false, units.format(Quantity::Voltage, bq25730.cmpin)).await?;
+    client.publish(topics.field("iin"), QoS::AtLeastOnce, false, units.format(Quantity::Current, bq25730.iin)).await?;
+    client.publish(topics.field("vbat"), QoS::AtLeastOnce, false, units.format(Quantity::Voltage, bq25730.vbat)).await?;
+    client.publish(topics.field("vsys"), QoS::AtLeastOnce, false, units.format(Quantity::Voltage, bq25730.vsys)).await?;
 
     // 发布 BQ76920 测量数据
     let bq76920 = &measurements.bq76920;
-    for (i, voltage) in bq76920.cell_voltages.iter().enumerate() {
-        client.publish(format!("{}/bq76920/cell_voltages/{}", topic_prefix, i), QoS::AtLeastOnce, false, voltage.to_string()).await?;
+    for (i, voltage) in bq76920.cells().iter().enumerate() {
+        client.publish(topics.field(CELL_TOPIC_KEYS[i]), QoS::AtLeastOnce, false, units.format(Quantity::Voltage, *voltage)).await?;
+    }
+    // 传感器故障时没有温度，不发布
+    if let Some(ts1) = bq76920.temperatures.ts1 {
+        client.publish(topics.field("ts1"), QoS::AtLeastOnce, false, units.format(Quantity::Other, ts1)).await?;
+    }
+    client.publish(topics.field("coulomb_counter"), QoS::AtLeastOnce, false, units.format(Quantity::Current, bq76920.coulomb_counter)).await?;
+    let system_status = if map.flags_as_names() {
+        serde_json::to_string(&bq76920.system_status.names())?
+    } else {
+        format!("{:?}", bq76920.system_status) // 使用 Debug 格式化
+    };
+    client.publish(topics.field("system_status"), QoS::AtLeastOnce, false, system_status).await?;
+    client.publish(topics.field("mos_status"), QoS::AtLeastOnce, false, format!("{:?}", bq76920.mos_status)).await?; // 使用 Debug 格式化
+    if map.mos_status_raw() {
+        client.publish(topics.field("mos_status_raw"), QoS::AtLeastOnce, false, bq76920.mos_status.as_bits().to_string()).await?;
     }
-    client.publish(format!("{}/bq76920/temperatures/ts1", topic_prefix), QoS::AtLeastOnce, false, bq76920.temperatures.ts1.to_string()).await?;
-    client.publish(format!("{}/bq76920/coulomb_counter", topic_prefix), QoS::AtLeastOnce, false, bq76920.coulomb_counter.to_string()).await?;
-    client.publish(format!("{}/bq76920/system_status", topic_prefix), QoS::AtLeastOnce, false, format!("{:?}", bq76920.system_status)).await?; // 使用 Debug 格式化
-    client.publish(format!("{}/bq76920/mos_status", topic_prefix), QoS::AtLeastOnce, false, format!("{:?}", bq76920.mos_status)).await?; // 使用 Debug 格式化
 
     // --- Publish BQ25730 Status ---
     let bq25730_status = &measurements.bq25730_alerts; // Renamed for clarity, still Bq25730Alerts type
-    let bq25730_status_base_topic = format!("{}/bq25730/status", topic_prefix); // Changed "alerts" to "status"
 
     // ChargerStatusFlags
     let csf = bq25730_status.charger_status_flags;
-    client.publish(format!("{}/charger/stat_ac", bq25730_status_base_topic), QoS::AtLeastOnce, false, csf.contains(ChargerStatusFlags::STAT_AC).to_string()).await?;
-    client.publish(format!("{}/charger/ico_done", bq25730_status_base_topic), QoS::AtLeastOnce, false, csf.contains(ChargerStatusFlags::ICO_DONE).to_string()).await?;
-    client.publish(format!("{}/charger/in_vap", bq25730_status_base_topic), QoS::AtLeastOnce, false, csf.contains(ChargerStatusFlags::IN_VAP).to_string()).await?;
-    client.publish(format!("{}/charger/in_vindpm", bq25730_status_base_topic), QoS::AtLeastOnce, false, csf.contains(ChargerStatusFlags::IN_VINDPM).to_string()).await?;
-    client.publish(format!("{}/charger/in_iin_dpm", bq25730_status_base_topic), QoS::AtLeastOnce, false, csf.contains(ChargerStatusFlags::IN_IIN_DPM).to_string()).await?;
-    client.publish(format!("{}/charger/in_fchrg", bq25730_status_base_topic), QoS::AtLeastOnce, false, csf.contains(ChargerStatusFlags::IN_FCHRG).to_string()).await?;
-    client.publish(format!("{}/charger/in_pchrg", bq25730_status_base_topic), QoS::AtLeastOnce, false, csf.contains(ChargerStatusFlags::IN_PCHRG).to_string()).await?;
-    client.publish(format!("{}/charger/in_otg", bq25730_status_base_topic), QoS::AtLeastOnce, false, csf.contains(ChargerStatusFlags::IN_OTG).to_string()).await?;
+    client.publish(topics.field("charger_stat_ac"), QoS::AtLeastOnce, false, flag_payload(csf.contains(ChargerStatusFlags::STAT_AC))).await?;
+    client.publish(topics.field("charger_ico_done"), QoS::AtLeastOnce, false, flag_payload(csf.contains(ChargerStatusFlags::ICO_DONE))).await?;
+    client.publish(topics.field("charger_in_vap"), QoS::AtLeastOnce, false, flag_payload(csf.contains(ChargerStatusFlags::IN_VAP))).await?;
+    client.publish(topics.field("charger_in_vindpm"), QoS::AtLeastOnce, false, flag_payload(csf.contains(ChargerStatusFlags::IN_VINDPM))).await?;
+    client.publish(topics.field("charger_in_iin_dpm"), QoS::AtLeastOnce, false, flag_payload(csf.contains(ChargerStatusFlags::IN_IIN_DPM))).await?;
+    client.publish(topics.field("charger_in_fchrg"), QoS::AtLeastOnce, false, flag_payload(csf.contains(ChargerStatusFlags::IN_FCHRG))).await?;
+    client.publish(topics.field("charger_in_pchrg"), QoS::AtLeastOnce, false, flag_payload(csf.contains(ChargerStatusFlags::IN_PCHRG))).await?;
+    client.publish(topics.field("charger_in_otg"), QoS::AtLeastOnce, false, flag_payload(csf.contains(ChargerStatusFlags::IN_OTG))).await?;
 
     // ChargerFaultFlags
     let cff = bq25730_status.charger_fault_flags;
-    client.publish(format!("{}/charger_fault/acov", bq25730_status_base_topic), QoS::AtLeastOnce, false, cff.contains(ChargerFaultFlags::FAULT_ACOV).to_string()).await?;
-    client.publish(format!("{}/charger_fault/batoc", bq25730_status_base_topic), QoS::AtLeastOnce, false, cff.contains(ChargerFaultFlags::FAULT_BATOC).to_string()).await?;
-    client.publish(format!("{}/charger_fault/acoc", bq25730_status_base_topic), QoS::AtLeastOnce, false, cff.contains(ChargerFaultFlags::FAULT_ACOC).to_string()).await?;
-    client.publish(format!("{}/charger_fault/sysovp", bq25730_status_base_topic), QoS::AtLeastOnce, false, cff.contains(ChargerFaultFlags::FAULT_SYSOVP).to_string()).await?;
-    client.publish(format!("{}/charger_fault/vsys_uvp", bq25730_status_base_topic), QoS::AtLeastOnce, false, cff.contains(ChargerFaultFlags::FAULT_VSYS_UVP).to_string()).await?;
-    client.publish(format!("{}/charger_fault/conv_off", bq25730_status_base_topic), QoS::AtLeastOnce, false, cff.contains(ChargerFaultFlags::FAULT_CONV_OFF).to_string()).await?;
-    client.publish(format!("{}/charger_fault/otg_ovp", bq25730_status_base_topic), QoS::AtLeastOnce, false, cff.contains(ChargerFaultFlags::FAULT_OTG_OVP).to_string()).await?;
-    client.publish(format!("{}/charger_fault/otg_uvp", bq25730_status_base_topic), QoS::AtLeastOnce, false, cff.contains(ChargerFaultFlags::FAULT_OTG_UVP).to_string()).await?;
+    client.publish(topics.field("charger_fault_acov"), QoS::AtLeastOnce, false, flag_payload(cff.contains(ChargerFaultFlags::FAULT_ACOV))).await?;
+    client.publish(topics.field("charger_fault_batoc"), QoS::AtLeastOnce, false, flag_payload(cff.contains(ChargerFaultFlags::FAULT_BATOC))).await?;
+    client.publish(topics.field("charger_fault_acoc"), QoS::AtLeastOnce, false, flag_payload(cff.contains(ChargerFaultFlags::FAULT_ACOC))).await?;
+    client.publish(topics.field("charger_fault_sysovp"), QoS::AtLeastOnce, false, flag_payload(cff.contains(ChargerFaultFlags::FAULT_SYSOVP))).await?;
+    client.publish(topics.field("charger_fault_vsys_uvp"), QoS::AtLeastOnce, false, flag_payload(cff.contains(ChargerFaultFlags::FAULT_VSYS_UVP))).await?;
+    client.publish(topics.field("charger_fault_conv_off"), QoS::AtLeastOnce, false, flag_payload(cff.contains(ChargerFaultFlags::FAULT_CONV_OFF))).await?;
+    client.publish(topics.field("charger_fault_otg_ovp"), QoS::AtLeastOnce, false, flag_payload(cff.contains(ChargerFaultFlags::FAULT_OTG_OVP))).await?;
+    client.publish(topics.field("charger_fault_otg_uvp"), QoS::AtLeastOnce, false, flag_payload(cff.contains(ChargerFaultFlags::FAULT_OTG_UVP))).await?;
 
     // ProchotLsbFlags
     let plf = bq25730_status.prochot_lsb_flags;
-    client.publish(format!("{}/prochot/lsb_stat_vindpm", bq25730_status_base_topic), QoS::AtLeastOnce, false, plf.contains(ProchotLsbFlags::STAT_VINDPM).to_string()).await?;
-    client.publish(format!("{}/prochot/lsb_stat_comp", bq25730_status_base_topic), QoS::AtLeastOnce, false, plf.contains(ProchotLsbFlags::STAT_COMP).to_string()).await?;
-    client.publish(format!("{}/prochot/lsb_stat_icrit", bq25730_status_base_topic), QoS::AtLeastOnce, false, plf.contains(ProchotLsbFlags::STAT_ICRIT).to_string()).await?;
-    client.publish(format!("{}/prochot/lsb_stat_inom", bq25730_status_base_topic), QoS::AtLeastOnce, false, plf.contains(ProchotLsbFlags::STAT_INOM).to_string()).await?;
-    client.publish(format!("{}/prochot/lsb_stat_idchg1", bq25730_status_base_topic), QoS::AtLeastOnce, false, plf.contains(ProchotLsbFlags::STAT_IDCHG1).to_string()).await?;
-    client.publish(format!("{}/prochot/lsb_stat_vsys", bq25730_status_base_topic), QoS::AtLeastOnce, false, plf.contains(ProchotLsbFlags::STAT_VSYS).to_string()).await?;
-    client.publish(format!("{}/prochot/lsb_stat_bat_removal", bq25730_status_base_topic), QoS::AtLeastOnce, false, plf.contains(ProchotLsbFlags::STAT_BAT_REMOVAL).to_string()).await?;
-    client.publish(format!("{}/prochot/lsb_stat_adpt_removal", bq25730_status_base_topic), QoS::AtLeastOnce, false, plf.contains(ProchotLsbFlags::STAT_ADPT_REMOVAL).to_string()).await?;
+    client.publish(topics.field("prochot_lsb_stat_vindpm"), QoS::AtLeastOnce, false, flag_payload(plf.contains(ProchotLsbFlags::STAT_VINDPM))).await?;
+    client.publish(topics.field("prochot_lsb_stat_comp"), QoS::AtLeastOnce, false, flag_payload(plf.contains(ProchotLsbFlags::STAT_COMP))).await?;
+    client.publish(topics.field("prochot_lsb_stat_icrit"), QoS::AtLeastOnce, false, flag_payload(plf.contains(ProchotLsbFlags::STAT_ICRIT))).await?;
+    client.publish(topics.field("prochot_lsb_stat_inom"), QoS::AtLeastOnce, false, flag_payload(plf.contains(ProchotLsbFlags::STAT_INOM))).await?;
+    client.publish(topics.field("prochot_lsb_stat_idchg1"), QoS::AtLeastOnce, false, flag_payload(plf.contains(ProchotLsbFlags::STAT_IDCHG1))).await?;
+    client.publish(topics.field("prochot_lsb_stat_vsys"), QoS::AtLeastOnce, false, flag_payload(plf.contains(ProchotLsbFlags::STAT_VSYS))).await?;
+    client.publish(topics.field("prochot_lsb_stat_bat_removal"), QoS::AtLeastOnce, false, flag_payload(plf.contains(ProchotLsbFlags::STAT_BAT_REMOVAL))).await?;
+    client.publish(topics.field("prochot_lsb_stat_adpt_removal"), QoS::AtLeastOnce, false, flag_payload(plf.contains(ProchotLsbFlags::STAT_ADPT_REMOVAL))).await?;
 
     // ProchotMsbFlags
     let pmf = bq25730_status.prochot_msb_flags;
-    client.publish(format!("{}/prochot/msb_en_prochot_ext", bq25730_status_base_topic), QoS::AtLeastOnce, false, pmf.contains(ProchotMsbFlags::EN_PROCHOT_EXT).to_string()).await?;
-    client.publish(format!("{}/prochot/msb_prochot_clear", bq25730_status_base_topic), QoS::AtLeastOnce, false, pmf.contains(ProchotMsbFlags::PROCHOT_CLEAR).to_string()).await?;
-    client.publish(format!("{}/prochot/msb_stat_vap_fail", bq25730_status_base_topic), QoS::AtLeastOnce, false, pmf.contains(ProchotMsbFlags::STAT_VAP_FAIL).to_string()).await?;
-    client.publish(format!("{}/prochot/msb_stat_exit_vap", bq25730_status_base_topic), QoS::AtLeastOnce, false, pmf.contains(ProchotMsbFlags::STAT_EXIT_VAP).to_string()).await?;
-    client.publish(format!("{}/prochot/width", bq25730_status_base_topic), QoS::AtLeastOnce, false, bq25730_status.prochot_width.to_string()).await?;
+    client.publish(topics.field("prochot_msb_en_prochot_ext"), QoS::AtLeastOnce, false, flag_payload(pmf.contains(ProchotMsbFlags::EN_PROCHOT_EXT))).await?;
+    client.publish(topics.field("prochot_msb_prochot_clear"), QoS::AtLeastOnce, false, flag_payload(pmf.contains(ProchotMsbFlags::PROCHOT_CLEAR))).await?;
+    client.publish(topics.field("prochot_msb_stat_vap_fail"), QoS::AtLeastOnce, false, flag_payload(pmf.contains(ProchotMsbFlags::STAT_VAP_FAIL))).await?;
+    client.publish(topics.field("prochot_msb_stat_exit_vap"), QoS::AtLeastOnce, false, flag_payload(pmf.contains(ProchotMsbFlags::STAT_EXIT_VAP))).await?;
+    client.publish(topics.field("prochot_width"), QoS::AtLeastOnce, false, bq25730_status.prochot_width.to_string()).await?;
 
     // --- Publish BQ76920 Status ---
     let bq76920_status = &measurements.bq76920_alerts; // Renamed for clarity
-    let bq76920_status_base_topic = format!("{}/bq76920/status", topic_prefix); // Changed "alerts" to "status"
     let ss = bq76920_status.system_status;
-    client.publish(format!("{}/system/ocd", bq76920_status_base_topic), QoS::AtLeastOnce, false, ss.contains(Bq76920SystemStatus::OCD).to_string()).await?;
-    client.publish(format!("{}/system/scd", bq76920_status_base_topic), QoS::AtLeastOnce, false, ss.contains(Bq76920SystemStatus::SCD).to_string()).await?;
-    client.publish(format!("{}/system/ov", bq76920_status_base_topic), QoS::AtLeastOnce, false, ss.contains(Bq76920SystemStatus::OV).to_string()).await?;
-    client.publish(format!("{}/system/uv", bq76920_status_base_topic), QoS::AtLeastOnce, false, ss.contains(Bq76920SystemStatus::UV).to_string()).await?;
-    client.publish(format!("{}/system/ovrd_alert", bq76920_status_base_topic), QoS::AtLeastOnce, false, ss.contains(Bq76920SystemStatus::OVRD_ALERT).to_string()).await?;
-    client.publish(format!("{}/system/device_xready", bq76920_status_base_topic), QoS::AtLeastOnce, false, ss.contains(Bq76920SystemStatus::DEVICE_XREADY).to_string()).await?;
-    client.publish(format!("{}/system/cc_ready", bq76920_status_base_topic), QoS::AtLeastOnce, false, ss.contains(Bq76920SystemStatus::CC_READY).to_string()).await?;
+    client.publish(topics.field("system_ocd"), QoS::AtLeastOnce, false, flag_payload(ss.contains(Bq76920SystemStatus::OCD))).await?;
+    client.publish(topics.field("system_scd"), QoS::AtLeastOnce, false, flag_payload(ss.contains(Bq76920SystemStatus::SCD))).await?;
+    client.publish(topics.field("system_ov"), QoS::AtLeastOnce, false, flag_payload(ss.contains(Bq76920SystemStatus::OV))).await?;
+    client.publish(topics.field("system_uv"), QoS::AtLeastOnce, false, flag_payload(ss.contains(Bq76920SystemStatus::UV))).await?;
+    client.publish(topics.field("system_ovrd_alert"), QoS::AtLeastOnce, false, flag_payload(ss.contains(Bq76920SystemStatus::OVRD_ALERT))).await?;
+    client.publish(topics.field("system_device_xready"), QoS::AtLeastOnce, false, flag_payload(ss.contains(Bq76920SystemStatus::DEVICE_XREADY))).await?;
+    client.publish(topics.field("system_cc_ready"), QoS::AtLeastOnce, false, flag_payload(ss.contains(Bq76920SystemStatus::CC_READY))).await?;
 
 
-    info!("已发布所有测量和告警数据到主题前缀 '{}'", topic_prefix);
+    info!("已发布所有测量和告警数据到主题前缀 '{}'", topics.measurements_all);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::data_models::MosStatus;
+    use crate::publisher::{CollectPublisher, TryPublisher};
+    use crate::test_support::PayloadBuilder;
+
+    fn refused(code: ConnectReturnCode) -> ConnectionError {
+        ConnectionError::ConnectionRefused(code)
+    }
+
+    #[test]
+    fn authentication_and_protocol_refusals_are_fatal() {
+        for code in [
+            ConnectReturnCode::BadUserNamePassword,
+            ConnectReturnCode::NotAuthorized,
+            ConnectReturnCode::BadClientId,
+            ConnectReturnCode::RefusedProtocolVersion,
+        ] {
+            assert!(fatal_connection_error(&refused(code)).is_some(), "{:?}", code);
+        }
+        assert_eq!(fatal_connection_error(&refused(ConnectReturnCode::ServiceUnavailable)), None);
+    }
+
+    #[test]
+    fn tls_handshake_failures_are_fatal_but_tls_transport_errors_are_not() {
+        let handshake = ConnectionError::Tls(rumqttc::TlsError::Io(io::Error::new(io::ErrorKind::InvalidData, "bad cert")));
+        assert!(fatal_connection_error(&handshake).unwrap().starts_with("TLS failure"));
+        let reset = ConnectionError::Tls(rumqttc::TlsError::Io(io::ErrorKind::ConnectionReset.into()));
+        assert_eq!(fatal_connection_error(&reset), None);
+    }
+
+    #[test]
+    fn network_errors_are_left_to_reconnect() {
+        for error in [
+            ConnectionError::Io(io::ErrorKind::ConnectionRefused.into()),
+            ConnectionError::NetworkTimeout,
+            ConnectionError::FlushTimeout,
+            ConnectionError::RequestsDone,
+        ] {
+            assert_eq!(fatal_connection_error(&error), None, "{:?}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn heartbeat_failures_count_once_the_client_is_disconnected() {
+        // 事件循环已停止：try_publish 立即失败而不是等待
+        let (inner, eventloop) = AsyncClient::new(MqttOptions::new("heartbeat-closed", "localhost", 1883), 10);
+        drop(eventloop);
+        let stats = Arc::new(DaemonStats::default());
+        let closed = TryPublisher::new(inner, stats.clone());
+        let mut heartbeats = HeartbeatSender::default();
+
+        heartbeats.beat(&closed, "ups", &stats).await;
+        assert_eq!(stats.heartbeat_failures.load(Ordering::Relaxed), 0, "第一次失败只记录警告");
+        heartbeats.beat(&closed, "ups", &stats).await;
+        heartbeats.beat(&closed, "ups", &stats).await;
+        assert_eq!(stats.heartbeat_failures.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.publishes_dropped.load(Ordering::Relaxed), 3);
+
+        // 恢复后连续失败次数清零，序号继续递增
+        let collector = CollectPublisher::default();
+        heartbeats.beat(&collector, "ups", &stats).await;
+        heartbeats.beat(&closed, "ups", &stats).await;
+        assert_eq!(stats.heartbeat_failures.load(Ordering::Relaxed), 2);
+        let messages = collector.into_messages();
+        let heartbeat: serde_json::Value = serde_json::from_slice(&messages[0].payload).unwrap();
+        assert_eq!((messages[0].topic.as_str(), heartbeat["seq"].as_u64()), (daemon_heartbeat_topic("ups").as_str(), Some(4)));
+    }
+
+    fn published_cells(cell_count: usize) -> (Vec<String>, serde_json::Value) {
+        let mut builder = PayloadBuilder::new().cell_count(cell_count);
+        for i in 0..5 {
+            builder = builder.cell_mv(i, 3600 + 10 * i as i32);
+        }
+        let collector = CollectPublisher::default();
+        futures::executor::block_on(publish_sample(&collector, "ups", &TopicMap::default(), &builder.sample(1, 1))).unwrap();
+        let messages = collector.into_messages();
+        let topics = Topics::new(&TopicMap::default(), "ups");
+        let cells = messages
+            .iter()
+            .filter(|m| CELL_TOPIC_KEYS.iter().any(|key| m.topic == topics.field(key)))
+            .map(|m| m.topic.clone())
+            .collect();
+        let all = messages.iter().find(|m| m.topic == "ups/measurements_all").expect("measurements_all");
+        (cells, serde_json::from_slice(&all.payload).unwrap())
+    }
+
+    #[test]
+    fn efficiency_is_published_only_when_known() {
+        let topic = Topics::new(&TopicMap::default(), "ups").field("efficiency_percent").to_string();
+        let mut sample = PayloadBuilder::new().sample(1, 1);
+        let published = |sample: &TimestampedMeasurements| {
+            let collector = CollectPublisher::default();
+            futures::executor::block_on(publish_sample(&collector, "ups", &TopicMap::default(), sample)).unwrap();
+            collector.into_messages().into_iter().find(|m| m.topic == topic).map(|m| String::from_utf8(m.payload).unwrap())
+        };
+        assert_eq!(published(&sample), None);
+        sample.derived.efficiency_percent = Some(92.5);
+        assert_eq!(published(&sample).as_deref(), Some("92.500"));
+    }
+
+    #[test]
+    fn history_commands_take_an_optional_count() {
+        assert_eq!(MqttCommand::parse(b"history"), MqttCommand::History(None));
+        assert_eq!(MqttCommand::parse(b" HISTORY 60 "), MqttCommand::History(Some(60)));
+        assert_eq!(MqttCommand::parse(b"history many"), MqttCommand::Unknown("history many".to_string()));
+    }
+
+    #[test]
+    fn report_interval_commands_need_a_positive_u16() {
+        assert_eq!(MqttCommand::parse(b"report_interval 250"), MqttCommand::SetReportInterval(250));
+        assert_eq!(MqttCommand::parse(b" Report_Interval  65535 "), MqttCommand::SetReportInterval(u16::MAX));
+        for payload in ["report_interval", "report_interval 0", "report_interval 65536", "report_interval -5"] {
+            assert_eq!(MqttCommand::parse(payload.as_bytes()), MqttCommand::Unknown(payload.to_string()));
+        }
+    }
+
+    #[test]
+    fn only_the_configured_cells_are_published() {
+        for cell_count in 3..=5 {
+            let (topics, all) = published_cells(cell_count);
+            assert_eq!(topics.len(), cell_count);
+            let voltages = all["data"]["bq76920"]["cell_voltages"].as_array().unwrap();
+            assert_eq!(voltages.len(), cell_count);
+            assert_eq!(all["data"]["bq76920"]["cell_count"], cell_count);
+            let expected: f64 = (0..cell_count).map(|i| 3.6 + 0.01 * i as f64).sum();
+            assert!((all["derived"]["pack_voltage"].as_f64().unwrap() - expected).abs() < 1e-3, "{}S", cell_count);
+        }
+    }
+
+    #[test]
+    fn mos_status_raw_is_published_only_when_enabled() {
+        let sample = PayloadBuilder::new().mos_status(MosStatus::DischargeOn).sample(1, 1);
+        for enabled in [false, true] {
+            let map = TopicMap::default().with_mos_status_raw(enabled);
+            let collector = CollectPublisher::default();
+            futures::executor::block_on(publish_sample(&collector, "ups", &map, &sample)).unwrap();
+            let messages = collector.into_messages();
+            let payload = |topic: &str| messages.iter().find(|m| m.topic == topic).map(|m| String::from_utf8(m.payload.clone()).unwrap());
+            assert_eq!(payload("ups/measurements_all/bq76920/mos_status").as_deref(), Some("DischargeOn"));
+            assert_eq!(payload("ups/measurements_all/bq76920/mos_status_raw"), enabled.then(|| "2".to_string()));
+        }
+    }
+}
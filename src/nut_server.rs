@@ -0,0 +1,536 @@
+//! Network UPS Tools (NUT) 兼容的 TCP 服务 (`NUT_LISTEN`)，供 `upsmon` / `upsc` 直接监视本守护进程。
+//!
+//! 只实现只读监视所需的子集：`LIST UPS`、`LIST VAR`、`GET VAR`、`GET UPSDESC`、`GET NUMLOGINS`，
+//! 以及 `upsmon` 登录时的 `USERNAME` / `PASSWORD` / `LOGIN` / `PRIMARY`（`MASTER`）握手与 `LOGOUT`。
+//! 协议按行处理，每个连接一个任务；会话状态机 (`NutSession`) 不涉及网络。
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::data_models::{ChargerStatusFlags, DeviceInfo, SystemStatus, TimestampedMeasurements};
+
+/// NUT 的标准端口
+pub const DEFAULT_PORT: u16 = 3493;
+/// 单行请求的最大长度，超出时断开连接
+const MAX_LINE_BYTES: usize = 1024;
+
+/// 解析 `NUT_LISTEN`：`地址:端口`，或只给地址时使用默认端口
+pub fn parse_listen(value: &str) -> Result<SocketAddr, String> {
+    let value = value.trim();
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    value
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, DEFAULT_PORT))
+        .map_err(|e| e.to_string())
+}
+
+/// NUT 服务的配置
+#[derive(Debug, Clone, PartialEq)]
+pub struct NutSettings {
+    pub listen: SocketAddr,
+    /// 对外的 UPS 名称，如 `upsmon` 中的 `ups120@host`
+    pub ups_name: String,
+    /// 设置后 `LOGIN` / `PRIMARY` 需要匹配的用户名与密码；未设置时接受任意凭据
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// 电量低于该值 (%) 时 `ups.status` 带 `LB`
+    pub low_battery_pct: f32,
+    /// 充电电流超过该值 (A) 时 `ups.status` 带 `CHRG`
+    pub charging_threshold: f32,
+}
+
+/// 生成变量表所需的最新状态
+#[derive(Debug, Clone)]
+pub struct NutSource {
+    pub latest: watch::Receiver<Option<Arc<TimestampedMeasurements>>>,
+    pub device: watch::Receiver<Option<DeviceInfo>>,
+}
+
+/// 由测量数据映射得到的 NUT 变量，按名称排序
+pub fn variables(
+    settings: &NutSettings,
+    sample: &TimestampedMeasurements,
+    device: Option<&DeviceInfo>,
+) -> Vec<(&'static str, String)> {
+    let data = &sample.data;
+    let mut vars = vec![
+        ("battery.voltage", format!("{:.2}", sample.derived.pack_voltage)),
+        ("device.mfr", "IvanLi-CN".to_string()),
+        ("device.model", "UPS120".to_string()),
+        ("device.type", "ups".to_string()),
+        ("driver.name", "ups120-daemon".to_string()),
+        ("driver.version", env!("CARGO_PKG_VERSION").to_string()),
+        ("input.voltage", format!("{:.2}", data.bq25730.vbus)),
+        ("output.voltage", format!("{:.2}", data.bq25730.vsys)),
+        ("ups.mfr", "IvanLi-CN".to_string()),
+        ("ups.model", "UPS120".to_string()),
+        ("ups.status", ups_status(settings, sample)),
+    ];
+    if let Some(ts1) = data.bq76920.temperatures.ts1 {
+        vars.push(("battery.temperature", format!("{:.1}", ts1)));
+    }
+    if let Some(soc) = sample.battery.soc_percent {
+        vars.push(("battery.charge", format!("{:.0}", soc)));
+        vars.push(("battery.charge.low", format!("{:.0}", settings.low_battery_pct)));
+    }
+    if let Some(runtime_min) = sample.battery.runtime_min {
+        vars.push(("battery.runtime", format!("{:.0}", runtime_min * 60.0)));
+    }
+    if let Some(serial) = device.and_then(|d| d.serial.as_deref()) {
+        vars.push(("device.serial", serial.to_string()));
+        vars.push(("ups.serial", serial.to_string()));
+    }
+    if let Some(firmware) = device.and_then(|d| d.firmware_version.as_deref()) {
+        vars.push(("ups.firmware", firmware.to_string()));
+    }
+    vars.sort_by_key(|(name, _)| *name);
+    vars
+}
+
+/// `OL` / `OB` 取自 ChargerStatus.STAT_AC；电量低于阈值或 BQ76920 欠压 (UV) 时加 `LB`
+pub fn ups_status(settings: &NutSettings, sample: &TimestampedMeasurements) -> String {
+    let data = &sample.data;
+    let on_line = data.bq25730_alerts.charger_status_flags.contains(ChargerStatusFlags::STAT_AC);
+    let mut status = vec![if on_line { "OL" } else { "OB" }];
+    if on_line && data.bq25730.ichg > settings.charging_threshold {
+        status.push("CHRG");
+    } else if !on_line {
+        status.push("DISCHRG");
+    }
+    let soc_low = sample.battery.soc_percent.is_some_and(|soc| soc <= settings.low_battery_pct);
+    if soc_low || data.bq76920_alerts.system_status.contains(SystemStatus::UV) {
+        status.push("LB");
+    }
+    status.join(" ")
+}
+
+/// 单个连接的会话状态
+#[derive(Debug, Default)]
+pub struct NutSession {
+    username: Option<String>,
+    password: Option<String>,
+    logged_in: bool,
+}
+
+/// 处理一行请求的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub lines: Vec<String>,
+    /// 回复后关闭连接
+    pub close: bool,
+}
+
+impl Reply {
+    fn line(line: impl Into<String>) -> Self {
+        Reply { lines: vec![line.into()], close: false }
+    }
+
+    fn err(code: &str) -> Self {
+        Reply::line(format!("ERR {}", code))
+    }
+}
+
+impl NutSession {
+    /// 处理一行请求。`snapshot` 为当前的最新样本与设备信息，`logins` 为所有连接的登录数
+    pub fn handle_line(
+        &mut self,
+        line: &str,
+        settings: &NutSettings,
+        snapshot: Option<(&TimestampedMeasurements, Option<&DeviceInfo>)>,
+        logins: &AtomicUsize,
+    ) -> Reply {
+        let Some(args) = tokenize(line) else {
+            return Reply::err("INVALID-ARGUMENT");
+        };
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let known_ups = |name: &str| name == settings.ups_name;
+        match args.as_slice() {
+            [] => Reply { lines: Vec::new(), close: false },
+            ["VER"] => Reply::line(format!("ups120-daemon {}", env!("CARGO_PKG_VERSION"))),
+            ["NETVER"] => Reply::line("1.3"),
+            ["HELP"] => Reply::line("Commands: HELP VER NETVER GET LIST USERNAME PASSWORD LOGIN PRIMARY LOGOUT"),
+            ["STARTTLS"] => Reply::err("FEATURE-NOT-CONFIGURED"),
+            ["USERNAME", name] => {
+                if self.username.is_some() {
+                    return Reply::err("ALREADY-SET-USERNAME");
+                }
+                self.username = Some(name.to_string());
+                Reply::line("OK")
+            }
+            ["PASSWORD", password] => {
+                if self.password.is_some() {
+                    return Reply::err("ALREADY-SET-PASSWORD");
+                }
+                self.password = Some(password.to_string());
+                Reply::line("OK")
+            }
+            ["LOGIN", ups] => {
+                if self.logged_in {
+                    return Reply::err("ALREADY-LOGGED-IN");
+                }
+                if let Err(reply) = self.check_credentials(settings) {
+                    return reply;
+                }
+                if !known_ups(ups) {
+                    return Reply::err("UNKNOWN-UPS");
+                }
+                self.logged_in = true;
+                logins.fetch_add(1, Ordering::Relaxed);
+                Reply::line("OK")
+            }
+            ["PRIMARY" | "MASTER", ups] => {
+                if let Err(reply) = self.check_credentials(settings) {
+                    return reply;
+                }
+                if !known_ups(ups) {
+                    return Reply::err("UNKNOWN-UPS");
+                }
+                Reply::line("OK")
+            }
+            ["LOGOUT"] => Reply { lines: vec!["OK Goodbye".to_string()], close: true },
+            ["LIST", "UPS"] => Reply {
+                lines: vec![
+                    "BEGIN LIST UPS".to_string(),
+                    format!("UPS {} \"UPS120\"", settings.ups_name),
+                    "END LIST UPS".to_string(),
+                ],
+                close: false,
+            },
+            ["LIST", "VAR", ups] => {
+                if !known_ups(ups) {
+                    return Reply::err("UNKNOWN-UPS");
+                }
+                let Some((sample, device)) = snapshot else {
+                    return Reply::err("DATA-STALE");
+                };
+                let mut lines = vec![format!("BEGIN LIST VAR {}", ups)];
+                lines.extend(
+                    variables(settings, sample, device)
+                        .into_iter()
+                        .map(|(name, value)| format!("VAR {} {} \"{}\"", ups, name, escape(&value))),
+                );
+                lines.push(format!("END LIST VAR {}", ups));
+                Reply { lines, close: false }
+            }
+            ["GET", "VAR", ups, var] => {
+                if !known_ups(ups) {
+                    return Reply::err("UNKNOWN-UPS");
+                }
+                let Some((sample, device)) = snapshot else {
+                    return Reply::err("DATA-STALE");
+                };
+                match variables(settings, sample, device).into_iter().find(|(name, _)| name == var) {
+                    Some((name, value)) => Reply::line(format!("VAR {} {} \"{}\"", ups, name, escape(&value))),
+                    None => Reply::err("VAR-NOT-SUPPORTED"),
+                }
+            }
+            ["GET", "UPSDESC", ups] if known_ups(ups) => Reply::line(format!("UPSDESC {} \"UPS120\"", ups)),
+            ["GET", "NUMLOGINS", ups] if known_ups(ups) => {
+                Reply::line(format!("NUMLOGINS {} {}", ups, logins.load(Ordering::Relaxed)))
+            }
+            ["GET", "UPSDESC" | "NUMLOGINS", _] => Reply::err("UNKNOWN-UPS"),
+            ["LIST" | "GET", ..] => Reply::err("INVALID-ARGUMENT"),
+            _ => Reply::err("UNKNOWN-COMMAND"),
+        }
+    }
+
+    fn check_credentials(&self, settings: &NutSettings) -> Result<(), Reply> {
+        let Some(username) = &self.username else {
+            return Err(Reply::err("USERNAME-REQUIRED"));
+        };
+        let Some(password) = &self.password else {
+            return Err(Reply::err("PASSWORD-REQUIRED"));
+        };
+        let user_ok = settings.username.as_ref().is_none_or(|expected| expected == username);
+        let password_ok = settings.password.as_ref().is_none_or(|expected| expected == password);
+        if user_ok && password_ok { Ok(()) } else { Err(Reply::err("ACCESS-DENIED")) }
+    }
+
+    /// 连接关闭时释放登录计数
+    pub fn close(&mut self, logins: &AtomicUsize) {
+        if std::mem::take(&mut self.logged_in) {
+            logins.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 按空白分隔参数，支持双引号与反斜杠转义；引号未闭合时返回 None
+pub fn tokenize(line: &str) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = line.trim().chars();
+    let mut current: Option<String> = None;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => arg.push(chars.next()?),
+                        other => arg.push(other),
+                    }
+                }
+            }
+            '\\' => current.get_or_insert_with(String::new).push(chars.next()?),
+            c if c.is_whitespace() => args.extend(current.take()),
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(current);
+    Some(args)
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// 监听 `settings.listen` 直到 `shutdown` 取消；绑定失败只记录错误
+pub async fn nut_server_task(settings: NutSettings, source: NutSource, shutdown: CancellationToken) {
+    let listener = match TcpListener::bind(settings.listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(addr = %settings.listen, error = ?e, "NUT 服务无法监听");
+            return;
+        }
+    };
+    info!("NUT 服务已监听 {} (UPS 名称 {})", settings.listen, settings.ups_name);
+    let settings = Arc::new(settings);
+    let logins = Arc::new(AtomicUsize::new(0));
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let settings = settings.clone();
+                    let source = source.clone();
+                    let logins = logins.clone();
+                    let shutdown = shutdown.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, &settings, &source, &logins, &shutdown).await {
+                            debug!("NUT 连接 {} 处理失败: {:?}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("NUT 服务接受连接失败: {:?}", e),
+            },
+        }
+    }
+    info!("NUT 服务已停止");
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    settings: &NutSettings,
+    source: &NutSource,
+    logins: &AtomicUsize,
+    shutdown: &CancellationToken,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut session = NutSession::default();
+    let mut line = String::new();
+    let result = loop {
+        line.clear();
+        let mut limited = (&mut reader).take(MAX_LINE_BYTES as u64);
+        let read = tokio::select! {
+            _ = shutdown.cancelled() => break Ok(()),
+            read = limited.read_line(&mut line) => read,
+        };
+        match read {
+            Ok(0) => break Ok(()),
+            Ok(_) if !line.ends_with('\n') && line.len() >= MAX_LINE_BYTES => {
+                break writer.write_all(b"ERR INVALID-ARGUMENT\n").await;
+            }
+            Ok(_) => {}
+            Err(e) => break Err(e),
+        }
+        let reply = {
+            let latest = source.latest.borrow();
+            let device = source.device.borrow();
+            let snapshot = latest.as_deref().map(|sample| (sample, device.as_ref()));
+            session.handle_line(&line, settings, snapshot, logins)
+        };
+        let mut out = String::new();
+        for reply_line in &reply.lines {
+            out.push_str(reply_line);
+            out.push('\n');
+        }
+        if let Err(e) = writer.write_all(out.as_bytes()).await {
+            break Err(e);
+        }
+        if reply.close {
+            break Ok(());
+        }
+    };
+    session.close(logins);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_support::PayloadBuilder;
+
+    fn settings(listen: SocketAddr) -> NutSettings {
+        NutSettings {
+            listen,
+            ups_name: "ups120".to_string(),
+            username: Some("monuser".to_string()),
+            password: Some("secret".to_string()),
+            low_battery_pct: 20.0,
+            charging_threshold: 0.05,
+        }
+    }
+
+    fn sample(builder: PayloadBuilder, soc: Option<f32>) -> TimestampedMeasurements {
+        let mut sample = builder.sample(1, 1_700_000_000_000);
+        sample.battery.soc_percent = soc;
+        sample
+    }
+
+    #[test]
+    fn ups_status_follows_mains_charge_and_low_battery() {
+        let settings = settings(parse_listen("127.0.0.1").unwrap());
+        let status = |builder: PayloadBuilder, soc| ups_status(&settings, &sample(builder, soc));
+        let mains = || PayloadBuilder::new().charger_flags(ChargerStatusFlags::STAT_AC);
+        let battery = || PayloadBuilder::new().charger_flags(ChargerStatusFlags::empty());
+        assert_eq!(status(mains(), Some(80.0)), "OL");
+        let mut charging = sample(mains(), Some(80.0));
+        charging.data.bq25730.ichg = 1.0;
+        assert_eq!(ups_status(&settings, &charging), "OL CHRG");
+        assert_eq!(status(battery(), Some(80.0)), "OB DISCHRG");
+        assert_eq!(status(battery(), Some(20.0)), "OB DISCHRG LB");
+        assert_eq!(status(battery().system_status(SystemStatus::UV), None), "OB DISCHRG LB");
+        assert_eq!(status(mains().system_status(SystemStatus::UV), Some(80.0)), "OL LB");
+    }
+
+    #[test]
+    fn arguments_are_tokenized_like_upsd() {
+        assert_eq!(tokenize("  GET VAR ups120 battery.charge \r\n").unwrap(), ["GET", "VAR", "ups120", "battery.charge"]);
+        assert_eq!(tokenize(r#"PASSWORD "two words""#).unwrap(), ["PASSWORD", "two words"]);
+        assert_eq!(tokenize(r#"PASSWORD "a\"b\\c""#).unwrap(), ["PASSWORD", r#"a"b\c"#]);
+        assert_eq!(tokenize(r"USERNAME a\ b").unwrap(), ["USERNAME", "a b"]);
+        assert_eq!(tokenize(r#"PASSWORD "unterminated"#), None);
+        assert!(tokenize("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn listen_addresses_default_to_the_nut_port() {
+        assert_eq!(parse_listen("0.0.0.0").unwrap(), "0.0.0.0:3493".parse().unwrap());
+        assert_eq!(parse_listen("[::1]").unwrap(), "[::1]:3493".parse().unwrap());
+        assert_eq!(parse_listen("127.0.0.1:13493").unwrap(), "127.0.0.1:13493".parse().unwrap());
+        assert!(parse_listen("localhost").is_err());
+    }
+
+    struct Client {
+        reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
+        writer: tokio::net::tcp::OwnedWriteHalf,
+    }
+
+    impl Client {
+        async fn connect(addr: SocketAddr) -> Self {
+            for _ in 0..100 {
+                if let Ok(stream) = TcpStream::connect(addr).await {
+                    let (reader, writer) = stream.into_split();
+                    return Client { reader: BufReader::new(reader), writer };
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("NUT 服务未监听 {}", addr);
+        }
+
+        async fn line(&mut self) -> String {
+            let mut line = String::new();
+            tokio::time::timeout(Duration::from_secs(2), self.reader.read_line(&mut line)).await.unwrap().unwrap();
+            line.trim_end_matches('\n').to_string()
+        }
+
+        /// 发送一行请求，读取 `lines` 行回复
+        async fn request(&mut self, request: &str, lines: usize) -> Vec<String> {
+            self.writer.write_all(format!("{}\n", request).as_bytes()).await.unwrap();
+            let mut reply = Vec::new();
+            for _ in 0..lines {
+                reply.push(self.line().await);
+            }
+            reply
+        }
+
+        /// 读取 `BEGIN ...` 到 `END ...` 的整个列表
+        async fn list(&mut self, request: &str) -> Vec<String> {
+            self.writer.write_all(format!("{}\n", request).as_bytes()).await.unwrap();
+            let mut reply = vec![self.line().await];
+            while !reply.last().unwrap().starts_with("END ") && !reply[0].starts_with("ERR ") {
+                reply.push(self.line().await);
+            }
+            reply
+        }
+    }
+
+    #[tokio::test]
+    async fn a_scripted_upsmon_session() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (latest, latest_rx) = watch::channel(None);
+        let (_device, device_rx) = watch::channel(None);
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(nut_server_task(
+            settings(addr),
+            NutSource { latest: latest_rx, device: device_rx },
+            shutdown.clone(),
+        ));
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(client.list("LIST UPS").await, ["BEGIN LIST UPS", "UPS ups120 \"UPS120\"", "END LIST UPS"]);
+        assert_eq!(client.request("GET VAR ups120 battery.charge", 1).await, ["ERR DATA-STALE"]);
+        assert_eq!(client.request("LOGIN ups120", 1).await, ["ERR USERNAME-REQUIRED"]);
+        assert_eq!(client.request("USERNAME monuser", 1).await, ["OK"]);
+        assert_eq!(client.request("PASSWORD wrong", 1).await, ["OK"]);
+        assert_eq!(client.request("LOGIN ups120", 1).await, ["ERR ACCESS-DENIED"]);
+        assert_eq!(client.request("PASSWORD secret", 1).await, ["ERR ALREADY-SET-PASSWORD"]);
+
+        // 第二个连接使用正确的凭据
+        let mut upsmon = Client::connect(addr).await;
+        assert_eq!(upsmon.request("USERNAME monuser", 1).await, ["OK"]);
+        assert_eq!(upsmon.request("PASSWORD secret", 1).await, ["OK"]);
+        assert_eq!(upsmon.request("LOGIN other", 1).await, ["ERR UNKNOWN-UPS"]);
+        assert_eq!(upsmon.request("LOGIN ups120", 1).await, ["OK"]);
+        assert_eq!(upsmon.request("PRIMARY ups120", 1).await, ["OK"]);
+        assert_eq!(client.request("GET NUMLOGINS ups120", 1).await, ["NUMLOGINS ups120 1"]);
+
+        latest.send_replace(Some(Arc::new(sample(PayloadBuilder::new(), Some(55.0)))));
+        assert_eq!(upsmon.request("GET VAR ups120 battery.charge", 1).await, ["VAR ups120 battery.charge \"55\""]);
+        assert_eq!(upsmon.request("GET VAR ups120 ups.status", 1).await, ["VAR ups120 ups.status \"OL\""]);
+        assert_eq!(upsmon.request("GET VAR ups120 battery.voltage", 1).await, ["VAR ups120 battery.voltage \"18.50\""]);
+        assert_eq!(upsmon.request("GET VAR ups120 no.such.var", 1).await, ["ERR VAR-NOT-SUPPORTED"]);
+        assert_eq!(upsmon.request("GET VAR other ups.status", 1).await, ["ERR UNKNOWN-UPS"]);
+        let vars = upsmon.list("LIST VAR ups120").await;
+        assert_eq!(vars.first().unwrap(), "BEGIN LIST VAR ups120");
+        assert_eq!(vars.last().unwrap(), "END LIST VAR ups120");
+        assert!(vars.contains(&"VAR ups120 battery.charge.low \"20\"".to_string()));
+        assert!(vars.contains(&"VAR ups120 device.mfr \"IvanLi-CN\"".to_string()));
+        let names: Vec<&str> = vars[1..vars.len() - 1].iter().map(|line| line.split(' ').nth(2).unwrap()).collect();
+        assert!(names.windows(2).all(|w| w[0] < w[1]), "{:?}", names);
+
+        assert_eq!(upsmon.request("FROBNICATE", 1).await, ["ERR UNKNOWN-COMMAND"]);
+        assert_eq!(upsmon.request("LOGOUT", 1).await, ["OK Goodbye"]);
+        assert_eq!(upsmon.line().await, "", "LOGOUT 后服务端关闭连接");
+        // 登录计数随连接关闭释放
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(client.request("GET NUMLOGINS ups120", 1).await, ["NUMLOGINS ups120 0"]);
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(1), task).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,280 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::stream::{self, BoxStream, StreamExt};
+use tracing::warn;
+use tokio::sync::broadcast;
+
+use crate::data_models::{AllMeasurements, ChargerFaultFlags, SystemStatus, TimestampedMeasurements};
+use crate::fields::FieldAccessor;
+
+/// 视为故障的 BQ76920 SysStat 位
+const FAULT_SYSTEM_STATUS: SystemStatus = SystemStatus::from_bits_truncate(
+    SystemStatus::OCD.bits()
+        | SystemStatus::SCD.bits()
+        | SystemStatus::OV.bits()
+        | SystemStatus::UV.bits()
+        | SystemStatus::OVRD_ALERT.bits()
+        | SystemStatus::DEVICE_XREADY.bits(),
+);
+
+/// 总线的一个订阅。只持有接收端，不会让总线保持打开：发送端 (`Pipeline`) 全部丢弃后
+/// 订阅者收到 `Closed`，按设备运行的任务借此在路由移除时结束
+pub type Subscription = broadcast::Receiver<Arc<TimestampedMeasurements>>;
+
+/// 测量数据广播总线：每帧解析后的数据发布一次，任意数量的订阅者各自消费
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    tx: broadcast::Sender<Arc<TimestampedMeasurements>>,
+}
+
+impl Pipeline {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Pipeline { tx }
+    }
+
+    /// 广播一帧数据；没有订阅者时直接丢弃
+    pub fn publish(&self, sample: Arc<TimestampedMeasurements>) {
+        let _ = self.tx.send(sample);
+    }
+
+    pub fn subscribe(&self) -> Subscription {
+        self.tx.subscribe()
+    }
+
+    /// 订阅某个字段的谓词状态变化，见 [`watch`]
+    pub fn watch(
+        &self,
+        selector: FieldSelector,
+        predicate: Predicate,
+        debounce: Duration,
+    ) -> BoxStream<'static, Transition> {
+        watch(self.subscribe(), selector, predicate, debounce)
+    }
+}
+
+/// 由订阅生成某个字段的谓词状态变化流。谓词结果需持续 `debounce`（按样本时间戳计）
+/// 才会产生一次 `Transition`；首个样本总会产生一次 `previous: None` 的初始事件。总线关闭时流结束
+pub fn watch(
+    samples: Subscription,
+    selector: FieldSelector,
+    predicate: Predicate,
+    debounce: Duration,
+) -> BoxStream<'static, Transition> {
+    let watcher = Watcher::new(selector, predicate, debounce);
+    stream::unfold((samples, watcher), |(mut rx, mut watcher)| async move {
+        loop {
+            match rx.recv().await {
+                Ok(sample) => {
+                    if let Some(transition) = watcher.update(&sample) {
+                        return Some((transition, (rx, watcher)));
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("字段订阅 {:?} 处理过慢，跳过 {} 帧", watcher.selector, n);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+    .boxed()
+}
+
+/// 可订阅的字段：扁平键或若干组合量
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldSelector {
+    /// 扁平键，如 `bq25730.vbat`、`bq76920.cell_voltages.2`（见 `fields::flatten`），用 [`FieldSelector::key`] 构造
+    Key(FieldAccessor),
+    /// 电池包电流 (BQ76920 库仑计，充电为正)
+    PackCurrent,
+    /// 各节电芯电压之和
+    PackVoltage,
+    /// 最低单节电压
+    MinCell,
+    /// 最高单节电压
+    MaxCell,
+    /// 任一故障标志置位时为 1，否则为 0
+    AnyFault,
+}
+
+impl FieldSelector {
+    /// 按扁平键构造选择器，键在此解析一次；未知键或非数值字段返回 None
+    pub fn key(key: &str) -> Option<Self> {
+        FieldAccessor::resolve(key).map(FieldSelector::Key)
+    }
+
+    pub fn extract<const N: usize>(&self, data: &AllMeasurements<N>) -> Option<f32> {
+        let cells = data.bq76920.cells();
+        match self {
+            FieldSelector::Key(accessor) => accessor.get(data).map(|v| v as f32),
+            FieldSelector::PackCurrent => Some(data.bq76920.coulomb_counter),
+            FieldSelector::PackVoltage => Some(cells.iter().sum()),
+            FieldSelector::MinCell => cells.iter().copied().reduce(f32::min),
+            FieldSelector::MaxCell => cells.iter().copied().reduce(f32::max),
+            FieldSelector::AnyFault => {
+                let fault = data.bq76920.system_status.intersects(FAULT_SYSTEM_STATUS)
+                    || data.bq76920_alerts.system_status.intersects(FAULT_SYSTEM_STATUS)
+                    || data.bq25730_alerts.charger_fault_flags != ChargerFaultFlags::empty();
+                Some(if fault { 1.0 } else { 0.0 })
+            }
+        }
+    }
+}
+
+/// 作用于字段数值的谓词
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    Above(f32),
+    Below(f32),
+    /// 闭区间 [lo, hi]
+    Between(f32, f32),
+    NonZero,
+    Not(Box<Predicate>),
+    All(Vec<Predicate>),
+    Any(Vec<Predicate>),
+}
+
+impl Predicate {
+    pub fn matches(&self, value: f32) -> bool {
+        match self {
+            Predicate::Above(threshold) => value > *threshold,
+            Predicate::Below(threshold) => value < *threshold,
+            Predicate::Between(lo, hi) => value >= *lo && value <= *hi,
+            Predicate::NonZero => value != 0.0,
+            Predicate::Not(inner) => !inner.matches(value),
+            Predicate::All(items) => items.iter().all(|p| p.matches(value)),
+            Predicate::Any(items) => items.iter().any(|p| p.matches(value)),
+        }
+    }
+}
+
+/// 谓词状态变化事件
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transition {
+    pub selector: FieldSelector,
+    /// 变化前的状态；首个样本为 None
+    pub previous: Option<bool>,
+    pub matched: bool,
+    pub value: f32,
+    pub ts_unix_ms: u64,
+}
+
+/// 单个字段订阅的状态机，与总线解耦，可直接喂样本
+#[derive(Debug)]
+pub struct Watcher {
+    selector: FieldSelector,
+    predicate: Predicate,
+    debounce: Duration,
+    state: Option<bool>,
+    // 尚未确认的新状态及其首次出现的时间戳
+    candidate: Option<(bool, u64)>,
+}
+
+impl Watcher {
+    pub fn new(selector: FieldSelector, predicate: Predicate, debounce: Duration) -> Self {
+        Watcher {
+            selector,
+            predicate,
+            debounce,
+            state: None,
+            candidate: None,
+        }
+    }
+
+    pub fn state(&self) -> Option<bool> {
+        self.state
+    }
+
+    pub fn update(&mut self, sample: &TimestampedMeasurements) -> Option<Transition> {
+        let value = self.selector.extract(&sample.data)?;
+        let matched = self.predicate.matches(value);
+        if self.state.is_none() {
+            self.state = Some(matched);
+            return Some(self.transition(None, matched, value, sample.ts_unix_ms));
+        }
+        if self.state == Some(matched) {
+            self.candidate = None;
+            return None;
+        }
+        let since = match self.candidate {
+            Some((candidate, since)) if candidate == matched => since,
+            _ => {
+                self.candidate = Some((matched, sample.ts_unix_ms));
+                sample.ts_unix_ms
+            }
+        };
+        if sample.ts_unix_ms.saturating_sub(since) < self.debounce.as_millis() as u64 {
+            return None;
+        }
+        let previous = self.state.replace(matched);
+        self.candidate = None;
+        Some(self.transition(previous, matched, value, sample.ts_unix_ms))
+    }
+
+    fn transition(&self, previous: Option<bool>, matched: bool, value: f32, ts_unix_ms: u64) -> Transition {
+        Transition {
+            selector: self.selector.clone(),
+            previous,
+            matched,
+            value,
+            ts_unix_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::PayloadBuilder;
+
+    #[tokio::test]
+    async fn subscriptions_end_when_the_last_pipeline_is_dropped() {
+        let pipeline = Pipeline::new(8);
+        let mut samples = pipeline.subscribe();
+        let mut transitions = watch(pipeline.subscribe(), FieldSelector::PackCurrent, Predicate::Below(-0.1), Duration::ZERO);
+        pipeline.publish(Arc::new(PayloadBuilder::new().pack_current_ma(-500).sample(1, 1_000)));
+        drop(pipeline);
+
+        assert_eq!(samples.recv().await.unwrap().frame_id, 1);
+        assert!(matches!(samples.recv().await, Err(broadcast::error::RecvError::Closed)));
+        let first = transitions.next().await.unwrap();
+        assert_eq!((first.previous, first.matched), (None, true));
+        assert!(transitions.next().await.is_none());
+    }
+
+    #[test]
+    fn key_selectors_read_the_field_directly() {
+        let selector = FieldSelector::key("bq25730.vbat").unwrap();
+        let mut watcher = Watcher::new(selector, Predicate::Above(12.0), Duration::ZERO);
+        let first = watcher.update(&PayloadBuilder::new().vbat_mv(12_500).sample(1, 1_000)).unwrap();
+        assert_eq!((first.matched, first.value), (true, 12.5));
+        let second = watcher.update(&PayloadBuilder::new().vbat_mv(11_000).sample(2, 2_000)).unwrap();
+        assert_eq!((second.previous, second.matched), (Some(true), false));
+        assert_eq!(FieldSelector::key("bq76920.mos_status"), None);
+    }
+
+    // 主循环把一帧交给历史、总线、最新样本与发布限速器，各方持有的是同一份数据
+    #[tokio::test]
+    async fn every_consumer_shares_the_same_sample() {
+        let sample = Arc::new(PayloadBuilder::new().sample(1, 1_000));
+        let history = crate::ring::History::new(4);
+        let pipeline = Pipeline::new(4);
+        let (mut first, mut second) = (pipeline.subscribe(), pipeline.subscribe());
+        let (latest_tx, latest_rx) = tokio::sync::watch::channel(None);
+        let mut throttle = crate::throttle::PublishThrottle::new(Duration::ZERO);
+
+        history.push(sample.clone());
+        latest_tx.send_replace(Some(sample.clone()));
+        pipeline.publish(sample.clone());
+        let published = throttle.offer(sample.clone(), tokio::time::Instant::now()).unwrap();
+
+        let received = [first.recv().await.unwrap(), second.recv().await.unwrap()];
+        let latest = latest_rx.borrow().clone().unwrap();
+        let kept = history.snapshot().remove(0);
+        for shared in received.iter().chain([&latest, &kept, &published]) {
+            assert!(Arc::ptr_eq(shared, &sample));
+        }
+    }
+}
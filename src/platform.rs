@@ -0,0 +1,210 @@
+use std::path::PathBuf;
+
+/// `set_active_configuration` 失败时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigurationFailure {
+    /// 视为致命错误，放弃本次连接
+    Fatal,
+    /// 预期内的失败（例如设备已由系统驱动配置好），记录后继续
+    Tolerate,
+}
+
+/// 平台相关的 USB/文件路径差异。
+/// 所有实现在任何平台上都会编译，`current()` 只负责按目标平台选择其一。
+pub trait Platform: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// 是否需要（且能够）分离/重新附加内核驱动
+    fn manages_kernel_driver(&self) -> bool;
+
+    fn classify_set_configuration_error(&self, err: rusb::Error) -> ConfigurationFailure;
+
+    /// 针对声明接口失败给出的驱动安装提示
+    fn claim_failure_hint(&self, err: rusb::Error) -> Option<&'static str>;
+
+    /// 状态文件的默认目录
+    fn default_state_dir(&self) -> PathBuf;
+
+    /// 本地控制套接字的默认路径；平台不支持 Unix 域套接字时为 None
+    fn default_control_socket(&self) -> Option<PathBuf>;
+}
+
+pub struct UnixPlatform;
+
+impl Platform for UnixPlatform {
+    fn name(&self) -> &'static str {
+        "unix"
+    }
+
+    fn manages_kernel_driver(&self) -> bool {
+        true
+    }
+
+    fn classify_set_configuration_error(&self, _err: rusb::Error) -> ConfigurationFailure {
+        ConfigurationFailure::Fatal
+    }
+
+    fn claim_failure_hint(&self, err: rusb::Error) -> Option<&'static str> {
+        match err {
+            rusb::Error::Access => Some("权限不足，请检查 udev 规则或以 root 运行"),
+            rusb::Error::Busy => Some("接口被其他进程占用"),
+            _ => None,
+        }
+    }
+
+    fn default_state_dir(&self) -> PathBuf {
+        PathBuf::from("/var/lib/ups120")
+    }
+
+    fn default_control_socket(&self) -> Option<PathBuf> {
+        Some(PathBuf::from("/run/ups120/control.sock"))
+    }
+}
+
+/// Windows 下通过 WinUSB 访问设备：没有内核驱动可分离，
+/// 设备在枚举时已由系统完成配置，再次设置配置会返回 NotSupported/Busy。
+pub struct WindowsPlatform;
+
+impl Platform for WindowsPlatform {
+    fn name(&self) -> &'static str {
+        "windows"
+    }
+
+    fn manages_kernel_driver(&self) -> bool {
+        false
+    }
+
+    fn classify_set_configuration_error(&self, err: rusb::Error) -> ConfigurationFailure {
+        match err {
+            rusb::Error::NotSupported | rusb::Error::Busy | rusb::Error::Io => ConfigurationFailure::Tolerate,
+            _ => ConfigurationFailure::Fatal,
+        }
+    }
+
+    fn claim_failure_hint(&self, err: rusb::Error) -> Option<&'static str> {
+        match err {
+            rusb::Error::NotSupported | rusb::Error::NotFound | rusb::Error::Access => {
+                Some("接口未绑定 WinUSB 驱动，请使用 Zadig 为该接口安装 WinUSB")
+            }
+            _ => None,
+        }
+    }
+
+    fn default_state_dir(&self) -> PathBuf {
+        std::env::var_os("ProgramData")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(r"C:\ProgramData"))
+            .join("ups120")
+    }
+
+    fn default_control_socket(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+pub fn current() -> &'static dyn Platform {
+    if cfg!(windows) { &WindowsPlatform } else { &UnixPlatform }
+}
+
+/// 进程的常驻内存 (RSS，字节)；只在 Linux 上读取 `/proc/self/status`，其他平台返回 None
+pub fn resident_memory_bytes() -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+        let kib: u64 = line.trim_start_matches("VmRSS:").trim().trim_end_matches("kB").trim().parse().ok()?;
+        Some(kib * 1024)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+/// 等待退出信号：所有平台都处理 Ctrl+C，Unix 额外处理 SIGTERM（systemd 停止服务时发送），
+/// Windows 额外处理 Ctrl+Break。返回收到的信号名称。
+pub async fn shutdown_signal() -> &'static str {
+    #[cfg(windows)]
+    {
+        match tokio::signal::windows::ctrl_break() {
+            Ok(mut ctrl_break) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => "Ctrl+C",
+                    _ = ctrl_break.recv() => "Ctrl+Break",
+                }
+            }
+            Err(e) => {
+                tracing::warn!("注册 Ctrl+Break 处理失败: {:?}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                "Ctrl+C"
+            }
+        }
+    }
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => "SIGINT",
+                    _ = sigterm.recv() => "SIGTERM",
+                }
+            }
+            Err(e) => {
+                tracing::warn!("注册 SIGTERM 处理失败: {:?}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl+C"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb_types::UsbError;
+
+    #[test]
+    fn current_matches_the_target() {
+        assert_eq!(current().name(), if cfg!(windows) { "windows" } else { "unix" });
+    }
+
+    #[test]
+    fn only_unix_detaches_kernel_drivers() {
+        assert!(UnixPlatform.manages_kernel_driver());
+        assert!(!WindowsPlatform.manages_kernel_driver());
+    }
+
+    #[test]
+    fn windows_tolerates_an_already_configured_device() {
+        for err in [rusb::Error::NotSupported, rusb::Error::Busy, rusb::Error::Io] {
+            assert_eq!(WindowsPlatform.classify_set_configuration_error(err), ConfigurationFailure::Tolerate);
+            assert_eq!(UnixPlatform.classify_set_configuration_error(err), ConfigurationFailure::Fatal);
+        }
+        assert_eq!(WindowsPlatform.classify_set_configuration_error(rusb::Error::NoDevice), ConfigurationFailure::Fatal);
+    }
+
+    #[test]
+    fn claim_failures_get_platform_specific_hints() {
+        let windows = WindowsPlatform.claim_failure_hint(rusb::Error::NotSupported);
+        assert!(windows.unwrap().contains("WinUSB"));
+        assert!(UnixPlatform.claim_failure_hint(rusb::Error::Access).unwrap().contains("udev"));
+        assert_eq!(UnixPlatform.claim_failure_hint(rusb::Error::NotSupported), None);
+
+        let error = UsbError::ClaimInterfaceFailed { source: rusb::Error::NotSupported, hint: windows };
+        assert!(error.to_string().contains("Zadig"));
+    }
+
+    #[test]
+    fn default_paths_are_platform_appropriate() {
+        assert_eq!(UnixPlatform.default_state_dir(), PathBuf::from("/var/lib/ups120"));
+        assert!(WindowsPlatform.default_state_dir().ends_with("ups120"));
+        assert!(UnixPlatform.default_control_socket().is_some());
+        assert_eq!(WindowsPlatform.default_control_socket(), None);
+    }
+}
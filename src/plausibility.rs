@@ -0,0 +1,165 @@
+//! 物理上不可能的样本检测。
+//!
+//! 偶尔有损坏的帧能"成功"解析，却带着 -40 A 的电流或 0 V 的单体电压。这里按可配置的
+//! 范围逐字段检查，由主循环决定记录、计数还是丢弃。
+
+use std::fmt;
+
+use crate::data_models::AllMeasurements;
+
+/// 各类字段的合理范围（闭区间）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// 单体电压 (V)，只检查实际串联的电芯
+    pub cell_voltage: (f32, f32),
+    /// 电流 (A)：BQ25730 的 ICHG / IDCHG / IIN、电池包电流与 INA226 电流
+    pub current: (f32, f32),
+    /// 温度 (°C)：TS1 与存在的 TS2 / TS3
+    pub temperature: (f32, f32),
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            cell_voltage: (1.5, 4.5),
+            current: (-50.0, 50.0),
+            temperature: (-40.0, 120.0),
+        }
+    }
+}
+
+/// 超出范围的字段
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub field: &'static str,
+    pub value: f32,
+    pub range: (f32, f32),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={} (允许 {}..={})", self.field, self.value, self.range.0, self.range.1)
+    }
+}
+
+// 连续这么多帧不合理时要求重新订阅
+const RESUBSCRIBE_STREAK: u32 = 2;
+
+/// 连续不合理样本的计数。连续两帧不合理多半是数据流失步，应重新订阅让设备从帧边界重新开始
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImplausibleStreak(u32);
+
+impl ImplausibleStreak {
+    /// 记录一帧的检查结果，返回是否应重新订阅；要求重新订阅后重新计数
+    pub fn record(&mut self, plausible: bool) -> bool {
+        if plausible {
+            self.0 = 0;
+            return false;
+        }
+        self.0 += 1;
+        if self.0 >= RESUBSCRIBE_STREAK {
+            self.0 = 0;
+            return true;
+        }
+        false
+    }
+}
+
+const CELL_FIELDS: [&str; 5] = ["cell0", "cell1", "cell2", "cell3", "cell4"];
+
+/// 检查一帧是否在合理范围内；NaN 也视为越界
+pub fn validate_sample(measurements: &AllMeasurements<5>, limits: &Limits) -> Result<(), Vec<Violation>> {
+    let mut violations = Vec::new();
+    let mut check = |field: &'static str, value: f32, range: (f32, f32)| {
+        if !(range.0..=range.1).contains(&value) {
+            violations.push(Violation { field, value, range });
+        }
+    };
+
+    for (field, voltage) in CELL_FIELDS.iter().zip(measurements.bq76920.cells()) {
+        check(field, *voltage, limits.cell_voltage);
+    }
+
+    let bq25730 = &measurements.bq25730;
+    check("ichg", bq25730.ichg, limits.current);
+    check("idchg", bq25730.idchg, limits.current);
+    check("iin", bq25730.iin, limits.current);
+    check("coulomb_counter", measurements.bq76920.coulomb_counter, limits.current);
+    if let Some(ina226) = &measurements.ina226 {
+        check("ina226_current", ina226.current, limits.current);
+    }
+
+    let temperatures = &measurements.bq76920.temperatures;
+    if let Some(ts1) = temperatures.ts1 {
+        check("ts1", ts1, limits.temperature);
+    }
+    if let Some(ts2) = temperatures.ts2 {
+        check("ts2", ts2, limits.temperature);
+    }
+    if let Some(ts3) = temperatures.ts3 {
+        check("ts3", ts3, limits.temperature);
+    }
+
+    if violations.is_empty() { Ok(()) } else { Err(violations) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::PayloadBuilder;
+
+    fn fields(result: Result<(), Vec<Violation>>) -> Vec<&'static str> {
+        result.err().unwrap_or_default().into_iter().map(|v| v.field).collect()
+    }
+
+    #[test]
+    fn a_normal_sample_passes() {
+        let sample = PayloadBuilder::new().ina226(18.0, -1.2).pack_current_ma(-1200).ts1_celsius(31.0).measurements();
+        assert_eq!(validate_sample(&sample, &Limits::default()), Ok(()));
+    }
+
+    #[test]
+    fn each_out_of_range_field_is_reported() {
+        let mut sample = PayloadBuilder::new().cell_mv(2, 0).ina226(18.0, 60.0).measurements();
+        sample.bq25730.ichg = -60.0;
+        sample.bq76920.temperatures.ts1 = Some(150.0);
+        sample.bq76920.coulomb_counter = f32::NAN;
+        assert_eq!(
+            fields(validate_sample(&sample, &Limits::default())),
+            vec!["cell2", "ichg", "coulomb_counter", "ina226_current", "ts1"]
+        );
+        let violation = validate_sample(&sample, &Limits::default()).unwrap_err().remove(0);
+        assert_eq!(violation.to_string(), "cell2=0 (允许 1.5..=4.5)");
+    }
+
+    #[test]
+    fn unused_cells_and_absent_sensors_are_not_checked() {
+        let mut sample = PayloadBuilder::new().cell_count(3).without_ina226().measurements();
+        sample.bq76920.cell_voltages[3] = 0.0;
+        sample.bq76920.cell_voltages[4] = 0.0;
+        sample.bq76920.temperatures.ts1 = None;
+        assert_eq!(validate_sample(&sample, &Limits::default()), Ok(()));
+    }
+
+    #[test]
+    fn limits_are_configurable_and_inclusive() {
+        let sample = PayloadBuilder::new().cell_mv(0, 4300).measurements();
+        let strict = Limits { cell_voltage: (3.0, 4.2), ..Limits::default() };
+        assert_eq!(fields(validate_sample(&sample, &strict)), vec!["cell0"]);
+        let edge = Limits { cell_voltage: (1.5, 4.3), ..Limits::default() };
+        assert_eq!(validate_sample(&sample, &edge), Ok(()));
+    }
+
+    #[test]
+    fn two_consecutive_rejections_ask_for_a_resubscribe() {
+        let mut streak = ImplausibleStreak::default();
+        assert!(!streak.record(false));
+        assert!(!streak.record(true));
+        assert!(!streak.record(false));
+        assert!(streak.record(false));
+        // 重新订阅后重新计数
+        assert!(!streak.record(false));
+        assert!(streak.record(false));
+    }
+}
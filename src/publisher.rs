@@ -0,0 +1,205 @@
+//! 发布的抽象。
+//!
+//! 测量数据的发布路径只依赖 `Publisher`：真实 broker 由 `AsyncClient` 实现，`--dry-run` 使用
+//! 只输出日志的 `LogPublisher`。`dry_run_session` 构造一个不连接 broker 的 `AsyncClient`，
+//! 其余直接使用 `AsyncClient` 的任务发出的消息同样转交 `LogPublisher`，QoS 1 发布输出后即视为已确认。
+//! 主循环通过 `TryPublisher` 发布，broker 不可达、发送队列占满时丢弃消息而不是停下来等待。
+
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use rumqttc::{AsyncClient, ClientError, QoS, Request};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+use crate::acks::{self, AckTracker};
+use crate::mqtt_handlers::MqttSession;
+use crate::stats::DaemonStats;
+
+// 与主 broker 客户端的请求队列容量一致
+const DRY_RUN_QUEUE: usize = 10;
+
+/// 发布一条消息；语义与 `AsyncClient::publish` 一致，消息交给发送队列即返回
+pub trait Publisher: Send + Sync {
+    fn publish<S, V>(&self, topic: S, qos: QoS, retain: bool, payload: V) -> impl Future<Output = Result<(), ClientError>> + Send
+    where
+        S: Into<String> + Send,
+        V: Into<Vec<u8>> + Send;
+}
+
+impl Publisher for AsyncClient {
+    fn publish<S, V>(&self, topic: S, qos: QoS, retain: bool, payload: V) -> impl Future<Output = Result<(), ClientError>> + Send
+    where
+        S: Into<String> + Send,
+        V: Into<Vec<u8>> + Send,
+    {
+        AsyncClient::publish(self, topic, qos, retain, payload)
+    }
+}
+
+/// 只在 info 级别输出 (topic, payload, qos, retain) 的发布者
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogPublisher;
+
+impl Publisher for LogPublisher {
+    fn publish<S, V>(&self, topic: S, qos: QoS, retain: bool, payload: V) -> impl Future<Output = Result<(), ClientError>> + Send
+    where
+        S: Into<String> + Send,
+        V: Into<Vec<u8>> + Send,
+    {
+        let (topic, payload) = (topic.into(), payload.into());
+        info!(topic = %topic, payload = %String::from_utf8_lossy(&payload), qos = ?qos, retain, "[dry-run] 发布");
+        std::future::ready(Ok(()))
+    }
+}
+
+/// 不等待发送队列的发布者：队列已满或事件循环已停止时立即返回 `ClientError::TryRequest`，
+/// 消息丢弃并计入 `publishes_dropped`
+#[derive(Debug, Clone)]
+pub struct TryPublisher {
+    client: AsyncClient,
+    stats: Arc<DaemonStats>,
+}
+
+impl TryPublisher {
+    pub fn new(client: AsyncClient, stats: Arc<DaemonStats>) -> Self {
+        TryPublisher { client, stats }
+    }
+}
+
+impl Publisher for TryPublisher {
+    fn publish<S, V>(&self, topic: S, qos: QoS, retain: bool, payload: V) -> impl Future<Output = Result<(), ClientError>> + Send
+    where
+        S: Into<String> + Send,
+        V: Into<Vec<u8>> + Send,
+    {
+        let result = self.client.try_publish(topic, qos, retain, payload);
+        if let Err(ClientError::TryRequest(_)) = &result {
+            self.stats.publishes_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        std::future::ready(result)
+    }
+}
+
+/// 一条待发布的消息
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutgoingMessage {
+    pub topic: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub payload: Vec<u8>,
+}
+
+/// 只记录消息、不发送的发布者，用来先生成一个样本的全部消息再逐条投递
+#[derive(Debug, Default)]
+pub struct CollectPublisher {
+    messages: Mutex<Vec<OutgoingMessage>>,
+}
+
+impl CollectPublisher {
+    pub fn into_messages(self) -> Vec<OutgoingMessage> {
+        self.messages.into_inner().unwrap()
+    }
+}
+
+impl Publisher for CollectPublisher {
+    fn publish<S, V>(&self, topic: S, qos: QoS, retain: bool, payload: V) -> impl Future<Output = Result<(), ClientError>> + Send
+    where
+        S: Into<String> + Send,
+        V: Into<Vec<u8>> + Send,
+    {
+        let message = OutgoingMessage { topic: topic.into(), qos, retain, payload: payload.into() };
+        self.messages.lock().unwrap().push(message);
+        std::future::ready(Ok(()))
+    }
+}
+
+/// 不连接 broker 的会话：发布请求转交 `LogPublisher`，订阅等其他请求只记录 debug 日志
+pub fn dry_run_session(acks: Arc<AckTracker>) -> MqttSession {
+    let (tx, rx) = flume::bounded::<Request>(DRY_RUN_QUEUE);
+    let stop = CancellationToken::new();
+    let stopped = stop.clone();
+    let loop_acks = acks.clone();
+    let event_loop = tokio::spawn(async move {
+        let mut pkid: u16 = 0;
+        loop {
+            let request = tokio::select! {
+                _ = stopped.cancelled() => break,
+                request = rx.recv_async() => match request {
+                    Ok(request) => request,
+                    Err(_) => break,
+                },
+            };
+            match request {
+                Request::Publish(publish) => {
+                    let qos = publish.qos;
+                    let _ = LogPublisher.publish(publish.topic, qos, publish.retain, publish.payload.to_vec()).await;
+                    if qos != QoS::AtMostOnce {
+                        pkid = pkid.checked_add(1).unwrap_or(1);
+                        loop_acks.on_outgoing(pkid);
+                        loop_acks.on_puback(pkid);
+                    }
+                }
+                other => debug!("[dry-run] 忽略请求: {:?}", other),
+            }
+        }
+    });
+    let (client, tracked) = acks::track(AsyncClient::from_senders(tx), acks, DRY_RUN_QUEUE);
+    MqttSession { client, tracked, stop, event_loop }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rumqttc::MqttOptions;
+
+    use super::*;
+    use crate::acks::track;
+    use crate::buffer::PublishBuffer;
+    use crate::delivery::{drain, PendingSample};
+    use crate::mqtt_handlers::{publish_daemon_stats, publish_heartbeat, DaemonHeartbeat};
+    use crate::test_support::PayloadBuilder;
+    use crate::topics::TopicMap;
+
+    #[tokio::test]
+    async fn a_stalled_event_loop_does_not_stop_the_main_loop_from_buffering() {
+        // 事件循环从不运行（broker 不可达），客户端队列很快被占满
+        let (inner, _eventloop) = AsyncClient::new(MqttOptions::new("publisher-stalled", "localhost", 1883), 10);
+        let (client, tracked) = track(inner, Arc::new(AckTracker::default()), 10);
+        let stats = Arc::new(DaemonStats::default());
+        let publisher = TryPublisher::new(client, stats.clone());
+        let topics = TopicMap::default();
+        let mut buffer = PublishBuffer::new(64);
+
+        // 按主循环的顺序：心跳、统计、设备状态，再缓冲并投递一个样本
+        let iterations = 40;
+        let looped = tokio::time::timeout(Duration::from_secs(2), async {
+            for seq in 0..iterations {
+                let heartbeat = DaemonHeartbeat { seq, ts_unix_ms: 1_700_000_000_000 + seq };
+                let _ = publish_heartbeat(&publisher, "ups", &heartbeat).await;
+                let _ = publish_daemon_stats(&publisher, "ups", &stats.snapshot()).await;
+                let _ = publisher.publish("ups/availability", QoS::AtLeastOnce, true, "online").await;
+                let sample = PayloadBuilder::new().sample(seq, 1_700_000_000_000 + seq);
+                buffer.push(PendingSample::new("ups".to_string(), Arc::new(sample)));
+                drain(&mut buffer, &tracked, &topics, true).await;
+            }
+        });
+        assert!(looped.await.is_ok(), "主循环被发送队列阻塞");
+        assert_eq!(buffer.len(), iterations as usize);
+        // 两级队列最多接收约 20 条，其余都被丢弃
+        assert!(stats.publishes_dropped.load(Ordering::Relaxed) >= 2 * iterations);
+    }
+
+    #[tokio::test]
+    async fn a_closed_client_drops_and_counts_the_message() {
+        let (inner, eventloop) = AsyncClient::new(MqttOptions::new("publisher-closed", "localhost", 1883), 10);
+        drop(eventloop);
+        let stats = Arc::new(DaemonStats::default());
+        let publisher = TryPublisher::new(inner, stats.clone());
+        let result = publisher.publish("ups/availability", QoS::AtLeastOnce, true, "online").await;
+        assert!(matches!(result, Err(ClientError::TryRequest(_))));
+        assert_eq!(stats.publishes_dropped.load(Ordering::Relaxed), 1);
+    }
+}
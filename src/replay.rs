@@ -0,0 +1,344 @@
+//! 回放模式 (`--replay <path> [--speed 10x]`)：不打开 USB 设备，把 `--capture` 记录的原始帧
+//! 经与真实设备相同的握手、分片读取与解析流程送入主循环，照常发布到 MQTT。
+//!
+//! `ReplayTransport` 实现 `UsbTransport`：推送端点按记录的时间间隔（除以 `speed`）依次返回
+//! 推送记录，响应端点依次返回响应记录，写入的命令只被接受不做比对。推送记录读完后读取返回
+//! `NoDevice`，回放任务输出摘要（帧数、解析错误数）后通知主循环按正常流程退出。
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::capture::{read_capture, CaptureRecord, Direction};
+use crate::conversion::ConversionContext;
+use crate::debug_dump::DebugDump;
+use crate::frame_id::FrameIdAllocator;
+use crate::sequence::SequenceTracker;
+use crate::timing::UsbTimeouts;
+use crate::transport::UsbTransport;
+use crate::usb_handlers::{connect_and_subscribe_usb, handle_push_frame, read_frame, PushContext};
+use crate::usb_types::{DeviceCommand, DeviceEvent, DeviceId, ProtocolVersion, UsbCommand, UsbEvent, UsbId, UsbLinkStats};
+use crate::utils::unix_ms_now;
+
+/// 回放时使用的设备标识
+pub const REPLAY_DEVICE: &str = "replay";
+
+/// 解析 `--speed`：`10x`、`0.5x` 或 `10`，须为正数
+pub fn parse_speed(text: &str) -> Result<f64, String> {
+    let number = text.trim().trim_end_matches(['x', 'X']);
+    match number.parse::<f64>() {
+        Ok(speed) if speed.is_finite() && speed > 0.0 => Ok(speed),
+        _ => Err(format!("invalid speed '{}', expected e.g. 1x, 10x, 0.5x", text)),
+    }
+}
+
+#[derive(Debug)]
+struct ReplayState {
+    pushes: VecDeque<CaptureRecord>,
+    responses: VecDeque<CaptureRecord>,
+    /// 首个推送记录的时间戳与开始回放的时刻
+    origin: Option<(u64, Instant)>,
+}
+
+/// 按抓取文件应答的传输
+#[derive(Debug)]
+pub struct ReplayTransport {
+    state: Mutex<ReplayState>,
+    speed: f64,
+}
+
+impl ReplayTransport {
+    pub fn new(records: Vec<CaptureRecord>, speed: f64) -> Self {
+        let (mut pushes, mut responses) = (VecDeque::new(), VecDeque::new());
+        for record in records {
+            match record.direction {
+                Direction::Push => pushes.push_back(record),
+                Direction::Response => responses.push_back(record),
+                Direction::Command => {}
+            }
+        }
+        ReplayTransport {
+            state: Mutex::new(ReplayState { pushes, responses, origin: None }),
+            speed,
+        }
+    }
+
+    pub fn open(path: &Path, speed: f64) -> std::io::Result<Self> {
+        Ok(Self::new(read_capture(path)?, speed))
+    }
+
+    fn copy(record: &CaptureRecord, buf: &mut [u8]) -> usize {
+        let n = record.bytes.len().min(buf.len());
+        buf[..n].copy_from_slice(&record.bytes[..n]);
+        n
+    }
+}
+
+impl UsbTransport for ReplayTransport {
+    fn write_command(&self, data: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+        Ok(data.len())
+    }
+
+    fn read_response(&self, buf: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+        match self.state.lock().unwrap().responses.pop_front() {
+            Some(record) => Ok(Self::copy(&record, buf)),
+            None => Err(rusb::Error::Timeout),
+        }
+    }
+
+    fn read_push(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let Some(next_us) = state.pushes.front().map(|record| record.timestamp_us) else {
+            return Err(rusb::Error::NoDevice);
+        };
+        let (origin_us, started) = *state.origin.get_or_insert((next_us, Instant::now()));
+        let offset_us = next_us.saturating_sub(origin_us) as f64 / self.speed;
+        let due = started + Duration::from_micros(offset_us as u64);
+        let wait = due.saturating_duration_since(Instant::now());
+        if wait > timeout {
+            drop(state);
+            std::thread::sleep(timeout);
+            return Err(rusb::Error::Timeout);
+        }
+        std::thread::sleep(wait);
+        let record = state.pushes.pop_front().expect("front checked above");
+        Ok(Self::copy(&record, buf))
+    }
+}
+
+/// 回放结束时的摘要
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplaySummary {
+    pub frames: u64,
+    pub parse_errors: u64,
+}
+
+/// 回放所需的设置
+pub struct ReplaySettings {
+    pub frame_ids: Arc<FrameIdAllocator>,
+    pub conversion_ctx: Arc<ConversionContext>,
+    pub timeouts: UsbTimeouts,
+    pub shutdown: CancellationToken,
+    /// 回放读完时取消，主循环据此执行与收到退出信号相同的退出流程
+    pub finished: CancellationToken,
+    pub debug_dump: DebugDump,
+}
+
+/// 代替设备注册表运行：握手后逐帧回放，事件以 `replay` 设备标识发给主循环。
+/// 读完后等待主循环的取消订阅命令再退出，使退出流程与真实设备一致
+pub async fn replay_task(
+    transport: ReplayTransport,
+    settings: ReplaySettings,
+    mut cmd_rx: mpsc::Receiver<DeviceCommand>,
+    event_tx: mpsc::Sender<DeviceEvent>,
+) -> ReplaySummary {
+    let device = DeviceId::Serial(REPLAY_DEVICE.to_string());
+    let (raw_tx, mut raw_rx) = mpsc::channel::<UsbEvent>(32);
+    let forward_device = device.clone();
+    tokio::spawn(async move {
+        while let Some(event) = raw_rx.recv().await {
+            if event_tx.send(DeviceEvent { device: forward_device.clone(), event }).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let transport = Arc::new(transport);
+    // 抓取文件只记录了当时的握手，回放时不发送额外的命令
+    let protocol = match connect_and_subscribe_usb(transport.as_ref(), &settings.timeouts, None).await {
+        Ok(protocol) => protocol,
+        Err(e) => {
+            warn!(error = %e, "抓取文件中没有完整的握手记录，按旧协议回放");
+            ProtocolVersion::LEGACY
+        }
+    };
+    let connected = UsbEvent::Connected { device_id: device.clone(), usb_id: UsbId { vid: 0, pid: 0 }, protocol };
+    if let Err(e) = raw_tx.send(connected).await {
+        error!("发送回放连接事件失败: {:?}", e);
+    }
+
+    let mut sequence = SequenceTracker::default();
+    let mut link_stats = UsbLinkStats::default();
+    let started = Instant::now();
+    loop {
+        let reader = Arc::clone(&transport);
+        let shutdown = settings.shutdown.clone();
+        let read = tokio::task::spawn_blocking(move || {
+            let mut buf = vec![0u8; 256];
+            read_frame(reader.as_ref(), &mut buf, Duration::from_secs(1), &shutdown).map(|n| {
+                buf.truncate(n);
+                buf
+            })
+        })
+        .await
+        .unwrap_or(Err(rusb::Error::Other));
+        match read {
+            Ok(frame) if frame.is_empty() => {}
+            Ok(frame) => {
+                let mut push = PushContext {
+                    device: &device,
+                    frame_ids: &settings.frame_ids,
+                    conversion_ctx: &settings.conversion_ctx,
+                    protocol,
+                    event_tx: &raw_tx,
+                    sequence: &mut sequence,
+                    link_stats: &mut link_stats,
+                    debug_dump: &settings.debug_dump,
+                };
+                handle_push_frame(&frame, unix_ms_now(), &mut push).await;
+            }
+            Err(rusb::Error::Timeout) => {}
+            Err(rusb::Error::NoDevice) => break,
+            Err(rusb::Error::Interrupted) if settings.shutdown.is_cancelled() => break,
+            Err(e) => {
+                error!(error = ?e, "回放读取失败");
+                break;
+            }
+        }
+    }
+    let summary = ReplaySummary { frames: link_stats.frames_received, parse_errors: link_stats.parse_errors };
+    info!(
+        frames = summary.frames,
+        parse_errors = summary.parse_errors,
+        elapsed = ?started.elapsed(),
+        "回放结束"
+    );
+    settings.finished.cancel();
+
+    while let Some(DeviceCommand { command, .. }) = cmd_rx.recv().await {
+        if let UsbCommand::Unsubscribe = command {
+            let _ = raw_tx.send(UsbEvent::Unsubscribed).await;
+            break;
+        }
+    }
+    let _ = raw_tx.send(UsbEvent::Detached).await;
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use binrw::BinWrite;
+
+    use crate::debug_dump::DumpTarget;
+    use crate::test_support::PayloadBuilder;
+    use crate::usb_types::{UsbData, SUPPORTED_PROTOCOL_VERSION};
+
+    fn record(timestamp_us: u64, direction: Direction, bytes: &[u8]) -> CaptureRecord {
+        CaptureRecord { timestamp_us, direction, bytes: bytes.to_vec() }
+    }
+
+    fn encode(data: &UsbData) -> Vec<u8> {
+        let mut cursor = Cursor::new(Vec::new());
+        data.write_be(&mut cursor).unwrap();
+        cursor.into_inner()
+    }
+
+    // 推送端点按 64 字节分包读取，抓取文件中每个包是一条记录
+    fn push_records(timestamp_us: u64, frame: &[u8]) -> Vec<CaptureRecord> {
+        frame.chunks(64).map(|chunk| record(timestamp_us, Direction::Push, chunk)).collect()
+    }
+
+    #[test]
+    fn speeds_accept_an_optional_x_suffix() {
+        assert_eq!(parse_speed("10x"), Ok(10.0));
+        assert_eq!(parse_speed(" 0.5X "), Ok(0.5));
+        assert_eq!(parse_speed("2"), Ok(2.0));
+        for text in ["0x", "-1x", "fast", "x", "infx", "NaN"] {
+            assert!(parse_speed(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn the_transport_replays_each_endpoint_in_order() {
+        let transport = ReplayTransport::new(
+            vec![
+                record(0, Direction::Command, &[1]),
+                record(10, Direction::Response, &[2, 2]),
+                record(20, Direction::Push, &[3, 3, 3]),
+                record(30, Direction::Response, &[4]),
+            ],
+            1.0,
+        );
+        let mut buf = [0u8; 8];
+        assert_eq!(transport.write_command(&[9, 9], Duration::ZERO), Ok(2));
+        assert_eq!(transport.read_response(&mut buf, Duration::ZERO), Ok(2));
+        assert_eq!(transport.read_response(&mut buf, Duration::ZERO), Ok(1));
+        assert_eq!(buf[0], 4);
+        assert_eq!(transport.read_response(&mut buf, Duration::ZERO), Err(rusb::Error::Timeout));
+        assert_eq!(transport.read_push(&mut buf, Duration::from_millis(10)), Ok(3));
+        // 推送记录读完后按设备断开处理
+        assert_eq!(transport.read_push(&mut buf, Duration::from_millis(10)), Err(rusb::Error::NoDevice));
+    }
+
+    #[test]
+    fn pushes_keep_the_recorded_spacing_divided_by_the_speed() {
+        // 相隔 1 秒的两帧，10 倍速回放相隔约 100 ms
+        let records = vec![record(0, Direction::Push, &[1]), record(1_000_000, Direction::Push, &[2])];
+        let transport = ReplayTransport::new(records, 10.0);
+        let mut buf = [0u8; 8];
+        let started = Instant::now();
+        assert_eq!(transport.read_push(&mut buf, Duration::from_secs(1)), Ok(1));
+        // 未到时间的帧在读取超时后留在队列中
+        assert_eq!(transport.read_push(&mut buf, Duration::from_millis(20)), Err(rusb::Error::Timeout));
+        assert_eq!(transport.read_push(&mut buf, Duration::from_secs(1)), Ok(1));
+        assert_eq!(buf[0], 2);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(100) && elapsed < Duration::from_secs(1), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn a_replay_runs_the_handshake_and_summarizes_frames_and_parse_errors() {
+        let builder = PayloadBuilder::new().sequence(1);
+        let version = UsbData::VersionResponse { protocol: SUPPORTED_PROTOCOL_VERSION, firmware: *b"1.2a" };
+        let mut records = vec![
+            record(0, Direction::Command, &encode(&UsbData::GetVersion)),
+            record(1, Direction::Response, &encode(&version)),
+            record(2, Direction::Command, &encode(&UsbData::SubscribeStatus)),
+            record(3, Direction::Response, &builder.response_frame()),
+        ];
+        records.extend(push_records(1_000, &builder.frame()));
+        records.push(record(2_000, Direction::Push, &[0x7F; 40]));
+        records.extend(push_records(3_000, &PayloadBuilder::new().sequence(2).frame()));
+
+        let finished = CancellationToken::new();
+        let settings = ReplaySettings {
+            frame_ids: Arc::new(FrameIdAllocator::in_memory()),
+            conversion_ctx: Arc::new(ConversionContext::default()),
+            timeouts: UsbTimeouts::default(),
+            shutdown: CancellationToken::new(),
+            finished: finished.clone(),
+            debug_dump: DebugDump::new(false, DumpTarget::Log),
+        };
+        let (cmd_tx, cmd_rx) = mpsc::channel(4);
+        let (event_tx, mut event_rx) = mpsc::channel(64);
+        let task = tokio::spawn(replay_task(ReplayTransport::new(records, 1000.0), settings, cmd_rx, event_tx));
+
+        // 读完后通知主循环退出，收到取消订阅后结束
+        tokio::time::timeout(Duration::from_secs(10), finished.cancelled()).await.unwrap();
+        cmd_tx.send(DeviceCommand { target: None, command: UsbCommand::Unsubscribe }).await.unwrap();
+        let summary = task.await.unwrap();
+        assert_eq!(summary, ReplaySummary { frames: 3, parse_errors: 1 });
+
+        let mut events = Vec::new();
+        while let Some(DeviceEvent { device, event }) = event_rx.recv().await {
+            assert_eq!(device, DeviceId::Serial(REPLAY_DEVICE.to_string()));
+            events.push(event);
+        }
+        assert!(matches!(
+            events.first(),
+            Some(UsbEvent::Connected { protocol: ProtocolVersion { protocol: SUPPORTED_PROTOCOL_VERSION, .. }, .. })
+        ));
+        let measurements = events.iter().filter(|event| matches!(event, UsbEvent::Measurements(_))).count();
+        assert_eq!(measurements, 2);
+        assert!(matches!(events[events.len() - 2..], [UsbEvent::Unsubscribed, UsbEvent::Detached]));
+    }
+}
@@ -0,0 +1,144 @@
+//! 固定容量的环形缓冲，保存最近的样本。
+//!
+//! `Ring` 本身与样本类型无关；`History` 是主循环与告警任务、控制接口共享的测量数据历史，
+//! 告警激活时取出激活前的数据作为上下文，`history` 命令按需返回最近 N 帧。
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::data_models::TimestampedMeasurements;
+
+/// 容量固定的环形缓冲：写满后新元素覆盖最旧的元素
+#[derive(Debug, Clone)]
+pub struct Ring<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T: Clone> Ring<T> {
+    /// 容量为 0 时不保存任何元素
+    pub fn new(capacity: usize) -> Self {
+        Ring { items: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// 追加一个元素；已满时丢弃最旧的元素并返回它
+    pub fn push(&mut self, item: T) -> Option<T> {
+        if self.capacity == 0 {
+            return Some(item);
+        }
+        let evicted = if self.items.len() == self.capacity { self.items.pop_front() } else { None };
+        self.items.push_back(item);
+        evicted
+    }
+
+    /// 全部元素，从旧到新
+    pub fn snapshot(&self) -> Vec<T> {
+        self.items.iter().cloned().collect()
+    }
+
+    /// 最近的至多 `n` 个元素，从旧到新
+    pub fn last(&self, n: usize) -> Vec<T> {
+        self.items.iter().skip(self.items.len().saturating_sub(n)).cloned().collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+}
+
+/// 共享的测量数据历史；克隆后指向同一个缓冲
+#[derive(Debug, Clone)]
+pub struct History {
+    ring: Arc<Mutex<Ring<Arc<TimestampedMeasurements>>>>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History { ring: Arc::new(Mutex::new(Ring::new(capacity))) }
+    }
+
+    pub fn push(&self, sample: Arc<TimestampedMeasurements>) {
+        self.ring.lock().unwrap().push(sample);
+    }
+
+    pub fn snapshot(&self) -> Vec<Arc<TimestampedMeasurements>> {
+        self.ring.lock().unwrap().snapshot()
+    }
+
+    pub fn last(&self, n: usize) -> Vec<Arc<TimestampedMeasurements>> {
+        self.ring.lock().unwrap().last(n)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.ring.lock().unwrap().capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::PayloadBuilder;
+
+    #[test]
+    fn the_oldest_items_are_overwritten_once_full() {
+        let mut ring = Ring::new(3);
+        for i in 0..3 {
+            assert_eq!(ring.push(i), None);
+        }
+        assert_eq!(ring.snapshot(), [0, 1, 2]);
+        assert_eq!(ring.push(3), Some(0));
+        assert_eq!(ring.push(4), Some(1));
+        assert_eq!(ring.snapshot(), [2, 3, 4]);
+        assert_eq!((ring.len(), ring.capacity()), (3, 3));
+        // 多次绕回后仍按从旧到新的顺序
+        for i in 5..100 {
+            ring.push(i);
+        }
+        assert_eq!(ring.snapshot(), [97, 98, 99]);
+    }
+
+    #[test]
+    fn last_returns_the_newest_items_oldest_first() {
+        let mut ring = Ring::new(5);
+        for i in 0..7 {
+            ring.push(i);
+        }
+        assert_eq!(ring.last(2), [5, 6]);
+        assert_eq!(ring.last(0), Vec::<i32>::new());
+        assert_eq!(ring.last(100), [2, 3, 4, 5, 6]);
+        ring.clear();
+        assert!(ring.is_empty() && ring.last(3).is_empty());
+    }
+
+    #[test]
+    fn a_zero_capacity_ring_keeps_nothing() {
+        let mut ring = Ring::new(0);
+        assert_eq!(ring.push(1), Some(1));
+        assert!(ring.is_empty() && ring.snapshot().is_empty());
+    }
+
+    #[test]
+    fn clones_of_the_history_share_one_buffer() {
+        let history = History::new(2);
+        let reader = history.clone();
+        for frame_id in 1..=3 {
+            history.push(Arc::new(PayloadBuilder::new().sample(frame_id, 1000 * frame_id)));
+        }
+        let frame_ids: Vec<u64> = reader.snapshot().iter().map(|sample| sample.frame_id).collect();
+        assert_eq!(frame_ids, [2, 3]);
+        assert_eq!(reader.last(1)[0].frame_id, 3);
+        assert_eq!(reader.capacity(), 2);
+    }
+}
@@ -0,0 +1,163 @@
+#[cfg(feature = "mqtt")]
+use std::time::Duration;
+
+#[cfg(feature = "mqtt")]
+use futures::StreamExt;
+#[cfg(feature = "mqtt")]
+use tracing::{error, info, warn};
+#[cfg(feature = "mqtt")]
+use rumqttc::{AsyncClient, QoS};
+use serde::Serialize;
+#[cfg(feature = "mqtt")]
+use tokio::sync::broadcast;
+
+#[cfg(feature = "mqtt")]
+use crate::data_models::active_advisories;
+#[cfg(feature = "mqtt")]
+use crate::pipeline::{self, FieldSelector, Predicate, Subscription};
+
+/// 电池充放电状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerState {
+    Charging,
+    Discharging,
+    Idle,
+}
+
+impl PowerState {
+    /// 按瞬时电池包电流判定（不去抖）
+    pub fn from_current(current: f32, threshold: f32) -> Self {
+        if current > threshold {
+            PowerState::Charging
+        } else if current < -threshold {
+            PowerState::Discharging
+        } else {
+            PowerState::Idle
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PowerState::Charging => "charging",
+            PowerState::Discharging => "discharging",
+            PowerState::Idle => "idle",
+        }
+    }
+}
+
+pub fn power_state_topic(topic_prefix: &str) -> String {
+    format!("{}/battery/power_state", topic_prefix)
+}
+
+pub fn fault_alert_topic(topic_prefix: &str) -> String {
+    format!("{}/alerts/fault", topic_prefix)
+}
+
+pub fn advisories_topic(topic_prefix: &str) -> String {
+    format!("{}/advisories", topic_prefix)
+}
+
+/// 充放电状态检测：电池包电流持续高于 `threshold` 为充电，持续低于 `-threshold` 为放电，
+/// 其余为空闲。状态变化时以 retained 方式发布到 `{prefix}/battery/power_state`。
+#[cfg(feature = "mqtt")]
+pub async fn power_state_task(
+    samples: Subscription,
+    client: AsyncClient,
+    topic_prefix: String,
+    threshold: f32,
+    debounce: Duration,
+) {
+    let charging = pipeline::watch(samples.resubscribe(), FieldSelector::PackCurrent, Predicate::Above(threshold), debounce)
+        .map(|t| (true, t.matched));
+    let discharging = pipeline::watch(samples, FieldSelector::PackCurrent, Predicate::Below(-threshold), debounce)
+        .map(|t| (false, t.matched));
+    let mut transitions = futures::stream::select(charging, discharging);
+
+    let (mut is_charging, mut is_discharging) = (false, false);
+    let mut current: Option<PowerState> = None;
+    while let Some((is_charge_watch, matched)) = transitions.next().await {
+        if is_charge_watch {
+            is_charging = matched;
+        } else {
+            is_discharging = matched;
+        }
+        let state = match (is_charging, is_discharging) {
+            (true, _) => PowerState::Charging,
+            (_, true) => PowerState::Discharging,
+            _ => PowerState::Idle,
+        };
+        if current == Some(state) {
+            continue;
+        }
+        info!("电池状态: {:?} -> {:?}", current, state);
+        current = Some(state);
+        let topic = power_state_topic(&topic_prefix);
+        if let Err(e) = client.publish(topic, QoS::AtLeastOnce, true, state.as_str()).await {
+            error!("发布电池状态失败: {:?}", e);
+        }
+    }
+}
+
+/// 故障告警：任一故障标志置位（持续 `debounce`）时发布 `active`，恢复后发布 `clear`
+#[cfg(feature = "mqtt")]
+pub async fn fault_alert_task(
+    samples: Subscription,
+    client: AsyncClient,
+    topic_prefix: String,
+    debounce: Duration,
+) {
+    let mut transitions = pipeline::watch(samples, FieldSelector::AnyFault, Predicate::NonZero, debounce);
+    while let Some(transition) = transitions.next().await {
+        let payload = if transition.matched {
+            warn!("检测到故障标志置位");
+            "active"
+        } else {
+            if transition.previous.is_some() {
+                info!("故障标志已清除");
+            }
+            "clear"
+        };
+        let topic = fault_alert_topic(&topic_prefix);
+        if let Err(e) = client.publish(topic, QoS::AtLeastOnce, true, payload).await {
+            error!("发布故障告警失败: {:?}", e);
+        }
+    }
+}
+
+/// 操作建议：当前置位的保护/故障标志集合变化时，以 retained 方式把对应建议
+/// (JSON 数组，无故障时为 `[]`) 发布到 `{prefix}/advisories`
+#[cfg(feature = "mqtt")]
+pub async fn advisory_task(mut samples: Subscription, client: AsyncClient, topic_prefix: String) {
+    let mut current: Option<Vec<&'static str>> = None;
+    loop {
+        let sample = match samples.recv().await {
+            Ok(sample) => sample,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("操作建议处理过慢，跳过 {} 帧", n);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let advisories = active_advisories(&sample.data);
+        let flags: Vec<&'static str> = advisories.iter().map(|a| a.flag).collect();
+        if current.as_ref() == Some(&flags) {
+            continue;
+        }
+        if !flags.is_empty() {
+            warn!("当前告警: {}", flags.join(", "));
+        }
+        current = Some(flags);
+        let payload = match serde_json::to_string(&advisories) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("序列化操作建议失败: {:?}", e);
+                continue;
+            }
+        };
+        let topic = advisories_topic(&topic_prefix);
+        if let Err(e) = client.publish(topic, QoS::AtLeastOnce, true, payload).await {
+            error!("发布操作建议失败: {:?}", e);
+        }
+    }
+}
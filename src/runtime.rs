@@ -0,0 +1,129 @@
+//! 剩余放电时间与充满时间的估算。
+//!
+//! 电流先做按时间加权的指数滑动平均，负载的短时尖峰不会让估算值大幅跳动；
+//! 平滑后的电流绝对值低于静置阈值时不给出估算。
+
+use std::time::Duration;
+
+/// 估算结果 (分钟)；放电时只有 `runtime_min`，充电时只有 `time_to_full_min`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RuntimeEstimate {
+    pub runtime_min: Option<f32>,
+    pub time_to_full_min: Option<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeEstimator {
+    capacity_mah: f32,
+    /// EMA 时间常数
+    window: Duration,
+    idle_current_a: f32,
+    /// (上一帧时间戳, 平滑后的电流 A，充电为正)
+    state: Option<(u64, f32)>,
+}
+
+impl RuntimeEstimator {
+    pub fn new(capacity_mah: f32, window: Duration, idle_current_a: f32) -> Self {
+        RuntimeEstimator {
+            capacity_mah,
+            window,
+            idle_current_a,
+            state: None,
+        }
+    }
+
+    /// 喂入一帧的电池电流 (A，充电为正) 与当前电量，返回估算结果
+    pub fn update(&mut self, ts_unix_ms: u64, current_a: f32, soc_percent: Option<f32>) -> RuntimeEstimate {
+        let smoothed = match self.state {
+            Some((last_ts, ema)) if ts_unix_ms > last_ts && !self.window.is_zero() => {
+                let dt = (ts_unix_ms - last_ts) as f32 / 1000.0;
+                let alpha = 1.0 - (-dt / self.window.as_secs_f32()).exp();
+                ema + alpha * (current_a - ema)
+            }
+            // 时钟回拨时保留原值，只更新时间戳
+            Some((_, ema)) if !self.window.is_zero() => ema,
+            _ => current_a,
+        };
+        self.state = Some((ts_unix_ms, smoothed));
+
+        let Some(soc) = soc_percent else {
+            return RuntimeEstimate::default();
+        };
+        if smoothed.abs() < self.idle_current_a {
+            return RuntimeEstimate::default();
+        }
+        let current_ma = smoothed.abs() * 1000.0;
+        let remaining_mah = self.capacity_mah * soc / 100.0;
+        let minutes = |mah: f32| (mah / current_ma * 60.0 * 10.0).round() / 10.0;
+        if smoothed > 0.0 {
+            RuntimeEstimate {
+                runtime_min: None,
+                time_to_full_min: Some(minutes(self.capacity_mah - remaining_mah)),
+            }
+        } else {
+            RuntimeEstimate {
+                runtime_min: Some(minutes(remaining_mah)),
+                time_to_full_min: None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimator() -> RuntimeEstimator {
+        RuntimeEstimator::new(10_000.0, Duration::from_secs(60), 0.1)
+    }
+
+    #[test]
+    fn discharging_estimates_runtime_from_the_remaining_capacity() {
+        // 10 Ah 的一半以 2.5 A 放出：120 分钟
+        let estimate = estimator().update(0, -2.5, Some(50.0));
+        assert_eq!(estimate, RuntimeEstimate { runtime_min: Some(120.0), time_to_full_min: None });
+    }
+
+    #[test]
+    fn charging_estimates_time_to_full() {
+        // 还差 2 Ah，以 4 A 充电：30 分钟
+        let estimate = estimator().update(0, 4.0, Some(80.0));
+        assert_eq!(estimate, RuntimeEstimate { runtime_min: None, time_to_full_min: Some(30.0) });
+    }
+
+    #[test]
+    fn near_zero_current_or_unknown_soc_gives_no_estimate() {
+        assert_eq!(estimator().update(0, 0.05, Some(50.0)), RuntimeEstimate::default());
+        assert_eq!(estimator().update(0, -0.09, Some(50.0)), RuntimeEstimate::default());
+        assert_eq!(estimator().update(0, -2.0, None), RuntimeEstimate::default());
+    }
+
+    #[test]
+    fn a_short_load_spike_barely_moves_the_estimate() {
+        let mut runtime = estimator();
+        let steady = runtime.update(0, -2.0, Some(50.0)).runtime_min.unwrap();
+        // 1 秒的 20 A 尖峰，时间常数 60 秒
+        let spiked = runtime.update(1_000, -20.0, Some(50.0)).runtime_min.unwrap();
+        assert!(spiked > steady * 0.75, "{} -> {}", steady, spiked);
+        // 尖峰过后逐渐回到稳态
+        let mut recovered = spiked;
+        for second in 2..=600u64 {
+            recovered = runtime.update(second * 1000, -2.0, Some(50.0)).runtime_min.unwrap();
+        }
+        assert!((recovered - steady).abs() < 0.5, "{} vs {}", recovered, steady);
+    }
+
+    #[test]
+    fn a_clock_jump_keeps_the_smoothed_current() {
+        let mut runtime = estimator();
+        runtime.update(10_000, -2.0, Some(50.0));
+        assert_eq!(runtime.update(5_000, -20.0, Some(50.0)).runtime_min, Some(150.0));
+    }
+
+    #[test]
+    fn a_zero_window_disables_smoothing() {
+        let mut runtime = RuntimeEstimator::new(10_000.0, Duration::ZERO, 0.1);
+        runtime.update(0, -2.0, Some(50.0));
+        assert_eq!(runtime.update(1_000, -5.0, Some(50.0)).runtime_min, Some(60.0));
+    }
+}
@@ -0,0 +1,153 @@
+//! 重复帧与乱序帧检测。
+//!
+//! 新固件在载荷末尾附带 u16 序号时按序号判断：相同为重复，跳号计为丢帧，小幅回退为乱序。
+//! 旧固件没有序号，退回比较载荷内容的哈希，只能识别与上一帧完全相同的重复帧。
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Cursor;
+
+use binrw::BinWrite;
+
+use crate::data_models::HostSideUsbPayload;
+
+/// 序号回退超过该值视为设备重新计数，而不是乱序
+const REORDER_WINDOW: u16 = 16;
+
+/// 一帧相对上一帧的判断结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameOrder {
+    /// 正常的下一帧（含首帧与序号重新计数）
+    Next,
+    /// 序号跳过了若干帧，本帧仍然有效
+    Gap { missed: u16 },
+    /// 与上一帧序号相同或内容完全相同，应丢弃
+    Duplicate,
+    /// 序号早于上一帧，应丢弃
+    OutOfOrder,
+}
+
+impl FrameOrder {
+    pub fn is_dropped(&self) -> bool {
+        matches!(self, FrameOrder::Duplicate | FrameOrder::OutOfOrder)
+    }
+}
+
+/// 每个 USB 连接一个，重新连接后重新开始
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    last_sequence: Option<u16>,
+    last_hash: Option<u64>,
+}
+
+impl SequenceTracker {
+    pub fn check(&mut self, payload: &HostSideUsbPayload) -> FrameOrder {
+        match payload.sequence {
+            Some(sequence) => {
+                self.last_hash = None;
+                let Some(last) = self.last_sequence else {
+                    self.last_sequence = Some(sequence);
+                    return FrameOrder::Next;
+                };
+                let ahead = sequence.wrapping_sub(last);
+                let behind = last.wrapping_sub(sequence);
+                if ahead == 0 {
+                    FrameOrder::Duplicate
+                } else if behind != 0 && behind <= REORDER_WINDOW {
+                    FrameOrder::OutOfOrder
+                } else {
+                    self.last_sequence = Some(sequence);
+                    // 大幅回退（设备重新计数）与正常递增一样按下一帧处理
+                    if (2..=u16::MAX / 2).contains(&ahead) {
+                        FrameOrder::Gap { missed: ahead - 1 }
+                    } else {
+                        FrameOrder::Next
+                    }
+                }
+            }
+            None => {
+                self.last_sequence = None;
+                let hash = payload_hash(payload);
+                if self.last_hash.replace(hash) == Some(hash) {
+                    FrameOrder::Duplicate
+                } else {
+                    FrameOrder::Next
+                }
+            }
+        }
+    }
+}
+
+fn payload_hash(payload: &HostSideUsbPayload) -> u64 {
+    let mut bytes = Cursor::new(Vec::with_capacity(HostSideUsbPayload::SIZE));
+    // 写入内存缓冲区不会失败
+    let _ = payload.write(&mut bytes);
+    let mut hasher = DefaultHasher::new();
+    bytes.into_inner().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::PayloadBuilder;
+    use crate::usb_types::UsbLinkStats;
+
+    fn sequenced(sequence: u16) -> HostSideUsbPayload {
+        PayloadBuilder::new().sequence(sequence).payload()
+    }
+
+    #[test]
+    fn sequence_numbers_classify_each_frame() {
+        let mut tracker = SequenceTracker::default();
+        let orders: Vec<_> = [10, 11, 11, 14, 12, 15].into_iter().map(|s| tracker.check(&sequenced(s))).collect();
+        assert_eq!(
+            orders,
+            [
+                FrameOrder::Next,
+                FrameOrder::Next,
+                FrameOrder::Duplicate,
+                FrameOrder::Gap { missed: 2 },
+                FrameOrder::OutOfOrder,
+                FrameOrder::Next,
+            ]
+        );
+        assert_eq!(orders.iter().filter(|o| o.is_dropped()).count(), 2);
+    }
+
+    #[test]
+    fn wraparound_and_restarts_are_not_gaps() {
+        let mut tracker = SequenceTracker::default();
+        tracker.check(&sequenced(u16::MAX));
+        assert_eq!(tracker.check(&sequenced(0)), FrameOrder::Next);
+        assert_eq!(tracker.check(&sequenced(2)), FrameOrder::Gap { missed: 1 });
+        // 设备重启后从头计数：大幅回退按下一帧处理
+        tracker.check(&sequenced(5000));
+        assert_eq!(tracker.check(&sequenced(1)), FrameOrder::Next);
+        assert_eq!(tracker.check(&sequenced(2)), FrameOrder::Next);
+    }
+
+    #[test]
+    fn without_a_sequence_only_identical_content_is_a_duplicate() {
+        let mut tracker = SequenceTracker::default();
+        let first = PayloadBuilder::new().vbat_mv(16_000).payload();
+        let second = PayloadBuilder::new().vbat_mv(16_016).payload();
+        assert_eq!(tracker.check(&first), FrameOrder::Next);
+        assert_eq!(tracker.check(&first), FrameOrder::Duplicate);
+        assert_eq!(tracker.check(&second), FrameOrder::Next);
+        // 内容相同但不相邻的帧不是重复
+        assert_eq!(tracker.check(&first), FrameOrder::Next);
+    }
+
+    #[test]
+    fn gaps_are_counted_in_the_link_stats() {
+        let mut tracker = SequenceTracker::default();
+        let mut stats = UsbLinkStats::default();
+        for sequence in [1, 2, 5, 5, 4, 9] {
+            stats.record_order(tracker.check(&sequenced(sequence)));
+        }
+        assert_eq!((stats.sequence_gaps, stats.duplicate_frames, stats.out_of_order_frames), (5, 1, 1));
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["sequence_gaps"], 5);
+    }
+}
@@ -0,0 +1,521 @@
+//! 模拟模式 (`--simulate`)：没有硬件时按场景生成合理的测量数据，作为 `UsbEvent::Measurements`
+//! 送入主循环，MQTT 发布、告警、自动发现等与真实设备完全相同。每帧经过一次线上编码与解析
+//! (`PayloadBuilder`)，数值的分辨率与真实设备一致。
+//!
+//! 场景 (`--sim-scenario`，TOML) 是按顺序执行、执行完从头循环的阶段列表：
+//!
+//! ```toml
+//! [[phases]]
+//! name = "charging"
+//! duration_secs = 60
+//! mains = true
+//! current_a = 1.5        # 电池包电流，充电为正
+//!
+//! [[phases]]
+//! name = "mains loss"
+//! duration_secs = 300
+//! mains = false
+//! current_a = -2.0
+//! faults = ["UV"]        # 阶段内保持置位的标志，名称同 BURST_TRIGGER_FLAGS
+//! ```
+//!
+//! 生成器只依赖种子与帧序号，同一种子与场景总是产生相同的数值序列（时间戳除外）。
+
+use std::f32::consts::TAU;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+use crate::config::ConfigError;
+use crate::data_models::{
+    AllMeasurements, Bq25730Alerts, Bq25730Measurements, Bq76920Alerts, Bq76920Measurements, ChargerFaultFlags,
+    ChargerStatusFlags, DeviceInfo, FlagBit, Ina226Measurements, MosStatus, SystemStatus, Temperatures,
+    TimestampedMeasurements,
+};
+use crate::frame_id::FrameIdAllocator;
+use crate::test_support::PayloadBuilder;
+use crate::usb_types::{DeviceCommand, DeviceEvent, DeviceId, ProtocolVersion, UsbCommand, UsbEvent, UsbId};
+use crate::utils::unix_ms_now;
+
+/// 模拟设备的标识
+pub const SIMULATED_DEVICE: &str = "simulated";
+
+// 模拟电池包容量 (Ah)，决定 SOC 随电流变化的速度
+const CAPACITY_AH: f32 = 2.0;
+// 单节电芯的内阻 (Ω)
+const CELL_RESISTANCE: f32 = 0.02;
+// vbat 慢速正弦波动的周期 (s) 与单节幅度 (V)
+const RIPPLE_PERIOD_SECS: f32 = 600.0;
+const RIPPLE_AMPLITUDE: f32 = 0.01;
+// 每帧随机触发一次短暂故障的概率与持续帧数
+const GLITCH_PROBABILITY: f64 = 0.002;
+const GLITCH_FRAMES: u32 = 5;
+
+/// 场景中的一个阶段
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Phase {
+    #[serde(default)]
+    pub name: String,
+    pub duration_secs: u64,
+    /// 适配器是否接入
+    pub mains: bool,
+    /// 电池包电流 (A)，充电为正
+    pub current_a: f32,
+    /// 阶段内保持置位的标志
+    #[serde(default)]
+    pub faults: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    pub phases: Vec<Phase>,
+}
+
+impl Default for Scenario {
+    /// 充电与放电各 10 分钟交替
+    fn default() -> Self {
+        let phase = |name: &str, mains: bool, current_a: f32| Phase {
+            name: name.to_string(),
+            duration_secs: 600,
+            mains,
+            current_a,
+            faults: Vec::new(),
+        };
+        Scenario {
+            phases: vec![phase("charging", true, 1.5), phase("discharging", false, -2.0)],
+        }
+    }
+}
+
+impl Scenario {
+    /// 读取并校验场景文件：至少一个阶段，持续时间为正，标志名可识别
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let invalid = |reason: String| ConfigError::File { path: path.to_path_buf(), reason };
+        let text = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let scenario: Scenario = toml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+        if scenario.phases.is_empty() {
+            return Err(invalid("scenario has no phases".to_string()));
+        }
+        for phase in &scenario.phases {
+            if phase.duration_secs == 0 {
+                return Err(invalid(format!("phase '{}' has zero duration", phase.name)));
+            }
+            if let Some(name) = phase.faults.iter().find(|name| FlagBit::from_name(name).is_none()) {
+                return Err(invalid(format!("phase '{}': unknown flag '{}'", phase.name, name)));
+            }
+        }
+        Ok(scenario)
+    }
+
+    fn total_secs(&self) -> u64 {
+        self.phases.iter().map(|phase| phase.duration_secs).sum()
+    }
+
+    /// `elapsed_secs` 时所处的阶段（循环执行）
+    fn phase_at(&self, elapsed_secs: f32) -> &Phase {
+        let total = self.total_secs().max(1) as f32;
+        let mut t = elapsed_secs % total;
+        for phase in &self.phases {
+            if t < phase.duration_secs as f32 {
+                return phase;
+            }
+            t -= phase.duration_secs as f32;
+        }
+        &self.phases[self.phases.len() - 1]
+    }
+}
+
+/// SplitMix64：足够均匀且可由种子完全复现
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// [0, 1) 均匀分布
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// [-amplitude, amplitude] 的噪声
+    fn noise(&mut self, amplitude: f32) -> f32 {
+        (self.next_f64() as f32 * 2.0 - 1.0) * amplitude
+    }
+}
+
+/// 测量数据生成器，与时钟解耦：每次调用 `sample` 前进 `interval`
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    scenario: Scenario,
+    interval: Duration,
+    cell_count: usize,
+    rng: Rng,
+    frame: u64,
+    soc: f32,
+    glitch: Option<(ChargerFaultFlags, u32)>,
+}
+
+impl Simulator {
+    pub fn new(scenario: Scenario, interval: Duration, cell_count: usize, seed: u64) -> Self {
+        Simulator {
+            scenario,
+            interval,
+            cell_count: cell_count.clamp(1, 5),
+            rng: Rng(seed),
+            frame: 0,
+            soc: 0.6,
+            glitch: None,
+        }
+    }
+
+    /// 当前阶段的名称
+    pub fn phase_name(&self) -> &str {
+        &self.scenario.phase_at(self.elapsed_secs()).name
+    }
+
+    fn elapsed_secs(&self) -> f32 {
+        self.frame as f32 * self.interval.as_secs_f32()
+    }
+
+    /// 生成下一帧的测量数据
+    pub fn sample(&mut self) -> AllMeasurements<5> {
+        let t = self.elapsed_secs();
+        let phase = self.scenario.phase_at(t).clone();
+        self.frame += 1;
+
+        // 充满或放空后电流归零，避免 SOC 越界
+        let mut current = phase.current_a + self.rng.noise(0.02);
+        if (current > 0.0 && self.soc >= 1.0) || (current < 0.0 && self.soc <= 0.0) {
+            current = 0.0;
+        }
+        self.soc = (self.soc + current * self.interval.as_secs_f32() / (CAPACITY_AH * 3600.0)).clamp(0.0, 1.0);
+
+        let ripple = RIPPLE_AMPLITUDE * (TAU * t / RIPPLE_PERIOD_SECS).sin();
+        let mut cell_voltages = [0.0f32; 5];
+        for (i, cell) in cell_voltages.iter_mut().take(self.cell_count).enumerate() {
+            let imbalance = (i as f32 - 2.0) * 0.003;
+            *cell = 3.0 + 1.2 * self.soc + current * CELL_RESISTANCE + ripple + imbalance + self.rng.noise(0.002);
+        }
+        let vbat: f32 = cell_voltages.iter().sum();
+        let vbus = if phase.mains { 19.5 + self.rng.noise(0.05) } else { 0.0 };
+        let (ichg, idchg) = if current >= 0.0 { (current, 0.0) } else { (0.0, -current) };
+        let load_a = 0.8 + self.rng.noise(0.05);
+        let iin = if phase.mains { (vbat * ichg + 12.0 * load_a) / vbus.max(1.0) } else { 0.0 };
+        let vsys = if phase.mains { vbat.max(12.0) + 0.1 } else { vbat - 0.05 };
+
+        let mut charger_status = ChargerStatusFlags::empty();
+        if phase.mains {
+            charger_status |= ChargerStatusFlags::STAT_AC;
+            if current > 0.0 {
+                charger_status |= ChargerStatusFlags::IN_FCHRG;
+            }
+        }
+
+        let mut data = AllMeasurements {
+            bq25730: Bq25730Measurements {
+                psys: vsys * load_a,
+                vbus,
+                idchg,
+                ichg,
+                cmpin: 0.0,
+                iin,
+                vbat,
+                vsys,
+            },
+            bq76920: Bq76920Measurements {
+                cell_voltages,
+                cell_count: self.cell_count,
+                temperatures: Temperatures {
+                    ts1: Some(25.0 + current.abs() * 2.0 + 3.0 * (TAU * t / 3600.0).sin() + self.rng.noise(0.2)),
+                    ts2: None,
+                    ts3: None,
+                    is_thermistor: true,
+                },
+                coulomb_counter: current,
+                system_status: SystemStatus::CC_READY,
+                mos_status: if phase.mains { MosStatus::BothOn } else { MosStatus::DischargeOn },
+            },
+            ina226: Some(Ina226Measurements {
+                voltage: vsys,
+                current: load_a,
+                power: vsys * load_a,
+            }),
+            bq25730_alerts: Bq25730Alerts {
+                charger_status_flags: charger_status,
+                ..Default::default()
+            },
+            bq76920_alerts: Bq76920Alerts::default(),
+        };
+
+        for name in &phase.faults {
+            if let Some((flag, _)) = FlagBit::from_name(name) {
+                flag.set(&mut data);
+            }
+        }
+        // 偶发的短暂充电器故障，用于演示事件与告警
+        self.glitch = match self.glitch.take() {
+            Some((flag, frames)) if frames > 1 => Some((flag, frames - 1)),
+            Some(_) => None,
+            None if phase.mains && self.rng.next_f64() < GLITCH_PROBABILITY => {
+                let flags = [ChargerFaultFlags::FAULT_ACOV, ChargerFaultFlags::FAULT_ACOC, ChargerFaultFlags::FAULT_SYSOVP];
+                Some((flags[(self.rng.next_u64() % flags.len() as u64) as usize], GLITCH_FRAMES))
+            }
+            None => None,
+        };
+        if let Some((flag, _)) = self.glitch {
+            data.bq25730_alerts.charger_fault_flags |= flag;
+        }
+        data
+    }
+
+    /// 以下一帧的测量数据构造状态帧，用于经由线上编码的模拟（数值分辨率与真实设备一致）
+    pub fn sample_builder(&mut self) -> PayloadBuilder {
+        PayloadBuilder::from_measurements(self.sample())
+    }
+}
+
+/// 模拟模式的设置
+pub struct SimulationSettings {
+    pub scenario: Scenario,
+    pub interval: Duration,
+    pub seed: u64,
+    pub cell_count: usize,
+    pub frame_ids: Arc<FrameIdAllocator>,
+    pub shutdown: CancellationToken,
+}
+
+/// 代替设备注册表运行：按间隔生成测量数据，事件以 `simulated` 设备标识发给主循环。
+/// 订阅/取消订阅命令分别恢复与暂停生成
+pub async fn simulation_task(
+    settings: SimulationSettings,
+    mut cmd_rx: mpsc::Receiver<DeviceCommand>,
+    event_tx: mpsc::Sender<DeviceEvent>,
+) {
+    let device = DeviceId::Serial(SIMULATED_DEVICE.to_string());
+    let send = |event: UsbEvent| {
+        let event_tx = event_tx.clone();
+        let device = device.clone();
+        async move {
+            if let Err(e) = event_tx.send(DeviceEvent { device, event }).await {
+                error!("发送模拟事件失败: {:?}", e);
+            }
+        }
+    };
+    let usb_id = UsbId { vid: 0, pid: 0 };
+    send(UsbEvent::Connected { device_id: device.clone(), usb_id, protocol: ProtocolVersion::LEGACY }).await;
+    send(UsbEvent::DeviceInfo(DeviceInfo {
+        device_id: device.to_string(),
+        usb_id: usb_id.to_string(),
+        bus: 0,
+        address: 0,
+        manufacturer: Some("ups120-daemon".to_string()),
+        product: Some("UPS120 simulator".to_string()),
+        serial: Some(SIMULATED_DEVICE.to_string()),
+        protocol_version: ProtocolVersion::LEGACY.protocol,
+        firmware_version: None,
+    }))
+    .await;
+
+    let mut simulator = Simulator::new(settings.scenario, settings.interval, settings.cell_count, settings.seed);
+    let mut timer = tokio::time::interval(settings.interval);
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut running = true;
+    let mut phase = String::new();
+    loop {
+        tokio::select! {
+            command = cmd_rx.recv() => match command.map(|c| c.command) {
+                Some(UsbCommand::Unsubscribe) => {
+                    running = false;
+                    send(UsbEvent::Unsubscribed).await;
+                }
+                Some(UsbCommand::Subscribe | UsbCommand::Reconnect) => running = true,
+                Some(UsbCommand::SetInterval(interval_ms)) => debug!(interval_ms, "模拟模式忽略推送间隔设置"),
+                None => break,
+            },
+            _ = timer.tick(), if running && !settings.shutdown.is_cancelled() => {
+                if simulator.phase_name() != phase {
+                    phase = simulator.phase_name().to_string();
+                    info!(phase = %phase, "模拟场景进入新阶段");
+                }
+                // 经过一次线上编码与解析，模拟数据与真实设备同样按 mV / ADC 计数量化
+                let builder = simulator.sample_builder();
+                let data = builder.measurements();
+                let sample = TimestampedMeasurements {
+                    frame_id: settings.frame_ids.next_id(),
+                    ts_unix_ms: unix_ms_now(),
+                    derived: data.bq76920.derived(),
+                    data,
+                    battery: Default::default(),
+                    raw: Some(builder.frame().into()),
+                };
+                send(UsbEvent::Measurements(Arc::new(sample))).await;
+            }
+        }
+    }
+    send(UsbEvent::Detached).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENARIO: &str = r#"
+        [[phases]]
+        name = "charging"
+        duration_secs = 60
+        mains = true
+        current_a = 1.5
+
+        [[phases]]
+        name = "mains loss"
+        duration_secs = 30
+        mains = false
+        current_a = -2.0
+        faults = ["UV"]
+    "#;
+
+    fn scenario() -> Scenario {
+        toml::from_str(SCENARIO).unwrap()
+    }
+
+    fn load(text: &str) -> Result<Scenario, ConfigError> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scenario.toml");
+        std::fs::write(&path, text).unwrap();
+        Scenario::load(&path)
+    }
+
+    #[test]
+    fn the_same_seed_produces_the_same_samples() {
+        let run = |seed| {
+            let mut simulator = Simulator::new(scenario(), Duration::from_secs(1), 4, seed);
+            (0..200).map(|_| simulator.sample()).collect::<Vec<_>>()
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn the_scenario_is_followed_and_repeats() {
+        let mut simulator = Simulator::new(scenario(), Duration::from_secs(1), 4, 1);
+        for second in 0..180u64 {
+            let mains = second % 90 < 60;
+            assert_eq!(simulator.phase_name(), if mains { "charging" } else { "mains loss" }, "t={}", second);
+            let data = simulator.sample();
+            let status = data.bq25730_alerts.charger_status_flags;
+            assert_eq!(status.contains(ChargerStatusFlags::STAT_AC), mains, "t={}", second);
+            assert_eq!(FlagBit::from_name("UV").unwrap().0.is_set(&data), !mains, "t={}", second);
+            if mains {
+                assert!(data.bq25730.vbus > 19.0 && data.bq25730.ichg > 1.4);
+                assert_eq!(data.bq76920.mos_status, MosStatus::BothOn);
+            } else {
+                assert_eq!((data.bq25730.vbus, data.bq25730.ichg), (0.0, 0.0));
+                assert!(data.bq25730.idchg > 1.9);
+                assert_eq!(data.bq76920.mos_status, MosStatus::DischargeOn);
+            }
+        }
+    }
+
+    #[test]
+    fn samples_are_plausible() {
+        let mut simulator = Simulator::new(Scenario::default(), Duration::from_secs(1), 3, 42);
+        for _ in 0..3000 {
+            let data = simulator.sample();
+            assert_eq!(data.bq76920.cell_count, 3);
+            assert_eq!(&data.bq76920.cell_voltages[3..], &[0.0, 0.0]);
+            for cell in &data.bq76920.cell_voltages[..3] {
+                assert!((2.9..4.3).contains(cell), "cell {}", cell);
+            }
+            let sum: f32 = data.bq76920.cell_voltages.iter().sum();
+            assert!((data.bq25730.vbat - sum).abs() < 1e-4);
+            assert!(data.bq76920.temperatures.ts1.is_some_and(|t| (15.0..40.0).contains(&t)));
+        }
+    }
+
+    #[test]
+    fn the_battery_stops_at_full_and_empty() {
+        let phase = |mains, current_a| Phase {
+            name: String::new(),
+            duration_secs: 100_000,
+            mains,
+            current_a,
+            faults: Vec::new(),
+        };
+        let mut charging = Simulator::new(Scenario { phases: vec![phase(true, 5.0)] }, Duration::from_secs(60), 5, 1);
+        let mut discharging = Simulator::new(Scenario { phases: vec![phase(false, -5.0)] }, Duration::from_secs(60), 5, 1);
+        for _ in 0..100 {
+            charging.sample();
+            discharging.sample();
+        }
+        assert_eq!((charging.soc, discharging.soc), (1.0, 0.0));
+        assert_eq!(charging.sample().bq76920.coulomb_counter, 0.0);
+        assert_eq!(discharging.sample().bq76920.coulomb_counter, 0.0);
+    }
+
+    #[test]
+    fn scenario_files_are_validated() {
+        assert_eq!(load(SCENARIO).unwrap(), scenario());
+        assert!(load("phases = []").is_err());
+        assert!(load("[[phases]]\nduration_secs = 0\nmains = true\ncurrent_a = 1.0").is_err());
+        assert!(load("[[phases]]\nduration_secs = 10\nmains = true\ncurrent_a = 1.0\nfaults = [\"NOPE\"]").is_err());
+        assert!(load("[[phases]]\nduration_secs = 10\nmains = true\ncurrent_a = 1.0\nvoltage = 3").is_err());
+    }
+
+    #[tokio::test]
+    async fn the_task_emits_device_events_and_honours_subscriptions() {
+        let (cmd_tx, cmd_rx) = mpsc::channel(4);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        let settings = SimulationSettings {
+            scenario: scenario(),
+            interval: Duration::from_millis(10),
+            seed: 3,
+            cell_count: 4,
+            frame_ids: Arc::new(FrameIdAllocator::in_memory()),
+            shutdown: CancellationToken::new(),
+        };
+        let task = tokio::spawn(simulation_task(settings, cmd_rx, event_tx));
+        let mut next = async || event_rx.recv().await.unwrap();
+        let event = next().await;
+        assert_eq!(event.device, DeviceId::Serial(SIMULATED_DEVICE.to_string()));
+        assert!(matches!(event.event, UsbEvent::Connected { .. }));
+        assert!(matches!(next().await.event, UsbEvent::DeviceInfo(info) if info.serial.as_deref() == Some(SIMULATED_DEVICE)));
+        let mut frame_ids = Vec::new();
+        for _ in 0..3 {
+            let UsbEvent::Measurements(sample) = next().await.event else {
+                panic!("应为测量数据");
+            };
+            assert_eq!(sample.data.bq76920.cell_count, 4);
+            assert!(sample.raw.is_some());
+            frame_ids.push(sample.frame_id);
+        }
+        assert!(frame_ids.windows(2).all(|w| w[1] > w[0]));
+
+        // 取消订阅后暂停生成，直到重新订阅
+        cmd_tx.send(DeviceCommand { target: None, command: UsbCommand::Unsubscribe }).await.unwrap();
+        while !matches!(next().await.event, UsbEvent::Unsubscribed) {}
+        assert!(tokio::time::timeout(Duration::from_millis(100), next()).await.is_err());
+        cmd_tx.send(DeviceCommand { target: None, command: UsbCommand::Subscribe }).await.unwrap();
+        assert!(matches!(next().await.event, UsbEvent::Measurements(_)));
+
+        drop(cmd_tx);
+        loop {
+            if matches!(next().await.event, UsbEvent::Detached) {
+                break;
+            }
+        }
+        task.await.unwrap();
+    }
+}
@@ -0,0 +1,193 @@
+//! 电量 (SOC) 估算。
+//!
+//! 按平均单体电压查表得到 SOC，再按电流方向做滞回：放电时只降不升、充电时只升不降，
+//! 静置时变化超过滞回量才更新，避免负载下几毫伏的电压抖动让电量来回跳动。
+
+/// 默认的单体电压 (V) -> SOC (%) 表，适用于常见的三元锂电芯（5S 电池包按单体平均电压查表）
+pub const DEFAULT_SOC_TABLE: &[(f32, f32)] = &[
+    (3.00, 0.0),
+    (3.30, 5.0),
+    (3.45, 10.0),
+    (3.55, 20.0),
+    (3.62, 30.0),
+    (3.68, 40.0),
+    (3.74, 50.0),
+    (3.80, 60.0),
+    (3.87, 70.0),
+    (3.95, 80.0),
+    (4.05, 90.0),
+    (4.20, 100.0),
+];
+
+/// 单体电压到 SOC 的分段线性表，电压严格递增，SOC 不递减
+#[derive(Debug, Clone, PartialEq)]
+pub struct SocTable {
+    points: Vec<(f32, f32)>,
+}
+
+impl Default for SocTable {
+    fn default() -> Self {
+        SocTable {
+            points: DEFAULT_SOC_TABLE.to_vec(),
+        }
+    }
+}
+
+impl SocTable {
+    pub fn new(points: Vec<(f32, f32)>) -> Result<Self, String> {
+        if points.len() < 2 {
+            return Err("at least two points are required".to_string());
+        }
+        for &(voltage, soc) in &points {
+            if !voltage.is_finite() || voltage <= 0.0 {
+                return Err(format!("invalid cell voltage {}", voltage));
+            }
+            if !(0.0..=100.0).contains(&soc) {
+                return Err(format!("SOC {} is outside 0-100", soc));
+            }
+        }
+        for pair in points.windows(2) {
+            if pair[1].0 <= pair[0].0 {
+                return Err("cell voltages must be strictly increasing".to_string());
+            }
+            if pair[1].1 < pair[0].1 {
+                return Err("SOC must not decrease as voltage increases".to_string());
+            }
+        }
+        Ok(SocTable { points })
+    }
+
+    /// 查表并线性插值，超出表范围时取端点值
+    pub fn lookup(&self, cell_voltage: f32) -> f32 {
+        let (first, last) = (self.points[0], self.points[self.points.len() - 1]);
+        if cell_voltage <= first.0 {
+            return first.1;
+        }
+        if cell_voltage >= last.0 {
+            return last.1;
+        }
+        for pair in self.points.windows(2) {
+            let ((v0, s0), (v1, s1)) = (pair[0], pair[1]);
+            if cell_voltage <= v1 {
+                return s0 + (s1 - s0) * (cell_voltage - v0) / (v1 - v0);
+            }
+        }
+        last.1
+    }
+}
+
+/// 带滞回的 SOC 估算器，每块设备一个
+#[derive(Debug, Clone)]
+pub struct SocEstimator {
+    table: SocTable,
+    /// 静置时 SOC 变化超过该值 (%) 才更新
+    hysteresis_pct: f32,
+    /// 电流绝对值低于该值 (A) 视为静置
+    idle_current_a: f32,
+    soc: Option<f32>,
+}
+
+impl SocEstimator {
+    pub fn new(table: SocTable, hysteresis_pct: f32, idle_current_a: f32) -> Self {
+        SocEstimator {
+            table,
+            hysteresis_pct,
+            idle_current_a,
+            soc: None,
+        }
+    }
+
+    pub fn soc(&self) -> Option<f32> {
+        self.soc
+    }
+
+    /// 喂入一帧。`cell_voltages` 为实际串联的各节电压 (V)，读数为 0 的通道忽略；
+    /// `current_a` 为充电为正的电池电流，没有电流读数时为 None（按静置处理）
+    pub fn update(&mut self, cell_voltages: &[f32], current_a: Option<f32>) -> Option<f32> {
+        let valid: Vec<f32> = cell_voltages.iter().copied().filter(|v| *v > 0.0).collect();
+        if valid.is_empty() {
+            return self.soc;
+        }
+        let average = valid.iter().sum::<f32>() / valid.len() as f32;
+        let candidate = self.table.lookup(average);
+
+        let next = match (self.soc, current_a) {
+            (None, _) => candidate,
+            (Some(previous), Some(current)) if current > self.idle_current_a => previous.max(candidate),
+            (Some(previous), Some(current)) if current < -self.idle_current_a => previous.min(candidate),
+            (Some(previous), _) if (candidate - previous).abs() >= self.hysteresis_pct => candidate,
+            (Some(previous), _) => previous,
+        };
+        let next = (next * 10.0).round() / 10.0;
+        self.soc = Some(next);
+        self.soc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimator() -> SocEstimator {
+        SocEstimator::new(SocTable::default(), 2.0, 0.05)
+    }
+
+    // 4.10 V 线性放电到 3.40 V，叠加 ±8 mV 的负载抖动
+    fn discharge_curve() -> impl Iterator<Item = f32> {
+        (0..500).map(|i| 4.10 - 0.70 * i as f32 / 499.0 + if i % 2 == 0 { 0.008 } else { -0.008 })
+    }
+
+    #[test]
+    fn lookup_interpolates_and_clamps_to_the_table_ends() {
+        let table = SocTable::default();
+        assert_eq!(table.lookup(2.5), 0.0);
+        assert_eq!(table.lookup(4.35), 100.0);
+        assert_eq!(table.lookup(3.74), 50.0);
+        assert!((table.lookup(3.77) - 55.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn invalid_tables_are_rejected() {
+        assert!(SocTable::new(vec![(3.0, 0.0)]).is_err());
+        assert!(SocTable::new(vec![(3.0, 0.0), (3.0, 50.0)]).is_err());
+        assert!(SocTable::new(vec![(3.0, 50.0), (4.0, 20.0)]).is_err());
+        assert!(SocTable::new(vec![(3.0, 0.0), (4.0, 120.0)]).is_err());
+        assert!(SocTable::new(vec![(-1.0, 0.0), (4.0, 100.0)]).is_err());
+        assert!(SocTable::new(vec![(3.0, 0.0), (4.2, 100.0)]).is_ok());
+    }
+
+    #[test]
+    fn soc_never_rises_while_discharging_despite_voltage_jitter() {
+        let mut soc = estimator();
+        let readings: Vec<f32> =
+            discharge_curve().map(|v| soc.update(&[v; 5], Some(-1.5)).unwrap()).collect();
+        assert!(readings.windows(2).all(|pair| pair[1] <= pair[0]), "放电时 SOC 回升");
+        assert!(readings[0] > 85.0 && *readings.last().unwrap() < 10.0);
+    }
+
+    #[test]
+    fn soc_never_falls_while_charging() {
+        let mut soc = estimator();
+        let curve: Vec<f32> = discharge_curve().collect();
+        let readings: Vec<f32> = curve.iter().rev().map(|v| soc.update(&[*v; 5], Some(2.0)).unwrap()).collect();
+        assert!(readings.windows(2).all(|pair| pair[1] >= pair[0]), "充电时 SOC 下降");
+    }
+
+    #[test]
+    fn idle_updates_need_to_exceed_the_hysteresis() {
+        let mut soc = estimator();
+        assert_eq!(soc.update(&[3.74; 5], None), Some(50.0));
+        // 3.75 V 约 51.7%，变化不足 2% 不更新
+        assert_eq!(soc.update(&[3.75; 5], Some(0.01)), Some(50.0));
+        assert_eq!(soc.update(&[3.73; 5], None), Some(50.0));
+        assert_eq!(soc.update(&[3.77; 5], None), Some(55.0));
+    }
+
+    #[test]
+    fn unconnected_cells_are_ignored() {
+        let mut soc = estimator();
+        assert_eq!(soc.update(&[0.0; 5], None), None);
+        assert_eq!(soc.update(&[3.74, 3.74, 3.74, 0.0, 0.0], None), Some(50.0));
+        assert_eq!(soc.update(&[], Some(-1.0)), Some(50.0));
+    }
+}
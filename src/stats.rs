@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::config::PRIMARY_BROKER;
+use crate::platform;
+
+/// 守护进程自身的运行统计，各任务共享 (Arc) 并直接更新计数器
+#[derive(Debug, Default)]
+pub struct DaemonStats {
+    started: StartTime,
+    /// 收到的测量数据帧数（所有设备）
+    pub measurements_received: AtomicU64,
+    /// 向主 broker 发布样本的次数与其中失败的次数
+    pub publishes_attempted: AtomicU64,
+    pub publishes_failed: AtomicU64,
+    /// 主循环发布时发送队列已满或事件循环已停止而丢弃的消息数（状态、事件、心跳等，不含缓冲的样本）
+    pub publishes_dropped: AtomicU64,
+    /// 首次连接之后的 USB 重连次数（所有设备）
+    pub usb_reconnects: AtomicU64,
+    /// 推送数据解析失败的次数（长度不符、不完整、binrw 解析失败等）
+    pub usb_parse_errors: AtomicU64,
+    /// 主 broker 事件循环当前的重试等待 (ms)，连接正常时为 0
+    pub mqtt_backoff_ms: AtomicU64,
+    /// 主循环尚未处理的设备事件数
+    pub usb_event_queue_depth: AtomicU64,
+    /// 连续第二次及之后发布失败的心跳数
+    pub heartbeat_failures: AtomicU64,
+    /// 当前生效的发布间隔 (ms)
+    pub publish_interval_ms: AtomicU64,
+    /// 最近一次 PubAck 往返时间 (ms)
+    pub mqtt_rtt_ms: AtomicU64,
+    /// 已发出但尚未确认的 QoS1 消息数
+    pub mqtt_inflight: AtomicU64,
+    /// broker 不可达时缓冲的样本数
+    pub buffer_depth: AtomicU64,
+    /// 缓冲区溢出丢弃的样本数
+    pub buffer_dropped: AtomicU64,
+    /// 固件违反载荷约定的次数 (如 present=0 却带有非零原始值)
+    pub contract_violations: AtomicU64,
+    /// 超出合理范围的样本数（无论是否丢弃）
+    pub implausible_samples: AtomicU64,
+    /// 最近一次 ACL 探测中发布被拒绝的主题类别数，非零即需检查 broker ACL
+    pub acl_denied_classes: AtomicU64,
+    /// JSONL 数据记录累计写入的字节数
+    pub data_log_bytes_written: AtomicU64,
+    /// 最近一条数据记录的写入耗时 (µs，含序列化与落盘)
+    pub data_log_write_latency_us: AtomicU64,
+    /// 数据记录写入失败次数
+    pub data_log_errors: AtomicU64,
+    /// 主 broker (MQTT_BROKER_HOST) 的连接状态
+    pub primary: BrokerStats,
+    /// 各镜像 broker 的连接状态，按名称索引
+    pub mirrors: Mutex<BTreeMap<String, Arc<BrokerStats>>>,
+}
+
+/// 统计开始的时间，用于计算运行时长
+#[derive(Debug)]
+struct StartTime(Instant);
+
+impl Default for StartTime {
+    fn default() -> Self {
+        StartTime(Instant::now())
+    }
+}
+
+/// 单个 broker 的连接与发布统计
+#[derive(Debug, Default)]
+pub struct BrokerStats {
+    pub connected: AtomicBool,
+    /// 成功发布的样本数
+    pub published: AtomicU64,
+    pub buffer_depth: AtomicU64,
+    pub buffer_dropped: AtomicU64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BrokerStatsSnapshot {
+    pub connected: bool,
+    pub published: u64,
+    pub buffer_depth: u64,
+    pub buffer_dropped: u64,
+}
+
+impl BrokerStats {
+    pub fn snapshot(&self) -> BrokerStatsSnapshot {
+        BrokerStatsSnapshot {
+            connected: self.connected.load(Ordering::Relaxed),
+            published: self.published.load(Ordering::Relaxed),
+            buffer_depth: self.buffer_depth.load(Ordering::Relaxed),
+            buffer_dropped: self.buffer_dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DaemonStatsSnapshot {
+    pub uptime_secs: u64,
+    /// 常驻内存 (字节)；无法读取的平台为 null
+    pub rss_bytes: Option<u64>,
+    pub measurements_received: u64,
+    pub publishes_attempted: u64,
+    pub publishes_failed: u64,
+    pub publishes_dropped: u64,
+    pub usb_reconnects: u64,
+    pub usb_parse_errors: u64,
+    pub mqtt_backoff_ms: u64,
+    pub usb_event_queue_depth: u64,
+    pub heartbeat_failures: u64,
+    pub publish_interval_ms: u64,
+    pub mqtt_rtt_ms: u64,
+    pub mqtt_inflight: u64,
+    pub buffer_depth: u64,
+    pub buffer_dropped: u64,
+    pub contract_violations: u64,
+    pub implausible_samples: u64,
+    pub acl_denied_classes: u64,
+    pub data_log_bytes_written: u64,
+    pub data_log_write_latency_us: u64,
+    pub data_log_errors: u64,
+    /// 按 broker 名称列出的连接状态，主 broker 为 `primary`
+    pub brokers: BTreeMap<String, BrokerStatsSnapshot>,
+}
+
+impl DaemonStats {
+    /// 注册镜像 broker 并返回其统计计数器
+    pub fn register_mirror(&self, name: &str) -> Arc<BrokerStats> {
+        self.mirrors.lock().unwrap().entry(name.to_string()).or_default().clone()
+    }
+
+    pub fn snapshot(&self) -> DaemonStatsSnapshot {
+        DaemonStatsSnapshot {
+            uptime_secs: self.started.0.elapsed().as_secs(),
+            rss_bytes: platform::resident_memory_bytes(),
+            measurements_received: self.measurements_received.load(Ordering::Relaxed),
+            publishes_attempted: self.publishes_attempted.load(Ordering::Relaxed),
+            publishes_failed: self.publishes_failed.load(Ordering::Relaxed),
+            publishes_dropped: self.publishes_dropped.load(Ordering::Relaxed),
+            usb_reconnects: self.usb_reconnects.load(Ordering::Relaxed),
+            usb_parse_errors: self.usb_parse_errors.load(Ordering::Relaxed),
+            mqtt_backoff_ms: self.mqtt_backoff_ms.load(Ordering::Relaxed),
+            usb_event_queue_depth: self.usb_event_queue_depth.load(Ordering::Relaxed),
+            heartbeat_failures: self.heartbeat_failures.load(Ordering::Relaxed),
+            publish_interval_ms: self.publish_interval_ms.load(Ordering::Relaxed),
+            mqtt_rtt_ms: self.mqtt_rtt_ms.load(Ordering::Relaxed),
+            mqtt_inflight: self.mqtt_inflight.load(Ordering::Relaxed),
+            buffer_depth: self.buffer_depth.load(Ordering::Relaxed),
+            buffer_dropped: self.buffer_dropped.load(Ordering::Relaxed),
+            contract_violations: self.contract_violations.load(Ordering::Relaxed),
+            implausible_samples: self.implausible_samples.load(Ordering::Relaxed),
+            acl_denied_classes: self.acl_denied_classes.load(Ordering::Relaxed),
+            data_log_bytes_written: self.data_log_bytes_written.load(Ordering::Relaxed),
+            data_log_write_latency_us: self.data_log_write_latency_us.load(Ordering::Relaxed),
+            data_log_errors: self.data_log_errors.load(Ordering::Relaxed),
+            brokers: {
+                let mut brokers: BTreeMap<String, BrokerStatsSnapshot> = self
+                    .mirrors
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(name, stats)| (name.clone(), stats.snapshot()))
+                    .collect();
+                brokers.insert(PRIMARY_BROKER.to_string(), self.primary.snapshot());
+                brokers
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn the_snapshot_serializes_every_counter() {
+        let stats = DaemonStats::default();
+        stats.measurements_received.fetch_add(42, Ordering::Relaxed);
+        stats.publishes_attempted.fetch_add(40, Ordering::Relaxed);
+        stats.publishes_failed.fetch_add(1, Ordering::Relaxed);
+        stats.usb_reconnects.fetch_add(2, Ordering::Relaxed);
+        stats.usb_parse_errors.fetch_add(3, Ordering::Relaxed);
+        stats.mqtt_backoff_ms.store(4000, Ordering::Relaxed);
+        stats.usb_event_queue_depth.store(5, Ordering::Relaxed);
+        stats.primary.connected.store(true, Ordering::Relaxed);
+        stats.primary.published.fetch_add(39, Ordering::Relaxed);
+
+        let json = serde_json::to_value(stats.snapshot()).unwrap();
+        let Value::Object(fields) = &json else {
+            panic!("应为 JSON 对象");
+        };
+        let mut keys: Vec<&str> = fields.keys().map(String::as_str).collect();
+        let mut expected = [
+            "uptime_secs",
+            "rss_bytes",
+            "measurements_received",
+            "publishes_attempted",
+            "publishes_failed",
+            "publishes_dropped",
+            "usb_reconnects",
+            "usb_parse_errors",
+            "mqtt_backoff_ms",
+            "usb_event_queue_depth",
+            "heartbeat_failures",
+            "publish_interval_ms",
+            "mqtt_rtt_ms",
+            "mqtt_inflight",
+            "buffer_depth",
+            "buffer_dropped",
+            "contract_violations",
+            "implausible_samples",
+            "acl_denied_classes",
+            "data_log_bytes_written",
+            "data_log_write_latency_us",
+            "data_log_errors",
+            "brokers",
+        ];
+        keys.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(keys, expected);
+        assert_eq!(json["uptime_secs"], 0);
+        for (key, value) in [
+            ("measurements_received", 42),
+            ("publishes_attempted", 40),
+            ("publishes_failed", 1),
+            ("publishes_dropped", 0),
+            ("usb_reconnects", 2),
+            ("usb_parse_errors", 3),
+            ("mqtt_backoff_ms", 4000),
+            ("usb_event_queue_depth", 5),
+            ("heartbeat_failures", 0),
+        ] {
+            assert_eq!(json[key], value, "{}", key);
+        }
+        assert_eq!(
+            json["brokers"],
+            serde_json::json!({ PRIMARY_BROKER: { "connected": true, "published": 39, "buffer_depth": 0, "buffer_dropped": 0 } })
+        );
+    }
+
+    #[test]
+    fn mirrors_are_listed_next_to_the_primary_broker() {
+        let stats = DaemonStats::default();
+        let backup = stats.register_mirror("backup");
+        backup.buffer_depth.store(7, Ordering::Relaxed);
+        // 再次注册得到同一组计数器
+        assert!(Arc::ptr_eq(&backup, &stats.register_mirror("backup")));
+        let brokers = stats.snapshot().brokers;
+        assert_eq!(brokers.keys().map(String::as_str).collect::<Vec<_>>(), ["backup", PRIMARY_BROKER]);
+        assert_eq!(brokers["backup"].buffer_depth, 7);
+        assert!(!brokers[PRIMARY_BROKER].connected);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn resident_memory_is_read_on_linux() {
+        assert!(DaemonStats::default().snapshot().rss_bytes.is_some_and(|bytes| bytes > 0));
+    }
+}
@@ -0,0 +1,185 @@
+use serde::Serialize;
+
+use crate::diagnostics::FrameAnomaly;
+use crate::usb_types::UsbError;
+
+/// 严格模式下会导致进程退出的条件（生产模式下这些条件只记录日志）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StrictCondition {
+    ParseError,
+    ReservedBits,
+    CrossCheck,
+    MqttUnreachable,
+    NoFrame,
+    SensorFault,
+    ContractViolation,
+}
+
+/// 条件 → 退出码的唯一映射表
+pub const EXIT_CODES: &[(StrictCondition, i32)] = &[
+    (StrictCondition::ParseError, 10),
+    (StrictCondition::ReservedBits, 11),
+    (StrictCondition::CrossCheck, 12),
+    (StrictCondition::MqttUnreachable, 13),
+    (StrictCondition::NoFrame, 14),
+    (StrictCondition::SensorFault, 15),
+    (StrictCondition::ContractViolation, 16),
+];
+
+impl StrictCondition {
+    pub fn exit_code(self) -> i32 {
+        EXIT_CODES
+            .iter()
+            .find(|(condition, _)| *condition == self)
+            .map(|(_, code)| *code)
+            .expect("every StrictCondition has an exit code")
+    }
+
+    /// 数据帧异常对应的条件
+    pub fn for_anomaly(anomaly: &FrameAnomaly) -> Self {
+        match anomaly {
+            FrameAnomaly::ReservedBits { .. } => StrictCondition::ReservedBits,
+            FrameAnomaly::CrossCheck { .. } => StrictCondition::CrossCheck,
+            FrameAnomaly::SensorFault { .. } => StrictCondition::SensorFault,
+            FrameAnomaly::ContractViolation { .. } => StrictCondition::ContractViolation,
+        }
+    }
+
+    /// 设备报告的错误对应的条件；只有解析错误在严格模式下致命，其余交给重连处理
+    pub fn for_usb_error(error: &UsbError) -> Option<Self> {
+        error.is_parse_error().then_some(StrictCondition::ParseError)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Observation {
+    pub condition: StrictCondition,
+    pub detail: String,
+}
+
+/// 严格模式运行期间观察到的所有情况，退出时以单行 JSON 输出到 stdout
+#[derive(Debug, Default, Serialize)]
+pub struct StrictReport {
+    pub frames: u64,
+    pub observations: Vec<Observation>,
+    pub fatal: Option<StrictCondition>,
+    pub exit_code: i32,
+}
+
+impl StrictReport {
+    pub fn record_frame(&mut self) {
+        self.frames += 1;
+    }
+
+    /// 记录一个致命条件，返回对应的退出码
+    pub fn fail(&mut self, condition: StrictCondition, detail: impl Into<String>) -> i32 {
+        self.observations.push(Observation {
+            condition,
+            detail: detail.into(),
+        });
+        if self.fatal.is_none() {
+            self.fatal = Some(condition);
+            self.exit_code = condition.exit_code();
+        }
+        self.exit_code
+    }
+
+    pub fn summary_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|e| format!("{{\"error\":\"{}\"}}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::data_models::HostSideUsbPayload;
+    use crate::test_support::{push_events, PayloadBuilder};
+    use crate::usb_types::{ProtocolVersion, UsbEvent};
+
+    const ALL: [StrictCondition; 7] = [
+        StrictCondition::ParseError,
+        StrictCondition::ReservedBits,
+        StrictCondition::CrossCheck,
+        StrictCondition::MqttUnreachable,
+        StrictCondition::NoFrame,
+        StrictCondition::SensorFault,
+        StrictCondition::ContractViolation,
+    ];
+
+    // 按主循环的规则，把一帧推送数据产生的事件映射为严格模式条件
+    async fn conditions_for(frame: Vec<u8>) -> Vec<StrictCondition> {
+        push_events(&[frame], ProtocolVersion::LEGACY)
+            .await
+            .iter()
+            .filter_map(|event| match event {
+                UsbEvent::Anomaly { anomaly, .. } => Some(StrictCondition::for_anomaly(anomaly)),
+                UsbEvent::Error(error) => StrictCondition::for_usb_error(error),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_condition_has_its_own_nonzero_exit_code() {
+        assert_eq!(EXIT_CODES.len(), ALL.len());
+        let codes: HashSet<i32> = ALL.iter().map(|c| c.exit_code()).collect();
+        assert_eq!(codes.len(), ALL.len());
+        assert!(!codes.contains(&0) && !codes.contains(&1));
+    }
+
+    #[tokio::test]
+    async fn each_frame_condition_is_detected_and_maps_to_its_exit_code() {
+        let v1 = PayloadBuilder::new().without_ina226();
+        let modified = |modify: fn(&mut HostSideUsbPayload)| {
+            let mut payload = v1.payload();
+            modify(&mut payload);
+            v1.frame_from(payload)
+        };
+
+        let cases = [
+            // 未知的 magic
+            (vec![0x55, 0x01, 0x02, 0x03], StrictCondition::ParseError, 10),
+            (modified(|p| p.bq76920_mos_status_bits |= 0x04), StrictCondition::ReservedBits, 11),
+            (modified(|p| p.bq76920_alerts_system_status_bits ^= 0x01), StrictCondition::CrossCheck, 12),
+            (modified(|p| p.bq76920_ts1_raw_adc = 0), StrictCondition::SensorFault, 15),
+            (
+                modified(|p| {
+                    p.bq76920_ts2_present = 0;
+                    p.bq76920_ts2_raw_adc = 0x123;
+                }),
+                StrictCondition::ContractViolation,
+                16,
+            ),
+        ];
+        for (frame, expected, code) in cases {
+            assert_eq!(conditions_for(frame).await, [expected]);
+            let mut report = StrictReport::default();
+            assert_eq!(report.fail(expected, "test"), code);
+        }
+        // 正常的帧不产生任何条件
+        assert!(conditions_for(v1.frame()).await.is_empty());
+    }
+
+    #[test]
+    fn the_first_fatal_condition_decides_the_exit_code() {
+        let mut report = StrictReport::default();
+        report.record_frame();
+        assert_eq!(report.fail(StrictCondition::ReservedBits, "frame 1"), 11);
+        assert_eq!(report.fail(StrictCondition::NoFrame, "no frame within 5s"), 11);
+        let summary: serde_json::Value = serde_json::from_str(&report.summary_json()).unwrap();
+        assert_eq!(summary["frames"], 1);
+        assert_eq!(summary["fatal"], "reserved_bits");
+        assert_eq!(summary["exit_code"], 11);
+        assert_eq!(summary["observations"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn only_parse_errors_are_fatal_usb_errors() {
+        assert_eq!(StrictCondition::for_usb_error(&UsbError::UnexpectedResponse), Some(StrictCondition::ParseError));
+        assert_eq!(StrictCondition::for_usb_error(&UsbError::Timeout), None);
+        assert_eq!(StrictCondition::for_usb_error(&UsbError::IncompletePayload { got: 64, expected: 80 }), None);
+    }
+}
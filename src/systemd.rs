@@ -0,0 +1,123 @@
+//! systemd 集成 (`Type=notify`)：就绪通知、看门狗与状态文本。
+//!
+//! 未设置 `NOTIFY_SOCKET`（不在 systemd 下运行）时所有通知都是空操作。看门狗只在 MQTT 事件循环
+//! 与 USB 管理任务都有心跳时才喂狗，任何一方卡死都会让 systemd 按 `WatchdogSec` 重启进程。
+
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{debug, info};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// 任务的心跳：任务在确实取得进展时（如阻塞读取返回）调用 `beat`，监视方检查距上次心跳的时间
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    tx: Arc<watch::Sender<Instant>>,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        let (tx, _) = watch::channel(Instant::now());
+        Heartbeat { tx: Arc::new(tx) }
+    }
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        self.tx.send_replace(Instant::now());
+    }
+
+    /// 距上次心跳的时间
+    pub fn age(&self) -> Duration {
+        self.tx.borrow().elapsed()
+    }
+}
+
+/// 向 systemd 发送状态通知
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    socket: Option<String>,
+    /// `WatchdogSec` 的一半；未启用看门狗时为 None
+    watchdog_interval: Option<Duration>,
+    status: Option<String>,
+}
+
+impl Notifier {
+    /// 读取 `NOTIFY_SOCKET` 与 `WATCHDOG_USEC`（`WATCHDOG_PID` 不是本进程时忽略看门狗）
+    pub fn from_env() -> Self {
+        let Some(socket) = env::var("NOTIFY_SOCKET").ok().filter(|s| !s.is_empty()) else {
+            return Notifier::default();
+        };
+        let for_us = env::var("WATCHDOG_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_none_or(|pid| pid == std::process::id());
+        let watchdog_interval = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|usec| *usec > 0 && for_us)
+            .map(|usec| Duration::from_micros(usec / 2));
+        info!("systemd 通知已启用 ({})，看门狗间隔 {:?}", socket, watchdog_interval);
+        Notifier {
+            socket: Some(socket),
+            watchdog_interval,
+            status: None,
+        }
+    }
+
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog_interval
+    }
+
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    pub fn watchdog(&self) {
+        self.notify("WATCHDOG=1");
+    }
+
+    /// 更新 `systemctl status` 中显示的状态文本；与上次相同时不发送
+    pub fn status(&mut self, status: &str) {
+        if self.socket.is_none() || self.status.as_deref() == Some(status) {
+            return;
+        }
+        self.status = Some(status.to_string());
+        self.notify(&format!("STATUS={}", status));
+    }
+
+    fn notify(&self, state: &str) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        if let Err(e) = send(socket, state) {
+            debug!("发送 systemd 通知 {} 失败: {:?}", state, e);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn send(socket: &str, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let datagram = UnixDatagram::unbound()?;
+    // `@` 开头为 Linux 抽象命名空间地址
+    #[cfg(target_os = "linux")]
+    if let Some(name) = socket.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        return datagram.send_to_addr(state.as_bytes(), &addr).map(|_| ());
+    }
+    datagram.send_to(state.as_bytes(), socket).map(|_| ())
+}
+
+#[cfg(not(unix))]
+fn send(_socket: &str, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
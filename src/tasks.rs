@@ -0,0 +1,258 @@
+//! 后台任务的集合：退出时统一等待所有任务结束，超时后中止仍在运行的任务。
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::task::{Id, JoinSet};
+use tracing::{debug, error};
+
+/// 带名称的 `JoinSet`，名称用于退出超时时报告哪些任务没有结束
+#[derive(Default)]
+pub struct TaskSet {
+    set: JoinSet<()>,
+    names: HashMap<Id, &'static str>,
+}
+
+impl TaskSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn spawn<F>(&mut self, name: &'static str, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let handle = self.set.spawn(task);
+        self.names.insert(handle.id(), name);
+    }
+
+    pub fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+
+    /// 回收已经结束的任务，不等待仍在运行的任务。任务随设备路由反复启动时调用，避免结果堆积
+    pub fn reap(&mut self) {
+        while let Some(result) = self.set.try_join_next_with_id() {
+            let id = match &result {
+                Ok((id, ())) => *id,
+                Err(e) => e.id(),
+            };
+            let name = self.names.remove(&id).unwrap_or("?");
+            if let Err(e) = result
+                && !e.is_cancelled()
+            {
+                error!(task = name, error = %e, "任务异常结束");
+            }
+        }
+    }
+
+    /// 等待所有任务结束；超过 `timeout` 时中止剩余任务，返回它们的名称
+    pub async fn shutdown(mut self, timeout: Duration) -> Vec<&'static str> {
+        let names = &mut self.names;
+        let set = &mut self.set;
+        let joined = tokio::time::timeout(timeout, async {
+            while let Some(result) = set.join_next_with_id().await {
+                let (id, outcome) = match result {
+                    Ok((id, ())) => (id, Ok(())),
+                    Err(e) => (e.id(), Err(e)),
+                };
+                let name = names.remove(&id).unwrap_or("?");
+                match outcome {
+                    Ok(()) => debug!(task = name, "任务已结束"),
+                    Err(e) if e.is_cancelled() => debug!(task = name, "任务已中止"),
+                    Err(e) => error!(task = name, error = %e, "任务异常结束"),
+                }
+            }
+        })
+        .await;
+        if joined.is_ok() {
+            return Vec::new();
+        }
+        self.set.abort_all();
+        let mut unfinished: Vec<&'static str> = self.names.into_values().collect();
+        unfinished.sort_unstable();
+        unfinished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reap_releases_finished_tasks_only() {
+        let mut tasks = TaskSet::new();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        tasks.spawn("finished", async {});
+        tasks.spawn("running", async {
+            let _ = rx.await;
+        });
+        tokio::task::yield_now().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        tasks.reap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks.names.values().copied().collect::<Vec<_>>(), vec!["running"]);
+        drop(tx);
+        assert!(tasks.shutdown(Duration::from_secs(1)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_reports_tasks_that_do_not_finish() {
+        let mut tasks = TaskSet::new();
+        tasks.spawn("stuck", std::future::pending());
+        assert_eq!(tasks.shutdown(Duration::from_millis(10)).await, vec!["stuck"]);
+    }
+
+    // 与 main 的退出顺序相同：取消、设备取消订阅、发布离线状态、停止事件循环，再等待所有任务
+    #[cfg(feature = "mqtt")]
+    #[tokio::test]
+    async fn daemon_tasks_finish_within_the_shutdown_timeout() {
+        use std::net::SocketAddr;
+        use std::sync::Arc;
+
+        use tokio::sync::{mpsc, watch};
+        use tokio_util::sync::CancellationToken;
+
+        use crate::acks::{publish_and_confirm, AckTracker};
+        use crate::client::Ups120Client;
+        use crate::config::DaemonConfig;
+        use crate::csv_logger::{csv_log_task, CsvLogSettings, CsvLogger};
+        use crate::frame_id::FrameIdAllocator;
+        use crate::health::{DaemonState, HealthRules};
+        use crate::http_api::{http_api_task, ApiState};
+        use crate::mqtt_handlers::{connect_mqtt_and_publish, daemon_availability_topic, ConnectHooks, MqttEvent};
+        use crate::nut_server::{nut_server_task, NutSettings, NutSource};
+        use crate::pipeline::Pipeline;
+        use crate::simulate::{simulation_task, Scenario, SimulationSettings};
+        use crate::stats::DaemonStats;
+        use crate::systemd::Heartbeat;
+        use crate::test_broker::TestBroker;
+        use crate::timing::Policy;
+        use crate::usb_types::{DeviceCommand, DeviceEvent, UsbCommand, UsbEvent};
+
+        fn free_addr() -> SocketAddr {
+            std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+        }
+
+        let broker = TestBroker::start().await;
+        let port = broker.port.to_string();
+        let config = DaemonConfig::from_vars(&[("MQTT_BROKER_HOST", "127.0.0.1"), ("MQTT_BROKER_PORT", &port)]).unwrap();
+        let shutdown = CancellationToken::new();
+        let mut tasks = TaskSet::new();
+
+        let (command_tx, _command_rx) = mpsc::channel(4);
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let session = connect_mqtt_and_publish(
+            &config,
+            command_tx,
+            events_tx,
+            Arc::new(DaemonStats::default()),
+            Arc::new(AckTracker::default()),
+            ConnectHooks::default(),
+            Heartbeat::default(),
+        )
+        .await
+        .unwrap();
+        let event_loop = session.event_loop;
+        tasks.spawn("mqtt_event_loop", async move {
+            event_loop.await.unwrap();
+        });
+
+        let settings = SimulationSettings {
+            scenario: Scenario::default(),
+            interval: Duration::from_millis(20),
+            seed: 0,
+            cell_count: 4,
+            frame_ids: Arc::new(FrameIdAllocator::in_memory()),
+            shutdown: shutdown.clone(),
+        };
+        let client = Ups120Client::from_task(|cmd_rx, event_tx| simulation_task(settings, cmd_rx, event_tx));
+        let (usb_cmd_tx, mut usb_event_rx, usb_task) = client.into_parts();
+        tasks.spawn("usb", async move {
+            usb_task.await.unwrap();
+        });
+
+        let pipeline = Pipeline::new(16);
+        let (latest_tx, latest_rx) = watch::channel(None);
+        let (_device_tx, device_rx) = watch::channel(None);
+        let (_alarms_tx, alarms_rx) = watch::channel(Default::default());
+        let (_daemon_tx, daemon_rx) = watch::channel(DaemonState::default());
+        let state = ApiState {
+            latest: latest_rx.clone(),
+            device: device_rx.clone(),
+            alarms: alarms_rx,
+            daemon: daemon_rx,
+            health_rules: HealthRules::default(),
+            pipeline: pipeline.clone(),
+            stream_queue: 4,
+        };
+        tasks.spawn("http_api", http_api_task(free_addr(), state, shutdown.clone()));
+        let nut = NutSettings {
+            listen: free_addr(),
+            ups_name: "ups120".to_string(),
+            username: None,
+            password: None,
+            low_battery_pct: 20.0,
+            charging_threshold: 0.1,
+        };
+        tasks.spawn("nut_server", nut_server_task(nut, NutSource { latest: latest_rx, device: device_rx }, shutdown.clone()));
+        let dir = tempfile::tempdir().unwrap();
+        let logger = CsvLogger::new(CsvLogSettings {
+            dir: dir.path().to_path_buf(),
+            max_file_bytes: 1 << 20,
+            max_files: 3,
+            rotate_daily: true,
+            flush_interval: Duration::from_secs(1),
+        })
+        .unwrap();
+        tasks.spawn("csv_log", csv_log_task(pipeline.clone(), logger, shutdown.clone()));
+
+        // 等 MQTT 连上、测量数据流经总线
+        let connected = tokio::time::timeout(Duration::from_secs(5), async {
+            while !matches!(events_rx.recv().await, Some(MqttEvent::Connected)) {}
+        });
+        assert!(connected.await.is_ok(), "MQTT 未连接");
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(DeviceEvent { event: UsbEvent::Measurements(sample), .. }) = usb_event_rx.recv().await {
+                    latest_tx.send_replace(Some(sample.clone()));
+                    pipeline.publish(sample);
+                    return;
+                }
+            }
+        });
+        assert!(received.await.is_ok(), "没有测量数据");
+        let csv_file = || std::fs::read_dir(dir.path()).unwrap().next().map(|entry| entry.unwrap().path());
+        let logged = tokio::time::timeout(Duration::from_secs(5), async {
+            while csv_file().is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        assert!(logged.await.is_ok(), "CSV 记录未创建");
+
+        let policy = Policy::default();
+        let started = tokio::time::Instant::now();
+        shutdown.cancel();
+        usb_cmd_tx.send(DeviceCommand { target: None, command: UsbCommand::Unsubscribe }).await.unwrap();
+        let unsubscribed = tokio::time::timeout(policy.shutdown_unsubscribe, async {
+            while !matches!(usb_event_rx.recv().await, Some(DeviceEvent { event: UsbEvent::Unsubscribed, .. }) | None) {}
+        });
+        assert!(unsubscribed.await.is_ok(), "设备未取消订阅");
+        let availability = daemon_availability_topic(&config.mqtt_topic_prefix);
+        let offline = vec![(availability.clone(), true, "offline".to_string())];
+        assert!(publish_and_confirm(&session.tracked, offline, started + policy.shutdown_confirm).await.unwrap());
+        drop(usb_cmd_tx);
+        drop(usb_event_rx);
+        session.stop.cancel();
+        assert_eq!(tasks.shutdown(policy.shutdown_tasks).await, Vec::<&str>::new());
+        assert!(started.elapsed() < policy.shutdown_unsubscribe + policy.shutdown_confirm + policy.shutdown_tasks);
+        assert!(broker.publishes().iter().any(|p| p.topic == availability && p.payload == b"offline" && p.retain));
+        // 退出时写出缓冲的 CSV 行：表头与一行数据
+        assert_eq!(std::fs::read_to_string(csv_file().unwrap()).unwrap().lines().count(), 2);
+    }
+}
@@ -0,0 +1,318 @@
+//! 测试用的最小 MQTT 3.1.1 broker。
+//!
+//! 只实现守护进程用到的报文：CONNECT、PUBLISH (QoS 0/1)、SUBSCRIBE、UNSUBSCRIBE、PINGREQ、
+//! DISCONNECT。收到的发布按顺序记录并以 QoS 0 转发给匹配的订阅；可以按主题前缀扣留 PubAck、
+//! 模拟 ACL 拒绝、断开现有连接，用来检查确认跟踪、重发与 ACL 探测。
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rumqttc::{matches, AsyncClient, Event, MqttOptions, Outgoing, Packet};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+
+use crate::acks::{track, AckTracker, TrackedClient};
+
+/// broker 收到的一条发布
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceivedPublish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+    pub dup: bool,
+    pub pkid: u16,
+}
+
+#[derive(Debug)]
+struct BrokerState {
+    publishes: Mutex<Vec<ReceivedPublish>>,
+    // 主题以此为前缀的 QoS 1 发布不回复 PubAck
+    withheld: Mutex<Option<String>>,
+    // 主题以这些为前缀的发布被静默丢弃：不回复 PubAck，也不转发
+    denied: Mutex<Vec<String>>,
+    // 非 None 时以该返回码拒绝 CONNECT 并断开
+    refused: Mutex<Option<u8>>,
+    // 各连接的订阅过滤器与写出通道
+    subscribers: Mutex<Vec<Subscriber>>,
+    next_connection: AtomicU64,
+    // 递增时断开所有现有连接
+    kick: watch::Sender<u64>,
+    // 每收到一条发布通知一次
+    received: watch::Sender<()>,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    connection: u64,
+    filters: Vec<String>,
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+/// 在本机随机端口上运行的 broker，随测试的 runtime 结束
+pub struct TestBroker {
+    pub port: u16,
+    state: Arc<BrokerState>,
+}
+
+impl TestBroker {
+    pub async fn start() -> TestBroker {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(BrokerState {
+            publishes: Mutex::new(Vec::new()),
+            withheld: Mutex::new(None),
+            denied: Mutex::new(Vec::new()),
+            refused: Mutex::new(None),
+            subscribers: Mutex::new(Vec::new()),
+            next_connection: AtomicU64::new(0),
+            kick: watch::Sender::new(0),
+            received: watch::Sender::new(()),
+        });
+        let accept_state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, accept_state.clone()));
+            }
+        });
+        TestBroker { port, state }
+    }
+
+    pub fn options(&self, client_id: &str) -> MqttOptions {
+        let mut options = MqttOptions::new(client_id, "127.0.0.1", self.port);
+        options.set_keep_alive(Duration::from_secs(5));
+        options
+    }
+
+    /// 连接到本 broker 的客户端，事件循环像 `connect_mqtt_and_publish` 一样把写出与 PubAck 交给确认跟踪
+    pub fn connect_tracked(&self, client_id: &str) -> (AsyncClient, TrackedClient) {
+        let acks = Arc::new(AckTracker::default());
+        let (inner, mut eventloop) = AsyncClient::new(self.options(client_id), 10);
+        let loop_acks = acks.clone();
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Outgoing(Outgoing::Publish(pkid))) if pkid != 0 => loop_acks.on_outgoing(pkid),
+                    Ok(Event::Incoming(Packet::PubAck(ack))) => {
+                        loop_acks.on_puback(ack.pkid);
+                    }
+                    Ok(_) => {}
+                    Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+                }
+            }
+        });
+        track(inner, acks, 10)
+    }
+
+    /// 按收到顺序的全部发布 (含重发)
+    pub fn publishes(&self) -> Vec<ReceivedPublish> {
+        self.state.publishes.lock().unwrap().clone()
+    }
+
+    /// 主题以 `prefix` 开头的发布不再回复 PubAck (空前缀为全部)；扣留的确认不会补发
+    pub fn withhold_acks(&self, prefix: &str) {
+        *self.state.withheld.lock().unwrap() = Some(prefix.to_string());
+    }
+
+    /// 恢复回复 PubAck
+    pub fn resume_acks(&self) {
+        *self.state.withheld.lock().unwrap() = None;
+    }
+
+    /// 模拟 ACL：主题以 `prefix` 开头的发布仍被记录，但不回复 PubAck、不转发给订阅者
+    pub fn deny(&self, prefix: &str) {
+        self.state.denied.lock().unwrap().push(prefix.to_string());
+    }
+
+    /// 之后的 CONNECT 以 `return_code` 拒绝 (如 5 为未授权，3 为服务不可用)
+    pub fn refuse_connections(&self, return_code: u8) {
+        *self.state.refused.lock().unwrap() = Some(return_code);
+    }
+
+    /// 恢复接受连接
+    pub fn accept_connections(&self) {
+        *self.state.refused.lock().unwrap() = None;
+    }
+
+    /// 迄今接受的 TCP 连接数 (含被拒绝的 CONNECT)
+    pub fn connection_attempts(&self) -> u64 {
+        self.state.next_connection.load(Ordering::Relaxed)
+    }
+
+    /// 断开所有现有连接，客户端随后自行重连
+    pub fn drop_connections(&self) {
+        self.state.kick.send_modify(|generation| *generation += 1);
+    }
+
+    /// 等待收到的发布满足 `done`，超时返回 false
+    pub async fn wait_for(&self, timeout: Duration, done: impl Fn(&[ReceivedPublish]) -> bool) -> bool {
+        let mut received = self.state.received.subscribe();
+        tokio::time::timeout(timeout, async {
+            loop {
+                received.borrow_and_update();
+                if done(&self.state.publishes.lock().unwrap()) {
+                    return;
+                }
+                if received.changed().await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            }
+        })
+        .await
+        .is_ok()
+    }
+}
+
+async fn serve(stream: TcpStream, state: Arc<BrokerState>) {
+    let connection = state.next_connection.fetch_add(1, Ordering::Relaxed);
+    let (mut reader, mut writer) = stream.into_split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    tokio::spawn(async move {
+        while let Some(bytes) = rx.recv().await {
+            if writer.write_all(&bytes).await.is_err() {
+                return;
+            }
+        }
+    });
+    state.subscribers.lock().unwrap().push(Subscriber { connection, filters: Vec::new(), tx: tx.clone() });
+    let mut kick = state.kick.subscribe();
+    kick.borrow_and_update();
+    loop {
+        let packet = tokio::select! {
+            packet = read_packet(&mut reader) => packet,
+            _ = kick.changed() => break,
+        };
+        let Some((header, body)) = packet else {
+            break;
+        };
+        let reply = match header >> 4 {
+            // CONNECT -> CONNACK
+            1 => {
+                let refused = *state.refused.lock().unwrap();
+                if let Some(code) = refused {
+                    let _ = tx.send(vec![0x20, 0x02, 0x00, code]);
+                    break;
+                }
+                vec![0x20, 0x02, 0x00, 0x00]
+            }
+            3 => {
+                let qos = (header >> 1) & 0x03;
+                let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                let topic = String::from_utf8_lossy(&body[2..2 + topic_len]).into_owned();
+                let mut offset = 2 + topic_len;
+                let mut pkid = 0;
+                if qos > 0 {
+                    pkid = u16::from_be_bytes([body[offset], body[offset + 1]]);
+                    offset += 2;
+                }
+                let withheld = matches!(&*state.withheld.lock().unwrap(), Some(prefix) if topic.starts_with(prefix.as_str()));
+                let denied = state.denied.lock().unwrap().iter().any(|prefix| topic.starts_with(prefix.as_str()));
+                let payload = body[offset..].to_vec();
+                if !denied {
+                    forward(&state, &topic, &payload);
+                }
+                state.publishes.lock().unwrap().push(ReceivedPublish {
+                    topic,
+                    payload,
+                    qos,
+                    retain: header & 0x01 != 0,
+                    dup: header & 0x08 != 0,
+                    pkid,
+                });
+                state.received.send_replace(());
+                if qos > 0 && !withheld && !denied {
+                    let [hi, lo] = pkid.to_be_bytes();
+                    vec![0x40, 0x02, hi, lo]
+                } else {
+                    Vec::new()
+                }
+            }
+            // SUBSCRIBE -> SUBACK，按请求的 QoS 授予
+            8 => {
+                let mut granted = Vec::new();
+                let mut filters = Vec::new();
+                let mut offset = 2;
+                while offset < body.len() {
+                    let len = u16::from_be_bytes([body[offset], body[offset + 1]]) as usize;
+                    filters.push(String::from_utf8_lossy(&body[offset + 2..offset + 2 + len]).into_owned());
+                    offset += 2 + len;
+                    granted.push(body[offset]);
+                    offset += 1;
+                }
+                update_filters(&state, connection, |existing| existing.extend(filters));
+                let mut reply = vec![0x90, 2 + granted.len() as u8, body[0], body[1]];
+                reply.extend(granted);
+                reply
+            }
+            // UNSUBSCRIBE -> UNSUBACK
+            10 => {
+                let mut removed = Vec::new();
+                let mut offset = 2;
+                while offset < body.len() {
+                    let len = u16::from_be_bytes([body[offset], body[offset + 1]]) as usize;
+                    removed.push(String::from_utf8_lossy(&body[offset + 2..offset + 2 + len]).into_owned());
+                    offset += 2 + len;
+                }
+                update_filters(&state, connection, |existing| existing.retain(|f| !removed.contains(f)));
+                vec![0xB0, 0x02, body[0], body[1]]
+            }
+            // PINGREQ -> PINGRESP
+            12 => vec![0xD0, 0x00],
+            // DISCONNECT
+            14 => break,
+            _ => Vec::new(),
+        };
+        if !reply.is_empty() && tx.send(reply).is_err() {
+            break;
+        }
+    }
+    state.subscribers.lock().unwrap().retain(|s| s.connection != connection);
+}
+
+fn update_filters(state: &BrokerState, connection: u64, update: impl FnOnce(&mut Vec<String>)) {
+    if let Some(subscriber) = state.subscribers.lock().unwrap().iter_mut().find(|s| s.connection == connection) {
+        update(&mut subscriber.filters);
+    }
+}
+
+// 以 QoS 0 转发给每个有匹配过滤器的连接 (每个连接至多一份)
+fn forward(state: &BrokerState, topic: &str, payload: &[u8]) {
+    let body_len = 2 + topic.len() + payload.len();
+    let mut packet = vec![0x30];
+    let mut remaining = body_len;
+    loop {
+        let byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend((topic.len() as u16).to_be_bytes());
+    packet.extend(topic.as_bytes());
+    packet.extend(payload);
+    for subscriber in state.subscribers.lock().unwrap().iter() {
+        if subscriber.filters.iter().any(|filter| matches(topic, filter)) {
+            let _ = subscriber.tx.send(packet.clone());
+        }
+    }
+}
+
+// 读取一个报文，返回 (固定头首字节, 可变头与载荷)
+async fn read_packet(stream: &mut (impl AsyncRead + Unpin)) -> Option<(u8, Vec<u8>)> {
+    let header = stream.read_u8().await.ok()?;
+    let mut remaining = 0usize;
+    for shift in (0..4).map(|i| i * 7) {
+        let byte = stream.read_u8().await.ok()?;
+        remaining |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0; remaining];
+    stream.read_exact(&mut body).await.ok()?;
+    Some((header, body))
+}
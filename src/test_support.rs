@@ -0,0 +1,361 @@
+//! 合成状态帧，供测试、文档示例与模拟/回放工具使用。
+//!
+//! `PayloadBuilder` 从一组合理的默认值出发，按字段修改后得到线上的字节帧（带 magic，大端），
+//! 以及守护进程解析该帧后应得到的 `AllMeasurements<5>`，不需要手写几十个字节。
+
+use std::io::Cursor;
+
+use binrw::BinWrite;
+
+use crate::conversion::{self, ConversionContext};
+use crate::data_models::{
+    AllMeasurements, Bq25730Alerts, Bq25730Measurements, Bq76920Alerts, Bq76920Measurements, ChargerFaultFlags,
+    ChargerStatusFlags, HostSideUsbPayload, Ina226Measurements, MosStatus, SystemStatus, Temperatures,
+    TimestampedMeasurements,
+};
+use crate::debug_dump::{DebugDump, DumpTarget};
+use crate::frame_id::FrameIdAllocator;
+use crate::sequence::SequenceTracker;
+use crate::usb_handlers::{handle_push_frame, PushContext};
+use crate::usb_types::{DeviceId, ProtocolVersion, UsbData, UsbEvent, UsbLinkStats};
+
+// v2 载荷中 INA226 读数的偏移 (BQ25730 ADC + 电芯电压 + TS + 电流与状态) 与长度，
+// 其后依次为充电器状态 u16、PROCHOT 状态 u16
+const INA226_OFFSET: usize = 8 * 2 + 5 * 4 + 9 + 6;
+const INA226_LEN: usize = 3 * 4;
+const CHARGER_STATUS_LEN: usize = 2;
+const PROCHOT_LEN: usize = 2;
+
+/// 状态帧构造器
+///
+/// ```
+/// use ups120_daemon::conversion::{to_measurements, ConversionContext};
+/// use ups120_daemon::data_models::ChargerStatusFlags;
+/// use ups120_daemon::framing::parse_frame;
+/// use ups120_daemon::test_support::PayloadBuilder;
+/// use ups120_daemon::usb_types::{ProtocolVersion, UsbData};
+///
+/// let builder = PayloadBuilder::new()
+///     .cell_mv(0, 3650)
+///     .vbat_mv(16800)
+///     .charger_flags(ChargerStatusFlags::STAT_AC);
+/// let (data, ignored) = parse_frame(&builder.frame(), &ProtocolVersion::LEGACY).unwrap();
+/// let UsbData::StatusPush(payload) = data else { panic!("应为 StatusPush") };
+/// assert_eq!(ignored, 0);
+/// assert_eq!(to_measurements::<5>(&payload, &ConversionContext::default()), builder.measurements());
+/// ```
+#[derive(Debug, Clone)]
+pub struct PayloadBuilder {
+    measurements: AllMeasurements<5>,
+    ctx: ConversionContext,
+    sequence: Option<u16>,
+}
+
+impl Default for PayloadBuilder {
+    /// 5 串电芯各 3.7 V、适配器接入且未充放电的静止状态
+    fn default() -> Self {
+        PayloadBuilder {
+            measurements: AllMeasurements {
+                bq25730: Bq25730Measurements {
+                    psys: 0.0,
+                    vbus: 19.5,
+                    idchg: 0.0,
+                    ichg: 0.0,
+                    cmpin: 0.0,
+                    iin: 0.0,
+                    vbat: 18.5,
+                    vsys: 18.6,
+                },
+                bq76920: Bq76920Measurements {
+                    cell_voltages: [3.7; 5],
+                    cell_count: 5,
+                    temperatures: Temperatures { ts1: Some(25.0), ts2: None, ts3: None, is_thermistor: true },
+                    coulomb_counter: 0.0,
+                    system_status: SystemStatus::CC_READY,
+                    mos_status: MosStatus::BothOn,
+                },
+                ina226: Some(Ina226Measurements { voltage: 18.6, current: 0.0, power: 0.0 }),
+                bq25730_alerts: Bq25730Alerts {
+                    charger_status_flags: ChargerStatusFlags::STAT_AC,
+                    ..Default::default()
+                },
+                // 与测量数据中的 SysStat 是同一个寄存器
+                bq76920_alerts: Bq76920Alerts { system_status: SystemStatus::CC_READY },
+            },
+            ctx: ConversionContext::default(),
+            sequence: None,
+        }
+    }
+}
+
+impl PayloadBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 以给定的测量数据为起点，按默认的转换上下文编码
+    pub fn from_measurements(measurements: AllMeasurements<5>) -> Self {
+        let ctx = ConversionContext { cell_count: measurements.bq76920.cell_count, ..ConversionContext::default() };
+        PayloadBuilder { measurements, ctx, sequence: None }
+    }
+
+    /// 编码与解析使用的转换上下文（检流电阻、电流方向、电芯数等）
+    pub fn context(mut self, ctx: ConversionContext) -> Self {
+        self.measurements.bq76920.cell_count = ctx.cell_count.min(5);
+        self.ctx = ctx;
+        self
+    }
+
+    /// 串联的电芯数，其余通道编码为 0
+    pub fn cell_count(mut self, cell_count: usize) -> Self {
+        self.ctx.cell_count = cell_count;
+        self.measurements.bq76920.cell_count = cell_count.min(5);
+        self
+    }
+
+    /// 第 `index` 节 (从 0 开始) 电芯的电压
+    pub fn cell_mv(mut self, index: usize, mv: i32) -> Self {
+        self.measurements.bq76920.cell_voltages[index] = mv as f32 / 1000.0;
+        self
+    }
+
+    pub fn vbat_mv(mut self, mv: u16) -> Self {
+        self.measurements.bq25730.vbat = mv as f32 / 1000.0;
+        self
+    }
+
+    pub fn vsys_mv(mut self, mv: u16) -> Self {
+        self.measurements.bq25730.vsys = mv as f32 / 1000.0;
+        self
+    }
+
+    pub fn vbus_mv(mut self, mv: u16) -> Self {
+        self.measurements.bq25730.vbus = mv as f32 / 1000.0;
+        self
+    }
+
+    /// 电池包电流 (BQ76920 库仑计)，充电为正
+    pub fn pack_current_ma(mut self, ma: i32) -> Self {
+        self.measurements.bq76920.coulomb_counter = ma as f32 / 1000.0;
+        self
+    }
+
+    /// TS1 温度 (°C)；编码为原始 ADC 值，解析结果在 1 LSB 之内
+    pub fn ts1_celsius(mut self, celsius: f32) -> Self {
+        self.measurements.bq76920.temperatures.ts1 = Some(celsius);
+        self
+    }
+
+    pub fn charger_flags(mut self, flags: ChargerStatusFlags) -> Self {
+        self.measurements.bq25730_alerts.charger_status_flags = flags;
+        self
+    }
+
+    pub fn charger_faults(mut self, faults: ChargerFaultFlags) -> Self {
+        self.measurements.bq25730_alerts.charger_fault_flags = faults;
+        self
+    }
+
+    pub fn system_status(mut self, status: SystemStatus) -> Self {
+        self.measurements.bq76920.system_status = status;
+        self.measurements.bq76920_alerts.system_status = status;
+        self
+    }
+
+    pub fn mos_status(mut self, status: MosStatus) -> Self {
+        self.measurements.bq76920.mos_status = status;
+        self
+    }
+
+    /// INA226 的电压 (V) 与电流 (A)，功率取两者之积
+    pub fn ina226(mut self, voltage: f32, current: f32) -> Self {
+        self.measurements.ina226 = Some(Ina226Measurements { voltage, current, power: voltage * current });
+        self
+    }
+
+    /// 按 v1 固件的布局编码：没有 INA226 读数与 PROCHOT 状态
+    pub fn without_ina226(mut self) -> Self {
+        self.measurements.ina226 = None;
+        self.measurements.bq25730_alerts.prochot_lsb_flags = Default::default();
+        self.measurements.bq25730_alerts.prochot_msb_flags = Default::default();
+        self.measurements.bq25730_alerts.prochot_width = 0;
+        self
+    }
+
+    /// 在载荷末尾附带帧序号；解析时协议版本须不低于 `SEQUENCE_PROTOCOL_VERSION`
+    pub fn sequence(mut self, sequence: u16) -> Self {
+        self.sequence = Some(sequence);
+        self
+    }
+
+    /// 线上的原始载荷
+    pub fn payload(&self) -> HostSideUsbPayload {
+        HostSideUsbPayload { sequence: self.sequence, ..conversion::to_payload(&self.measurements, &self.ctx) }
+    }
+
+    /// 带 StatusPush magic 的整帧
+    pub fn frame(&self) -> Vec<u8> {
+        self.encode(UsbData::StatusPush(self.payload()))
+    }
+
+    /// 按本构造器的布局 (v1/v2) 编码改动过的原始载荷，用于构造保留位、约定违例等异常帧
+    pub fn frame_from(&self, payload: HostSideUsbPayload) -> Vec<u8> {
+        self.encode(UsbData::StatusPush(payload))
+    }
+
+    /// 带 StatusResponse magic 的整帧，用于订阅与轮询的响应
+    pub fn response_frame(&self) -> Vec<u8> {
+        self.encode(UsbData::StatusResponse(self.payload()))
+    }
+
+    /// 守护进程解析该帧后得到的测量数据；数值经过与线上编码相同的量化
+    pub fn measurements(&self) -> AllMeasurements<5> {
+        conversion::to_measurements(&self.payload(), &self.ctx)
+    }
+
+    /// 主循环交给总线的样本：解析后的测量数据加上帧号、时间戳、派生指标与原始帧
+    pub fn sample(&self, frame_id: u64, ts_unix_ms: u64) -> TimestampedMeasurements {
+        let data = self.measurements();
+        TimestampedMeasurements {
+            frame_id,
+            ts_unix_ms,
+            derived: data.bq76920.derived(),
+            data,
+            battery: Default::default(),
+            raw: Some(self.frame().into()),
+        }
+    }
+
+    fn encode(&self, data: UsbData) -> Vec<u8> {
+        let mut cursor = Cursor::new(Vec::new());
+        data.write_be(&mut cursor).expect("写入内存缓冲不会失败");
+        let mut frame = cursor.into_inner();
+        if self.measurements.ina226.is_none() {
+            // BinWrite 总是按 v2 布局写入，去掉 v1 没有的字段（先去掉靠后的 PROCHOT，偏移不受影响）
+            let ina226 = 1 + INA226_OFFSET;
+            let prochot = ina226 + INA226_LEN + CHARGER_STATUS_LEN;
+            frame.drain(prochot..prochot + PROCHOT_LEN);
+            frame.drain(ina226..ina226 + INA226_LEN);
+        }
+        frame
+    }
+}
+
+/// 像 USB 管理任务一样逐帧处理推送数据，返回产生的全部事件 (测量数据、异常与错误)
+pub async fn push_events(frames: &[Vec<u8>], protocol: ProtocolVersion) -> Vec<UsbEvent> {
+    let device = DeviceId::BusAddr { bus: 1, address: 2 };
+    let frame_ids = FrameIdAllocator::in_memory();
+    let conversion_ctx = ConversionContext::default();
+    let debug_dump = DebugDump::new(false, DumpTarget::Log);
+    let (event_tx, mut event_rx) = tokio::sync::mpsc::channel(1024);
+    let mut sequence = SequenceTracker::default();
+    let mut link_stats = UsbLinkStats::default();
+    for (i, frame) in frames.iter().enumerate() {
+        let mut push = PushContext {
+            device: &device,
+            frame_ids: &frame_ids,
+            conversion_ctx: &conversion_ctx,
+            protocol,
+            event_tx: &event_tx,
+            sequence: &mut sequence,
+            link_stats: &mut link_stats,
+            debug_dump: &debug_dump,
+        };
+        handle_push_frame(frame, 1_700_000_000_000 + i as u64, &mut push).await;
+    }
+    drop(event_tx);
+    let mut events = Vec::new();
+    while let Some(event) = event_rx.recv().await {
+        events.push(event);
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::framing::{parse_frame, MAGIC_STATUS_PUSH, MAGIC_STATUS_RESPONSE};
+    use crate::usb_types::SEQUENCE_PROTOCOL_VERSION;
+
+    fn parsed(frame: &[u8], protocol: &ProtocolVersion, ctx: &ConversionContext) -> AllMeasurements<5> {
+        match parse_frame(frame, protocol).unwrap() {
+            (UsbData::StatusPush(payload) | UsbData::StatusResponse(payload), 0) => conversion::to_measurements(&payload, ctx),
+            other => panic!("应为无多余字节的状态帧: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn every_setter_survives_the_wire() {
+        let builders = [
+            PayloadBuilder::new(),
+            PayloadBuilder::new().cell_mv(0, 3650).cell_mv(4, 3420),
+            PayloadBuilder::new().cell_count(3),
+            PayloadBuilder::new().vbat_mv(16_800).vsys_mv(16_900).vbus_mv(12_000),
+            PayloadBuilder::new().pack_current_ma(-1500),
+            PayloadBuilder::new().ts1_celsius(45.0),
+            PayloadBuilder::new().charger_flags(ChargerStatusFlags::IN_FCHRG).charger_faults(ChargerFaultFlags::FAULT_ACOV),
+            PayloadBuilder::new().system_status(SystemStatus::OV | SystemStatus::CC_READY).mos_status(MosStatus::ChargeOn),
+            PayloadBuilder::new().ina226(12.0, 2.5),
+            PayloadBuilder::new().without_ina226().cell_mv(1, 3555),
+        ];
+        for builder in builders {
+            assert_eq!(parsed(&builder.frame(), &ProtocolVersion::LEGACY, &builder.ctx), builder.measurements(), "{:?}", builder);
+        }
+    }
+
+    #[test]
+    fn setters_show_up_in_the_expected_measurements() {
+        let data = PayloadBuilder::new()
+            .cell_mv(0, 3650)
+            .vbat_mv(16_800)
+            .pack_current_ma(-1500)
+            .charger_flags(ChargerStatusFlags::STAT_AC | ChargerStatusFlags::IN_FCHRG)
+            .system_status(SystemStatus::UV)
+            .mos_status(MosStatus::DischargeOn)
+            .ina226(12.0, 2.5)
+            .measurements();
+        assert_eq!(data.bq76920.cell_voltages[0], 3.65);
+        assert_eq!(data.bq25730.vbat, 16.8);
+        assert!((data.bq76920.coulomb_counter + 1.5).abs() < 1e-3, "{}", data.bq76920.coulomb_counter);
+        assert_eq!(data.bq25730_alerts.charger_status_flags, ChargerStatusFlags::STAT_AC | ChargerStatusFlags::IN_FCHRG);
+        assert_eq!((data.bq76920.system_status, data.bq76920_alerts.system_status), (SystemStatus::UV, SystemStatus::UV));
+        assert_eq!(data.bq76920.mos_status, MosStatus::DischargeOn);
+        let ina226 = data.ina226.unwrap();
+        assert_eq!((ina226.voltage, ina226.current, ina226.power), (12.0, 2.5, 30.0));
+
+        let three = PayloadBuilder::new().cell_count(3).measurements();
+        assert_eq!(three.bq76920.cells(), [3.7, 3.7, 3.7]);
+        assert!(PayloadBuilder::new().without_ina226().measurements().ina226.is_none());
+    }
+
+    #[test]
+    fn frames_carry_the_magic_and_the_layout_length() {
+        let builder = PayloadBuilder::new();
+        let frame = builder.frame();
+        assert_eq!((frame[0], frame.len()), (MAGIC_STATUS_PUSH, 1 + HostSideUsbPayload::SIZE));
+        let response = builder.response_frame();
+        assert_eq!((response[0], &response[1..]), (MAGIC_STATUS_RESPONSE, &frame[1..]));
+
+        let v1 = PayloadBuilder::new().without_ina226().frame();
+        assert_eq!(v1.len(), 1 + HostSideUsbPayload::V1_SIZE);
+
+        let sequenced = builder.clone().sequence(0x0102);
+        let frame = sequenced.frame();
+        assert_eq!(frame.len(), 1 + HostSideUsbPayload::SIZE + HostSideUsbPayload::SEQUENCE_SIZE);
+        assert_eq!(frame[frame.len() - 2..], [0x01, 0x02]);
+        let protocol = ProtocolVersion { protocol: SEQUENCE_PROTOCOL_VERSION, firmware: None };
+        assert_eq!(parsed(&frame, &protocol, &sequenced.ctx), sequenced.measurements());
+    }
+
+    #[test]
+    fn samples_carry_the_frame_and_the_derived_metrics() {
+        let builder = PayloadBuilder::new().cell_mv(2, 3500);
+        let sample = builder.sample(7, 1_700_000_000_000);
+        assert_eq!((sample.frame_id, sample.ts_unix_ms), (7, 1_700_000_000_000));
+        assert_eq!(sample.data, builder.measurements());
+        assert_eq!(sample.raw.as_deref(), Some(&builder.frame()[..]));
+        assert_eq!((sample.derived.cell_min, sample.derived.cell_max), (3.5, 3.7));
+        assert!((sample.derived.cell_delta - 0.2).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// 发布限速器：在最小间隔内到达的样本只保留最新的一份，
+/// 待间隔到期后再发布，既不丢弃也不突发。
+#[derive(Debug)]
+pub struct PublishThrottle<T> {
+    min_interval: Duration,
+    last_publish: Option<Instant>,
+    pending: Option<T>,
+}
+
+impl<T> PublishThrottle<T> {
+    pub fn new(min_interval: Duration) -> Self {
+        PublishThrottle {
+            min_interval,
+            last_publish: None,
+            pending: None,
+        }
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        self.min_interval
+    }
+
+    pub fn set_min_interval(&mut self, min_interval: Duration) {
+        self.min_interval = min_interval;
+    }
+
+    /// 提交新样本。若已过最小间隔则直接返回该样本供立即发布，
+    /// 否则暂存（覆盖之前暂存的样本）并返回 None。
+    pub fn offer(&mut self, sample: T, now: Instant) -> Option<T> {
+        match self.last_publish {
+            Some(last) if now < last + self.min_interval => {
+                self.pending = Some(sample);
+                None
+            }
+            _ => {
+                self.pending = None;
+                self.last_publish = Some(now);
+                Some(sample)
+            }
+        }
+    }
+
+    /// 暂存样本的发布时间点；没有暂存样本时为 None
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref()?;
+        Some(self.last_publish.map_or_else(Instant::now, |last| last + self.min_interval))
+    }
+
+    /// 若暂存样本已到期则取出
+    pub fn take_due(&mut self, now: Instant) -> Option<T> {
+        match self.deadline() {
+            Some(deadline) if now >= deadline => {
+                self.last_publish = Some(now);
+                self.pending.take()
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rapid_offers_collapse_into_one_publish_of_the_latest_sample() {
+        let start = Instant::now();
+        let interval = Duration::from_millis(1000);
+        let mut throttle = PublishThrottle::new(interval);
+        assert_eq!(throttle.offer(0, start), Some(0));
+
+        // 间隔内到达的 N 个样本都只是暂存
+        for n in 1..=10u64 {
+            assert_eq!(throttle.offer(n, start + Duration::from_millis(n * 50)), None);
+        }
+        assert_eq!(throttle.deadline(), Some(start + interval));
+        assert_eq!(throttle.take_due(start + Duration::from_millis(999)), None);
+        assert_eq!(throttle.take_due(start + interval), Some(10));
+        assert_eq!(throttle.deadline(), None);
+        assert_eq!(throttle.take_due(start + interval * 2), None);
+    }
+
+    #[test]
+    fn a_sample_after_the_interval_is_published_immediately() {
+        let start = Instant::now();
+        let mut throttle = PublishThrottle::new(Duration::from_millis(100));
+        assert_eq!(throttle.offer(1, start), Some(1));
+        assert_eq!(throttle.offer(2, start + Duration::from_millis(50)), None);
+        // 新样本在到期后到达：直接发布，暂存的旧样本随之作废
+        assert_eq!(throttle.offer(3, start + Duration::from_millis(150)), Some(3));
+        assert_eq!(throttle.deadline(), None);
+    }
+
+    #[test]
+    fn a_zero_interval_never_holds_samples_back() {
+        let start = Instant::now();
+        let mut throttle = PublishThrottle::new(Duration::ZERO);
+        for n in 0..5 {
+            assert_eq!(throttle.offer(n, start), Some(n));
+        }
+    }
+}
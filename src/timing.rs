@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+/// 超时、重试间隔等时间参数的集中定义，由 `DaemonConfig` 从环境变量构造。
+///
+/// 默认值即此前散落在各处的硬编码值；在较慢的嵌入式主机上可整体调大。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    pub usb: UsbTimeouts,
+    /// 创建 USB 上下文失败后的重试间隔 (`USB_CONTEXT_RETRY_SECS`，默认 10 秒)
+    pub usb_context_retry: Duration,
+    /// 查找或打开设备失败后的重试间隔 (`USB_OPEN_RETRY_SECS`，默认 25 秒)
+    pub usb_open_retry: Duration,
+    /// 打开后查找端点或订阅失败时的重试间隔 (`USB_SETUP_RETRY_SECS`，默认 5 秒)
+    pub usb_setup_retry: Duration,
+    /// 首次连接 MQTT broker 失败后的重试间隔 (`MQTT_CONNECT_RETRY_SECS`，默认 10 秒)
+    pub mqtt_connect_retry: Duration,
+    /// MQTT keep alive (`MQTT_KEEP_ALIVE_SECS`，默认 5 秒)
+    pub mqtt_keep_alive: Duration,
+    /// 断线重连退避的初始值 (`MQTT_RECONNECT_BACKOFF_INITIAL_MS`，默认 1 秒)
+    pub mqtt_reconnect_initial: Duration,
+    /// 断线重连退避的上限 (`MQTT_RECONNECT_BACKOFF_MAX_SECS`，默认 60 秒)
+    pub mqtt_reconnect_max: Duration,
+    /// 退出时等待关键消息 PubAck 的最长时间 (`SHUTDOWN_CONFIRM_TIMEOUT_MS`，默认 3 秒)
+    pub shutdown_confirm: Duration,
+    /// 退出时等待设备取消订阅的最长时间 (`SHUTDOWN_UNSUBSCRIBE_TIMEOUT_MS`，默认 6 秒)。
+    /// 包括发送命令与等待设备确认，应大于命令与响应超时之和
+    pub shutdown_unsubscribe: Duration,
+    /// 退出时等待所有后台任务结束的最长时间 (`SHUTDOWN_TASKS_TIMEOUT_MS`，默认 5 秒)，超时后中止剩余任务
+    pub shutdown_tasks: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            usb: UsbTimeouts::default(),
+            usb_context_retry: Duration::from_secs(10),
+            usb_open_retry: Duration::from_secs(25),
+            usb_setup_retry: Duration::from_secs(5),
+            mqtt_connect_retry: Duration::from_secs(10),
+            mqtt_keep_alive: Duration::from_secs(5),
+            mqtt_reconnect_initial: Duration::from_secs(1),
+            mqtt_reconnect_max: Duration::from_secs(60),
+            shutdown_confirm: Duration::from_secs(3),
+            shutdown_unsubscribe: Duration::from_secs(6),
+            shutdown_tasks: Duration::from_secs(5),
+        }
+    }
+}
+
+/// USB 传输的超时
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbTimeouts {
+    /// 向命令端点写入的超时 (`USB_COMMAND_TIMEOUT_MS`，默认 5 秒)
+    pub command: Duration,
+    /// 等待响应端点回复的超时 (`USB_RESPONSE_TIMEOUT_MS`，默认 5 秒)
+    pub response: Duration,
+    /// 推送端点单次读取的超时 (`USB_PUSH_READ_TIMEOUT_MS`，默认 10 秒)。
+    /// 读取超时只表示暂时没有数据，不会触发重连
+    pub push_read: Duration,
+    /// 持续这么久没有收到 StatusPush 视为数据过期：先重新订阅，再过同样时间仍无数据则重连
+    /// (`USB_STALE_TIMEOUT_SECS`，默认 30 秒)
+    pub stale_timeout: Duration,
+}
+
+impl Default for UsbTimeouts {
+    fn default() -> Self {
+        UsbTimeouts {
+            command: Duration::from_secs(5),
+            response: Duration::from_secs(5),
+            push_read: Duration::from_secs(10),
+            stale_timeout: Duration::from_secs(30),
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use crate::config::ConfigError;
+use crate::units::OutputUnits;
+use crate::usb_types::DeviceId;
+
+/// 各字段主题的键与默认后缀（相对于测量数据主题前缀）
+pub const DEFAULT_TOPICS: &[(&str, &str)] = &[
+    ("psys", "bq25730/psys"),
+    ("vbus", "bq25730/vbus"),
+    ("idchg", "bq25730/idchg"),
+    ("ichg", "bq25730/ichg"),
+    ("cmpin", "bq25730/cmpin"),
+    ("iin", "bq25730/iin"),
+    ("vbat", "bq25730/vbat"),
+    ("vsys", "bq25730/vsys"),
+    ("cell0", "bq76920/cell_voltages/0"),
+    ("cell1", "bq76920/cell_voltages/1"),
+    ("cell2", "bq76920/cell_voltages/2"),
+    ("cell3", "bq76920/cell_voltages/3"),
+    ("cell4", "bq76920/cell_voltages/4"),
+    ("ts1", "bq76920/temperatures/ts1"),
+    ("coulomb_counter", "bq76920/coulomb_counter"),
+    ("system_status", "bq76920/system_status"),
+    ("mos_status", "bq76920/mos_status"),
+    ("mos_status_raw", "bq76920/mos_status_raw"),
+    ("pack_voltage", "bq76920/pack_voltage"),
+    ("cell_min", "bq76920/cell_min"),
+    ("cell_max", "bq76920/cell_max"),
+    ("cell_delta", "bq76920/cell_delta"),
+    ("efficiency_percent", "efficiency_percent"),
+    ("charger_stat_ac", "bq25730/status/charger/stat_ac"),
+    ("charger_ico_done", "bq25730/status/charger/ico_done"),
+    ("charger_in_vap", "bq25730/status/charger/in_vap"),
+    ("charger_in_vindpm", "bq25730/status/charger/in_vindpm"),
+    ("charger_in_iin_dpm", "bq25730/status/charger/in_iin_dpm"),
+    ("charger_in_fchrg", "bq25730/status/charger/in_fchrg"),
+    ("charger_in_pchrg", "bq25730/status/charger/in_pchrg"),
+    ("charger_in_otg", "bq25730/status/charger/in_otg"),
+    ("charger_fault_acov", "bq25730/status/charger_fault/acov"),
+    ("charger_fault_batoc", "bq25730/status/charger_fault/batoc"),
+    ("charger_fault_acoc", "bq25730/status/charger_fault/acoc"),
+    ("charger_fault_sysovp", "bq25730/status/charger_fault/sysovp"),
+    ("charger_fault_vsys_uvp", "bq25730/status/charger_fault/vsys_uvp"),
+    ("charger_fault_conv_off", "bq25730/status/charger_fault/conv_off"),
+    ("charger_fault_otg_ovp", "bq25730/status/charger_fault/otg_ovp"),
+    ("charger_fault_otg_uvp", "bq25730/status/charger_fault/otg_uvp"),
+    ("prochot_lsb_stat_vindpm", "bq25730/status/prochot/lsb_stat_vindpm"),
+    ("prochot_lsb_stat_comp", "bq25730/status/prochot/lsb_stat_comp"),
+    ("prochot_lsb_stat_icrit", "bq25730/status/prochot/lsb_stat_icrit"),
+    ("prochot_lsb_stat_inom", "bq25730/status/prochot/lsb_stat_inom"),
+    ("prochot_lsb_stat_idchg1", "bq25730/status/prochot/lsb_stat_idchg1"),
+    ("prochot_lsb_stat_vsys", "bq25730/status/prochot/lsb_stat_vsys"),
+    ("prochot_lsb_stat_bat_removal", "bq25730/status/prochot/lsb_stat_bat_removal"),
+    ("prochot_lsb_stat_adpt_removal", "bq25730/status/prochot/lsb_stat_adpt_removal"),
+    ("prochot_msb_en_prochot_ext", "bq25730/status/prochot/msb_en_prochot_ext"),
+    ("prochot_msb_prochot_clear", "bq25730/status/prochot/msb_prochot_clear"),
+    ("prochot_msb_stat_vap_fail", "bq25730/status/prochot/msb_stat_vap_fail"),
+    ("prochot_msb_stat_exit_vap", "bq25730/status/prochot/msb_stat_exit_vap"),
+    ("prochot_width", "bq25730/status/prochot/width"),
+    ("system_ocd", "bq76920/status/system/ocd"),
+    ("system_scd", "bq76920/status/system/scd"),
+    ("system_ov", "bq76920/status/system/ov"),
+    ("system_uv", "bq76920/status/system/uv"),
+    ("system_ovrd_alert", "bq76920/status/system/ovrd_alert"),
+    ("system_device_xready", "bq76920/status/system/device_xready"),
+    ("system_cc_ready", "bq76920/status/system/cc_ready"),
+];
+
+/// 单节电芯电压对应的主题键
+pub const CELL_TOPIC_KEYS: [&str; 5] = ["cell0", "cell1", "cell2", "cell3", "cell4"];
+
+// 测量数据前缀下固定的主题后缀；`*_topic` 函数与 `Topics` 共用，命名只在这里定义
+pub const MEASUREMENTS_ALL: &str = "measurements_all";
+pub const LAST_UPDATE: &str = "last_update";
+pub const SOC: &str = "battery/soc";
+pub const RUNTIME: &str = "battery/runtime_min";
+pub const TIME_TO_FULL: &str = "battery/time_to_full_min";
+pub const STATE_AGE: &str = "state_age";
+pub const DEVICE_INFO: &str = "device/info";
+pub const DEVICE_USB_ID: &str = "device/usb_id";
+pub const DEVICE_AVAILABILITY: &str = "device/availability";
+pub const ALARMS: &str = "alarms";
+pub const ACTIVE_ALARMS: &str = "alarms/active";
+
+/// 按设备区分的主题前缀 `{prefix}/{device}`；MQTT 通配符与层级分隔符替换为 `_`
+pub fn device_topic_prefix(topic_prefix: &str, device: &DeviceId) -> String {
+    let device: String = device
+        .to_string()
+        .chars()
+        .map(|c| if matches!(c, '/' | '+' | '#') || c.is_control() { '_' } else { c })
+        .collect();
+    format!("{}/{}", topic_prefix, device)
+}
+
+/// 字段主题映射与载荷格式选项：配置文件 `[topics]` 中的覆盖项加上默认后缀，启动时构建一次后复用
+#[derive(Debug, Clone)]
+pub struct TopicMap {
+    suffixes: HashMap<&'static str, String>,
+    /// 是否额外以数值发布 MOS 管状态
+    mos_status_raw: bool,
+    /// 标志以名称数组而非整数发布
+    flags_as_names: bool,
+    /// 数值的发布单位与小数位数
+    units: OutputUnits,
+    /// 按前缀缓存的完整主题，克隆后共享
+    cache: Arc<Mutex<HashMap<String, Arc<Topics>>>>,
+}
+
+impl Default for TopicMap {
+    fn default() -> Self {
+        TopicMap {
+            suffixes: DEFAULT_TOPICS.iter().map(|(key, suffix)| (*key, suffix.to_string())).collect(),
+            mos_status_raw: false,
+            flags_as_names: false,
+            units: OutputUnits::default(),
+            cache: Arc::default(),
+        }
+    }
+}
+
+impl TopicMap {
+    /// 应用覆盖项。未知的键、空主题、含通配符的主题以及映射到同一主题的多个键都会被拒绝
+    pub fn new(overrides: &BTreeMap<String, String>) -> Result<Self, ConfigError> {
+        let mut map = TopicMap::default();
+        for (key, target) in overrides {
+            let Some((known_key, _)) = DEFAULT_TOPICS.iter().find(|(k, _)| k == key) else {
+                return Err(invalid(key, target, "unknown metric"));
+            };
+            let target = target.trim_matches('/');
+            if target.is_empty() || target.contains(['+', '#']) {
+                return Err(invalid(key, target, "topic must be non-empty and contain no wildcards"));
+            }
+            map.suffixes.insert(known_key, target.to_string());
+        }
+
+        let mut seen = HashSet::new();
+        for (key, _) in DEFAULT_TOPICS {
+            let suffix = &map.suffixes[key];
+            if !seen.insert(suffix.as_str()) {
+                return Err(invalid(key, suffix, "duplicate target topic"));
+            }
+        }
+        Ok(map)
+    }
+
+    /// 额外把 MOS 管状态以 `MosStatus::as_bits` 的数值发布到 `mos_status_raw` 主题
+    pub fn with_mos_status_raw(mut self, enabled: bool) -> Self {
+        self.mos_status_raw = enabled;
+        self
+    }
+
+    pub fn mos_status_raw(&self) -> bool {
+        self.mos_status_raw
+    }
+
+    /// `measurements_all` 与 `system_status` 主题中的标志以名称数组发布，见 `flag_names`
+    pub fn with_flags_as_names(mut self, enabled: bool) -> Self {
+        self.flags_as_names = enabled;
+        self
+    }
+
+    pub fn flags_as_names(&self) -> bool {
+        self.flags_as_names
+    }
+
+    /// 字段主题与 `measurements_all` 中数值的单位与小数位数
+    pub fn with_units(mut self, units: OutputUnits) -> Self {
+        self.units = units;
+        self
+    }
+
+    pub fn units(&self) -> &OutputUnits {
+        &self.units
+    }
+
+    /// `{topic_prefix}/{后缀}`；键必须来自 `DEFAULT_TOPICS`
+    pub fn topic(&self, topic_prefix: &str, key: &str) -> String {
+        let suffix = self.suffixes.get(key).map(String::as_str).unwrap_or(key);
+        format!("{}/{}", topic_prefix, suffix)
+    }
+
+    /// 测量数据前缀 `measurement_prefix` 下的全部主题，每个前缀只构建一次
+    pub fn for_prefix(&self, measurement_prefix: &str) -> Arc<Topics> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(topics) = cache.get(measurement_prefix) {
+            return Arc::clone(topics);
+        }
+        let topics = Arc::new(Topics::new(self, measurement_prefix));
+        cache.insert(measurement_prefix.to_string(), Arc::clone(&topics));
+        topics
+    }
+}
+
+/// 某个测量数据前缀下发布用的全部主题，由 `TopicMap::for_prefix` 构建一次后，
+/// 测量数据、告警与状态恢复的发布方按引用共享
+///
+/// ```
+/// use ups120_daemon::topics::{TopicMap, Topics};
+/// use ups120_daemon::usb_types::DeviceId;
+///
+/// let topics = Topics::new(&TopicMap::default(), "ups120");
+/// assert_eq!(topics.measurements_all, "ups120/measurements_all");
+/// assert_eq!(topics.last_update, "ups120/last_update");
+/// assert_eq!(topics.soc, "ups120/battery/soc");
+/// assert_eq!(topics.runtime, "ups120/battery/runtime_min");
+/// assert_eq!(topics.time_to_full, "ups120/battery/time_to_full_min");
+/// assert_eq!(topics.state_age, "ups120/state_age");
+/// assert_eq!(topics.device_info, "ups120/device/info");
+/// assert_eq!(topics.device_usb_id, "ups120/device/usb_id");
+/// assert_eq!(topics.device_availability, "ups120/device/availability");
+/// assert_eq!(topics.active_alarms, "ups120/alarms/active");
+/// assert_eq!(topics.alarm("cell_ov"), "ups120/alarms/cell_ov");
+/// assert_eq!(topics.alarm_context("cell_ov"), "ups120/alarms/cell_ov/context");
+/// assert_eq!(topics.field("psys"), "ups120/measurements_all/bq25730/psys");
+/// assert_eq!(topics.field("cell4"), "ups120/measurements_all/bq76920/cell_voltages/4");
+///
+/// let device = DeviceId::Serial("UPS/01".to_string());
+/// let topics = Topics::for_device(&TopicMap::default(), "ups120", Some(&device));
+/// assert_eq!(topics.soc, "ups120/UPS_01/battery/soc");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topics {
+    pub prefix: String,
+    pub measurements_all: String,
+    pub last_update: String,
+    pub soc: String,
+    pub runtime: String,
+    pub time_to_full: String,
+    pub state_age: String,
+    pub device_info: String,
+    pub device_usb_id: String,
+    pub device_availability: String,
+    pub active_alarms: String,
+    /// `measurements_all` 下的各字段主题
+    fields: TopicCache,
+}
+
+impl Topics {
+    pub fn new(map: &TopicMap, measurement_prefix: &str) -> Self {
+        let topic = |suffix: &str| format!("{}/{}", measurement_prefix, suffix);
+        let measurements_all = topic(MEASUREMENTS_ALL);
+        Topics {
+            prefix: measurement_prefix.to_string(),
+            fields: TopicCache::new(map, &measurements_all),
+            measurements_all,
+            last_update: topic(LAST_UPDATE),
+            soc: topic(SOC),
+            runtime: topic(RUNTIME),
+            time_to_full: topic(TIME_TO_FULL),
+            state_age: topic(STATE_AGE),
+            device_info: topic(DEVICE_INFO),
+            device_usb_id: topic(DEVICE_USB_ID),
+            device_availability: topic(DEVICE_AVAILABILITY),
+            active_alarms: topic(ACTIVE_ALARMS),
+        }
+    }
+
+    /// 按设备发布时前缀为 `{topic_prefix}/{device}`，否则直接使用 `topic_prefix`
+    pub fn for_device(map: &TopicMap, topic_prefix: &str, device: Option<&DeviceId>) -> Self {
+        match device {
+            Some(device) => Topics::new(map, &device_topic_prefix(topic_prefix, device)),
+            None => Topics::new(map, topic_prefix),
+        }
+    }
+
+    /// 字段主题，键来自 `DEFAULT_TOPICS`
+    pub fn field(&self, key: &str) -> Cow<'_, str> {
+        self.fields.get(key)
+    }
+
+    /// 单个告警的状态主题；告警名来自配置，按需拼接
+    pub fn alarm(&self, name: &str) -> String {
+        format!("{}/{}/{}", self.prefix, ALARMS, name)
+    }
+
+    pub fn alarm_context(&self, name: &str) -> String {
+        format!("{}/{}/{}/context", self.prefix, ALARMS, name)
+    }
+}
+
+/// 某个前缀下 `DEFAULT_TOPICS` 全部键对应的完整主题，发布时不再逐条 `format!`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicCache {
+    prefix: String,
+    topics: HashMap<&'static str, String>,
+}
+
+impl TopicCache {
+    pub fn new(map: &TopicMap, topic_prefix: &str) -> Self {
+        TopicCache {
+            prefix: topic_prefix.to_string(),
+            topics: DEFAULT_TOPICS.iter().map(|(key, _)| (*key, map.topic(topic_prefix, key))).collect(),
+        }
+    }
+
+    /// 与 `TopicMap::topic` 相同的结果；未知的键按后缀拼接
+    pub fn get(&self, key: &str) -> Cow<'_, str> {
+        match self.topics.get(key) {
+            Some(topic) => Cow::Borrowed(topic),
+            None => Cow::Owned(format!("{}/{}", self.prefix, key)),
+        }
+    }
+}
+
+fn invalid(key: &str, target: &str, reason: &str) -> ConfigError {
+    ConfigError::Invalid {
+        key: "topics",
+        value: format!("{} = \"{}\"", key, target),
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_prefixes_use_the_serial_or_bus_and_address() {
+        assert_eq!(device_topic_prefix("ups120", &DeviceId::Serial("A1B2".to_string())), "ups120/A1B2");
+        assert_eq!(device_topic_prefix("ups120", &DeviceId::BusAddr { bus: 1, address: 12 }), "ups120/bus1-addr12");
+    }
+
+    #[test]
+    fn wildcards_and_separators_in_serials_are_replaced() {
+        let device = DeviceId::Serial("a/b+c#d\u{7}".to_string());
+        assert_eq!(device_topic_prefix("home/ups", &device), "home/ups/a_b_c_d_");
+    }
+
+    #[test]
+    fn cached_topics_match_the_hardcoded_names() {
+        let topics = Topics::new(&TopicMap::default(), "ups120");
+        let fixed = [
+            (&topics.measurements_all, "ups120/measurements_all"),
+            (&topics.last_update, "ups120/last_update"),
+            (&topics.soc, "ups120/battery/soc"),
+            (&topics.runtime, "ups120/battery/runtime_min"),
+            (&topics.time_to_full, "ups120/battery/time_to_full_min"),
+            (&topics.state_age, "ups120/state_age"),
+            (&topics.device_info, "ups120/device/info"),
+            (&topics.device_usb_id, "ups120/device/usb_id"),
+            (&topics.device_availability, "ups120/device/availability"),
+            (&topics.active_alarms, "ups120/alarms/active"),
+        ];
+        for (cached, expected) in fixed {
+            assert_eq!(cached, expected);
+        }
+        assert_eq!(topics.alarm("low_soc"), "ups120/alarms/low_soc");
+        assert_eq!(topics.alarm_context("low_soc"), "ups120/alarms/low_soc/context");
+        for (key, expected) in [
+            ("vbat", "ups120/measurements_all/bq25730/vbat"),
+            ("cell0", "ups120/measurements_all/bq76920/cell_voltages/0"),
+            ("ts1", "ups120/measurements_all/bq76920/temperatures/ts1"),
+            ("cell_delta", "ups120/measurements_all/bq76920/cell_delta"),
+            ("efficiency_percent", "ups120/measurements_all/efficiency_percent"),
+            ("charger_stat_ac", "ups120/measurements_all/bq25730/status/charger/stat_ac"),
+            ("prochot_width", "ups120/measurements_all/bq25730/status/prochot/width"),
+            ("system_cc_ready", "ups120/measurements_all/bq76920/status/system/cc_ready"),
+        ] {
+            assert_eq!(topics.field(key), expected);
+        }
+        // 每个字段主题都是 measurements_all 下的默认后缀，且都已预先构建
+        for (key, suffix) in DEFAULT_TOPICS {
+            assert_eq!(topics.field(key), format!("ups120/measurements_all/{}", suffix));
+            assert!(matches!(topics.field(key), Cow::Borrowed(_)), "{}", key);
+        }
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn cached_topics_agree_with_the_topic_functions() {
+        use crate::{alarms, last_state, mqtt_handlers};
+
+        let device = DeviceId::Serial("UPS01".to_string());
+        let prefix = device_topic_prefix("ups120", &device);
+        let topics = Topics::for_device(&TopicMap::default(), "ups120", Some(&device));
+        assert_eq!(topics.prefix, prefix);
+        assert_eq!(topics.measurements_all, mqtt_handlers::measurements_all_topic(&prefix));
+        assert_eq!(topics.soc, mqtt_handlers::soc_topic(&prefix));
+        assert_eq!(topics.runtime, mqtt_handlers::runtime_topic(&prefix));
+        assert_eq!(topics.time_to_full, mqtt_handlers::time_to_full_topic(&prefix));
+        assert_eq!(topics.device_info, mqtt_handlers::device_info_topic(&prefix));
+        assert_eq!(topics.device_usb_id, mqtt_handlers::device_usb_id_topic(&prefix));
+        assert_eq!(topics.device_availability, mqtt_handlers::device_availability_topic(&prefix));
+        assert_eq!(topics.state_age, last_state::state_age_topic(&prefix));
+        assert_eq!(topics.active_alarms, alarms::active_alarms_topic(&prefix));
+        assert_eq!(topics.alarm("low_soc"), alarms::alarm_topic(&prefix, "low_soc"));
+        assert_eq!(topics.alarm_context("low_soc"), alarms::alarm_context_topic(&prefix, "low_soc"));
+        for (key, _) in DEFAULT_TOPICS {
+            assert_eq!(topics.field(key), TopicMap::default().topic(&topics.measurements_all, key));
+        }
+    }
+
+    #[test]
+    fn overrides_reach_the_cache_and_each_prefix_is_built_once() {
+        let overrides = BTreeMap::from([("vbat".to_string(), "battery/voltage".to_string())]);
+        let map = TopicMap::new(&overrides).unwrap();
+        let topics = map.for_prefix("ups120");
+        assert_eq!(topics.field("vbat"), "ups120/measurements_all/battery/voltage");
+        assert_eq!(topics.field("vsys"), "ups120/measurements_all/bq25730/vsys");
+        // 克隆的映射共享同一份缓存
+        assert!(Arc::ptr_eq(&topics, &map.clone().for_prefix("ups120")));
+        assert!(!Arc::ptr_eq(&topics, &map.for_prefix("ups120/UPS01")));
+        // 不在 DEFAULT_TOPICS 中的键按后缀拼接
+        assert_eq!(topics.field("bq76920/extra"), "ups120/measurements_all/bq76920/extra");
+    }
+}
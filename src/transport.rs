@@ -0,0 +1,143 @@
+//! USB 传输的抽象。
+//!
+//! 握手、取消订阅与帧读取只依赖这里的三个操作，真实设备由 `RusbTransport` 实现，
+//! `MockTransport` 按预先编排的字节序列应答，便于在没有硬件时验证协议逻辑。
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::capture::{CaptureSink, Direction};
+
+/// 命令 (OUT)、响应 (IN)、推送 (IN) 端点地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Endpoints {
+    pub command: u8,
+    pub response: u8,
+    pub push: u8,
+}
+
+/// 与设备交换数据的三个阻塞操作，语义与 `rusb` 的中断传输一致：
+/// 超时返回 `rusb::Error::Timeout`，设备断开返回 `rusb::Error::NoDevice`
+pub trait UsbTransport {
+    /// 向命令端点写入一条命令，返回写入的字节数
+    fn write_command(&self, data: &[u8], timeout: Duration) -> rusb::Result<usize>;
+    /// 从响应端点读取一次
+    fn read_response(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+    /// 从推送端点读取一次
+    fn read_push(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+}
+
+/// 基于已打开的 rusb 设备句柄的传输；设置了抓取时，成功收发的数据同时交给 `CaptureSink`
+pub struct RusbTransport<'a> {
+    handle: &'a rusb::DeviceHandle<rusb::Context>,
+    endpoints: Endpoints,
+    capture: Option<CaptureSink>,
+}
+
+impl<'a> RusbTransport<'a> {
+    pub fn new(handle: &'a rusb::DeviceHandle<rusb::Context>, endpoints: Endpoints) -> Self {
+        RusbTransport { handle, endpoints, capture: None }
+    }
+
+    pub fn with_capture(mut self, capture: Option<CaptureSink>) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    fn record(&self, direction: Direction, data: &[u8]) {
+        if let Some(capture) = &self.capture {
+            capture.record(direction, data);
+        }
+    }
+}
+
+impl UsbTransport for RusbTransport<'_> {
+    fn write_command(&self, data: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        let written = self.handle.write_interrupt(self.endpoints.command, data, timeout)?;
+        self.record(Direction::Command, &data[..written]);
+        Ok(written)
+    }
+
+    fn read_response(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        let n = self.handle.read_interrupt(self.endpoints.response, buf, timeout)?;
+        self.record(Direction::Response, &buf[..n]);
+        Ok(n)
+    }
+
+    fn read_push(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        let n = self.handle.read_interrupt(self.endpoints.push, buf, timeout)?;
+        self.record(Direction::Push, &buf[..n]);
+        Ok(n)
+    }
+}
+
+/// 按编排的结果依次应答的传输；脚本读完后读取返回超时，写入的命令全部记录下来。
+/// 超出缓冲区的数据会被截断，与真实设备的溢出行为不同，编排时应按包长拆分。
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    written: Mutex<Vec<Vec<u8>>>,
+    responses: Mutex<VecDeque<rusb::Result<Vec<u8>>>>,
+    pushes: Mutex<VecDeque<rusb::Result<Vec<u8>>>>,
+    /// 写入命令时返回的错误，用于模拟命令端点失败
+    write_error: Mutex<Option<rusb::Error>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 追加一次响应端点读取的结果
+    pub fn respond(self, result: rusb::Result<Vec<u8>>) -> Self {
+        self.responses.lock().unwrap().push_back(result);
+        self
+    }
+
+    /// 追加一次推送端点读取的结果
+    pub fn push(self, result: rusb::Result<Vec<u8>>) -> Self {
+        self.pushes.lock().unwrap().push_back(result);
+        self
+    }
+
+    /// 之后的命令写入都以该错误失败
+    pub fn fail_writes(self, error: rusb::Error) -> Self {
+        *self.write_error.lock().unwrap() = Some(error);
+        self
+    }
+
+    /// 已写入的命令，按写入顺序
+    pub fn written(&self) -> Vec<Vec<u8>> {
+        self.written.lock().unwrap().clone()
+    }
+
+    fn next(queue: &Mutex<VecDeque<rusb::Result<Vec<u8>>>>, buf: &mut [u8]) -> rusb::Result<usize> {
+        match queue.lock().unwrap().pop_front() {
+            Some(Ok(data)) => {
+                let n = data.len().min(buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                Ok(n)
+            }
+            Some(Err(e)) => Err(e),
+            None => Err(rusb::Error::Timeout),
+        }
+    }
+}
+
+impl UsbTransport for MockTransport {
+    fn write_command(&self, data: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+        if let Some(e) = *self.write_error.lock().unwrap() {
+            return Err(e);
+        }
+        self.written.lock().unwrap().push(data.to_vec());
+        Ok(data.len())
+    }
+
+    fn read_response(&self, buf: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+        Self::next(&self.responses, buf)
+    }
+
+    fn read_push(&self, buf: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+        Self::next(&self.pushes, buf)
+    }
+}
@@ -0,0 +1,241 @@
+//! 发布时的单位与小数位数。
+//!
+//! 只作用于发布的文本与 JSON：存储与告警、记录使用的浮点数始终为 V / A / W，
+//! 换算和舍入在格式化输出时完成。
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::ConfigError;
+
+/// 物理量类别
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantity {
+    Voltage,
+    Current,
+    Power,
+    /// 温度等不换算单位、只按精度舍入的量
+    Other,
+}
+
+/// 基本单位或千分之一
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Scale {
+    #[default]
+    Base,
+    Milli,
+}
+
+impl Scale {
+    fn factor(self) -> f64 {
+        match self {
+            Scale::Base => 1.0,
+            Scale::Milli => 1000.0,
+        }
+    }
+
+    /// 解析 `V` / `mV` 这类取值；`base` 为基本单位的符号
+    pub fn parse(key: &'static str, value: &str, base: &str) -> Result<Self, ConfigError> {
+        let trimmed = value.trim();
+        if trimmed.eq_ignore_ascii_case(base) {
+            Ok(Scale::Base)
+        } else if trimmed.len() == base.len() + 1
+            && trimmed.starts_with('m')
+            && trimmed[1..].eq_ignore_ascii_case(base)
+        {
+            Ok(Scale::Milli)
+        } else {
+            Err(ConfigError::Invalid {
+                key,
+                value: value.to_string(),
+                reason: format!("expected {}/m{}", base, base),
+            })
+        }
+    }
+}
+
+/// `measurements_all` JSON 中各数值字段的类别；单体电压数组另行处理
+const JSON_FIELDS: &[(&str, Quantity)] = &[
+    ("/data/bq25730/psys", Quantity::Power),
+    ("/data/bq25730/vbus", Quantity::Voltage),
+    ("/data/bq25730/idchg", Quantity::Current),
+    ("/data/bq25730/ichg", Quantity::Current),
+    ("/data/bq25730/cmpin", Quantity::Voltage),
+    ("/data/bq25730/iin", Quantity::Current),
+    ("/data/bq25730/vbat", Quantity::Voltage),
+    ("/data/bq25730/vsys", Quantity::Voltage),
+    ("/data/bq76920/coulomb_counter", Quantity::Current),
+    ("/data/bq76920/temperatures/ts1", Quantity::Other),
+    ("/data/ina226/voltage", Quantity::Voltage),
+    ("/data/ina226/current", Quantity::Current),
+    ("/data/ina226/power", Quantity::Power),
+    ("/derived/pack_voltage", Quantity::Voltage),
+    ("/derived/cell_min", Quantity::Voltage),
+    ("/derived/cell_max", Quantity::Voltage),
+    ("/derived/cell_delta", Quantity::Voltage),
+    ("/derived/efficiency_percent", Quantity::Other),
+];
+
+/// JSON 载荷中的 `units` 对象
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct UnitLabels {
+    pub voltage: &'static str,
+    pub current: &'static str,
+    pub power: &'static str,
+}
+
+/// 各类物理量的发布单位与小数位数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputUnits {
+    pub voltage: Scale,
+    pub current: Scale,
+    pub power: Scale,
+    pub precision: usize,
+}
+
+impl Default for OutputUnits {
+    fn default() -> Self {
+        OutputUnits { voltage: Scale::Base, current: Scale::Base, power: Scale::Base, precision: 3 }
+    }
+}
+
+impl OutputUnits {
+    fn scale(&self, quantity: Quantity) -> Scale {
+        match quantity {
+            Quantity::Voltage => self.voltage,
+            Quantity::Current => self.current,
+            Quantity::Power => self.power,
+            Quantity::Other => Scale::Base,
+        }
+    }
+
+    /// 换算并按精度格式化；以 V / A / W 存储的数值不会被修改
+    pub fn format(&self, quantity: Quantity, value: f32) -> String {
+        let scaled = value as f64 * self.scale(quantity).factor();
+        let text = format!("{:.*}", self.precision, scaled);
+        // 舍入到 0 的负数不输出 "-0.000"
+        match text.strip_prefix('-') {
+            Some(rest) if rest.bytes().all(|b| b == b'0' || b == b'.') => rest.to_string(),
+            _ => text,
+        }
+    }
+
+    pub fn labels(&self) -> UnitLabels {
+        let label = |scale: Scale, base: &'static str, milli: &'static str| match scale {
+            Scale::Base => base,
+            Scale::Milli => milli,
+        };
+        UnitLabels {
+            voltage: label(self.voltage, "V", "mV"),
+            current: label(self.current, "A", "mA"),
+            power: label(self.power, "W", "mW"),
+        }
+    }
+
+    /// 按单位与精度改写 `measurements_all` 的 JSON，并加入 `units` 对象
+    pub fn apply_json(&self, json: &mut Value) {
+        for (pointer, quantity) in JSON_FIELDS {
+            if let Some(field) = json.pointer_mut(pointer) {
+                self.rewrite(field, *quantity);
+            }
+        }
+        if let Some(Value::Array(cells)) = json.pointer_mut("/data/bq76920/cell_voltages") {
+            for cell in cells {
+                self.rewrite(cell, Quantity::Voltage);
+            }
+        }
+        if let Value::Object(map) = json
+            && let Ok(units) = serde_json::to_value(self.labels())
+        {
+            map.insert("units".to_string(), units);
+        }
+    }
+
+    fn rewrite(&self, field: &mut Value, quantity: Quantity) {
+        let Some(value) = field.as_f64() else {
+            return;
+        };
+        // 经格式化后的文本取回数值，JSON 中不会出现 3.3000002 这类 f32 的尾数
+        if let Ok(rounded) = self.format(quantity, value as f32).parse::<f64>()
+            && let Some(number) = serde_json::Number::from_f64(rounded)
+        {
+            *field = Value::Number(number);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::PayloadBuilder;
+
+    fn milli(precision: usize) -> OutputUnits {
+        OutputUnits { voltage: Scale::Milli, current: Scale::Milli, power: Scale::Milli, precision }
+    }
+
+    #[test]
+    fn formatting_rounds_the_text_without_f32_noise() {
+        let units = OutputUnits::default();
+        assert_eq!(units.format(Quantity::Voltage, 3.3000002), "3.300");
+        assert_eq!(units.format(Quantity::Current, -1.2344), "-1.234");
+        assert_eq!(milli(0).format(Quantity::Voltage, 3.3000002), "3300");
+        assert_eq!(milli(1).format(Quantity::Power, 0.01234), "12.3");
+        // 温度等不换算单位
+        assert_eq!(milli(1).format(Quantity::Other, 25.04), "25.0");
+        // 舍入到 0 的负数不带符号
+        assert_eq!(units.format(Quantity::Current, -0.0004), "0.000");
+        assert_eq!(milli(0).format(Quantity::Current, -0.0004), "0");
+        assert_eq!(units.format(Quantity::Current, -0.0005), "-0.001");
+    }
+
+    #[test]
+    fn scales_parse_the_base_unit_or_its_milli_prefix() {
+        assert_eq!(Scale::parse("VOLTAGE_UNIT", "V", "V").unwrap(), Scale::Base);
+        assert_eq!(Scale::parse("VOLTAGE_UNIT", " v ", "V").unwrap(), Scale::Base);
+        assert_eq!(Scale::parse("VOLTAGE_UNIT", "mV", "V").unwrap(), Scale::Milli);
+        assert_eq!(Scale::parse("CURRENT_UNIT", "ma", "A").unwrap(), Scale::Milli);
+        // 大写 M 是兆，不是毫
+        for value in ["MV", "kV", "mmV", "", "volts"] {
+            let error = Scale::parse("VOLTAGE_UNIT", value, "V").unwrap_err();
+            assert!(matches!(error, ConfigError::Invalid { key: "VOLTAGE_UNIT", .. }), "{}: {:?}", value, error);
+        }
+    }
+
+    #[test]
+    fn labels_follow_each_scale() {
+        assert_eq!(OutputUnits::default().labels(), UnitLabels { voltage: "V", current: "A", power: "W" });
+        let mixed = OutputUnits { current: Scale::Milli, ..OutputUnits::default() };
+        assert_eq!(mixed.labels(), UnitLabels { voltage: "V", current: "mA", power: "W" });
+    }
+
+    #[test]
+    fn every_json_field_exists_in_a_full_sample() {
+        let mut sample = PayloadBuilder::new().sample(1, 1_700_000_000_000);
+        // 效率只在输入功率足够时输出
+        sample.derived.efficiency_percent = Some(90.0);
+        let json = serde_json::to_value(&sample).unwrap();
+        for (pointer, _) in JSON_FIELDS {
+            assert!(json.pointer(pointer).is_some(), "{} 不在 measurements_all 中", pointer);
+        }
+        assert!(json.pointer("/data/bq76920/cell_voltages").is_some_and(Value::is_array));
+    }
+
+    #[test]
+    fn json_values_are_scaled_and_rounded_without_touching_the_sample() {
+        let sample = PayloadBuilder::new().cell_mv(0, 3301).sample(1, 1_700_000_000_000);
+        let before = sample.data.bq76920.cell_voltages[0];
+        let mut json = serde_json::to_value(&sample).unwrap();
+        milli(0).apply_json(&mut json);
+
+        assert_eq!(json["units"], serde_json::json!({ "voltage": "mV", "current": "mA", "power": "mW" }));
+        assert_eq!(json["data"]["bq76920"]["cell_voltages"][0].as_f64(), Some(3301.0));
+        let vbus = json["data"]["bq25730"]["vbus"].as_f64().unwrap();
+        assert_eq!(vbus.fract(), 0.0);
+        assert!((vbus - sample.data.bq25730.vbus as f64 * 1000.0).abs() <= 0.5);
+        // 帧号与时间戳不属于物理量，保持原样
+        assert_eq!(json["frame_id"], 1);
+        assert_eq!(json["ts_unix_ms"], 1_700_000_000_000u64);
+        assert_eq!(sample.data.bq76920.cell_voltages[0], before);
+    }
+}
@@ -2,56 +2,205 @@ use std::io::Cursor;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use binrw::{BinRead, BinWrite};
-use log::{debug, error, info, warn};
+use binrw::BinWrite;
+use tracing::{debug, error, info, warn};
 use rusb::UsbContext;
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 
-use super::usb_types::{UsbCommand, UsbEvent, UsbError, UsbData}; // Removed 'as HostUsbData' and the incorrect import below
+use super::usb_types::{DeviceEvent, DeviceId, DeviceSelector, ProtocolVersion, UsbCommand, UsbId, UsbEvent, UsbError, UsbData, UsbLayout, UsbLinkStats, UsbMode, SUPPORTED_PROTOCOL_VERSION}; // Removed 'as HostUsbData' and the incorrect import below
+use crate::capture::CaptureSink;
+use crate::coalesce::{Emission, ErrorCoalescer};
+use crate::conversion::{self, ConversionContext};
+use crate::data_models::{DeviceInfo, HostSideUsbPayload, TimestampedMeasurements};
+use crate::debug_dump::DebugDump;
+use crate::diagnostics::check_payload;
+use crate::frame_id::FrameIdAllocator;
+use crate::framing::{self, FrameStatus};
+use crate::platform::{self, ConfigurationFailure};
+use crate::sequence::{FrameOrder, SequenceTracker};
+use crate::systemd::Heartbeat;
+use crate::timing::{Policy, UsbTimeouts};
+use crate::transport::{Endpoints, RusbTransport, UsbTransport};
+use crate::utils::unix_ms_now;
+
+// 推送读取的分段间隔，决定收到退出信号后多快放开设备句柄
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// 一帧的后续分片应紧随首个分片到达；超过这个间隔视为传输结束
+const FRAGMENT_TIMEOUT: Duration = Duration::from_millis(200);
+
+// 合并连续的同类错误事件：首次立即转发，重复次数在窗口结束时汇总，超过每分钟上限的只给出丢弃数。
+// 转发时附上设备标识
+async fn coalesce_error_events(
+    device: DeviceId,
+    mut rx: mpsc::Receiver<UsbEvent>,
+    tx: mpsc::Sender<DeviceEvent>,
+    mut coalescer: ErrorCoalescer,
+) {
+    let tag = |event| DeviceEvent { device: device.clone(), event };
+    loop {
+        let deadline = coalescer.deadline();
+        let events: Vec<UsbEvent> = tokio::select! {
+            event = rx.recv() => match event {
+                Some(UsbEvent::Error(e)) => {
+                    let category = e.category();
+                    let mut error = Some(e);
+                    coalescer
+                        .record(category, tokio::time::Instant::now())
+                        .into_iter()
+                        .filter_map(|emission| match emission {
+                            Emission::First => error.take().map(UsbEvent::Error),
+                            summary => summary_event(summary),
+                        })
+                        .collect()
+                }
+                Some(other) => vec![other],
+                None => {
+                    for event in coalescer.finish().into_iter().filter_map(summary_event) {
+                        let _ = tx.send(tag(event)).await;
+                    }
+                    return;
+                }
+            },
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)), if deadline.is_some() => {
+                coalescer.flush(tokio::time::Instant::now()).into_iter().filter_map(summary_event).collect()
+            }
+        };
+        for event in events {
+            if tx.send(tag(event)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn summary_event(emission: Emission) -> Option<UsbEvent> {
+    match emission {
+        Emission::First => None,
+        Emission::Repeated { category, count } => Some(UsbEvent::ErrorRepeated { category, count }),
+        Emission::Suppressed { count } => Some(UsbEvent::ErrorsSuppressed { count }),
+    }
+}
 
 // USB 连接和数据收发函数
-pub async fn connect_and_subscribe_usb(
-    handle: rusb::DeviceHandle<rusb::Context>, 
-    command_ep_address: u8,
-    response_ep_address: u8,
-) -> Result<rusb::DeviceHandle<rusb::Context>, UsbError> {
-    // Minor comment to force re-evaluation
+pub async fn connect_and_subscribe_usb<T: UsbTransport>(
+    transport: &T,
+    timeouts: &UsbTimeouts,
+    report_interval_ms: Option<u16>,
+) -> Result<ProtocolVersion, UsbError> {
+    let version = negotiate_protocol(transport, timeouts)?;
+    request_status(transport, &UsbData::SubscribeStatus, &version, timeouts)?;
+    info!("成功收到 StatusResponse 确认。");
+    if let Some(interval_ms) = report_interval_ms {
+        set_report_interval(transport, interval_ms, timeouts)?;
+    }
+    Ok(version)
+}
+
+/// 发送 SetReportInterval 并读取 ReportIntervalResponse，返回固件实际采用的间隔。旧固件不认识该命令，
+/// 不回复或回复其他内容时记录警告并返回 None，推送按固件默认的间隔继续
+pub fn set_report_interval<T: UsbTransport>(
+    transport: &T,
+    interval_ms: u16,
+    timeouts: &UsbTimeouts,
+) -> Result<Option<u16>, UsbError> {
+    let request = UsbData::SetReportInterval(interval_ms);
     let mut cmd_buffer = [0u8; 64];
     let mut writer = Cursor::new(&mut cmd_buffer[..]);
-    UsbData::SubscribeStatus.write_be(&mut writer).map_err(|e| UsbError::BinrwError(e.to_string()))?;
+    request.write_be(&mut writer)?;
     let cmd_len = writer.position() as usize;
+    if let Err(e) = transport.write_command(&cmd_buffer[..cmd_len], timeouts.command) {
+        error!("发送 {:?} 命令失败: {:?}", request, e);
+        return Err(UsbError::from(e));
+    }
 
-    match handle.write_interrupt(
-        command_ep_address,
-        &cmd_buffer[..cmd_len],
-        Duration::from_secs(5),
-    ) {
+    let mut resp_buf = [0u8; 256];
+    let n = match transport.read_response(&mut resp_buf, timeouts.response) {
+        Ok(n) => n,
+        Err(rusb::Error::Timeout) => {
+            warn!(interval_ms, "固件未响应 SetReportInterval，可能不支持该命令，按固件默认的推送间隔继续");
+            return Ok(None);
+        }
+        Err(e) => {
+            error!("读取 ReportIntervalResponse 失败: {:?}", e);
+            return Err(UsbError::ResponseReadFailed(e));
+        }
+    };
+    // 响应不含状态载荷，与协议版本无关
+    match framing::parse_frame(&resp_buf[..n], &ProtocolVersion::LEGACY) {
+        Ok((UsbData::ReportIntervalResponse { interval_ms: applied }, _)) => {
+            if applied != interval_ms {
+                warn!(requested = interval_ms, applied, "固件调整了推送间隔");
+            } else {
+                info!(interval_ms, "推送间隔已设置");
+            }
+            Ok(Some(applied))
+        }
+        other => {
+            warn!("SetReportInterval 收到意外的响应 ({:?})，按固件默认的推送间隔继续", other);
+            Ok(None)
+        }
+    }
+}
+
+/// 查询协议版本，高于支持的版本时拒绝继续
+pub fn negotiate_protocol<T: UsbTransport>(
+    transport: &T,
+    timeouts: &UsbTimeouts,
+) -> Result<ProtocolVersion, UsbError> {
+    let version = query_protocol_version(transport, timeouts)?;
+    if version.protocol > SUPPORTED_PROTOCOL_VERSION {
+        error!("设备协议版本 {} 高于支持的版本 {}，拒绝订阅", version.protocol, SUPPORTED_PROTOCOL_VERSION);
+        return Err(UsbError::UnsupportedProtocol {
+            device: version.protocol,
+            supported: SUPPORTED_PROTOCOL_VERSION,
+        });
+    }
+    Ok(version)
+}
+
+/// 发送一条请求 (SubscribeStatus 或 GetStatus)，从响应端点读取一个 StatusResponse，按 `protocol` 解析，
+/// 返回载荷、帧末尾被忽略的字节数与响应的原始帧。写入与读取分别使用 `timeouts.command` 与 `timeouts.response`，
+/// 读取超时返回 `UsbError::Timeout`
+pub fn request_status<T: UsbTransport>(
+    transport: &T,
+    request: &UsbData,
+    protocol: &ProtocolVersion,
+    timeouts: &UsbTimeouts,
+) -> Result<(HostSideUsbPayload, usize, Vec<u8>), UsbError> {
+    let mut cmd_buffer = [0u8; 64];
+    let mut writer = Cursor::new(&mut cmd_buffer[..]);
+    request.write_be(&mut writer)?;
+    let cmd_len = writer.position() as usize;
+
+    match transport.write_command(&cmd_buffer[..cmd_len], timeouts.command) {
         Ok(len_written) => {
-            info!("已发送 SubscribeStatus 命令 ({} bytes)", len_written);
+            debug!("已发送 {:?} 命令 ({} bytes)", request, len_written);
         }
         Err(e) => {
-            error!("发送 SubscribeStatus 命令失败: {:?}", e);
+            error!("发送 {:?} 命令失败: {:?}", request, e);
             return Err(UsbError::from(e));
         }
     };
 
-    info!("等待来自响应端点 {:#02x} 的 StatusResponse...", response_ep_address);
+    debug!("等待 StatusResponse...");
     let mut resp_buf = [0u8; 256];
-    match handle.read_interrupt(response_ep_address, &mut resp_buf, Duration::from_secs(5)) {
+    match transport.read_response(&mut resp_buf, timeouts.response) {
         Ok(n) => {
-            info!("从响应端点读取到 {} 字节。", n);
-            log::debug!("上位机接收用于响应的原始字节: {:x?}", &resp_buf[..n]);
-            match UsbData::read_le(&mut Cursor::new(&resp_buf[..n])) {
-                Ok(UsbData::StatusResponse(_measurements)) => {
-                    info!("成功收到 StatusResponse 确认。");
-                }
-                Ok(other_data) => {
+            debug!("从响应端点读取到 {} 字节: {:x?}", n, &resp_buf[..n]);
+            match framing::parse_frame(&resp_buf[..n], protocol) {
+                Ok((UsbData::StatusResponse(payload), ignored)) => Ok((payload, ignored, resp_buf[..n].to_vec())),
+                Ok((other_data, _)) => {
                     error!("收到意外的响应类型: {:?}", other_data);
-                    return Err(UsbError::UnexpectedResponse);
+                    Err(UsbError::UnexpectedResponse)
                 }
-                Err(e) => {
+                Err(UsbError::BinrwError(e)) => {
                     error!("解析 StatusResponse 失败: {:?}", e);
-                    return Err(UsbError::ResponseParseError(e.to_string()));
+                    Err(UsbError::ResponseParseError(e))
+                }
+                Err(e) => {
+                    error!("StatusResponse 长度不符: {}", e);
+                    Err(e)
                 }
             }
         }
@@ -60,94 +209,309 @@ pub async fn connect_and_subscribe_usb(
             if e == rusb::Error::Timeout {
                 return Err(UsbError::Timeout);
             }
-            return Err(UsbError::ResponseReadFailed(e.to_string()));
+            Err(UsbError::ResponseReadFailed(e))
         }
     }
-    Ok(handle)
 }
 
+/// 发送 GetVersion 并读取 VersionResponse。旧固件不认识该命令，不回复或回复其他内容时
+/// 按 `ProtocolVersion::LEGACY` 处理
+fn query_protocol_version<T: UsbTransport>(
+    transport: &T,
+    timeouts: &UsbTimeouts,
+) -> Result<ProtocolVersion, UsbError> {
+    let mut cmd_buffer = [0u8; 64];
+    let mut writer = Cursor::new(&mut cmd_buffer[..]);
+    UsbData::GetVersion.write_be(&mut writer)?;
+    let cmd_len = writer.position() as usize;
+    if let Err(e) = transport.write_command(&cmd_buffer[..cmd_len], timeouts.command) {
+        error!("发送 GetVersion 命令失败: {:?}", e);
+        return Err(UsbError::from(e));
+    }
+
+    let mut resp_buf = [0u8; 256];
+    let n = match transport.read_response(&mut resp_buf, timeouts.response) {
+        Ok(n) => n,
+        Err(rusb::Error::Timeout) => {
+            warn!("固件未响应 GetVersion，按旧固件处理 (协议版本 {})", ProtocolVersion::LEGACY.protocol);
+            return Ok(ProtocolVersion::LEGACY);
+        }
+        Err(e) => {
+            error!("读取 VersionResponse 失败: {:?}", e);
+            return Err(UsbError::ResponseReadFailed(e));
+        }
+    };
+    match framing::parse_frame(&resp_buf[..n], &ProtocolVersion::LEGACY) {
+        Ok((UsbData::VersionResponse { protocol, firmware }, _)) => {
+            let version = ProtocolVersion { protocol, firmware: Some(firmware) };
+            info!("设备版本: {}", version);
+            Ok(version)
+        }
+        other => {
+            warn!("GetVersion 收到意外的响应 ({:?})，按旧固件处理", other);
+            Ok(ProtocolVersion::LEGACY)
+        }
+    }
+}
+
+/// 各设备管理任务共用的参数
+#[derive(Clone)]
+pub struct UsbManagerSettings {
+    /// 按优先级排列的候选 VID/PID
+    pub usb_ids: Vec<UsbId>,
+    pub frame_ids: Arc<FrameIdAllocator>,
+    pub conversion_ctx: Arc<ConversionContext>,
+    pub error_coalesce_window: Duration,
+    pub error_max_per_minute: u32,
+    pub timing: Policy,
+    pub layout: UsbLayout,
+    pub mode: UsbMode,
+    /// 链路统计的发送周期
+    pub stats_interval: Duration,
+    /// 退出信号：中断进行中的推送读取与重试等待
+    pub shutdown: CancellationToken,
+    /// 阻塞读取返回、重试等待结束时跳动，供 systemd 看门狗判断 USB 线程没有卡死
+    pub heartbeat: Heartbeat,
+    /// `--capture`：记录所有收发的原始帧
+    pub capture: Option<CaptureSink>,
+    /// 运行时切换的原始帧转储
+    pub debug_dump: DebugDump,
+    /// 订阅后设置的推送间隔 (ms)；None 时使用固件默认值
+    pub report_interval: Option<u16>,
+}
+
+/// 管理一块设备的连接、订阅与数据接收；设备由注册表发现后按 `device` 打开
 pub async fn usb_manager_task(
-    usb_vid: u16,
-    usb_pid: u16,
+    device: DeviceId,
+    settings: UsbManagerSettings,
     mut cmd_rx: mpsc::Receiver<UsbCommand>,
-    event_tx: mpsc::Sender<UsbEvent>,
+    event_tx: mpsc::Sender<DeviceEvent>,
 ) {
+    let UsbManagerSettings { usb_ids, frame_ids, conversion_ctx, timing, layout, mode, stats_interval, shutdown, heartbeat, capture, debug_dump, report_interval, .. } = settings;
+    // `SetInterval` 命令修改后，之后的重连与重新订阅沿用新的间隔
+    let mut report_interval = report_interval;
+    let poll_interval = match mode {
+        UsbMode::Poll { interval } => Some(interval),
+        UsbMode::Push => None,
+    };
+    let selector = DeviceSelector::from(&device);
+
+    // 设备反复失败时错误事件可能刷屏；所有事件经合并任务转发，其余事件原样通过
+    let (raw_event_tx, raw_event_rx) = mpsc::channel::<UsbEvent>(32);
+    let error_coalescer = ErrorCoalescer::new(
+        settings.error_coalesce_window,
+        settings.error_max_per_minute,
+        tokio::time::Instant::now(),
+    );
+    tokio::spawn(coalesce_error_events(device.clone(), raw_event_rx, event_tx, error_coalescer));
+    let event_tx = raw_event_tx;
+
+    let mut link_stats = UsbLinkStats::default();
+    let mut connected_before = false;
+    let mut stats_timer = tokio::time::interval_at(tokio::time::Instant::now() + stats_interval, stats_interval);
+    stats_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
     loop {
+        heartbeat.beat();
         let usb_context = match rusb::Context::new() {
             Ok(ctx) => ctx,
             Err(e) => {
-                error!("创建 USB 上下文失败: {:?}, 10秒后重试...", e);
-                tokio::time::sleep(Duration::from_secs(10)).await;
+                error!("创建 USB 上下文失败: {:?}, {:?}后重试...", e, timing.usb_context_retry);
+                if !retry_after(&shutdown, timing.usb_context_retry).await {
+                    break;
+                }
                 continue;
             }
         };
 
-        let (handle_option, command_ep_address, response_ep_address_opt, push_ep_address_opt) =
-            match find_and_open_usb_device(&usb_context, usb_vid, usb_pid).await {
+        let (claimed, command_ep_address, response_ep_address, push_ep_address, device_id, usb_id) =
+            match find_and_open_usb_device(&usb_context, &usb_ids, &selector, &layout).await {
                 Ok(h_info) => h_info,
                 Err(e) => {
-                    error!("USB 设备查找或打开失败: {}, 25秒后重试...", e); // 增加重试延迟
-                    tokio::time::sleep(Duration::from_secs(25)).await; // 增加重试延迟
+                    error!(device = %device, error_kind = e.category(), error = %e, retry_in = ?timing.usb_open_retry, "USB 设备查找或打开失败，稍后重试");
+                    if !retry_after(&shutdown, timing.usb_open_retry).await {
+                        break;
+                    }
                     continue;
                 }
             };
-        
-        let mut current_handle = match handle_option { 
-            Some(h) => h,
-            None => { 
-                error!("find_and_open_usb_device 返回 None handle，这是不期望的。");
-                let _ = event_tx.send(UsbEvent::Error(UsbError::DeviceNotFound)).await;
-                tokio::time::sleep(Duration::from_secs(10)).await;
-                continue;
-            }
-        };
-        
-        let response_ep_address = match response_ep_address_opt {
-            Some(ep) => ep,
-            None => {
-                error!("未能获取响应端点地址，尝试重新连接USB...");
-                let _ = event_tx.send(UsbEvent::Error(UsbError::EndpointNotFound("响应端点未找到".to_string()))).await;
-                tokio::time::sleep(Duration::from_secs(5)).await;
-                continue;
-            }
-        };
-        let push_ep_address = match push_ep_address_opt {
-            Some(ep) => ep,
-            None => {
-                error!("未能获取推送端点地址，尝试重新连接USB...");
-                let _ = event_tx.send(UsbEvent::Error(UsbError::EndpointNotFound("推送端点未找到".to_string()))).await;
-                tokio::time::sleep(Duration::from_secs(5)).await;
-                continue;
-            }
+
+        let endpoints = Endpoints {
+            command: command_ep_address,
+            response: response_ep_address,
+            push: push_ep_address,
         };
+        let descriptors = read_string_descriptors(claimed.handle());
 
-        current_handle = match connect_and_subscribe_usb(current_handle, command_ep_address, response_ep_address).await {
-            Ok(h) => h,
-            Err(e) => { 
-                error!("USB 订阅失败: {}, 尝试重新连接USB...", e);
-                if let Err(send_err) = event_tx.send(UsbEvent::Error(e)).await { 
+        // 协议版本在每次连接时重新协商，后续用于选择载荷解析方式；轮询模式不订阅
+        let setup = match mode {
+            UsbMode::Push => connect_and_subscribe_usb(&transport(&claimed, endpoints, &capture), &timing.usb, report_interval).await,
+            UsbMode::Poll { .. } => negotiate_protocol(&transport(&claimed, endpoints, &capture), &timing.usb),
+        };
+        let protocol = match setup {
+            Ok(protocol) => protocol,
+            Err(e) => {
+                drop(claimed);
+                error!(device = %device, error_kind = e.category(), error = %e, "USB 订阅失败，尝试重新连接");
+                if let Err(send_err) = event_tx.send(UsbEvent::Error(e)).await {
                     error!("发送 USB 错误事件失败: {:?}", send_err);
                 }
-                tokio::time::sleep(Duration::from_secs(5)).await;
+                if !retry_after(&shutdown, timing.usb_setup_retry).await {
+                    break;
+                }
                 continue;
             }
         };
 
-        let handle_arc = Arc::new(Mutex::new(Some(current_handle)));
+        info!(device = %device_id, usb_id = %usb_id, reconnect = connected_before, "USB 设备已就绪");
+        if connected_before {
+            link_stats.record_reconnect();
+        }
+        connected_before = true;
+        let usb_device = claimed.handle().device();
+        let (manufacturer, product, serial) = descriptors;
+        let device_info = DeviceInfo {
+            device_id: device_id.to_string(),
+            usb_id: usb_id.to_string(),
+            bus: usb_device.bus_number(),
+            address: usb_device.address(),
+            manufacturer,
+            product,
+            serial,
+            protocol_version: protocol.protocol,
+            firmware_version: protocol.firmware_string(),
+        };
+        if let Err(e) = event_tx.send(UsbEvent::Connected { device_id, usb_id, protocol }).await {
+            error!("发送 USB 连接事件失败: {:?}", e);
+        }
+        if let Err(e) = event_tx.send(UsbEvent::DeviceInfo(device_info)).await {
+            error!("发送设备信息事件失败: {:?}", e);
+        }
+
+        let handle_arc = Arc::new(Mutex::new(Some(claimed)));
         let read_buffer_arc = Arc::new(Mutex::new(vec![0u8; 256]));
+        // 看门狗：固件偶尔卡死，连接仍在但不再推送。超时后先重发订阅，仍无数据再完整重连
+        let mut watchdog = StaleWatchdog::new(timing.usb.stale_timeout, tokio::time::Instant::now());
+        let mut sequence = SequenceTracker::default();
+        // 推送模式下不使用，间隔只为构造定时器
+        let mut poll_timer = tokio::time::interval(poll_interval.unwrap_or(timing.usb.push_read));
+        poll_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
         loop {
+            let stale = match poll_interval {
+                None if !shutdown.is_cancelled() => watchdog.check(tokio::time::Instant::now()),
+                _ => StaleAction::None,
+            };
+            if stale != StaleAction::None {
+                if stale == StaleAction::Reconnect {
+                    error!(device = %device, stale_timeout = ?timing.usb.stale_timeout, "重新订阅后仍无推送数据，重新连接 USB 设备");
+                    link_stats.record_timeout();
+                    if let Err(e) = event_tx.send(UsbEvent::Disconnected(UsbError::Timeout)).await {
+                        error!("发送 USB 断开事件失败: {:?}", e);
+                    }
+                    break;
+                }
+                warn!(device = %device, since_last_push = ?watchdog.last_push.elapsed(), "没有收到推送数据，尝试重新订阅");
+                if let Err(e) = event_tx.send(UsbEvent::Stale).await {
+                    error!("发送 USB 数据过期事件失败: {:?}", e);
+                }
+                // 此时没有进行中的推送读取，句柄可以直接取出
+                let Some(claimed) = handle_arc.lock().unwrap().take() else {
+                    break;
+                };
+                let result = connect_and_subscribe_usb(&transport(&claimed, endpoints, &capture), &timing.usb, report_interval).await;
+                *handle_arc.lock().unwrap() = Some(claimed);
+                if let Err(e) = result {
+                    error!(device = %device, error_kind = e.category(), error = %e, "重新订阅失败，尝试重新连接");
+                    link_stats.record_error(&e);
+                    if let Err(send_err) = event_tx.send(UsbEvent::Disconnected(e)).await {
+                        error!("发送 USB 断开事件失败: {:?}", send_err);
+                    }
+                    break;
+                }
+                watchdog.on_resubscribed(tokio::time::Instant::now());
+            }
+
             tokio::select! {
+                _ = stats_timer.tick() => {
+                    if let Err(e) = event_tx.send(UsbEvent::Stats(link_stats.clone())).await {
+                        error!("发送 USB 链路统计失败: {:?}", e);
+                    }
+                }
                 cmd = cmd_rx.recv() => {
                     match cmd {
                         Some(UsbCommand::Subscribe) => {
                             info!("USB 管理任务收到订阅命令。尝试重新连接并订阅...");
                             break; 
                         }
-                        Some(UsbCommand::Unsubscribe) => { 
-                            info!("USB 管理任务收到取消订阅命令 (placeholder logic)。");
-                            let _ = event_tx.send(UsbEvent::Error(UsbError::Other("Unsubscribe not fully implemented yet".to_string()))).await;
-                            break; 
+                        Some(UsbCommand::Reconnect) => {
+                            info!("USB 管理任务收到重连命令。");
+                            break;
+                        }
+                        Some(UsbCommand::SetInterval(interval_ms)) => {
+                            info!(interval_ms, "USB 管理任务收到设置推送间隔命令。");
+                            report_interval = Some(interval_ms);
+                            let handle_clone = Arc::clone(&handle_arc);
+                            let timeouts = timing.usb;
+                            let capture = capture.clone();
+                            // 推送读取可能仍持有句柄，等它返回后再发送
+                            let result = tokio::task::spawn_blocking(move || match handle_clone.lock().unwrap().as_ref() {
+                                Some(claimed) => set_report_interval(&transport(claimed, endpoints, &capture), interval_ms, &timeouts),
+                                None => Err(UsbError::from(rusb::Error::NoDevice)),
+                            })
+                            .await
+                            .unwrap_or_else(|e| Err(UsbError::TaskFailed(e)));
+                            if let Err(e) = result {
+                                error!(device = %device, error_kind = e.category(), error = %e, "设置推送间隔失败");
+                                link_stats.record_error(&e);
+                                if let Err(send_err) = event_tx.send(UsbEvent::Error(e)).await {
+                                    error!("发送 USB 错误事件失败: {:?}", send_err);
+                                }
+                            }
+                        }
+                        Some(UsbCommand::Unsubscribe) => {
+                            info!("USB 管理任务收到取消订阅命令，通知设备停止推送...");
+                            let handle_clone = Arc::clone(&handle_arc);
+                            let timeouts = timing.usb;
+                            let capture = capture.clone();
+                            // 推送读取可能仍持有句柄，等它返回后再取出
+                            let result = tokio::task::spawn_blocking(move || {
+                                let claimed = handle_clone
+                                    .lock()
+                                    .unwrap()
+                                    .take()
+                                    .ok_or_else(|| UsbError::Other("device handle already released".to_string()))?;
+                                unsubscribe_and_release(claimed, endpoints, &timeouts, capture)
+                            })
+                            .await
+                            .unwrap_or_else(|e| Err(UsbError::TaskFailed(e)));
+                            if let Err(e) = result {
+                                error!("取消订阅失败: {}", e);
+                                let _ = event_tx.send(UsbEvent::Error(e)).await;
+                            }
+                            if let Err(e) = event_tx.send(UsbEvent::Unsubscribed).await {
+                                error!("发送取消订阅完成事件失败: {:?}", e);
+                            }
+                            // 保持空闲，直到收到订阅或重连命令再重新连接；空闲时没有阻塞调用，按周期跳动心跳
+                            let mut idle_timer = tokio::time::interval(timing.usb.push_read);
+                            loop {
+                                tokio::select! {
+                                    command = cmd_rx.recv() => match command {
+                                        Some(UsbCommand::Subscribe) | Some(UsbCommand::Reconnect) => break,
+                                        Some(UsbCommand::Unsubscribe) => debug!("已取消订阅，忽略重复的取消订阅命令。"),
+                                        Some(UsbCommand::SetInterval(interval_ms)) => {
+                                            debug!(interval_ms, "已取消订阅，推送间隔在重新订阅时设置。");
+                                            report_interval = Some(interval_ms);
+                                        }
+                                        None => {
+                                            info!("命令通道关闭，USB 管理任务退出。");
+                                            return;
+                                        }
+                                    },
+                                    _ = idle_timer.tick() => heartbeat.beat(),
+                                }
+                            }
+                            break;
                         }
                         None => {
                             info!("命令通道关闭，USB 管理任务退出。");
@@ -155,65 +519,93 @@ pub async fn usb_manager_task(
                         }
                     }
                 }
+                _ = poll_timer.tick(), if poll_interval.is_some() && !shutdown.is_cancelled() => {
+                    let handle_clone = Arc::clone(&handle_arc);
+                    let timeouts = timing.usb;
+                    let capture = capture.clone();
+                    let result = tokio::task::spawn_blocking(move || match handle_clone.lock().unwrap().as_ref() {
+                        Some(claimed) => request_status(&transport(claimed, endpoints, &capture), &UsbData::GetStatus, &protocol, &timeouts),
+                        None => Err(UsbError::from(rusb::Error::NoDevice)),
+                    })
+                    .await
+                    .unwrap_or_else(|e| Err(UsbError::TaskFailed(e)));
+                    heartbeat.beat();
+                    match result {
+                        Ok((payload, ignored, raw)) => {
+                            link_stats.record_frame(1 + payload.encoded_len() + ignored);
+                            link_stats.record_trailing(ignored);
+                            link_stats.record_push(std::time::Instant::now());
+                            if accept_frame(&mut sequence, &payload, &mut link_stats) {
+                                emit_status(&payload, &raw, unix_ms_now(), &frame_ids, &conversion_ctx, &event_tx).await;
+                            }
+                        }
+                        Err(e) => {
+                            error!(device = %device, error_kind = e.category(), error = %e, "轮询设备状态失败，尝试重新连接");
+                            link_stats.record_error(&e);
+                            if let Err(send_err) = event_tx.send(UsbEvent::Disconnected(e)).await {
+                                error!("发送 USB 断开事件失败: {:?}", send_err);
+                            }
+                            break;
+                        }
+                    }
+                }
                 read_result = async {
                     debug!("尝试从 USB IN 端点 {:#02x} 读取数据...", push_ep_address);
                     let handle_clone = Arc::clone(&handle_arc);
                     let read_buffer_clone = Arc::clone(&read_buffer_arc);
-                    let push_ep_address_clone = push_ep_address;
-                    let read_timeout = Duration::from_secs(10);
+                    let read_timeout = timing.usb.push_read;
+                    let shutdown_clone = shutdown.clone();
+                    let capture = capture.clone();
 
                     tokio::task::spawn_blocking(move || {
                         let mut locked_handle_option = handle_clone.lock().unwrap();
-                        if let Some(handle_inner) = locked_handle_option.as_mut() { 
+                        if let Some(claimed) = locked_handle_option.as_mut() {
                             let mut locked_buf = read_buffer_clone.lock().unwrap();
-                            handle_inner.read_interrupt(push_ep_address_clone, &mut locked_buf, read_timeout)
+                            read_frame(&transport(claimed, endpoints, &capture), &mut locked_buf, read_timeout, &shutdown_clone)
                         } else {
                             Err(rusb::Error::NoDevice) 
                         }
-                    }).await.unwrap_or_else(|_join_error| Err(rusb::Error::Other)) 
-                } => {
+                    }).await.unwrap_or(Err(rusb::Error::Other)) 
+                }, if poll_interval.is_none() && !shutdown.is_cancelled() => {
+                    heartbeat.beat();
                     match read_result {
                         Ok(n) => {
                             if n == 0 {
                                 debug!("从 USB IN 端点 {:#02x} 读取到 0 字节数据，可能为正常轮询。", push_ep_address);
                                 continue; 
                             }
+                            // 时间戳在收到推送时记录，而不是在 MQTT 发布时，避免缓冲延迟影响
+                            let received_unix_ms = unix_ms_now();
                             debug!("成功从 USB IN 端点 {:#02x} 读取到 {} 字节数据。", push_ep_address, n);
-                            let measurements_result = {
-                                let locked_buf = read_buffer_arc.lock().unwrap();
-                                // 日志点1: 提升日志级别并确保打印
-                                info!("[LOG POINT 1] 上位机接收推送原始字节 ({} bytes): {:x?}", n, &locked_buf[..n]);
-                                let mut reader = Cursor::new(&locked_buf[..n]);
-                                UsbData::read_le(&mut reader)
+                            let frame = read_buffer_arc.lock().unwrap()[..n].to_vec();
+                            let mut push = PushContext {
+                                device: &device,
+                                frame_ids: &frame_ids,
+                                conversion_ctx: &conversion_ctx,
+                                protocol,
+                                event_tx: &event_tx,
+                                sequence: &mut sequence,
+                                link_stats: &mut link_stats,
+                                debug_dump: &debug_dump,
                             };
-
-                            match measurements_result {
-                                Ok(UsbData::StatusPush(measurements)) => {
-                                    // 日志点2: 打印解析后的数据
-                                    info!("[LOG POINT 2] USB 数据解析成功: {:?}", measurements);
-                                    if let Err(e) = event_tx.send(UsbEvent::Measurements(measurements)).await {
-                                        error!("发送 USB 测量数据失败: {:?}", e);
-                                    }
-                                }
-                                Ok(other_data) => {
-                                    warn!("收到非 StatusPush 的 USB 数据类型: {:?}", other_data);
-                                    if let Err(e) = event_tx.send(UsbEvent::Error(UsbError::UnexpectedResponse)).await {
-                                        error!("发送 USB 错误事件失败: {:?}", e);
-                                    }
-                                }
-                                Err(e) => {
-                                    error!("USB 推送数据解析失败: {:?}", e);
-                                    if let Err(send_err) = event_tx.send(UsbEvent::Error(UsbError::BinrwError(e.to_string()))).await {
-                                        error!("发送 USB 解析错误事件失败: {:?}", send_err);
-                                    }
-                                }
+                            if handle_push_frame(&frame, received_unix_ms, &mut push).await {
+                                watchdog.on_push(tokio::time::Instant::now());
                             }
                         }
+                        // 读取超时只说明这段时间内没有推送，由看门狗判断是否过期
+                        Err(rusb::Error::Timeout) => {
+                            debug!("USB 推送端点 {:#02x} 暂无数据", push_ep_address);
+                            link_stats.record_timeout();
+                        }
+                        // 收到退出信号，读取提前结束；保持连接，等待主循环的取消订阅命令
+                        Err(rusb::Error::Interrupted) if shutdown.is_cancelled() => {
+                            debug!("推送读取因退出信号中断");
+                        }
                         Err(e) => {
-                            error!("USB 读取失败: {:?}", e);
+                            error!(device = %device, error_kind = "rusb", error = ?e, "USB 读取失败");
                             let usb_error = UsbError::from(e); 
-                            if let Err(send_err) = event_tx.send(UsbEvent::Error(usb_error)).await {
-                                error!("发送 USB 读取错误事件失败: {:?}", send_err);
+                            if let Err(send_err) = event_tx.send(UsbEvent::Disconnected(usb_error)).await {
+                                error!("发送 USB 断开事件失败: {:?}", send_err);
                             }
                             break; 
                         }
@@ -221,36 +613,428 @@ pub async fn usb_manager_task(
                 }
             }
         }
+        // 重新打开设备前先释放旧句柄的接口；进行中的推送读取返回后才能取出句柄
+        let _ = tokio::task::spawn_blocking(move || drop(handle_arc.lock().unwrap().take())).await;
     }
+    info!("收到退出信号，USB 管理任务退出。");
+    let _ = event_tx.send(UsbEvent::Detached).await;
 }
 
+/// 处理一帧推送数据所需的状态；USB 管理任务与回放共用
+pub struct PushContext<'a> {
+    pub device: &'a DeviceId,
+    pub frame_ids: &'a FrameIdAllocator,
+    pub conversion_ctx: &'a ConversionContext,
+    /// 握手得到的协议版本，决定载荷是否带帧序号
+    pub protocol: ProtocolVersion,
+    pub event_tx: &'a mpsc::Sender<UsbEvent>,
+    pub sequence: &'a mut SequenceTracker,
+    pub link_stats: &'a mut UsbLinkStats,
+    pub debug_dump: &'a DebugDump,
+}
+
+/// 处理从推送端点读到的一帧：长度检查、解析、序号判断与转换，结果或错误以事件发给主循环。
+/// 收到有效的 StatusPush（无论是否因重复被丢弃）时返回 true；任意无法解析的字节只产生错误事件，
+/// 不会 panic，也不会让读取循环断开重连
+pub async fn handle_push_frame(frame: &[u8], received_unix_ms: u64, ctx: &mut PushContext<'_>) -> bool {
+    let device = ctx.device;
+    let event_tx = ctx.event_tx;
+    ctx.link_stats.record_frame(frame.len());
+    ctx.debug_dump.dump(device, frame, event_tx).await;
+    if let FrameStatus::Incomplete { got, expected } = framing::frame_status(frame) {
+        warn!(
+            device = %device,
+            error_kind = "incomplete_payload",
+            magic = frame[0],
+            got,
+            expected,
+            head = %framing::hex_head(frame),
+            "USB 推送数据不完整，丢弃"
+        );
+        report_frame_error(UsbError::IncompletePayload { got, expected }, ctx).await;
+        return false;
+    }
+    // 日志点1: 提升日志级别并确保打印
+    debug!("[LOG POINT 1] 上位机接收推送原始字节 ({} bytes): {:x?}", frame.len(), frame);
+    match framing::parse_frame(frame, &ctx.protocol) {
+        Ok((UsbData::StatusPush(payload), ignored)) => {
+            ctx.link_stats.record_trailing(ignored);
+            ctx.link_stats.record_push(std::time::Instant::now());
+            if accept_frame(ctx.sequence, &payload, ctx.link_stats) {
+                emit_status(&payload, frame, received_unix_ms, ctx.frame_ids, ctx.conversion_ctx, event_tx).await;
+            }
+            true
+        }
+        Ok((other_data, _)) => {
+            warn!(device = %device, error_kind = "unexpected_response", data = ?other_data, "收到非 StatusPush 的 USB 数据");
+            report_frame_error(UsbError::UnexpectedResponse, ctx).await;
+            false
+        }
+        Err(usb_error) => {
+            match &usb_error {
+                UsbError::LengthMismatch { .. } => {
+                    warn!(device = %device, error_kind = usb_error.category(), error = %usb_error, "USB 推送数据长度不符，丢弃")
+                }
+                _ => error!(device = %device, error_kind = usb_error.category(), error = %usb_error, "USB 推送数据解析失败"),
+            }
+            report_frame_error(usb_error, ctx).await;
+            false
+        }
+    }
+}
+
+// 无法使用的推送帧只计入统计并上报，读取循环继续，不因此重新连接
+async fn report_frame_error(usb_error: UsbError, ctx: &mut PushContext<'_>) {
+    ctx.link_stats.record_error(&usb_error);
+    if let Err(e) = ctx.event_tx.send(UsbEvent::Error(usb_error)).await {
+        error!("发送 USB 错误事件失败: {:?}", e);
+    }
+}
+
+/// 按序号（或内容）判断重复与乱序，计入链路统计；返回 false 表示丢弃该帧
+fn accept_frame(sequence: &mut SequenceTracker, payload: &HostSideUsbPayload, link_stats: &mut UsbLinkStats) -> bool {
+    let order = sequence.check(payload);
+    link_stats.record_order(order);
+    match order {
+        FrameOrder::Next => {}
+        FrameOrder::Gap { missed } => warn!("USB 帧序号跳过 {} 帧 (当前 {:?})，可能丢帧", missed, payload.sequence),
+        FrameOrder::Duplicate => debug!("丢弃重复的 USB 帧 (序号 {:?})", payload.sequence),
+        FrameOrder::OutOfOrder => warn!("丢弃乱序的 USB 帧 (序号 {:?})", payload.sequence),
+    }
+    !order.is_dropped()
+}
+
+/// 把一帧状态载荷转换为测量数据发给主循环，样本附带原始帧 `raw`。异常检查在原始载荷上进行，
+/// 转换后的 AllMeasurements 已丢失原始位
+async fn emit_status(
+    payload: &HostSideUsbPayload,
+    raw: &[u8],
+    received_unix_ms: u64,
+    frame_ids: &FrameIdAllocator,
+    conversion_ctx: &ConversionContext,
+    event_tx: &mpsc::Sender<UsbEvent>,
+) {
+    let frame_id = frame_ids.next_id();
+    for anomaly in check_payload(payload, conversion_ctx.cell_count) {
+        warn!("USB 推送数据异常 (frame {}): {}", frame_id, anomaly);
+        if let Err(e) = event_tx.send(UsbEvent::Anomaly { frame_id, anomaly }).await {
+            error!("发送 USB 异常事件失败: {:?}", e);
+        }
+    }
+    let measurements = conversion::to_measurements(payload, conversion_ctx);
+    // 日志点2: 打印解析后的数据
+    debug!("[LOG POINT 2] USB 数据解析成功 (frame {}): {:?}", frame_id, measurements);
+    let sample = TimestampedMeasurements {
+        frame_id,
+        ts_unix_ms: received_unix_ms,
+        derived: measurements.bq76920.derived(),
+        data: measurements,
+        battery: Default::default(),
+        raw: Some(raw.into()),
+    };
+    if let Err(e) = event_tx.send(UsbEvent::Measurements(Arc::new(sample))).await {
+        error!("发送 USB 测量数据失败: {:?}", e);
+    }
+}
+
+fn transport<'a>(claimed: &'a ClaimedInterface, endpoints: Endpoints, capture: &Option<CaptureSink>) -> RusbTransport<'a> {
+    RusbTransport::new(claimed.handle(), endpoints).with_capture(capture.clone())
+}
+
+// 等待重试间隔；期间收到退出信号时返回 false
+/// 看门狗对推送数据过期的处理
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StaleAction {
+    None,
+    /// 超过 `stale_timeout` 没有推送，重新订阅一次
+    Resubscribe,
+    /// 重新订阅后又过了 `stale_timeout` 仍无推送，重新连接
+    Reconnect,
+}
+
+// 推送模式下跟踪最近一次有效推送的时间，时间由调用方传入
+#[derive(Debug)]
+struct StaleWatchdog {
+    timeout: Duration,
+    last_push: tokio::time::Instant,
+    resubscribed: bool,
+}
+
+impl StaleWatchdog {
+    fn new(timeout: Duration, now: tokio::time::Instant) -> Self {
+        StaleWatchdog { timeout, last_push: now, resubscribed: false }
+    }
+
+    fn check(&self, now: tokio::time::Instant) -> StaleAction {
+        if now.saturating_duration_since(self.last_push) < self.timeout {
+            StaleAction::None
+        } else if self.resubscribed {
+            StaleAction::Reconnect
+        } else {
+            StaleAction::Resubscribe
+        }
+    }
+
+    fn on_push(&mut self, now: tokio::time::Instant) {
+        self.last_push = now;
+        self.resubscribed = false;
+    }
+
+    fn on_resubscribed(&mut self, now: tokio::time::Instant) {
+        self.last_push = now;
+        self.resubscribed = true;
+    }
+}
+
+async fn retry_after(shutdown: &CancellationToken, delay: Duration) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(delay) => true,
+        _ = shutdown.cancelled() => false,
+    }
+}
+
+// 阻塞读取不能从外部打断，因此按较短的间隔分段读取，每段之间检查退出信号。
+// 整体仍以 `timeout` 为限；收到退出信号时返回 `Interrupted`。
+fn read_push_cancellable<T: UsbTransport>(
+    transport: &T,
+    buf: &mut [u8],
+    timeout: Duration,
+    shutdown: &CancellationToken,
+) -> rusb::Result<usize> {
+    let deadline = std::time::Instant::now() + timeout;
+    loop {
+        if shutdown.is_cancelled() {
+            return Err(rusb::Error::Interrupted);
+        }
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        if remaining.is_zero() {
+            return Err(rusb::Error::Timeout);
+        }
+        match transport.read_push(buf, remaining.min(READ_POLL_INTERVAL)) {
+            Err(rusb::Error::Timeout) => continue,
+            result => return result,
+        }
+    }
+}
+
+/// 读取一整帧：首个分片按推送超时等待，之后按帧头判断的长度继续读取后续分片，
+/// 直到收齐、收到零长度包或分片间隔超时。返回已读取的总字节数，是否完整由调用方判断
+pub fn read_frame<T: UsbTransport>(
+    transport: &T,
+    buf: &mut [u8],
+    timeout: Duration,
+    shutdown: &CancellationToken,
+) -> rusb::Result<usize> {
+    let mut len = read_push_cancellable(transport, buf, timeout, shutdown)?;
+    while matches!(framing::frame_status(&buf[..len]), FrameStatus::Incomplete { .. }) && len < buf.len() {
+        match read_push_cancellable(transport, &mut buf[len..], FRAGMENT_TIMEOUT, shutdown) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(rusb::Error::Timeout) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// 同一主机上有多块板子时用序列号区分；读取失败或为空时退回总线号与地址
+pub fn read_device_id(device: &rusb::Device<rusb::Context>, handle: &rusb::DeviceHandle<rusb::Context>) -> DeviceId {
+    let serial = device
+        .device_descriptor()
+        .and_then(|desc| handle.read_serial_number_string_ascii(&desc));
+    device_id_from_serial(serial, device.bus_number(), device.address())
+}
+
+/// 序列号可用时以序列号标识设备；读取失败或为空时退回 `bus{N}-addr{M}`
+fn device_id_from_serial(serial: rusb::Result<String>, bus: u8, address: u8) -> DeviceId {
+    match serial {
+        Ok(serial) if !serial.trim().is_empty() => DeviceId::Serial(serial.trim().to_string()),
+        Ok(_) => DeviceId::BusAddr { bus, address },
+        Err(e) => {
+            warn!("读取 USB 序列号失败: {:?}，使用总线号与地址标识设备。", e);
+            DeviceId::BusAddr { bus, address }
+        }
+    }
+}
+
+/// 读取厂商、产品与序列号字符串描述符；读取失败或为空的项为 None
+fn read_string_descriptors(handle: &rusb::DeviceHandle<rusb::Context>) -> (Option<String>, Option<String>, Option<String>) {
+    let desc = match handle.device().device_descriptor() {
+        Ok(desc) => desc,
+        Err(e) => {
+            warn!("读取设备描述符失败: {:?}", e);
+            return (None, None, None);
+        }
+    };
+    let read = |name: &str, result: rusb::Result<String>| match result {
+        Ok(value) if !value.trim().is_empty() => Some(value.trim().to_string()),
+        Ok(_) => None,
+        Err(e) => {
+            debug!("读取{}字符串描述符失败: {:?}", name, e);
+            None
+        }
+    };
+    (
+        read("厂商", handle.read_manufacturer_string_ascii(&desc)),
+        read("产品", handle.read_product_string_ascii(&desc)),
+        read("序列号", handle.read_serial_number_string_ascii(&desc)),
+    )
+}
+
+type Candidate = (rusb::Device<rusb::Context>, UsbId);
+type SelectedDevice = (rusb::Device<rusb::Context>, rusb::DeviceHandle<rusb::Context>, DeviceId, UsbId);
+
+// 按 USB_SERIAL / USB_BUS_ADDR 从匹配 VID/PID 的设备中选出一个并打开
+fn select_device(
+    candidates: Vec<Candidate>,
+    selector: &DeviceSelector,
+) -> Result<SelectedDevice, UsbError> {
+    match selector {
+        DeviceSelector::Any => {
+            let (device, usb_id) = candidates.into_iter().next().expect("candidates is not empty");
+            let handle = device.open().map_err(UsbError::OpenFailed)?;
+            let device_id = read_device_id(&device, &handle);
+            Ok((device, handle, device_id, usb_id))
+        }
+        DeviceSelector::BusAddr { bus, address } => {
+            let available: Vec<String> = candidates
+                .iter()
+                .map(|(d, _)| format!("{}:{}", d.bus_number(), d.address()))
+                .collect();
+            let Some((device, usb_id)) = candidates
+                .into_iter()
+                .find(|(d, _)| d.bus_number() == *bus && d.address() == *address)
+            else {
+                return Err(UsbError::DeviceNotFound(format!(
+                    "no device at bus {}:{} (available: {})",
+                    bus,
+                    address,
+                    available.join(", ")
+                )));
+            };
+            let handle = device.open().map_err(UsbError::OpenFailed)?;
+            let device_id = read_device_id(&device, &handle);
+            Ok((device, handle, device_id, usb_id))
+        }
+        DeviceSelector::Serial(wanted) => {
+            // 必须打开设备才能读取序列号；不匹配的句柄随即关闭
+            let mut available = Vec::new();
+            for (device, usb_id) in candidates {
+                let handle = match device.open() {
+                    Ok(handle) => handle,
+                    Err(e) => {
+                        warn!("无法打开 USB 设备 (Bus: {}, Addr: {}) 读取序列号: {}", device.bus_number(), device.address(), e);
+                        available.push(format!("<bus {}:{} unreadable>", device.bus_number(), device.address()));
+                        continue;
+                    }
+                };
+                match read_device_id(&device, &handle) {
+                    DeviceId::Serial(serial) if serial == *wanted => {
+                        return Ok((device, handle, DeviceId::Serial(serial), usb_id));
+                    }
+                    device_id => {
+                        info!("跳过 USB 设备 {} (Bus: {}, Addr: {})", device_id, device.bus_number(), device.address());
+                        available.push(device_id.to_string());
+                    }
+                }
+            }
+            Err(UsbError::DeviceNotFound(format!(
+                "no device with serial '{}' (available: {})",
+                wanted,
+                available.join(", ")
+            )))
+        }
+    }
+}
+
+/// 已声明的 USB 接口。drop 时释放接口，若打开设备时分离过内核驱动则重新附加，
+/// 出错、重连与退出都经过同一清理路径。
+pub struct ClaimedInterface {
+    handle: rusb::DeviceHandle<rusb::Context>,
+    interface_number: u8,
+    reattach_kernel_driver: bool,
+    released: bool,
+}
+
+impl ClaimedInterface {
+    pub fn new(handle: rusb::DeviceHandle<rusb::Context>, interface_number: u8, reattach_kernel_driver: bool) -> Self {
+        ClaimedInterface {
+            handle,
+            interface_number,
+            reattach_kernel_driver,
+            released: false,
+        }
+    }
+
+    pub fn handle(&self) -> &rusb::DeviceHandle<rusb::Context> {
+        &self.handle
+    }
+
+    /// 显式释放并返回释放接口的错误；drop 时的清理只记录日志
+    pub fn release(mut self) -> Result<(), UsbError> {
+        self.cleanup()
+    }
+
+    fn cleanup(&mut self) -> Result<(), UsbError> {
+        if self.released {
+            return Ok(());
+        }
+        self.released = true;
+        let released = self.handle.release_interface(self.interface_number);
+        match &released {
+            Ok(()) => info!("已释放 USB 接口 {}。", self.interface_number),
+            // 设备已拔出时释放必然失败，不影响后续重新打开
+            Err(e) => warn!("释放 USB 接口 {} 失败: {:?}", self.interface_number, e),
+        }
+        if self.reattach_kernel_driver {
+            match self.handle.attach_kernel_driver(self.interface_number) {
+                Ok(()) => info!("已重新附加接口 {} 的内核驱动。", self.interface_number),
+                Err(e) => warn!("重新附加接口 {} 的内核驱动失败: {:?}", self.interface_number, e),
+            }
+        }
+        released.map_err(UsbError::from)
+    }
+}
+
+impl Drop for ClaimedInterface {
+    fn drop(&mut self) {
+        let _ = self.cleanup();
+    }
+}
+
+type OpenedDevice = (ClaimedInterface, u8, u8, u8, DeviceId, UsbId);
+
 pub async fn find_and_open_usb_device(
     context: &rusb::Context,
-    vid: u16,
-    pid: u16,
-) -> Result<(Option<rusb::DeviceHandle<rusb::Context>>, u8, Option<u8>, Option<u8>), UsbError> {
+    usb_ids: &[UsbId],
+    selector: &DeviceSelector,
+    layout: &UsbLayout,
+) -> Result<OpenedDevice, UsbError> {
     let device_list = context.devices().map_err(UsbError::from)?;
-    let mut device_found_rusb = None;
-
-    for device_rusb in device_list.iter() {
-        let device_desc = device_rusb.device_descriptor().map_err(UsbError::from)?;
-        if device_desc.vendor_id() == vid && device_desc.product_id() == pid {
-            info!(
-                "找到 USB 设备: {:04x}:{:04x} (Bus: {}, Addr: {})",
-                device_desc.vendor_id(),
-                device_desc.product_id(),
-                device_rusb.bus_number(),
-                device_rusb.address()
-            );
-            device_found_rusb = Some(device_rusb);
-            break;
+    // 候选设备按 VID/PID 的优先级排列，同一优先级内保持枚举顺序
+    let mut candidates = Vec::new();
+    for usb_id in usb_ids {
+        for device_rusb in device_list.iter() {
+            let device_desc = device_rusb.device_descriptor().map_err(UsbError::from)?;
+            if device_desc.vendor_id() == usb_id.vid && device_desc.product_id() == usb_id.pid {
+                info!(
+                    "找到 USB 设备: {} (Bus: {}, Addr: {})",
+                    usb_id,
+                    device_rusb.bus_number(),
+                    device_rusb.address()
+                );
+                candidates.push((device_rusb, *usb_id));
+            }
         }
     }
+    if candidates.is_empty() {
+        let ids: Vec<String> = usb_ids.iter().map(UsbId::to_string).collect();
+        return Err(UsbError::DeviceNotFound(format!("no device matching {} connected", ids.join(", "))));
+    }
 
-    let device_rusb = device_found_rusb.ok_or(UsbError::DeviceNotFound)?;
-
-    let handle = device_rusb.open().map_err(|e| UsbError::OpenFailed(e.to_string()))?;
+    let (device_rusb, handle, device_id, usb_id) = select_device(candidates, selector)?;
     info!("已打开 USB 设备句柄。");
+    info!("USB 设备标识: {} (匹配 {})", device_id, usb_id);
 
     // 尝试重置设备，看是否有助于解决重连问题
     // 将 reset 调用提前到内核驱动处理之前
@@ -264,16 +1048,17 @@ pub async fn find_and_open_usb_device(
         // tokio::time::sleep(Duration::from_millis(200)).await; // 可选的短暂延时增加
     }
 
-    let interface_number = 1;
+    let interface_number = layout.interface();
     let mut detached_here = false;
+    let platform = platform::current();
 
-    if cfg!(any(target_os = "linux", target_os = "macos")) {
+    if platform.manages_kernel_driver() {
         match handle.kernel_driver_active(interface_number) { 
             Ok(true) => {
                 info!("内核驱动已附加到接口{}，尝试分离...", interface_number);
                 if let Err(e) = handle.detach_kernel_driver(interface_number) {
                     warn!("分离接口{}内核驱动失败: {:?}. 将立即返回错误。", interface_number, e);
-                    return Err(UsbError::DetachFailed(e.to_string())); // 返回错误
+                    return Err(UsbError::DetachFailed(e)); // 返回错误
                 } else {
                     info!("接口{}内核驱动已成功分离。", interface_number);
                     detached_here = true;
@@ -289,29 +1074,74 @@ pub async fn find_and_open_usb_device(
     // 已将 reset 调用提前
 
     if let Err(e) = handle.set_active_configuration(1) {
-         if detached_here {
-            if let Err(attach_err) = handle.attach_kernel_driver(interface_number) {
-                warn!("配置失败后，重新附加内核驱动到接口 {} 失败: {:?}", interface_number, attach_err);
+        match platform.classify_set_configuration_error(e) {
+            ConfigurationFailure::Tolerate => {
+                info!("设置 USB 配置 1 返回 {:?}，在 {} 平台上属预期情况，继续。", e, platform.name());
+            }
+            ConfigurationFailure::Fatal => {
+                if detached_here
+                    && let Err(attach_err) = handle.attach_kernel_driver(interface_number)
+                {
+                    warn!("配置失败后，重新附加内核驱动到接口 {} 失败: {:?}", interface_number, attach_err);
+                }
+                return Err(UsbError::SetConfigurationFailed(e));
             }
-         }
-         return Err(UsbError::SetConfigurationFailed(e.to_string()));
+        }
+    } else {
+        info!("已设置 USB 配置 1。");
     }
-    info!("已设置 USB 配置 1。");
 
     if let Err(e) = handle.claim_interface(interface_number) { 
-        if detached_here { 
-            if let Err(attach_err) = handle.attach_kernel_driver(interface_number) {
-                 warn!("声明接口失败后，重新附加内核驱动到接口 {} 失败: {:?}", interface_number, attach_err);
-            }
+        if detached_here
+            && let Err(attach_err) = handle.attach_kernel_driver(interface_number)
+        {
+            warn!("声明接口失败后，重新附加内核驱动到接口 {} 失败: {:?}", interface_number, attach_err);
         }
-        return Err(UsbError::ClaimInterfaceFailed(e.to_string()));
+        return Err(UsbError::ClaimInterfaceFailed { source: e, hint: platform.claim_failure_hint(e) });
     }
     info!("已声明 USB 接口 {}。", interface_number);
+    // 此后的任何返回路径都会经由 guard 释放接口并恢复内核驱动
+    let claimed = ClaimedInterface::new(handle, interface_number, detached_here);
+
+    let (command_ep_address, response_ep_address, push_ep_address) =
+        match (layout.ep_cmd, layout.ep_response, layout.ep_push) {
+            (Some(command), Some(response), Some(push)) => {
+                info!(
+                    "使用配置的端点: 命令 {:#04x}，响应 {:#04x}，推送 {:#04x} (接口 {})",
+                    command, response, push, interface_number
+                );
+                (command, response, push)
+            }
+            (command, response, push) => {
+                let config_descriptor = device_rusb.active_config_descriptor().map_err(UsbError::from)?;
+                log_endpoint_table(&config_descriptor);
+                let (found_command, found_response, found_push) =
+                    discover_endpoints(&config_descriptor, interface_number)?;
+                let selected = (
+                    command.unwrap_or(found_command),
+                    response.unwrap_or(found_response),
+                    push.unwrap_or(found_push),
+                );
+                if command.is_some() || response.is_some() || push.is_some() {
+                    info!(
+                        "端点（部分来自配置）: 命令 {:#04x}，响应 {:#04x}，推送 {:#04x}",
+                        selected.0, selected.1, selected.2
+                    );
+                }
+                selected
+            }
+        };
+
+    Ok((claimed, command_ep_address, response_ep_address, push_ep_address, device_id, usb_id))
+}
 
-    let config_descriptor = device_rusb.active_config_descriptor().map_err(UsbError::from)?;
+// 按描述符在指定接口上发现端点：第一个 OUT 中断端点为命令端点，
+// 第一个 IN 中断端点为响应端点，第二个为推送端点（只有一个时两者共用）
+fn discover_endpoints(
+    config_descriptor: &rusb::ConfigDescriptor,
+    interface_number: u8,
+) -> Result<(u8, u8, u8), UsbError> {
     let mut command_ep_address = 0u8;
-    let response_ep_address: Option<u8>;
-    let push_ep_address: Option<u8>;
     let mut in_interrupt_eps = Vec::new();
     
     let mut found_claimed_interface_descriptors = false; 
@@ -353,52 +1183,57 @@ pub async fn find_and_open_usb_device(
     }
 
     if !in_interrupt_eps.is_empty() {
-        response_ep_address = Some(in_interrupt_eps[0]);
-        info!("USB 响应 IN 端点设置为: {:#02x}", response_ep_address.unwrap());
-        if in_interrupt_eps.len() > 1 {
-            push_ep_address = Some(in_interrupt_eps[1]);
-            info!("USB 推送 IN 端点设置为: {:#02x}", push_ep_address.unwrap());
+        let response_ep_address = in_interrupt_eps[0];
+        info!("USB 响应 IN 端点设置为: {:#02x}", response_ep_address);
+        let push_ep_address = if in_interrupt_eps.len() > 1 {
+            info!("USB 推送 IN 端点设置为: {:#02x}", in_interrupt_eps[1]);
+            in_interrupt_eps[1]
         } else {
-            warn!("只找到一个 USB IN 中断端点 {:#02x}。将用作响应和推送端点。", response_ep_address.unwrap());
-            push_ep_address = response_ep_address; // Assign directly
-        }
+            warn!("只找到一个 USB IN 中断端点 {:#02x}。将用作响应和推送端点。", response_ep_address);
+            response_ep_address
+        };
+        Ok((command_ep_address, response_ep_address, push_ep_address))
     } else {
         error!("在接口 {} 上未能找到任何 USB IN 中断端点。", interface_number);
-        // To satisfy definite assignment for response_ep_address and push_ep_address before early return:
-        // This path returns Err, so their values won't be used in Ok.
-        // However, to avoid uninitialized variable errors if the compiler can't prove all paths,
-        // assign a dummy value or ensure all paths leading to Ok assign them.
-        // Since this path returns Err, we don't strictly need to assign them for the Ok case.
-        // The compiler should understand this. If not, we might need to restructure or use dummy None.
-        return Err(UsbError::EndpointNotFound(format!("IN 端点未在接口 {} 上找到", interface_number)));
-    }
-    
-    // At this point, if we haven't returned Err, response_ep_address and push_ep_address must have been assigned.
-    // The check for .is_none() is somewhat redundant if the logic guarantees assignment or error.
-    // However, keeping it for safety or if logic changes.
-    if response_ep_address.is_none() || push_ep_address.is_none() {
-        // This case should ideally not be reached if the above logic is sound and assigns in all success paths.
-        return Err(UsbError::EndpointNotFound("未能成功分配响应或推送IN端点 (逻辑意外)".to_string()));
+        Err(UsbError::EndpointNotFound(format!("IN 端点未在接口 {} 上找到", interface_number)))
     }
+}
 
-    Ok((Some(handle), command_ep_address, response_ep_address, push_ep_address))
+// 列出当前配置下所有接口的端点，便于为 USB_INTERFACE / USB_EP_* 选值
+fn log_endpoint_table(config_descriptor: &rusb::ConfigDescriptor) {
+    info!("USB 配置 {} 的端点:", config_descriptor.number());
+    for iface in config_descriptor.interfaces() {
+        for iface_desc in iface.descriptors() {
+            info!(
+                "  接口 {} (alt {}, class {:#04x})",
+                iface_desc.interface_number(),
+                iface_desc.setting_number(),
+                iface_desc.class_code()
+            );
+            for ep in iface_desc.endpoint_descriptors() {
+                info!(
+                    "    端点 {:#04x} {:?} {:?} max_packet {}",
+                    ep.address(),
+                    ep.direction(),
+                    ep.transfer_type(),
+                    ep.max_packet_size()
+                );
+            }
+        }
+    }
 }
 
-pub async fn send_unsubscribe_command(
-    handle: rusb::DeviceHandle<rusb::Context>, 
-    command_ep_address: u8,
+pub fn send_unsubscribe_command<T: UsbTransport>(
+    transport: &T,
+    timeouts: &UsbTimeouts,
 ) -> Result<(), UsbError> {
     info!("正在发送取消订阅命令...");
     let mut cmd_buffer = [0u8; 64];
     let mut writer = Cursor::new(&mut cmd_buffer[..]);
-    UsbData::UnsubscribeStatus.write_be(&mut writer).map_err(|e| UsbError::BinrwError(e.to_string()))?;
+    UsbData::UnsubscribeStatus.write_be(&mut writer)?;
     let cmd_len = writer.position() as usize;
 
-    match handle.write_interrupt(
-        command_ep_address,
-        &cmd_buffer[..cmd_len],
-        Duration::from_secs(5),
-    ) {
+    match transport.write_command(&cmd_buffer[..cmd_len], timeouts.command) {
         Ok(len_written) => {
             info!("已成功发送取消订阅命令 ({} bytes)。", len_written);
             Ok(())
@@ -408,4 +1243,415 @@ pub async fn send_unsubscribe_command(
             Err(UsbError::from(e))
         }
     }
-}
\ No newline at end of file
+}
+
+/// 取消订阅并释放接口：发送 UnsubscribeStatus，在响应端点上等待设备的确认（可选，
+/// 固件不一定回复），最后释放接口并恢复内核驱动。阻塞调用，应在 `spawn_blocking` 中执行。
+pub fn unsubscribe_and_release(
+    claimed: ClaimedInterface,
+    endpoints: Endpoints,
+    timeouts: &UsbTimeouts,
+    capture: Option<CaptureSink>,
+) -> Result<(), UsbError> {
+    let transport = RusbTransport::new(claimed.handle(), endpoints).with_capture(capture);
+    send_unsubscribe_command(&transport, timeouts)?;
+
+    let mut resp_buf = [0u8; 256];
+    match transport.read_response(&mut resp_buf, timeouts.response) {
+        Ok(n) => {
+            info!("设备已确认取消订阅 ({} bytes)。", n);
+            debug!("取消订阅响应原始字节: {:x?}", &resp_buf[..n]);
+        }
+        Err(rusb::Error::Timeout) => info!("{:?} 内未收到取消订阅确认，继续。", timeouts.response),
+        Err(e) => warn!("读取取消订阅确认失败: {:?}，继续。", e),
+    }
+
+    claimed.release()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::{push_events, PayloadBuilder};
+    use crate::transport::MockTransport;
+
+    // 推送端点的最大包长
+    const MAX_PACKET: usize = 64;
+
+    fn chunked(frame: &[u8]) -> MockTransport {
+        frame.chunks(MAX_PACKET).fold(MockTransport::new(), |transport, chunk| transport.push(Ok(chunk.to_vec())))
+    }
+
+    fn read(transport: &MockTransport) -> (Vec<u8>, rusb::Result<usize>) {
+        let mut buf = [0u8; 256];
+        let result = read_frame(transport, &mut buf, Duration::from_millis(50), &CancellationToken::new());
+        let len = *result.as_ref().unwrap_or(&0);
+        (buf[..len].to_vec(), result)
+    }
+
+    #[test]
+    fn status_frame_split_into_64_byte_packets_is_reassembled() {
+        let frame = PayloadBuilder::new().cell_mv(4, 3555).frame();
+        assert!(frame.len() > MAX_PACKET);
+        let (read, result) = read(&chunked(&frame));
+        assert_eq!(result, Ok(frame.len()));
+        assert_eq!(read, frame);
+        assert_eq!(framing::frame_status(&read), FrameStatus::Complete);
+    }
+
+    #[test]
+    fn zero_length_packet_ends_an_incomplete_frame() {
+        let frame = PayloadBuilder::new().frame();
+        let transport = MockTransport::new().push(Ok(frame[..MAX_PACKET].to_vec())).push(Ok(Vec::new()));
+        let (read, _) = read(&transport);
+        assert_eq!(read.len(), MAX_PACKET);
+        assert_eq!(framing::frame_status(&read), FrameStatus::Incomplete { got: MAX_PACKET, expected: frame.len() });
+    }
+
+    #[test]
+    fn missing_tail_ends_with_the_fragment_timeout() {
+        let frame = PayloadBuilder::new().frame();
+        let (read, result) = read(&MockTransport::new().push(Ok(frame[..MAX_PACKET].to_vec())));
+        assert_eq!(result, Ok(MAX_PACKET));
+        assert_eq!(read, frame[..MAX_PACKET]);
+    }
+
+    #[test]
+    fn devices_without_a_serial_fall_back_to_bus_and_address() {
+        assert_eq!(device_id_from_serial(Ok(" UPS120-0042 ".to_string()), 1, 5), DeviceId::Serial("UPS120-0042".to_string()));
+        assert_eq!(device_id_from_serial(Ok("  ".to_string()), 1, 5), DeviceId::BusAddr { bus: 1, address: 5 });
+        assert_eq!(device_id_from_serial(Err(rusb::Error::Pipe), 3, 7).to_string(), "bus3-addr7");
+    }
+
+    #[test]
+    fn read_error_between_packets_is_returned() {
+        let frame = PayloadBuilder::new().frame();
+        let transport = MockTransport::new().push(Ok(frame[..MAX_PACKET].to_vec())).push(Err(rusb::Error::NoDevice));
+        assert_eq!(read(&transport).1, Err(rusb::Error::NoDevice));
+    }
+
+    #[tokio::test]
+    async fn reconnect_waits_use_the_policy_and_end_on_shutdown() {
+        let timing = Policy { usb_open_retry: Duration::from_millis(150), ..Policy::default() };
+        let shutdown = CancellationToken::new();
+        let started = tokio::time::Instant::now();
+        assert!(retry_after(&shutdown, timing.usb_open_retry).await);
+        let waited = started.elapsed();
+        assert!(waited >= timing.usb_open_retry && waited < Policy::default().usb_open_retry, "{:?}", waited);
+
+        // 退出信号打断默认的 25 秒等待
+        let cancel = shutdown.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cancel.cancel();
+        });
+        let started = tokio::time::Instant::now();
+        assert!(!retry_after(&shutdown, Policy::default().usb_open_retry).await);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn a_silent_device_is_resubscribed_once_then_reconnected() {
+        let timeout = Policy::default().usb.stale_timeout;
+        let t0 = tokio::time::Instant::now();
+        let mut watchdog = StaleWatchdog::new(timeout, t0);
+        assert_eq!(watchdog.check(t0 + timeout - Duration::from_millis(1)), StaleAction::None);
+        assert_eq!(watchdog.check(t0 + timeout), StaleAction::Resubscribe);
+
+        // 旧固件不回复 GetVersion，随后确认订阅；推送端点一直没有数据
+        let builder = PayloadBuilder::new().without_ina226();
+        let transport = MockTransport::new().respond(Err(rusb::Error::Timeout)).respond(Ok(builder.response_frame()));
+        let resubscribed_at = t0 + timeout;
+        connect_and_subscribe_usb(&transport, &UsbTimeouts::default(), None).await.unwrap();
+        watchdog.on_resubscribed(resubscribed_at);
+        assert_eq!(transport.written().len(), 2);
+        let (buf, result) = read(&transport);
+        assert!(buf.is_empty() && result == Err(rusb::Error::Timeout));
+
+        assert_eq!(watchdog.check(resubscribed_at + timeout / 2), StaleAction::None);
+        assert_eq!(watchdog.check(resubscribed_at + timeout), StaleAction::Reconnect);
+    }
+
+    #[tokio::test]
+    async fn a_push_after_resubscribing_restarts_the_watchdog() {
+        let timeout = Duration::from_secs(30);
+        let t0 = tokio::time::Instant::now();
+        let mut watchdog = StaleWatchdog::new(timeout, t0);
+        watchdog.on_resubscribed(t0 + timeout);
+
+        let builder = PayloadBuilder::new().without_ina226();
+        let (frame, result) = read(&chunked(&builder.frame()));
+        assert_eq!(result, Ok(builder.frame().len()));
+        let events = push_events(&[frame], ProtocolVersion::LEGACY).await;
+        assert!(events.iter().any(|e| matches!(e, UsbEvent::Measurements(_))));
+        watchdog.on_push(t0 + timeout + Duration::from_secs(10));
+        // 恢复推送后再次过期时先重新订阅，而不是直接重连
+        assert_eq!(watchdog.check(t0 + timeout * 2), StaleAction::None);
+        assert_eq!(watchdog.check(t0 + timeout * 2 + Duration::from_secs(10)), StaleAction::Resubscribe);
+    }
+
+    #[tokio::test]
+    async fn a_failed_resubscribe_surfaces_the_error() {
+        let transport = MockTransport::new().fail_writes(rusb::Error::NoDevice);
+        let error = connect_and_subscribe_usb(&transport, &UsbTimeouts::default(), None).await.unwrap_err();
+        assert!(matches!(error, UsbError::RusbError(rusb::Error::NoDevice)), "{:?}", error);
+    }
+
+    // 像真实设备一样，没有数据时读取阻塞到超时
+    struct SilentDevice;
+
+    impl UsbTransport for SilentDevice {
+        fn write_command(&self, data: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+            Ok(data.len())
+        }
+
+        fn read_response(&self, _buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+            std::thread::sleep(timeout);
+            Err(rusb::Error::Timeout)
+        }
+
+        fn read_push(&self, _buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+            std::thread::sleep(timeout);
+            Err(rusb::Error::Timeout)
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_interrupts_a_long_push_read_within_a_second() {
+        let shutdown = CancellationToken::new();
+        let token = shutdown.clone();
+        let read = tokio::task::spawn_blocking(move || {
+            let mut buf = [0u8; 256];
+            read_frame(&SilentDevice, &mut buf, Duration::from_secs(10), &token)
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let signalled = std::time::Instant::now();
+        shutdown.cancel();
+        let result = tokio::time::timeout(Duration::from_secs(5), read).await.expect("读取未被打断").unwrap();
+        let latency = signalled.elapsed();
+        assert_eq!(result, Err(rusb::Error::Interrupted));
+        assert!(latency <= READ_POLL_INTERVAL + Duration::from_millis(500), "退出延迟 {:?}", latency);
+    }
+
+    #[test]
+    fn a_cancelled_token_skips_the_read_entirely() {
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+        let started = std::time::Instant::now();
+        let mut buf = [0u8; 64];
+        assert_eq!(read_frame(&SilentDevice, &mut buf, Duration::from_secs(10), &shutdown), Err(rusb::Error::Interrupted));
+        assert!(started.elapsed() < READ_POLL_INTERVAL);
+    }
+
+    fn encode(data: &UsbData) -> Vec<u8> {
+        let mut cursor = Cursor::new(Vec::new());
+        data.write_be(&mut cursor).unwrap();
+        cursor.into_inner()
+    }
+
+    #[test]
+    fn a_status_request_returns_the_parsed_response() {
+        let builder = PayloadBuilder::new().cell_mv(1, 3420);
+        let transport = MockTransport::new().respond(Ok(builder.response_frame()));
+        let (payload, ignored, raw) =
+            request_status(&transport, &UsbData::GetStatus, &ProtocolVersion::LEGACY, &UsbTimeouts::default()).unwrap();
+        assert_eq!(transport.written(), vec![encode(&UsbData::GetStatus)]);
+        assert_eq!(payload.bq76920_cell2_mv, 3420);
+        assert_eq!(ignored, 0);
+        assert_eq!(raw, builder.response_frame());
+    }
+
+    #[tokio::test]
+    async fn trailing_bytes_are_counted_in_the_link_stats() {
+        let builder = PayloadBuilder::new().cell_mv(0, 3550);
+        let padded = |cell_mv, extra| {
+            let mut frame = builder.clone().cell_mv(0, cell_mv).frame();
+            frame.extend(std::iter::repeat_n(0xA5, extra));
+            frame
+        };
+        let frame_ids = FrameIdAllocator::in_memory();
+        let conversion_ctx = ConversionContext::default();
+        let debug_dump = DebugDump::default();
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        let mut sequence = SequenceTracker::default();
+        let mut link_stats = UsbLinkStats::default();
+        let device = DeviceId::Serial("UPS01".to_string());
+        // 内容相同的帧会被当作重复丢弃，每帧换一个电芯电压
+        let frames = [padded(3550, 0), padded(3560, 16), padded(3570, 4)];
+        for (i, frame) in frames.iter().enumerate() {
+            let mut push = PushContext {
+                device: &device,
+                frame_ids: &frame_ids,
+                conversion_ctx: &conversion_ctx,
+                protocol: ProtocolVersion::LEGACY,
+                event_tx: &event_tx,
+                sequence: &mut sequence,
+                link_stats: &mut link_stats,
+                debug_dump: &debug_dump,
+            };
+            assert!(handle_push_frame(frame, 1_700_000_000_000 + i as u64, &mut push).await);
+        }
+        drop(event_tx);
+        let mut measurements = 0;
+        while let Some(event) = event_rx.recv().await {
+            assert!(matches!(event, UsbEvent::Measurements(_)), "{:?}", event);
+            measurements += 1;
+        }
+        assert_eq!(measurements, 3);
+        assert_eq!((link_stats.frames_with_trailing_bytes, link_stats.trailing_bytes_ignored), (2, 20));
+        assert_eq!(link_stats.bytes_received, 3 * (1 + HostSideUsbPayload::SIZE as u64) + 20);
+        let json = serde_json::to_value(&link_stats).unwrap();
+        assert_eq!((&json["frames_with_trailing_bytes"], &json["trailing_bytes_ignored"]), (&2.into(), &20.into()));
+
+        // 轮询的响应同样返回被忽略的字节数
+        let mut response = builder.response_frame();
+        response.extend([0xA5; 16]);
+        let transport = MockTransport::new().respond(Ok(response));
+        let (payload, ignored, _) =
+            request_status(&transport, &UsbData::GetStatus, &ProtocolVersion::LEGACY, &UsbTimeouts::default()).unwrap();
+        assert_eq!((payload.bq76920_cell1_mv, ignored), (3550, 16));
+    }
+
+    #[test]
+    fn a_status_request_without_a_reply_times_out() {
+        let transport = MockTransport::new();
+        let error = request_status(&transport, &UsbData::GetStatus, &ProtocolVersion::LEGACY, &UsbTimeouts::default())
+            .unwrap_err();
+        assert!(matches!(error, UsbError::Timeout), "{:?}", error);
+    }
+
+    #[test]
+    fn garbage_and_unexpected_replies_are_rejected() {
+        let builder = PayloadBuilder::new();
+        let garbage = MockTransport::new().respond(Ok(vec![0x7F; 80]));
+        let error = request_status(&garbage, &UsbData::GetStatus, &ProtocolVersion::LEGACY, &UsbTimeouts::default())
+            .unwrap_err();
+        assert!(matches!(error, UsbError::ResponseParseError(_)), "{:?}", error);
+
+        // 推送帧不是对请求的响应
+        let push = MockTransport::new().respond(Ok(builder.frame()));
+        let error =
+            request_status(&push, &UsbData::GetStatus, &ProtocolVersion::LEGACY, &UsbTimeouts::default()).unwrap_err();
+        assert!(matches!(error, UsbError::UnexpectedResponse), "{:?}", error);
+
+        let truncated = MockTransport::new().respond(Ok(builder.response_frame()[..20].to_vec()));
+        let error = request_status(&truncated, &UsbData::GetStatus, &ProtocolVersion::LEGACY, &UsbTimeouts::default())
+            .unwrap_err();
+        assert!(matches!(error, UsbError::LengthMismatch { got: 20, .. }), "{:?}", error);
+    }
+
+    #[test]
+    fn read_and_write_failures_are_distinguished() {
+        let unplugged = MockTransport::new().respond(Err(rusb::Error::NoDevice));
+        let error = request_status(&unplugged, &UsbData::GetStatus, &ProtocolVersion::LEGACY, &UsbTimeouts::default())
+            .unwrap_err();
+        assert!(matches!(error, UsbError::ResponseReadFailed(rusb::Error::NoDevice)), "{:?}", error);
+
+        let stalled = MockTransport::new().fail_writes(rusb::Error::Pipe).respond(Ok(PayloadBuilder::new().response_frame()));
+        let error = request_status(&stalled, &UsbData::GetStatus, &ProtocolVersion::LEGACY, &UsbTimeouts::default())
+            .unwrap_err();
+        assert!(matches!(error, UsbError::RusbError(rusb::Error::Pipe)), "{:?}", error);
+    }
+
+    fn version_response(protocol: u16) -> rusb::Result<Vec<u8>> {
+        Ok(encode(&UsbData::VersionResponse { protocol, firmware: *b"1.2a" }))
+    }
+
+    #[tokio::test]
+    async fn subscribing_negotiates_the_protocol_and_sets_the_report_interval() {
+        let builder = PayloadBuilder::new().sequence(1);
+        let transport = MockTransport::new()
+            .respond(version_response(2))
+            .respond(Ok(builder.response_frame()))
+            .respond(Ok(encode(&UsbData::ReportIntervalResponse { interval_ms: 500 })));
+        let version = connect_and_subscribe_usb(&transport, &UsbTimeouts::default(), Some(500)).await.unwrap();
+        assert_eq!(version, ProtocolVersion { protocol: 2, firmware: Some(*b"1.2a") });
+        assert_eq!(
+            transport.written(),
+            vec![encode(&UsbData::GetVersion), encode(&UsbData::SubscribeStatus), encode(&UsbData::SetReportInterval(500))]
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribing_fails_on_timeout_garbage_or_a_newer_protocol() {
+        let timeouts = UsbTimeouts::default();
+        let silent = MockTransport::new().respond(version_response(1));
+        assert!(matches!(connect_and_subscribe_usb(&silent, &timeouts, None).await, Err(UsbError::Timeout)));
+
+        let garbage = MockTransport::new().respond(version_response(1)).respond(Ok(vec![0x7F; 80]));
+        assert!(matches!(connect_and_subscribe_usb(&garbage, &timeouts, None).await, Err(UsbError::ResponseParseError(_))));
+
+        let newer = MockTransport::new().respond(version_response(SUPPORTED_PROTOCOL_VERSION + 1));
+        let error = connect_and_subscribe_usb(&newer, &timeouts, None).await.unwrap_err();
+        assert!(matches!(error, UsbError::UnsupportedProtocol { .. }), "{:?}", error);
+        // 协议不支持时不发送订阅命令
+        assert_eq!(newer.written(), vec![encode(&UsbData::GetVersion)]);
+    }
+
+    #[test]
+    fn negotiation_falls_back_to_legacy_only_when_the_firmware_does_not_answer() {
+        let timeouts = UsbTimeouts::default();
+        let supported = MockTransport::new().respond(version_response(SUPPORTED_PROTOCOL_VERSION));
+        let version = negotiate_protocol(&supported, &timeouts).unwrap();
+        assert_eq!(version, ProtocolVersion { protocol: SUPPORTED_PROTOCOL_VERSION, firmware: Some(*b"1.2a") });
+
+        // 不回复或回复其他帧的旧固件按协议 v1 处理
+        let silent = MockTransport::new();
+        assert_eq!(negotiate_protocol(&silent, &timeouts).unwrap(), ProtocolVersion::LEGACY);
+        let status = MockTransport::new().respond(Ok(PayloadBuilder::new().response_frame()));
+        assert_eq!(negotiate_protocol(&status, &timeouts).unwrap(), ProtocolVersion::LEGACY);
+        let garbage = MockTransport::new().respond(Ok(vec![0x7F; 8]));
+        assert_eq!(negotiate_protocol(&garbage, &timeouts).unwrap(), ProtocolVersion::LEGACY);
+
+        // 读写失败不是旧固件，交给管理任务重连
+        let pipe = MockTransport::new().respond(Err(rusb::Error::Pipe));
+        assert!(matches!(negotiate_protocol(&pipe, &timeouts), Err(UsbError::ResponseReadFailed(rusb::Error::Pipe))));
+        let unplugged = MockTransport::new().fail_writes(rusb::Error::NoDevice);
+        assert!(negotiate_protocol(&unplugged, &timeouts).is_err());
+    }
+
+    #[test]
+    fn report_interval_acks_are_returned_and_old_firmware_is_tolerated() {
+        let timeouts = UsbTimeouts::default();
+        let ack = |interval_ms| Ok(encode(&UsbData::ReportIntervalResponse { interval_ms }));
+
+        let accepted = MockTransport::new().respond(ack(500));
+        assert_eq!(set_report_interval(&accepted, 500, &timeouts).unwrap(), Some(500));
+        // 间隔为大端 u16
+        assert_eq!(accepted.written(), vec![vec![0x04, 0x01, 0xF4]]);
+        let clamped = MockTransport::new().respond(ack(1000));
+        assert_eq!(set_report_interval(&clamped, 100, &timeouts).unwrap(), Some(1000));
+
+        // 旧固件不回复或回复其他帧：按默认间隔继续
+        assert_eq!(set_report_interval(&MockTransport::new(), 500, &timeouts).unwrap(), None);
+        let status = MockTransport::new().respond(Ok(PayloadBuilder::new().response_frame()));
+        assert_eq!(set_report_interval(&status, 500, &timeouts).unwrap(), None);
+
+        let pipe = MockTransport::new().respond(Err(rusb::Error::Pipe));
+        assert!(matches!(set_report_interval(&pipe, 500, &timeouts), Err(UsbError::ResponseReadFailed(rusb::Error::Pipe))));
+        let unplugged = MockTransport::new().fail_writes(rusb::Error::NoDevice);
+        assert!(set_report_interval(&unplugged, 500, &timeouts).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_device_unplugged_mid_stream_ends_the_read_loop() {
+        let builder = PayloadBuilder::new().without_ina226();
+        let frame = builder.frame();
+        let transport = MockTransport::new()
+            .respond(Err(rusb::Error::Timeout))
+            .respond(Ok(builder.response_frame()))
+            .push(Ok(frame.clone()))
+            .push(Ok(frame[..20].to_vec()))
+            .push(Err(rusb::Error::NoDevice));
+        let protocol = connect_and_subscribe_usb(&transport, &UsbTimeouts::default(), None).await.unwrap();
+        assert_eq!(protocol, ProtocolVersion::LEGACY);
+
+        let (first, result) = read(&transport);
+        assert_eq!(result, Ok(frame.len()));
+        assert!(push_events(&[first], protocol).await.iter().any(|e| matches!(e, UsbEvent::Measurements(_))));
+        let (_, result) = read(&transport);
+        // 半帧之后设备断开，错误交给管理任务触发重连
+        assert_eq!(result, Err(rusb::Error::NoDevice));
+    }
+}
@@ -1,89 +1,413 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use binrw::{BinRead, BinWrite};
-use super::data_models::AllMeasurements;
+use serde::{Deserialize, Serialize};
+use super::data_models::{DeviceInfo, HostSideUsbPayload, TimestampedMeasurements};
+use crate::diagnostics::FrameAnomaly;
+use crate::framing::PayloadArgs;
+use crate::sequence::FrameOrder;
 
 #[repr(u8)]
 #[derive(BinRead, BinWrite, Debug, Clone)] // 移除 Copy
+#[br(import_raw(payload: PayloadArgs))]
 pub enum UsbData {
     // Commands
     #[brw(magic = 0x00u8)]
     SubscribeStatus,
     #[brw(magic = 0x01u8)]
     UnsubscribeStatus,
+    #[brw(magic = 0x02u8)]
+    GetVersion,
+    #[brw(magic = 0x03u8)]
+    GetStatus, // 轮询模式：请求一次 StatusResponse，不开启推送
+    #[brw(magic = 0x04u8, big)]
+    SetReportInterval(u16), // StatusPush 的推送间隔 (ms)
 
     // Responses
     #[brw(magic = 0x80u8)]
-    StatusResponse(AllMeasurements<5>),
+    StatusResponse(#[br(args_raw = payload)] HostSideUsbPayload), // 原始载荷，物理量转换见 conversion 模块
+    #[brw(magic = 0x82u8, big)]
+    VersionResponse { protocol: u16, firmware: [u8; 4] },
+    #[brw(magic = 0x84u8, big)]
+    ReportIntervalResponse { interval_ms: u16 }, // 固件实际采用的推送间隔，可能被限制在固件支持的范围内
 
     // Push Data
     #[brw(magic = 0xC0u8)]
-    StatusPush(AllMeasurements<5>),
+    StatusPush(#[br(args_raw = payload)] HostSideUsbPayload),
+}
+
+/// 守护进程能解析的最高载荷协议版本
+pub const SUPPORTED_PROTOCOL_VERSION: u16 = 2;
+
+/// 从该协议版本起，状态载荷在已知字段之后附带 u16 帧序号；更早的固件没有序号，
+/// 载荷之后的字节一律视为守护进程不认识的字段
+pub const SEQUENCE_PROTOCOL_VERSION: u16 = 2;
+
+/// 握手得到的协议版本；`firmware` 为 None 表示固件不支持版本查询
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolVersion {
+    pub protocol: u16,
+    pub firmware: Option<[u8; 4]>,
+}
+
+impl ProtocolVersion {
+    /// 不支持 GetVersion 的旧固件
+    pub const LEGACY: ProtocolVersion = ProtocolVersion {
+        protocol: 1,
+        firmware: None,
+    };
+
+    /// 按协议版本解析状态载荷的参数
+    pub fn payload_args(&self) -> PayloadArgs {
+        PayloadArgs { sequence: self.protocol >= SEQUENCE_PROTOCOL_VERSION }
+    }
+}
+
+impl ProtocolVersion {
+    /// 固件版本的 `a.b.c.d` 形式
+    pub fn firmware_string(&self) -> Option<String> {
+        self.firmware.map(|[a, b, c, d]| format!("{}.{}.{}.{}", a, b, c, d))
+    }
+}
+
+impl std::fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.firmware_string() {
+            Some(firmware) => write!(f, "protocol v{}, firmware {}", self.protocol, firmware),
+            None => write!(f, "protocol v{} (legacy firmware)", self.protocol),
+        }
+    }
 }
 
 // USB 命令枚举 (现在可以从 UsbData 中派生)
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum UsbCommand {
     Subscribe,
     Unsubscribe,
+    Reconnect,
+    /// 设置推送间隔 (ms)，之后的重连也使用该间隔
+    SetInterval(u16),
+}
+
+/// 发往设备注册表的命令；`target` 为 None 时发给所有设备
+#[derive(Debug)]
+pub struct DeviceCommand {
+    pub target: Option<DeviceId>,
+    pub command: UsbCommand,
+}
+
+/// USB 设备标识：优先使用序列号，设备未提供序列号时退回总线号与地址
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DeviceId {
+    Serial(String),
+    BusAddr { bus: u8, address: u8 },
+}
+
+impl std::fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceId::Serial(serial) => write!(f, "{}", serial),
+            DeviceId::BusAddr { bus, address } => write!(f, "bus{}-addr{}", bus, address),
+        }
+    }
+}
+
+/// USB VID/PID 组合，显示为 `1209:0002`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UsbId {
+    pub vid: u16,
+    pub pid: u16,
+}
+
+impl std::fmt::Display for UsbId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vid, self.pid)
+    }
+}
+
+/// 有多块 VID/PID 相同的板子时选择哪一块 (`USB_SERIAL` / `USB_BUS_ADDR`)
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum DeviceSelector {
+    /// 第一个匹配 VID/PID 的设备
+    #[default]
+    Any,
+    Serial(String),
+    BusAddr { bus: u8, address: u8 },
+}
+
+impl std::fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceSelector::Any => write!(f, "any"),
+            DeviceSelector::Serial(serial) => write!(f, "serial {}", serial),
+            DeviceSelector::BusAddr { bus, address } => write!(f, "bus {}:{}", bus, address),
+        }
+    }
+}
+
+impl From<&DeviceId> for DeviceSelector {
+    fn from(device: &DeviceId) -> Self {
+        match device {
+            DeviceId::Serial(serial) => DeviceSelector::Serial(serial.clone()),
+            DeviceId::BusAddr { bus, address } => DeviceSelector::BusAddr { bus: *bus, address: *address },
+        }
+    }
+}
+
+// 平均推送间隔按最近多少个间隔计算
+const LINK_INTERVAL_WINDOW: usize = 32;
+
+/// 单块设备 USB 链路的累计统计，自管理任务启动起计数，跨重连保留
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsbLinkStats {
+    /// 读取到的非空帧数
+    pub frames_received: u64,
+    pub bytes_received: u64,
+    /// 长度不符、解析失败或类型不符的帧数
+    pub parse_errors: u64,
+    /// 推送读取超时、轮询请求超时与看门狗超时的次数
+    pub timeouts: u64,
+    /// 超时以外的传输错误数，包括分片读取结束时仍未收齐的帧
+    pub transport_errors: u64,
+    /// 首次连接之后重新连接成功的次数
+    pub reconnects: u64,
+    /// 与上一帧序号相同或内容完全相同而丢弃的帧数
+    pub duplicate_frames: u64,
+    /// 序号早于上一帧而丢弃的帧数
+    pub out_of_order_frames: u64,
+    /// 按序号跳号推算的丢帧数；旧固件没有序号，始终为 0
+    pub sequence_gaps: u64,
+    /// 已知字段之后带有多余字节的帧数；非 0 说明固件比守护进程新
+    pub frames_with_trailing_bytes: u64,
+    /// 累计忽略的多余字节数
+    pub trailing_bytes_ignored: u64,
+    /// 最近 32 个数据帧间隔的平均值 (ms)；不足两帧时为 None
+    pub avg_push_interval_ms: Option<f64>,
+    #[serde(skip)]
+    intervals: VecDeque<Duration>,
+    #[serde(skip)]
+    last_push_at: Option<Instant>,
+}
+
+impl UsbLinkStats {
+    /// 读取到一帧（无论能否解析）
+    pub fn record_frame(&mut self, bytes: usize) {
+        self.frames_received += 1;
+        self.bytes_received += bytes as u64;
+    }
+
+    /// 收到一帧有效的状态数据，更新平均间隔
+    pub fn record_push(&mut self, at: Instant) {
+        if let Some(last) = self.last_push_at.replace(at) {
+            if self.intervals.len() == LINK_INTERVAL_WINDOW {
+                self.intervals.pop_front();
+            }
+            self.intervals.push_back(at.saturating_duration_since(last));
+            let total: Duration = self.intervals.iter().sum();
+            self.avg_push_interval_ms = Some(total.as_secs_f64() * 1000.0 / self.intervals.len() as f64);
+        }
+    }
+
+    /// 按错误类别计入解析错误、超时或传输错误，其他错误不计
+    pub fn record_error(&mut self, error: &UsbError) {
+        if error.is_parse_error() {
+            self.parse_errors += 1;
+        } else if matches!(error, UsbError::Timeout) {
+            self.timeouts += 1;
+        } else if error.is_transport_error() {
+            self.transport_errors += 1;
+        }
+    }
+
+    /// 解析后忽略了 `ignored` 个多余字节
+    pub fn record_trailing(&mut self, ignored: usize) {
+        if ignored > 0 {
+            self.frames_with_trailing_bytes += 1;
+            self.trailing_bytes_ignored += ignored as u64;
+        }
+    }
+
+    pub fn record_order(&mut self, order: FrameOrder) {
+        match order {
+            FrameOrder::Next => {}
+            FrameOrder::Gap { missed } => self.sequence_gaps += missed as u64,
+            FrameOrder::Duplicate => self.duplicate_frames += 1,
+            FrameOrder::OutOfOrder => self.out_of_order_frames += 1,
+        }
+    }
+
+    pub fn record_timeout(&mut self) {
+        self.timeouts += 1;
+    }
+
+    pub fn record_reconnect(&mut self) {
+        self.reconnects += 1;
+    }
+}
+
+/// 数据获取方式 (`USB_MODE`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UsbMode {
+    /// 订阅后由设备持续推送（默认）
+    #[default]
+    Push,
+    /// 不订阅，按间隔发送 GetStatus 读取一次状态 (`USB_POLL_INTERVAL_SECS`)
+    Poll { interval: std::time::Duration },
+}
+
+/// 接口号与端点地址的覆盖 (`USB_INTERFACE` / `USB_EP_CMD` / `USB_EP_RESPONSE` / `USB_EP_PUSH`)；
+/// 未设置的项按描述符自动发现
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UsbLayout {
+    pub interface: Option<u8>,
+    pub ep_cmd: Option<u8>,
+    pub ep_response: Option<u8>,
+    pub ep_push: Option<u8>,
+}
+
+impl UsbLayout {
+    /// 未覆盖时声明的接口号
+    pub const DEFAULT_INTERFACE: u8 = 1;
+
+    pub fn interface(&self) -> u8 {
+        self.interface.unwrap_or(Self::DEFAULT_INTERFACE)
+    }
+}
+
+/// 带设备标识的 USB 事件，主循环据此把数据路由到各设备的主题前缀
+#[derive(Debug)]
+pub struct DeviceEvent {
+    pub device: DeviceId,
+    pub event: UsbEvent,
 }
 
 // USB 事件枚举 (现在可以从 UsbData 中派生)
 #[derive(Debug)]
 pub enum UsbEvent {
-    Measurements(AllMeasurements<5>),
+    Connected { device_id: DeviceId, usb_id: UsbId, protocol: ProtocolVersion }, // 设备已打开并订阅成功，附带匹配到的 VID/PID 与协议版本
+    DeviceInfo(DeviceInfo), // 紧随 Connected 发出：字符串描述符与固件版本
+    Measurements(Arc<TimestampedMeasurements>), // 时间戳为 USB 推送的接收时间；下游共享同一份样本，不再逐个克隆
+    Stats(UsbLinkStats), // 周期性发出的链路统计 (`USB_STATS_INTERVAL_SECS`)
     Error(UsbError), // Changed to use UsbError
+    Disconnected(UsbError), // 已建立的连接中断（读取失败、推送超时或设备移除），管理任务即将重连
+    Anomaly { frame_id: u64, anomaly: FrameAnomaly }, // 帧解析成功但内容异常
+    Stale, // 超过 USB_STALE_TIMEOUT_SECS 没有推送数据，管理任务正在重新订阅
+    Unsubscribed, // 已通知设备停止推送并释放接口，管理任务等待订阅或重连命令
+    ErrorRepeated { category: &'static str, count: u64 }, // 合并窗口内未单独发出的同类错误数
+    ErrorsSuppressed { count: u64 }, // 超过每分钟上限而丢弃的错误数
+    RawFrame(Vec<u8>), // 原始帧转储，仅在转储开关打开且 `DEBUG_DUMP_TARGET=mqtt` 时发出
+    Detached, // 设备已拔出或收到退出信号，管理任务已停止
 }
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum UsbError {
-    DeviceNotFound,
-    OpenFailed(String),
-    SetConfigurationFailed(String),
-    ClaimInterfaceFailed(String),
-    DetachFailed(String), // 新增: 内核驱动分离失败
+    #[error("USB device not found: {0}")]
+    DeviceNotFound(String),
+    #[error("Failed to open USB device: {0}")]
+    OpenFailed(#[source] rusb::Error),
+    #[error("Failed to set USB configuration: {0}")]
+    SetConfigurationFailed(#[source] rusb::Error),
+    /// `hint` 为平台相关的排查提示（权限、驱动绑定等）
+    #[error("Failed to claim USB interface: {source}{}", .hint.map(|hint| format!(" ({})", hint)).unwrap_or_default())]
+    ClaimInterfaceFailed { source: rusb::Error, hint: Option<&'static str> },
+    #[error("Failed to detach kernel driver: {0}")]
+    DetachFailed(#[source] rusb::Error), // 新增: 内核驱动分离失败
+    #[error("USB endpoint not found: {0}")]
     EndpointNotFound(String),
-    CommandWriteFailed(String),
-    ResponseReadFailed(String),
-    ResponseParseError(String),
+    #[error("Failed to write USB command: {0}")]
+    CommandWriteFailed(#[source] rusb::Error),
+    #[error("Failed to read USB response: {0}")]
+    ResponseReadFailed(#[source] rusb::Error),
+    #[error("Failed to parse USB response: {0}")]
+    ResponseParseError(#[source] binrw::Error),
+    #[error("Received unexpected USB response")]
     UnexpectedResponse,
+    #[error("USB subscription failed: {0}")]
     SubscriptionFailed(String), // General subscription failure
-    RusbError(rusb::Error),
-    IoError(std::io::Error),
-    BinrwError(String), // For binrw read/write errors
+    #[error("Rusb error: {0}")]
+    RusbError(#[source] rusb::Error),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Binrw error: {0}")]
+    BinrwError(#[from] binrw::Error), // For binrw read/write errors
+    #[error("USB operation timed out")]
     Timeout, // For timeout errors specifically
+    #[error("Incomplete USB payload: got {got} of {expected} bytes")]
+    IncompletePayload { got: usize, expected: usize }, // 分片读取结束时仍未收齐一帧
+    /// 帧长度短于 magic 对应的预期长度；`head` 为帧头最多 32 字节的十六进制
+    #[error("{}", length_mismatch_message(.magic, .got, .expected, .head))]
+    LengthMismatch { magic: Option<u8>, got: usize, expected: usize, head: String },
+    #[error("Unsupported USB protocol version {device} (daemon supports up to {supported})")]
+    UnsupportedProtocol { device: u16, supported: u16 }, // 固件的协议版本高于守护进程支持的版本
+    /// 执行阻塞 USB 操作的任务 panic 或被取消
+    #[error("USB task failed: {0}")]
+    TaskFailed(#[from] tokio::task::JoinError),
+    #[error("USB error: {0}")]
     Other(String),
 }
 
-impl std::fmt::Display for UsbError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            UsbError::DeviceNotFound => write!(f, "USB device not found"),
-            UsbError::OpenFailed(s) => write!(f, "Failed to open USB device: {}", s),
-            UsbError::SetConfigurationFailed(s) => write!(f, "Failed to set USB configuration: {}", s),
-            UsbError::ClaimInterfaceFailed(s) => write!(f, "Failed to claim USB interface: {}", s),
-            UsbError::DetachFailed(s) => write!(f, "Failed to detach kernel driver: {}", s),
-            UsbError::EndpointNotFound(s) => write!(f, "USB endpoint not found: {}", s),
-            UsbError::CommandWriteFailed(s) => write!(f, "Failed to write USB command: {}", s),
-            UsbError::ResponseReadFailed(s) => write!(f, "Failed to read USB response: {}", s),
-            UsbError::ResponseParseError(s) => write!(f, "Failed to parse USB response: {}", s),
-            UsbError::UnexpectedResponse => write!(f, "Received unexpected USB response"),
-            UsbError::SubscriptionFailed(s) => write!(f, "USB subscription failed: {}", s),
-            UsbError::RusbError(e) => write!(f, "Rusb error: {}", e),
-            UsbError::IoError(e) => write!(f, "IO error: {}", e),
-            UsbError::BinrwError(s) => write!(f, "Binrw error: {}", s),
-            UsbError::Timeout => write!(f, "USB operation timed out"),
-            UsbError::Other(s) => write!(f, "USB error: {}", s),
-        }
+fn length_mismatch_message(magic: &Option<u8>, got: &usize, expected: &usize, head: &str) -> String {
+    match magic {
+        Some(magic) => format!(
+            "USB frame length mismatch for magic {:#04x}: got {} bytes, expected at least {} (head: {})",
+            magic, got, expected, head
+        ),
+        None => "Empty USB frame".to_string(),
     }
 }
 
-impl std::error::Error for UsbError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+impl UsbError {
+    /// 错误类别，用于合并连续的同类错误（忽略附带的描述文本）
+    pub fn category(&self) -> &'static str {
         match self {
-            UsbError::RusbError(e) => Some(e),
-            UsbError::IoError(e) => Some(e),
-            _ => None,
+            UsbError::DeviceNotFound(_) => "device_not_found",
+            UsbError::OpenFailed(_) => "open_failed",
+            UsbError::SetConfigurationFailed(_) => "set_configuration_failed",
+            UsbError::ClaimInterfaceFailed { .. } => "claim_interface_failed",
+            UsbError::DetachFailed(_) => "detach_failed",
+            UsbError::EndpointNotFound(_) => "endpoint_not_found",
+            UsbError::CommandWriteFailed(_) => "command_write_failed",
+            UsbError::ResponseReadFailed(_) => "response_read_failed",
+            UsbError::ResponseParseError(_) => "response_parse_error",
+            UsbError::UnexpectedResponse => "unexpected_response",
+            UsbError::SubscriptionFailed(_) => "subscription_failed",
+            UsbError::RusbError(_) => "rusb",
+            UsbError::IoError(_) => "io",
+            UsbError::BinrwError(_) => "binrw",
+            UsbError::Timeout => "timeout",
+            UsbError::IncompletePayload { .. } => "incomplete_payload",
+            UsbError::LengthMismatch { .. } => "length_mismatch",
+            UsbError::UnsupportedProtocol { .. } => "unsupported_protocol",
+            UsbError::TaskFailed(_) => "task_failed",
+            UsbError::Other(_) => "other",
         }
     }
+
+    /// 是否属于数据帧解析类错误（而非连接/传输错误）
+    pub fn is_parse_error(&self) -> bool {
+        matches!(
+            self,
+            UsbError::ResponseParseError(_)
+                | UsbError::BinrwError(_)
+                | UsbError::UnexpectedResponse
+                | UsbError::LengthMismatch { .. }
+        )
+    }
+
+    /// 是否属于传输类错误：读写失败、超时，以及分片读取结束时仍未收齐一帧（字节没有到达，而不是无法解析）
+    pub fn is_transport_error(&self) -> bool {
+        matches!(
+            self,
+            UsbError::CommandWriteFailed(_)
+                | UsbError::ResponseReadFailed(_)
+                | UsbError::RusbError(_)
+                | UsbError::IoError(_)
+                | UsbError::Timeout
+                | UsbError::IncompletePayload { .. }
+        )
+    }
 }
 
 impl From<rusb::Error> for UsbError {
@@ -96,15 +420,87 @@ impl From<rusb::Error> for UsbError {
     }
 }
 
-impl From<std::io::Error> for UsbError {
-    fn from(err: std::io::Error) -> Self {
-        UsbError::IoError(err)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol_versions_pick_the_payload_layout_and_display_the_firmware() {
+        assert!(!ProtocolVersion::LEGACY.payload_args().sequence);
+        let current = ProtocolVersion { protocol: SUPPORTED_PROTOCOL_VERSION, firmware: Some([1, 2, 0, 7]) };
+        assert!(current.payload_args().sequence);
+        assert_eq!(current.firmware_string().as_deref(), Some("1.2.0.7"));
+        assert_eq!(current.to_string(), format!("protocol v{}, firmware 1.2.0.7", SUPPORTED_PROTOCOL_VERSION));
+        assert_eq!(ProtocolVersion::LEGACY.to_string(), "protocol v1 (legacy firmware)");
+    }
+
+    #[test]
+    fn incomplete_payload_is_a_transport_error() {
+        let incomplete = UsbError::IncompletePayload { got: 64, expected: 78 };
+        assert!(incomplete.is_transport_error());
+        assert!(!incomplete.is_parse_error());
+
+        let mismatch = UsbError::LengthMismatch { magic: Some(0x01), got: 10, expected: 78, head: String::new() };
+        assert!(mismatch.is_parse_error());
+        assert!(!mismatch.is_transport_error());
     }
-}
 
-// Helper to convert binrw::Error to UsbError::BinrwError
-impl From<binrw::Error> for UsbError {
-    fn from(err: binrw::Error) -> Self {
-        UsbError::BinrwError(err.to_string())
+    #[test]
+    fn link_stats_count_each_error_class_once() {
+        let mut stats = UsbLinkStats::default();
+        stats.record_error(&UsbError::IncompletePayload { got: 64, expected: 78 });
+        stats.record_error(&UsbError::UnexpectedResponse);
+        stats.record_error(&UsbError::Timeout);
+        stats.record_error(&UsbError::Other("x".into()));
+        assert_eq!((stats.parse_errors, stats.timeouts, stats.transport_errors), (1, 1, 1));
     }
-}
\ No newline at end of file
+
+    fn pushes_at(offsets_ms: &[u64]) -> UsbLinkStats {
+        let start = Instant::now();
+        let mut stats = UsbLinkStats::default();
+        for offset in offsets_ms {
+            stats.record_push(start + Duration::from_millis(*offset));
+        }
+        stats
+    }
+
+    #[test]
+    fn the_push_interval_needs_two_frames() {
+        assert_eq!(pushes_at(&[]).avg_push_interval_ms, None);
+        assert_eq!(pushes_at(&[0]).avg_push_interval_ms, None);
+        assert_eq!(pushes_at(&[0, 1000]).avg_push_interval_ms, Some(1000.0));
+    }
+
+    #[test]
+    fn the_push_interval_is_the_mean_of_the_latest_window() {
+        assert_eq!(pushes_at(&[0, 900, 2000, 3000]).avg_push_interval_ms, Some(1000.0));
+
+        // 前 32 个间隔为 100 ms，之后 32 个为 500 ms：窗口只保留后者
+        let offsets: Vec<u64> = (0..=32).map(|i| i * 100).chain((1..=32).map(|i| 3200 + i * 500)).collect();
+        let stats = pushes_at(&offsets);
+        assert_eq!(stats.intervals.len(), LINK_INTERVAL_WINDOW);
+        assert_eq!(stats.avg_push_interval_ms, Some(500.0));
+    }
+
+    #[test]
+    fn a_push_stamped_earlier_than_the_last_counts_as_zero() {
+        let start = Instant::now() + Duration::from_secs(1);
+        let mut stats = UsbLinkStats::default();
+        stats.record_push(start);
+        stats.record_push(start - Duration::from_millis(10));
+        assert_eq!(stats.avg_push_interval_ms, Some(0.0));
+    }
+
+    #[test]
+    fn link_stats_serialize_without_the_interval_window() {
+        let mut stats = pushes_at(&[0, 250]);
+        stats.record_frame(78);
+        stats.record_timeout();
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["frames_received"], 1);
+        assert_eq!(json["bytes_received"], 78);
+        assert_eq!(json["timeouts"], 1);
+        assert_eq!(json["avg_push_interval_ms"], 250.0);
+        assert!(json.get("intervals").is_none() && json.get("last_push_at").is_none());
+    }
+}
@@ -9,12 +9,331 @@ pub fn adc_to_temperature(raw: i16) -> f32 {
     raw as f32 * 0.01
 }
 
-// No specific conversion functions like convert_bq25730 or convert_bq76920 are needed here
-// if the BinRead implementation in src/binrw_impls.rs correctly converts
-// raw byte data directly into the final host data model types with correct physical units.
+/// BQ25730 PSYS 的换算参数，对应板上的检流电阻与充电芯片的寄存器配置
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PsysConfig {
+    /// 输入检流电阻 RSNS_AC (mΩ)，通常为 5 或 10
+    pub rsns_ac_mohm: f32,
+    /// ChargeOption1.PSYS_RATIO：true 为 1 µA/W，false 为 0.25 µA/W
+    pub psys_ratio: bool,
+    /// ADCOption.ADC_FULLSCALE：true 为 3.06 V 满量程 (12 mV/LSB)，false 为 2.04 V (8 mV/LSB)
+    pub adc_fullscale: bool,
+}
+
+impl Default for PsysConfig {
+    fn default() -> Self {
+        PsysConfig {
+            rsns_ac_mohm: 10.0,
+            psys_ratio: false,
+            adc_fullscale: true,
+        }
+    }
+}
+
+/// PSYS 每个 ADC 计数对应的功率 (W)。
+///
+/// 基准为 RSNS_AC = 10 mΩ、PSYS_RATIO = 0、ADC_FULLSCALE = 1 时的 1.28 W/LSB：
+/// ADC LSB 为 12 mV，8 mV 满量程时按比例缩小；PSYS_RATIO = 1 时输出电流放大 4 倍，
+/// 每 LSB 对应的功率为 1/4；检流电阻减半时同样的检流电压对应两倍功率。
+pub fn psys_lsb_watts(cfg: &PsysConfig) -> f32 {
+    let adc_lsb_mv = if cfg.adc_fullscale { 12.0 } else { 8.0 };
+    let ratio_gain = if cfg.psys_ratio { 4.0 } else { 1.0 };
+    1.28 * (adc_lsb_mv / 12.0) * (10.0 / cfg.rsns_ac_mohm) / ratio_gain
+}
+
+/// PSYS 原始 ADC 计数 -> 系统功率 (W)
+pub fn psys_raw_to_watts(raw: u16, cfg: &PsysConfig) -> f32 {
+    raw as f32 * psys_lsb_watts(cfg)
+}
+
+/// 系统功率 (W) -> PSYS 原始 ADC 计数，`psys_raw_to_watts` 的逆运算（四舍五入到最近的计数）
+pub fn watts_to_psys_raw(watts: f32, cfg: &PsysConfig) -> u16 {
+    (watts / psys_lsb_watts(cfg)).round().clamp(0.0, u16::MAX as f32) as u16
+}
+
+/// BQ76920 TS 通道按内部芯片温度换算时的常数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DieTempConfig {
+    /// 25°C 时的电压 (µV)
+    pub v25_uv: i32,
+    /// ADC 的 LSB (µV)
+    pub lsb_uv: i32,
+    /// 温度系数 (µV / 0.01°C)
+    pub uv_per_centidegree: i32,
+}
+
+impl Default for DieTempConfig {
+    fn default() -> Self {
+        DieTempConfig {
+            v25_uv: 1_200_000,
+            lsb_uv: 382,
+            uv_per_centidegree: 42,
+        }
+    }
+}
+
+/// TS 原始 ADC 值 -> 温度 (°C)：T = 25°C - (V - V25) / 温度系数，按 0.01°C 截断
+pub fn ts_raw_to_celsius(raw_adc: u16, cfg: &DieTempConfig) -> f32 {
+    let v_sensor_uv = raw_adc as i32 * cfg.lsb_uv;
+    let temp_diff_uv = v_sensor_uv - cfg.v25_uv;
+    let temp_cc = 2500i32 - (temp_diff_uv / cfg.uv_per_centidegree);
+    temp_cc as f32 / 100.0
+}
+
+/// 温度 (°C) -> TS 原始 ADC 值，`ts_raw_to_celsius` 的逆运算。
+/// 正向换算截断到 0.01°C（小于 1 LSB），这里四舍五入到最近的计数，还原误差不超过 1 LSB
+pub fn celsius_to_ts_raw(celsius: f32, cfg: &DieTempConfig) -> u16 {
+    let temp_cc = (celsius * 100.0).round() as i32;
+    let v_sensor_uv = cfg.v25_uv + (2500 - temp_cc) * cfg.uv_per_centidegree;
+    ((v_sensor_uv + cfg.lsb_uv / 2) / cfg.lsb_uv).clamp(0, u16::MAX as i32) as u16
+}
+
+/// 外接 NTC 热敏电阻的参数（β 方程）。BQ76920 在热敏电阻模式下通过内部 10 kΩ 上拉到
+/// REGOUT (3.3 V) 驱动 TS 引脚，引脚电压与 NTC 阻值一一对应
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThermistorConfig {
+    /// β 值 (K)
+    pub beta: f32,
+    /// 25°C 时的阻值 (Ω)
+    pub r25_ohms: f32,
+}
+
+impl Default for ThermistorConfig {
+    fn default() -> Self {
+        ThermistorConfig {
+            beta: 3435.0,
+            r25_ohms: 10_000.0,
+        }
+    }
+}
+
+const TS_PULLUP_OHMS: f32 = 10_000.0;
+const TS_REGOUT_V: f32 = 3.3;
+const TS_LSB_V: f32 = 382e-6;
+const KELVIN_25C: f32 = 298.15;
+
+/// TS 原始 ADC 值 -> NTC 阻值 (Ω)
+pub fn ts_raw_to_thermistor_ohms(raw_adc: u16) -> f32 {
+    let v_ts = (raw_adc as f32 * TS_LSB_V).min(TS_REGOUT_V - TS_LSB_V);
+    TS_PULLUP_OHMS * v_ts / (TS_REGOUT_V - v_ts)
+}
+
+/// NTC 阻值 (Ω) -> 温度 (°C)：1/T = 1/T25 + ln(R/R25)/β
+pub fn thermistor_ohms_to_celsius(ohms: f32, cfg: &ThermistorConfig) -> f32 {
+    let inv_t = 1.0 / KELVIN_25C + (ohms.max(f32::MIN_POSITIVE) / cfg.r25_ohms).ln() / cfg.beta;
+    1.0 / inv_t - 273.15
+}
+
+/// 热敏电阻模式下 TS 原始 ADC 值 -> 温度 (°C)
+pub fn thermistor_raw_to_celsius(raw_adc: u16, cfg: &ThermistorConfig) -> f32 {
+    thermistor_ohms_to_celsius(ts_raw_to_thermistor_ohms(raw_adc), cfg)
+}
+
+/// 温度 (°C) -> 热敏电阻模式下的 TS 原始 ADC 值，`thermistor_raw_to_celsius` 的逆运算
+pub fn celsius_to_thermistor_raw(celsius: f32, cfg: &ThermistorConfig) -> u16 {
+    let ohms = cfg.r25_ohms * (cfg.beta * (1.0 / (celsius + 273.15) - 1.0 / KELVIN_25C)).exp();
+    let v_ts = TS_REGOUT_V * ohms / (TS_PULLUP_OHMS + ohms);
+    (v_ts / TS_LSB_V).round().clamp(0.0, u16::MAX as f32) as u16
+}
+
+/// BQ25730 的检流电阻，决定 ICHG / IDCHG / IIN 的 ADC LSB
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CurrentSenseConfig {
+    /// 电池侧检流电阻 RSNS_RSR (mΩ)，影响 ICHG 与 IDCHG
+    pub rsns_bat_mohm: f32,
+    /// 输入检流电阻 RSNS_RAC (mΩ)，影响 IIN 与 PSYS
+    pub rsns_ac_mohm: f32,
+}
+
+impl Default for CurrentSenseConfig {
+    fn default() -> Self {
+        CurrentSenseConfig {
+            rsns_bat_mohm: 10.0,
+            rsns_ac_mohm: 10.0,
+        }
+    }
+}
+
+// 数据手册给出的 10 mΩ 检流电阻下的 ADC LSB (mA)；5 mΩ 时加倍
+const ICHG_LSB_MA_10MOHM: f32 = 64.0;
+const IDCHG_LSB_MA_10MOHM: f32 = 256.0;
+const IIN_LSB_MA_10MOHM: f32 = 50.0;
+
+impl CurrentSenseConfig {
+    /// 充电电流 ADC 的 LSB (mA)：10 mΩ 为 64 mA，5 mΩ 为 128 mA
+    pub fn ichg_lsb_ma(&self) -> f32 {
+        ICHG_LSB_MA_10MOHM * 10.0 / self.rsns_bat_mohm
+    }
+
+    /// 放电电流 ADC 的 LSB (mA)：10 mΩ 为 256 mA，5 mΩ 为 512 mA
+    pub fn idchg_lsb_ma(&self) -> f32 {
+        IDCHG_LSB_MA_10MOHM * 10.0 / self.rsns_bat_mohm
+    }
+
+    /// 输入电流 ADC 的 LSB (mA)：10 mΩ 为 50 mA，5 mΩ 为 100 mA
+    pub fn iin_lsb_ma(&self) -> f32 {
+        IIN_LSB_MA_10MOHM * 10.0 / self.rsns_ac_mohm
+    }
+}
+
+/// 电流 ADC 原始计数 -> 电流 (A)
+pub fn current_raw_to_amps(raw: u16, lsb_ma: f32) -> f32 {
+    raw as f32 * lsb_ma / 1000.0
+}
+
+/// 电流 (A) -> ADC 原始计数，`current_raw_to_amps` 的逆运算（四舍五入到最近的计数）
+pub fn amps_to_current_raw(amps: f32, lsb_ma: f32) -> u16 {
+    (amps * 1000.0 / lsb_ma).round().clamp(0.0, u16::MAX as f32) as u16
+}
+
+/// 当前 Unix 时间戳 (毫秒)
+pub fn unix_ms_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// 先写临时文件再 rename，掉电时目标文件要么是旧内容要么是新内容
+pub fn write_atomic(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+// No specific conversion functions like convert_bq25730 or convert_bq76920 are needed here;
+// src/conversion.rs converts the raw USB payload into the final host data model types
+// with correct physical units, driven by an immutable ConversionContext.
 
 // Imports that might be needed by other utility functions, if any, can be added here.
 // For now, keeping it minimal.
 // use crate::data_models::{...};
 // use bq25730_async_rs::data_types::{...};
-// use bq769x0_async_rs::data_types::{...};
\ No newline at end of file
+// use bq769x0_async_rs::data_types::{...};
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::diagnostics::TS_ADC_MAX;
+
+    fn psys(rsns_ac_mohm: f32, psys_ratio: bool, adc_fullscale: bool) -> PsysConfig {
+        PsysConfig { rsns_ac_mohm, psys_ratio, adc_fullscale }
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() <= 1e-4 * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn psys_lsb_follows_the_sense_resistor_ratio_and_full_scale() {
+        // 10 mΩ、PSYS_RATIO = 0、3.06 V 满量程是换算的基准
+        assert!(close(psys_lsb_watts(&PsysConfig::default()), 1.28));
+        assert!(close(psys_lsb_watts(&psys(5.0, false, true)), 2.56));
+        assert!(close(psys_lsb_watts(&psys(10.0, true, true)), 0.32));
+        assert!(close(psys_lsb_watts(&psys(10.0, false, false)), 1.28 * 8.0 / 12.0));
+        assert!(close(psys_lsb_watts(&psys(5.0, true, false)), 1.28 * 2.0 / 4.0 * 8.0 / 12.0));
+    }
+
+    #[test]
+    fn psys_examples_convert_to_watts() {
+        assert!(close(psys_raw_to_watts(0, &PsysConfig::default()), 0.0));
+        // 50 个计数：基准下 64 W，PSYS_RATIO = 1 时 16 W
+        assert!(close(psys_raw_to_watts(50, &PsysConfig::default()), 64.0));
+        assert!(close(psys_raw_to_watts(50, &psys(10.0, true, true)), 16.0));
+        assert!(close(psys_raw_to_watts(50, &psys(5.0, false, true)), 128.0));
+    }
+
+    #[test]
+    fn psys_inverse_round_trips_every_configuration() {
+        for cfg in [PsysConfig::default(), psys(5.0, false, true), psys(10.0, true, false), psys(5.0, true, false)] {
+            for raw in [0u16, 1, 7, 50, 255, 1023] {
+                assert_eq!(watts_to_psys_raw(psys_raw_to_watts(raw, &cfg), &cfg), raw, "{:?}", cfg);
+            }
+            assert_eq!(watts_to_psys_raw(-3.0, &cfg), 0);
+            assert_eq!(watts_to_psys_raw(f32::MAX, &cfg), u16::MAX);
+        }
+    }
+
+    #[test]
+    fn current_lsbs_match_the_datasheet_table() {
+        let ten = CurrentSenseConfig::default();
+        let five = CurrentSenseConfig { rsns_bat_mohm: 5.0, rsns_ac_mohm: 5.0 };
+        assert_eq!((ten.ichg_lsb_ma(), ten.idchg_lsb_ma(), ten.iin_lsb_ma()), (64.0, 256.0, 50.0));
+        assert_eq!((five.ichg_lsb_ma(), five.idchg_lsb_ma(), five.iin_lsb_ma()), (128.0, 512.0, 100.0));
+        // 两个电阻互不影响
+        let mixed = CurrentSenseConfig { rsns_bat_mohm: 5.0, rsns_ac_mohm: 10.0 };
+        assert_eq!((mixed.ichg_lsb_ma(), mixed.iin_lsb_ma()), (128.0, 50.0));
+    }
+
+    #[test]
+    fn a_5_mohm_board_reports_twice_the_current_per_count() {
+        let five = CurrentSenseConfig { rsns_bat_mohm: 5.0, rsns_ac_mohm: 5.0 };
+        // ICHG 寄存器 0x20 (32 个计数)：10 mΩ 为 2.048 A，5 mΩ 为 4.096 A
+        assert!(close(current_raw_to_amps(32, CurrentSenseConfig::default().ichg_lsb_ma()), 2.048));
+        assert!(close(current_raw_to_amps(32, five.ichg_lsb_ma()), 4.096));
+        assert!(close(current_raw_to_amps(10, five.idchg_lsb_ma()), 5.12));
+        assert!(close(current_raw_to_amps(40, five.iin_lsb_ma()), 4.0));
+    }
+
+    #[test]
+    fn current_inverse_round_trips_and_clamps() {
+        let five = CurrentSenseConfig { rsns_bat_mohm: 5.0, rsns_ac_mohm: 5.0 };
+        for lsb in [five.ichg_lsb_ma(), five.idchg_lsb_ma(), CurrentSenseConfig::default().iin_lsb_ma()] {
+            for raw in [0u16, 1, 32, 127, 255] {
+                assert_eq!(amps_to_current_raw(current_raw_to_amps(raw, lsb), lsb), raw);
+            }
+            assert_eq!(amps_to_current_raw(-1.0, lsb), 0);
+        }
+    }
+
+    #[test]
+    fn die_temperature_reference_points() {
+        let cfg = DieTempConfig::default();
+        // 1.2 V 对应 25°C：3141 × 382 µV = 1.199862 V
+        assert!((ts_raw_to_celsius(3141, &cfg) - 25.03).abs() < 0.01);
+        assert_eq!(celsius_to_ts_raw(25.0, &cfg), 3141);
+        // 电压越高温度越低
+        assert!(ts_raw_to_celsius(3500, &cfg) < ts_raw_to_celsius(3000, &cfg));
+    }
+
+    #[test]
+    fn die_temperature_inverse_is_within_one_lsb_over_the_adc_range() {
+        let cfg = DieTempConfig::default();
+        for raw in 0..=TS_ADC_MAX {
+            let back = celsius_to_ts_raw(ts_raw_to_celsius(raw, &cfg), &cfg);
+            assert!(back.abs_diff(raw) <= 1, "{} -> {}", raw, back);
+        }
+    }
+
+    #[test]
+    fn thermistor_known_points_are_within_half_a_degree() {
+        let cfg = ThermistorConfig::default();
+        // 3435K / 10 kΩ NTC 在各温度下的标称阻值 (取整到 10 Ω)
+        for (ohms, celsius) in [(46_290.0, -10.0), (28_700.0, 0.0), (10_000.0, 25.0), (4_100.0, 50.0), (1_450.0, 85.0)] {
+            let got = thermistor_ohms_to_celsius(ohms, &cfg);
+            assert!((got - celsius).abs() <= 0.5, "{} Ω: {} °C", ohms, got);
+        }
+        let steeper = ThermistorConfig { beta: 3950.0, r25_ohms: 100_000.0 };
+        assert!((thermistor_ohms_to_celsius(100_000.0, &steeper) - 25.0).abs() < 0.01);
+        assert!((thermistor_ohms_to_celsius(35_880.0, &steeper) - 50.0).abs() <= 0.5);
+    }
+
+    #[test]
+    fn thermistor_mode_reads_the_pin_divider() {
+        let cfg = ThermistorConfig::default();
+        // 10 kΩ 上拉与 10 kΩ NTC 分压，TS 引脚为 REGOUT 的一半
+        let raw = celsius_to_thermistor_raw(25.0, &cfg);
+        assert_eq!(raw, (1.65 / 382e-6f32).round() as u16);
+        assert!((ts_raw_to_thermistor_ohms(raw) - 10_000.0).abs() < 5.0);
+        for celsius in [-20.0, 0.0, 25.0, 45.0, 80.0] {
+            let back = thermistor_raw_to_celsius(celsius_to_thermistor_raw(celsius, &cfg), &cfg);
+            assert!((back - celsius).abs() <= 0.5, "{} -> {}", celsius, back);
+        }
+    }
+}
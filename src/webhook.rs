@@ -0,0 +1,473 @@
+//! Webhook 通知（配置文件 `[[webhooks]]`）：故障标志变化、阈值告警激活/清除时 POST 到指定地址，
+//! 如 ntfy、Slack incoming webhook。
+//!
+//! 默认请求体为 JSON `{"event", "name", "field", "old", "new", "value", "device", "ts_unix_ms"}`；
+//! 配置 `template` 时按 `{字段}` 替换生成请求体，以适配不同服务的格式。每个 webhook 有独立的队列与
+//! 发送任务，网络错误与 5xx / 429 按退避重试几次，主循环投递事件从不阻塞。
+
+use std::time::Duration;
+
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+
+use crate::alarms::AlarmEvent;
+use crate::backoff::Backoff;
+use crate::config::ConfigError;
+use crate::flag_events::FlagEvent;
+use crate::usb_types::DeviceId;
+
+// 每个 webhook 的待发送事件数，溢出时丢弃新事件
+const WEBHOOK_QUEUE_SIZE: usize = 64;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_INITIAL: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// 可订阅的事件类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventKind {
+    /// 充电器故障与电池保护标志的变化
+    Fault,
+    /// 其余状态标志的变化
+    Flag,
+    /// 阈值告警激活或清除
+    Alarm,
+}
+
+/// 一个 webhook，来自配置文件 `[[webhooks]]`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// 设置时附带 `Authorization: Bearer <token>`
+    pub token: Option<String>,
+    /// 发送的事件类型，默认故障与告警
+    #[serde(default = "default_events")]
+    pub events: Vec<WebhookEventKind>,
+    /// 请求体模板，`{event}`、`{name}`、`{field}`、`{old}`、`{new}`、`{value}`、`{device}`、
+    /// `{ts_unix_ms}` 替换为事件内容；未设置时发送事件 JSON
+    pub template: Option<String>,
+    /// 使用模板时的 Content-Type
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    /// 暂时性失败的最大重试次数
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+fn default_events() -> Vec<WebhookEventKind> {
+    vec![WebhookEventKind::Fault, WebhookEventKind::Alarm]
+}
+
+fn default_content_type() -> String {
+    "application/json".to_string()
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+impl WebhookConfig {
+    pub fn parsed_url(&self) -> Result<Url, String> {
+        let url = Url::parse(&self.url).map_err(|e| e.to_string())?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err("expected an http:// or https:// URL".to_string());
+        }
+        Ok(url)
+    }
+}
+
+pub fn validate_webhooks(webhooks: &[WebhookConfig]) -> Result<(), ConfigError> {
+    for webhook in webhooks {
+        let reason = match webhook.parsed_url() {
+            Err(reason) => reason,
+            Ok(_) if webhook.events.is_empty() => "events must not be empty".to_string(),
+            Ok(_) if webhook.retries > 10 => "retries must be at most 10".to_string(),
+            Ok(_) => continue,
+        };
+        return Err(ConfigError::Invalid {
+            key: "webhooks",
+            value: webhook.url.clone(),
+            reason,
+        });
+    }
+    Ok(())
+}
+
+/// 发送给 webhook 的事件
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WebhookEvent {
+    pub event: WebhookEventKind,
+    /// 标志名或告警名
+    pub name: String,
+    /// 受影响的字段：标志名，或告警规则的扁平键
+    pub field: String,
+    pub old: Value,
+    pub new: Value,
+    /// 告警触发时的数值；标志事件为 null
+    pub value: Option<f64>,
+    pub device: String,
+    pub ts_unix_ms: u64,
+}
+
+impl WebhookEvent {
+    pub fn from_flag(device: &DeviceId, event: &FlagEvent) -> Self {
+        WebhookEvent {
+            event: if event.is_fault() { WebhookEventKind::Fault } else { WebhookEventKind::Flag },
+            name: event.flag.to_string(),
+            field: event.flag.to_string(),
+            old: Value::Bool(event.old),
+            new: Value::Bool(event.new),
+            value: None,
+            device: device.to_string(),
+            ts_unix_ms: event.ts_unix_ms,
+        }
+    }
+
+    pub fn from_alarm(device: &DeviceId, event: &AlarmEvent) -> Self {
+        WebhookEvent {
+            event: WebhookEventKind::Alarm,
+            name: event.name.clone(),
+            field: event.field.clone(),
+            old: event.previous.map_or(Value::Null, |state| Value::String(state.to_string())),
+            new: Value::String(event.state.to_string()),
+            value: Some(event.value),
+            device: device.to_string(),
+            ts_unix_ms: event.ts_unix_ms,
+        }
+    }
+
+    /// 按模板生成请求体；未知的占位符原样保留
+    pub fn render(&self, template: &str) -> String {
+        let text = |value: &Value| match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        let event = match self.event {
+            WebhookEventKind::Fault => "fault",
+            WebhookEventKind::Flag => "flag",
+            WebhookEventKind::Alarm => "alarm",
+        };
+        let replacements = [
+            ("{event}", event.to_string()),
+            ("{name}", self.name.clone()),
+            ("{field}", self.field.clone()),
+            ("{old}", text(&self.old)),
+            ("{new}", text(&self.new)),
+            ("{value}", self.value.map(|v| v.to_string()).unwrap_or_default()),
+            ("{device}", self.device.clone()),
+            ("{ts_unix_ms}", self.ts_unix_ms.to_string()),
+        ];
+        let mut body = template.to_string();
+        for (key, value) in &replacements {
+            body = body.replace(key, value);
+        }
+        body
+    }
+}
+
+/// 主循环与告警任务持有的句柄；投递事件从不阻塞
+#[derive(Clone)]
+pub struct WebhookHandle {
+    sinks: Vec<(Vec<WebhookEventKind>, mpsc::Sender<WebhookEvent>)>,
+}
+
+impl WebhookHandle {
+    pub fn notify(&self, event: WebhookEvent) {
+        for (kinds, tx) in &self.sinks {
+            if !kinds.contains(&event.event) {
+                continue;
+            }
+            if let Err(e) = tx.try_send(event.clone()) {
+                warn!("webhook 队列已满或已关闭，丢弃事件 {}: {}", event.name, e);
+            }
+        }
+    }
+}
+
+/// 为每个 webhook 启动发送任务；未配置任何 webhook 时返回 None
+pub fn spawn_webhooks(webhooks: &[WebhookConfig]) -> Result<Option<WebhookHandle>, String> {
+    if webhooks.is_empty() {
+        return Ok(None);
+    }
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut sinks = Vec::with_capacity(webhooks.len());
+    for webhook in webhooks {
+        let url = webhook.parsed_url()?;
+        info!("webhook: {} (事件 {:?})", url.host_str().unwrap_or("?"), webhook.events);
+        let (tx, rx) = mpsc::channel(WEBHOOK_QUEUE_SIZE);
+        tokio::spawn(webhook_task(client.clone(), url, webhook.clone(), rx));
+        sinks.push((webhook.events.clone(), tx));
+    }
+    Ok(Some(WebhookHandle { sinks }))
+}
+
+async fn webhook_task(client: reqwest::Client, url: Url, config: WebhookConfig, mut rx: mpsc::Receiver<WebhookEvent>) {
+    while let Some(event) = rx.recv().await {
+        let (body, content_type) = match &config.template {
+            Some(template) => (event.render(template), config.content_type.as_str()),
+            None => match serde_json::to_string(&event) {
+                Ok(body) => (body, "application/json"),
+                Err(e) => {
+                    error!("序列化 webhook 事件失败: {:?}", e);
+                    continue;
+                }
+            },
+        };
+        let mut backoff = Backoff::new(RETRY_INITIAL, RETRY_MAX);
+        let mut attempt = 0;
+        loop {
+            match deliver(&client, &url, config.token.as_deref(), content_type, body.clone()).await {
+                Ok(()) => {
+                    debug!("webhook 已发送事件 {}", event.name);
+                    break;
+                }
+                Err(Delivery::Rejected(status)) => {
+                    error!(url = %url, status = %status, event = %event.name, "webhook 拒绝请求，丢弃事件");
+                    break;
+                }
+                Err(Delivery::Retry(message)) if attempt < config.retries => {
+                    attempt += 1;
+                    let delay = backoff.next_delay();
+                    warn!(url = %url, error = %message, attempt, "webhook 发送失败，{:?} 后重试", delay);
+                    tokio::time::sleep(delay).await;
+                }
+                Err(Delivery::Retry(message)) => {
+                    error!(url = %url, error = %message, event = %event.name, "webhook 重试次数已用尽，丢弃事件");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+enum Delivery {
+    /// 4xx（超时与限流除外），重试也不会成功
+    Rejected(StatusCode),
+    /// 网络错误、超时、5xx 或限流
+    Retry(String),
+}
+
+async fn deliver(
+    client: &reqwest::Client,
+    url: &Url,
+    token: Option<&str>,
+    content_type: &str,
+    body: String,
+) -> Result<(), Delivery> {
+    let mut request = client.post(url.clone()).header("Content-Type", content_type).body(body);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await.map_err(|e| Delivery::Retry(e.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else if status.is_client_error() && status != StatusCode::REQUEST_TIMEOUT && status != StatusCode::TOO_MANY_REQUESTS {
+        Err(Delivery::Rejected(status))
+    } else {
+        Err(Delivery::Retry(status.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::sync::Notify;
+
+    use super::*;
+
+    /// 收到的一个请求：请求头 (小写名称) 与请求体
+    #[derive(Debug, Clone)]
+    struct Received {
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+        }
+    }
+
+    /// 本机 HTTP 服务：记录每个请求，依次按 `statuses` 回复，用完后回复 200
+    struct TestServer {
+        url: String,
+        received: Arc<Mutex<Vec<Received>>>,
+        notify: Arc<Notify>,
+    }
+
+    impl TestServer {
+        async fn start(statuses: &[u16]) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/hook", listener.local_addr().unwrap());
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let notify = Arc::new(Notify::new());
+            let statuses = Arc::new(Mutex::new(statuses.to_vec()));
+            let (server_received, server_notify) = (received.clone(), notify.clone());
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut reader = BufReader::new(reader);
+                    let mut headers = Vec::new();
+                    let mut line = String::new();
+                    reader.read_line(&mut line).await.unwrap();
+                    loop {
+                        line.clear();
+                        reader.read_line(&mut line).await.unwrap();
+                        let Some((key, value)) = line.trim_end().split_once(':') else {
+                            break;
+                        };
+                        headers.push((key.to_ascii_lowercase(), value.trim().to_string()));
+                    }
+                    let len = headers.iter().find(|(k, _)| k == "content-length").map_or(0, |(_, v)| v.parse().unwrap());
+                    let mut body = vec![0; len];
+                    reader.read_exact(&mut body).await.unwrap();
+                    server_received.lock().unwrap().push(Received { headers, body: String::from_utf8(body).unwrap() });
+                    let status = {
+                        let mut statuses = statuses.lock().unwrap();
+                        if statuses.is_empty() { 200 } else { statuses.remove(0) }
+                    };
+                    let response = format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+                    let _ = writer.write_all(response.as_bytes()).await;
+                    server_notify.notify_one();
+                }
+            });
+            TestServer { url, received, notify }
+        }
+
+        async fn wait_for(&self, count: usize) -> Vec<Received> {
+            tokio::time::timeout(Duration::from_secs(10), async {
+                loop {
+                    let notified = self.notify.notified();
+                    if self.received.lock().unwrap().len() >= count {
+                        return;
+                    }
+                    notified.await;
+                }
+            })
+            .await
+            .expect("webhook 请求未到达");
+            self.received.lock().unwrap().clone()
+        }
+    }
+
+    fn config(toml: &str) -> WebhookConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn device() -> DeviceId {
+        DeviceId::Serial("UPS01".to_string())
+    }
+
+    fn scd() -> FlagEvent {
+        FlagEvent { flag: "system_scd", old: false, new: true, ts_unix_ms: 1000 }
+    }
+
+    fn alarm() -> AlarmEvent {
+        AlarmEvent {
+            name: "low_soc".to_string(),
+            field: "battery.soc_percent".to_string(),
+            state: "active",
+            previous: Some("clear"),
+            severity: crate::data_models::Severity::Warning,
+            value: 8.5,
+            threshold: 10.0,
+            rate_per_sec: None,
+            ts_unix_ms: 2000,
+            acknowledged: false,
+            ack: None,
+            was_acknowledged: false,
+        }
+    }
+
+    #[test]
+    fn flag_events_are_classified() {
+        assert_eq!(WebhookEvent::from_flag(&device(), &scd()).event, WebhookEventKind::Fault);
+        let acoc = FlagEvent { flag: "charger_fault_acoc", ..scd() };
+        assert_eq!(WebhookEvent::from_flag(&device(), &acoc).event, WebhookEventKind::Fault);
+        let stat_ac = FlagEvent { flag: "charger_stat_ac", ..scd() };
+        assert_eq!(WebhookEvent::from_flag(&device(), &stat_ac).event, WebhookEventKind::Flag);
+    }
+
+    #[test]
+    fn templates_substitute_each_field() {
+        let event = WebhookEvent::from_alarm(&device(), &alarm());
+        assert_eq!(
+            event.render("{event} {name} {field} {old}->{new} {value} {device} {ts_unix_ms} {unknown}"),
+            "alarm low_soc battery.soc_percent clear->active 8.5 UPS01 2000 {unknown}"
+        );
+        assert_eq!(WebhookEvent::from_flag(&device(), &scd()).render("{old}->{new} [{value}]"), "false->true []");
+    }
+
+    #[test]
+    fn webhooks_are_validated() {
+        assert!(validate_webhooks(&[config("url = \"https://ntfy.sh/ups\"")]).is_ok());
+        assert!(validate_webhooks(&[config("url = \"ftp://example.com\"")]).is_err());
+        assert!(validate_webhooks(&[config("url = \"https://ntfy.sh/ups\"\nevents = []")]).is_err());
+        assert!(validate_webhooks(&[config("url = \"https://ntfy.sh/ups\"\nretries = 11")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn events_are_delivered_as_json_with_the_token() {
+        let server = TestServer::start(&[]).await;
+        let handle = spawn_webhooks(&[config(&format!("url = \"{}\"\ntoken = \"s3cret\"", server.url))]).unwrap().unwrap();
+        // 默认不订阅普通标志变化
+        handle.notify(WebhookEvent::from_flag(&device(), &FlagEvent { flag: "charger_stat_ac", ..scd() }));
+        handle.notify(WebhookEvent::from_flag(&device(), &scd()));
+        handle.notify(WebhookEvent::from_alarm(&device(), &alarm()));
+        let received = server.wait_for(2).await;
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].header("authorization"), Some("Bearer s3cret"));
+        assert_eq!(received[0].header("content-type"), Some("application/json"));
+        let flag: Value = serde_json::from_str(&received[0].body).unwrap();
+        assert_eq!(
+            flag,
+            serde_json::json!({
+                "event": "fault", "name": "system_scd", "field": "system_scd", "old": false, "new": true,
+                "value": null, "device": "UPS01", "ts_unix_ms": 1000,
+            })
+        );
+        let alarm: Value = serde_json::from_str(&received[1].body).unwrap();
+        assert_eq!((alarm["event"].as_str(), alarm["old"].as_str(), alarm["value"].as_f64()), (Some("alarm"), Some("clear"), Some(8.5)));
+    }
+
+    #[tokio::test]
+    async fn templates_set_the_body_and_content_type() {
+        let server = TestServer::start(&[]).await;
+        let toml = format!("url = \"{}\"\ntemplate = \"{{name}} on {{device}}\"\ncontent_type = \"text/plain\"", server.url);
+        let handle = spawn_webhooks(&[config(&toml)]).unwrap().unwrap();
+        handle.notify(WebhookEvent::from_flag(&device(), &scd()));
+        let received = server.wait_for(1).await;
+        assert_eq!(received[0].body, "system_scd on UPS01");
+        assert_eq!(received[0].header("content-type"), Some("text/plain"));
+        assert_eq!(received[0].header("authorization"), None);
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried_and_rejections_are_not() {
+        let server = TestServer::start(&[503, 200, 400]).await;
+        let handle = spawn_webhooks(&[config(&format!("url = \"{}\"", server.url))]).unwrap().unwrap();
+        handle.notify(WebhookEvent::from_flag(&device(), &scd()));
+        handle.notify(WebhookEvent::from_alarm(&device(), &alarm()));
+        handle.notify(WebhookEvent::from_flag(&device(), &FlagEvent { ts_unix_ms: 3000, ..scd() }));
+        // 第一个事件 503 后重试成功，第二个被 400 拒绝后不再重试，第三个照常发送
+        let received = server.wait_for(4).await;
+        let timestamps: Vec<String> = received
+            .iter()
+            .map(|r| serde_json::from_str::<Value>(&r.body).unwrap()["ts_unix_ms"].to_string())
+            .collect();
+        assert_eq!(timestamps, ["1000", "1000", "2000", "3000"]);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(server.received.lock().unwrap().len(), 4);
+    }
+}
@@ -0,0 +1,611 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::Args;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet};
+
+use crate::config::{parse_hex, validate_topic_prefix};
+use crate::mqtt_handlers::fatal_connection_error;
+
+/// pid.codes 的 VID，UPS120 固件使用该 VID
+pub const DEFAULT_VID: u16 = 0x1209;
+const DEFAULT_PID: u16 = 0x0002;
+
+/// `ups120-daemon init` 的参数；给出的值作为默认答案，`--non-interactive` 时直接采用
+#[derive(Debug, Clone, Args)]
+pub struct InitArgs {
+    /// 输出文件
+    #[arg(long, short, default_value = ".env")]
+    pub output: PathBuf,
+    /// 覆盖已存在的文件
+    #[arg(long)]
+    pub force: bool,
+    /// 不提问，全部使用命令行参数与默认值（脚本用）
+    #[arg(long)]
+    pub non_interactive: bool,
+    /// 写入前测试 MQTT 与 USB 连接
+    #[arg(long)]
+    pub test_connections: bool,
+    #[arg(long)]
+    pub host: Option<String>,
+    #[arg(long)]
+    pub port: Option<u16>,
+    #[arg(long)]
+    pub username: Option<String>,
+    #[arg(long)]
+    pub password: Option<String>,
+    #[arg(long)]
+    pub client_id: Option<String>,
+    #[arg(long)]
+    pub prefix: Option<String>,
+    /// 十六进制，如 0x1209
+    #[arg(long)]
+    pub vid: Option<String>,
+    #[arg(long)]
+    pub pid: Option<String>,
+}
+
+/// 向导的提问方式：终端交互，或由脚本/测试提供答案
+pub trait Prompter {
+    /// 返回用户输入；直接回车时返回 `default`
+    fn ask(&mut self, question: &str, default: &str) -> io::Result<String>;
+
+    fn confirm(&mut self, question: &str, default: bool) -> io::Result<bool> {
+        let hint = if default { "Y/n" } else { "y/N" };
+        loop {
+            let answer = self.ask(&format!("{} [{}]", question, hint), "")?;
+            match answer.trim().to_ascii_lowercase().as_str() {
+                "" => return Ok(default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => self.notify("请输入 y 或 n")?,
+            }
+        }
+    }
+
+    /// 输出提示信息（校验失败原因等）
+    fn notify(&mut self, message: &str) -> io::Result<()>;
+
+    /// 非交互模式下校验失败直接报错，不再重复提问
+    fn interactive(&self) -> bool {
+        true
+    }
+}
+
+/// 基于 stdin/stdout 的交互式提问
+pub struct StdioPrompter;
+
+impl Prompter for StdioPrompter {
+    fn ask(&mut self, question: &str, default: &str) -> io::Result<String> {
+        let mut stdout = io::stdout();
+        if default.is_empty() {
+            write!(stdout, "{}: ", question)?;
+        } else {
+            write!(stdout, "{} [{}]: ", question, default)?;
+        }
+        stdout.flush()?;
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stdin closed"));
+        }
+        let line = line.trim();
+        Ok(if line.is_empty() { default.to_string() } else { line.to_string() })
+    }
+
+    fn notify(&mut self, message: &str) -> io::Result<()> {
+        println!("{}", message);
+        Ok(())
+    }
+}
+
+/// 非交互模式：每个问题都采用默认值
+pub struct DefaultsPrompter;
+
+impl Prompter for DefaultsPrompter {
+    fn ask(&mut self, _question: &str, default: &str) -> io::Result<String> {
+        Ok(default.to_string())
+    }
+
+    fn notify(&mut self, message: &str) -> io::Result<()> {
+        eprintln!("{}", message);
+        Ok(())
+    }
+
+    fn interactive(&self) -> bool {
+        false
+    }
+}
+
+/// 枚举到的候选 USB 设备
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbCandidate {
+    pub vid: u16,
+    pub pid: u16,
+    pub bus: u8,
+    pub address: u8,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+}
+
+impl fmt::Display for UsbCandidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:04x} bus {} addr {} - {} (serial {})",
+            self.vid,
+            self.pid,
+            self.bus,
+            self.address,
+            self.product.as_deref().unwrap_or("unknown product"),
+            self.serial.as_deref().unwrap_or("-")
+        )
+    }
+}
+
+/// USB 设备枚举，测试时可替换为固定列表
+pub trait DeviceEnumerator {
+    fn candidates(&self, vid: u16) -> Vec<UsbCandidate>;
+
+    /// 确认设备可以打开（权限、占用等）
+    fn probe(&self, candidate: &UsbCandidate) -> Result<(), String>;
+}
+
+pub struct RusbEnumerator;
+
+impl DeviceEnumerator for RusbEnumerator {
+    fn candidates(&self, vid: u16) -> Vec<UsbCandidate> {
+        let Ok(devices) = rusb::devices() else {
+            return Vec::new();
+        };
+        devices
+            .iter()
+            .filter_map(|device| {
+                let desc = device.device_descriptor().ok()?;
+                if desc.vendor_id() != vid {
+                    return None;
+                }
+                // 字符串描述符需要打开设备，没有权限时只列出 VID/PID
+                let handle = device.open().ok();
+                let read = |f: fn(&rusb::DeviceHandle<rusb::GlobalContext>, &rusb::DeviceDescriptor) -> rusb::Result<String>| {
+                    handle.as_ref().and_then(|h| f(h, &desc).ok()).filter(|s| !s.trim().is_empty())
+                };
+                Some(UsbCandidate {
+                    vid: desc.vendor_id(),
+                    pid: desc.product_id(),
+                    bus: device.bus_number(),
+                    address: device.address(),
+                    product: read(rusb::DeviceHandle::read_product_string_ascii),
+                    serial: read(rusb::DeviceHandle::read_serial_number_string_ascii),
+                })
+            })
+            .collect()
+    }
+
+    fn probe(&self, candidate: &UsbCandidate) -> Result<(), String> {
+        let devices = rusb::devices().map_err(|e| e.to_string())?;
+        let device = devices
+            .iter()
+            .find(|d| d.bus_number() == candidate.bus && d.address() == candidate.address)
+            .ok_or_else(|| "device no longer present".to_string())?;
+        device.open().map(|_| ()).map_err(|e| e.to_string())
+    }
+}
+
+/// 向导收集到的配置
+#[derive(Debug, Clone, PartialEq)]
+pub struct WizardAnswers {
+    pub broker_host: String,
+    pub broker_port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub client_id: String,
+    pub topic_prefix: String,
+    pub usb_vid: u16,
+    pub usb_pid: u16,
+    /// 选中设备的序列号，仅作为注释记录
+    pub usb_serial: Option<String>,
+    pub publish_min_interval_ms: u64,
+    pub stats_interval_secs: u64,
+}
+
+#[derive(Debug)]
+pub enum WizardError {
+    Io(io::Error),
+    Invalid { field: &'static str, value: String, reason: String },
+    /// 目标文件已存在且未指定 `--force`
+    Exists(PathBuf),
+}
+
+impl fmt::Display for WizardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WizardError::Io(e) => write!(f, "I/O error: {}", e),
+            WizardError::Invalid { field, value, reason } => write!(f, "Invalid {} '{}': {}", field, value, reason),
+            WizardError::Exists(path) => write!(f, "{} already exists (use --force to overwrite)", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for WizardError {}
+
+impl From<io::Error> for WizardError {
+    fn from(e: io::Error) -> Self {
+        WizardError::Io(e)
+    }
+}
+
+// 提问并校验；交互模式下校验失败会重新提问
+fn ask_valid<T>(
+    prompter: &mut dyn Prompter,
+    field: &'static str,
+    question: &str,
+    default: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<T, WizardError> {
+    loop {
+        let answer = prompter.ask(question, default)?;
+        match parse(answer.trim()) {
+            Ok(value) => return Ok(value),
+            Err(reason) if prompter.interactive() => prompter.notify(&format!("无效的输入: {}", reason))?,
+            Err(reason) => return Err(WizardError::Invalid { field, value: answer, reason }),
+        }
+    }
+}
+
+fn optional(value: &str) -> Option<String> {
+    (!value.trim().is_empty()).then(|| value.trim().to_string())
+}
+
+/// 依次询问 broker、认证、主题前缀与 USB 设备，返回校验后的答案
+pub fn run_wizard(
+    args: &InitArgs,
+    prompter: &mut dyn Prompter,
+    devices: &dyn DeviceEnumerator,
+) -> Result<WizardAnswers, WizardError> {
+    let broker_host = ask_valid(
+        prompter,
+        "host",
+        "MQTT broker 地址",
+        args.host.as_deref().unwrap_or("localhost"),
+        |v| if v.is_empty() { Err("must not be empty".to_string()) } else { Ok(v.to_string()) },
+    )?;
+    let broker_port = ask_valid(
+        prompter,
+        "port",
+        "MQTT broker 端口",
+        &args.port.unwrap_or(1883).to_string(),
+        |v| match v.parse::<u16>() {
+            Ok(0) => Err("must not be 0".to_string()),
+            Ok(port) => Ok(port),
+            Err(e) => Err(e.to_string()),
+        },
+    )?;
+    let username = optional(&prompter.ask("MQTT 用户名（留空表示匿名）", args.username.as_deref().unwrap_or(""))?);
+    let password = match &username {
+        Some(_) => optional(&prompter.ask("MQTT 密码", args.password.as_deref().unwrap_or(""))?),
+        None => None,
+    };
+    let client_id = ask_valid(
+        prompter,
+        "client_id",
+        "MQTT 客户端 ID",
+        args.client_id.as_deref().unwrap_or("ups120_cli_client"),
+        |v| if v.is_empty() { Err("must not be empty".to_string()) } else { Ok(v.to_string()) },
+    )?;
+    let topic_prefix = ask_valid(
+        prompter,
+        "prefix",
+        "主题前缀",
+        args.prefix.as_deref().unwrap_or("ups120"),
+        |v| validate_topic_prefix(v).map(|()| v.to_string()),
+    )?;
+
+    let vid = match &args.vid {
+        Some(vid) => parse_hex(vid).map_err(|reason| WizardError::Invalid { field: "vid", value: vid.clone(), reason })?,
+        None => DEFAULT_VID,
+    };
+    let (usb_vid, usb_pid, usb_serial) = select_device(args, prompter, devices, vid)?;
+
+    let publish_min_interval_ms = ask_valid(prompter, "publish_min_interval_ms", "最小发布间隔 (ms，0 表示不限速)", "0", |v| {
+        v.parse::<u64>().map_err(|e| e.to_string())
+    })?;
+    let stats_interval_secs = ask_valid(prompter, "stats_interval_secs", "统计发布周期 (秒)", "30", |v| match v.parse::<u64>() {
+        Ok(0) => Err("must not be 0".to_string()),
+        Ok(secs) => Ok(secs),
+        Err(e) => Err(e.to_string()),
+    })?;
+
+    Ok(WizardAnswers {
+        broker_host,
+        broker_port,
+        username,
+        password,
+        client_id,
+        topic_prefix,
+        usb_vid,
+        usb_pid,
+        usb_serial,
+        publish_min_interval_ms,
+        stats_interval_secs,
+    })
+}
+
+// 列出该 VID 下的设备供选择；没有设备或指定了 --pid 时直接询问 PID
+fn select_device(
+    args: &InitArgs,
+    prompter: &mut dyn Prompter,
+    devices: &dyn DeviceEnumerator,
+    vid: u16,
+) -> Result<(u16, u16, Option<String>), WizardError> {
+    let candidates = if args.pid.is_some() { Vec::new() } else { devices.candidates(vid) };
+    if !candidates.is_empty() {
+        prompter.notify("检测到以下 USB 设备:")?;
+        for (i, candidate) in candidates.iter().enumerate() {
+            prompter.notify(&format!("  {}) {}", i + 1, candidate))?;
+        }
+        let index = ask_valid(prompter, "device", "选择设备编号", "1", |v| match v.parse::<usize>() {
+            Ok(n) if (1..=candidates.len()).contains(&n) => Ok(n - 1),
+            _ => Err(format!("expected 1..={}", candidates.len())),
+        })?;
+        let chosen = &candidates[index];
+        return Ok((chosen.vid, chosen.pid, chosen.serial.clone()));
+    }
+    if args.pid.is_none() {
+        prompter.notify(&format!("未检测到 VID {:#06x} 的 USB 设备，请手动输入 PID", vid))?;
+    }
+    let default_pid = args.pid.clone().unwrap_or_else(|| format!("{:#06x}", DEFAULT_PID));
+    let pid = ask_valid(prompter, "pid", "USB PID", &default_pid, parse_hex)?;
+    Ok((vid, pid, None))
+}
+
+/// 生成带注释的 .env 内容
+pub fn render_env(answers: &WizardAnswers) -> String {
+    let mut out = String::new();
+    out.push_str("# ups120-daemon 配置，由 `ups120-daemon init` 生成\n");
+    out.push_str("# 其余可选项及默认值见 README.md\n\n");
+    out.push_str("# MQTT broker\n");
+    out.push_str(&format!("MQTT_BROKER_HOST={}\n", answers.broker_host));
+    out.push_str(&format!("MQTT_BROKER_PORT={}\n", answers.broker_port));
+    match (&answers.username, &answers.password) {
+        (Some(username), password) => {
+            out.push_str(&format!("MQTT_USERNAME={}\n", quote_env_value(username)));
+            out.push_str(&format!("MQTT_PASSWORD={}\n", quote_env_value(password.as_deref().unwrap_or(""))));
+        }
+        (None, _) => out.push_str("# MQTT_USERNAME=\n# MQTT_PASSWORD=\n"),
+    }
+    out.push_str(&format!("MQTT_CLIENT_ID={}\n", answers.client_id));
+    out.push_str(&format!("MQTT_TOPIC_PREFIX={}\n\n", answers.topic_prefix));
+    out.push_str("# USB 设备\n");
+    if let Some(serial) = &answers.usb_serial {
+        out.push_str(&format!("# 选中设备的序列号: {}\n", serial));
+    }
+    out.push_str(&format!("USB_VID={:#06x}\n", answers.usb_vid));
+    out.push_str(&format!("USB_PID={:#06x}\n\n", answers.usb_pid));
+    out.push_str("# 发布间隔\n");
+    out.push_str(&format!("PUBLISH_MIN_INTERVAL_MS={}\n", answers.publish_min_interval_ms));
+    out.push_str(&format!("STATS_INTERVAL_SECS={}\n", answers.stats_interval_secs));
+    out
+}
+
+/// 按 dotenv 与 systemd `EnvironmentFile=` 都能还原的方式给值加双引号：转义 `\`、`"` 与 `$`
+/// （dotenv 会展开双引号内的 `$VAR`），空格与 `#` 在引号内按字面处理
+pub fn quote_env_value(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if matches!(c, '\\' | '"' | '$') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+// 配置中有 MQTT 密码，只允许属主读写
+#[cfg(unix)]
+const CONFIG_FILE_MODE: u32 = 0o600;
+
+// 新建的文件直接以 CONFIG_FILE_MODE 创建，避免写入凭据前有一段时间可被其他用户读取
+fn open_private(path: &Path, options: &mut OpenOptions) -> io::Result<std::fs::File> {
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(options, CONFIG_FILE_MODE);
+    let file = options.open(path)?;
+    // 已存在的文件不受 mode 影响，同样收紧权限
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(CONFIG_FILE_MODE))?;
+    Ok(file)
+}
+
+/// 写入配置文件（Unix 上权限为 0600）；文件已存在时除非 `force` 否则报错，不会覆盖
+pub fn write_config(path: &Path, contents: &str, force: bool) -> Result<(), WizardError> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    if force {
+        // 与 utils::write_atomic 相同：写入临时文件后改名替换
+        let tmp = path.with_extension("tmp");
+        let mut file = open_private(&tmp, OpenOptions::new().write(true).create(true).truncate(true))?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        return Ok(());
+    }
+    let mut file = match open_private(path, OpenOptions::new().write(true).create_new(true)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(WizardError::Exists(path.to_path_buf())),
+        Err(e) => return Err(e.into()),
+    };
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// 尝试连接 broker 并等待 ConnAck
+pub async fn test_mqtt(answers: &WizardAnswers) -> Result<(), String> {
+    let mut options = MqttOptions::new(format!("{}-init", answers.client_id), &answers.broker_host, answers.broker_port);
+    options.set_keep_alive(Duration::from_secs(5));
+    if let Some(username) = &answers.username {
+        options.set_credentials(username, answers.password.clone().unwrap_or_default());
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    let result = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => return Ok(()),
+                Ok(_) => {}
+                Err(e) => return Err(fatal_connection_error(&e).unwrap_or_else(|| e.to_string())),
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|_| Err("no ConnAck within 5s".to_string()));
+    if result.is_ok() {
+        let _ = client.disconnect().await;
+        let _ = tokio::time::timeout(Duration::from_secs(1), eventloop.poll()).await;
+    }
+    result
+}
+
+/// `ups120-daemon init` 入口
+pub async fn run(args: &InitArgs) -> Result<(), WizardError> {
+    if args.output.exists() && !args.force {
+        return Err(WizardError::Exists(args.output.clone()));
+    }
+    let mut stdio = StdioPrompter;
+    let mut defaults = DefaultsPrompter;
+    let prompter: &mut dyn Prompter = if args.non_interactive { &mut defaults } else { &mut stdio };
+    let devices = RusbEnumerator;
+    let answers = run_wizard(args, prompter, &devices)?;
+
+    let test = args.test_connections
+        || (!args.non_interactive && prompter.confirm("现在测试 MQTT 与 USB 连接吗?", true)?);
+    if test {
+        match test_mqtt(&answers).await {
+            Ok(()) => prompter.notify("MQTT 连接成功")?,
+            Err(e) => prompter.notify(&format!("MQTT 连接失败: {}", e))?,
+        }
+        let usb = devices
+            .candidates(answers.usb_vid)
+            .into_iter()
+            .find(|c| c.pid == answers.usb_pid && (answers.usb_serial.is_none() || c.serial == answers.usb_serial));
+        match usb {
+            Some(candidate) => match devices.probe(&candidate) {
+                Ok(()) => prompter.notify(&format!("USB 设备可以打开: {}", candidate))?,
+                Err(e) => prompter.notify(&format!("USB 设备无法打开: {}（检查权限或 udev 规则）", e))?,
+            },
+            None => prompter.notify("未找到所选 USB 设备")?,
+        }
+    }
+
+    write_config(&args.output, &render_env(&answers), args.force)?;
+    println!("配置已写入 {}", args.output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answers(password: &str) -> WizardAnswers {
+        WizardAnswers {
+            broker_host: "broker.local".to_string(),
+            broker_port: 1883,
+            username: Some("ups user".to_string()),
+            password: Some(password.to_string()),
+            client_id: "ups120".to_string(),
+            topic_prefix: "ups120".to_string(),
+            usb_vid: DEFAULT_VID,
+            usb_pid: DEFAULT_PID,
+            usb_serial: None,
+            publish_min_interval_ms: 1000,
+            stats_interval_secs: 60,
+        }
+    }
+
+    // 迭代器接口已标记为废弃，但只有它能在不修改进程环境变量的情况下按 dotenv 的规则解析
+    #[allow(deprecated)]
+    fn all_vars(path: &Path) -> Vec<(String, String)> {
+        dotenv::from_path_iter(path).unwrap().map(Result::unwrap).collect()
+    }
+
+    fn parsed(path: &Path, key: &str) -> String {
+        all_vars(path).into_iter().find(|(k, _)| k == key).map(|(_, v)| v).unwrap()
+    }
+
+    #[test]
+    fn password_with_shell_characters_survives_dotenv() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ups120.env");
+        let password = r#"p@ss $HOME #1 "q" 'a' \b"#;
+        write_config(&path, &render_env(&answers(password)), false).unwrap();
+        assert_eq!(parsed(&path, "MQTT_PASSWORD"), password);
+        assert_eq!(parsed(&path, "MQTT_USERNAME"), "ups user");
+    }
+
+    #[test]
+    fn quoting_escapes_backslash_quote_and_dollar() {
+        assert_eq!(quote_env_value(r#"a\b"c$d e#f"#), r#""a\\b\"c\$d e#f""#);
+        assert_eq!(quote_env_value(""), "\"\"");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn config_is_written_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ups120.env");
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        write_config(&path, "A=1\n", false).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert!(matches!(write_config(&path, "A=2\n", false), Err(WizardError::Exists(_))));
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        std::fs::write(path.with_extension("tmp"), "").unwrap();
+        write_config(&path, "A=2\n", true).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "A=2\n");
+    }
+
+    #[test]
+    fn the_generated_file_loads_as_the_daemon_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ups120.env");
+        let mut answers = answers("s3cret $USER");
+        answers.usb_pid = 0x0003;
+        answers.usb_serial = Some("UPS120-0042".to_string());
+        write_config(&path, &render_env(&answers), false).unwrap();
+
+        let vars = all_vars(&path);
+        let vars: Vec<(&str, &str)> = vars.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        let config = crate::config::DaemonConfig::from_vars(&vars).unwrap();
+        assert_eq!((config.mqtt_broker_host.as_str(), config.mqtt_broker_port), ("broker.local", 1883));
+        assert_eq!(config.mqtt_username.as_deref(), Some("ups user"));
+        assert_eq!(config.mqtt_password.as_deref(), Some("s3cret $USER"));
+        assert_eq!(config.mqtt_topic_prefix, "ups120");
+        assert_eq!(config.usb_ids.iter().map(ToString::to_string).collect::<Vec<_>>(), ["1209:0003"]);
+        assert_eq!(config.publish_min_interval, Duration::from_millis(1000));
+        assert_eq!(config.stats_interval, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn credentials_are_commented_out_without_a_username() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ups120.env");
+        let mut anonymous = answers("ignored");
+        anonymous.username = None;
+        write_config(&path, &render_env(&anonymous), false).unwrap();
+        let keys: Vec<String> = all_vars(&path).into_iter().map(|(k, _)| k).collect();
+        assert!(!keys.iter().any(|k| k.starts_with("MQTT_USERNAME") || k.starts_with("MQTT_PASSWORD")), "{:?}", keys);
+
+        // 有用户名没有密码时写入空密码
+        let mut no_password = answers("");
+        no_password.password = None;
+        write_config(&path, &render_env(&no_password), true).unwrap();
+        assert_eq!(parsed(&path, "MQTT_PASSWORD"), "");
+    }
+}
@@ -0,0 +1,72 @@
+//! 数据记录的稳态分配预算。
+//!
+//! 全局分配器对整个测试进程生效，单独放在一个测试二进制中，并且不用 libtest 的测试线程：
+//! `main` 在单线程中运行，计数只包含被测代码的分配。
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use ups120_daemon::datalog::{DataLogSettings, DataLogger, FsyncPolicy};
+use ups120_daemon::stats::DaemonStats;
+use ups120_daemon::test_support::PayloadBuilder;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocations() -> u64 {
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+// 稳态预算：不切换文件时，每条记录的序列化与写入不做堆分配
+fn steady_state_writes_stay_within_the_allocation_budget() {
+    let dir = tempfile::tempdir().unwrap();
+    let settings = DataLogSettings {
+        dir: dir.path().to_path_buf(),
+        max_file_bytes: u64::MAX,
+        max_files: 2,
+        fsync: FsyncPolicy::Never,
+        fsync_interval: Duration::from_secs(10),
+    };
+    let mut logger = DataLogger::new(settings, Arc::new(DaemonStats::default())).unwrap();
+    let mut sample = PayloadBuilder::new().sample(0, 1_700_000_000_000);
+    // 预热：打开文件，缓冲区增长到峰值容量
+    for frame_id in 0..1000 {
+        sample.frame_id = frame_id;
+        logger.write(&sample).unwrap();
+    }
+    let before = allocations();
+    for frame_id in 1000..2000 {
+        sample.frame_id = frame_id;
+        logger.write(&sample).unwrap();
+    }
+    let after = allocations();
+    logger.close().unwrap();
+    assert_eq!(after - before, 0, "allocations over 1000 steady-state records");
+}
+
+fn main() {
+    steady_state_writes_stay_within_the_allocation_budget();
+    println!("test steady_state_writes_stay_within_the_allocation_budget ... ok");
+}